@@ -0,0 +1,8 @@
+ALTER TABLE emergency_access
+ADD COLUMN reminder_interval_hours INTEGER;
+ALTER TABLE emergency_access
+ADD COLUMN escalation_after_hours INTEGER;
+ALTER TABLE emergency_access
+ADD COLUMN escalation_contacts TEXT;
+ALTER TABLE emergency_access
+ADD COLUMN escalated_at DATETIME;
@@ -0,0 +1,2 @@
+ALTER TABLE emergency_access
+ADD COLUMN org_uuid CHAR(36) REFERENCES organizations (uuid);
@@ -0,0 +1,2 @@
+ALTER TABLE emergency_access
+ADD COLUMN scope_folders TEXT;
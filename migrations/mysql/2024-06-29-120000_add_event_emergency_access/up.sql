@@ -0,0 +1,4 @@
+ALTER TABLE event
+ADD COLUMN emergency_access_uuid CHAR(36);
+ALTER TABLE event
+ADD COLUMN grantee_user_uuid CHAR(36);
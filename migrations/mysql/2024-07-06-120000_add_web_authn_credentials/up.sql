@@ -0,0 +1,12 @@
+CREATE TABLE web_authn_credentials (
+    uuid                    CHAR(36) NOT NULL PRIMARY KEY,
+    user_uuid               CHAR(36) NOT NULL,
+    name                    TEXT NOT NULL,
+    credential              TEXT NOT NULL,
+    supports_prf            BOOLEAN NOT NULL DEFAULT FALSE,
+    encrypted_user_key      TEXT,
+    encrypted_public_key    TEXT,
+    encrypted_private_key   TEXT,
+    creation_date           DATETIME NOT NULL,
+    FOREIGN KEY (user_uuid) REFERENCES users (uuid)
+);
@@ -0,0 +1,6 @@
+CREATE TABLE twofactor_duo_ctx (
+    state      VARCHAR(64) NOT NULL PRIMARY KEY,
+    user_email VARCHAR(255) NOT NULL,
+    nonce      VARCHAR(64) NOT NULL,
+    exp        BIGINT NOT NULL
+);
@@ -0,0 +1,4 @@
+ALTER TABLE users_organizations
+ADD COLUMN twofactor_grace_until DATETIME;
+ALTER TABLE users_organizations
+ADD COLUMN twofactor_reminder_at DATETIME;
@@ -0,0 +1,5 @@
+ALTER TABLE users
+ADD COLUMN uses_key_connector BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE users
+ADD COLUMN key_connector_key TEXT;
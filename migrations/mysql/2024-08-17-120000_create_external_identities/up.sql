@@ -0,0 +1,11 @@
+CREATE TABLE external_identities (
+    uuid            CHAR(36) NOT NULL PRIMARY KEY,
+    user_uuid       CHAR(36) NOT NULL,
+    issuer          VARCHAR(255) NOT NULL,
+    subject         VARCHAR(255) NOT NULL,
+    email           TEXT NOT NULL,
+    creation_date   DATETIME NOT NULL,
+    last_used_date  DATETIME NOT NULL,
+    FOREIGN KEY (user_uuid) REFERENCES users (uuid),
+    UNIQUE (issuer, subject)
+);
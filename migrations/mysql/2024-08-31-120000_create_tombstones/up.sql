@@ -0,0 +1,11 @@
+CREATE TABLE tombstones (
+    uuid            CHAR(36) NOT NULL PRIMARY KEY,
+    atype           INTEGER NOT NULL,
+    object_uuid     CHAR(36) NOT NULL,
+    user_uuid       CHAR(36),
+    org_uuid        CHAR(36),
+    deleted_at      DATETIME NOT NULL
+);
+
+CREATE INDEX idx_tombstones_user_deleted_at ON tombstones (user_uuid, deleted_at);
+CREATE INDEX idx_tombstones_org_deleted_at ON tombstones (org_uuid, deleted_at);
@@ -0,0 +1,14 @@
+CREATE TABLE mail_queue (
+    uuid            CHAR(36) NOT NULL PRIMARY KEY,
+    recipient       TEXT NOT NULL,
+    subject         TEXT NOT NULL,
+    body_html       MEDIUMTEXT NOT NULL,
+    body_text       MEDIUMTEXT NOT NULL,
+    status          INTEGER NOT NULL,
+    attempts        INTEGER NOT NULL,
+    next_attempt_at DATETIME NOT NULL,
+    last_error      TEXT,
+    created_at      DATETIME NOT NULL
+);
+
+CREATE INDEX idx_mail_queue_status_next_attempt_at ON mail_queue (status, next_attempt_at);
@@ -0,0 +1,6 @@
+CREATE TABLE mail_preferences (
+    user_uuid CHAR(36) NOT NULL REFERENCES users (uuid),
+    mail_type INTEGER  NOT NULL,
+    enabled   BOOLEAN  NOT NULL,
+    PRIMARY KEY (user_uuid, mail_type)
+);
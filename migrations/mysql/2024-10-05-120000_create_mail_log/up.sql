@@ -0,0 +1,14 @@
+CREATE TABLE mail_log (
+    uuid            CHAR(36) NOT NULL PRIMARY KEY,
+    recipient       TEXT NOT NULL,
+    template        TEXT NOT NULL,
+    subject         TEXT NOT NULL,
+    body_html       MEDIUMTEXT NOT NULL,
+    body_text       MEDIUMTEXT NOT NULL,
+    status          INTEGER NOT NULL,
+    error           TEXT,
+    created_at      DATETIME NOT NULL,
+    updated_at      DATETIME NOT NULL
+);
+
+CREATE INDEX idx_mail_log_created_at ON mail_log (created_at);
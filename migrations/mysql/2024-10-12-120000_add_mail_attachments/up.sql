@@ -0,0 +1,5 @@
+ALTER TABLE mail_queue
+ADD COLUMN attachments MEDIUMTEXT;
+
+ALTER TABLE mail_log
+ADD COLUMN attachments MEDIUMTEXT;
@@ -0,0 +1,17 @@
+CREATE TABLE jobs (
+    name            VARCHAR(255) NOT NULL PRIMARY KEY,
+    enabled         BOOLEAN NOT NULL,
+    schedule        TEXT,
+    updated_at      DATETIME NOT NULL
+);
+
+CREATE TABLE job_runs (
+    uuid            CHAR(36) NOT NULL PRIMARY KEY,
+    job_name        VARCHAR(255) NOT NULL,
+    manual          BOOLEAN NOT NULL,
+    started_at      DATETIME NOT NULL,
+    duration_ms     BIGINT NOT NULL,
+    error           TEXT
+);
+
+CREATE INDEX idx_job_runs_job_name_started_at ON job_runs (job_name, started_at);
@@ -0,0 +1,4 @@
+ALTER TABLE users_organizations
+ADD COLUMN twofactor_grace_until TIMESTAMP;
+ALTER TABLE users_organizations
+ADD COLUMN twofactor_reminder_at TIMESTAMP;
@@ -0,0 +1,4 @@
+ALTER TABLE devices
+ADD COLUMN twofactor_remember_created_at TIMESTAMP;
+ALTER TABLE devices
+ADD COLUMN twofactor_remember_last_used TIMESTAMP;
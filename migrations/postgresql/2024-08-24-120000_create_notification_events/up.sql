@@ -0,0 +1,11 @@
+CREATE TABLE notification_events (
+    uuid            CHAR(36) NOT NULL PRIMARY KEY,
+    user_uuid       CHAR(36) NOT NULL,
+    seq             BIGINT NOT NULL,
+    atype           INTEGER NOT NULL,
+    data            TEXT NOT NULL,
+    created_at      TIMESTAMP NOT NULL,
+    delivered       BOOLEAN NOT NULL DEFAULT FALSE,
+    FOREIGN KEY (user_uuid) REFERENCES users (uuid),
+    UNIQUE (user_uuid, seq)
+);
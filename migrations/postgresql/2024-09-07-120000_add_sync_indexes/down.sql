@@ -0,0 +1,12 @@
+DROP INDEX idx_ciphers_user_uuid;
+DROP INDEX idx_ciphers_organization_uuid;
+DROP INDEX idx_attachments_cipher_uuid;
+DROP INDEX idx_folders_user_uuid;
+DROP INDEX idx_folders_ciphers_folder_uuid;
+DROP INDEX idx_ciphers_collections_collection_uuid;
+DROP INDEX idx_users_collections_collection_uuid;
+DROP INDEX idx_collections_org_uuid;
+DROP INDEX idx_users_organizations_org_uuid;
+DROP INDEX idx_groups_users_users_organizations_uuid;
+DROP INDEX idx_collections_groups_groups_uuid;
+DROP INDEX idx_sends_user_uuid;
@@ -0,0 +1,13 @@
+-- The foreign keys used by the sync queries, MySQL/MariaDB already creates an index for each foreign key
+CREATE INDEX idx_ciphers_user_uuid ON ciphers (user_uuid);
+CREATE INDEX idx_ciphers_organization_uuid ON ciphers (organization_uuid);
+CREATE INDEX idx_attachments_cipher_uuid ON attachments (cipher_uuid);
+CREATE INDEX idx_folders_user_uuid ON folders (user_uuid);
+CREATE INDEX idx_folders_ciphers_folder_uuid ON folders_ciphers (folder_uuid);
+CREATE INDEX idx_ciphers_collections_collection_uuid ON ciphers_collections (collection_uuid);
+CREATE INDEX idx_users_collections_collection_uuid ON users_collections (collection_uuid);
+CREATE INDEX idx_collections_org_uuid ON collections (org_uuid);
+CREATE INDEX idx_users_organizations_org_uuid ON users_organizations (org_uuid);
+CREATE INDEX idx_groups_users_users_organizations_uuid ON groups_users (users_organizations_uuid);
+CREATE INDEX idx_collections_groups_groups_uuid ON collections_groups (groups_uuid);
+CREATE INDEX idx_sends_user_uuid ON sends (user_uuid);
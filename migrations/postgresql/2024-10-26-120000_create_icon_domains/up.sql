@@ -0,0 +1,8 @@
+CREATE TABLE icon_domains (
+    domain          VARCHAR(255) NOT NULL PRIMARY KEY,
+    status          INTEGER NOT NULL,
+    failures        INTEGER NOT NULL,
+    last_error      TEXT,
+    checked_at      TIMESTAMP NOT NULL,
+    retry_at        TIMESTAMP
+);
@@ -0,0 +1,5 @@
+CREATE TABLE web_authn_login_challenges (
+    uuid    CHAR(36) NOT NULL PRIMARY KEY,
+    state   TEXT NOT NULL,
+    exp     BIGINT NOT NULL
+);
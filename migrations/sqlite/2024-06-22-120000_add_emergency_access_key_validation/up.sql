@@ -0,0 +1,4 @@
+ALTER TABLE emergency_access
+ADD COLUMN key_fingerprint TEXT;
+ALTER TABLE emergency_access
+ADD COLUMN key_invalid_at DATETIME;
@@ -0,0 +1,4 @@
+ALTER TABLE event
+ADD COLUMN emergency_access_uuid TEXT;
+ALTER TABLE event
+ADD COLUMN grantee_user_uuid TEXT;
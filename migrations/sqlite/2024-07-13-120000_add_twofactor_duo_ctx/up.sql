@@ -0,0 +1,6 @@
+CREATE TABLE twofactor_duo_ctx (
+    state      TEXT NOT NULL PRIMARY KEY,
+    user_email TEXT NOT NULL,
+    nonce      TEXT NOT NULL,
+    exp        BIGINT NOT NULL
+);
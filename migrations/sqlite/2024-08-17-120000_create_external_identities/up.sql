@@ -0,0 +1,11 @@
+CREATE TABLE external_identities (
+    uuid            TEXT NOT NULL PRIMARY KEY,
+    user_uuid       TEXT NOT NULL,
+    issuer          TEXT NOT NULL,
+    subject         TEXT NOT NULL,
+    email           TEXT NOT NULL,
+    creation_date   DATETIME NOT NULL,
+    last_used_date  DATETIME NOT NULL,
+    FOREIGN KEY (user_uuid) REFERENCES users (uuid),
+    UNIQUE (issuer, subject)
+);
@@ -0,0 +1,11 @@
+CREATE TABLE notification_events (
+    uuid            TEXT NOT NULL PRIMARY KEY,
+    user_uuid       TEXT NOT NULL,
+    seq             INTEGER NOT NULL,
+    atype           INTEGER NOT NULL,
+    data            TEXT NOT NULL,
+    created_at      DATETIME NOT NULL,
+    delivered       BOOLEAN NOT NULL DEFAULT 0, -- FALSE
+    FOREIGN KEY (user_uuid) REFERENCES users (uuid),
+    UNIQUE (user_uuid, seq)
+);
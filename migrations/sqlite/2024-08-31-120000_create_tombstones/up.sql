@@ -0,0 +1,11 @@
+CREATE TABLE tombstones (
+    uuid            TEXT NOT NULL PRIMARY KEY,
+    atype           INTEGER NOT NULL,
+    object_uuid     TEXT NOT NULL,
+    user_uuid       TEXT,
+    org_uuid        TEXT,
+    deleted_at      DATETIME NOT NULL
+);
+
+CREATE INDEX idx_tombstones_user_deleted_at ON tombstones (user_uuid, deleted_at);
+CREATE INDEX idx_tombstones_org_deleted_at ON tombstones (org_uuid, deleted_at);
@@ -0,0 +1,5 @@
+ALTER TABLE mail_queue
+ADD COLUMN attachments TEXT;
+
+ALTER TABLE mail_log
+ADD COLUMN attachments TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE mail_queue
+ADD COLUMN digest BOOLEAN NOT NULL DEFAULT 0; -- FALSE
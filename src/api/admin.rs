@@ -1,7 +1,7 @@
 use once_cell::sync::Lazy;
 use serde::de::DeserializeOwned;
 use serde_json::Value;
-use std::env;
+use std::{env, net::IpAddr};
 
 use rocket::serde::json::Json;
 use rocket::{
@@ -9,32 +9,34 @@ use rocket::{
     http::{Cookie, CookieJar, MediaType, SameSite, Status},
     request::{FromRequest, Outcome, Request},
     response::{content::RawHtml as Html, Redirect},
-    Catcher, Route,
+    Catcher, Route, State,
 };
 
 use crate::{
     api::{
+        admin_setup,
         core::{log_event, two_factor},
-        unregister_push_device, ApiResult, EmptyResult, JsonResult, Notify,
+        event_bus::{AdminAction, DomainEvent, EventContext, EVENT_BUS},
+        unregister_push_device, ApiResult, EmptyResult, JsonResult, ListQuery, Notify,
     },
     auth::{decode_admin, encode_jwt, generate_admin_claims, ClientIp},
     config::ConfigBuilder,
-    db::{backup_database, get_sql_server_version, models::*, DbConn, DbConnType},
+    db::{get_sql_server_version, models::*, DbConn, DbConnType, DbPool},
     error::{Error, MapResult},
-    mail,
+    http_client::{get_reqwest_client, TracedSend},
+    mail, mapping_rules,
     util::{
-        container_base_image, format_naive_datetime_local, get_display_size, get_reqwest_client,
-        is_running_in_container, NumberOrString,
+        container_base_image, format_naive_datetime_local, get_display_size, is_running_in_container, NumberOrString,
     },
     CONFIG, VERSION,
 };
 
 pub fn routes() -> Vec<Route> {
-    if !CONFIG.disable_admin_token() && !CONFIG.is_admin_token_set() {
+    if !CONFIG.disable_admin_token() && !CONFIG.is_admin_token_set() && !CONFIG.setup_wizard() {
         return routes![admin_disabled];
     }
 
-    routes![
+    let mut routes = routes![
         get_users_json,
         get_user_json,
         get_user_by_mail_json,
@@ -53,18 +55,45 @@ pub fn routes() -> Vec<Route> {
         post_config,
         delete_config,
         backup_db,
+        export_db,
+        retry_queued_mail,
+        delete_queued_mail,
+        mail_log_overview,
+        resend_logged_mail,
+        db_maintenance,
+        prewarm_icons,
+        jobs_overview,
+        jobs_json,
+        update_job,
+        run_job,
         test_smtp,
+        get_email_templates,
+        preview_email_template,
         users_overview,
+        get_organizations_json,
         organizations_overview,
         delete_organization,
         diagnostics,
         get_diagnostics_config,
+        route_stats,
+        route_stats_json,
+        user_events,
+        user_events_json,
         resend_user_invite,
-    ]
+        get_user_external_identities,
+        delete_user_external_identity,
+        evaluate_mapping_rules,
+        get_migrations,
+        apply_migrations,
+    ];
+    if admin_setup::is_pending() {
+        routes.append(&mut admin_setup::routes());
+    }
+    routes
 }
 
 pub fn catchers() -> Vec<Catcher> {
-    if !CONFIG.disable_admin_token() && !CONFIG.is_admin_token_set() {
+    if !CONFIG.disable_admin_token() && !CONFIG.is_admin_token_set() && !CONFIG.setup_wizard() {
         catchers![]
     } else {
         catchers![admin_login]
@@ -81,9 +110,6 @@ static DB_TYPE: Lazy<&str> = Lazy::new(|| {
         .unwrap_or("Unknown")
 });
 
-static CAN_BACKUP: Lazy<bool> =
-    Lazy::new(|| DbConnType::from_url(&CONFIG.database_url()).map(|t| t == DbConnType::sqlite).unwrap_or(false));
-
 #[get("/")]
 fn admin_disabled() -> &'static str {
     "The admin panel is disabled, please configure the 'ADMIN_TOKEN' variable to enable it"
@@ -97,7 +123,7 @@ const BASE_TEMPLATE: &str = "admin/base";
 
 const ACTING_ADMIN_USER: &str = "vaultwarden-admin-00000-000000000000";
 
-fn admin_path() -> String {
+pub(super) fn admin_path() -> String {
     format!("{}{}", CONFIG.domain_path(), ADMIN_PATH)
 }
 
@@ -128,7 +154,7 @@ fn admin_url() -> String {
 }
 
 #[derive(Responder)]
-enum AdminResponse {
+pub(super) enum AdminResponse {
     #[response(status = 200)]
     Ok(ApiResult<Html<String>>),
     #[response(status = 401)]
@@ -146,13 +172,17 @@ fn admin_login(request: &Request<'_>) -> ApiResult<Html<String>> {
     render_admin_login(None, Some(redirect))
 }
 
-fn render_admin_login(msg: Option<&str>, redirect: Option<String>) -> ApiResult<Html<String>> {
+pub(super) fn render_admin_login(msg: Option<&str>, redirect: Option<String>) -> ApiResult<Html<String>> {
     // If there is an error, show it
     let msg = msg.map(|msg| format!("Error: {msg}"));
+    // Until the admin token is set, the setup code only opens the setup wizard, see `admin_setup`
+    let setup = admin_setup::is_pending();
+    let redirect = redirect.filter(|redirect| !redirect.is_empty() && !setup);
     let json = json!({
         "page_content": "admin/login",
         "error": msg,
         "redirect": redirect,
+        "setup": setup,
         "urlpath": CONFIG.domain_path()
     });
 
@@ -168,7 +198,12 @@ struct LoginForm {
 }
 
 #[post("/", data = "<data>")]
-fn post_admin_login(data: Form<LoginForm>, cookies: &CookieJar<'_>, ip: ClientIp) -> Result<Redirect, AdminResponse> {
+async fn post_admin_login(
+    data: Form<LoginForm>,
+    cookies: &CookieJar<'_>,
+    ip: ClientIp,
+    mut conn: DbConn,
+) -> Result<Redirect, AdminResponse> {
     let data = data.into_inner();
     let redirect = data.redirect;
 
@@ -182,6 +217,7 @@ fn post_admin_login(data: Form<LoginForm>, cookies: &CookieJar<'_>, ip: ClientIp
     // If the token is invalid, redirect to login page
     if !_validate_token(&data.token) {
         error!("Invalid admin token. IP: {}", ip.ip);
+        log_admin_action(AdminAction::FailedLogIn, None, &ip.ip, &mut conn).await;
         Err(AdminResponse::Unauthorized(render_admin_login(Some("Invalid admin token, please try again."), redirect)))
     } else {
         // If the token received is valid, generate JWT and save it as a cookie
@@ -195,6 +231,7 @@ fn post_admin_login(data: Form<LoginForm>, cookies: &CookieJar<'_>, ip: ClientIp
             .http_only(true);
 
         cookies.add(cookie);
+        log_admin_action(AdminAction::LoggedIn, None, &ip.ip, &mut conn).await;
         if let Some(redirect) = redirect {
             Ok(Redirect::to(format!("{}{}", admin_path(), redirect)))
         } else {
@@ -203,8 +240,29 @@ fn post_admin_login(data: Form<LoginForm>, cookies: &CookieJar<'_>, ip: ClientIp
     }
 }
 
+/// Emits an admin action on the event bus, for the SIEM export.
+pub(super) async fn log_admin_action(action: AdminAction, target: Option<&str>, ip: &IpAddr, conn: &mut DbConn) {
+    let ctx = EventContext {
+        user_uuid: String::from(ACTING_ADMIN_USER),
+        device_uuid: String::new(),
+        device_type: 14, // Use UnknownBrowser type
+        ip: *ip,
+    };
+    EVENT_BUS
+        .emit(
+            DomainEvent::AdminAction {
+                action,
+                target,
+            },
+            &ctx,
+            conn,
+        )
+        .await;
+}
+
 fn _validate_token(token: &str) -> bool {
     match CONFIG.admin_token().as_ref() {
+        // The setup code is only accepted by the setup wizard
         None => false,
         Some(t) if t.starts_with("$argon2") => {
             use argon2::password_hash::PasswordVerifier;
@@ -224,7 +282,7 @@ fn _validate_token(token: &str) -> bool {
 }
 
 #[derive(Serialize)]
-struct AdminTemplateData {
+pub(super) struct AdminTemplateData {
     page_content: String,
     page_data: Option<Value>,
     logged_in: bool,
@@ -232,7 +290,7 @@ struct AdminTemplateData {
 }
 
 impl AdminTemplateData {
-    fn new(page_content: &str, page_data: Value) -> Self {
+    pub(super) fn new(page_content: &str, page_data: Value) -> Self {
         Self {
             page_content: String::from(page_content),
             page_data: Some(page_data),
@@ -241,7 +299,7 @@ impl AdminTemplateData {
         }
     }
 
-    fn render(self) -> Result<String, Error> {
+    pub(super) fn render(self) -> Result<String, Error> {
         CONFIG.render_template(BASE_TEMPLATE, &self)
     }
 }
@@ -249,7 +307,6 @@ impl AdminTemplateData {
 fn render_admin_page() -> ApiResult<Html<String>> {
     let settings_json = json!({
         "config": CONFIG.prepare_json(),
-        "can_backup": *CAN_BACKUP,
     });
     let text = AdminTemplateData::new("admin/settings", settings_json).render()?;
     Ok(Html(text))
@@ -282,11 +339,18 @@ async fn get_user_or_404(uuid: &str, conn: &mut DbConn) -> ApiResult<User> {
 #[post("/invite", data = "<data>")]
 async fn invite_user(data: Json<InviteData>, _token: AdminToken, mut conn: DbConn) -> JsonResult {
     let data: InviteData = data.into_inner();
-    if User::find_by_mail(&data.email, &mut conn).await.is_some() {
+    let user = invite_new_user(data.email, &mut conn).await?;
+    Ok(Json(user.to_json(&mut conn).await))
+}
+
+// The actions on the users are shared with the `user` commands of the CLI
+
+pub(super) async fn invite_new_user(email: String, conn: &mut DbConn) -> ApiResult<User> {
+    if User::find_by_mail(&email, conn).await.is_some() {
         err_code!("User already exists", Status::Conflict.code)
     }
 
-    let mut user = User::new(data.email);
+    let mut user = User::new(email);
 
     async fn _generate_invite(user: &User, conn: &mut DbConn) -> EmptyResult {
         if CONFIG.mail_enabled() {
@@ -297,10 +361,53 @@ async fn invite_user(data: Json<InviteData>, _token: AdminToken, mut conn: DbCon
         }
     }
 
-    _generate_invite(&user, &mut conn).await.map_err(|e| e.with_code(Status::InternalServerError.code))?;
-    user.save(&mut conn).await.map_err(|e| e.with_code(Status::InternalServerError.code))?;
+    _generate_invite(&user, conn).await.map_err(|e| e.with_code(Status::InternalServerError.code))?;
+    user.save(conn).await.map_err(|e| e.with_code(Status::InternalServerError.code))?;
+    Ok(user)
+}
 
-    Ok(Json(user.to_json(&mut conn).await))
+pub(super) async fn delete_user_account(user: User, ip: &IpAddr, conn: &mut DbConn) -> EmptyResult {
+    // Get the user_org records before deleting the actual user
+    let user_orgs = UserOrganization::find_any_state_by_user(&user.uuid, conn).await;
+    let email = user.email.clone();
+    let res = user.delete(conn).await;
+    if res.is_ok() {
+        log_admin_action(AdminAction::UserDeleted, Some(&email), ip, conn).await;
+    }
+
+    for user_org in user_orgs {
+        log_event(
+            EventType::OrganizationUserRemoved as i32,
+            &user_org.uuid,
+            &user_org.org_uuid,
+            ACTING_ADMIN_USER,
+            14, // Use UnknownBrowser type
+            ip,
+            conn,
+        )
+        .await;
+    }
+
+    res
+}
+
+pub(super) async fn disable_user_account(user: &mut User, ip: &IpAddr, conn: &mut DbConn) -> EmptyResult {
+    Device::delete_all_by_user(&user.uuid, conn).await?;
+    user.reset_security_stamp();
+    user.enabled = false;
+
+    user.save(conn).await?;
+    log_admin_action(AdminAction::UserDisabled, Some(&user.email), ip, conn).await;
+    Ok(())
+}
+
+pub(super) async fn remove_user_2fa(user: &mut User, ip: &IpAddr, conn: &mut DbConn) -> EmptyResult {
+    TwoFactor::delete_all_by_user(&user.uuid, conn).await?;
+    two_factor::enforce_2fa_policy(user, ACTING_ADMIN_USER, 14, ip, conn).await?;
+    user.totp_recover = None;
+    user.save(conn).await?;
+    log_admin_action(AdminAction::User2faRemoved, Some(&user.email), ip, conn).await;
+    Ok(())
 }
 
 #[post("/test/smtp", data = "<data>")]
@@ -314,15 +421,40 @@ async fn test_smtp(data: Json<InviteData>, _token: AdminToken) -> EmptyResult {
     }
 }
 
+#[get("/email_templates")]
+fn get_email_templates(_token: AdminToken) -> Json<Value> {
+    Json(json!(CONFIG.email_template_names()))
+}
+
+#[get("/email_templates/<name>/preview")]
+fn preview_email_template(name: &str, _token: AdminToken) -> JsonResult {
+    if !CONFIG.email_template_names().iter().any(|n| n == name) {
+        err_code!("Email template not found", Status::NotFound.code)
+    }
+    let (subject, html, text) = mail::preview_template(name)?;
+    Ok(Json(json!({
+        "subject": subject,
+        "html": html,
+        "text": text,
+    })))
+}
+
 #[get("/logout")]
 fn logout(cookies: &CookieJar<'_>) -> Redirect {
     cookies.remove(Cookie::build(COOKIE_NAME).path(admin_path()));
     Redirect::to(admin_path())
 }
 
-#[get("/users")]
-async fn get_users_json(_token: AdminToken, mut conn: DbConn) -> Json<Value> {
-    let users = User::get_all(&mut conn).await;
+#[get("/users?<list..>")]
+async fn get_users_json(list: ListQuery, _token: AdminToken, mut conn: DbConn) -> Json<Value> {
+    let (users, total) = if list.is_paginated() {
+        let sort = UserSort::from_name(list.sort());
+        User::find_page(list.search(), sort, list.descending(), list.offset(), list.limit(), &mut conn).await
+    } else {
+        let users = User::get_all(&mut conn).await;
+        let total = users.len() as i64;
+        (users, total)
+    };
     let mut users_json = Vec::with_capacity(users.len());
     for u in users {
         let mut usr = u.to_json(&mut conn).await;
@@ -335,12 +467,18 @@ async fn get_users_json(_token: AdminToken, mut conn: DbConn) -> Json<Value> {
         users_json.push(usr);
     }
 
-    Json(Value::Array(users_json))
+    if list.is_paginated() {
+        Json(list.list_json(users_json, total))
+    } else {
+        Json(Value::Array(users_json))
+    }
 }
 
-#[get("/users/overview")]
-async fn users_overview(_token: AdminToken, mut conn: DbConn) -> ApiResult<Html<String>> {
-    let users = User::get_all(&mut conn).await;
+#[get("/users/overview?<list..>")]
+async fn users_overview(list: ListQuery, _token: AdminToken, mut conn: DbConn) -> ApiResult<Html<String>> {
+    let sort = UserSort::from_name(list.sort());
+    let (users, total) =
+        User::find_page(list.search(), sort, list.descending(), list.offset(), list.limit(), &mut conn).await;
     let mut users_json = Vec::with_capacity(users.len());
     for u in users {
         let mut usr = u.to_json(&mut conn).await;
@@ -356,7 +494,11 @@ async fn users_overview(_token: AdminToken, mut conn: DbConn) -> ApiResult<Html<
         users_json.push(usr);
     }
 
-    let text = AdminTemplateData::new("admin/users", json!(users_json)).render()?;
+    let page_data = json!({
+        "users": users_json,
+        "list": list.page_json(total, sort.name(), &UserSort::ALL.map(UserSort::name)),
+    });
+    let text = AdminTemplateData::new("admin/users", page_data).render()?;
     Ok(Html(text))
 }
 
@@ -384,29 +526,11 @@ async fn get_user_json(uuid: &str, _token: AdminToken, mut conn: DbConn) -> Json
 #[post("/users/<uuid>/delete")]
 async fn delete_user(uuid: &str, token: AdminToken, mut conn: DbConn) -> EmptyResult {
     let user = get_user_or_404(uuid, &mut conn).await?;
-
-    // Get the user_org records before deleting the actual user
-    let user_orgs = UserOrganization::find_any_state_by_user(uuid, &mut conn).await;
-    let res = user.delete(&mut conn).await;
-
-    for user_org in user_orgs {
-        log_event(
-            EventType::OrganizationUserRemoved as i32,
-            &user_org.uuid,
-            &user_org.org_uuid,
-            ACTING_ADMIN_USER,
-            14, // Use UnknownBrowser type
-            &token.ip.ip,
-            &mut conn,
-        )
-        .await;
-    }
-
-    res
+    delete_user_account(user, &token.ip.ip, &mut conn).await
 }
 
 #[post("/users/<uuid>/deauth")]
-async fn deauth_user(uuid: &str, _token: AdminToken, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
+async fn deauth_user(uuid: &str, token: AdminToken, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
     let mut user = get_user_or_404(uuid, &mut conn).await?;
 
     nt.send_logout(&user, None).await;
@@ -423,38 +547,61 @@ async fn deauth_user(uuid: &str, _token: AdminToken, mut conn: DbConn, nt: Notif
     Device::delete_all_by_user(&user.uuid, &mut conn).await?;
     user.reset_security_stamp();
 
-    user.save(&mut conn).await
+    user.save(&mut conn).await?;
+    log_admin_action(AdminAction::UserDeauthorized, Some(&user.email), &token.ip.ip, &mut conn).await;
+    Ok(())
 }
 
 #[post("/users/<uuid>/disable")]
-async fn disable_user(uuid: &str, _token: AdminToken, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
+async fn disable_user(uuid: &str, token: AdminToken, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
     let mut user = get_user_or_404(uuid, &mut conn).await?;
-    Device::delete_all_by_user(&user.uuid, &mut conn).await?;
-    user.reset_security_stamp();
-    user.enabled = false;
-
-    let save_result = user.save(&mut conn).await;
+    let result = disable_user_account(&mut user, &token.ip.ip, &mut conn).await;
 
     nt.send_logout(&user, None).await;
-
-    save_result
+    result
 }
 
 #[post("/users/<uuid>/enable")]
-async fn enable_user(uuid: &str, _token: AdminToken, mut conn: DbConn) -> EmptyResult {
+async fn enable_user(uuid: &str, token: AdminToken, mut conn: DbConn) -> EmptyResult {
     let mut user = get_user_or_404(uuid, &mut conn).await?;
     user.enabled = true;
 
-    user.save(&mut conn).await
+    user.save(&mut conn).await?;
+    log_admin_action(AdminAction::UserEnabled, Some(&user.email), &token.ip.ip, &mut conn).await;
+    Ok(())
 }
 
 #[post("/users/<uuid>/remove-2fa")]
 async fn remove_2fa(uuid: &str, token: AdminToken, mut conn: DbConn) -> EmptyResult {
     let mut user = get_user_or_404(uuid, &mut conn).await?;
-    TwoFactor::delete_all_by_user(&user.uuid, &mut conn).await?;
-    two_factor::enforce_2fa_policy(&user, ACTING_ADMIN_USER, 14, &token.ip.ip, &mut conn).await?;
-    user.totp_recover = None;
-    user.save(&mut conn).await
+    remove_user_2fa(&mut user, &token.ip.ip, &mut conn).await
+}
+
+#[get("/users/<uuid>/external-identities")]
+async fn get_user_external_identities(uuid: &str, _token: AdminToken, mut conn: DbConn) -> JsonResult {
+    let user = get_user_or_404(uuid, &mut conn).await?;
+    let identities = ExternalIdentity::find_all_by_user(&user.uuid, &mut conn).await;
+    Ok(Json(Value::Array(identities.iter().map(ExternalIdentity::to_json).collect())))
+}
+
+#[post("/users/<uuid>/external-identities/<identity_uuid>/delete")]
+async fn delete_user_external_identity(
+    uuid: &str,
+    identity_uuid: &str,
+    _token: AdminToken,
+    mut conn: DbConn,
+) -> EmptyResult {
+    let user = get_user_or_404(uuid, &mut conn).await?;
+    match ExternalIdentity::find_by_uuid_and_user(identity_uuid, &user.uuid, &mut conn).await {
+        Some(identity) => {
+            info!(
+                "Admin unlinked the external identity {} of {} from user {}",
+                identity.subject, identity.issuer, user.email
+            );
+            identity.delete(&mut conn).await
+        }
+        None => err_code!("External identity doesn't exist", Status::NotFound.code),
+    }
 }
 
 #[post("/users/<uuid>/invite/resend")]
@@ -537,14 +684,70 @@ async fn update_user_org_type(data: Json<UserOrgTypeData>, token: AdminToken, mu
     user_to_edit.save(&mut conn).await
 }
 
+#[derive(Deserialize)]
+struct MappingRulesEvaluationData {
+    email: Option<String>,
+    /// Claims to evaluate instead of the ones of the user in the directory
+    claims: Option<mapping_rules::Claims>,
+}
+
+// Dry-run of the mapping rules, shows the access they would grant without changing anything
+#[post("/mapping-rules/evaluate", data = "<data>")]
+async fn evaluate_mapping_rules(
+    data: Json<MappingRulesEvaluationData>,
+    _token: AdminToken,
+    mut conn: DbConn,
+) -> JsonResult {
+    let data: MappingRulesEvaluationData = data.into_inner();
+    let rules = mapping_rules::load_rules()?;
+
+    let user = match &data.email {
+        Some(email) => User::find_by_mail(email, &mut conn).await,
+        None => None,
+    };
+
+    let claims: mapping_rules::Claims = match (data.claims, &data.email) {
+        (Some(claims), _) => claims.into_iter().map(|(k, v)| (k.to_lowercase(), v)).collect(),
+        #[cfg(feature = "ldap")]
+        (None, Some(email)) if crate::ldap::enabled() => match crate::ldap::find_user(email).await? {
+            Some(directory_user) => directory_user.claims(email),
+            None => err!("User not found in the directory"),
+        },
+        _ => err!("No claims to evaluate, provide them or the email of a user in the directory"),
+    };
+
+    let assignments = mapping_rules::evaluate(&rules, &claims);
+    Ok(Json(json!({
+        "rules": rules.len(),
+        "enforced": CONFIG.ldap_mapping_enforce(),
+        "userFound": user.is_some(),
+        "claims": claims,
+        "assignments": mapping_rules::assignments_json(user.as_ref(), &assignments, &mut conn).await,
+    })))
+}
+
 #[post("/users/update_revision")]
 async fn update_revision_users(_token: AdminToken, mut conn: DbConn) -> EmptyResult {
     User::update_all_revisions(&mut conn).await
 }
 
-#[get("/organizations/overview")]
-async fn organizations_overview(_token: AdminToken, mut conn: DbConn) -> ApiResult<Html<String>> {
-    let organizations = Organization::get_all(&mut conn).await;
+#[get("/organizations?<list..>")]
+async fn get_organizations_json(list: ListQuery, _token: AdminToken, mut conn: DbConn) -> Json<Value> {
+    if !list.is_paginated() {
+        let organizations = Organization::get_all(&mut conn).await;
+        return Json(Value::Array(organizations.iter().map(Organization::to_json).collect()));
+    }
+    let sort = OrgSort::from_name(list.sort());
+    let (organizations, total) =
+        Organization::find_page(list.search(), sort, list.descending(), list.offset(), list.limit(), &mut conn).await;
+    Json(list.list_json(organizations.iter().map(Organization::to_json).collect(), total))
+}
+
+#[get("/organizations/overview?<list..>")]
+async fn organizations_overview(list: ListQuery, _token: AdminToken, mut conn: DbConn) -> ApiResult<Html<String>> {
+    let sort = OrgSort::from_name(list.sort());
+    let (organizations, total) =
+        Organization::find_page(list.search(), sort, list.descending(), list.offset(), list.limit(), &mut conn).await;
     let mut organizations_json = Vec::with_capacity(organizations.len());
     for o in organizations {
         let mut org = o.to_json();
@@ -558,14 +761,21 @@ async fn organizations_overview(_token: AdminToken, mut conn: DbConn) -> ApiResu
         organizations_json.push(org);
     }
 
-    let text = AdminTemplateData::new("admin/organizations", json!(organizations_json)).render()?;
+    let page_data = json!({
+        "organizations": organizations_json,
+        "list": list.page_json(total, sort.name(), &OrgSort::ALL.map(OrgSort::name)),
+    });
+    let text = AdminTemplateData::new("admin/organizations", page_data).render()?;
     Ok(Html(text))
 }
 
 #[post("/organizations/<uuid>/delete")]
-async fn delete_organization(uuid: &str, _token: AdminToken, mut conn: DbConn) -> EmptyResult {
+async fn delete_organization(uuid: &str, token: AdminToken, mut conn: DbConn) -> EmptyResult {
     let org = Organization::find_by_uuid(uuid, &mut conn).await.map_res("Organization doesn't exist")?;
-    org.delete(&mut conn).await
+    let org_name = org.name.clone();
+    org.delete(&mut conn).await?;
+    log_admin_action(AdminAction::OrganizationDeleted, Some(&org_name), &token.ip.ip, &mut conn).await;
+    Ok(())
 }
 
 #[derive(Deserialize)]
@@ -596,13 +806,13 @@ struct TimeApi {
 async fn get_json_api<T: DeserializeOwned>(url: &str) -> Result<T, Error> {
     let json_api = get_reqwest_client();
 
-    Ok(json_api.get(url).send().await?.error_for_status()?.json::<T>().await?)
+    Ok(json_api.get(url).send_traced().await?.error_for_status()?.json::<T>().await?)
 }
 
 async fn has_http_access() -> bool {
     let http_access = get_reqwest_client();
 
-    match http_access.head("https://github.com/dani-garcia/vaultwarden").send().await {
+    match http_access.head("https://github.com/dani-garcia/vaultwarden").send_traced().await {
         Ok(r) => r.status().is_success(),
         _ => false,
     }
@@ -668,7 +878,12 @@ async fn get_ntp_time(has_http_access: bool) -> String {
 }
 
 #[get("/diagnostics")]
-async fn diagnostics(_token: AdminToken, ip_header: IpHeader, mut conn: DbConn) -> ApiResult<Html<String>> {
+async fn diagnostics(
+    _token: AdminToken,
+    ip_header: IpHeader,
+    pool: &State<DbPool>,
+    mut conn: DbConn,
+) -> ApiResult<Html<String>> {
     use chrono::prelude::*;
     use std::net::ToSocketAddrs;
 
@@ -721,6 +936,12 @@ async fn diagnostics(_token: AdminToken, ip_header: IpHeader, mut conn: DbConn)
         "uses_proxy": uses_proxy,
         "db_type": *DB_TYPE,
         "db_version": get_sql_server_version(&mut conn).await,
+        "db_pool_stats": pool.stats_json(),
+        "db_maintenance": crate::db_maintenance::last_run_json(),
+        "mail_queue": mail::queue_json(&mut conn).await,
+        "icon_cache": crate::api::icon_stats_json(&mut conn).await,
+        "websocket_enabled": CONFIG.enable_websocket(),
+        "websocket_stats": crate::api::websocket_stats(),
         "admin_url": format!("{}/diagnostics", admin_url()),
         "overrides": &CONFIG.get_overrides().join(", "),
         "host_arch": std::env::consts::ARCH,
@@ -734,6 +955,98 @@ async fn diagnostics(_token: AdminToken, ip_header: IpHeader, mut conn: DbConn)
     Ok(Html(text))
 }
 
+#[get("/migrations")]
+async fn get_migrations(_token: AdminToken) -> JsonResult {
+    let status = crate::db::migration_status().await?;
+    Ok(Json(json!({
+        "backend": status.backend,
+        "schema_version": status.schema_version,
+        "applied": status.applied,
+        "pending": status.pending,
+        "maintenance": crate::db::in_maintenance(),
+    })))
+}
+
+#[post("/migrations/apply")]
+async fn apply_migrations(token: AdminToken, pool: &State<DbPool>) -> JsonResult {
+    crate::db::apply_migrations(pool).await?;
+    get_migrations(token).await
+}
+
+#[get("/diagnostics/routes")]
+fn route_stats(_token: AdminToken) -> ApiResult<Html<String>> {
+    let text = AdminTemplateData::new("admin/route_stats", crate::route_stats::stats_json()).render()?;
+    Ok(Html(text))
+}
+
+#[get("/diagnostics/routes/json")]
+fn route_stats_json(_token: AdminToken) -> Json<Value> {
+    Json(crate::route_stats::stats_json())
+}
+
+#[get("/diagnostics/user-events")]
+async fn user_events(_token: AdminToken, mut conn: DbConn) -> ApiResult<Html<String>> {
+    let text = AdminTemplateData::new("admin/user_events", user_events_summary(&mut conn).await).render()?;
+    Ok(Html(text))
+}
+
+#[get("/diagnostics/user-events/json")]
+async fn user_events_json(_token: AdminToken, mut conn: DbConn) -> Json<Value> {
+    Json(user_events_summary(&mut conn).await)
+}
+
+// The totals of the personal events of all the users, the events themselves are only shown to their user
+async fn user_events_summary(conn: &mut DbConn) -> Value {
+    use chrono::{TimeDelta, Utc};
+
+    let now = Utc::now().naive_utc();
+    let last_day = now - TimeDelta::try_days(1).unwrap();
+    let last_month = now - TimeDelta::try_days(30).unwrap();
+
+    let day_counts: std::collections::HashMap<i32, i64> =
+        Event::count_personal_by_type(&last_day, conn).await.into_iter().collect();
+    let event_types: Vec<Value> = Event::count_personal_by_type(&last_month, conn)
+        .await
+        .into_iter()
+        .map(|(event_type, month_count)| {
+            json!({
+                "type": event_type,
+                "name": user_event_name(event_type),
+                "last_day": day_counts.get(&event_type).copied().unwrap_or(0),
+                "last_month": month_count,
+            })
+        })
+        .collect();
+
+    let failed_logins: Vec<Value> =
+        Event::top_personal_users_by_type(EventType::UserFailedLogIn as i32, &last_day, 10, conn)
+            .await
+            .into_iter()
+            .map(|(email, count)| json!({ "email": email, "count": count }))
+            .collect();
+
+    json!({
+        "enabled": CONFIG.user_events_enabled(),
+        "event_types": event_types,
+        "failed_logins": failed_logins,
+    })
+}
+
+fn user_event_name(event_type: i32) -> &'static str {
+    match event_type {
+        t if t == EventType::UserLoggedIn as i32 => "Logged in",
+        t if t == EventType::UserChangedPassword as i32 => "Changed the master password",
+        t if t == EventType::UserUpdated2fa as i32 => "Updated the two-step login",
+        t if t == EventType::UserDisabled2fa as i32 => "Disabled the two-step login",
+        t if t == EventType::UserRecovered2fa as i32 => "Recovered the two-step login",
+        t if t == EventType::UserFailedLogIn as i32 => "Failed login",
+        t if t == EventType::UserFailedLogIn2fa as i32 => "Failed two-step login",
+        t if t == EventType::UserClientExportedVault as i32 => "Exported the vault",
+        t if t == EventType::UserRotatedKeys as i32 => "Rotated the encryption key",
+        _ => "Other",
+    }
+}
+
 #[get("/diagnostics/config")]
 fn get_diagnostics_config(_token: AdminToken) -> Json<Value> {
     let support_json = CONFIG.get_support_json();
@@ -741,27 +1054,150 @@ fn get_diagnostics_config(_token: AdminToken) -> Json<Value> {
 }
 
 #[post("/config", data = "<data>")]
-fn post_config(data: Json<ConfigBuilder>, _token: AdminToken) -> EmptyResult {
+async fn post_config(data: Json<ConfigBuilder>, token: AdminToken, mut conn: DbConn) -> EmptyResult {
     let data: ConfigBuilder = data.into_inner();
-    CONFIG.update_config(data)
+    CONFIG.update_config(data)?;
+    log_admin_action(AdminAction::ConfigUpdated, None, &token.ip.ip, &mut conn).await;
+    Ok(())
 }
 
 #[post("/config/delete")]
-fn delete_config(_token: AdminToken) -> EmptyResult {
-    CONFIG.delete_user_config()
+async fn delete_config(token: AdminToken, mut conn: DbConn) -> EmptyResult {
+    CONFIG.delete_user_config()?;
+    log_admin_action(AdminAction::ConfigDeleted, None, &token.ip.ip, &mut conn).await;
+    Ok(())
 }
 
 #[post("/config/backup_db")]
-async fn backup_db(_token: AdminToken, mut conn: DbConn) -> EmptyResult {
-    if *CAN_BACKUP {
-        backup_database(&mut conn).await
-    } else {
-        err!("Can't back up current DB (Only SQLite supports this feature)");
+async fn backup_db(token: AdminToken, mut conn: DbConn) -> JsonResult {
+    let file_name = crate::backup::create_backup(&mut conn).await?;
+    log_admin_action(AdminAction::DatabaseBackedUp, None, &token.ip.ip, &mut conn).await;
+    Ok(Json(json!({
+        "file_name": file_name,
+    })))
+}
+
+#[post("/config/export_db")]
+async fn export_db(token: AdminToken, mut conn: DbConn) -> JsonResult {
+    let file_name = crate::archive::create_archive(&mut conn).await?;
+    log_admin_action(AdminAction::DatabaseExported, None, &token.ip.ip, &mut conn).await;
+    Ok(Json(json!({
+        "file_name": file_name,
+    })))
+}
+
+#[post("/mail_queue/<uuid>/retry")]
+async fn retry_queued_mail(uuid: &str, _token: AdminToken, mut conn: DbConn) -> EmptyResult {
+    mail::retry_dead_letter(uuid, &mut conn).await
+}
+
+#[post("/mail_queue/<uuid>/delete")]
+async fn delete_queued_mail(uuid: &str, _token: AdminToken, mut conn: DbConn) -> EmptyResult {
+    let Some(queued_mail) = QueuedMail::find_by_uuid(uuid, &mut conn).await else {
+        err!("Queued mail not found")
+    };
+    queued_mail.delete(&mut conn).await
+}
+
+// Only the latest mails are listed, the filters find the older ones
+const MAIL_LOG_LIMIT: i64 = 500;
+
+#[get("/mail_log?<recipient>&<template>&<status>")]
+async fn mail_log_overview(
+    recipient: Option<&str>,
+    template: Option<&str>,
+    status: Option<&str>,
+    _token: AdminToken,
+    mut conn: DbConn,
+) -> ApiResult<Html<String>> {
+    // The empty fields of the filter form are sent too
+    let recipient = recipient.map(str::trim).filter(|r| !r.is_empty());
+    let template = template.filter(|t| !t.is_empty());
+    let status = status.filter(|s| !s.is_empty());
+
+    let mails = MailLog::find_filtered(
+        recipient,
+        template,
+        status.and_then(MailLogStatus::from_name),
+        MAIL_LOG_LIMIT,
+        &mut conn,
+    )
+    .await;
+    let mails_json: Vec<Value> = mails.iter().map(MailLog::to_json).collect();
+
+    let page_data = json!({
+        "mails": mails_json,
+        "limit": MAIL_LOG_LIMIT,
+        "templates": MailLog::find_templates(&mut conn).await,
+        "statuses": MailLogStatus::ALL.map(MailLogStatus::name),
+        "recipient": recipient,
+        "template": template,
+        "status": status,
+        "days_retain": CONFIG.mail_log_days_retain(),
+    });
+    let text = AdminTemplateData::new("admin/mail_log", page_data).render()?;
+    Ok(Html(text))
+}
+
+#[post("/mail_log/<uuid>/resend")]
+async fn resend_logged_mail(uuid: &str, _token: AdminToken, mut conn: DbConn) -> EmptyResult {
+    mail::resend_logged_mail(uuid, &mut conn).await
+}
+
+#[post("/config/db_maintenance")]
+async fn db_maintenance(_token: AdminToken, mut conn: DbConn) -> JsonResult {
+    let run = crate::db_maintenance::run_maintenance(&mut conn, "admin").await?;
+    Ok(Json(run))
+}
+
+#[derive(Deserialize)]
+struct IconPrewarmData {
+    // Domains or URIs
+    domains: Vec<String>,
+}
+
+#[post("/icons/prewarm", data = "<data>")]
+async fn prewarm_icons(data: Json<IconPrewarmData>, _token: AdminToken, mut conn: DbConn) -> JsonResult {
+    if CONFIG.icon_service() != "internal" {
+        err!("The icons are only downloaded by the internal icon service")
     }
+    let pending = crate::api::queue_icon_prewarm(&data.into_inner().domains, &mut conn).await?;
+    Ok(Json(json!({
+        "pending": pending,
+    })))
+}
+
+#[get("/jobs")]
+async fn jobs_overview(_token: AdminToken, mut conn: DbConn) -> ApiResult<Html<String>> {
+    let text = AdminTemplateData::new("admin/jobs", crate::jobs::jobs_json(&mut conn).await).render()?;
+    Ok(Html(text))
+}
+
+#[get("/jobs/json")]
+async fn jobs_json(_token: AdminToken, mut conn: DbConn) -> Json<Value> {
+    Json(crate::jobs::jobs_json(&mut conn).await)
+}
+
+#[derive(Deserialize)]
+struct JobSettingsData {
+    enabled: bool,
+    // The schedule of the config when empty
+    schedule: Option<String>,
+}
+
+#[post("/jobs/<name>", data = "<data>")]
+async fn update_job(name: &str, data: Json<JobSettingsData>, _token: AdminToken, mut conn: DbConn) -> EmptyResult {
+    let data = data.into_inner();
+    crate::jobs::update_settings(name, data.enabled, data.schedule, &mut conn).await
+}
+
+#[post("/jobs/<name>/run")]
+fn run_job(name: &str, _token: AdminToken, pool: &State<DbPool>) -> EmptyResult {
+    crate::jobs::trigger(name, pool.inner().clone())
 }
 
 pub struct AdminToken {
-    ip: ClientIp,
+    pub(super) ip: ClientIp,
 }
 
 #[rocket::async_trait]
@@ -0,0 +1,95 @@
+//
+// The `user` and `invite` commands, to manage the users when the admin panel can't be reached
+//
+// They run against the configured database, with the server stopped, and do the same as the actions of the admin
+// panel, which are logged as done by the admin from the local host. The disabled users are logged out when their
+// clients refresh their token, there's no server to notify them.
+//
+use std::net::{IpAddr, Ipv4Addr};
+
+use chrono::NaiveDateTime;
+
+use crate::{
+    api::admin::{delete_user_account, disable_user_account, invite_new_user, remove_user_2fa},
+    db::{
+        models::{TwoFactor, User},
+        DbConn,
+    },
+    Error,
+};
+
+const DT_FMT: &str = "%Y-%m-%d %H:%M:%S";
+const LOCAL_HOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+pub enum UserCommand {
+    List,
+    Disable(String),
+    Delete(String),
+    Remove2fa(String),
+    Invite(String),
+}
+
+/// Runs the command, and returns what's printed once it's done.
+pub async fn run_user_command(command: UserCommand, conn: &mut DbConn) -> Result<String, Error> {
+    match command {
+        UserCommand::List => list_users(conn).await,
+        UserCommand::Disable(email) => {
+            let mut user = find_user(&email, conn).await?;
+            disable_user_account(&mut user, &LOCAL_HOST, conn).await?;
+            Ok(format!("Disabled {email} and deleted their devices"))
+        }
+        UserCommand::Delete(email) => {
+            let user = find_user(&email, conn).await?;
+            delete_user_account(user, &LOCAL_HOST, conn).await?;
+            Ok(format!("Deleted {email} and their vault"))
+        }
+        UserCommand::Remove2fa(email) => {
+            let mut user = find_user(&email, conn).await?;
+            remove_user_2fa(&mut user, &LOCAL_HOST, conn).await?;
+            Ok(format!("Removed the two-step login methods of {email}"))
+        }
+        UserCommand::Invite(email) => {
+            let user = invite_new_user(email, conn).await?;
+            Ok(format!("Invited {}", user.email))
+        }
+    }
+}
+
+async fn find_user(email: &str, conn: &mut DbConn) -> Result<User, Error> {
+    match User::find_by_mail(email, conn).await {
+        Some(user) => Ok(user),
+        None => err!(format!("There's no user with the email {email}")),
+    }
+}
+
+fn format_date(date: Option<NaiveDateTime>) -> String {
+    date.map_or_else(|| String::from("never"), |date| date.format(DT_FMT).to_string())
+}
+
+async fn list_users(conn: &mut DbConn) -> Result<String, Error> {
+    let mut lines =
+        vec![format!("{:<40} {:<9} {:<4} {:<20} {:<20}", "EMAIL", "STATUS", "2FA", "CREATED", "LAST ACTIVE")];
+    for user in User::get_all(conn).await {
+        let status = if !user.enabled {
+            "disabled"
+        } else if user.password_hash.is_empty() {
+            "invited"
+        } else {
+            "enabled"
+        };
+        let two_factor = if TwoFactor::find_by_user(&user.uuid, conn).await.is_empty() {
+            "no"
+        } else {
+            "yes"
+        };
+        lines.push(format!(
+            "{:<40} {status:<9} {two_factor:<4} {:<20} {:<20}",
+            user.email,
+            format_date(Some(user.created_at)),
+            format_date(user.last_active(conn).await)
+        ));
+    }
+    let count = lines.len() - 1;
+    lines.push(format!("\n{count} users"));
+    Ok(lines.join("\n"))
+}
@@ -0,0 +1,300 @@
+//
+// The setup wizard, served at `/admin/setup` while the admin token isn't set and `SETUP_WIZARD` is enabled
+//
+// The wizard is opened with the setup code printed in the log at startup. The code can only be used once, it opens a
+// session of the wizard and doesn't give access to the rest of the admin panel. A restart prints a new one.
+// The wizard shows the state of the database, and saves the domain, the SMTP settings, which can be tested with a mail,
+// and the admin token, hashed with Argon2, to `config.json`. Once the admin token is saved, the wizard isn't served
+// anymore and the admin panel works like usual.
+//
+use std::sync::Mutex;
+
+use data_encoding::BASE32_NOPAD;
+use once_cell::sync::Lazy;
+use rocket::{
+    form::Form,
+    http::{Cookie, CookieJar, SameSite, Status},
+    request::{FromRequest, Outcome, Request},
+    response::{content::RawHtml as Html, Redirect},
+    serde::json::Json,
+    Route,
+};
+use serde_json::Value;
+
+use crate::{
+    api::{
+        admin::{admin_path, log_admin_action, render_admin_login, AdminResponse, AdminTemplateData},
+        event_bus::AdminAction,
+        ApiResult, EmptyResult,
+    },
+    auth::ClientIp,
+    config::ConfigBuilder,
+    crypto,
+    db::{self, get_sql_server_version, DbConn},
+    error::Error,
+    CONFIG,
+};
+
+// Only valid for this run of the server, a restart prints a new one. It's taken by the first login to the wizard.
+static SETUP_CODE: Lazy<Mutex<Option<String>>> =
+    Lazy::new(|| Mutex::new(Some(crypto::encode_random_bytes::<10>(BASE32_NOPAD))));
+// The session of the wizard opened with the setup code
+static SETUP_SESSION: Mutex<Option<String>> = Mutex::new(None);
+
+const SETUP_COOKIE_NAME: &str = "VW_ADMIN_SETUP";
+
+pub fn routes() -> Vec<Route> {
+    routes![setup_page, setup_login, setup_domain, setup_smtp, setup_test_smtp, setup_admin_token]
+}
+
+/// Whether the wizard is served, until the admin token is set.
+pub fn is_pending() -> bool {
+    CONFIG.setup_wizard() && !CONFIG.disable_admin_token() && !CONFIG.is_admin_token_set()
+}
+
+pub fn log_setup_code() {
+    if !is_pending() {
+        return;
+    }
+    if let Some(code) = SETUP_CODE.lock().unwrap().as_deref() {
+        warn!(
+            "The admin token isn't set. Open `{}/setup` with the single-use setup code `{code}` to configure the server",
+            admin_path()
+        );
+    }
+}
+
+/// Takes the setup code if it matches, and returns the token of the new session of the wizard.
+fn take_setup_code(code: &str) -> Option<String> {
+    if !is_pending() {
+        return None;
+    }
+    let mut setup_code = SETUP_CODE.lock().unwrap();
+    if !setup_code.as_deref().is_some_and(|setup_code| crypto::ct_eq(setup_code, code.trim())) {
+        return None;
+    }
+    *setup_code = None;
+
+    let session = crypto::encode_random_bytes::<32>(BASE32_NOPAD);
+    *SETUP_SESSION.lock().unwrap() = Some(session.clone());
+    Some(session)
+}
+
+fn ensure_pending() -> EmptyResult {
+    if !is_pending() {
+        err_code!("The setup is already done", Status::NotFound.code)
+    }
+    Ok(())
+}
+
+/// The session of the wizard, only valid while the setup is pending.
+struct SetupSession {
+    ip: ClientIp,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SetupSession {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let ip = match ClientIp::from_request(request).await {
+            Outcome::Success(ip) => ip,
+            _ => err_handler!("Error getting Client IP"),
+        };
+
+        let valid = request.cookies().get(SETUP_COOKIE_NAME).is_some_and(|cookie| {
+            SETUP_SESSION.lock().unwrap().as_deref().is_some_and(|session| crypto::ct_eq(session, cookie.value()))
+        });
+        if !valid || !is_pending() {
+            return Outcome::Error((Status::Unauthorized, "Unauthorized"));
+        }
+        Outcome::Success(Self {
+            ip,
+        })
+    }
+}
+
+#[derive(Responder)]
+enum SetupResponse {
+    Page(ApiResult<Html<String>>),
+    Done(Redirect),
+}
+
+#[get("/setup")]
+async fn setup_page(session: Option<SetupSession>, mut conn: DbConn) -> SetupResponse {
+    if !is_pending() {
+        return SetupResponse::Done(Redirect::to(admin_path()));
+    }
+    if session.is_none() {
+        return SetupResponse::Page(render_admin_login(None, None));
+    }
+
+    let database = match db::migration_status().await {
+        Ok(status) => json!({
+            "type": status.backend,
+            "version": get_sql_server_version(&mut conn).await,
+            "pending_migrations": status.pending.len(),
+        }),
+        Err(e) => json!({
+            "error": e.to_string(),
+        }),
+    };
+    let page_data = json!({
+        "domain": CONFIG.domain_set().then(|| CONFIG.domain()),
+        "database": database,
+        "mail_enabled": CONFIG.mail_enabled(),
+        "smtp": {
+            "host": CONFIG.smtp_host(),
+            "port": CONFIG.smtp_port(),
+            "security": CONFIG.smtp_security(),
+            "from": CONFIG.smtp_from(),
+            "from_name": CONFIG.smtp_from_name(),
+            "username": CONFIG.smtp_username(),
+        },
+    });
+    SetupResponse::Page(AdminTemplateData::new("admin/setup", page_data).render().map(Html))
+}
+
+#[derive(FromForm)]
+struct SetupLoginForm {
+    token: String,
+}
+
+#[post("/setup", data = "<data>")]
+async fn setup_login(
+    data: Form<SetupLoginForm>,
+    cookies: &CookieJar<'_>,
+    ip: ClientIp,
+    mut conn: DbConn,
+) -> Result<Redirect, AdminResponse> {
+    if crate::ratelimit::check_limit_admin(&ip.ip).is_err() {
+        return Err(AdminResponse::TooManyRequests(render_admin_login(
+            Some("Too many requests, try again later."),
+            None,
+        )));
+    }
+
+    let Some(session) = take_setup_code(&data.token) else {
+        error!("Invalid setup code. IP: {}", ip.ip);
+        log_admin_action(AdminAction::FailedLogIn, None, &ip.ip, &mut conn).await;
+        return Err(AdminResponse::Unauthorized(render_admin_login(
+            Some("Invalid setup code. It can only be used once, restart the server to get a new one."),
+            None,
+        )));
+    };
+
+    let cookie =
+        Cookie::build((SETUP_COOKIE_NAME, session)).path(admin_path()).same_site(SameSite::Strict).http_only(true);
+    cookies.add(cookie);
+    log_admin_action(AdminAction::LoggedIn, None, &ip.ip, &mut conn).await;
+    Ok(Redirect::to(format!("{}/setup", admin_path())))
+}
+
+// Merged into the saved config, like the changes of the settings page
+async fn save(values: Value, session: &SetupSession, conn: &mut DbConn) -> EmptyResult {
+    let builder: ConfigBuilder = serde_json::from_value(values)?;
+    CONFIG.update_config_partial(builder)?;
+    log_admin_action(AdminAction::ConfigUpdated, None, &session.ip.ip, conn).await;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct DomainData {
+    domain: String,
+}
+
+#[post("/setup/domain", data = "<data>")]
+async fn setup_domain(data: Json<DomainData>, session: SetupSession, mut conn: DbConn) -> EmptyResult {
+    ensure_pending()?;
+    let domain = data.into_inner().domain.trim().trim_end_matches('/').to_string();
+    save(json!({ "domain": domain }), &session, &mut conn).await
+}
+
+#[derive(Deserialize)]
+struct SmtpData {
+    host: String,
+    port: Option<u16>,
+    security: String,
+    from: String,
+    from_name: Option<String>,
+    username: Option<String>,
+    // Empty to keep the saved one
+    password: Option<String>,
+}
+
+#[post("/setup/smtp", data = "<data>")]
+async fn setup_smtp(data: Json<SmtpData>, session: SetupSession, mut conn: DbConn) -> EmptyResult {
+    ensure_pending()?;
+    let data = data.into_inner();
+    let non_empty = |value: Option<String>| value.filter(|value| !value.trim().is_empty());
+    save(
+        json!({
+            "_enable_smtp": true,
+            "smtp_host": data.host.trim(),
+            "smtp_port": data.port,
+            "smtp_security": data.security,
+            "smtp_from": data.from.trim(),
+            "smtp_from_name": non_empty(data.from_name),
+            "smtp_username": non_empty(data.username),
+            "smtp_password": non_empty(data.password),
+        }),
+        &session,
+        &mut conn,
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct TestSmtpData {
+    email: String,
+}
+
+#[post("/setup/test/smtp", data = "<data>")]
+async fn setup_test_smtp(data: Json<TestSmtpData>, _session: SetupSession) -> EmptyResult {
+    ensure_pending()?;
+    if !CONFIG.mail_enabled() {
+        err!("Mail is not enabled")
+    }
+    crate::mail::send_test(&data.into_inner().email).await
+}
+
+#[derive(Deserialize)]
+struct AdminTokenData {
+    token: String,
+    token_confirm: String,
+}
+
+#[post("/setup/admin_token", data = "<data>")]
+async fn setup_admin_token(data: Json<AdminTokenData>, session: SetupSession, mut conn: DbConn) -> EmptyResult {
+    ensure_pending()?;
+    let data = data.into_inner();
+    if data.token.len() < 8 {
+        err!("The admin token must contain at least 8 characters")
+    }
+    if data.token != data.token_confirm {
+        err!("The admin tokens do not match")
+    }
+
+    let hash = db::run_blocking(move || hash_admin_token(&data.token)).await?;
+    save(json!({ "admin_token": hash }), &session, &mut conn).await?;
+    info!("The setup is done, the admin token is set");
+    Ok(())
+}
+
+// Like `vaultwarden hash` with its default preset
+fn hash_admin_token(token: &str) -> Result<String, Error> {
+    use argon2::{
+        password_hash::SaltString, Algorithm::Argon2id, Argon2, ParamsBuilder, PasswordHasher, Version::V0x13,
+    };
+
+    let mut argon2_params = ParamsBuilder::new();
+    argon2_params.m_cost(65540);
+    argon2_params.t_cost(3);
+    argon2_params.p_cost(4);
+    let argon2 = Argon2::new(Argon2id, V0x13, argon2_params.build().unwrap());
+    let salt = SaltString::encode_b64(&crypto::get_random_bytes::<32>()).unwrap();
+    match argon2.hash_password(token.as_bytes(), &salt) {
+        Ok(hash) => Ok(hash.to_string()),
+        Err(e) => err!("Unable to hash the admin token", e.to_string()),
+    }
+}
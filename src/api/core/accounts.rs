@@ -2,18 +2,20 @@ use crate::db::DbPool;
 use chrono::Utc;
 use rocket::serde::json::Json;
 use serde_json::Value;
+use std::collections::HashMap;
 
 use crate::{
     api::{
-        core::{log_user_event, two_factor::email},
-        register_push_device, unregister_push_device, AnonymousNotify, EmptyResult, JsonResult, JsonUpcase, Notify,
-        PasswordOrOtpData, UpdateType,
+        core::{emergency_access, log_user_event, passkeys, two_factor::email},
+        register_push_device, unregister_push_device, AnonymousNotify, ApiResult, EmptyResult, JsonResult, JsonUpcase,
+        Notify, PasswordOrOtpData, UpdateType,
     },
-    auth::{decode_delete, decode_invite, decode_verify_email, ClientHeaders, Headers},
+    auth::{decode_delete, decode_emergency_access_invite, decode_invite, decode_verify_email, ClientHeaders, Headers},
     crypto,
     db::{models::*, DbConn},
     mail,
     util::NumberOrString,
+    webhook::{self, WebhookEvent},
     CONFIG,
 };
 
@@ -49,9 +51,13 @@ pub fn routes() -> Vec<rocket::Route> {
         verify_password,
         api_key,
         rotate_api_key,
+        get_external_identities,
+        delete_external_identity,
         get_known_device,
         get_known_device_from_path,
         put_avatar,
+        get_mail_preferences,
+        put_mail_preferences,
         put_device_token,
         put_clear_device_token,
         post_clear_device_token,
@@ -119,6 +125,23 @@ async fn is_email_2fa_required(org_user_uuid: Option<String>, conn: &mut DbConn)
     false
 }
 
+// The unknown users found in the directory can be provisioned on registration, see `LDAP_PROVISIONING`
+async fn ldap_provisioning_allowed(_email: &str, _conn: &mut DbConn) -> ApiResult<bool> {
+    #[cfg(feature = "ldap")]
+    if crate::ldap::enabled() {
+        return crate::ldap::provisioning_allowed(_email, _conn).await;
+    }
+    Ok(false)
+}
+
+async fn ldap_provision_user(_user: &User, _conn: &mut DbConn) -> EmptyResult {
+    #[cfg(feature = "ldap")]
+    if crate::ldap::enabled() {
+        return crate::ldap::provision_user(_user, _conn).await;
+    }
+    Ok(())
+}
+
 #[post("/accounts/register", data = "<data>")]
 async fn register(data: JsonUpcase<RegisterData>, conn: DbConn) -> JsonResult {
     _register(data, conn).await
@@ -142,6 +165,8 @@ pub async fn _register(data: JsonUpcase<RegisterData>, mut conn: DbConn) -> Json
     enforce_password_hint_setting(&password_hint)?;
 
     let mut verified_by_invite = false;
+    let mut emergency_access_claims = None;
+    let mut provisioned = false;
 
     let mut user = match User::find_by_mail(&email, &mut conn).await {
         Some(mut user) => {
@@ -150,8 +175,18 @@ pub async fn _register(data: JsonUpcase<RegisterData>, mut conn: DbConn) -> Json
             }
 
             if let Some(token) = data.Token {
-                let claims = decode_invite(&token)?;
-                if claims.email == email {
+                // The token is either an organization invite, or an emergency access invite
+                let claims_email = match decode_invite(&token) {
+                    Ok(claims) => claims.email,
+                    Err(_) if CONFIG.emergency_access_allowed() => {
+                        let claims = decode_emergency_access_invite(&token)?;
+                        let claims_email = claims.email.clone();
+                        emergency_access_claims = Some(claims);
+                        claims_email
+                    }
+                    Err(e) => return Err(e),
+                };
+                if claims_email == email {
                     // Verify the email address when signing up via a valid invite token
                     verified_by_invite = true;
                     user.verified_at = Some(Utc::now().naive_utc());
@@ -178,7 +213,12 @@ pub async fn _register(data: JsonUpcase<RegisterData>, mut conn: DbConn) -> Json
             // Order is important here; the invitation check must come first
             // because the vaultwarden admin can invite anyone, regardless
             // of other signup restrictions.
-            if Invitation::take(&email, &mut conn).await || CONFIG.is_signup_allowed(&email) {
+            if Invitation::take(&email, &mut conn).await {
+                User::new(email.clone())
+            } else if ldap_provisioning_allowed(&email, &mut conn).await? {
+                provisioned = true;
+                User::new(email.clone())
+            } else if CONFIG.is_signup_allowed(&email) {
                 User::new(email.clone())
             } else {
                 err!("Registration not allowed or user already exists")
@@ -213,8 +253,14 @@ pub async fn _register(data: JsonUpcase<RegisterData>, mut conn: DbConn) -> Json
         user.public_key = Some(keys.PublicKey);
     }
 
+    // With the approval policy, the provisioned accounts are disabled until an admin enables them
+    if provisioned && CONFIG.ldap_provisioning().eq_ignore_ascii_case("approval") {
+        user.enabled = false;
+    }
+
     if CONFIG.mail_enabled() {
-        if CONFIG.signups_verify() && !verified_by_invite {
+        // The provisioned users always have to verify their email address, they only join the organization then
+        if (CONFIG.signups_verify() || provisioned) && !verified_by_invite {
             if let Err(e) = mail::send_welcome_must_verify(&user.email, &user.uuid).await {
                 error!("Error sending welcome email: {:#?}", e);
             }
@@ -230,10 +276,31 @@ pub async fn _register(data: JsonUpcase<RegisterData>, mut conn: DbConn) -> Json
 
     user.save(&mut conn).await?;
 
+    if provisioned {
+        info!("User {} registered from the directory, pending the verification of their email address", user.email);
+    }
+
+    webhook::send(
+        WebhookEvent::UserRegistered,
+        json!({
+            "userId": user.uuid,
+            "email": user.email,
+            "name": user.name,
+        }),
+    );
+
     // accept any open emergency access invitations
-    if !CONFIG.mail_enabled() && CONFIG.emergency_access_allowed() {
-        for mut emergency_invite in EmergencyAccess::find_all_invited_by_grantee_email(&user.email, &mut conn).await {
-            let _ = emergency_invite.accept_invite(&user.uuid, &user.email, &mut conn).await;
+    if CONFIG.emergency_access_allowed() {
+        if !CONFIG.mail_enabled() {
+            for mut emergency_invite in EmergencyAccess::find_all_invited_by_grantee_email(&user.email, &mut conn).await
+            {
+                let _ = emergency_invite.accept_invite(&user.uuid, &user.email, &mut conn).await;
+            }
+        } else if let Some(claims) = emergency_access_claims {
+            // Signing up via the invite link proves the email address, so link the pending grant right away
+            if let Err(e) = emergency_access::accept_invite_with_claims(&claims, &user, &mut conn).await {
+                error!("Error accepting emergency access invite after registration: {:#?}", e);
+            }
         }
     }
 
@@ -304,6 +371,46 @@ async fn put_avatar(data: JsonUpcase<AvatarData>, headers: Headers, mut conn: Db
     Ok(Json(user.to_json(&mut conn).await))
 }
 
+#[get("/accounts/mail-preferences")]
+async fn get_mail_preferences(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    Json(mail_preferences_json(&headers.user.uuid, &mut conn).await)
+}
+
+// The mail types are the keys, the ones which aren't in the data are left unchanged
+#[put("/accounts/mail-preferences", data = "<data>")]
+async fn put_mail_preferences(
+    data: JsonUpcase<HashMap<String, bool>>,
+    headers: Headers,
+    mut conn: DbConn,
+) -> JsonResult {
+    let data: HashMap<String, bool> = data.into_inner().data;
+
+    let mut preferences = Vec::with_capacity(data.len());
+    for (name, enabled) in data {
+        let Some(mail_type) = MailType::from_name(&name) else {
+            err!(format!("Unknown mail type `{name}`"))
+        };
+        preferences.push((mail_type, enabled));
+    }
+    for (mail_type, enabled) in preferences {
+        MailPreference::set(&headers.user.uuid, mail_type, enabled, &mut conn).await?;
+    }
+
+    Ok(Json(mail_preferences_json(&headers.user.uuid, &mut conn).await))
+}
+
+async fn mail_preferences_json(user_uuid: &str, conn: &mut DbConn) -> Value {
+    let preferences = MailPreference::find_by_user(user_uuid, conn).await;
+    let mut json = json!({
+        "Object": "mailPreferences",
+    });
+    for mail_type in MailType::ALL {
+        let enabled = preferences.iter().find(|p| p.mail_type == mail_type as i32).map_or(true, |p| p.enabled);
+        json[mail_type.name()] = Value::Bool(enabled);
+    }
+    json
+}
+
 #[get("/users/<uuid>/public-key")]
 async fn get_public_keys(uuid: &str, _headers: Headers, mut conn: DbConn) -> JsonResult {
     let user = match User::find_by_uuid(uuid, &mut conn).await {
@@ -467,6 +574,14 @@ struct UpdateResetPasswordData {
     ResetPasswordKey: String,
 }
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct UpdateWebAuthnKeyData {
+    Id: String,
+    EncryptedPublicKey: String,
+    EncryptedUserKey: String,
+}
+
 use super::ciphers::CipherData;
 use super::sends::{update_send_from_data, SendData};
 
@@ -478,6 +593,8 @@ struct KeyData {
     Sends: Vec<SendData>,
     EmergencyAccessKeys: Vec<UpdateEmergencyAccessData>,
     ResetPasswordKeys: Vec<UpdateResetPasswordData>,
+    // Only sent by the clients which support unlocking the vault with a passkey
+    WebAuthnKeys: Option<Vec<UpdateWebAuthnKeyData>>,
     Key: String,
     MasterPasswordHash: String,
     PrivateKey: String,
@@ -520,6 +637,8 @@ async fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, mut conn: D
     }
 
     // Update emergency access data
+    // Grants which are not part of the rotation are suspended after the user has been saved
+    let mut rotated_emergency_access = Vec::with_capacity(data.EmergencyAccessKeys.len());
     for emergency_access_data in data.EmergencyAccessKeys {
         let mut saved_emergency_access = match EmergencyAccess::find_by_uuid(&emergency_access_data.Id, &mut conn).await
         {
@@ -532,7 +651,9 @@ async fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, mut conn: D
         }
 
         saved_emergency_access.key_encrypted = Some(emergency_access_data.KeyEncrypted);
-        saved_emergency_access.save(&mut conn).await?
+        saved_emergency_access.key_invalid_at = None;
+        saved_emergency_access.save(&mut conn).await?;
+        rotated_emergency_access.push(saved_emergency_access.uuid);
     }
 
     // Update reset password data
@@ -549,6 +670,25 @@ async fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, mut conn: D
         user_org.save(&mut conn).await?
     }
 
+    // Update the keys of the passkeys which can unlock the vault
+    // The passkeys which are not part of the rotation can't unlock it anymore after the user has been saved
+    let mut rotated_passkeys = Vec::new();
+    for webauthn_key_data in data.WebAuthnKeys.unwrap_or_default() {
+        let mut passkey =
+            match WebAuthnCredential::find_by_uuid_and_user(&webauthn_key_data.Id, user_uuid, &mut conn).await {
+                Some(passkey) => passkey,
+                None => err!("Passkey doesn't exist"),
+            };
+        if passkey.encrypted_user_key.is_none() {
+            err!("This passkey doesn't unlock the vault")
+        }
+
+        passkey.encrypted_public_key = Some(webauthn_key_data.EncryptedPublicKey);
+        passkey.encrypted_user_key = Some(webauthn_key_data.EncryptedUserKey);
+        passkey.save(&mut conn).await?;
+        rotated_passkeys.push(passkey.uuid);
+    }
+
     // Update send data
     for send_data in data.Sends {
         let mut send = match Send::find_by_uuid(send_data.Id.as_ref().unwrap(), &mut conn).await {
@@ -576,8 +716,7 @@ async fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, mut conn: D
             // Prevent triggering cipher updates via WebSockets by settings UpdateType::None
             // The user sessions are invalidated because all the ciphers were re-encrypted and thus triggering an update could cause issues.
             // We force the users to logout after the user has been saved to try and prevent these issues.
-            update_cipher_from_data(&mut saved_cipher, cipher_data, &headers, None, &mut conn, &nt, UpdateType::None)
-                .await?
+            update_cipher_from_data(&mut saved_cipher, cipher_data, &headers, None, &mut conn, UpdateType::None).await?
         }
     }
 
@@ -589,6 +728,16 @@ async fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, mut conn: D
     user.reset_security_stamp();
 
     let save_result = user.save(&mut conn).await;
+    if save_result.is_ok() {
+        if let Err(e) = emergency_access::revoke_stale_grants(&user, &rotated_emergency_access, &mut conn).await {
+            error!("Error suspending emergency access grants after a key rotation: {:#?}", e);
+        }
+        if let Err(e) = passkeys::disable_stale_vault_unlock(&user.uuid, &rotated_passkeys, &mut conn).await {
+            error!("Error disabling the passkey vault unlock after a key rotation: {:#?}", e);
+        }
+        log_user_event(EventType::UserRotatedKeys as i32, &user.uuid, headers.device.atype, &headers.ip.ip, &mut conn)
+            .await;
+    }
 
     // Prevent logging out the client where the user requested this endpoint from.
     // If you do logout the user it will causes issues at the client side.
@@ -770,6 +919,7 @@ async fn post_verify_email_token(data: JsonUpcase<VerifyEmailTokenData>, mut con
     if claims.sub != user.uuid {
         err!("Invalid claim");
     }
+    let first_verification = user.verified_at.is_none();
     user.verified_at = Some(Utc::now().naive_utc());
     user.last_verifying_at = None;
     user.login_verify_count = 0;
@@ -777,6 +927,16 @@ async fn post_verify_email_token(data: JsonUpcase<VerifyEmailTokenData>, mut con
         error!("Error saving email verification: {:#?}", e);
     }
 
+    // The users registered from the directory join the provisioning organization once their email address is verified
+    if first_verification {
+        ldap_provision_user(&user, &mut conn).await?;
+    }
+
+    // Now that the email address is verified, link any emergency access grants still waiting for this user
+    if CONFIG.emergency_access_allowed() {
+        emergency_access::accept_all_pending_invites(&user, &mut conn).await;
+    }
+
     Ok(())
 }
 
@@ -958,12 +1118,12 @@ async fn _api_key(data: JsonUpcase<PasswordOrOtpData>, rotate: bool, headers: He
     data.validate(&user, true, &mut conn).await?;
 
     if rotate || user.api_key.is_none() {
-        user.api_key = Some(crypto::generate_api_key());
+        user.set_api_key(&crypto::generate_api_key());
         user.save(&mut conn).await.expect("Error saving API key");
     }
 
     Ok(Json(json!({
-      "ApiKey": user.api_key,
+      "ApiKey": user.api_key(),
       "RevisionDate": format_date(&user.updated_at),
       "Object": "apiKey",
     })))
@@ -979,6 +1139,40 @@ async fn rotate_api_key(data: JsonUpcase<PasswordOrOtpData>, headers: Headers, c
     _api_key(data, true, headers, conn).await
 }
 
+#[get("/accounts/external-identities")]
+async fn get_external_identities(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let identities = ExternalIdentity::find_all_by_user(&headers.user.uuid, &mut conn).await;
+    let identities_json: Vec<Value> = identities.iter().map(ExternalIdentity::to_json).collect();
+
+    Json(json!({
+        "Data": identities_json,
+        "Object": "list",
+        "ContinuationToken": null
+    }))
+}
+
+// Unlinking allows the user to be linked to another directory entry on their next login
+#[post("/accounts/external-identities/<uuid>/delete", data = "<data>")]
+async fn delete_external_identity(
+    uuid: &str,
+    data: JsonUpcase<PasswordOrOtpData>,
+    headers: Headers,
+    mut conn: DbConn,
+) -> EmptyResult {
+    let data: PasswordOrOtpData = data.into_inner().data;
+    let user = headers.user;
+
+    data.validate(&user, true, &mut conn).await?;
+
+    match ExternalIdentity::find_by_uuid_and_user(uuid, &user.uuid, &mut conn).await {
+        Some(identity) => {
+            info!("User {} unlinked the external identity {} of {}", user.email, identity.subject, identity.issuer);
+            identity.delete(&mut conn).await
+        }
+        None => err!("External identity not found"),
+    }
+}
+
 // This variant is deprecated: https://github.com/bitwarden/server/pull/2682
 #[get("/devices/knowndevice/<email>/<uuid>")]
 async fn get_known_device_from_path(email: &str, uuid: &str, mut conn: DbConn) -> JsonResult {
@@ -1051,6 +1245,7 @@ async fn put_device_token(uuid: &str, data: JsonUpcase<PushToken>, headers: Head
     let data = data.into_inner().data;
     let token = data.PushToken;
 
+    // The device of the headers can be a cached copy, saving it could undo a change made since
     let mut device = match Device::find_by_uuid_and_user(&headers.device.uuid, &headers.user.uuid, &mut conn).await {
         Some(device) => device,
         None => err!(format!("Error: device {uuid} should be present before a token can be assigned")),
@@ -1059,7 +1254,7 @@ async fn put_device_token(uuid: &str, data: JsonUpcase<PushToken>, headers: Head
     // if the device already has been registered
     if device.is_registered() {
         // check if the new token is the same as the registered token
-        if device.push_token.is_some() && device.push_token.unwrap() == token.clone() {
+        if device.push_token().is_some_and(|registered| registered == token) {
             debug!("Device {} is already registered and token is the same", uuid);
             return Ok(());
         } else {
@@ -1069,7 +1264,7 @@ async fn put_device_token(uuid: &str, data: JsonUpcase<PushToken>, headers: Head
         // clear the push_uuid
         device.push_uuid = None;
     }
-    device.push_token = Some(token);
+    device.set_push_token(&token);
     if let Err(e) = device.save(&mut conn).await {
         err!(format!("An error occurred while trying to save the device push token: {e}"));
     }
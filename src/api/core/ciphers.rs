@@ -1,4 +1,5 @@
 use std::collections::{HashMap, HashSet};
+use std::io::Write;
 
 use chrono::{NaiveDateTime, Utc};
 use num_traits::ToPrimitive;
@@ -6,16 +7,20 @@ use rocket::fs::TempFile;
 use rocket::serde::json::Json;
 use rocket::{
     form::{Form, FromForm},
-    Route,
+    Either, Route,
 };
 use serde_json::Value;
 
 use crate::util::NumberOrString;
 use crate::{
-    api::{self, core::log_event, EmptyResult, JsonResult, JsonUpcase, Notify, PasswordOrOtpData, UpdateType},
+    api::{
+        self, core::log_event, event_bus, event_bus::DomainEvent, ApiResult, EmptyResult, JsonResult, JsonUpcase,
+        Notify, PasswordOrOtpData, UpdateType,
+    },
     auth::Headers,
-    crypto,
-    db::{models::*, DbConn, DbPool},
+    compression::{AcceptEncoding, EncodedJson, JsonEncoder},
+    db::{models::*, DbConn, DbPool, DbReadConn},
+    log_context::{Phase, PhaseTimer},
     CONFIG,
 };
 
@@ -34,6 +39,7 @@ pub fn routes() -> Vec<Route> {
     // admin/non-admin implementations can be shared.
     routes![
         sync,
+        get_sync_deletions,
         get_ciphers,
         get_cipher,
         get_cipher_admin,
@@ -101,8 +107,17 @@ struct SyncData {
     exclude_domains: bool, // Default: 'false'
 }
 
+// Vaults with fewer ciphers are serialized at once, and compressed by the fairing like the other responses
+const SYNC_ENCODE_MIN_CIPHERS: usize = 1000;
+
 #[get("/sync?<data..>")]
-async fn sync(data: SyncData, headers: Headers, mut conn: DbConn) -> Json<Value> {
+async fn sync(
+    data: SyncData,
+    headers: Headers,
+    accept: AcceptEncoding,
+    mut conn: DbReadConn,
+) -> ApiResult<Either<Json<Value>, EncodedJson>> {
+    let _timer = PhaseTimer::start(Phase::Serialization);
     let user_json = headers.user.to_json(&mut conn).await;
 
     // Get all ciphers which are visible by the user
@@ -110,16 +125,7 @@ async fn sync(data: SyncData, headers: Headers, mut conn: DbConn) -> Json<Value>
 
     let cipher_sync_data = CipherSyncData::new(&headers.user.uuid, CipherSyncType::User, &mut conn).await;
 
-    // Lets generate the ciphers_json using all the gathered info
-    let mut ciphers_json = Vec::with_capacity(ciphers.len());
-    for c in ciphers {
-        ciphers_json.push(
-            c.to_json(&headers.host, &headers.user.uuid, Some(&cipher_sync_data), CipherSyncType::User, &mut conn)
-                .await,
-        );
-    }
-
-    let collections = Collection::find_by_user_uuid(headers.user.uuid.clone(), &mut conn).await;
+    let collections = Collection::find_by_user_uuid(&headers.user.uuid, &mut conn).await;
     let mut collections_json = Vec::with_capacity(collections.len());
     for c in collections {
         collections_json.push(c.to_json_details(&headers.user.uuid, Some(&cipher_sync_data), &mut conn).await);
@@ -134,27 +140,97 @@ async fn sync(data: SyncData, headers: Headers, mut conn: DbConn) -> Json<Value>
     let policies_json: Vec<Value> =
         OrgPolicy::find_confirmed_by_user(&headers.user.uuid, &mut conn).await.iter().map(OrgPolicy::to_json).collect();
 
+    let host = headers.host.clone();
+    let user_uuid = headers.user.uuid.clone();
     let domains_json = if data.exclude_domains {
         Value::Null
     } else {
         api::core::_get_eq_domains(headers, true).into_inner()
     };
 
-    Json(json!({
+    let mut sync_json = json!({
         "Profile": user_json,
         "Folders": folders_json,
         "Collections": collections_json,
         "Policies": policies_json,
-        "Ciphers": ciphers_json,
         "Domains": domains_json,
         "Sends": sends_json,
         "unofficialServer": true,
         "Object": "sync"
-    }))
+    });
+
+    if ciphers.len() < SYNC_ENCODE_MIN_CIPHERS {
+        // Lets generate the ciphers_json using all the gathered info
+        let mut ciphers_json = Vec::with_capacity(ciphers.len());
+        for c in ciphers {
+            ciphers_json
+                .push(c.to_json(&host, &user_uuid, Some(&cipher_sync_data), CipherSyncType::User, &mut conn).await);
+        }
+        sync_json["Ciphers"] = Value::Array(ciphers_json);
+        return Ok(Either::Left(Json(sync_json)));
+    }
+
+    // The large vaults are written one cipher at a time, straight into the compressor, instead of building the whole
+    // response. The other lists are small, they're written first, and the ciphers are the last member of the object.
+    let mut head = sync_json.to_string().into_bytes();
+    head.pop(); // The closing brace
+    let mut encoder = JsonEncoder::new(&accept);
+    encoder.write_all(&head)?;
+    encoder.write_all(br#","Ciphers":["#)?;
+    for (i, c) in ciphers.into_iter().enumerate() {
+        if i > 0 {
+            encoder.write_all(b",")?;
+        }
+        let cipher_json = c.to_json(&host, &user_uuid, Some(&cipher_sync_data), CipherSyncType::User, &mut conn).await;
+        serde_json::to_writer(&mut encoder, &cipher_json)?;
+    }
+    encoder.write_all(b"]}")?;
+    // The connection isn't needed anymore, don't keep it while the response is sent
+    drop(conn);
+
+    Ok(Either::Right(encoder.finish()?))
+}
+
+// The objects deleted since the last sync, so the clients can remove them from their cache without a full sync.
+// When the last sync is older than the kept tombstones, `FullSyncRequired` tells the client to do a full sync.
+#[get("/sync/deletions?<since>")]
+async fn get_sync_deletions(since: &str, headers: Headers, mut conn: DbReadConn) -> JsonResult {
+    let since = match NaiveDateTime::parse_from_str(since, "%+") {
+        // ISO 8601 format
+        Ok(since) => since,
+        Err(_) => err!("Invalid date, `since` needs to be an ISO 8601 date"),
+    };
+
+    if since < Tombstone::retained_since() {
+        return Ok(Json(json!({
+            "Data": [],
+            "FullSyncRequired": true,
+            "Object": "list",
+            "ContinuationToken": null
+        })));
+    }
+
+    let org_uuids: Vec<String> = UserOrganization::find_confirmed_by_user(&headers.user.uuid, &mut conn)
+        .await
+        .into_iter()
+        .map(|user_org| user_org.org_uuid)
+        .collect();
+    let deletions_json: Vec<Value> = Tombstone::find_visible_since(&headers.user.uuid, &org_uuids, &since, &mut conn)
+        .await
+        .iter()
+        .map(Tombstone::to_json)
+        .collect();
+
+    Ok(Json(json!({
+        "Data": deletions_json,
+        "FullSyncRequired": false,
+        "Object": "list",
+        "ContinuationToken": null
+    })))
 }
 
 #[get("/ciphers")]
-async fn get_ciphers(headers: Headers, mut conn: DbConn) -> Json<Value> {
+async fn get_ciphers(headers: Headers, mut conn: DbReadConn) -> Json<Value> {
     let ciphers = Cipher::find_by_user_visible(&headers.user.uuid, &mut conn).await;
     let cipher_sync_data = CipherSyncData::new(&headers.user.uuid, CipherSyncType::User, &mut conn).await;
 
@@ -263,25 +339,15 @@ pub struct Attachments2Data {
 
 /// Called when an org admin clones an org cipher.
 #[post("/ciphers/admin", data = "<data>")]
-async fn post_ciphers_admin(
-    data: JsonUpcase<ShareCipherData>,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify<'_>,
-) -> JsonResult {
-    post_ciphers_create(data, headers, conn, nt).await
+async fn post_ciphers_admin(data: JsonUpcase<ShareCipherData>, headers: Headers, conn: DbConn) -> JsonResult {
+    post_ciphers_create(data, headers, conn).await
 }
 
 /// Called when creating a new org-owned cipher, or cloning a cipher (whether
 /// user- or org-owned). When cloning a cipher to a user-owned cipher,
 /// `organizationId` is null.
 #[post("/ciphers/create", data = "<data>")]
-async fn post_ciphers_create(
-    data: JsonUpcase<ShareCipherData>,
-    headers: Headers,
-    mut conn: DbConn,
-    nt: Notify<'_>,
-) -> JsonResult {
+async fn post_ciphers_create(data: JsonUpcase<ShareCipherData>, headers: Headers, mut conn: DbConn) -> JsonResult {
     let mut data: ShareCipherData = data.into_inner().data;
 
     // Check if there are one more more collections selected when this cipher is part of an organization.
@@ -307,12 +373,12 @@ async fn post_ciphers_create(
     // or otherwise), we can just ignore this field entirely.
     data.Cipher.LastKnownRevisionDate = None;
 
-    share_cipher_by_uuid(&cipher.uuid, data, &headers, &mut conn, &nt).await
+    share_cipher_by_uuid(&cipher.uuid, data, &headers, &mut conn).await
 }
 
 /// Called when creating a new user-owned cipher.
 #[post("/ciphers", data = "<data>")]
-async fn post_ciphers(data: JsonUpcase<CipherData>, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> JsonResult {
+async fn post_ciphers(data: JsonUpcase<CipherData>, headers: Headers, mut conn: DbConn) -> JsonResult {
     let mut data: CipherData = data.into_inner().data;
 
     // The web/browser clients set this field to null as expected, but the
@@ -322,7 +388,7 @@ async fn post_ciphers(data: JsonUpcase<CipherData>, headers: Headers, mut conn:
     data.LastKnownRevisionDate = None;
 
     let mut cipher = Cipher::new(data.Type, data.Name.clone());
-    update_cipher_from_data(&mut cipher, data, &headers, None, &mut conn, &nt, UpdateType::SyncCipherCreate).await?;
+    update_cipher_from_data(&mut cipher, data, &headers, None, &mut conn, UpdateType::SyncCipherCreate).await?;
 
     Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, None, CipherSyncType::User, &mut conn).await))
 }
@@ -351,11 +417,10 @@ async fn enforce_personal_ownership_policy(
 
 pub async fn update_cipher_from_data(
     cipher: &mut Cipher,
-    data: CipherData,
+    mut data: CipherData,
     headers: &Headers,
     shared_to_collections: Option<Vec<String>>,
     conn: &mut DbConn,
-    nt: &Notify<'_>,
     ut: UpdateType,
 ) -> EmptyResult {
     enforce_personal_ownership_policy(Some(&data), headers, conn).await?;
@@ -363,7 +428,7 @@ pub async fn update_cipher_from_data(
     // Check that the client isn't updating an existing cipher with stale data.
     // And only perform this check when not importing ciphers, else the date/time check will fail.
     if ut != UpdateType::None {
-        if let Some(dt) = data.LastKnownRevisionDate {
+        if let Some(dt) = data.LastKnownRevisionDate.take() {
             match NaiveDateTime::parse_from_str(&dt, "%+") {
                 // ISO 8601 format
                 Err(err) => warn!("Error parsing LastKnownRevisionDate '{}': {}", dt, err),
@@ -388,7 +453,7 @@ pub async fn update_cipher_from_data(
     // Check if this cipher is being transferred from a personal to an organization vault
     let transfer_cipher = cipher.organization_uuid.is_none() && data.OrganizationId.is_some();
 
-    if let Some(org_id) = data.OrganizationId {
+    if let Some(org_id) = data.OrganizationId.take() {
         match UserOrganization::find_by_user_and_org(&headers.user.uuid, &org_id, conn).await {
             None => err!("You don't have permission to add item to organization"),
             Some(org_user) => {
@@ -424,7 +489,7 @@ pub async fn update_cipher_from_data(
     }
 
     // Modify attachments name and keys when rotating
-    if let Some(attachments) = data.Attachments2 {
+    if let Some(attachments) = data.Attachments2.take() {
         for (id, attachment) in attachments {
             let mut saved_att = match Attachment::find_by_id(&id, conn).await {
                 Some(att) => att,
@@ -452,6 +517,32 @@ pub async fn update_cipher_from_data(
         }
     }
 
+    let (folder_id, favorite) = (data.FolderId.clone(), data.Favorite);
+    set_cipher_data(cipher, data)?;
+
+    cipher.save(conn).await?;
+    cipher.move_to_folder(folder_id, &headers.user.uuid, conn).await?;
+    cipher.set_favorite(favorite, &headers.user.uuid, conn).await?;
+
+    if ut != UpdateType::None {
+        let event_type = match (&ut, transfer_cipher) {
+            (UpdateType::SyncCipherCreate, true) => EventType::CipherCreated,
+            (UpdateType::SyncCipherUpdate, true) => EventType::CipherShared,
+            (_, _) => EventType::CipherUpdated,
+        };
+        let event = DomainEvent::CipherUpdated {
+            cipher,
+            ut,
+            event_type,
+            collection_uuids: shared_to_collections,
+        };
+        event_bus::emit(event, headers, conn).await;
+    }
+    Ok(())
+}
+
+/// Sets the content of the cipher sent by the client, without saving it.
+pub fn set_cipher_data(cipher: &mut Cipher, data: CipherData) -> EmptyResult {
     // Cleanup cipher data, like removing the 'Response' key.
     // This key is somewhere generated during Javascript so no way for us this fix this.
     // Also, upstream only retrieves keys they actually want to store, and thus skip the 'Response' key.
@@ -495,40 +586,6 @@ pub async fn update_cipher_from_data(
     cipher.password_history = data.PasswordHistory.map(|f| f.to_string());
     cipher.reprompt = data.Reprompt;
 
-    cipher.save(conn).await?;
-    cipher.move_to_folder(data.FolderId, &headers.user.uuid, conn).await?;
-    cipher.set_favorite(data.Favorite, &headers.user.uuid, conn).await?;
-
-    if ut != UpdateType::None {
-        // Only log events for organizational ciphers
-        if let Some(org_uuid) = &cipher.organization_uuid {
-            let event_type = match (&ut, transfer_cipher) {
-                (UpdateType::SyncCipherCreate, true) => EventType::CipherCreated,
-                (UpdateType::SyncCipherUpdate, true) => EventType::CipherShared,
-                (_, _) => EventType::CipherUpdated,
-            };
-
-            log_event(
-                event_type as i32,
-                &cipher.uuid,
-                org_uuid,
-                &headers.user.uuid,
-                headers.device.atype,
-                &headers.ip.ip,
-                conn,
-            )
-            .await;
-        }
-        nt.send_cipher_update(
-            ut,
-            cipher,
-            &cipher.update_users_revision(conn).await,
-            &headers.device.uuid,
-            shared_to_collections,
-            conn,
-        )
-        .await;
-    }
     Ok(())
 }
 
@@ -588,7 +645,7 @@ async fn post_ciphers_import(
         cipher_data.FolderId = folder_uuid;
 
         let mut cipher = Cipher::new(cipher_data.Type, cipher_data.Name.clone());
-        update_cipher_from_data(&mut cipher, cipher_data, &headers, None, &mut conn, &nt, UpdateType::None).await?;
+        update_cipher_from_data(&mut cipher, cipher_data, &headers, None, &mut conn, UpdateType::None).await?;
     }
 
     let mut user = headers.user;
@@ -600,46 +657,22 @@ async fn post_ciphers_import(
 
 /// Called when an org admin modifies an existing org cipher.
 #[put("/ciphers/<uuid>/admin", data = "<data>")]
-async fn put_cipher_admin(
-    uuid: &str,
-    data: JsonUpcase<CipherData>,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify<'_>,
-) -> JsonResult {
-    put_cipher(uuid, data, headers, conn, nt).await
+async fn put_cipher_admin(uuid: &str, data: JsonUpcase<CipherData>, headers: Headers, conn: DbConn) -> JsonResult {
+    put_cipher(uuid, data, headers, conn).await
 }
 
 #[post("/ciphers/<uuid>/admin", data = "<data>")]
-async fn post_cipher_admin(
-    uuid: &str,
-    data: JsonUpcase<CipherData>,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify<'_>,
-) -> JsonResult {
-    post_cipher(uuid, data, headers, conn, nt).await
+async fn post_cipher_admin(uuid: &str, data: JsonUpcase<CipherData>, headers: Headers, conn: DbConn) -> JsonResult {
+    post_cipher(uuid, data, headers, conn).await
 }
 
 #[post("/ciphers/<uuid>", data = "<data>")]
-async fn post_cipher(
-    uuid: &str,
-    data: JsonUpcase<CipherData>,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify<'_>,
-) -> JsonResult {
-    put_cipher(uuid, data, headers, conn, nt).await
+async fn post_cipher(uuid: &str, data: JsonUpcase<CipherData>, headers: Headers, conn: DbConn) -> JsonResult {
+    put_cipher(uuid, data, headers, conn).await
 }
 
 #[put("/ciphers/<uuid>", data = "<data>")]
-async fn put_cipher(
-    uuid: &str,
-    data: JsonUpcase<CipherData>,
-    headers: Headers,
-    mut conn: DbConn,
-    nt: Notify<'_>,
-) -> JsonResult {
+async fn put_cipher(uuid: &str, data: JsonUpcase<CipherData>, headers: Headers, mut conn: DbConn) -> JsonResult {
     let data: CipherData = data.into_inner().data;
 
     let mut cipher = match Cipher::find_by_uuid(uuid, &mut conn).await {
@@ -656,7 +689,7 @@ async fn put_cipher(
         err!("Cipher is not write accessible")
     }
 
-    update_cipher_from_data(&mut cipher, data, &headers, None, &mut conn, &nt, UpdateType::SyncCipherUpdate).await?;
+    update_cipher_from_data(&mut cipher, data, &headers, None, &mut conn, UpdateType::SyncCipherUpdate).await?;
 
     Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, None, CipherSyncType::User, &mut conn).await))
 }
@@ -717,9 +750,8 @@ async fn put_collections_update(
     data: JsonUpcase<CollectionsAdminData>,
     headers: Headers,
     conn: DbConn,
-    nt: Notify<'_>,
 ) -> EmptyResult {
-    post_collections_admin(uuid, data, headers, conn, nt).await
+    post_collections_admin(uuid, data, headers, conn).await
 }
 
 #[post("/ciphers/<uuid>/collections", data = "<data>")]
@@ -728,9 +760,8 @@ async fn post_collections_update(
     data: JsonUpcase<CollectionsAdminData>,
     headers: Headers,
     conn: DbConn,
-    nt: Notify<'_>,
 ) -> EmptyResult {
-    post_collections_admin(uuid, data, headers, conn, nt).await
+    post_collections_admin(uuid, data, headers, conn).await
 }
 
 #[put("/ciphers/<uuid>/collections-admin", data = "<data>")]
@@ -739,9 +770,8 @@ async fn put_collections_admin(
     data: JsonUpcase<CollectionsAdminData>,
     headers: Headers,
     conn: DbConn,
-    nt: Notify<'_>,
 ) -> EmptyResult {
-    post_collections_admin(uuid, data, headers, conn, nt).await
+    post_collections_admin(uuid, data, headers, conn).await
 }
 
 #[post("/ciphers/<uuid>/collections-admin", data = "<data>")]
@@ -750,7 +780,6 @@ async fn post_collections_admin(
     data: JsonUpcase<CollectionsAdminData>,
     headers: Headers,
     mut conn: DbConn,
-    nt: Notify<'_>,
 ) -> EmptyResult {
     let data: CollectionsAdminData = data.into_inner().data;
 
@@ -786,26 +815,13 @@ async fn post_collections_admin(
         }
     }
 
-    nt.send_cipher_update(
-        UpdateType::SyncCipherUpdate,
-        &cipher,
-        &cipher.update_users_revision(&mut conn).await,
-        &headers.device.uuid,
-        Some(Vec::from_iter(posted_collections)),
-        &mut conn,
-    )
-    .await;
-
-    log_event(
-        EventType::CipherUpdatedCollections as i32,
-        &cipher.uuid,
-        &cipher.organization_uuid.unwrap(),
-        &headers.user.uuid,
-        headers.device.atype,
-        &headers.ip.ip,
-        &mut conn,
-    )
-    .await;
+    let event = DomainEvent::CipherUpdated {
+        cipher: &cipher,
+        ut: UpdateType::SyncCipherUpdate,
+        event_type: EventType::CipherUpdatedCollections,
+        collection_uuids: Some(Vec::from_iter(posted_collections)),
+    };
+    event_bus::emit(event, &headers, &mut conn).await;
 
     Ok(())
 }
@@ -823,11 +839,10 @@ async fn post_cipher_share(
     data: JsonUpcase<ShareCipherData>,
     headers: Headers,
     mut conn: DbConn,
-    nt: Notify<'_>,
 ) -> JsonResult {
     let data: ShareCipherData = data.into_inner().data;
 
-    share_cipher_by_uuid(uuid, data, &headers, &mut conn, &nt).await
+    share_cipher_by_uuid(uuid, data, &headers, &mut conn).await
 }
 
 #[put("/ciphers/<uuid>/share", data = "<data>")]
@@ -836,11 +851,10 @@ async fn put_cipher_share(
     data: JsonUpcase<ShareCipherData>,
     headers: Headers,
     mut conn: DbConn,
-    nt: Notify<'_>,
 ) -> JsonResult {
     let data: ShareCipherData = data.into_inner().data;
 
-    share_cipher_by_uuid(uuid, data, &headers, &mut conn, &nt).await
+    share_cipher_by_uuid(uuid, data, &headers, &mut conn).await
 }
 
 #[derive(Deserialize)]
@@ -855,7 +869,6 @@ async fn put_cipher_share_selected(
     data: JsonUpcase<ShareSelectedCipherData>,
     headers: Headers,
     mut conn: DbConn,
-    nt: Notify<'_>,
 ) -> EmptyResult {
     let mut data: ShareSelectedCipherData = data.into_inner().data;
 
@@ -880,7 +893,7 @@ async fn put_cipher_share_selected(
         };
 
         match shared_cipher_data.Cipher.Id.take() {
-            Some(id) => share_cipher_by_uuid(&id, shared_cipher_data, &headers, &mut conn, &nt).await?,
+            Some(id) => share_cipher_by_uuid(&id, shared_cipher_data, &headers, &mut conn).await?,
             None => err!("Request missing ids field"),
         };
     }
@@ -888,13 +901,7 @@ async fn put_cipher_share_selected(
     Ok(())
 }
 
-async fn share_cipher_by_uuid(
-    uuid: &str,
-    data: ShareCipherData,
-    headers: &Headers,
-    conn: &mut DbConn,
-    nt: &Notify<'_>,
-) -> JsonResult {
+async fn share_cipher_by_uuid(uuid: &str, data: ShareCipherData, headers: &Headers, conn: &mut DbConn) -> JsonResult {
     let mut cipher = match Cipher::find_by_uuid(uuid, conn).await {
         Some(cipher) => {
             if cipher.is_write_accessible_to_user(&headers.user.uuid, conn).await {
@@ -931,7 +938,7 @@ async fn share_cipher_by_uuid(
         UpdateType::SyncCipherCreate
     };
 
-    update_cipher_from_data(&mut cipher, data.Cipher, headers, Some(shared_to_collections), conn, nt, ut).await?;
+    update_cipher_from_data(&mut cipher, data.Cipher, headers, Some(shared_to_collections), conn, ut).await?;
 
     Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, None, CipherSyncType::User, conn).await))
 }
@@ -1000,7 +1007,9 @@ async fn post_attachment_v2(
     if file_size < 0 {
         err!("Attachment size can't be negative")
     }
-    let attachment_id = crypto::generate_attachment_id();
+    enforce_attachment_file_size_limit(file_size)?;
+
+    let attachment_id = Attachment::generate_id();
     let attachment =
         Attachment::new(attachment_id.clone(), cipher.uuid.clone(), data.FileName, file_size, Some(data.Key));
     attachment.save(&mut conn).await.expect("Error saving attachment");
@@ -1020,6 +1029,18 @@ async fn post_attachment_v2(
     })))
 }
 
+/// Rejects attachments larger than the configured `attachment_file_size_limit`.
+fn enforce_attachment_file_size_limit(size: i64) -> EmptyResult {
+    let max_size = CONFIG.attachment_file_size_limit_bytes();
+    if size > max_size {
+        err_code!(
+            format!("Attachment is too large. The maximum allowed size is {}", crate::util::get_display_size(max_size)),
+            413
+        );
+    }
+    Ok(())
+}
+
 #[derive(FromForm)]
 struct UploadData<'f> {
     key: Option<String>,
@@ -1040,7 +1061,6 @@ async fn save_attachment(
     data: Form<UploadData<'_>>,
     headers: &Headers,
     mut conn: DbConn,
-    nt: Notify<'_>,
 ) -> Result<(Cipher, DbConn), crate::error::Error> {
     let mut data = data.into_inner();
 
@@ -1050,6 +1070,7 @@ async fn save_attachment(
     if size < 0 {
         err!("Attachment size can't be negative")
     }
+    enforce_attachment_file_size_limit(size)?;
 
     let cipher = match Cipher::find_by_uuid(cipher_uuid, &mut conn).await {
         Some(cipher) => cipher,
@@ -1123,7 +1144,7 @@ async fn save_attachment(
 
     let file_id = match &attachment {
         Some(attachment) => attachment.id.clone(), // v2 API
-        None => crypto::generate_attachment_id(),  // Legacy API
+        None => Attachment::generate_id(),         // Legacy API
     };
 
     if let Some(attachment) = &mut attachment {
@@ -1178,28 +1199,13 @@ async fn save_attachment(
         data.data.move_copy_to(file_path).await?
     }
 
-    nt.send_cipher_update(
-        UpdateType::SyncCipherUpdate,
-        &cipher,
-        &cipher.update_users_revision(&mut conn).await,
-        &headers.device.uuid,
-        None,
-        &mut conn,
-    )
-    .await;
-
-    if let Some(org_uuid) = &cipher.organization_uuid {
-        log_event(
-            EventType::CipherAttachmentCreated as i32,
-            &cipher.uuid,
-            org_uuid,
-            &headers.user.uuid,
-            headers.device.atype,
-            &headers.ip.ip,
-            &mut conn,
-        )
-        .await;
-    }
+    let event = DomainEvent::CipherUpdated {
+        cipher: &cipher,
+        ut: UpdateType::SyncCipherUpdate,
+        event_type: EventType::CipherAttachmentCreated,
+        collection_uuids: None,
+    };
+    event_bus::emit(event, &headers, &mut conn).await;
 
     Ok((cipher, conn))
 }
@@ -1215,7 +1221,6 @@ async fn post_attachment_v2_data(
     data: Form<UploadData<'_>>,
     headers: Headers,
     mut conn: DbConn,
-    nt: Notify<'_>,
 ) -> EmptyResult {
     let attachment = match Attachment::find_by_id(attachment_id, &mut conn).await {
         Some(attachment) if uuid == attachment.cipher_uuid => Some(attachment),
@@ -1223,38 +1228,26 @@ async fn post_attachment_v2_data(
         None => err!("Attachment doesn't exist"),
     };
 
-    save_attachment(attachment, uuid, data, &headers, conn, nt).await?;
+    save_attachment(attachment, uuid, data, &headers, conn).await?;
 
     Ok(())
 }
 
 /// Legacy API for creating an attachment associated with a cipher.
 #[post("/ciphers/<uuid>/attachment", format = "multipart/form-data", data = "<data>")]
-async fn post_attachment(
-    uuid: &str,
-    data: Form<UploadData<'_>>,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify<'_>,
-) -> JsonResult {
+async fn post_attachment(uuid: &str, data: Form<UploadData<'_>>, headers: Headers, conn: DbConn) -> JsonResult {
     // Setting this as None signifies to save_attachment() that it should create
     // the attachment database record as well as saving the data to disk.
     let attachment = None;
 
-    let (cipher, mut conn) = save_attachment(attachment, uuid, data, &headers, conn, nt).await?;
+    let (cipher, mut conn) = save_attachment(attachment, uuid, data, &headers, conn).await?;
 
     Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, None, CipherSyncType::User, &mut conn).await))
 }
 
 #[post("/ciphers/<uuid>/attachment-admin", format = "multipart/form-data", data = "<data>")]
-async fn post_attachment_admin(
-    uuid: &str,
-    data: Form<UploadData<'_>>,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify<'_>,
-) -> JsonResult {
-    post_attachment(uuid, data, headers, conn, nt).await
+async fn post_attachment_admin(uuid: &str, data: Form<UploadData<'_>>, headers: Headers, conn: DbConn) -> JsonResult {
+    post_attachment(uuid, data, headers, conn).await
 }
 
 #[post("/ciphers/<uuid>/attachment/<attachment_id>/share", format = "multipart/form-data", data = "<data>")]
@@ -1264,169 +1257,109 @@ async fn post_attachment_share(
     data: Form<UploadData<'_>>,
     headers: Headers,
     mut conn: DbConn,
-    nt: Notify<'_>,
 ) -> JsonResult {
-    _delete_cipher_attachment_by_id(uuid, attachment_id, &headers, &mut conn, &nt).await?;
-    post_attachment(uuid, data, headers, conn, nt).await
+    _delete_cipher_attachment_by_id(uuid, attachment_id, &headers, &mut conn).await?;
+    post_attachment(uuid, data, headers, conn).await
 }
 
 #[post("/ciphers/<uuid>/attachment/<attachment_id>/delete-admin")]
-async fn delete_attachment_post_admin(
-    uuid: &str,
-    attachment_id: &str,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify<'_>,
-) -> EmptyResult {
-    delete_attachment(uuid, attachment_id, headers, conn, nt).await
+async fn delete_attachment_post_admin(uuid: &str, attachment_id: &str, headers: Headers, conn: DbConn) -> EmptyResult {
+    delete_attachment(uuid, attachment_id, headers, conn).await
 }
 
 #[post("/ciphers/<uuid>/attachment/<attachment_id>/delete")]
-async fn delete_attachment_post(
-    uuid: &str,
-    attachment_id: &str,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify<'_>,
-) -> EmptyResult {
-    delete_attachment(uuid, attachment_id, headers, conn, nt).await
+async fn delete_attachment_post(uuid: &str, attachment_id: &str, headers: Headers, conn: DbConn) -> EmptyResult {
+    delete_attachment(uuid, attachment_id, headers, conn).await
 }
 
 #[delete("/ciphers/<uuid>/attachment/<attachment_id>")]
-async fn delete_attachment(
-    uuid: &str,
-    attachment_id: &str,
-    headers: Headers,
-    mut conn: DbConn,
-    nt: Notify<'_>,
-) -> EmptyResult {
-    _delete_cipher_attachment_by_id(uuid, attachment_id, &headers, &mut conn, &nt).await
+async fn delete_attachment(uuid: &str, attachment_id: &str, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    _delete_cipher_attachment_by_id(uuid, attachment_id, &headers, &mut conn).await
 }
 
 #[delete("/ciphers/<uuid>/attachment/<attachment_id>/admin")]
-async fn delete_attachment_admin(
-    uuid: &str,
-    attachment_id: &str,
-    headers: Headers,
-    mut conn: DbConn,
-    nt: Notify<'_>,
-) -> EmptyResult {
-    _delete_cipher_attachment_by_id(uuid, attachment_id, &headers, &mut conn, &nt).await
+async fn delete_attachment_admin(uuid: &str, attachment_id: &str, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    _delete_cipher_attachment_by_id(uuid, attachment_id, &headers, &mut conn).await
 }
 
 #[post("/ciphers/<uuid>/delete")]
-async fn delete_cipher_post(uuid: &str, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
-    _delete_cipher_by_uuid(uuid, &headers, &mut conn, false, &nt).await
+async fn delete_cipher_post(uuid: &str, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    _delete_cipher_by_uuid(uuid, &headers, &mut conn, false).await
     // permanent delete
 }
 
 #[post("/ciphers/<uuid>/delete-admin")]
-async fn delete_cipher_post_admin(uuid: &str, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
-    _delete_cipher_by_uuid(uuid, &headers, &mut conn, false, &nt).await
+async fn delete_cipher_post_admin(uuid: &str, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    _delete_cipher_by_uuid(uuid, &headers, &mut conn, false).await
     // permanent delete
 }
 
 #[put("/ciphers/<uuid>/delete")]
-async fn delete_cipher_put(uuid: &str, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
-    _delete_cipher_by_uuid(uuid, &headers, &mut conn, true, &nt).await
+async fn delete_cipher_put(uuid: &str, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    _delete_cipher_by_uuid(uuid, &headers, &mut conn, true).await
     // soft delete
 }
 
 #[put("/ciphers/<uuid>/delete-admin")]
-async fn delete_cipher_put_admin(uuid: &str, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
-    _delete_cipher_by_uuid(uuid, &headers, &mut conn, true, &nt).await
+async fn delete_cipher_put_admin(uuid: &str, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    _delete_cipher_by_uuid(uuid, &headers, &mut conn, true).await
 }
 
 #[delete("/ciphers/<uuid>")]
-async fn delete_cipher(uuid: &str, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
-    _delete_cipher_by_uuid(uuid, &headers, &mut conn, false, &nt).await
+async fn delete_cipher(uuid: &str, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    _delete_cipher_by_uuid(uuid, &headers, &mut conn, false).await
     // permanent delete
 }
 
 #[delete("/ciphers/<uuid>/admin")]
-async fn delete_cipher_admin(uuid: &str, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
-    _delete_cipher_by_uuid(uuid, &headers, &mut conn, false, &nt).await
+async fn delete_cipher_admin(uuid: &str, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    _delete_cipher_by_uuid(uuid, &headers, &mut conn, false).await
     // permanent delete
 }
 
 #[delete("/ciphers", data = "<data>")]
-async fn delete_cipher_selected(
-    data: JsonUpcase<Value>,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify<'_>,
-) -> EmptyResult {
-    _delete_multiple_ciphers(data, headers, conn, false, nt).await // permanent delete
+async fn delete_cipher_selected(data: JsonUpcase<Value>, headers: Headers, conn: DbConn) -> EmptyResult {
+    _delete_multiple_ciphers(data, headers, conn, false).await // permanent delete
 }
 
 #[post("/ciphers/delete", data = "<data>")]
-async fn delete_cipher_selected_post(
-    data: JsonUpcase<Value>,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify<'_>,
-) -> EmptyResult {
-    _delete_multiple_ciphers(data, headers, conn, false, nt).await // permanent delete
+async fn delete_cipher_selected_post(data: JsonUpcase<Value>, headers: Headers, conn: DbConn) -> EmptyResult {
+    _delete_multiple_ciphers(data, headers, conn, false).await // permanent delete
 }
 
 #[put("/ciphers/delete", data = "<data>")]
-async fn delete_cipher_selected_put(
-    data: JsonUpcase<Value>,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify<'_>,
-) -> EmptyResult {
-    _delete_multiple_ciphers(data, headers, conn, true, nt).await // soft delete
+async fn delete_cipher_selected_put(data: JsonUpcase<Value>, headers: Headers, conn: DbConn) -> EmptyResult {
+    _delete_multiple_ciphers(data, headers, conn, true).await // soft delete
 }
 
 #[delete("/ciphers/admin", data = "<data>")]
-async fn delete_cipher_selected_admin(
-    data: JsonUpcase<Value>,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify<'_>,
-) -> EmptyResult {
-    _delete_multiple_ciphers(data, headers, conn, false, nt).await // permanent delete
+async fn delete_cipher_selected_admin(data: JsonUpcase<Value>, headers: Headers, conn: DbConn) -> EmptyResult {
+    _delete_multiple_ciphers(data, headers, conn, false).await // permanent delete
 }
 
 #[post("/ciphers/delete-admin", data = "<data>")]
-async fn delete_cipher_selected_post_admin(
-    data: JsonUpcase<Value>,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify<'_>,
-) -> EmptyResult {
-    _delete_multiple_ciphers(data, headers, conn, false, nt).await // permanent delete
+async fn delete_cipher_selected_post_admin(data: JsonUpcase<Value>, headers: Headers, conn: DbConn) -> EmptyResult {
+    _delete_multiple_ciphers(data, headers, conn, false).await // permanent delete
 }
 
 #[put("/ciphers/delete-admin", data = "<data>")]
-async fn delete_cipher_selected_put_admin(
-    data: JsonUpcase<Value>,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify<'_>,
-) -> EmptyResult {
-    _delete_multiple_ciphers(data, headers, conn, true, nt).await // soft delete
+async fn delete_cipher_selected_put_admin(data: JsonUpcase<Value>, headers: Headers, conn: DbConn) -> EmptyResult {
+    _delete_multiple_ciphers(data, headers, conn, true).await // soft delete
 }
 
 #[put("/ciphers/<uuid>/restore")]
-async fn restore_cipher_put(uuid: &str, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> JsonResult {
-    _restore_cipher_by_uuid(uuid, &headers, &mut conn, &nt).await
+async fn restore_cipher_put(uuid: &str, headers: Headers, mut conn: DbConn) -> JsonResult {
+    _restore_cipher_by_uuid(uuid, &headers, &mut conn).await
 }
 
 #[put("/ciphers/<uuid>/restore-admin")]
-async fn restore_cipher_put_admin(uuid: &str, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> JsonResult {
-    _restore_cipher_by_uuid(uuid, &headers, &mut conn, &nt).await
+async fn restore_cipher_put_admin(uuid: &str, headers: Headers, mut conn: DbConn) -> JsonResult {
+    _restore_cipher_by_uuid(uuid, &headers, &mut conn).await
 }
 
 #[put("/ciphers/restore", data = "<data>")]
-async fn restore_cipher_selected(
-    data: JsonUpcase<Value>,
-    headers: Headers,
-    mut conn: DbConn,
-    nt: Notify<'_>,
-) -> JsonResult {
-    _restore_multiple_ciphers(data, &headers, &mut conn, &nt).await
+async fn restore_cipher_selected(data: JsonUpcase<Value>, headers: Headers, mut conn: DbConn) -> JsonResult {
+    _restore_multiple_ciphers(data, &headers, &mut conn).await
 }
 
 #[derive(Deserialize)]
@@ -1437,14 +1370,9 @@ struct MoveCipherData {
 }
 
 #[post("/ciphers/move", data = "<data>")]
-async fn move_cipher_selected(
-    data: JsonUpcase<MoveCipherData>,
-    headers: Headers,
-    mut conn: DbConn,
-    nt: Notify<'_>,
-) -> EmptyResult {
+async fn move_cipher_selected(data: JsonUpcase<MoveCipherData>, headers: Headers, mut conn: DbConn) -> EmptyResult {
     let data = data.into_inner().data;
-    let user_uuid = headers.user.uuid;
+    let user_uuid = headers.user.uuid.clone();
 
     if let Some(ref folder_id) = data.FolderId {
         match Folder::find_by_uuid(folder_id, &mut conn).await {
@@ -1470,12 +1398,11 @@ async fn move_cipher_selected(
         // Move cipher
         cipher.move_to_folder(data.FolderId.clone(), &user_uuid, &mut conn).await?;
 
-        nt.send_cipher_update(
-            UpdateType::SyncCipherUpdate,
-            &cipher,
-            &[user_uuid.clone()],
-            &headers.device.uuid,
-            None,
+        event_bus::emit(
+            DomainEvent::CipherMoved {
+                cipher: &cipher,
+            },
+            &headers,
             &mut conn,
         )
         .await;
@@ -1485,13 +1412,8 @@ async fn move_cipher_selected(
 }
 
 #[put("/ciphers/move", data = "<data>")]
-async fn move_cipher_selected_put(
-    data: JsonUpcase<MoveCipherData>,
-    headers: Headers,
-    conn: DbConn,
-    nt: Notify<'_>,
-) -> EmptyResult {
-    move_cipher_selected(data, headers, conn, nt).await
+async fn move_cipher_selected_put(data: JsonUpcase<MoveCipherData>, headers: Headers, conn: DbConn) -> EmptyResult {
+    move_cipher_selected(data, headers, conn).await
 }
 
 #[derive(FromForm)]
@@ -1561,13 +1483,7 @@ async fn delete_all(
     }
 }
 
-async fn _delete_cipher_by_uuid(
-    uuid: &str,
-    headers: &Headers,
-    conn: &mut DbConn,
-    soft_delete: bool,
-    nt: &Notify<'_>,
-) -> EmptyResult {
+async fn _delete_cipher_by_uuid(uuid: &str, headers: &Headers, conn: &mut DbConn, soft_delete: bool) -> EmptyResult {
     let mut cipher = match Cipher::find_by_uuid(uuid, conn).await {
         Some(cipher) => cipher,
         None => err!("Cipher doesn't exist"),
@@ -1580,37 +1496,15 @@ async fn _delete_cipher_by_uuid(
     if soft_delete {
         cipher.deleted_at = Some(Utc::now().naive_utc());
         cipher.save(conn).await?;
-        nt.send_cipher_update(
-            UpdateType::SyncCipherUpdate,
-            &cipher,
-            &cipher.update_users_revision(conn).await,
-            &headers.device.uuid,
-            None,
-            conn,
-        )
-        .await;
     } else {
         cipher.delete(conn).await?;
-        nt.send_cipher_update(
-            UpdateType::SyncCipherDelete,
-            &cipher,
-            &cipher.update_users_revision(conn).await,
-            &headers.device.uuid,
-            None,
-            conn,
-        )
-        .await;
     }
 
-    if let Some(org_uuid) = cipher.organization_uuid {
-        let event_type = match soft_delete {
-            true => EventType::CipherSoftDeleted as i32,
-            false => EventType::CipherDeleted as i32,
-        };
-
-        log_event(event_type, &cipher.uuid, &org_uuid, &headers.user.uuid, headers.device.atype, &headers.ip.ip, conn)
-            .await;
-    }
+    let event = DomainEvent::CipherDeleted {
+        cipher: &cipher,
+        soft_delete,
+    };
+    event_bus::emit(event, headers, conn).await;
 
     Ok(())
 }
@@ -1620,7 +1514,6 @@ async fn _delete_multiple_ciphers(
     headers: Headers,
     mut conn: DbConn,
     soft_delete: bool,
-    nt: Notify<'_>,
 ) -> EmptyResult {
     let data: Value = data.into_inner().data;
 
@@ -1633,7 +1526,7 @@ async fn _delete_multiple_ciphers(
     };
 
     for uuid in uuids {
-        if let error @ Err(_) = _delete_cipher_by_uuid(uuid, &headers, &mut conn, soft_delete, &nt).await {
+        if let error @ Err(_) = _delete_cipher_by_uuid(uuid, &headers, &mut conn, soft_delete).await {
             return error;
         };
     }
@@ -1641,7 +1534,7 @@ async fn _delete_multiple_ciphers(
     Ok(())
 }
 
-async fn _restore_cipher_by_uuid(uuid: &str, headers: &Headers, conn: &mut DbConn, nt: &Notify<'_>) -> JsonResult {
+async fn _restore_cipher_by_uuid(uuid: &str, headers: &Headers, conn: &mut DbConn) -> JsonResult {
     let mut cipher = match Cipher::find_by_uuid(uuid, conn).await {
         Some(cipher) => cipher,
         None => err!("Cipher doesn't exist"),
@@ -1654,38 +1547,18 @@ async fn _restore_cipher_by_uuid(uuid: &str, headers: &Headers, conn: &mut DbCon
     cipher.deleted_at = None;
     cipher.save(conn).await?;
 
-    nt.send_cipher_update(
-        UpdateType::SyncCipherUpdate,
-        &cipher,
-        &cipher.update_users_revision(conn).await,
-        &headers.device.uuid,
-        None,
-        conn,
-    )
-    .await;
-
-    if let Some(org_uuid) = &cipher.organization_uuid {
-        log_event(
-            EventType::CipherRestored as i32,
-            &cipher.uuid.clone(),
-            org_uuid,
-            &headers.user.uuid,
-            headers.device.atype,
-            &headers.ip.ip,
-            conn,
-        )
-        .await;
-    }
+    let event = DomainEvent::CipherUpdated {
+        cipher: &cipher,
+        ut: UpdateType::SyncCipherUpdate,
+        event_type: EventType::CipherRestored,
+        collection_uuids: None,
+    };
+    event_bus::emit(event, headers, conn).await;
 
     Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, None, CipherSyncType::User, conn).await))
 }
 
-async fn _restore_multiple_ciphers(
-    data: JsonUpcase<Value>,
-    headers: &Headers,
-    conn: &mut DbConn,
-    nt: &Notify<'_>,
-) -> JsonResult {
+async fn _restore_multiple_ciphers(data: JsonUpcase<Value>, headers: &Headers, conn: &mut DbConn) -> JsonResult {
     let data: Value = data.into_inner().data;
 
     let uuids = match data.get("Ids") {
@@ -1698,7 +1571,7 @@ async fn _restore_multiple_ciphers(
 
     let mut ciphers: Vec<Value> = Vec::new();
     for uuid in uuids {
-        match _restore_cipher_by_uuid(uuid, headers, conn, nt).await {
+        match _restore_cipher_by_uuid(uuid, headers, conn).await {
             Ok(json) => ciphers.push(json.into_inner()),
             err => return err,
         }
@@ -1716,7 +1589,6 @@ async fn _delete_cipher_attachment_by_id(
     attachment_id: &str,
     headers: &Headers,
     conn: &mut DbConn,
-    nt: &Notify<'_>,
 ) -> EmptyResult {
     let attachment = match Attachment::find_by_id(attachment_id, conn).await {
         Some(attachment) => attachment,
@@ -1738,28 +1610,14 @@ async fn _delete_cipher_attachment_by_id(
 
     // Delete attachment
     attachment.delete(conn).await?;
-    nt.send_cipher_update(
-        UpdateType::SyncCipherUpdate,
-        &cipher,
-        &cipher.update_users_revision(conn).await,
-        &headers.device.uuid,
-        None,
-        conn,
-    )
-    .await;
-
-    if let Some(org_uuid) = cipher.organization_uuid {
-        log_event(
-            EventType::CipherAttachmentDeleted as i32,
-            &cipher.uuid,
-            &org_uuid,
-            &headers.user.uuid,
-            headers.device.atype,
-            &headers.ip.ip,
-            conn,
-        )
-        .await;
-    }
+
+    let event = DomainEvent::CipherUpdated {
+        cipher: &cipher,
+        ut: UpdateType::SyncCipherUpdate,
+        event_type: EventType::CipherAttachmentDeleted,
+        collection_uuids: None,
+    };
+    event_bus::emit(event, headers, conn).await;
     Ok(())
 }
 
@@ -1805,8 +1663,15 @@ impl CipherSyncData {
             }
         }
 
+        // Generate a HashMap with the Organization UUID as key and the UserOrganization record
+        let user_organizations: HashMap<String, UserOrganization> = UserOrganization::find_by_user(user_uuid, conn)
+            .await
+            .into_iter()
+            .map(|uo| (uo.org_uuid.clone(), uo))
+            .collect();
+
         // Generate a list of Cipher UUID's containing a Vec with one or more Attachment records
-        let user_org_uuids = UserOrganization::get_org_uuid_by_user(user_uuid, conn).await;
+        let user_org_uuids: Vec<String> = user_organizations.keys().cloned().collect();
         let attachments = Attachment::find_all_by_user_and_orgs(user_uuid, &user_org_uuids, conn).await;
         let mut cipher_attachments: HashMap<String, Vec<Attachment>> = HashMap::with_capacity(attachments.len());
         for attachment in attachments {
@@ -1821,13 +1686,6 @@ impl CipherSyncData {
             cipher_collections.entry(cipher).or_default().push(collection);
         }
 
-        // Generate a HashMap with the Organization UUID as key and the UserOrganization record
-        let user_organizations: HashMap<String, UserOrganization> = UserOrganization::find_by_user(user_uuid, conn)
-            .await
-            .into_iter()
-            .map(|uo| (uo.org_uuid.clone(), uo))
-            .collect();
-
         // Generate a HashMap with the User_Collections UUID as key and the CollectionUser record
         let user_collections: HashMap<String, CollectionUser> = CollectionUser::find_by_user(user_uuid, conn)
             .await
@@ -1835,16 +1693,22 @@ impl CipherSyncData {
             .map(|uc| (uc.collection_uuid.clone(), uc))
             .collect();
 
-        // Generate a HashMap with the collections_uuid as key and the CollectionGroup record
-        let user_collections_groups: HashMap<String, CollectionGroup> = if CONFIG.org_groups_enabled() {
-            CollectionGroup::find_by_user(user_uuid, conn)
-                .await
-                .into_iter()
-                .map(|collection_group| (collection_group.collections_uuid.clone(), collection_group))
-                .collect()
-        } else {
-            HashMap::new()
-        };
+        // Generate a HashMap with the collections_uuid as key and the CollectionGroup record.
+        // When several groups of the user give access to the same collection, the most permissive flags are kept.
+        let mut user_collections_groups: HashMap<String, CollectionGroup> = HashMap::new();
+        if CONFIG.org_groups_enabled() {
+            for collection_group in CollectionGroup::find_by_user(user_uuid, conn).await {
+                match user_collections_groups.get_mut(&collection_group.collections_uuid) {
+                    Some(existing) => {
+                        existing.read_only &= collection_group.read_only;
+                        existing.hide_passwords &= collection_group.hide_passwords;
+                    }
+                    None => {
+                        user_collections_groups.insert(collection_group.collections_uuid.clone(), collection_group);
+                    }
+                }
+            }
+        }
 
         // Get all organizations that the user has full access to via group assignment
         let user_group_full_access_for_organizations: HashSet<String> = if CONFIG.org_groups_enabled() {
@@ -0,0 +1,402 @@
+//
+// Built-in directory sync, an alternative to running the Bitwarden Directory Connector
+//
+// A background job periodically lists the members of the configured directory groups, and reconciles
+// the organization members and groups with them, using the same import as the Directory Connector:
+// new members are invited, members who left all the synced groups are revoked (or removed when
+// `DIRECTORY_SYNC_OVERWRITE_EXISTING` is enabled), and the synced groups are recreated in the organization.
+//
+// Supported directories are LDAP/Active Directory (requires the `ldap` feature and the LDAP_* settings),
+// Microsoft Entra ID (Azure AD) through the Microsoft Graph API, and Google Workspace through the Admin SDK.
+//
+use std::collections::{HashMap, HashSet};
+
+use chrono::Utc;
+use reqwest::header::AUTHORIZATION;
+use serde_json::Value;
+
+use crate::{
+    api::{
+        core::public::{import_directory, OrgImportData, OrgImportGroupData, OrgImportUserData},
+        EmptyResult,
+    },
+    db::{
+        models::{Organization, User, UserOrgStatus, UserOrganization},
+        DbConn, DbPool,
+    },
+    error::Error,
+    http_client::{get_reqwest_client, TracedSend},
+    CONFIG,
+};
+
+const AZURE_GRAPH_URL: &str = "https://graph.microsoft.com/v1.0";
+const GOOGLE_DIRECTORY_URL: &str = "https://admin.googleapis.com/admin/directory/v1";
+const GOOGLE_DIRECTORY_SCOPE: &str = "https://www.googleapis.com/auth/admin.directory.group.readonly";
+
+/// A group read from the directory.
+struct DirectoryGroup {
+    /// The id of the group in the directory, stored as the external id of the organization group
+    id: String,
+    name: String,
+    members: Vec<DirectoryMember>,
+}
+
+struct DirectoryMember {
+    /// The id of the user in the directory, stored as the external id of the organization member
+    id: String,
+    email: String,
+}
+
+/// Parses the `DIRECTORY_SYNC_ORGS` setting, a semicolon separated list of `organization id:group`.
+/// An organization can be listed multiple times to sync several groups into it.
+pub fn parse_sync_orgs(orgs: &str) -> Result<HashMap<String, Vec<String>>, String> {
+    let mut sync_orgs: HashMap<String, Vec<String>> = HashMap::new();
+    for entry in orgs.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        match entry.split_once(':') {
+            Some((org_uuid, group)) if !org_uuid.trim().is_empty() && !group.trim().is_empty() => {
+                sync_orgs.entry(org_uuid.trim().to_string()).or_default().push(group.trim().to_string());
+            }
+            _ => return Err(format!("Invalid entry `{entry}`, expected `organization id:group`")),
+        }
+    }
+    Ok(sync_orgs)
+}
+
+pub async fn directory_sync_job(pool: DbPool) {
+    debug!("Start directory_sync_job");
+    if !CONFIG._enable_directory_sync() {
+        return;
+    }
+    let (provider, sync_orgs) = match (CONFIG.directory_sync_provider(), CONFIG.directory_sync_orgs()) {
+        (Some(provider), Some(sync_orgs)) => match parse_sync_orgs(&sync_orgs) {
+            Ok(sync_orgs) => (provider.to_lowercase(), sync_orgs),
+            Err(e) => {
+                error!("Invalid `DIRECTORY_SYNC_ORGS`: {e}");
+                return;
+            }
+        },
+        _ => return,
+    };
+
+    let mut conn = match pool.get().await {
+        Ok(conn) => conn,
+        _ => {
+            error!("Failed to get DB connection while syncing the directory");
+            return;
+        }
+    };
+
+    // The token is shared by all the organizations of this run
+    let token = match provider.as_str() {
+        "azure" => azure_token().await,
+        "google" => google_token().await,
+        _ => Ok(String::new()),
+    };
+    let token = match token {
+        Ok(token) => token,
+        Err(e) => {
+            error!("Error authenticating to the directory: {e:#?}");
+            return;
+        }
+    };
+
+    for (org_uuid, groups) in sync_orgs {
+        if Organization::find_by_uuid(&org_uuid, &mut conn).await.is_none() {
+            warn!("Organization {org_uuid} from `DIRECTORY_SYNC_ORGS` not found, skipping its directory sync");
+            continue;
+        }
+        if let Err(e) = sync_org(&org_uuid, &groups, &provider, &token, &mut conn).await {
+            error!("Error syncing the directory of organization {org_uuid}: {e:#?}");
+        }
+    }
+}
+
+async fn sync_org(org_uuid: &str, groups: &[String], provider: &str, token: &str, conn: &mut DbConn) -> EmptyResult {
+    // Read every group before changing anything, a partial directory would revoke the members of the missing groups
+    let mut directory_groups = Vec::with_capacity(groups.len());
+    for group in groups {
+        let directory_group = match provider {
+            "ldap" => ldap_group(group).await?,
+            "azure" => azure_group(group, token).await?,
+            "google" => google_group(group, token).await?,
+            _ => err!(format!("Unknown directory sync provider {provider}")),
+        };
+        directory_groups.push(directory_group);
+    }
+
+    let mut members = Vec::new();
+    let mut synced_ids = HashSet::new();
+    for member in directory_groups.iter().flat_map(|g| &g.members) {
+        if synced_ids.insert(member.id.clone()) {
+            members.push(OrgImportUserData {
+                Email: member.email.to_lowercase(),
+                ExternalId: member.id.clone(),
+                Deleted: false,
+            });
+        }
+    }
+
+    // Previously synced members who left all the groups are revoked, or removed by OverwriteExisting
+    for user_org in UserOrganization::find_by_org(org_uuid, conn).await {
+        let external_id = match user_org.external_id {
+            Some(ref external_id) if !synced_ids.contains(external_id) => external_id.clone(),
+            _ => continue,
+        };
+        if user_org.status == UserOrgStatus::Revoked as i32 {
+            continue;
+        }
+        if let Some(user) = User::find_by_uuid(&user_org.user_uuid, conn).await {
+            members.push(OrgImportUserData {
+                Email: user.email,
+                ExternalId: external_id,
+                Deleted: true,
+            });
+        }
+    }
+
+    info!(
+        "Syncing {} directory members and {} groups into organization {org_uuid}",
+        synced_ids.len(),
+        directory_groups.len()
+    );
+
+    let data = OrgImportData {
+        Groups: directory_groups
+            .into_iter()
+            .map(|g| OrgImportGroupData {
+                Name: g.name,
+                ExternalId: g.id,
+                MemberExternalIds: g.members.into_iter().map(|m| m.id).collect(),
+            })
+            .collect(),
+        Members: members,
+        OverwriteExisting: CONFIG.directory_sync_overwrite_existing(),
+    };
+    import_directory(org_uuid, data, conn).await
+}
+
+//
+// LDAP/Active Directory
+//
+
+#[cfg(feature = "ldap")]
+async fn ldap_group(group_dn: &str) -> Result<DirectoryGroup, Error> {
+    let members = crate::ldap::find_group_members(group_dn).await?;
+
+    // The name of the group is the value of the first RDN of its DN, `Admins` for `cn=Admins,ou=groups,dc=example,dc=com`
+    let name = group_dn.split(',').next().and_then(|rdn| rdn.split_once('=')).map_or(group_dn, |(_, v)| v.trim());
+
+    Ok(DirectoryGroup {
+        id: group_dn.to_string(),
+        name: name.to_string(),
+        members: members
+            .into_iter()
+            .map(|m| DirectoryMember {
+                id: m.dn,
+                email: m.email,
+            })
+            .collect(),
+    })
+}
+
+#[cfg(not(feature = "ldap"))]
+async fn ldap_group(_group_dn: &str) -> Result<DirectoryGroup, Error> {
+    err!("The directory sync with LDAP requires the 'ldap' feature")
+}
+
+//
+// Microsoft Entra ID (Azure AD)
+//
+
+#[derive(Deserialize)]
+struct AccessToken {
+    access_token: String,
+}
+
+async fn azure_token() -> Result<String, Error> {
+    let tenant_id = CONFIG.directory_sync_azure_tenant_id().unwrap_or_default();
+    let client_id = CONFIG.directory_sync_azure_client_id().unwrap_or_default();
+    let client_secret = CONFIG.directory_sync_azure_client_secret().unwrap_or_default();
+
+    let params = [
+        ("grant_type", "client_credentials"),
+        ("scope", "https://graph.microsoft.com/.default"),
+        ("client_id", &client_id),
+        ("client_secret", &client_secret),
+    ];
+
+    let res = match get_reqwest_client()
+        .post(format!("https://login.microsoftonline.com/{tenant_id}/oauth2/v2.0/token"))
+        .form(&params)
+        .send_traced()
+        .await
+    {
+        Ok(r) => r,
+        Err(e) => err!(format!("Error getting a Microsoft Graph token: {e}")),
+    };
+
+    match res.error_for_status() {
+        Ok(res) => match res.json::<AccessToken>().await {
+            Ok(token) => Ok(token.access_token),
+            Err(e) => err!(format!("Unexpected Microsoft Graph token received: {e}")),
+        },
+        Err(e) => err!(format!("Error getting a Microsoft Graph token: {e}")),
+    }
+}
+
+async fn azure_group(group_id: &str, token: &str) -> Result<DirectoryGroup, Error> {
+    let group = get_json(&format!("{AZURE_GRAPH_URL}/groups/{group_id}?$select=id,displayName"), token).await?;
+    let name = group["displayName"].as_str().unwrap_or(group_id).to_string();
+
+    // The transitive members include the members of the nested groups, only the users are kept
+    let mut members = Vec::new();
+    let mut next_url = Some(format!(
+        "{AZURE_GRAPH_URL}/groups/{group_id}/transitiveMembers/microsoft.graph.user?$select=id,mail,userPrincipalName,accountEnabled&$top=999"
+    ));
+    while let Some(url) = next_url {
+        let page = get_json(&url, token).await?;
+        for user in page["value"].as_array().into_iter().flatten() {
+            if user["accountEnabled"].as_bool() == Some(false) {
+                continue;
+            }
+            let email = user["mail"].as_str().or_else(|| user["userPrincipalName"].as_str());
+            if let (Some(id), Some(email)) = (user["id"].as_str(), email) {
+                members.push(DirectoryMember {
+                    id: id.to_string(),
+                    email: email.to_string(),
+                });
+            }
+        }
+        next_url = page["@odata.nextLink"].as_str().map(String::from);
+    }
+
+    Ok(DirectoryGroup {
+        id: group_id.to_string(),
+        name,
+        members,
+    })
+}
+
+//
+// Google Workspace
+//
+
+#[derive(Deserialize)]
+struct GoogleServiceAccount {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Serialize)]
+struct GoogleTokenClaims {
+    iss: String,
+    sub: String,
+    scope: String,
+    aud: String,
+    iat: i64,
+    exp: i64,
+}
+
+// Service accounts authenticate with a signed JWT, and impersonate an administrator through the domain-wide delegation
+async fn google_token() -> Result<String, Error> {
+    let credentials_path = CONFIG.directory_sync_google_credentials().unwrap_or_default();
+    let credentials = match tokio::fs::read_to_string(&credentials_path).await {
+        Ok(credentials) => credentials,
+        Err(e) => err!(format!("Error reading the Google service account key {credentials_path}: {e}")),
+    };
+    let account: GoogleServiceAccount = match serde_json::from_str(&credentials) {
+        Ok(account) => account,
+        Err(e) => err!(format!("Invalid Google service account key {credentials_path}: {e}")),
+    };
+
+    let now = Utc::now().timestamp();
+    let claims = GoogleTokenClaims {
+        iss: account.client_email,
+        sub: CONFIG.directory_sync_google_admin_email().unwrap_or_default(),
+        scope: GOOGLE_DIRECTORY_SCOPE.to_string(),
+        aud: account.token_uri.clone(),
+        iat: now,
+        exp: now + 3600,
+    };
+    let key = match jsonwebtoken::EncodingKey::from_rsa_pem(account.private_key.as_bytes()) {
+        Ok(key) => key,
+        Err(e) => err!(format!("Invalid private key in the Google service account key: {e}")),
+    };
+    let assertion =
+        match jsonwebtoken::encode(&jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256), &claims, &key) {
+            Ok(assertion) => assertion,
+            Err(e) => err!(format!("Error signing the Google token request: {e}")),
+        };
+
+    let params = [("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"), ("assertion", &assertion)];
+    let res = match get_reqwest_client().post(&account.token_uri).form(&params).send_traced().await {
+        Ok(r) => r,
+        Err(e) => err!(format!("Error getting a Google token: {e}")),
+    };
+
+    match res.error_for_status() {
+        Ok(res) => match res.json::<AccessToken>().await {
+            Ok(token) => Ok(token.access_token),
+            Err(e) => err!(format!("Unexpected Google token received: {e}")),
+        },
+        Err(e) => err!(format!("Error getting a Google token: {e}")),
+    }
+}
+
+async fn google_group(group_key: &str, token: &str) -> Result<DirectoryGroup, Error> {
+    let group = get_json(&format!("{GOOGLE_DIRECTORY_URL}/groups/{group_key}"), token).await?;
+    let name = group["name"].as_str().unwrap_or(group_key).to_string();
+    // The id doesn't change when the email address of the group is renamed
+    let id = group["id"].as_str().unwrap_or(group_key).to_string();
+
+    let mut members = Vec::new();
+    let mut page_token: Option<String> = None;
+    loop {
+        let mut url =
+            format!("{GOOGLE_DIRECTORY_URL}/groups/{group_key}/members?includeDerivedMembership=true&maxResults=200");
+        if let Some(page_token) = &page_token {
+            url.push_str("&pageToken=");
+            url.extend(url::form_urlencoded::byte_serialize(page_token.as_bytes()));
+        }
+
+        let page = get_json(&url, token).await?;
+        for member in page["members"].as_array().into_iter().flatten() {
+            if member["type"].as_str() != Some("USER") || member["status"].as_str() == Some("SUSPENDED") {
+                continue;
+            }
+            if let (Some(id), Some(email)) = (member["id"].as_str(), member["email"].as_str()) {
+                members.push(DirectoryMember {
+                    id: id.to_string(),
+                    email: email.to_string(),
+                });
+            }
+        }
+
+        page_token = page["nextPageToken"].as_str().map(String::from);
+        if page_token.is_none() {
+            break;
+        }
+    }
+
+    Ok(DirectoryGroup {
+        id,
+        name,
+        members,
+    })
+}
+
+async fn get_json(url: &str, token: &str) -> Result<Value, Error> {
+    let res = match get_reqwest_client().get(url).header(AUTHORIZATION, format!("Bearer {token}")).send_traced().await {
+        Ok(r) => r,
+        Err(e) => err!(format!("Error querying the directory: {e}")),
+    };
+
+    match res.error_for_status() {
+        Ok(res) => match res.json::<Value>().await {
+            Ok(json) => Ok(json),
+            Err(e) => err!(format!("Unexpected response from the directory: {e}")),
+        },
+        Err(e) => err!(format!("Error querying the directory: {e}")),
+    }
+}
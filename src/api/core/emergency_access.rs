@@ -1,14 +1,18 @@
+use std::net::{IpAddr, Ipv4Addr};
+
 use chrono::{TimeDelta, Utc};
 use rocket::{serde::json::Json, Route};
 use serde_json::Value;
 
 use crate::{
     api::{
-        core::{CipherSyncData, CipherSyncType},
-        EmptyResult, JsonResult, JsonUpcase,
+        core::{log_emergency_access_event, log_event, CipherSyncData, CipherSyncType},
+        EmptyResult, JsonResult, JsonUpcase, PasswordOrOtpData,
     },
-    auth::{decode_emergency_access_invite, Headers},
+    auth::{decode_emergency_access_invite, EmergencyAccessInviteJwtClaims, Headers},
+    crypto,
     db::{models::*, DbConn, DbPool},
+    http_client::{get_reqwest_client, TracedSend},
     mail,
     util::NumberOrString,
     CONFIG,
@@ -34,6 +38,10 @@ pub fn routes() -> Vec<Route> {
         password_emergency_access,
         view_emergency_access,
         policies_emergency_access,
+        validate_emergency_access,
+        get_org_emergency_access_grantees,
+        put_org_emergency_access_enrollment,
+        delete_org_emergency_access_enrollment,
     ]
 }
 
@@ -114,27 +122,38 @@ struct EmergencyAccessUpdateData {
     Type: NumberOrString,
     WaitTimeDays: i32,
     KeyEncrypted: Option<String>,
+    ReminderIntervalHours: Option<i32>,
+    EscalationAfterHours: Option<i32>,
+    EscalationContacts: Option<Vec<String>>,
+    ScopeFolderIds: Option<Vec<String>>,
 }
 
 #[put("/emergency-access/<emer_id>", data = "<data>")]
-async fn put_emergency_access(emer_id: &str, data: JsonUpcase<EmergencyAccessUpdateData>, conn: DbConn) -> JsonResult {
-    post_emergency_access(emer_id, data, conn).await
+async fn put_emergency_access(
+    emer_id: &str,
+    data: JsonUpcase<EmergencyAccessUpdateData>,
+    headers: Headers,
+    conn: DbConn,
+) -> JsonResult {
+    post_emergency_access(emer_id, data, headers, conn).await
 }
 
 #[post("/emergency-access/<emer_id>", data = "<data>")]
 async fn post_emergency_access(
     emer_id: &str,
     data: JsonUpcase<EmergencyAccessUpdateData>,
+    headers: Headers,
     mut conn: DbConn,
 ) -> JsonResult {
     check_emergency_access_enabled()?;
 
     let data: EmergencyAccessUpdateData = data.into_inner().data;
 
-    let mut emergency_access = match EmergencyAccess::find_by_uuid(emer_id, &mut conn).await {
-        Some(emergency_access) => emergency_access,
-        None => err!("Emergency access not valid."),
-    };
+    let mut emergency_access =
+        match EmergencyAccess::find_by_uuid_and_grantor_uuid(emer_id, &headers.user.uuid, &mut conn).await {
+            Some(emergency_access) => emergency_access,
+            None => err!("Emergency access not valid."),
+        };
 
     let new_type = match EmergencyAccessType::from_str(&data.Type.into_string()) {
         Some(new_type) => new_type as i32,
@@ -146,11 +165,95 @@ async fn post_emergency_access(
     if data.KeyEncrypted.is_some() {
         emergency_access.key_encrypted = data.KeyEncrypted;
     }
+    // The official clients do not know about these settings, so the ones which aren't provided are kept
+    set_notification_schedule(
+        &mut emergency_access,
+        data.ReminderIntervalHours,
+        data.EscalationAfterHours,
+        data.EscalationContacts,
+        &headers.user.email,
+    )?;
+    if let Some(folder_ids) = data.ScopeFolderIds {
+        set_scope(&mut emergency_access, folder_ids, &mut conn).await?;
+    }
+    if emergency_access.has_scope() && emergency_access.atype != EmergencyAccessType::View as i32 {
+        err!("A limited scope is only supported for view access.")
+    }
 
     emergency_access.save(&mut conn).await?;
     Ok(Json(emergency_access.to_json()))
 }
 
+const MAX_ESCALATION_CONTACTS: usize = 5;
+
+/// Updates the reminder and escalation settings, the ones which aren't provided keep their current value.
+/// A delay of 0 hours disables the reminder or the escalation, an empty list removes the escalation contacts.
+/// Any address is accepted, so the grantors can't use this to find out which ones are registered. The escalation mails
+/// are only sent to the contacts who are users of this server with a verified email, not to arbitrary addresses.
+fn set_notification_schedule(
+    emergency_access: &mut EmergencyAccess,
+    reminder_interval_hours: Option<i32>,
+    escalation_after_hours: Option<i32>,
+    escalation_contacts: Option<Vec<String>>,
+    grantor_email: &str,
+) -> EmptyResult {
+    if matches!(reminder_interval_hours, Some(hours) if hours < 0) {
+        err!("The reminder interval can not be negative.")
+    }
+    if matches!(escalation_after_hours, Some(hours) if hours < 0) {
+        err!("The escalation delay can not be negative.")
+    }
+
+    if let Some(hours) = reminder_interval_hours {
+        emergency_access.reminder_interval_hours = Some(hours).filter(|h| *h > 0);
+    }
+    if let Some(hours) = escalation_after_hours {
+        emergency_access.escalation_after_hours = Some(hours).filter(|h| *h > 0);
+    }
+
+    if let Some(escalation_contacts) = escalation_contacts {
+        let contacts: Vec<String> =
+            escalation_contacts.iter().map(|c| c.trim().to_lowercase()).filter(|c| !c.is_empty()).collect();
+        if contacts.len() > MAX_ESCALATION_CONTACTS {
+            err!(format!("You can not add more than {MAX_ESCALATION_CONTACTS} escalation contacts."))
+        }
+        for contact in &contacts {
+            if contact == grantor_email {
+                err!("You can not set yourself as an escalation contact.")
+            }
+        }
+        emergency_access.escalation_contacts = if contacts.is_empty() {
+            None
+        } else {
+            Some(contacts.join(","))
+        };
+    }
+
+    if emergency_access.escalation_after_hours.is_some() && emergency_access.escalation_contacts.is_none() {
+        err!("An escalation delay requires at least one escalation contact.")
+    }
+    Ok(())
+}
+
+/// Limits the grant to the personal ciphers in the given folders of the grantor.
+/// An empty list removes the limitation, which gives access to the whole personal vault again.
+/// The organization ciphers are never shared through emergency access, so only the folders can limit the scope.
+async fn set_scope(emergency_access: &mut EmergencyAccess, folder_ids: Vec<String>, conn: &mut DbConn) -> EmptyResult {
+    for folder_id in &folder_ids {
+        match Folder::find_by_uuid(folder_id, conn).await {
+            Some(folder) if folder.user_uuid == emergency_access.grantor_uuid => (),
+            _ => err!(format!("Invalid folder: {folder_id}")),
+        }
+    }
+
+    emergency_access.scope_folders = if folder_ids.is_empty() {
+        None
+    } else {
+        Some(folder_ids.join(","))
+    };
+    Ok(())
+}
+
 // endregion
 
 // region delete
@@ -163,13 +266,24 @@ async fn delete_emergency_access(emer_id: &str, headers: Headers, mut conn: DbCo
 
     let emergency_access = match EmergencyAccess::find_by_uuid(emer_id, &mut conn).await {
         Some(emer) => {
-            if emer.grantor_uuid != grantor_user.uuid && emer.grantee_uuid != Some(grantor_user.uuid) {
+            if emer.grantor_uuid != grantor_user.uuid && emer.grantee_uuid.as_ref() != Some(&grantor_user.uuid) {
                 err!("Emergency access not valid.")
             }
             emer
         }
         None => err!("Emergency access not valid."),
     };
+
+    log_emergency_access_event(
+        EventType::EmergencyAccessDeleted as i32,
+        &emergency_access,
+        Some(&grantor_user.uuid),
+        Some(headers.device.atype),
+        Some(&headers.ip.ip),
+        &mut conn,
+    )
+    .await;
+
     emergency_access.delete(&mut conn).await?;
     Ok(())
 }
@@ -189,6 +303,10 @@ struct EmergencyAccessInviteData {
     Email: String,
     Type: NumberOrString,
     WaitTimeDays: i32,
+    ReminderIntervalHours: Option<i32>,
+    EscalationAfterHours: Option<i32>,
+    EscalationContacts: Option<Vec<String>>,
+    ScopeFolderIds: Option<Vec<String>>,
 }
 
 #[post("/emergency-access/invite", data = "<data>")]
@@ -250,8 +368,29 @@ async fn send_invite(data: JsonUpcase<EmergencyAccessInviteData>, headers: Heade
 
     let mut new_emergency_access =
         EmergencyAccess::new(grantor_user.uuid, grantee_user.email, emergency_access_status, new_type, wait_time_days);
+    set_notification_schedule(
+        &mut new_emergency_access,
+        data.ReminderIntervalHours,
+        data.EscalationAfterHours,
+        data.EscalationContacts,
+        &grantor_user.email,
+    )?;
+    set_scope(&mut new_emergency_access, data.ScopeFolderIds.unwrap_or_default(), &mut conn).await?;
+    if new_emergency_access.has_scope() && new_type != EmergencyAccessType::View as i32 {
+        err!("A limited scope is only supported for view access.")
+    }
     new_emergency_access.save(&mut conn).await?;
 
+    log_emergency_access_event(
+        EventType::EmergencyAccessInvited as i32,
+        &new_emergency_access,
+        Some(&grantor_user.uuid),
+        Some(headers.device.atype),
+        Some(&headers.ip.ip),
+        &mut conn,
+    )
+    .await;
+
     if CONFIG.mail_enabled() {
         mail::send_emergency_access_invite(
             &new_emergency_access.email.expect("Grantee email does not exists"),
@@ -259,11 +398,21 @@ async fn send_invite(data: JsonUpcase<EmergencyAccessInviteData>, headers: Heade
             &new_emergency_access.uuid,
             &grantor_user.name,
             &grantor_user.email,
+            new_user,
         )
         .await?;
     } else if !new_user {
         // if mail is not enabled immediately accept the invitation for existing users
         new_emergency_access.accept_invite(&grantee_user.uuid, &email, &mut conn).await?;
+        log_emergency_access_event(
+            EventType::EmergencyAccessAccepted as i32,
+            &new_emergency_access,
+            None,
+            None,
+            None,
+            &mut conn,
+        )
+        .await;
     }
 
     Ok(())
@@ -301,15 +450,25 @@ async fn resend_invite(emer_id: &str, headers: Headers, mut conn: DbConn) -> Emp
     if CONFIG.mail_enabled() {
         mail::send_emergency_access_invite(
             &email,
-            &grantor_user.uuid,
+            &grantee_user.uuid,
             &emergency_access.uuid,
             &grantor_user.name,
             &grantor_user.email,
+            grantee_user.password_hash.is_empty(),
         )
         .await?;
     } else if !grantee_user.password_hash.is_empty() {
         // accept the invitation for existing user
         emergency_access.accept_invite(&grantee_user.uuid, &email, &mut conn).await?;
+        log_emergency_access_event(
+            EventType::EmergencyAccessAccepted as i32,
+            &emergency_access,
+            None,
+            None,
+            None,
+            &mut conn,
+        )
+        .await;
     } else if CONFIG.invitations_allowed() && Invitation::find_by_mail(&email, &mut conn).await.is_none() {
         let invitation = Invitation::new(&email);
         invitation.save(&mut conn).await?;
@@ -346,30 +505,85 @@ async fn accept_invite(emer_id: &str, data: JsonUpcase<AcceptData>, headers: Hea
         None => err!("Invited user not found"),
     };
 
-    let mut emergency_access = match EmergencyAccess::find_by_uuid(emer_id, &mut conn).await {
+    if emer_id != claims.emer_id {
+        err!("Emergency access invitation error.")
+    }
+
+    accept_invite_with_claims(&claims, &grantee_user, &mut conn).await
+}
+
+/// Links the grant from an invite token to the grantee.
+/// This is used both by the accept endpoint and during registration, so accepting an already linked grant is not an error.
+pub async fn accept_invite_with_claims(
+    claims: &EmergencyAccessInviteJwtClaims,
+    grantee_user: &User,
+    conn: &mut DbConn,
+) -> EmptyResult {
+    let mut emergency_access = match EmergencyAccess::find_by_uuid(&claims.emer_id, conn).await {
         Some(emer) => emer,
         None => err!("Emergency access not valid."),
     };
 
+    if emergency_access.grantee_uuid.as_ref() == Some(&grantee_user.uuid)
+        && emergency_access.status != EmergencyAccessStatus::Invited as i32
+    {
+        return Ok(());
+    }
+
     // get grantor user to send Accepted email
-    let grantor_user = match User::find_by_uuid(&emergency_access.grantor_uuid, &mut conn).await {
+    let grantor_user = match User::find_by_uuid(&emergency_access.grantor_uuid, conn).await {
         Some(user) => user,
         None => err!("Grantor user not found."),
     };
 
-    if emer_id == claims.emer_id
-        && grantor_user.name == claims.grantor_name
-        && grantor_user.email == claims.grantor_email
-    {
-        emergency_access.accept_invite(&grantee_user.uuid, &grantee_user.email, &mut conn).await?;
+    if grantor_user.name != claims.grantor_name || grantor_user.email != claims.grantor_email {
+        err!("Emergency access invitation error.")
+    }
 
-        if CONFIG.mail_enabled() {
-            mail::send_emergency_access_invite_accepted(&grantor_user.email, &grantee_user.email).await?;
+    emergency_access.accept_invite(&grantee_user.uuid, &grantee_user.email, conn).await?;
+    log_emergency_access_event(
+        EventType::EmergencyAccessAccepted as i32,
+        &emergency_access,
+        Some(&grantee_user.uuid),
+        None,
+        None,
+        conn,
+    )
+    .await;
+
+    if CONFIG.mail_enabled() {
+        mail::send_emergency_access_invite_accepted(&grantor_user.email, &grantee_user.email).await?;
+    }
+
+    Ok(())
+}
+
+/// Accepts all open invites for a grantee, once the email address has been proven to belong to the user.
+pub async fn accept_all_pending_invites(grantee_user: &User, conn: &mut DbConn) {
+    for mut emergency_invite in EmergencyAccess::find_all_invited_by_grantee_email(&grantee_user.email, conn).await {
+        if let Err(e) = emergency_invite.accept_invite(&grantee_user.uuid, &grantee_user.email, conn).await {
+            error!("Error accepting emergency access invite {}: {:#?}", emergency_invite.uuid, e);
+            continue;
         }
+        log_emergency_access_event(
+            EventType::EmergencyAccessAccepted as i32,
+            &emergency_invite,
+            Some(&grantee_user.uuid),
+            None,
+            None,
+            conn,
+        )
+        .await;
 
-        Ok(())
-    } else {
-        err!("Emergency access invitation error.")
+        if CONFIG.mail_enabled() {
+            if let Some(grantor_user) = User::find_by_uuid(&emergency_invite.grantor_uuid, conn).await {
+                if let Err(e) =
+                    mail::send_emergency_access_invite_accepted(&grantor_user.email, &grantee_user.email).await
+                {
+                    error!("Error sending emergency access invite accepted email: {:#?}", e);
+                }
+            }
+        }
     }
 }
 
@@ -416,10 +630,22 @@ async fn confirm_emergency_access(
 
         emergency_access.status = EmergencyAccessStatus::Confirmed as i32;
         emergency_access.key_encrypted = Some(key);
+        emergency_access.key_fingerprint = grantee_user.public_key.as_deref().map(crypto::public_key_fingerprint);
+        emergency_access.key_invalid_at = None;
         emergency_access.email = None;
 
         emergency_access.save(&mut conn).await?;
 
+        log_emergency_access_event(
+            EventType::EmergencyAccessConfirmed as i32,
+            &emergency_access,
+            Some(&grantor_user.uuid),
+            Some(headers.device.atype),
+            Some(&headers.ip.ip),
+            &mut conn,
+        )
+        .await;
+
         if CONFIG.mail_enabled() {
             mail::send_emergency_access_invite_confirmed(&grantee_user.email, &grantor_user.name).await?;
         }
@@ -437,14 +663,14 @@ async fn confirm_emergency_access(
 async fn initiate_emergency_access(emer_id: &str, headers: Headers, mut conn: DbConn) -> JsonResult {
     check_emergency_access_enabled()?;
 
-    let initiating_user = headers.user;
+    let initiating_user = &headers.user;
     let mut emergency_access = match EmergencyAccess::find_by_uuid(emer_id, &mut conn).await {
         Some(emer) => emer,
         None => err!("Emergency access not valid."),
     };
 
     if emergency_access.status != EmergencyAccessStatus::Confirmed as i32
-        || emergency_access.grantee_uuid != Some(initiating_user.uuid)
+        || emergency_access.grantee_uuid.as_ref() != Some(&initiating_user.uuid)
     {
         err!("Emergency access not valid.")
     }
@@ -459,8 +685,36 @@ async fn initiate_emergency_access(emer_id: &str, headers: Headers, mut conn: Db
     emergency_access.updated_at = now;
     emergency_access.recovery_initiated_at = Some(now);
     emergency_access.last_notification_at = Some(now);
+    emergency_access.escalated_at = None;
     emergency_access.save(&mut conn).await?;
 
+    log_org_emergency_access_event(
+        &emergency_access,
+        EventType::OrganizationUserEmergencyAccessInitiated,
+        &initiating_user.uuid,
+        headers.device.atype,
+        &headers.ip.ip,
+        &mut conn,
+    )
+    .await;
+    log_emergency_access_event(
+        EventType::EmergencyAccessRecoveryInitiated as i32,
+        &emergency_access,
+        Some(&initiating_user.uuid),
+        Some(headers.device.atype),
+        Some(&headers.ip.ip),
+        &mut conn,
+    )
+    .await;
+
+    send_secondary_notification(
+        "recoveryInitiated",
+        &emergency_access,
+        &grantor_user,
+        initiating_user,
+        i64::from(emergency_access.wait_time_days),
+    );
+
     if CONFIG.mail_enabled() {
         mail::send_emergency_access_recovery_initiated(
             &grantor_user.email,
@@ -502,6 +756,25 @@ async fn approve_emergency_access(emer_id: &str, headers: Headers, mut conn: DbC
         emergency_access.status = EmergencyAccessStatus::RecoveryApproved as i32;
         emergency_access.save(&mut conn).await?;
 
+        log_org_emergency_access_event(
+            &emergency_access,
+            EventType::OrganizationUserEmergencyAccessApproved,
+            &grantor_user.uuid,
+            headers.device.atype,
+            &headers.ip.ip,
+            &mut conn,
+        )
+        .await;
+        log_emergency_access_event(
+            EventType::EmergencyAccessRecoveryApproved as i32,
+            &emergency_access,
+            Some(&grantor_user.uuid),
+            Some(headers.device.atype),
+            Some(&headers.ip.ip),
+            &mut conn,
+        )
+        .await;
+
         if CONFIG.mail_enabled() {
             mail::send_emergency_access_recovery_approved(&grantee_user.email, &grantor_user.name).await?;
         }
@@ -541,6 +814,25 @@ async fn reject_emergency_access(emer_id: &str, headers: Headers, mut conn: DbCo
         emergency_access.status = EmergencyAccessStatus::Confirmed as i32;
         emergency_access.save(&mut conn).await?;
 
+        log_org_emergency_access_event(
+            &emergency_access,
+            EventType::OrganizationUserEmergencyAccessRejected,
+            &grantor_user.uuid,
+            headers.device.atype,
+            &headers.ip.ip,
+            &mut conn,
+        )
+        .await;
+        log_emergency_access_event(
+            EventType::EmergencyAccessRecoveryRejected as i32,
+            &emergency_access,
+            Some(&grantor_user.uuid),
+            Some(headers.device.atype),
+            Some(&headers.ip.ip),
+            &mut conn,
+        )
+        .await;
+
         if CONFIG.mail_enabled() {
             mail::send_emergency_access_recovery_rejected(&grantee_user.email, &grantor_user.name).await?;
         }
@@ -567,7 +859,26 @@ async fn view_emergency_access(emer_id: &str, headers: Headers, mut conn: DbConn
         err!("Emergency access not valid.")
     }
 
-    let ciphers = Cipher::find_owned_by_user(&emergency_access.grantor_uuid, &mut conn).await;
+    log_org_emergency_access_event(
+        &emergency_access,
+        EventType::OrganizationUserEmergencyAccessUsed,
+        &headers.user.uuid,
+        headers.device.atype,
+        &headers.ip.ip,
+        &mut conn,
+    )
+    .await;
+    log_emergency_access_event(
+        EventType::EmergencyAccessViewed as i32,
+        &emergency_access,
+        Some(&headers.user.uuid),
+        Some(headers.device.atype),
+        Some(&headers.ip.ip),
+        &mut conn,
+    )
+    .await;
+
+    let ciphers = get_scoped_ciphers(&emergency_access, &mut conn).await;
     let cipher_sync_data = CipherSyncData::new(&emergency_access.grantor_uuid, CipherSyncType::User, &mut conn).await;
 
     let mut ciphers_json = Vec::with_capacity(ciphers.len());
@@ -661,6 +972,25 @@ async fn password_emergency_access(
     grantor_user.set_password(new_master_password_hash, Some(data.Key), true, None);
     grantor_user.save(&mut conn).await?;
 
+    log_org_emergency_access_event(
+        &emergency_access,
+        EventType::OrganizationUserEmergencyAccessUsed,
+        &requesting_user.uuid,
+        headers.device.atype,
+        &headers.ip.ip,
+        &mut conn,
+    )
+    .await;
+    log_emergency_access_event(
+        EventType::EmergencyAccessTakeover as i32,
+        &emergency_access,
+        Some(&requesting_user.uuid),
+        Some(headers.device.atype),
+        Some(&headers.ip.ip),
+        &mut conn,
+    )
+    .await;
+
     // Disable TwoFactor providers since they will otherwise block logins
     TwoFactor::delete_all_by_user(&grantor_user.uuid, &mut conn).await?;
 
@@ -675,6 +1005,420 @@ async fn password_emergency_access(
 
 // endregion
 
+// region organization
+
+// Members of an organization with the EmergencyAccess policy enabled can designate its owners as emergency contacts.
+// The member fetches the public keys of the owners, encrypts the user key for each of them and enrolls,
+// which creates confirmed grants using the type and wait time configured in the policy.
+
+pub fn validate_org_policy_data(data: &Option<Value>) -> EmptyResult {
+    let policy_data = match data {
+        Some(data) => match serde_json::from_value::<crate::util::UpCase<EmergencyAccessPolicyData>>(data.clone()) {
+            Ok(policy_data) => policy_data.data,
+            Err(_) => err!("Invalid emergency access policy data"),
+        },
+        None => err!("Emergency access policy data is required"),
+    };
+
+    if EmergencyAccessType::from_str(&policy_data.Type.to_string()).is_none() {
+        err!("Invalid emergency access type.")
+    }
+    if policy_data.WaitTimeDays < 1 {
+        err!("The wait time must be at least one day.")
+    }
+    Ok(())
+}
+
+async fn get_org_emergency_access_policy(
+    org_id: &str,
+    user_uuid: &str,
+    conn: &mut DbConn,
+) -> Result<(UserOrganization, EmergencyAccessPolicyData), crate::error::Error> {
+    check_emergency_access_enabled()?;
+
+    let member = match UserOrganization::find_by_user_and_org(user_uuid, org_id, conn).await {
+        Some(member) if member.status == UserOrgStatus::Confirmed as i32 => member,
+        _ => err!("User isn't a confirmed member of the organization"),
+    };
+
+    match OrgPolicy::org_emergency_access_data(org_id, conn).await {
+        Some(policy_data) => Ok((member, policy_data)),
+        None => err!("Emergency access policy not enabled"),
+    }
+}
+
+#[get("/organizations/<org_id>/emergency-access/grantees")]
+async fn get_org_emergency_access_grantees(org_id: &str, headers: Headers, mut conn: DbConn) -> JsonResult {
+    let (_, policy_data) = get_org_emergency_access_policy(org_id, &headers.user.uuid, &mut conn).await?;
+
+    let mut grantees_json = Vec::new();
+    for owner in UserOrganization::find_by_org_and_type(org_id, UserOrgType::Owner, &mut conn).await {
+        if owner.user_uuid == headers.user.uuid || owner.status != UserOrgStatus::Confirmed as i32 {
+            continue;
+        }
+        if let Some(user) = User::find_by_uuid(&owner.user_uuid, &mut conn).await {
+            grantees_json.push(json!({
+                "UserId": user.uuid,
+                "Email": user.email,
+                "Name": user.name,
+                "PublicKey": user.public_key,
+                "Object": "emergencyAccessOrganizationGrantee",
+            }));
+        }
+    }
+
+    Ok(Json(json!({
+        "Data": grantees_json,
+        "Type": policy_data.Type,
+        "WaitTimeDays": policy_data.WaitTimeDays,
+        "Object": "list",
+        "ContinuationToken": null
+    })))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OrgEmergencyAccessKeyData {
+    GranteeId: String,
+    KeyEncrypted: String,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OrgEmergencyAccessEnrollmentData {
+    Keys: Vec<OrgEmergencyAccessKeyData>,
+    MasterPasswordHash: Option<String>,
+    Otp: Option<String>,
+}
+
+#[put("/organizations/<org_id>/emergency-access/enrollment", data = "<data>")]
+async fn put_org_emergency_access_enrollment(
+    org_id: &str,
+    data: JsonUpcase<OrgEmergencyAccessEnrollmentData>,
+    headers: Headers,
+    mut conn: DbConn,
+) -> EmptyResult {
+    let (member, policy_data) = get_org_emergency_access_policy(org_id, &headers.user.uuid, &mut conn).await?;
+    let data: OrgEmergencyAccessEnrollmentData = data.into_inner().data;
+
+    // Enrolling hands out access to the whole vault, so require the member to explicitly consent
+    PasswordOrOtpData {
+        MasterPasswordHash: data.MasterPasswordHash,
+        Otp: data.Otp,
+    }
+    .validate(&headers.user, true, &mut conn)
+    .await?;
+
+    if data.Keys.is_empty() {
+        err!("At least one owner needs to be selected as emergency contact.")
+    }
+
+    for key_data in &data.Keys {
+        if key_data.GranteeId == headers.user.uuid {
+            err!("You can not set yourself as an emergency contact.")
+        }
+        match UserOrganization::find_by_user_and_org(&key_data.GranteeId, org_id, &mut conn).await {
+            Some(owner) if owner.atype == UserOrgType::Owner && owner.status == UserOrgStatus::Confirmed as i32 => (),
+            _ => err!("Emergency contacts need to be confirmed owners of the organization"),
+        }
+    }
+
+    // Replace any previous enrollment, so new owners or rotated keys are picked up
+    for ea in EmergencyAccess::find_all_by_org_and_grantor_uuid(org_id, &headers.user.uuid, &mut conn).await {
+        log_emergency_access_event(
+            EventType::EmergencyAccessDeleted as i32,
+            &ea,
+            Some(&headers.user.uuid),
+            Some(headers.device.atype),
+            Some(&headers.ip.ip),
+            &mut conn,
+        )
+        .await;
+        ea.delete(&mut conn).await?;
+    }
+
+    for key_data in data.Keys {
+        let mut new_emergency_access = EmergencyAccess::new(
+            headers.user.uuid.clone(),
+            String::new(),
+            EmergencyAccessStatus::Confirmed as i32,
+            policy_data.Type,
+            policy_data.WaitTimeDays,
+        );
+        new_emergency_access.email = None;
+        new_emergency_access.key_fingerprint = User::find_by_uuid(&key_data.GranteeId, &mut conn)
+            .await
+            .and_then(|u| u.public_key)
+            .as_deref()
+            .map(crypto::public_key_fingerprint);
+        new_emergency_access.grantee_uuid = Some(key_data.GranteeId);
+        new_emergency_access.key_encrypted = Some(key_data.KeyEncrypted);
+        new_emergency_access.org_uuid = Some(String::from(org_id));
+        new_emergency_access.save(&mut conn).await?;
+        log_emergency_access_event(
+            EventType::EmergencyAccessConfirmed as i32,
+            &new_emergency_access,
+            Some(&headers.user.uuid),
+            Some(headers.device.atype),
+            Some(&headers.ip.ip),
+            &mut conn,
+        )
+        .await;
+    }
+
+    log_event(
+        EventType::OrganizationUserEmergencyAccessEnroll as i32,
+        &member.uuid,
+        org_id,
+        &headers.user.uuid,
+        headers.device.atype,
+        &headers.ip.ip,
+        &mut conn,
+    )
+    .await;
+
+    Ok(())
+}
+
+#[delete("/organizations/<org_id>/emergency-access/enrollment")]
+async fn delete_org_emergency_access_enrollment(org_id: &str, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    check_emergency_access_enabled()?;
+
+    let member = match UserOrganization::find_by_user_and_org(&headers.user.uuid, org_id, &mut conn).await {
+        Some(member) => member,
+        None => err!("User isn't member of the organization"),
+    };
+
+    for ea in EmergencyAccess::find_all_by_org_and_grantor_uuid(org_id, &headers.user.uuid, &mut conn).await {
+        log_emergency_access_event(
+            EventType::EmergencyAccessDeleted as i32,
+            &ea,
+            Some(&headers.user.uuid),
+            Some(headers.device.atype),
+            Some(&headers.ip.ip),
+            &mut conn,
+        )
+        .await;
+        ea.delete(&mut conn).await?;
+    }
+
+    log_event(
+        EventType::OrganizationUserEmergencyAccessWithdraw as i32,
+        &member.uuid,
+        org_id,
+        &headers.user.uuid,
+        headers.device.atype,
+        &headers.ip.ip,
+        &mut conn,
+    )
+    .await;
+
+    Ok(())
+}
+
+/// Logs an organization event for grants which were created through the organization policy.
+async fn log_org_emergency_access_event(
+    emergency_access: &EmergencyAccess,
+    event_type: EventType,
+    act_user_uuid: &str,
+    device_type: i32,
+    ip: &IpAddr,
+    conn: &mut DbConn,
+) {
+    let Some(org_uuid) = emergency_access.org_uuid.as_deref() else {
+        return;
+    };
+    if let Some(member) = UserOrganization::find_by_user_and_org(&emergency_access.grantor_uuid, org_uuid, conn).await {
+        log_event(event_type as i32, &member.uuid, org_uuid, act_user_uuid, device_type, ip, conn).await;
+    }
+}
+
+// endregion
+
+// region notification
+
+/// Sends an emergency access notification as a JSON POST to the configured secondary channel, if any.
+/// The request is sent in the background and failures are only logged,
+/// so a slow or broken webhook or SMS gateway doesn't delay the requests or prevent the emails from being sent.
+fn send_secondary_notification(
+    event: &str,
+    emergency_access: &EmergencyAccess,
+    grantor_user: &User,
+    grantee_user: &User,
+    days_left: i64,
+) {
+    let Some(url) = CONFIG.emergency_access_notification_url() else {
+        return;
+    };
+
+    let payload = json!({
+        "event": event,
+        "emergencyAccessId": emergency_access.uuid,
+        "type": emergency_access.get_type_as_str(),
+        "waitTimeDays": emergency_access.wait_time_days,
+        "daysLeft": days_left,
+        "grantorName": grantor_user.name,
+        "grantorEmail": grantor_user.email,
+        "granteeName": grantee_user.name,
+        "granteeEmail": grantee_user.email,
+        "escalationContacts": emergency_access.escalation_contacts_list(),
+        "url": format!("{}/#/settings/emergency-access", CONFIG.domain()),
+    });
+
+    let mut request = get_reqwest_client().post(&url).json(&payload);
+    if let Some(token) = CONFIG.emergency_access_notification_token() {
+        request = request.bearer_auth(token);
+    }
+
+    let event = event.to_string();
+    let emer_uuid = emergency_access.uuid.clone();
+    tokio::spawn(async move {
+        match request.send_traced().await.and_then(|r| r.error_for_status()) {
+            Ok(_) => debug!("Sent emergency access {event} notification for {emer_uuid}"),
+            Err(e) => error!("Error sending emergency access {event} notification for {emer_uuid}: {e:#?}"),
+        }
+    });
+}
+
+// endregion
+
+// region validation
+
+/// Checks if the stored key material of a grant can still be used by the grantee.
+/// The server can't decrypt the key, but it can verify it is an RSA encrypted value matching the grantee's current public key.
+fn validate_key_escrow(emergency_access: &EmergencyAccess, grantee_user: Option<&User>) -> Vec<&'static str> {
+    let mut errors = Vec::new();
+
+    if emergency_access.status < EmergencyAccessStatus::Confirmed as i32 {
+        errors.push("The emergency access has not been confirmed yet");
+        return errors;
+    }
+
+    let Some(key_encrypted) = emergency_access.key_encrypted.as_deref() else {
+        errors.push("No encrypted key is stored for this emergency access");
+        return errors;
+    };
+
+    let Some(public_key) = grantee_user.and_then(|u| u.public_key.as_deref()) else {
+        errors.push("The grantee has no public key");
+        return errors;
+    };
+
+    // RSA encrypted values are stored as `<type>.<base64 data>`, optionally followed by `|<mac>`
+    // Types 3 till 6 are the RSA based encryption types the clients use
+    let encrypted_size = match key_encrypted.split_once('.') {
+        Some((enc_type, data)) if matches!(enc_type, "3" | "4" | "5" | "6") => {
+            let data = data.split('|').next().unwrap_or_default();
+            data_encoding::BASE64.decode(data.as_bytes()).ok().map(|d| d.len())
+        }
+        _ => None,
+    };
+
+    match (encrypted_size, crypto::rsa_public_key_size(public_key)) {
+        (None, _) => errors.push("The stored key is not a valid RSA encrypted value"),
+        (_, None) => errors.push("The public key of the grantee is not a valid RSA key"),
+        (Some(encrypted_size), Some(key_size)) if encrypted_size != key_size => {
+            errors.push("The stored key does not match the size of the grantee's public key")
+        }
+        _ => (),
+    }
+
+    if let Some(key_fingerprint) = &emergency_access.key_fingerprint {
+        if *key_fingerprint != crypto::public_key_fingerprint(public_key) {
+            errors.push("The grantee's keys have changed since the emergency access was confirmed");
+        }
+    }
+
+    errors
+}
+
+#[post("/emergency-access/<emer_id>/validate")]
+async fn validate_emergency_access(emer_id: &str, headers: Headers, mut conn: DbConn) -> JsonResult {
+    check_emergency_access_enabled()?;
+
+    let emergency_access = match EmergencyAccess::find_by_uuid(emer_id, &mut conn).await {
+        Some(emer) => emer,
+        None => err!("Emergency access not valid."),
+    };
+
+    if emergency_access.grantor_uuid != headers.user.uuid
+        && emergency_access.grantee_uuid.as_ref() != Some(&headers.user.uuid)
+    {
+        err!("Emergency access not valid.")
+    }
+
+    let grantee_user = match emergency_access.grantee_uuid.as_deref() {
+        Some(grantee_uuid) => User::find_by_uuid(grantee_uuid, &mut conn).await,
+        None => None,
+    };
+    let errors = validate_key_escrow(&emergency_access, grantee_user.as_ref());
+
+    Ok(Json(json!({
+        "Id": emergency_access.uuid,
+        "Valid": errors.is_empty(),
+        "Errors": errors,
+        "Object": "emergencyAccessValidation",
+    })))
+}
+
+/// Suspends the confirmed grants of a grantor which were not re-encrypted during an account key rotation.
+/// Their stored key can't be used anymore, so they are reset to the accepted state, which lets the grantor confirm them again.
+pub async fn revoke_stale_grants(grantor_user: &User, rotated_uuids: &[String], conn: &mut DbConn) -> EmptyResult {
+    let now = Utc::now().naive_utc();
+    let mut revoked_grantee_names = Vec::new();
+
+    for mut emer in EmergencyAccess::find_all_by_grantor_uuid(&grantor_user.uuid, conn).await {
+        if emer.status < EmergencyAccessStatus::Confirmed as i32 || rotated_uuids.contains(&emer.uuid) {
+            continue;
+        }
+
+        emer.status = EmergencyAccessStatus::Accepted as i32;
+        emer.key_encrypted = None;
+        emer.key_fingerprint = None;
+        emer.key_invalid_at = Some(now);
+        emer.recovery_initiated_at = None;
+        emer.last_notification_at = None;
+        emer.escalated_at = None;
+        emer.updated_at = now;
+        emer.save(conn).await?;
+
+        log_emergency_access_event(
+            EventType::EmergencyAccessRevoked as i32,
+            &emer,
+            Some(&grantor_user.uuid),
+            None,
+            None,
+            conn,
+        )
+        .await;
+
+        let Some(grantee_user) = User::find_by_uuid(emer.grantee_uuid.as_deref().unwrap_or_default(), conn).await
+        else {
+            continue;
+        };
+        info!("Suspended emergency access {} of {} after a key rotation", emer.uuid, grantor_user.email);
+
+        if CONFIG.mail_enabled() {
+            if let Err(e) = mail::send_emergency_access_revoked(&grantee_user.email, &grantor_user.name).await {
+                error!("Error sending emergency access revoked email: {:#?}", e);
+            }
+        }
+        revoked_grantee_names.push(grantee_user.name);
+    }
+
+    if CONFIG.mail_enabled() && !revoked_grantee_names.is_empty() {
+        if let Err(e) =
+            mail::send_emergency_access_reconfirm_required(&grantor_user.email, &revoked_grantee_names).await
+        {
+            error!("Error sending emergency access reconfirm required email: {:#?}", e);
+        }
+    }
+
+    Ok(())
+}
+
+// endregion
+
 #[get("/emergency-access/<emer_id>/policies")]
 async fn policies_emergency_access(emer_id: &str, headers: Headers, mut conn: DbConn) -> JsonResult {
     let requesting_user = headers.user;
@@ -702,15 +1446,39 @@ async fn policies_emergency_access(emer_id: &str, headers: Headers, mut conn: Db
     })))
 }
 
+/// Returns the ciphers the grantee is allowed to view, taking the scope of the grant into account.
+async fn get_scoped_ciphers(emergency_access: &EmergencyAccess, conn: &mut DbConn) -> Vec<Cipher> {
+    if !emergency_access.has_scope() {
+        return Cipher::find_owned_by_user(&emergency_access.grantor_uuid, conn).await;
+    }
+
+    // The folders can contain organization ciphers, which aren't shared
+    let mut ciphers: Vec<Cipher> = Vec::new();
+    for folder_id in emergency_access.scope_folders_list() {
+        ciphers.extend(Cipher::find_by_folder(&folder_id, conn).await);
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    ciphers.retain(|c| {
+        c.deleted_at.is_none()
+            && c.user_uuid.as_ref() == Some(&emergency_access.grantor_uuid)
+            && seen.insert(c.uuid.clone())
+    });
+    ciphers
+}
+
 fn is_valid_request(
     emergency_access: &EmergencyAccess,
     requesting_user_uuid: &str,
     requested_access_type: EmergencyAccessType,
 ) -> bool {
+    let requested_access_type = requested_access_type as i32;
     emergency_access.grantee_uuid.is_some()
         && emergency_access.grantee_uuid.as_ref().unwrap() == requesting_user_uuid
         && emergency_access.status == EmergencyAccessStatus::RecoveryApproved as i32
-        && emergency_access.atype == requested_access_type as i32
+        && emergency_access.atype == requested_access_type
+        // Scoped grants only give access to a part of the vault, which can't be enforced with a takeover
+        && (requested_access_type == EmergencyAccessType::View as i32 || !emergency_access.has_scope())
 }
 
 fn check_emergency_access_enabled() -> EmptyResult {
@@ -745,6 +1513,36 @@ pub async fn emergency_request_timeout_job(pool: DbPool) {
                     .await
                     .expect("Unable to update emergency access status");
 
+                log_org_emergency_access_event(
+                    &emer,
+                    EventType::OrganizationUserEmergencyAccessApproved,
+                    &emer.grantor_uuid,
+                    DeviceType::Server as i32,
+                    &IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    &mut conn,
+                )
+                .await;
+                log_emergency_access_event(
+                    EventType::EmergencyAccessRecoveryTimedOut as i32,
+                    &emer,
+                    None,
+                    None,
+                    None,
+                    &mut conn,
+                )
+                .await;
+
+                if CONFIG.emergency_access_notification_url().is_some() {
+                    let grantor_user = User::find_by_uuid(&emer.grantor_uuid, &mut conn).await;
+                    let grantee_user = match emer.grantee_uuid.as_deref() {
+                        Some(grantee_uuid) => User::find_by_uuid(grantee_uuid, &mut conn).await,
+                        None => None,
+                    };
+                    if let (Some(grantor_user), Some(grantee_user)) = (grantor_user, grantee_user) {
+                        send_secondary_notification("recoveryTimedOut", &emer, &grantor_user, &grantee_user, 0);
+                    }
+                }
+
                 if CONFIG.mail_enabled() {
                     // get grantor user to send Accepted email
                     let grantor_user =
@@ -790,42 +1588,84 @@ pub async fn emergency_notification_reminder_job(pool: DbPool) {
 
         let now = Utc::now().naive_utc();
         for mut emer in emergency_access_list {
-            // The find_all_recoveries_initiated already checks if the recovery_initiated_at is not null (None)
-            // Calculate the day before the recovery will become active
-            let final_recovery_reminder_at =
-                emer.recovery_initiated_at.unwrap() + TimeDelta::try_days(i64::from(emer.wait_time_days - 1)).unwrap();
-            // Calculate if a day has passed since the previous notification, else no notification has been sent before
-            let next_recovery_reminder_at = if let Some(last_notification_at) = emer.last_notification_at {
-                last_notification_at + TimeDelta::try_days(1).unwrap()
-            } else {
-                now
-            };
-            if final_recovery_reminder_at.le(&now) && next_recovery_reminder_at.le(&now) {
-                // Only update the last notification date
-                // Updating the whole record could cause issues when the emergency_request_timeout_job is also active
+            let reminder_due = emer.is_reminder_due(&now);
+            let escalation_due = emer.is_escalation_due(&now);
+            if !reminder_due && !escalation_due {
+                continue;
+            }
+
+            // Only update the notification dates
+            // Updating the whole record could cause issues when the emergency_request_timeout_job is also active
+            if reminder_due {
                 emer.update_last_notification_date_and_save(&now, &mut conn)
                     .await
                     .expect("Unable to update emergency access notification date");
+            }
+            if escalation_due {
+                emer.update_escalation_date_and_save(&now, &mut conn)
+                    .await
+                    .expect("Unable to update emergency access escalation date");
+            }
 
-                if CONFIG.mail_enabled() {
-                    // get grantor user to send Accepted email
-                    let grantor_user =
-                        User::find_by_uuid(&emer.grantor_uuid, &mut conn).await.expect("Grantor user not found");
+            if !CONFIG.mail_enabled() && CONFIG.emergency_access_notification_url().is_none() {
+                continue;
+            }
 
-                    // get grantee user to send Accepted email
-                    let grantee_user =
-                        User::find_by_uuid(&emer.grantee_uuid.clone().expect("Grantee user invalid"), &mut conn)
-                            .await
-                            .expect("Grantee user not found");
+            // get grantor user to send the notifications
+            let grantor_user = User::find_by_uuid(&emer.grantor_uuid, &mut conn).await.expect("Grantor user not found");
+
+            // get grantee user to send the notifications
+            let grantee_user = User::find_by_uuid(&emer.grantee_uuid.clone().expect("Grantee user invalid"), &mut conn)
+                .await
+                .expect("Grantee user not found");
+
+            let days_left = emer.days_until_recovery(&now).max(1);
 
-                    mail::send_emergency_access_recovery_reminder(
+            if reminder_due {
+                send_secondary_notification("recoveryReminder", &emer, &grantor_user, &grantee_user, days_left);
+            }
+            if escalation_due {
+                send_secondary_notification("recoveryEscalated", &emer, &grantor_user, &grantee_user, days_left);
+            }
+
+            if !CONFIG.mail_enabled() {
+                continue;
+            }
+
+            let days_left = days_left.to_string();
+            if reminder_due {
+                info!("Sending emergency access reminder for {} to grantor {}", emer.uuid, grantor_user.email);
+                mail::send_emergency_access_recovery_reminder(
+                    &grantor_user.email,
+                    &grantee_user.name,
+                    emer.get_type_as_str(),
+                    &days_left,
+                )
+                .await
+                .expect("Error on sending email");
+            }
+
+            if escalation_due {
+                for contact in emer.escalation_contacts_list() {
+                    let verified =
+                        User::find_by_mail(&contact, &mut conn).await.is_some_and(|u| u.verified_at.is_some());
+                    if !verified {
+                        debug!("Skipping the escalation of {} to {}, not a verified user", emer.uuid, contact);
+                        continue;
+                    }
+                    info!("Sending emergency access escalation for {} to {}", emer.uuid, contact);
+                    if let Err(e) = mail::send_emergency_access_recovery_escalation(
+                        &contact,
+                        &grantor_user.name,
                         &grantor_user.email,
                         &grantee_user.name,
                         emer.get_type_as_str(),
-                        "1", // This notification is only triggered one day before the activation
+                        &days_left,
                     )
                     .await
-                    .expect("Error on sending email");
+                    {
+                        error!("Error sending emergency access escalation to {}: {:#?}", contact, e);
+                    }
                 }
             }
         }
@@ -833,3 +1673,53 @@ pub async fn emergency_notification_reminder_job(pool: DbPool) {
         error!("Failed to get DB connection while searching emergency notification reminder")
     }
 }
+
+pub async fn emergency_access_validation_job(pool: DbPool) {
+    debug!("Start emergency_access_validation_job");
+    if !CONFIG.emergency_access_allowed() {
+        return;
+    }
+
+    if let Ok(mut conn) = pool.get().await {
+        let now = Utc::now().naive_utc();
+        for mut emer in EmergencyAccess::find_all_confirmed(&mut conn).await {
+            let grantee_user = match emer.grantee_uuid.as_deref() {
+                Some(grantee_uuid) => User::find_by_uuid(grantee_uuid, &mut conn).await,
+                None => None,
+            };
+            let errors = validate_key_escrow(&emer, grantee_user.as_ref());
+
+            if errors.is_empty() {
+                if emer.key_invalid_at.is_some() {
+                    emer.update_key_invalid_date_and_save(None, &mut conn)
+                        .await
+                        .expect("Unable to update emergency access key validation date");
+                }
+                continue;
+            }
+
+            // Only notify the grantor once, until the grant has been fixed
+            if emer.key_invalid_at.is_some() {
+                continue;
+            }
+            warn!("Emergency access {} has invalid key material: {}", emer.uuid, errors.join(", "));
+            emer.update_key_invalid_date_and_save(Some(now), &mut conn)
+                .await
+                .expect("Unable to update emergency access key validation date");
+
+            if CONFIG.mail_enabled() {
+                let grantor_user =
+                    User::find_by_uuid(&emer.grantor_uuid, &mut conn).await.expect("Grantor user not found");
+                let grantee_name = grantee_user.map(|u| u.name).unwrap_or_default();
+
+                if let Err(e) =
+                    mail::send_emergency_access_key_invalid(&grantor_user.email, &grantee_name, &errors).await
+                {
+                    error!("Error sending emergency access key invalid email: {:#?}", e);
+                }
+            }
+        }
+    } else {
+        error!("Failed to get DB connection while validating emergency access keys")
+    }
+}
@@ -1,15 +1,19 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDateTime};
+use data_encoding::BASE64URL_NOPAD;
 use rocket::{form::FromForm, serde::json::Json, Route};
 use serde_json::Value;
 
 use crate::{
-    api::{EmptyResult, JsonResult, JsonUpcaseVec},
+    api::{
+        event_bus::{DomainEvent, EventContext, EVENT_BUS},
+        ApiResult, EmptyResult, JsonResult, JsonUpcaseVec,
+    },
     auth::{AdminHeaders, Headers},
     db::{
-        models::{Cipher, Event, UserOrganization},
-        DbConn, DbPool,
+        models::{Cipher, EmergencyAccess, Event, EventFilter, UserOrganization},
+        DbConn, DbReadConn,
     },
     util::parse_date,
     CONFIG,
@@ -18,7 +22,14 @@ use crate::{
 /// ###############################################################################################################
 /// /api routes
 pub fn routes() -> Vec<Route> {
-    routes![get_org_events, get_cipher_events, get_user_events,]
+    routes![
+        get_org_events,
+        get_cipher_events,
+        get_user_events,
+        get_personal_events,
+        get_emergency_access_events,
+        get_emergency_access_timeline,
+    ]
 }
 
 #[derive(FromForm)]
@@ -30,37 +41,92 @@ struct EventRange {
     continuation_token: Option<String>,
 }
 
+/// The filters of the organization events. The continuation token is the cursor of the last event already returned.
+#[derive(FromForm)]
+pub(super) struct OrgEventQuery {
+    start: Option<String>,
+    end: Option<String>,
+    #[field(name = "continuationToken")]
+    pub continuation_token: Option<String>,
+    #[field(name = "actingUserId")]
+    acting_user_id: Option<String>,
+    #[field(name = "cipherId")]
+    cipher_id: Option<String>,
+    #[field(name = "type")]
+    event_types: Vec<i32>,
+}
+
+impl OrgEventQuery {
+    pub fn to_filter(&self) -> ApiResult<EventFilter> {
+        let cursor = match &self.continuation_token {
+            Some(token) => match decode_cursor(token) {
+                Some(cursor) => Some(cursor),
+                None => err!("Invalid continuation token"),
+            },
+            None => None,
+        };
+        Ok(EventFilter {
+            start: self.start.as_deref().map(parse_query_date).transpose()?,
+            end: self.end.as_deref().map(parse_query_date).transpose()?,
+            act_user_uuid: self.acting_user_id.clone(),
+            cipher_uuid: self.cipher_id.clone(),
+            event_types: self.event_types.clone(),
+            cursor,
+        })
+    }
+}
+
+fn parse_query_date(date: &str) -> ApiResult<NaiveDateTime> {
+    match DateTime::parse_from_rfc3339(date) {
+        Ok(date) => Ok(date.naive_utc()),
+        Err(_) => err!(format!("Invalid date `{date}`")),
+    }
+}
+
+/// The cursor of an event, its position in the pages: its date, and its UUID for the events with the same date.
+pub(super) fn encode_cursor(event: &Event) -> String {
+    let cursor = format!("{}_{}", event.event_date.and_utc().timestamp_micros(), event.uuid);
+    BASE64URL_NOPAD.encode(cursor.as_bytes())
+}
+
+fn decode_cursor(token: &str) -> Option<(NaiveDateTime, String)> {
+    let cursor = String::from_utf8(BASE64URL_NOPAD.decode(token.as_bytes()).ok()?).ok()?;
+    let (micros, uuid) = cursor.split_once('_')?;
+    let date = DateTime::from_timestamp_micros(micros.parse().ok()?)?.naive_utc();
+    Some((date, uuid.to_string()))
+}
+
+/// The continuation token of a page of events, when the page is full there probably is a next one.
+pub(super) fn next_page_token(events: &[Event], page_size: i64) -> Option<String> {
+    if events.len() as i64 == page_size {
+        events.last().map(encode_cursor)
+    } else {
+        None
+    }
+}
+
 // Upstream: https://github.com/bitwarden/server/blob/9ecf69d9cabce732cf2c57976dd9afa5728578fb/src/Api/Controllers/EventsController.cs#LL84C35-L84C41
+// The filters are Vaultwarden specific, the web vault only sends the dates.
 #[get("/organizations/<org_id>/events?<data..>")]
-async fn get_org_events(org_id: &str, data: EventRange, _headers: AdminHeaders, mut conn: DbConn) -> JsonResult {
+async fn get_org_events(org_id: &str, data: OrgEventQuery, _headers: AdminHeaders, mut conn: DbReadConn) -> JsonResult {
     // Return an empty vec when we org events are disabled.
     // This prevents client errors
-    let events_json: Vec<Value> = if !CONFIG.org_events_enabled() {
+    let events = if !CONFIG.org_events_enabled() {
         Vec::with_capacity(0)
     } else {
-        let start_date = parse_date(&data.start);
-        let end_date = if let Some(before_date) = &data.continuation_token {
-            parse_date(before_date)
-        } else {
-            parse_date(&data.end)
-        };
-
-        Event::find_by_organization_uuid(org_id, &start_date, &end_date, &mut conn)
-            .await
-            .iter()
-            .map(|e| e.to_json())
-            .collect()
+        Event::find_by_organization_filtered(org_id, &data.to_filter()?, false, Event::PAGE_SIZE, &mut conn).await
     };
+    let events_json: Vec<Value> = events.iter().map(|e| e.to_json()).collect();
 
     Ok(Json(json!({
         "Data": events_json,
         "Object": "list",
-        "ContinuationToken": get_continuation_token(&events_json),
+        "ContinuationToken": next_page_token(&events, Event::PAGE_SIZE),
     })))
 }
 
 #[get("/ciphers/<cipher_id>/events?<data..>")]
-async fn get_cipher_events(cipher_id: &str, data: EventRange, headers: Headers, mut conn: DbConn) -> JsonResult {
+async fn get_cipher_events(cipher_id: &str, data: EventRange, headers: Headers, mut conn: DbReadConn) -> JsonResult {
     // Return an empty vec when we org events are disabled.
     // This prevents client errors
     let events_json: Vec<Value> = if !CONFIG.org_events_enabled() {
@@ -97,7 +163,7 @@ async fn get_user_events(
     user_org_id: &str,
     data: EventRange,
     _headers: AdminHeaders,
-    mut conn: DbConn,
+    mut conn: DbReadConn,
 ) -> JsonResult {
     // Return an empty vec when we org events are disabled.
     // This prevents client errors
@@ -125,6 +191,88 @@ async fn get_user_events(
     })))
 }
 
+// The history of the account itself, available when `USER_EVENTS_ENABLED` is set
+#[get("/accounts/events?<data..>")]
+async fn get_personal_events(data: EventRange, headers: Headers, mut conn: DbReadConn) -> JsonResult {
+    // Return an empty vec when the user events are disabled.
+    // This prevents client errors
+    let events_json: Vec<Value> = if !CONFIG.user_events_enabled() {
+        Vec::with_capacity(0)
+    } else {
+        let start_date = parse_date(&data.start);
+        let end_date = if let Some(before_date) = &data.continuation_token {
+            parse_date(before_date)
+        } else {
+            parse_date(&data.end)
+        };
+
+        Event::find_personal_by_user_uuid(&headers.user.uuid, &start_date, &end_date, &mut conn)
+            .await
+            .iter()
+            .map(|e| e.to_json())
+            .collect()
+    };
+
+    Ok(Json(json!({
+        "Data": events_json,
+        "Object": "list",
+        "ContinuationToken": get_continuation_token(&events_json),
+    })))
+}
+
+// The emergency access events are always available to both parties, regardless of `ORG_EVENTS_ENABLED`
+#[get("/emergency-access/events?<data..>")]
+async fn get_emergency_access_events(data: EventRange, headers: Headers, mut conn: DbReadConn) -> JsonResult {
+    let start_date = parse_date(&data.start);
+    let end_date = if let Some(before_date) = &data.continuation_token {
+        parse_date(before_date)
+    } else {
+        parse_date(&data.end)
+    };
+
+    let events_json: Vec<Value> =
+        Event::find_emergency_access_by_user_uuid(&headers.user.uuid, &start_date, &end_date, &mut conn)
+            .await
+            .iter()
+            .map(|e| e.to_json())
+            .collect();
+
+    Ok(Json(json!({
+        "Data": events_json,
+        "Object": "list",
+        "ContinuationToken": get_continuation_token(&events_json),
+    })))
+}
+
+#[get("/emergency-access/<emer_id>/events?<data..>")]
+async fn get_emergency_access_timeline(
+    emer_id: &str,
+    data: EventRange,
+    headers: Headers,
+    mut conn: DbReadConn,
+) -> JsonResult {
+    let start_date = parse_date(&data.start);
+    let end_date = if let Some(before_date) = &data.continuation_token {
+        parse_date(before_date)
+    } else {
+        parse_date(&data.end)
+    };
+
+    // Only the events of the grantor or the grantee are returned, also the ones of the deleted grants
+    let events_json: Vec<Value> =
+        Event::find_by_emergency_access_uuid_and_user(emer_id, &headers.user.uuid, &start_date, &end_date, &mut conn)
+            .await
+            .iter()
+            .map(|e| e.to_json())
+            .collect();
+
+    Ok(Json(json!({
+        "Data": events_json,
+        "Object": "list",
+        "ContinuationToken": get_continuation_token(&events_json),
+    })))
+}
+
 fn get_continuation_token(events_json: &[Value]) -> Option<&str> {
     // When the length of the vec equals the max page_size there probably is more data
     // When it is less, then all events are loaded.
@@ -162,13 +310,14 @@ struct EventCollection {
 // https://github.com/bitwarden/server/blob/8a22c0479e987e756ce7412c48a732f9002f0a2d/src/Core/Services/Implementations/EventService.cs
 #[post("/collect", format = "application/json", data = "<data>")]
 async fn post_events_collect(data: JsonUpcaseVec<EventCollection>, headers: Headers, mut conn: DbConn) -> EmptyResult {
-    if !CONFIG.org_events_enabled() {
+    if !CONFIG.org_events_enabled() && !CONFIG.user_events_enabled() {
         return Ok(());
     }
 
     for event in data.iter().map(|d| &d.data) {
         let event_date = parse_date(&event.Date);
         match event.Type {
+            // The exports of the vault are only reported by the clients
             1000..=1099 => {
                 _log_user_event(
                     event.Type,
@@ -180,6 +329,7 @@ async fn post_events_collect(data: JsonUpcaseVec<EventCollection>, headers: Head
                 )
                 .await;
             }
+            _ if !CONFIG.org_events_enabled() => {}
             1600..=1699 => {
                 if let Some(org_uuid) = &event.OrganizationId {
                     _log_event(
@@ -219,8 +369,29 @@ async fn post_events_collect(data: JsonUpcaseVec<EventCollection>, headers: Head
     Ok(())
 }
 
+/// Emits the event on the event bus, which stores it, and sends it to the webhooks and the SIEM.
+/// The webhooks and the SIEM get the events even when they aren't stored.
 pub async fn log_user_event(event_type: i32, user_uuid: &str, device_type: i32, ip: &IpAddr, conn: &mut DbConn) {
-    if !CONFIG.org_events_enabled() {
+    let ctx = EventContext {
+        user_uuid: String::from(user_uuid),
+        device_uuid: String::new(),
+        device_type,
+        ip: *ip,
+    };
+    EVENT_BUS
+        .emit(
+            DomainEvent::UserEvent {
+                event_type,
+            },
+            &ctx,
+            conn,
+        )
+        .await;
+}
+
+/// Stores the event of `log_user_event` when the events are enabled.
+pub async fn store_user_event(event_type: i32, user_uuid: &str, device_type: i32, ip: &IpAddr, conn: &mut DbConn) {
+    if !CONFIG.org_events_enabled() && !CONFIG.user_events_enabled() {
         return;
     }
     _log_user_event(event_type, user_uuid, device_type, None, ip, conn).await;
@@ -234,15 +405,23 @@ async fn _log_user_event(
     ip: &IpAddr,
     conn: &mut DbConn,
 ) {
-    let orgs = UserOrganization::get_org_uuid_by_user(user_uuid, conn).await;
+    let orgs = if CONFIG.org_events_enabled() {
+        UserOrganization::get_org_uuid_by_user(user_uuid, conn).await
+    } else {
+        Vec::new()
+    };
     let mut events: Vec<Event> = Vec::with_capacity(orgs.len() + 1); // We need an event per org and one without an org
 
-    // Upstream saves the event also without any org_uuid.
+    // Upstream saves the event also without any org_uuid, it's the personal history of the account
     let mut event = Event::new(event_type, event_date);
     event.user_uuid = Some(String::from(user_uuid));
     event.act_user_uuid = Some(String::from(user_uuid));
     event.device_type = Some(device_type);
-    event.ip_address = Some(ip.to_string());
+    event.ip_address = Some(if CONFIG.user_events_enabled() && CONFIG.user_events_anonymize_ip() {
+        anonymize_ip(ip)
+    } else {
+        ip.to_string()
+    });
     events.push(event);
 
     // For each org a user is a member of store these events per org
@@ -259,6 +438,20 @@ async fn _log_user_event(
     Event::save_user_event(events, conn).await.unwrap_or(());
 }
 
+// Keeps the network of the address, the /24 for IPv4 and the /48 for IPv6
+fn anonymize_ip(ip: &IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            Ipv4Addr::new(a, b, c, 0).to_string()
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0).to_string()
+        }
+    }
+}
+
 pub async fn log_event(
     event_type: i32,
     source_uuid: &str,
@@ -268,6 +461,8 @@ pub async fn log_event(
     ip: &IpAddr,
     conn: &mut DbConn,
 ) {
+    // The webhooks are sent even when the events aren't stored
+    crate::webhook::send_organization_event(event_type, source_uuid, org_uuid, act_user_uuid, device_type, ip);
     if !CONFIG.org_events_enabled() {
         return;
     }
@@ -321,16 +516,46 @@ async fn _log_event(
     event.save(conn).await.unwrap_or(());
 }
 
-pub async fn event_cleanup_job(pool: DbPool) {
-    debug!("Start events cleanup job");
-    if CONFIG.events_days_retain().is_none() {
-        debug!("events_days_retain is not configured, abort");
-        return;
+/// Emergency access events are stored for both the grantor and the grantee, so they can audit what happened.
+/// These are always logged, regardless of `ORG_EVENTS_ENABLED`.
+pub async fn log_emergency_access_event(
+    event_type: i32,
+    emergency_access: &EmergencyAccess,
+    act_user_uuid: Option<&str>,
+    device_type: Option<i32>,
+    ip: Option<&IpAddr>,
+    conn: &mut DbConn,
+) {
+    let mut event = Event::new(event_type, None);
+    event.user_uuid = Some(emergency_access.grantor_uuid.clone());
+    event.grantee_user_uuid = emergency_access.grantee_uuid.clone();
+    event.emergency_access_uuid = Some(emergency_access.uuid.clone());
+    event.act_user_uuid = act_user_uuid.map(String::from);
+    event.device_type = device_type;
+    event.ip_address = ip.map(|ip| ip.to_string());
+
+    event.save(conn).await.unwrap_or(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anonymize_ip() {
+        assert_eq!(anonymize_ip(&"192.0.2.123".parse().unwrap()), "192.0.2.0");
+        assert_eq!(anonymize_ip(&"2001:db8:85a3:8d3:1319:8a2e:370:7348".parse().unwrap()), "2001:db8:85a3::");
+        assert_eq!(anonymize_ip(&"::1".parse().unwrap()), "::");
     }
 
-    if let Ok(mut conn) = pool.get().await {
-        Event::clean_events(&mut conn).await.ok();
-    } else {
-        error!("Failed to get DB connection while trying to cleanup the events table")
+    #[test]
+    fn test_cursor() {
+        let mut event = Event::new(1000, Some(parse_date("2024-03-01T12:30:45.123456Z")));
+        event.uuid = String::from("8c8a1f0e-3a4b-4c5d-9e6f-7a8b9c0d1e2f");
+        let cursor = decode_cursor(&encode_cursor(&event)).unwrap();
+        assert_eq!(cursor, (event.event_date, event.uuid));
+
+        assert!(decode_cursor("2024-03-01T12:30:45.123456Z").is_none());
+        assert!(decode_cursor(&BASE64URL_NOPAD.encode(b"abc_def")).is_none());
     }
 }
@@ -0,0 +1,187 @@
+use once_cell::sync::OnceCell;
+use openssl::{
+    pkey::Private,
+    rsa::{Padding, Rsa},
+};
+use rocket::serde::json::Json;
+use rocket::Route;
+use serde_json::Value;
+
+use crate::{
+    api::{EmptyResult, JsonResult, JsonUpcase},
+    auth::Headers,
+    crypto,
+    db::{models::*, DbConn},
+    error::Error,
+    CONFIG,
+};
+
+// Emulation of the Bitwarden Key Connector, so the members of the configured organizations can unlock their vault
+// without a master password. The clients store the master key of the user here, and fetch it after logging in.
+//
+// This changes the trust model of the end-to-end encryption: the master key is only protected by an RSA key held
+// by the server, so whoever controls the server can decrypt the vaults of these users. That is why it has to be
+// explicitly acknowledged in the config. Owners and admins can't use it, like with the official server.
+//
+// Vaultwarden has no SSO login, so the users who converted to the Key Connector login with their API key or a passkey.
+
+pub fn routes() -> Vec<Route> {
+    routes![get_user_keys, post_user_keys, put_user_keys, post_convert_to_key_connector]
+}
+
+/// The URL of the emulated Key Connector, as given to the clients.
+pub fn url() -> String {
+    format!("{}/api/key-connector", CONFIG.domain())
+}
+
+/// Returns true if the members of the organization are offered the Key Connector.
+pub fn enabled_for_org(org_uuid: &str) -> bool {
+    CONFIG.key_connector_enabled()
+        && CONFIG.key_connector_orgs().is_some_and(|orgs| orgs.split(',').any(|o| o.trim() == org_uuid))
+}
+
+/// Adds the Key Connector decryption option to a token response, for the users who converted to it.
+pub fn add_decryption_option(user: &User, result: &mut Value) {
+    if user.uses_key_connector && CONFIG.key_connector_enabled() {
+        result["KeyConnectorUrl"] = Value::String(url());
+        result["UserDecryptionOptions"]["KeyConnectorOption"] = json!({
+            "KeyConnectorUrl": url(),
+        });
+    }
+}
+
+fn check_key_connector_enabled() -> EmptyResult {
+    if !CONFIG.key_connector_enabled() {
+        err!("Key Connector is disabled")
+    }
+    Ok(())
+}
+
+// The key is created when it doesn't exist yet, losing it makes the vaults of the Key Connector users unrecoverable
+fn server_key() -> Result<&'static Rsa<Private>, Error> {
+    static SERVER_KEY: OnceCell<Rsa<Private>> = OnceCell::new();
+    SERVER_KEY.get_or_try_init(|| {
+        let path = CONFIG.key_connector_rsa_key();
+        match std::fs::read(&path) {
+            Ok(pem) => Ok(Rsa::private_key_from_pem(&pem)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let key = Rsa::generate(2048)?;
+                std::fs::write(&path, key.private_key_to_pem()?)?;
+                info!("Key Connector private key created correctly.");
+                Ok(key)
+            }
+            Err(e) => Err(e.into()),
+        }
+    })
+}
+
+fn encrypt_key(key: &str) -> Result<String, Error> {
+    let rsa = server_key()?;
+    let mut encrypted = vec![0u8; rsa.size() as usize];
+    let len = rsa.public_encrypt(key.as_bytes(), &mut encrypted, Padding::PKCS1_OAEP)?;
+    Ok(data_encoding::BASE64.encode(&encrypted[..len]))
+}
+
+fn decrypt_key(encrypted: &str) -> Result<String, Error> {
+    let rsa = server_key()?;
+    let encrypted = match data_encoding::BASE64.decode(encrypted.as_bytes()) {
+        Ok(encrypted) => encrypted,
+        Err(_) => err!("Invalid stored Key Connector key"),
+    };
+    let mut key = vec![0u8; rsa.size() as usize];
+    let len = rsa.private_decrypt(&encrypted, &mut key, Padding::PKCS1_OAEP)?;
+    key.truncate(len);
+    match String::from_utf8(key) {
+        Ok(key) => Ok(key),
+        Err(_) => err!("Invalid stored Key Connector key"),
+    }
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct UserKeyData {
+    Key: String,
+}
+
+#[get("/key-connector/user-keys")]
+async fn get_user_keys(headers: Headers) -> JsonResult {
+    check_key_connector_enabled()?;
+
+    let key = match headers.user.key_connector_key {
+        Some(ref encrypted) => decrypt_key(encrypted)?,
+        None => err!("No key stored for this user"),
+    };
+
+    Ok(Json(json!({
+        "Key": key,
+    })))
+}
+
+#[post("/key-connector/user-keys", data = "<data>")]
+async fn post_user_keys(data: JsonUpcase<UserKeyData>, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    check_key_connector_enabled()?;
+    let data: UserKeyData = data.into_inner().data;
+
+    let mut user = headers.user;
+    if user.key_connector_key.is_some() {
+        err!("A key is already stored for this user")
+    }
+    check_user_allowed(&user, &mut conn).await?;
+
+    user.key_connector_key = Some(encrypt_key(&data.Key)?);
+    user.save(&mut conn).await
+}
+
+#[put("/key-connector/user-keys", data = "<data>")]
+async fn put_user_keys(data: JsonUpcase<UserKeyData>, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    check_key_connector_enabled()?;
+    let data: UserKeyData = data.into_inner().data;
+
+    let mut user = headers.user;
+    if user.key_connector_key.is_none() {
+        err!("No key stored for this user")
+    }
+
+    user.key_connector_key = Some(encrypt_key(&data.Key)?);
+    user.save(&mut conn).await
+}
+
+// Only the confirmed members of a Key Connector organization can use it, and not if they are an owner or admin of one
+async fn check_user_allowed(user: &User, conn: &mut DbConn) -> EmptyResult {
+    let memberships: Vec<UserOrganization> = UserOrganization::find_confirmed_by_user(&user.uuid, conn)
+        .await
+        .into_iter()
+        .filter(|m| enabled_for_org(&m.org_uuid))
+        .collect();
+
+    if memberships.is_empty() {
+        err!("You are not a member of an organization using Key Connector")
+    }
+    if memberships.iter().any(|m| m.atype >= UserOrgType::Admin) {
+        err!("Owners and admins can't use Key Connector")
+    }
+    Ok(())
+}
+
+#[post("/accounts/convert-to-key-connector")]
+async fn post_convert_to_key_connector(headers: Headers, mut conn: DbConn) -> EmptyResult {
+    check_key_connector_enabled()?;
+
+    let mut user = headers.user;
+    if user.uses_key_connector {
+        err!("Already uses Key Connector")
+    }
+    if user.key_connector_key.is_none() {
+        err!("The master key needs to be stored in the Key Connector first")
+    }
+    check_user_allowed(&user, &mut conn).await?;
+
+    // Removes the master password, the hash of a random value can't be matched by any password
+    user.password_hash = crypto::get_random_bytes::<32>().to_vec();
+    user.password_hint = None;
+    user.uses_key_connector = true;
+    user.save(&mut conn).await?;
+
+    info!("User {} converted to Key Connector", user.email);
+    Ok(())
+}
@@ -1,17 +1,23 @@
 pub mod accounts;
 mod ciphers;
+mod directory_sync;
 mod emergency_access;
 mod events;
 mod folders;
+pub mod key_connector;
 mod organizations;
+pub mod passkeys;
 mod public;
 mod sends;
 pub mod two_factor;
 
 pub use accounts::purge_auth_requests;
 pub use ciphers::{purge_trashed_ciphers, CipherData, CipherSyncData, CipherSyncType};
-pub use emergency_access::{emergency_notification_reminder_job, emergency_request_timeout_job};
-pub use events::{event_cleanup_job, log_event, log_user_event};
+pub use directory_sync::{directory_sync_job, parse_sync_orgs};
+pub use emergency_access::{
+    emergency_access_validation_job, emergency_notification_reminder_job, emergency_request_timeout_job,
+};
+pub use events::{log_emergency_access_event, log_event, log_user_event, store_user_event};
 pub use sends::purge_sends;
 
 pub fn routes() -> Vec<Route> {
@@ -25,7 +31,9 @@ pub fn routes() -> Vec<Route> {
     routes.append(&mut emergency_access::routes());
     routes.append(&mut events::routes());
     routes.append(&mut folders::routes());
+    routes.append(&mut key_connector::routes());
     routes.append(&mut organizations::routes());
+    routes.append(&mut passkeys::routes());
     routes.append(&mut two_factor::routes());
     routes.append(&mut sends::routes());
     routes.append(&mut public::routes());
@@ -53,7 +61,8 @@ use crate::{
     auth::Headers,
     db::DbConn,
     error::Error,
-    util::{get_reqwest_client, parse_experimental_client_feature_flags},
+    http_client::{get_reqwest_client, TracedSend},
+    util::parse_experimental_client_feature_flags,
 };
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -66,6 +75,12 @@ struct GlobalDomain {
 
 const GLOBAL_DOMAINS: &str = include_str!("../../static/global_domains.json");
 
+/// The domain groups of the global equivalent domains.
+pub fn global_equivalent_domains() -> Vec<Vec<String>> {
+    let globals: Vec<GlobalDomain> = serde_json::from_str(GLOBAL_DOMAINS).unwrap();
+    globals.into_iter().map(|global| global.Domains).collect()
+}
+
 #[get("/settings/domains")]
 fn get_eq_domains(headers: Headers) -> Json<Value> {
     _get_eq_domains(headers, false)
@@ -146,7 +161,7 @@ async fn hibp_breach(username: &str) -> JsonResult {
     if let Some(api_key) = crate::CONFIG.hibp_api_key() {
         let hibp_client = get_reqwest_client();
 
-        let res = hibp_client.get(&url).header("hibp-api-key", api_key).send().await?;
+        let res = hibp_client.get(&url).header("hibp-api-key", api_key).send_traced().await?;
 
         // If we get a 404, return a 404, it means no breached accounts
         if res.status() == 404 {
@@ -216,12 +231,17 @@ fn config() -> Json<Value> {
           "sso": "",
         },
         "featureStates": feature_states,
+        // Vaultwarden specific, lets clients know the upload limits before starting a transfer
+        "fileSizeLimits": {
+          "send": crate::CONFIG.send_file_size_limit_bytes(),
+          "attachment": crate::CONFIG.attachment_file_size_limit_bytes(),
+        },
         "object": "config",
     }))
 }
 
 pub fn catchers() -> Vec<Catcher> {
-    catchers![api_not_found]
+    catchers![api_not_found, api_service_unavailable]
 }
 
 #[catch(404)]
@@ -234,3 +254,18 @@ fn api_not_found() -> Json<Value> {
         }
     }))
 }
+
+#[catch(503)]
+fn api_service_unavailable() -> Json<Value> {
+    let description = match crate::db::in_maintenance() {
+        true => "The server is in maintenance, try again later.",
+        false => "The database is unavailable, try again later.",
+    };
+    Json(json!({
+        "error": {
+            "code": 503,
+            "reason": "Service Unavailable",
+            "description": description
+        }
+    }))
+}
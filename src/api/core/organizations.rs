@@ -1,15 +1,24 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use futures::StreamExt;
 use num_traits::FromPrimitive;
+use once_cell::sync::Lazy;
 use rocket::serde::json::Json;
-use rocket::Route;
+use rocket::{http::Status, Route, State};
 use serde_json::Value;
 
 use crate::{
     api::{
-        core::{log_event, two_factor, CipherSyncData, CipherSyncType},
-        EmptyResult, JsonResult, JsonUpcase, JsonUpcaseVec, JsonVec, Notify, PasswordOrOtpData, UpdateType,
+        core::{emergency_access, log_event, two_factor, CipherSyncData, CipherSyncType},
+        ApiResult, EmptyResult, JsonResult, JsonUpcase, JsonUpcaseVec, JsonVec, ListQuery, Notify, PasswordOrOtpData,
+        UpdateType,
     },
     auth::{decode_invite, AdminHeaders, Headers, ManagerHeaders, ManagerHeadersLoose, OwnerHeaders},
-    db::{models::*, DbConn},
+    db::{models::*, DbConn, DbConnType, DbPool},
     error::Error,
     mail,
     util::{convert_json_key_lcase_first, NumberOrString},
@@ -41,6 +50,7 @@ pub fn routes() -> Vec<Route> {
         bulk_delete_organization_collections,
         get_org_details,
         get_org_users,
+        get_org_twofactor_report,
         send_invite,
         reinvite_user,
         bulk_reinvite_user,
@@ -54,6 +64,7 @@ pub fn routes() -> Vec<Route> {
         bulk_delete_user,
         post_delete_user,
         post_org_import,
+        get_org_import_status,
         list_policies,
         list_policies_token,
         get_policy,
@@ -93,6 +104,7 @@ pub fn routes() -> Vec<Route> {
         put_reset_password_enrollment,
         get_reset_password_details,
         put_reset_password,
+        put_reset_two_factor,
         get_org_export,
         api_key,
         rotate_api_key,
@@ -293,7 +305,7 @@ async fn post_organization(
 async fn get_user_collections(headers: Headers, mut conn: DbConn) -> Json<Value> {
     Json(json!({
         "Data":
-            Collection::find_by_user_uuid(headers.user.uuid, &mut conn).await
+            Collection::find_by_user_uuid(&headers.user.uuid, &mut conn).await
             .iter()
             .map(Collection::to_json)
             .collect::<Value>(),
@@ -773,6 +785,12 @@ struct GetOrgUserData {
     include_collections: Option<bool>,
     #[field(name = "includeGroups")]
     include_groups: Option<bool>,
+    // Vaultwarden specific pagination, see `ListQuery`, the clients still get all the members
+    page: Option<usize>,
+    per_page: Option<usize>,
+    search: Option<String>,
+    sort: Option<String>,
+    order: Option<String>,
 }
 
 #[get("/organizations/<org_id>/users?<data..>")]
@@ -782,8 +800,33 @@ async fn get_org_users(
     _headers: ManagerHeadersLoose,
     mut conn: DbConn,
 ) -> Json<Value> {
-    let mut users_json = Vec::new();
-    for u in UserOrganization::find_by_org(org_id, &mut conn).await {
+    let list = ListQuery {
+        page: data.page,
+        per_page: data.per_page,
+        search: data.search,
+        sort: data.sort,
+        order: data.order,
+    };
+    let (members, total) = if list.is_paginated() {
+        let sort = MemberSort::from_name(list.sort());
+        UserOrganization::find_page_by_org(
+            org_id,
+            list.search(),
+            sort,
+            list.descending(),
+            list.offset(),
+            list.limit(),
+            &mut conn,
+        )
+        .await
+    } else {
+        let members = UserOrganization::find_by_org(org_id, &mut conn).await;
+        let total = members.len() as i64;
+        (members, total)
+    };
+
+    let mut users_json = Vec::with_capacity(members.len());
+    for u in members {
         users_json.push(
             u.to_json_user_details(
                 data.include_collections.unwrap_or(false),
@@ -794,6 +837,9 @@ async fn get_org_users(
         );
     }
 
+    if list.is_paginated() {
+        return Json(list.list_json(users_json, total));
+    }
     Json(json!({
         "Data": users_json,
         "Object": "list",
@@ -801,6 +847,21 @@ async fn get_org_users(
     }))
 }
 
+// Vaultwarden specific, lets the admins find the members whose accounts aren't protected by two-step login
+#[get("/organizations/<org_id>/reports/two-factor")]
+async fn get_org_twofactor_report(org_id: &str, _headers: AdminHeaders, mut conn: DbConn) -> Json<Value> {
+    let mut members_json = Vec::new();
+    for member in UserOrganization::find_by_org(org_id, &mut conn).await {
+        members_json.push(member.to_json_twofactor_report(&mut conn).await);
+    }
+
+    Json(json!({
+        "Data": members_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    }))
+}
+
 #[post("/organizations/<org_id>/keys", data = "<data>")]
 async fn post_org_keys(
     org_id: &str,
@@ -1363,6 +1424,11 @@ async fn edit_user(
         }
     }
 
+    // Owners who lose their role can't be emergency contacts through the organization anymore
+    if user_to_edit.atype == UserOrgType::Owner && new_type != UserOrgType::Owner {
+        EmergencyAccess::delete_all_by_org_and_grantee(org_id, &user_to_edit.user_uuid, &mut conn).await?;
+    }
+
     user_to_edit.access_all = data.AccessAll;
     user_to_edit.atype = new_type as i32;
 
@@ -1544,8 +1610,7 @@ async fn bulk_public_keys(
     }))
 }
 
-use super::ciphers::update_cipher_from_data;
-use super::ciphers::CipherData;
+use super::ciphers::{set_cipher_data, CipherData};
 
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
@@ -1564,62 +1629,203 @@ struct RelationsData {
     Value: usize,
 }
 
+// The imports with more ciphers are done in the background, the client can poll their progress
+const IMPORT_BACKGROUND_MIN_CIPHERS: usize = 500;
+// The ciphers inserted at once, and the batches inserted at the same time
+const IMPORT_BATCH_SIZE: usize = 500;
+const IMPORT_PARALLEL_BATCHES: usize = 4;
+// The finished imports are forgotten after an hour
+const IMPORT_STATUS_TTL: Duration = Duration::from_secs(60 * 60);
+
+struct ImportProgress {
+    org_uuid: String,
+    total: usize,
+    processed: usize,
+    error: Option<String>,
+    finished_at: Option<Instant>,
+}
+
+impl ImportProgress {
+    fn to_json(&self, id: &str) -> Value {
+        let status = match (&self.error, self.finished_at) {
+            (Some(_), _) => "failed",
+            (None, Some(_)) => "done",
+            (None, None) => "running",
+        };
+        json!({
+            "Id": id,
+            "OrganizationId": self.org_uuid,
+            "Status": status,
+            "Total": self.total,
+            "Processed": self.processed,
+            "Error": self.error,
+            "Object": "organizationImport"
+        })
+    }
+}
+
+// The imports of this instance, by their ID
+static ORG_IMPORTS: Lazy<Mutex<HashMap<String, ImportProgress>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn update_import(import_id: &str, f: impl FnOnce(&mut ImportProgress)) {
+    if let Some(progress) = ORG_IMPORTS.lock().unwrap().get_mut(import_id) {
+        f(progress);
+    }
+}
+
 #[post("/ciphers/import-organization?<query..>", data = "<data>")]
 async fn post_org_import(
     query: OrgIdData,
     data: JsonUpcase<ImportData>,
     headers: AdminHeaders,
     mut conn: DbConn,
-    nt: Notify<'_>,
-) -> EmptyResult {
+    pool: &State<DbPool>,
+) -> ApiResult<(Status, Json<Value>)> {
     let data: ImportData = data.into_inner().data;
     let org_id = query.organization_id;
 
     // Validate the import before continuing
     // Bitwarden does not process the import if there is one item invalid.
     // Since we check for the size of the encrypted note length, we need to do that here to pre-validate it.
-    // TODO: See if we can optimize the whole cipher adding/importing and prevent duplicate code and checks.
     Cipher::validate_notes(&data.Ciphers)?;
 
-    let mut collections = Vec::new();
-    for coll in data.Collections {
-        let collection = Collection::new(org_id.clone(), coll.Name, coll.ExternalId);
-        if collection.save(&mut conn).await.is_err() {
-            collections.push(Err(Error::new("Failed to create Collection", "Failed to create Collection")));
-        } else {
-            collections.push(Ok(collection));
+    let mut ciphers = Vec::with_capacity(data.Ciphers.len());
+    for cipher_data in data.Ciphers {
+        if cipher_data.OrganizationId.as_ref().is_some_and(|id| *id != org_id) {
+            err!("Organization mismatch. Please resync the client before importing")
         }
+        let mut cipher = Cipher::new(cipher_data.Type, cipher_data.Name.clone());
+        cipher.organization_uuid = Some(org_id.clone());
+        set_cipher_data(&mut cipher, cipher_data)?;
+        ciphers.push(cipher);
     }
 
     // Read the relations between collections and ciphers
-    let mut relations = Vec::new();
+    let mut relations = HashSet::new();
     for relation in data.CollectionRelationships {
-        relations.push((relation.Key, relation.Value));
+        if relation.Key >= ciphers.len() || relation.Value >= data.Collections.len() {
+            err!("Invalid relation between a cipher and a collection")
+        }
+        relations.insert((relation.Key, relation.Value));
     }
 
-    let headers: Headers = headers.into();
+    let mut collections = Vec::with_capacity(data.Collections.len());
+    for coll in data.Collections {
+        let collection = Collection::new(org_id.clone(), coll.Name, coll.ExternalId);
+        collection.save(&mut conn).await?;
+        collections.push(collection);
+    }
+    let relations: Vec<(usize, String)> = relations
+        .into_iter()
+        .map(|(cipher_index, coll_index)| (cipher_index, collections[coll_index].uuid.clone()))
+        .collect();
 
-    let mut ciphers = Vec::new();
-    for cipher_data in data.Ciphers {
-        let mut cipher = Cipher::new(cipher_data.Type, cipher_data.Name.clone());
-        update_cipher_from_data(&mut cipher, cipher_data, &headers, None, &mut conn, &nt, UpdateType::None).await.ok();
-        ciphers.push(cipher);
+    let import_id = crate::util::get_uuid();
+    ORG_IMPORTS.lock().unwrap().retain(|_, progress| {
+        progress.finished_at.map_or(true, |finished_at| finished_at.elapsed() < IMPORT_STATUS_TTL)
+    });
+    ORG_IMPORTS.lock().unwrap().insert(
+        import_id.clone(),
+        ImportProgress {
+            org_uuid: org_id,
+            total: ciphers.len(),
+            processed: 0,
+            error: None,
+            finished_at: None,
+        },
+    );
+    drop(conn);
+
+    let background = ciphers.len() >= IMPORT_BACKGROUND_MIN_CIPHERS;
+    let import =
+        insert_org_import(import_id.clone(), ciphers, relations, collections, headers.user.uuid, pool.inner().clone());
+    if background {
+        crate::log_context::spawn(import);
+        let status = ORG_IMPORTS.lock().unwrap().get(&import_id).map(|progress| progress.to_json(&import_id));
+        return Ok((Status::Accepted, Json(status.unwrap_or_default())));
+    }
+
+    import.await?;
+    let status = ORG_IMPORTS.lock().unwrap().get(&import_id).map(|progress| progress.to_json(&import_id));
+    Ok((Status::Ok, Json(status.unwrap_or_default())))
+}
+
+// Inserts the ciphers of the import and their collections in batches, at the same time on the other databases.
+// The batches are inserted in the order they finish in, so the progress is the number of ciphers already inserted.
+async fn insert_org_import(
+    import_id: String,
+    ciphers: Vec<Cipher>,
+    relations: Vec<(usize, String)>,
+    collections: Vec<Collection>,
+    user_uuid: String,
+    pool: DbPool,
+) -> EmptyResult {
+    let mut batch_relations = vec![Vec::new(); ciphers.len().div_ceil(IMPORT_BATCH_SIZE)];
+    for (cipher_index, coll_uuid) in relations {
+        batch_relations[cipher_index / IMPORT_BATCH_SIZE].push((ciphers[cipher_index].uuid.clone(), coll_uuid));
+    }
+    let mut ciphers = ciphers.into_iter();
+    let batches: Vec<(Vec<Cipher>, Vec<(String, String)>)> = batch_relations
+        .into_iter()
+        .map(|relations| (ciphers.by_ref().take(IMPORT_BATCH_SIZE).collect(), relations))
+        .collect();
+
+    // SQLite only has one writer, the batches would wait for each other
+    let parallel_batches = match DbConnType::from_url(&CONFIG.database_url()) {
+        Ok(DbConnType::sqlite) => 1,
+        _ => IMPORT_PARALLEL_BATCHES,
+    };
+    let mut inserts = futures::stream::iter(batches)
+        .map(|(ciphers, relations)| {
+            let pool = pool.clone();
+            let import_id = import_id.clone();
+            async move {
+                let mut conn = pool.get().await?;
+                Cipher::insert_all(&ciphers, &mut conn).await?;
+                CollectionCipher::insert_all(&relations, &mut conn).await?;
+                update_import(&import_id, |progress| progress.processed += ciphers.len());
+                Ok::<(), Error>(())
+            }
+        })
+        .buffer_unordered(parallel_batches);
+
+    let mut result = Ok(());
+    while let Some(batch_result) = inserts.next().await {
+        if let Err(e) = batch_result {
+            result = Err(e);
+            break;
+        }
     }
+    drop(inserts);
 
-    // Assign the collections
-    for (cipher_index, coll_index) in relations {
-        let cipher_id = &ciphers[cipher_index].uuid;
-        let coll = &collections[coll_index];
-        let coll_id = match coll {
-            Ok(coll) => coll.uuid.as_str(),
-            Err(_) => err!("Failed to assign to collection"),
-        };
+    // The ciphers are shown to the users of the collections on their next sync, even when the import failed midway
+    match pool.get().await {
+        Ok(mut conn) => {
+            for collection in &collections {
+                collection.update_users_revision(&mut conn).await;
+            }
+            User::update_uuid_revision(&user_uuid, &mut conn).await;
+        }
+        Err(e) => error!("Failed to get DB connection while updating the revision of the imported ciphers: {e}"),
+    }
 
-        CollectionCipher::save(cipher_id, coll_id, &mut conn).await?;
+    if let Err(e) = &result {
+        error!("Error importing the ciphers of an organization: {e:#?}");
     }
+    update_import(&import_id, |progress| {
+        progress.error = result.as_ref().err().map(|e| e.to_string());
+        progress.finished_at = Some(Instant::now());
+    });
+    result
+}
 
-    let mut user = headers.user;
-    user.update_revision(&mut conn).await
+#[get("/organizations/<org_id>/ciphers-import/<import_id>")]
+fn get_org_import_status(org_id: &str, import_id: &str, _headers: AdminHeaders) -> JsonResult {
+    let imports = ORG_IMPORTS.lock().unwrap();
+    match imports.get(import_id) {
+        Some(progress) if progress.org_uuid == org_id => Ok(Json(progress.to_json(import_id))),
+        _ => err_code!("Import not found", 404),
+    }
 }
 
 #[get("/organizations/<org_id>/policies")]
@@ -1696,10 +1902,12 @@ async fn put_policy(
         None => err!("Invalid or unsupported policy type"),
     };
 
-    // When enabling the TwoFactorAuthentication policy, revoke all members that do not have 2FA
+    // When enabling the TwoFactorAuthentication policy, revoke all members that do not have one of the required 2FA providers
     if pol_type_enum == OrgPolicyType::TwoFactorAuthentication && data.enabled {
+        let policy_data = two_factor::validate_policy_data(&data.data)?;
         two_factor::enforce_2fa_policy_for_org(
             org_id,
+            &policy_data,
             &headers.user.uuid,
             headers.device.atype,
             &headers.ip.ip,
@@ -1742,6 +1950,15 @@ async fn put_policy(
         }
     }
 
+    // When disabling the EmergencyAccess policy, remove the grants created through it
+    if pol_type_enum == OrgPolicyType::EmergencyAccess {
+        if data.enabled {
+            emergency_access::validate_org_policy_data(&data.data)?;
+        } else {
+            EmergencyAccess::delete_all_by_org(org_id, &mut conn).await?;
+        }
+    }
+
     let mut policy = match OrgPolicy::find_by_org_and_type(org_id, pol_type_enum, &mut conn).await {
         Some(p) => p,
         None => OrgPolicy::new(String::from(org_id), pol_type_enum, "{}".to_string()),
@@ -2046,6 +2263,8 @@ async fn _revoke_organization_user(
 
             user_org.revoke();
             user_org.save(conn).await?;
+            // Revoked owners can't be emergency contacts through the organization anymore
+            EmergencyAccess::delete_all_by_org_and_grantee(org_id, &user_org.user_uuid, conn).await?;
 
             log_event(
                 EventType::OrganizationUserRevoked as i32,
@@ -2752,6 +2971,95 @@ async fn put_reset_password(
     Ok(())
 }
 
+// Vaultwarden specific, lets the owners remove the two-step login of a member who lost access to it.
+// The two-step login protects the whole account, including its other organizations, so this is only possible when the
+// member enrolled in account recovery, which already allows the owners to take over the account.
+#[put("/organizations/<org_id>/users/<org_user_id>/reset-two-factor", data = "<data>")]
+async fn put_reset_two_factor(
+    org_id: &str,
+    org_user_id: &str,
+    headers: OwnerHeaders,
+    data: JsonUpcase<PasswordOrOtpData>,
+    mut conn: DbConn,
+) -> EmptyResult {
+    let data: PasswordOrOtpData = data.into_inner().data;
+    data.validate(&headers.user, true, &mut conn).await?;
+
+    let org = match Organization::find_by_uuid(org_id, &mut conn).await {
+        Some(org) => org,
+        None => err!("Required organization not found"),
+    };
+
+    let mut org_user = match UserOrganization::find_by_uuid_and_org(org_user_id, &org.uuid, &mut conn).await {
+        Some(user) => user,
+        None => err!("User to reset isn't member of required organization"),
+    };
+    if org_user.status != (UserOrgStatus::Confirmed as i32) {
+        err!("Organization user must be confirmed to reset its two-step login");
+    }
+    if org_user.user_uuid == headers.user.uuid {
+        err!("You can't reset your own two-step login");
+    }
+
+    let mut user = match User::find_by_uuid(&org_user.user_uuid, &mut conn).await {
+        Some(user) => user,
+        None => err!("User not found"),
+    };
+
+    let recovery_enrolled = org_user.reset_password_key.is_some()
+        && matches!(
+            OrgPolicy::find_by_org_and_type(org_id, OrgPolicyType::ResetPassword, &mut conn).await,
+            Some(policy) if policy.enabled
+        );
+    if !recovery_enrolled {
+        err!("The user must be enrolled in account recovery to reset their two-step login");
+    }
+
+    if TwoFactor::find_by_user(&user.uuid, &mut conn).await.is_empty() {
+        err!("Two-step login is not enabled for this user");
+    }
+
+    // Like the password reset, notify the user first to make sure the reset can't happen silently
+    if CONFIG.mail_enabled() {
+        if let Err(e) = mail::send_admin_reset_2fa(&user.email, &user.name, &org.name).await {
+            err!(format!("Error sending user reset two-step login email: {e:#?}"));
+        }
+    }
+
+    // Give the member time to enable a new method before the 2FA policy of this organization revokes them
+    let grace_days = CONFIG.two_factor_policy_grace_days();
+    if grace_days > 0 {
+        let now = chrono::Utc::now().naive_utc();
+        let Some(grace_until) = chrono::TimeDelta::try_days(grace_days).and_then(|grace| now.checked_add_signed(grace))
+        else {
+            err!("Invalid two-step login grace period")
+        };
+        org_user.twofactor_grace_until = Some(grace_until);
+        org_user.twofactor_reminder_at = Some(now);
+        org_user.save(&mut conn).await?;
+    }
+
+    TwoFactor::delete_all_by_user(&user.uuid, &mut conn).await?;
+    two_factor::enforce_2fa_policy(&user, &headers.user.uuid, headers.device.atype, &headers.ip.ip, &mut conn).await?;
+
+    // Remove the recovery code, not needed without twofactors
+    user.totp_recover = None;
+    user.save(&mut conn).await?;
+
+    log_event(
+        EventType::OrganizationUserAdminReset2fa as i32,
+        org_user_id,
+        org_id,
+        &headers.user.uuid,
+        headers.device.atype,
+        &headers.ip.ip,
+        &mut conn,
+    )
+    .await;
+
+    Ok(())
+}
+
 #[get("/organizations/<org_id>/users/<org_user_id>/reset-password-details")]
 async fn get_reset_password_details(
     org_id: &str,
@@ -2936,7 +3244,7 @@ async fn _api_key(
     let org_api_key = match OrganizationApiKey::find_by_org_uuid(org_id, &conn).await {
         Some(mut org_api_key) => {
             if rotate {
-                org_api_key.api_key = crate::crypto::generate_api_key();
+                org_api_key.set_api_key(&crate::crypto::generate_api_key());
                 org_api_key.revision_date = chrono::Utc::now().naive_utc();
                 org_api_key.save(&conn).await.expect("Error rotating organization API Key");
             }
@@ -2944,14 +3252,14 @@ async fn _api_key(
         }
         None => {
             let api_key = crate::crypto::generate_api_key();
-            let new_org_api_key = OrganizationApiKey::new(String::from(org_id), api_key);
+            let new_org_api_key = OrganizationApiKey::new(String::from(org_id), &api_key);
             new_org_api_key.save(&conn).await.expect("Error creating organization API Key");
             new_org_api_key
         }
     };
 
     Ok(Json(json!({
-      "ApiKey": org_api_key.api_key,
+      "ApiKey": org_api_key.api_key(),
       "RevisionDate": crate::util::format_date(&org_api_key.revision_date),
       "Object": "apiKey",
     })))
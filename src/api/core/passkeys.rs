@@ -0,0 +1,323 @@
+use rocket::serde::json::Json;
+use rocket::Route;
+use serde_json::Value;
+use webauthn_rs::{proto::*, AuthenticationState, RegistrationState};
+
+use crate::{
+    api::{
+        core::two_factor::webauthn::{PublicKeyCredentialCopy, RegisterPublicKeyCredentialCopy, WebauthnConfig},
+        ApiResult, EmptyResult, JsonResult, JsonUpcase, PasswordOrOtpData,
+    },
+    auth::{decode_webauthn_state, encode_jwt, generate_webauthn_state_claims, Headers},
+    db::{models::*, DbConn},
+    error::Error,
+    util::UpCase,
+    CONFIG,
+};
+
+// Passkeys are WebAuthn credentials which can be used to log in instead of the master password.
+// When the authenticator supports the PRF extension, the clients also store the user key encrypted with a key derived from it,
+// which is returned on login so the vault can be unlocked with the passkey as well.
+// A login state is stored until its first use, the user isn't known yet so the clients get its uuid as the token.
+// The other ceremonies are done by a logged in user, their state is handed to the clients as a signed token.
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        get_passkeys,
+        post_passkey_attestation_options,
+        post_passkey,
+        post_passkey_assertion_options,
+        put_passkey,
+        delete_passkey,
+    ]
+}
+
+const MAX_PASSKEYS: usize = 5;
+
+// Same validity as the signed tokens of the other ceremonies
+const LOGIN_CHALLENGE_VALIDITY_SECS: i64 = 5 * 60;
+
+fn check_passkeys_enabled() -> EmptyResult {
+    if !CONFIG.domain_set() {
+        err!("`DOMAIN` environment variable is not set. Passkeys are disabled")
+    }
+    Ok(())
+}
+
+fn get_credentials(passkeys: &[WebAuthnCredential]) -> Result<Vec<Credential>, Error> {
+    passkeys.iter().map(|p| serde_json::from_str(&p.credential).map_err(Error::from)).collect()
+}
+
+#[get("/webauthn")]
+async fn get_passkeys(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let passkeys_json: Vec<Value> = WebAuthnCredential::find_all_by_user(&headers.user.uuid, &mut conn)
+        .await
+        .iter()
+        .map(WebAuthnCredential::to_json)
+        .collect();
+
+    Json(json!({
+        "Data": passkeys_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    }))
+}
+
+#[post("/webauthn/attestation-options", data = "<data>")]
+async fn post_passkey_attestation_options(
+    data: JsonUpcase<PasswordOrOtpData>,
+    headers: Headers,
+    mut conn: DbConn,
+) -> JsonResult {
+    check_passkeys_enabled()?;
+    let data: PasswordOrOtpData = data.into_inner().data;
+    let user = headers.user;
+
+    data.validate(&user, false, &mut conn).await?;
+
+    let passkeys = WebAuthnCredential::find_all_by_user(&user.uuid, &mut conn).await;
+    if passkeys.len() >= MAX_PASSKEYS {
+        err!(format!("You can't register more than {MAX_PASSKEYS} passkeys"))
+    }
+    // Return the existing credentials to the clients to avoid double registering
+    let exclude_credentials = get_credentials(&passkeys)?.into_iter().map(|c| c.cred_id).collect();
+
+    let (challenge, state) = WebauthnConfig::load().generate_challenge_register_options(
+        user.uuid.as_bytes().to_vec(),
+        user.email,
+        user.name,
+        Some(exclude_credentials),
+        Some(UserVerificationPolicy::Required),
+        None,
+    )?;
+
+    // Passkeys need to be discoverable, since the user isn't known when the login starts
+    let mut options = serde_json::to_value(challenge.public_key)?;
+    options["authenticatorSelection"] = json!({
+        "residentKey": "required",
+        "requireResidentKey": true,
+        "userVerification": "required",
+    });
+
+    let claims = generate_webauthn_state_claims(user.uuid, serde_json::to_string(&state)?);
+    Ok(Json(json!({
+        "Options": options,
+        "Token": encode_jwt(&claims),
+        "Object": "webauthnCredentialCreateOptions",
+    })))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct PasskeyCreateData {
+    DeviceResponse: RegisterPublicKeyCredentialCopy,
+    Name: String,
+    Token: String,
+    SupportsPrf: bool,
+    EncryptedUserKey: Option<String>,
+    EncryptedPublicKey: Option<String>,
+    EncryptedPrivateKey: Option<String>,
+}
+
+#[post("/webauthn", data = "<data>")]
+async fn post_passkey(data: JsonUpcase<PasskeyCreateData>, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    check_passkeys_enabled()?;
+    let data: PasskeyCreateData = data.into_inner().data;
+    let user = headers.user;
+
+    let state: RegistrationState = match decode_webauthn_state(&data.Token) {
+        Ok(claims) if claims.sub == user.uuid => serde_json::from_str(&claims.state)?,
+        _ => err!("The passkey registration has expired. Please try again"),
+    };
+
+    if data.Name.trim().is_empty() {
+        err!("A name is required for the passkey")
+    }
+    if WebAuthnCredential::find_all_by_user(&user.uuid, &mut conn).await.len() >= MAX_PASSKEYS {
+        err!(format!("You can't register more than {MAX_PASSKEYS} passkeys"))
+    }
+
+    let (credential, _data) =
+        WebauthnConfig::load().register_credential(&data.DeviceResponse.into(), &state, |_| Ok(false))?;
+
+    let mut passkey =
+        WebAuthnCredential::new(user.uuid.clone(), data.Name, serde_json::to_string(&credential)?, data.SupportsPrf);
+    if data.SupportsPrf {
+        passkey.encrypted_user_key = data.EncryptedUserKey;
+        passkey.encrypted_public_key = data.EncryptedPublicKey;
+        passkey.encrypted_private_key = data.EncryptedPrivateKey;
+    }
+    passkey.save(&mut conn).await
+}
+
+#[post("/webauthn/assertion-options", data = "<data>")]
+async fn post_passkey_assertion_options(
+    data: JsonUpcase<PasswordOrOtpData>,
+    headers: Headers,
+    mut conn: DbConn,
+) -> JsonResult {
+    check_passkeys_enabled()?;
+    let data: PasswordOrOtpData = data.into_inner().data;
+    let user = headers.user;
+
+    data.validate(&user, false, &mut conn).await?;
+
+    let credentials = get_credentials(&WebAuthnCredential::find_all_by_user(&user.uuid, &mut conn).await)?;
+    if credentials.is_empty() {
+        err!("No passkeys registered")
+    }
+
+    let (response, state) = WebauthnConfig::load().generate_challenge_authenticate_options(credentials, None)?;
+    let claims = generate_webauthn_state_claims(user.uuid, serde_json::to_string(&state)?);
+    Ok(Json(json!({
+        "Options": response.public_key,
+        "Token": encode_jwt(&claims),
+        "Object": "webAuthnLoginAssertionOptions",
+    })))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct PasskeyUpdateData {
+    DeviceResponse: PublicKeyCredentialCopy,
+    Token: String,
+    EncryptedUserKey: String,
+    EncryptedPublicKey: String,
+    EncryptedPrivateKey: String,
+}
+
+// Used by the clients to enable vault encryption for a passkey which supports PRF, but was registered without it
+#[put("/webauthn", data = "<data>")]
+async fn put_passkey(data: JsonUpcase<PasskeyUpdateData>, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    check_passkeys_enabled()?;
+    let data: PasskeyUpdateData = data.into_inner().data;
+
+    let claims = match decode_webauthn_state(&data.Token) {
+        Ok(claims) if claims.sub == headers.user.uuid => claims,
+        _ => err!("The passkey assertion has expired. Please try again"),
+    };
+
+    let (mut passkey, _) = validate_passkey_assertion(&claims.state, data.DeviceResponse, &mut conn).await?;
+    if passkey.user_uuid != headers.user.uuid {
+        err!("Passkey not found")
+    }
+    if !passkey.supports_prf {
+        err!("This passkey doesn't support encryption")
+    }
+
+    passkey.encrypted_user_key = Some(data.EncryptedUserKey);
+    passkey.encrypted_public_key = Some(data.EncryptedPublicKey);
+    passkey.encrypted_private_key = Some(data.EncryptedPrivateKey);
+    passkey.save(&mut conn).await
+}
+
+#[post("/webauthn/<uuid>/delete", data = "<data>")]
+async fn delete_passkey(
+    uuid: &str,
+    data: JsonUpcase<PasswordOrOtpData>,
+    headers: Headers,
+    mut conn: DbConn,
+) -> EmptyResult {
+    let data: PasswordOrOtpData = data.into_inner().data;
+    let user = headers.user;
+
+    data.validate(&user, true, &mut conn).await?;
+
+    let passkey = match WebAuthnCredential::find_by_uuid_and_user(uuid, &user.uuid, &mut conn).await {
+        Some(passkey) => passkey,
+        None => err!("Passkey not found"),
+    };
+    passkey.delete(&mut conn).await
+}
+
+/// Removes the vault keys of the passkeys which weren't part of a key rotation, they still hold the old user key.
+/// The passkeys can still be used to log in, and the clients can enable the vault unlock again.
+pub async fn disable_stale_vault_unlock(user_uuid: &str, rotated: &[String], conn: &mut DbConn) -> EmptyResult {
+    for mut passkey in WebAuthnCredential::find_all_by_user(user_uuid, conn).await {
+        if passkey.encrypted_user_key.is_none() || rotated.contains(&passkey.uuid) {
+            continue;
+        }
+        passkey.encrypted_user_key = None;
+        passkey.encrypted_public_key = None;
+        passkey.encrypted_private_key = None;
+        passkey.save(conn).await?;
+    }
+    Ok(())
+}
+
+/// Generates the options for a passkey login, used by the identity endpoint.
+/// No credentials are listed, so the authenticator offers all the discoverable credentials it has for this domain.
+pub async fn generate_passkey_login_options(conn: &mut DbConn) -> JsonResult {
+    check_passkeys_enabled()?;
+
+    let (response, state) = WebauthnConfig::load().generate_challenge_authenticate_options(Vec::new(), None)?;
+
+    WebAuthnLoginChallenge::purge_expired(conn).await?;
+    let challenge = WebAuthnLoginChallenge::new(serde_json::to_string(&state)?, LOGIN_CHALLENGE_VALIDITY_SECS);
+    challenge.save(conn).await?;
+
+    Ok(Json(json!({
+        "Options": response.public_key,
+        "Token": challenge.uuid,
+        "Object": "webAuthnLoginAssertionOptions",
+    })))
+}
+
+/// Validates a passkey login and returns the user and the passkey which was used.
+pub async fn validate_passkey_login(
+    token: &str,
+    device_response: &str,
+    conn: &mut DbConn,
+) -> ApiResult<(User, WebAuthnCredential)> {
+    check_passkeys_enabled()?;
+
+    // The challenge is removed before validating the assertion, so it can't be replayed even when the validation fails
+    let Some(challenge) = WebAuthnLoginChallenge::take(token, conn).await else {
+        err!("The passkey login has expired. Please try again")
+    };
+    let device_response: UpCase<PublicKeyCredentialCopy> = serde_json::from_str(device_response)?;
+
+    let (passkey, user) = validate_passkey_assertion(&challenge.state, device_response.data, conn).await?;
+    match user {
+        Some(user) => Ok((user, passkey)),
+        None => err!("Passkey not found"),
+    }
+}
+
+/// Validates an assertion against the state of the ceremony and updates the signature counter of the passkey.
+/// The user is found using the user handle, which contains the uuid given during the registration.
+async fn validate_passkey_assertion(
+    state: &str,
+    device_response: PublicKeyCredentialCopy,
+    conn: &mut DbConn,
+) -> ApiResult<(WebAuthnCredential, Option<User>)> {
+    let rsp: PublicKeyCredential = device_response.into();
+
+    let user_uuid = match rsp.response.user_handle.as_ref().map(|h| String::from_utf8(h.0.clone())) {
+        Some(Ok(user_uuid)) => user_uuid,
+        _ => err!("Passkey not found"),
+    };
+    let passkeys = WebAuthnCredential::find_all_by_user(&user_uuid, conn).await;
+    let credentials = get_credentials(&passkeys)?;
+
+    // The state of a login doesn't contain any credentials, since the user wasn't known yet.
+    // The library only accepts credentials which are part of the state, so add the ones of this user.
+    let mut state: Value = serde_json::from_str(state)?;
+    state["credentials"] = serde_json::to_value(&credentials)?;
+    let state: AuthenticationState = serde_json::from_value(state)?;
+
+    let (cred_id, auth_data) = WebauthnConfig::load().authenticate_credential(&rsp, &state)?;
+
+    for (mut passkey, mut credential) in passkeys.into_iter().zip(credentials) {
+        if &credential.cred_id == cred_id {
+            credential.counter = auth_data.counter;
+            passkey.credential = serde_json::to_string(&credential)?;
+            passkey.save(conn).await?;
+
+            let user = User::find_by_uuid(&user_uuid, conn).await;
+            return Ok((passkey, user));
+        }
+    }
+
+    err!("Passkey not found")
+}
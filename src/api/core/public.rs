@@ -1,68 +1,133 @@
 use chrono::Utc;
 use rocket::{
     request::{self, FromRequest, Outcome},
-    Request, Route,
+    serde::json::Json,
+    Request, Route, State,
 };
+use serde_json::Value;
 
-use std::collections::HashSet;
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    api::{EmptyResult, JsonUpcase},
+    api::{
+        core::events::{encode_cursor, next_page_token, OrgEventQuery},
+        EmptyResult, JsonResult, JsonUpcase,
+    },
     auth,
-    db::{models::*, DbConn},
+    db::{models::*, DbConn, DbPool},
     mail, CONFIG,
 };
 
 pub fn routes() -> Vec<Route> {
-    routes![ldap_import]
+    routes![ldap_import, get_events, stream_events]
 }
 
+// The archival tools get bigger pages than the web vault
+const STREAM_PAGE_SIZE: i64 = 500;
+const STREAM_MAX_WAIT: u64 = 60;
+const STREAM_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
-struct OrgImportGroupData {
-    Name: String,
-    ExternalId: String,
-    MemberExternalIds: Vec<String>,
+pub struct OrgImportGroupData {
+    pub Name: String,
+    pub ExternalId: String,
+    pub MemberExternalIds: Vec<String>,
 }
 
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
-struct OrgImportUserData {
-    Email: String,
-    ExternalId: String,
-    Deleted: bool,
+pub struct OrgImportUserData {
+    pub Email: String,
+    pub ExternalId: String,
+    pub Deleted: bool,
 }
 
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
-struct OrgImportData {
-    Groups: Vec<OrgImportGroupData>,
-    Members: Vec<OrgImportUserData>,
-    OverwriteExisting: bool,
+pub struct OrgImportData {
+    pub Groups: Vec<OrgImportGroupData>,
+    pub Members: Vec<OrgImportUserData>,
+    pub OverwriteExisting: bool,
     // LargeImport: bool, // For now this will not be used, upstream uses this to prevent syncs of more then 2000 users or groups without the flag set.
 }
 
 #[post("/public/organization/import", data = "<data>")]
 async fn ldap_import(data: JsonUpcase<OrgImportData>, token: PublicToken, mut conn: DbConn) -> EmptyResult {
+    import_directory(&token.0, data.into_inner().data, &mut conn).await
+}
+
+// Upstream: https://github.com/bitwarden/server/blob/8a22c0479e987e756ce7412c48a732f9002f0a2d/src/Api/Public/Controllers/EventsController.cs
+// The newest events first, with the same filters as the organization events.
+#[get("/public/events?<query..>")]
+async fn get_events(query: OrgEventQuery, token: PublicToken, mut conn: DbConn) -> JsonResult {
+    let events = if !CONFIG.org_events_enabled() {
+        Vec::with_capacity(0)
+    } else {
+        Event::find_by_organization_filtered(&token.0, &query.to_filter()?, false, Event::PAGE_SIZE, &mut conn).await
+    };
+    let events_json: Vec<Value> = events.iter().map(|e| e.to_json()).collect();
+
+    Ok(Json(json!({
+        "object": "list",
+        "data": events_json,
+        "continuationToken": next_page_token(&events, Event::PAGE_SIZE),
+    })))
+}
+
+/// The events after the continuation token, the oldest first, for the tools which archive all the events.
+/// Without a new event, the request waits up to `wait` seconds for one before answering with an empty page.
+/// The continuation token of the answer is the one of the next request, even when the page is empty.
+#[get("/public/events/stream?<wait>&<query..>")]
+async fn stream_events(
+    wait: Option<u64>,
+    query: OrgEventQuery,
+    token: PublicToken,
+    pool: &State<DbPool>,
+) -> JsonResult {
+    let filter = query.to_filter()?;
+    let deadline = Instant::now() + Duration::from_secs(wait.unwrap_or(0).min(STREAM_MAX_WAIT));
+
+    let events = loop {
+        // The connection is given back to the pool while waiting
+        let events = if CONFIG.org_events_enabled() {
+            let mut conn = pool.get().await?;
+            Event::find_by_organization_filtered(&token.0, &filter, true, STREAM_PAGE_SIZE, &mut conn).await
+        } else {
+            Vec::with_capacity(0)
+        };
+        if !events.is_empty() || Instant::now() + STREAM_POLL_INTERVAL > deadline {
+            break events;
+        }
+        tokio::time::sleep(STREAM_POLL_INTERVAL).await;
+    };
+    let events_json: Vec<Value> = events.iter().map(|e| e.to_json()).collect();
+
+    Ok(Json(json!({
+        "object": "list",
+        "data": events_json,
+        "continuationToken": events.last().map(encode_cursor).or(query.continuation_token),
+    })))
+}
+
+/// Reconciles the members and groups of the organization with the ones of a directory.
+/// Used by the Directory Connector and the built-in directory sync.
+pub async fn import_directory(org_id: &str, data: OrgImportData, conn: &mut DbConn) -> EmptyResult {
     // Most of the logic for this function can be found here
     // https://github.com/bitwarden/server/blob/fd892b2ff4547648a276734fb2b14a8abae2c6f5/src/Core/Services/Implementations/OrganizationService.cs#L1797
 
-    let org_id = token.0;
-    let data = data.into_inner().data;
-
     for user_data in &data.Members {
         if user_data.Deleted {
             // If user is marked for deletion and it exists, revoke it
-            if let Some(mut user_org) =
-                UserOrganization::find_by_email_and_org(&user_data.Email, &org_id, &mut conn).await
-            {
+            if let Some(mut user_org) = UserOrganization::find_by_email_and_org(&user_data.Email, org_id, conn).await {
                 // Only revoke a user if it is not the last confirmed owner
                 let revoked = if user_org.atype == UserOrgType::Owner
                     && user_org.status == UserOrgStatus::Confirmed as i32
                 {
-                    if UserOrganization::count_confirmed_by_org_and_type(&org_id, UserOrgType::Owner, &mut conn).await
-                        <= 1
-                    {
+                    if UserOrganization::count_confirmed_by_org_and_type(org_id, UserOrgType::Owner, conn).await <= 1 {
                         warn!("Can't revoke the last owner");
                         false
                     } else {
@@ -74,30 +139,29 @@ async fn ldap_import(data: JsonUpcase<OrgImportData>, token: PublicToken, mut co
 
                 let ext_modified = user_org.set_external_id(Some(user_data.ExternalId.clone()));
                 if revoked || ext_modified {
-                    user_org.save(&mut conn).await?;
+                    user_org.save(conn).await?;
                 }
             }
         // If user is part of the organization, restore it
-        } else if let Some(mut user_org) =
-            UserOrganization::find_by_email_and_org(&user_data.Email, &org_id, &mut conn).await
+        } else if let Some(mut user_org) = UserOrganization::find_by_email_and_org(&user_data.Email, org_id, conn).await
         {
             let restored = user_org.restore();
             let ext_modified = user_org.set_external_id(Some(user_data.ExternalId.clone()));
             if restored || ext_modified {
-                user_org.save(&mut conn).await?;
+                user_org.save(conn).await?;
             }
         } else {
             // If user is not part of the organization
-            let user = match User::find_by_mail(&user_data.Email, &mut conn).await {
+            let user = match User::find_by_mail(&user_data.Email, conn).await {
                 Some(user) => user, // exists in vaultwarden
                 None => {
                     // User does not exist yet
                     let mut new_user = User::new(user_data.Email.clone());
-                    new_user.save(&mut conn).await?;
+                    new_user.save(conn).await?;
 
                     if !CONFIG.mail_enabled() {
                         let invitation = Invitation::new(&new_user.email);
-                        invitation.save(&mut conn).await?;
+                        invitation.save(conn).await?;
                     }
                     new_user
                 }
@@ -108,16 +172,16 @@ async fn ldap_import(data: JsonUpcase<OrgImportData>, token: PublicToken, mut co
                 UserOrgStatus::Accepted as i32 // Automatically mark user as accepted if no email invites
             };
 
-            let mut new_org_user = UserOrganization::new(user.uuid.clone(), org_id.clone());
+            let mut new_org_user = UserOrganization::new(user.uuid.clone(), org_id.to_string());
             new_org_user.set_external_id(Some(user_data.ExternalId.clone()));
             new_org_user.access_all = false;
             new_org_user.atype = UserOrgType::User as i32;
             new_org_user.status = user_org_status;
 
-            new_org_user.save(&mut conn).await?;
+            new_org_user.save(conn).await?;
 
             if CONFIG.mail_enabled() {
-                let (org_name, org_email) = match Organization::find_by_uuid(&org_id, &mut conn).await {
+                let (org_name, org_email) = match Organization::find_by_uuid(org_id, conn).await {
                     Some(org) => (org.name, org.billing_email),
                     None => err!("Error looking up organization"),
                 };
@@ -125,7 +189,7 @@ async fn ldap_import(data: JsonUpcase<OrgImportData>, token: PublicToken, mut co
                 mail::send_invite(
                     &user_data.Email,
                     &user.uuid,
-                    Some(org_id.clone()),
+                    Some(org_id.to_string()),
                     Some(new_org_user.uuid),
                     &org_name,
                     Some(org_email),
@@ -137,23 +201,26 @@ async fn ldap_import(data: JsonUpcase<OrgImportData>, token: PublicToken, mut co
 
     if CONFIG.org_groups_enabled() {
         for group_data in &data.Groups {
-            let group_uuid = match Group::find_by_external_id(&group_data.ExternalId, &mut conn).await {
+            let group_uuid = match Group::find_by_external_id(&group_data.ExternalId, conn).await {
                 Some(group) => group.uuid,
                 None => {
-                    let mut group =
-                        Group::new(org_id.clone(), group_data.Name.clone(), false, Some(group_data.ExternalId.clone()));
-                    group.save(&mut conn).await?;
+                    let mut group = Group::new(
+                        org_id.to_string(),
+                        group_data.Name.clone(),
+                        false,
+                        Some(group_data.ExternalId.clone()),
+                    );
+                    group.save(conn).await?;
                     group.uuid
                 }
             };
 
-            GroupUser::delete_all_by_group(&group_uuid, &mut conn).await?;
+            GroupUser::delete_all_by_group(&group_uuid, conn).await?;
 
             for ext_id in &group_data.MemberExternalIds {
-                if let Some(user_org) = UserOrganization::find_by_external_id_and_org(ext_id, &org_id, &mut conn).await
-                {
+                if let Some(user_org) = UserOrganization::find_by_external_id_and_org(ext_id, org_id, conn).await {
                     let mut group_user = GroupUser::new(group_uuid.clone(), user_org.uuid.clone());
-                    group_user.save(&mut conn).await?;
+                    group_user.save(conn).await?;
                 }
             }
         }
@@ -165,20 +232,19 @@ async fn ldap_import(data: JsonUpcase<OrgImportData>, token: PublicToken, mut co
     if data.OverwriteExisting {
         // Generate a HashSet to quickly verify if a member is listed or not.
         let sync_members: HashSet<String> = data.Members.into_iter().map(|m| m.ExternalId).collect();
-        for user_org in UserOrganization::find_by_org(&org_id, &mut conn).await {
+        for user_org in UserOrganization::find_by_org(org_id, conn).await {
             if let Some(ref user_external_id) = user_org.external_id {
                 if !sync_members.contains(user_external_id) {
                     if user_org.atype == UserOrgType::Owner && user_org.status == UserOrgStatus::Confirmed as i32 {
                         // Removing owner, check that there is at least one other confirmed owner
-                        if UserOrganization::count_confirmed_by_org_and_type(&org_id, UserOrgType::Owner, &mut conn)
-                            .await
+                        if UserOrganization::count_confirmed_by_org_and_type(org_id, UserOrgType::Owner, conn).await
                             <= 1
                         {
                             warn!("Can't delete the last owner");
                             continue;
                         }
                     }
-                    user_org.delete(&mut conn).await?;
+                    user_org.delete(conn).await?;
                 }
             }
         }
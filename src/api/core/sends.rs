@@ -3,7 +3,6 @@ use std::path::Path;
 use chrono::{DateTime, TimeDelta, Utc};
 use num_traits::ToPrimitive;
 use rocket::form::Form;
-use rocket::fs::NamedFile;
 use rocket::fs::TempFile;
 use rocket::serde::json::Json;
 use serde_json::Value;
@@ -12,14 +11,18 @@ use crate::{
     api::{ApiResult, EmptyResult, JsonResult, JsonUpcase, Notify, UpdateType},
     auth::{ClientIp, Headers, Host},
     db::{models::*, DbConn, DbPool},
-    util::{NumberOrString, SafeString},
+    util::{NumberOrString, RangedFile, SafeString},
     CONFIG,
 };
 
 const SEND_INACCESSIBLE_MSG: &str = "Send does not exist or is no longer available";
 
-// The max file size allowed by Bitwarden clients and add an extra 5% to avoid issues
-const SIZE_525_MB: i64 = 550_502_400;
+// The clients upload the files larger than this in blocks with an Azure specific API, which S3 doesn't support
+#[cfg(feature = "s3")]
+const MAX_PRESIGNED_UPLOAD_SIZE: i64 = 256 * 1024 * 1024;
+// The time the clients have to start the upload of a Send file to the S3 bucket
+#[cfg(feature = "s3")]
+const SEND_UPLOAD_PRESIGN_EXPIRATION: i64 = 3600;
 
 pub fn routes() -> Vec<rocket::Route> {
     routes![
@@ -34,7 +37,8 @@ pub fn routes() -> Vec<rocket::Route> {
         put_remove_password,
         download_send,
         post_send_file_v2,
-        post_send_file_v2_data
+        post_send_file_v2_data,
+        get_send_file_v2_upload
     ]
 }
 
@@ -106,6 +110,66 @@ async fn enforce_disable_hide_email_policy(data: &SendData, headers: &Headers, c
     Ok(())
 }
 
+/// Rejects Send files larger than the configured `send_file_size_limit`.
+fn enforce_send_file_size_limit(size: i64) -> EmptyResult {
+    let max_size = CONFIG.send_file_size_limit_bytes();
+    if size > max_size {
+        err_code!(
+            format!("Send file is too large. The maximum allowed size is {}", crate::util::get_display_size(max_size)),
+            413
+        );
+    }
+    Ok(())
+}
+
+/// Stores the uploaded file of a Send, either in the sends folder or in the configured S3 bucket.
+async fn save_send_file(data: &mut TempFile<'_>, send_uuid: &str, file_id: &str) -> EmptyResult {
+    #[cfg(feature = "s3")]
+    {
+        if crate::s3::sends_enabled() {
+            // Persist the upload to a temporary file first, since small uploads are only buffered in memory
+            let tmp_path = tokio::fs::canonicalize(&CONFIG.tmp_folder()).await?.join(crate::util::get_uuid());
+            if let Err(_err) = data.persist_to(&tmp_path).await {
+                data.move_copy_to(&tmp_path).await?
+            }
+
+            let res = crate::s3::upload_file(&crate::s3::send_file_key(send_uuid, file_id), &tmp_path).await;
+            tokio::fs::remove_file(&tmp_path).await.ok();
+            return res;
+        }
+    }
+
+    let folder_path = tokio::fs::canonicalize(&CONFIG.sends_folder()).await?.join(send_uuid);
+    let file_path = folder_path.join(file_id);
+    tokio::fs::create_dir_all(&folder_path).await?;
+
+    if let Err(_err) = data.persist_to(&file_path).await {
+        data.move_copy_to(file_path).await?
+    }
+    Ok(())
+}
+
+/// The upload type and URL the client uploads the Send file to.
+/// With S3, it's a pre-signed URL of the bucket so the file doesn't pass through Vaultwarden.
+/// The clients upload it like to Azure, with a single PUT for the files up to 256 MiB, which S3 accepts.
+fn send_file_upload(send_uuid: &str, file_id: &str, file_length: i64) -> ApiResult<(i32, String)> {
+    #[cfg(feature = "s3")]
+    {
+        if crate::s3::sends_enabled() && file_length <= MAX_PRESIGNED_UPLOAD_SIZE {
+            let key = crate::s3::send_file_key(send_uuid, file_id);
+            let Some(file_length) = file_length.to_u64() else {
+                err!("Invalid send length")
+            };
+            let url = crate::s3::presign_upload_url(&key, file_length, SEND_UPLOAD_PRESIGN_EXPIRATION)?;
+            return Ok((1, url));
+        }
+    }
+    #[cfg(not(feature = "s3"))]
+    let _ = file_length;
+
+    Ok((0, format!("/sends/{send_uuid}/file/{file_id}")))
+}
+
 fn create_send(data: SendData, user_uuid: String) -> ApiResult<Send> {
     let data_val = if data.Type == SendType::Text as i32 {
         data.Text
@@ -229,6 +293,8 @@ async fn post_send_file(data: Form<UploadData<'_>>, headers: Headers, mut conn:
 
     enforce_disable_hide_email_policy(&model, &headers, &mut conn).await?;
 
+    enforce_send_file_size_limit(size)?;
+
     let size_limit = match CONFIG.user_send_limit() {
         Some(0) => err!("File uploads are disabled"),
         Some(limit_kb) => {
@@ -241,9 +307,9 @@ async fn post_send_file(data: Form<UploadData<'_>>, headers: Headers, mut conn:
             if left <= 0 {
                 err!("Send storage limit reached! Delete some sends to free up space")
             }
-            i64::clamp(left, 0, SIZE_525_MB)
+            left
         }
-        None => SIZE_525_MB,
+        None => i64::MAX,
     };
 
     if size > size_limit {
@@ -256,13 +322,7 @@ async fn post_send_file(data: Form<UploadData<'_>>, headers: Headers, mut conn:
     }
 
     let file_id = crate::crypto::generate_send_id();
-    let folder_path = tokio::fs::canonicalize(&CONFIG.sends_folder()).await?.join(&send.uuid);
-    let file_path = folder_path.join(&file_id);
-    tokio::fs::create_dir_all(&folder_path).await?;
-
-    if let Err(_err) = data.persist_to(&file_path).await {
-        data.move_copy_to(file_path).await?
-    }
+    save_send_file(&mut data, &send.uuid, &file_id).await?;
 
     let mut data_value: Value = serde_json::from_str(&send.data)?;
     if let Some(o) = data_value.as_object_mut() {
@@ -288,7 +348,12 @@ async fn post_send_file(data: Form<UploadData<'_>>, headers: Headers, mut conn:
 
 // Upstream: https://github.com/bitwarden/server/blob/d0c793c95181dfb1b447eb450f85ba0bfd7ef643/src/Api/Controllers/SendsController.cs#L190
 #[post("/sends/file/v2", data = "<data>")]
-async fn post_send_file_v2(data: JsonUpcase<SendData>, headers: Headers, mut conn: DbConn) -> JsonResult {
+async fn post_send_file_v2(
+    data: JsonUpcase<SendData>,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
     enforce_disable_send_policy(&headers, &mut conn).await?;
 
     let data = data.into_inner().data;
@@ -307,6 +372,8 @@ async fn post_send_file_v2(data: JsonUpcase<SendData>, headers: Headers, mut con
         err!("Send size can't be negative")
     }
 
+    enforce_send_file_size_limit(file_length)?;
+
     let size_limit = match CONFIG.user_send_limit() {
         Some(0) => err!("File uploads are disabled"),
         Some(limit_kb) => {
@@ -319,9 +386,9 @@ async fn post_send_file_v2(data: JsonUpcase<SendData>, headers: Headers, mut con
             if left <= 0 {
                 err!("Send storage limit reached! Delete some sends to free up space")
             }
-            i64::clamp(left, 0, SIZE_525_MB)
+            left
         }
-        None => SIZE_525_MB,
+        None => i64::MAX,
     };
 
     if file_length > size_limit {
@@ -341,10 +408,54 @@ async fn post_send_file_v2(data: JsonUpcase<SendData>, headers: Headers, mut con
     send.data = serde_json::to_string(&data_value)?;
     send.save(&mut conn).await?;
 
+    let (upload_type, url) = send_file_upload(&send.uuid, &file_id, file_length)?;
+    if upload_type != 0 {
+        // The upload doesn't reach Vaultwarden, so the other devices are notified right away
+        nt.send_send_update(
+            UpdateType::SyncSendCreate,
+            &send,
+            &send.update_users_revision(&mut conn).await,
+            &headers.device.uuid,
+            &mut conn,
+        )
+        .await;
+    }
+
+    Ok(Json(json!({
+        "fileUploadType": upload_type, // 0 == Direct | 1 == Azure
+        "object": "send-fileUpload",
+        "url": url,
+        "sendResponse": send.to_json()
+    })))
+}
+
+// Called by the clients when the pre-signed upload URL expired before the upload
+// https://github.com/bitwarden/server/blob/d0c793c95181dfb1b447eb450f85ba0bfd7ef643/src/Api/Controllers/SendsController.cs#L222
+#[get("/sends/<send_uuid>/file/<file_id>")]
+async fn get_send_file_v2_upload(send_uuid: &str, file_id: &str, headers: Headers, mut conn: DbConn) -> JsonResult {
+    let Some(send) = Send::find_by_uuid(send_uuid, &mut conn).await else {
+        err!("Send not found. Unable to save the file.")
+    };
+    if send.user_uuid.as_ref() != Some(&headers.user.uuid) {
+        err!("Send doesn't belong to user");
+    }
+    if send.atype != SendType::File as i32 {
+        err!("Send content is not a file");
+    }
+
+    let data: Value = serde_json::from_str(&send.data)?;
+    if data["Id"].as_str() != Some(file_id) {
+        err!("Send file does not match send data.");
+    }
+    let Some(file_length) = data["Size"].as_i64() else {
+        err!("Invalid send length")
+    };
+
+    let (upload_type, url) = send_file_upload(send_uuid, file_id, file_length)?;
     Ok(Json(json!({
-        "fileUploadType": 0, // 0 == Direct | 1 == Azure
+        "fileUploadType": upload_type, // 0 == Direct | 1 == Azure
         "object": "send-fileUpload",
-        "url": format!("/sends/{}/file/{}", send.uuid, file_id),
+        "url": url,
         "sendResponse": send.to_json()
     })))
 }
@@ -374,13 +485,12 @@ async fn post_send_file_v2_data(
         err!("Send doesn't belong to user");
     }
 
-    let folder_path = tokio::fs::canonicalize(&CONFIG.sends_folder()).await?.join(send_uuid);
-    let file_path = folder_path.join(file_id);
-    tokio::fs::create_dir_all(&folder_path).await?;
+    let Some(size) = data.data.len().to_i64() else {
+        err!("Invalid send size");
+    };
+    enforce_send_file_size_limit(size)?;
 
-    if let Err(_err) = data.data.persist_to(&file_path).await {
-        data.data.move_copy_to(file_path).await?
-    }
+    save_send_file(&mut data.data, send_uuid, file_id).await?;
 
     nt.send_send_update(
         UpdateType::SyncSendCreate,
@@ -516,6 +626,22 @@ async fn post_access_file(
     .await;
 
     let token_claims = crate::auth::generate_send_claims(send_id, file_id);
+
+    #[cfg(feature = "s3")]
+    {
+        if crate::s3::sends_enabled() {
+            // Let the client download the file directly from the bucket.
+            // The pre-signed URL expires at the same moment the download token would have.
+            let key = crate::s3::send_file_key(send_id, file_id);
+            let url = crate::s3::presign_url(&reqwest::Method::GET, &key, token_claims.exp - token_claims.nbf)?;
+            return Ok(Json(json!({
+                "Object": "send-fileDownload",
+                "Id": file_id,
+                "Url": url
+            })));
+        }
+    }
+
     let token = crate::auth::encode_jwt(&token_claims);
     Ok(Json(json!({
         "Object": "send-fileDownload",
@@ -525,10 +651,10 @@ async fn post_access_file(
 }
 
 #[get("/sends/<send_id>/<file_id>?<t>")]
-async fn download_send(send_id: SafeString, file_id: SafeString, t: &str) -> Option<NamedFile> {
+async fn download_send(send_id: SafeString, file_id: SafeString, t: &str) -> Option<RangedFile> {
     if let Ok(claims) = crate::auth::decode_send(t) {
         if claims.sub == format!("{send_id}/{file_id}") {
-            return NamedFile::open(Path::new(&CONFIG.sends_folder()).join(send_id).join(file_id)).await.ok();
+            return RangedFile::open(&Path::new(&CONFIG.sends_folder()).join(send_id).join(file_id)).await.ok();
         }
     }
     None
@@ -10,7 +10,7 @@ use crate::{
     auth::{ClientIp, Headers},
     crypto,
     db::{
-        models::{EventType, TwoFactor, TwoFactorType},
+        models::{EventType, TwoFactor, TwoFactorType, User},
         DbConn,
     },
     util::NumberOrString,
@@ -142,7 +142,19 @@ pub async fn validate_totp_code(
     // The amount of steps back and forward in time
     // Also check if we need to disable time drifted TOTP codes.
     // If that is the case, we set the steps to 0 so only the current TOTP is valid.
-    let steps = i64::from(!CONFIG.authenticator_disable_time_drift());
+    let steps = if CONFIG.authenticator_disable_time_drift() {
+        0
+    } else {
+        CONFIG.authenticator_time_drift_steps()
+    };
+
+    // Codes of the last used time step and the ones before it can't be used again, the user keeps track of it
+    // in case the authenticator has been removed and added again. The reuse window extends this to the following steps.
+    let user_last_step = match User::find_by_uuid(user_uuid, conn).await {
+        Some(user) => user.totp_last_step,
+        None => err!("User not found"),
+    };
+    let last_step = twofactor.last_used.max(user_last_step) + CONFIG.authenticator_reuse_window();
 
     // Get the current system time in UNIX Epoch (UTC)
     let current_time = chrono::Utc::now();
@@ -157,7 +169,7 @@ pub async fn validate_totp_code(
         let generated = totp_custom::<Sha1>(30, 6, &decoded_secret, time);
 
         // Check the given code equals the generated and if the time_step is larger then the one last used.
-        if generated == totp_code && time_step > twofactor.last_used {
+        if generated == totp_code && time_step > last_step {
             // If the step does not equals 0 the time is drifted either server or client side.
             if step != 0 {
                 warn!("TOTP Time drift detected. The step offset is {}", step);
@@ -167,8 +179,9 @@ pub async fn validate_totp_code(
             // This will also save a newly created twofactor if the code is correct.
             twofactor.last_used = time_step;
             twofactor.save(conn).await?;
+            User::update_totp_last_step(user_uuid, time_step, conn).await?;
             return Ok(());
-        } else if generated == totp_code && time_step <= twofactor.last_used {
+        } else if generated == totp_code && time_step <= last_step {
             warn!("This TOTP or a TOTP code within {} steps back or forward has already been used!", steps);
             err!(
                 format!("Invalid TOTP code! Server time: {} IP: {}", current_time.format("%F %T UTC"), ip.ip),
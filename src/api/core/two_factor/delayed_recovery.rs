@@ -0,0 +1,266 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use data_encoding::BASE32;
+use rocket::Route;
+
+use crate::{
+    api::{
+        core::{log_user_event, two_factor::enforce_2fa_policy},
+        EmptyResult, JsonUpcase,
+    },
+    auth::ClientHeaders,
+    crypto,
+    db::{
+        models::{DeviceType, EventType, TwoFactor, TwoFactorType, User},
+        DbConn, DbPool,
+    },
+    error::{Error, MapResult},
+    mail, CONFIG,
+};
+
+// An alternative to the recovery code for users who lost it together with their 2FA device.
+// The removal of the two-step login methods is requested with the master password, confirmed with a code sent by email
+// and only executed after a cooling-off period. Any successful two-step login during that period cancels the recovery,
+// so a compromised master password and mailbox are not enough to remove the 2FA of an account which is still in use.
+
+pub fn routes() -> Vec<Route> {
+    routes![request_delayed_recovery, confirm_delayed_recovery]
+}
+
+/// Data stored in the TwoFactor table in the db
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DelayedRecoveryData {
+    /// Token sent by email to confirm the recovery
+    pub token: String,
+    /// UNIX timestamp of the request
+    pub requested_at: i64,
+    /// UNIX timestamp of the confirmation, the cooling-off period starts from here
+    pub confirmed_at: Option<i64>,
+    /// IP address of the request, included in the notifications
+    pub ip: String,
+}
+
+impl DelayedRecoveryData {
+    pub fn new(token: String, ip: String) -> Self {
+        Self {
+            token,
+            requested_at: Utc::now().timestamp(),
+            confirmed_at: None,
+            ip,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+
+    pub fn from_json(string: &str) -> Result<Self, Error> {
+        let res: Result<Self, crate::serde_json::Error> = serde_json::from_str(string);
+        match res {
+            Ok(x) => Ok(x),
+            Err(_) => err!("Could not decode DelayedRecoveryData from string"),
+        }
+    }
+
+    /// The moment the two-step login methods will be removed, if the recovery has been confirmed.
+    fn recovery_at(&self) -> Option<NaiveDateTime> {
+        self.confirmed_at.and_then(|confirmed_at| {
+            DateTime::from_timestamp(confirmed_at, 0)
+                .map(|dt| dt.naive_utc() + TimeDelta::try_hours(CONFIG.two_factor_recovery_delay_hours()).unwrap())
+        })
+    }
+}
+
+fn check_delayed_recovery_enabled() -> EmptyResult {
+    if CONFIG.two_factor_recovery_delay_hours() <= 0 {
+        err!("Delayed two-step login recovery is disabled")
+    }
+    if !CONFIG.mail_enabled() {
+        err!("Email is disabled for this server. Delayed two-step login recovery is not available")
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct DelayedRecoveryRequestData {
+    MasterPasswordHash: String,
+    Email: String,
+}
+
+#[post("/two-factor/recover-delayed", data = "<data>")]
+async fn request_delayed_recovery(
+    data: JsonUpcase<DelayedRecoveryRequestData>,
+    client_headers: ClientHeaders,
+    mut conn: DbConn,
+) -> EmptyResult {
+    check_delayed_recovery_enabled()?;
+    let data: DelayedRecoveryRequestData = data.into_inner().data;
+
+    let user = match User::find_by_mail(&data.Email, &mut conn).await {
+        Some(user) if user.check_valid_password(&data.MasterPasswordHash) => user,
+        _ => err!("Username or password is incorrect. Try again."),
+    };
+
+    if TwoFactor::find_by_user(&user.uuid, &mut conn).await.is_empty() {
+        err!("Two-step login is not enabled for this account")
+    }
+
+    // Only one recovery per user can be pending, a new request replaces the previous one
+    if let Some(tf) =
+        TwoFactor::find_by_user_and_type(&user.uuid, TwoFactorType::DelayedRecovery as i32, &mut conn).await
+    {
+        tf.delete(&mut conn).await?;
+    }
+
+    let recovery_data =
+        DelayedRecoveryData::new(crypto::encode_random_bytes::<20>(BASE32), client_headers.ip.ip.to_string());
+    let twofactor = TwoFactor::new(user.uuid, TwoFactorType::DelayedRecovery, recovery_data.to_json());
+    twofactor.save(&mut conn).await?;
+
+    mail::send_2fa_recovery_request(&user.email, &recovery_data.token, &recovery_data.ip).await
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct DelayedRecoveryConfirmData {
+    MasterPasswordHash: String,
+    Email: String,
+    Token: String,
+}
+
+#[post("/two-factor/recover-delayed/confirm", data = "<data>")]
+async fn confirm_delayed_recovery(data: JsonUpcase<DelayedRecoveryConfirmData>, mut conn: DbConn) -> EmptyResult {
+    check_delayed_recovery_enabled()?;
+    let data: DelayedRecoveryConfirmData = data.into_inner().data;
+
+    let user = match User::find_by_mail(&data.Email, &mut conn).await {
+        Some(user) if user.check_valid_password(&data.MasterPasswordHash) => user,
+        _ => err!("Username or password is incorrect. Try again."),
+    };
+
+    let mut twofactor = TwoFactor::find_by_user_and_type(&user.uuid, TwoFactorType::DelayedRecovery as i32, &mut conn)
+        .await
+        .map_res("No two-step login recovery has been requested")?;
+    let mut recovery_data = DelayedRecoveryData::from_json(&twofactor.data)?;
+
+    if recovery_data.confirmed_at.is_some() {
+        err!("The two-step login recovery has already been confirmed")
+    }
+
+    // The confirmation uses the same expiration time as the email 2FA tokens
+    let requested_at = DateTime::from_timestamp(recovery_data.requested_at, 0)
+        .expect("Delayed recovery timestamp invalid.")
+        .naive_utc();
+    let max_time = CONFIG.email_expiration_time() as i64;
+    if requested_at + TimeDelta::try_seconds(max_time).unwrap() < Utc::now().naive_utc() {
+        twofactor.delete(&mut conn).await?;
+        err!("Token has expired")
+    }
+
+    if !crypto::ct_eq(&recovery_data.token, data.Token.trim()) {
+        twofactor.delete(&mut conn).await?;
+        err!("Token is invalid, request a new two-step login recovery")
+    }
+
+    recovery_data.confirmed_at = Some(Utc::now().timestamp());
+    twofactor.data = recovery_data.to_json();
+    twofactor.save(&mut conn).await?;
+
+    let recovery_at = recovery_data.recovery_at().expect("Confirmed recovery without date");
+    mail::send_2fa_recovery_scheduled(&user.email, &recovery_at, &recovery_data.ip).await
+}
+
+/// Cancels a pending delayed recovery, called after a successful two-step login.
+pub async fn cancel_delayed_recovery(user: &User, conn: &mut DbConn) -> EmptyResult {
+    let twofactor =
+        match TwoFactor::find_by_user_and_type(&user.uuid, TwoFactorType::DelayedRecovery as i32, conn).await {
+            Some(tf) => tf,
+            None => return Ok(()),
+        };
+    let recovery_data = DelayedRecoveryData::from_json(&twofactor.data)?;
+    twofactor.delete(conn).await?;
+
+    // Unconfirmed requests would expire anyway, only notify about the scheduled ones
+    if recovery_data.confirmed_at.is_some() && CONFIG.mail_enabled() {
+        mail::send_2fa_recovery_canceled(&user.email).await?;
+    }
+    Ok(())
+}
+
+pub async fn delayed_2fa_recovery_job(pool: DbPool) {
+    debug!("Start delayed_2fa_recovery_job");
+    if CONFIG.two_factor_recovery_delay_hours() <= 0 {
+        return;
+    }
+
+    let mut conn = match pool.get().await {
+        Ok(conn) => conn,
+        _ => {
+            error!("Failed to get DB connection while processing delayed 2FA recoveries");
+            return;
+        }
+    };
+
+    let now = Utc::now().naive_utc();
+    let max_time = TimeDelta::try_seconds(CONFIG.email_expiration_time() as i64).unwrap();
+    for twofactor in TwoFactor::find_by_type(TwoFactorType::DelayedRecovery as i32, &mut conn).await {
+        let recovery_data = match DelayedRecoveryData::from_json(&twofactor.data) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Invalid delayed 2FA recovery of user {}: {e:#?}", twofactor.user_uuid);
+                continue;
+            }
+        };
+
+        let recovery_at = match recovery_data.recovery_at() {
+            Some(recovery_at) => recovery_at,
+            None => {
+                // Remove the requests which have never been confirmed
+                let expired = match DateTime::from_timestamp(recovery_data.requested_at, 0) {
+                    Some(requested_at) => requested_at.naive_utc() + max_time < now,
+                    None => true,
+                };
+                if expired {
+                    twofactor.delete(&mut conn).await.ok();
+                }
+                continue;
+            }
+        };
+        if recovery_at > now {
+            continue;
+        }
+
+        let mut user = match User::find_by_uuid(&twofactor.user_uuid, &mut conn).await {
+            Some(user) => user,
+            None => {
+                twofactor.delete(&mut conn).await.ok();
+                continue;
+            }
+        };
+        if let Err(e) = recover_user(&mut user, &mut conn).await {
+            error!("Error processing the delayed 2FA recovery of {}: {e:#?}", user.email);
+        }
+    }
+}
+
+// Removes all the two-step login methods of the user, like a recovery with the recovery code does
+async fn recover_user(user: &mut User, conn: &mut DbConn) -> EmptyResult {
+    let ip = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+    TwoFactor::delete_all_by_user(&user.uuid, conn).await?;
+    enforce_2fa_policy(user, &user.uuid, DeviceType::Server as i32, &ip, conn).await?;
+
+    log_user_event(EventType::UserRecovered2fa as i32, &user.uuid, DeviceType::Server as i32, &ip, conn).await;
+
+    // Remove the recovery code, not needed without twofactors
+    user.totp_recover = None;
+    user.save(conn).await?;
+
+    info!("Two-step login of {} has been removed after a delayed recovery", user.email);
+    if CONFIG.mail_enabled() {
+        mail::send_2fa_recovery_completed(&user.email).await?;
+    }
+    Ok(())
+}
@@ -5,8 +5,9 @@ use rocket::Route;
 
 use crate::{
     api::{
-        core::log_user_event, core::two_factor::_generate_recover_code, ApiResult, EmptyResult, JsonResult, JsonUpcase,
-        PasswordOrOtpData,
+        core::log_user_event,
+        core::two_factor::{_generate_recover_code, duo_oidc},
+        ApiResult, EmptyResult, JsonResult, JsonUpcase, PasswordOrOtpData,
     },
     auth::Headers,
     crypto,
@@ -15,7 +16,7 @@ use crate::{
         DbConn,
     },
     error::MapResult,
-    util::get_reqwest_client,
+    http_client::{get_reqwest_client, TracedSend},
     CONFIG,
 };
 
@@ -169,7 +170,13 @@ async fn activate_duo(data: JsonUpcase<EnableDuoData>, headers: Headers, mut con
     let (data, data_str) = if check_duo_fields_custom(&data) {
         let data_req: DuoData = data.into();
         let data_str = serde_json::to_string(&data_req)?;
-        duo_api_request("GET", "/auth/v2/check", "", &data_req).await.map_res("Failed to validate Duo credentials")?;
+        if CONFIG.duo_use_iframe() {
+            duo_api_request("GET", "/auth/v2/check", "", &data_req)
+                .await
+                .map_res("Failed to validate Duo credentials")?;
+        } else {
+            duo_oidc::validate_duo_credentials(&data_req.ik, &data_req.sk, &data_req.host).await?;
+        }
         (data_req.obscure(), data_str)
     } else {
         (DuoData::secret(), String::new())
@@ -217,7 +224,7 @@ async fn duo_api_request(method: &str, path: &str, params: &str, data: &DuoData)
         .basic_auth(username, Some(password))
         .header(header::USER_AGENT, "vaultwarden:Duo/1.0 (Rust)")
         .header(header::DATE, date)
-        .send()
+        .send_traced()
         .await?
         .error_for_status()?;
 
@@ -255,7 +262,7 @@ async fn get_user_duo_data(uuid: &str, conn: &mut DbConn) -> DuoStatus {
 }
 
 // let (ik, sk, ak, host) = get_duo_keys();
-async fn get_duo_keys_email(email: &str, conn: &mut DbConn) -> ApiResult<(String, String, String, String)> {
+pub async fn get_duo_keys_email(email: &str, conn: &mut DbConn) -> ApiResult<(String, String, String, String)> {
     let data = match User::find_by_mail(email, conn).await {
         Some(u) => get_user_duo_data(&u.uuid, conn).await.data(),
         _ => DuoData::global(),
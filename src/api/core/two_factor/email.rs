@@ -57,19 +57,24 @@ async fn send_email_login(data: JsonUpcase<SendEmailLoginData>, mut conn: DbConn
 }
 
 /// Generate the token, save the data for later verification and send email to user
+/// A new token replaces the previous one, so only the last token sent can be used.
 pub async fn send_token(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
     let type_ = TwoFactorType::Email as i32;
     let mut twofactor =
         TwoFactor::find_by_user_and_type(user_uuid, type_, conn).await.map_res("Two factor not found")?;
 
-    let generated_token = crypto::generate_email_token(CONFIG.email_token_size());
-
     let mut twofactor_data = EmailTokenData::from_json(&twofactor.data)?;
-    twofactor_data.set_token(generated_token);
+    let cooldown = twofactor_data.resend_cooldown();
+    if cooldown > 0 {
+        err!(format!("A code has already been sent. Please wait {cooldown} seconds before requesting a new one"))
+    }
+
+    let generated_token = crypto::generate_email_token(CONFIG.email_token_size());
+    twofactor_data.set_token(&generated_token);
     twofactor.data = twofactor_data.to_json();
     twofactor.save(conn).await?;
 
-    mail::send_token(&twofactor_data.email, &twofactor_data.last_token.map_res("Token is empty")?).await?;
+    mail::send_token(&twofactor_data.email, &generated_token).await?;
 
     Ok(())
 }
@@ -131,13 +136,14 @@ async fn send_email(data: JsonUpcase<SendEmailData>, headers: Headers, mut conn:
     }
 
     let generated_token = crypto::generate_email_token(CONFIG.email_token_size());
-    let twofactor_data = EmailTokenData::new(data.Email, generated_token);
+    let mut twofactor_data = EmailTokenData::new(data.Email);
+    twofactor_data.set_token(&generated_token);
 
     // Uses EmailVerificationChallenge as type to show that it's not verified yet.
     let twofactor = TwoFactor::new(user.uuid, TwoFactorType::EmailVerificationChallenge, twofactor_data.to_json());
     twofactor.save(&mut conn).await?;
 
-    mail::send_token(&twofactor_data.email, &twofactor_data.last_token.map_res("Token is empty")?).await?;
+    mail::send_token(&twofactor_data.email, &generated_token).await?;
 
     Ok(())
 }
@@ -171,12 +177,13 @@ async fn email(data: JsonUpcase<EmailData>, headers: Headers, mut conn: DbConn)
 
     let mut email_data = EmailTokenData::from_json(&twofactor.data)?;
 
-    let issued_token = match &email_data.last_token {
-        Some(t) => t,
-        _ => err!("No token available"),
-    };
-
-    if !crypto::ct_eq(issued_token, data.Token) {
+    if email_data.last_token.is_none() {
+        err!("No token available")
+    }
+    if email_data.is_token_expired() {
+        err!("Token has expired")
+    }
+    if !email_data.is_token_valid(&data.Token) {
         err!("Token is invalid")
     }
 
@@ -202,17 +209,29 @@ pub async fn validate_email_code_str(user_uuid: &str, token: &str, data: &str, c
     let mut twofactor = TwoFactor::find_by_user_and_type(user_uuid, TwoFactorType::Email as i32, conn)
         .await
         .map_res("Two factor not found")?;
-    let issued_token = match &email_data.last_token {
-        Some(t) => t,
-        _ => err!(
+    if email_data.last_token.is_none() {
+        err!(
             "No token available",
             ErrorEvent {
                 event: EventType::UserFailedLogIn2fa
             }
-        ),
-    };
+        )
+    }
+
+    if email_data.is_token_expired() {
+        email_data.reset_token();
+        twofactor.data = email_data.to_json();
+        twofactor.save(conn).await?;
+
+        err!(
+            "Token has expired",
+            ErrorEvent {
+                event: EventType::UserFailedLogIn2fa
+            }
+        )
+    }
 
-    if !crypto::ct_eq(issued_token, token) {
+    if !email_data.is_token_valid(token) {
         email_data.add_attempt();
         if email_data.attempts >= CONFIG.email_attempts_limit() {
             email_data.reset_token();
@@ -228,21 +247,11 @@ pub async fn validate_email_code_str(user_uuid: &str, token: &str, data: &str, c
         )
     }
 
+    // Tokens can only be used once
     email_data.reset_token();
     twofactor.data = email_data.to_json();
     twofactor.save(conn).await?;
 
-    let date = DateTime::from_timestamp(email_data.token_sent, 0).expect("Email token timestamp invalid.").naive_utc();
-    let max_time = CONFIG.email_expiration_time() as i64;
-    if date + TimeDelta::try_seconds(max_time).unwrap() < Utc::now().naive_utc() {
-        err!(
-            "Token has expired",
-            ErrorEvent {
-                event: EventType::UserFailedLogIn2fa
-            }
-        )
-    }
-
     Ok(())
 }
 
@@ -251,7 +260,7 @@ pub async fn validate_email_code_str(user_uuid: &str, token: &str, data: &str, c
 pub struct EmailTokenData {
     /// Email address where the token will be sent to. Can be different from account email.
     pub email: String,
-    /// Some(token): last valid token issued that has not been entered.
+    /// Some(hash): hash of the last valid token issued that has not been entered.
     /// None: valid token was used and removed.
     pub last_token: Option<String>,
     /// UNIX timestamp of token issue.
@@ -261,18 +270,25 @@ pub struct EmailTokenData {
 }
 
 impl EmailTokenData {
-    pub fn new(email: String, token: String) -> EmailTokenData {
+    pub fn new(email: String) -> EmailTokenData {
         EmailTokenData {
             email,
-            last_token: Some(token),
-            token_sent: Utc::now().timestamp(),
+            last_token: None,
+            token_sent: 0,
             attempts: 0,
         }
     }
 
-    pub fn set_token(&mut self, token: String) {
-        self.last_token = Some(token);
+    // Only a hash of the token is stored, the token itself is only sent by email
+    fn hash_token(token: &str) -> String {
+        crypto::sha256_hex(token.trim().as_bytes())
+    }
+
+    /// Replaces the previous token, which can't be used anymore.
+    pub fn set_token(&mut self, token: &str) {
+        self.last_token = Some(Self::hash_token(token));
         self.token_sent = Utc::now().timestamp();
+        self.attempts = 0;
     }
 
     pub fn reset_token(&mut self) {
@@ -280,6 +296,30 @@ impl EmailTokenData {
         self.attempts = 0;
     }
 
+    pub fn is_token_valid(&self, token: &str) -> bool {
+        match &self.last_token {
+            Some(hash) => crypto::ct_eq(hash, Self::hash_token(token)),
+            None => false,
+        }
+    }
+
+    pub fn is_token_expired(&self) -> bool {
+        let max_time = CONFIG.email_expiration_time() as i64;
+        match DateTime::from_timestamp(self.token_sent, 0) {
+            Some(date) => date.naive_utc() + TimeDelta::try_seconds(max_time).unwrap() < Utc::now().naive_utc(),
+            None => true,
+        }
+    }
+
+    /// Returns the number of seconds to wait before a new token can be sent, 0 if it can be sent right away.
+    pub fn resend_cooldown(&self) -> i64 {
+        if self.last_token.is_none() {
+            return 0;
+        }
+        let elapsed = Utc::now().timestamp() - self.token_sent;
+        (CONFIG.email_resend_cooldown() as i64 - elapsed).max(0)
+    }
+
     pub fn add_attempt(&mut self) {
         self.attempts += 1;
     }
@@ -301,7 +341,7 @@ pub async fn activate_email_2fa(user: &User, conn: &mut DbConn) -> EmptyResult {
     if user.verified_at.is_none() {
         err!("Auto-enabling of email 2FA failed because the users email address has not been verified!");
     }
-    let twofactor_data = EmailTokenData::new(user.email.clone(), String::new());
+    let twofactor_data = EmailTokenData::new(user.email.clone());
     let twofactor = TwoFactor::new(user.uuid.clone(), TwoFactorType::Email, twofactor_data.to_json());
     twofactor.save(conn).await
 }
@@ -358,4 +398,19 @@ mod tests {
         // If it's smaller than 3 characters it should only show asterisks.
         assert_eq!(result, "***@example.ext");
     }
+
+    #[test]
+    fn test_email_token_single_use() {
+        let mut data = EmailTokenData::new("bytes@example.ext".to_string());
+        assert!(!data.is_token_valid(""));
+
+        data.set_token("123456");
+        // Only the hash of the token should be stored
+        assert_ne!(data.last_token.as_deref(), Some("123456"));
+        assert!(data.is_token_valid("123456"));
+        assert!(!data.is_token_valid("654321"));
+
+        data.reset_token();
+        assert!(!data.is_token_valid("123456"));
+    }
 }
@@ -1,5 +1,6 @@
-use chrono::{TimeDelta, Utc};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
 use data_encoding::BASE32;
+use num_traits::FromPrimitive;
 use rocket::serde::json::Json;
 use rocket::Route;
 use serde_json::Value;
@@ -7,7 +8,7 @@ use serde_json::Value;
 use crate::{
     api::{
         core::{log_event, log_user_event},
-        EmptyResult, JsonResult, JsonUpcase, PasswordOrOtpData,
+        ApiResult, EmptyResult, JsonResult, JsonUpcase, PasswordOrOtpData,
     },
     auth::{ClientHeaders, Headers},
     crypto,
@@ -18,9 +19,13 @@ use crate::{
 };
 
 pub mod authenticator;
+pub mod delayed_recovery;
 pub mod duo;
+pub mod duo_oidc;
 pub mod email;
 pub mod protected_actions;
+pub mod recovery_codes;
+pub mod remember;
 pub mod webauthn;
 pub mod yubikey;
 
@@ -35,11 +40,14 @@ pub fn routes() -> Vec<Route> {
     ];
 
     routes.append(&mut authenticator::routes());
+    routes.append(&mut delayed_recovery::routes());
     routes.append(&mut duo::routes());
     routes.append(&mut email::routes());
     routes.append(&mut webauthn::routes());
     routes.append(&mut yubikey::routes());
     routes.append(&mut protected_actions::routes());
+    routes.append(&mut recovery_codes::routes());
+    routes.append(&mut remember::routes());
 
     routes
 }
@@ -94,8 +102,10 @@ async fn recover(data: JsonUpcase<RecoverTwoFactor>, client_headers: ClientHeade
         err!("Username or password is incorrect. Try again.")
     }
 
-    // Check if recovery code is correct
-    if !user.check_valid_recovery_code(&data.RecoveryCode) {
+    // Check if recovery code is correct, or one of the single-use recovery codes
+    if !user.check_valid_recovery_code(&data.RecoveryCode)
+        && !recovery_codes::consume_recovery_code(&user.uuid, &data.RecoveryCode, &mut conn).await?
+    {
         err!("Recovery code is incorrect. Try again.")
     }
 
@@ -155,9 +165,8 @@ async fn disable_twofactor(data: JsonUpcase<DisableTwoFactorData>, headers: Head
             .await;
     }
 
-    if TwoFactor::find_by_user(&user.uuid, &mut conn).await.is_empty() {
-        enforce_2fa_policy(&user, &user.uuid, headers.device.atype, &headers.ip.ip, &mut conn).await?;
-    }
+    // The removed provider could have been the only one required by an organization
+    enforce_2fa_policy(&user, &user.uuid, headers.device.atype, &headers.ip.ip, &mut conn).await?;
 
     Ok(Json(json!({
         "Enabled": false,
@@ -171,6 +180,51 @@ async fn disable_twofactor_put(data: JsonUpcase<DisableTwoFactorData>, headers:
     disable_twofactor(data, headers, conn).await
 }
 
+/// Validates the data of a TwoFactorAuthentication policy and returns the providers it requires.
+pub fn validate_policy_data(data: &Option<Value>) -> ApiResult<TwoFactorPolicyData> {
+    let policy_data = match data {
+        None | Some(Value::Null) => TwoFactorPolicyData::default(),
+        Some(data) => match serde_json::from_value::<crate::util::UpCase<TwoFactorPolicyData>>(data.clone()) {
+            Ok(policy_data) => policy_data.data,
+            Err(_) => err!("Invalid two-step login policy data"),
+        },
+    };
+
+    if policy_data.Providers.iter().any(|p| provider_name(*p).is_none()) {
+        err!("Invalid two-step login provider")
+    }
+    if policy_data.RememberMaxDays.is_some_and(|days| days <= 0) {
+        err!("The maximum two-step login remember duration must be at least one day")
+    }
+    Ok(policy_data)
+}
+
+fn provider_name(atype: i32) -> Option<&'static str> {
+    match TwoFactorType::from_i32(atype) {
+        Some(TwoFactorType::Authenticator) => Some("Authenticator app"),
+        Some(TwoFactorType::Email) => Some("Email"),
+        Some(TwoFactorType::Duo) => Some("Duo"),
+        Some(TwoFactorType::YubiKey) => Some("YubiKey OTP security key"),
+        Some(TwoFactorType::Webauthn) => Some("FIDO2 WebAuthn"),
+        _ => None,
+    }
+}
+
+/// Notifies a member revoked by the TwoFactorAuthentication policy,
+/// asking to enroll one of the providers required by the organization when it restricts them.
+async fn send_2fa_policy_mail(email: &str, org_name: &str, policy_data: &TwoFactorPolicyData) -> EmptyResult {
+    if !CONFIG.mail_enabled() {
+        return Ok(());
+    }
+
+    if policy_data.Providers.is_empty() {
+        mail::send_2fa_removed_from_org(email, org_name).await
+    } else {
+        let providers: Vec<&str> = policy_data.Providers.iter().filter_map(|p| provider_name(*p)).collect();
+        mail::send_2fa_provider_required_by_org(email, org_name, &providers).await
+    }
+}
+
 pub async fn enforce_2fa_policy(
     user: &User,
     act_uuid: &str,
@@ -178,31 +232,55 @@ pub async fn enforce_2fa_policy(
     ip: &std::net::IpAddr,
     conn: &mut DbConn,
 ) -> EmptyResult {
-    for member in UserOrganization::find_by_user_and_policy(&user.uuid, OrgPolicyType::TwoFactorAuthentication, conn)
-        .await
-        .into_iter()
+    let twofactors = TwoFactor::find_by_user(&user.uuid, conn).await;
+    let now = Utc::now().naive_utc();
+    for mut member in
+        UserOrganization::find_by_user_and_policy(&user.uuid, OrgPolicyType::TwoFactorAuthentication, conn).await
     {
         // Policy only applies to non-Owner/non-Admin members who have accepted joining the org
-        if member.atype < UserOrgType::Admin {
-            if CONFIG.mail_enabled() {
-                let org = Organization::find_by_uuid(&member.org_uuid, conn).await.unwrap();
-                mail::send_2fa_removed_from_org(&user.email, &org.name).await?;
+        if member.atype >= UserOrgType::Admin {
+            continue;
+        }
+
+        let policy_data =
+            match OrgPolicy::find_by_org_and_type(&member.org_uuid, OrgPolicyType::TwoFactorAuthentication, conn).await
+            {
+                Some(policy) => policy.twofactor_policy_data(),
+                None => continue,
+            };
+        if policy_data.is_satisfied_by(&twofactors) {
+            // The member complied with the policy during the grace period
+            if member.twofactor_grace_until.is_some() {
+                member.twofactor_grace_until = None;
+                member.twofactor_reminder_at = None;
+                member.save(conn).await?;
             }
-            let mut member = member;
-            member.revoke();
-            member.save(conn).await?;
-
-            log_event(
-                EventType::OrganizationUserRevoked as i32,
-                &member.uuid,
-                &member.org_uuid,
-                act_uuid,
-                device_type,
-                ip,
-                conn,
-            )
-            .await;
+            continue;
+        }
+
+        // Members in their grace period keep their access until it ends
+        if member.twofactor_grace_until.is_some_and(|until| until > now) {
+            continue;
         }
+
+        let org = Organization::find_by_uuid(&member.org_uuid, conn).await.unwrap();
+        send_2fa_policy_mail(&user.email, &org.name, &policy_data).await?;
+
+        member.revoke();
+        member.twofactor_grace_until = None;
+        member.twofactor_reminder_at = None;
+        member.save(conn).await?;
+
+        log_event(
+            EventType::OrganizationUserRevoked as i32,
+            &member.uuid,
+            &member.org_uuid,
+            act_uuid,
+            device_type,
+            ip,
+            conn,
+        )
+        .await;
     }
 
     Ok(())
@@ -210,34 +288,146 @@ pub async fn enforce_2fa_policy(
 
 pub async fn enforce_2fa_policy_for_org(
     org_uuid: &str,
+    policy_data: &TwoFactorPolicyData,
     act_uuid: &str,
     device_type: i32,
     ip: &std::net::IpAddr,
     conn: &mut DbConn,
 ) -> EmptyResult {
     let org = Organization::find_by_uuid(org_uuid, conn).await.unwrap();
-    for member in UserOrganization::find_confirmed_by_org(org_uuid, conn).await.into_iter() {
+    let grace_days = CONFIG.two_factor_policy_grace_days();
+    for mut member in UserOrganization::find_confirmed_by_org(org_uuid, conn).await.into_iter() {
         // Don't enforce the policy for Admins and Owners.
-        if member.atype < UserOrgType::Admin && TwoFactor::find_by_user(&member.user_uuid, conn).await.is_empty() {
-            if CONFIG.mail_enabled() {
-                let user = User::find_by_uuid(&member.user_uuid, conn).await.unwrap();
-                mail::send_2fa_removed_from_org(&user.email, &org.name).await?;
+        if member.atype >= UserOrgType::Admin
+            || policy_data.is_satisfied_by(&TwoFactor::find_by_user(&member.user_uuid, conn).await)
+        {
+            continue;
+        }
+        let user = User::find_by_uuid(&member.user_uuid, conn).await.unwrap();
+
+        // With a grace period the members are reminded to enable two-step login,
+        // and only revoked by the twofactor_policy_grace_job when it has passed
+        if grace_days > 0 {
+            if member.twofactor_grace_until.is_none() {
+                let now = Utc::now().naive_utc();
+                let Some(grace_until) = TimeDelta::try_days(grace_days).and_then(|grace| now.checked_add_signed(grace))
+                else {
+                    err!("Invalid two-step login grace period")
+                };
+                member.twofactor_grace_until = Some(grace_until);
+                member.twofactor_reminder_at = Some(now);
+                member.save(conn).await?;
+
+                send_2fa_policy_reminder(&user.email, &org.name, &grace_until, policy_data).await?;
             }
-            let mut member = member;
-            member.revoke();
-            member.save(conn).await?;
-
-            log_event(
-                EventType::OrganizationUserRevoked as i32,
-                &member.uuid,
-                org_uuid,
-                act_uuid,
-                device_type,
-                ip,
-                conn,
-            )
+            continue;
+        }
+
+        send_2fa_policy_mail(&user.email, &org.name, policy_data).await?;
+
+        member.revoke();
+        member.save(conn).await?;
+
+        log_event(EventType::OrganizationUserRevoked as i32, &member.uuid, org_uuid, act_uuid, device_type, ip, conn)
             .await;
+    }
+
+    Ok(())
+}
+
+async fn send_2fa_policy_reminder(
+    email: &str,
+    org_name: &str,
+    grace_until: &NaiveDateTime,
+    policy_data: &TwoFactorPolicyData,
+) -> EmptyResult {
+    if !CONFIG.mail_enabled() {
+        return Ok(());
+    }
+
+    let providers: Vec<&str> = policy_data.Providers.iter().filter_map(|p| provider_name(*p)).collect();
+    mail::send_2fa_policy_reminder(email, org_name, grace_until, &providers).await
+}
+
+pub async fn twofactor_policy_grace_job(pool: DbPool) {
+    debug!("Start twofactor_policy_grace_job");
+
+    let mut conn = match pool.get().await {
+        Ok(conn) => conn,
+        _ => {
+            error!("Failed to get DB connection in twofactor_policy_grace_job()");
+            return;
         }
+    };
+
+    for member in UserOrganization::find_in_twofactor_grace(&mut conn).await {
+        let member_uuid = member.uuid.clone();
+        if let Err(e) = process_twofactor_grace(member, &mut conn).await {
+            error!("Error processing the two-step login grace period of member {member_uuid}: {e:#?}");
+        }
+    }
+}
+
+async fn process_twofactor_grace(mut member: UserOrganization, conn: &mut DbConn) -> EmptyResult {
+    let now = Utc::now().naive_utc();
+    let policy_data =
+        match OrgPolicy::find_by_org_and_type(&member.org_uuid, OrgPolicyType::TwoFactorAuthentication, conn).await {
+            Some(policy) if policy.enabled => Some(policy.twofactor_policy_data()),
+            _ => None,
+        };
+    let twofactors = TwoFactor::find_by_user(&member.user_uuid, conn).await;
+
+    // The grace period isn't needed anymore when the policy has been disabled, the member doesn't fall under it or complied with it
+    let (Some(policy_data), Some(grace_until)) = (policy_data, member.twofactor_grace_until) else {
+        member.twofactor_grace_until = None;
+        member.twofactor_reminder_at = None;
+        return member.save(conn).await;
+    };
+    if member.atype >= UserOrgType::Admin
+        || member.status != UserOrgStatus::Confirmed as i32
+        || policy_data.is_satisfied_by(&twofactors)
+    {
+        member.twofactor_grace_until = None;
+        member.twofactor_reminder_at = None;
+        return member.save(conn).await;
+    }
+
+    let (Some(user), Some(org)) =
+        (User::find_by_uuid(&member.user_uuid, conn).await, Organization::find_by_uuid(&member.org_uuid, conn).await)
+    else {
+        err!("User or organization not found")
+    };
+
+    if grace_until <= now {
+        send_2fa_policy_mail(&user.email, &org.name, &policy_data).await?;
+
+        member.revoke();
+        member.twofactor_grace_until = None;
+        member.twofactor_reminder_at = None;
+        member.save(conn).await?;
+
+        log_event(
+            EventType::OrganizationUserRevoked as i32,
+            &member.uuid,
+            &member.org_uuid,
+            &user.uuid,
+            DeviceType::Server as i32,
+            &std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
+            conn,
+        )
+        .await;
+        return Ok(());
+    }
+
+    // Remind the member once a day
+    let reminder_due = match member.twofactor_reminder_at {
+        Some(reminder_at) => reminder_at + TimeDelta::try_days(1).unwrap() <= now,
+        None => true,
+    };
+    if reminder_due {
+        send_2fa_policy_reminder(&user.email, &org.name, &grace_until, &policy_data).await?;
+        member.twofactor_reminder_at = Some(now);
+        member.save(conn).await?;
     }
 
     Ok(())
@@ -0,0 +1,201 @@
+use chrono::{DateTime, Utc};
+use data_encoding::BASE32;
+use rocket::serde::json::Json;
+use rocket::Route;
+
+use crate::{
+    api::{core::log_user_event, EmptyResult, JsonResult, JsonUpcase, PasswordOrOtpData},
+    auth::Headers,
+    crypto,
+    db::{
+        models::{EventType, TwoFactor, TwoFactorType},
+        DbConn,
+    },
+    error::{Error, MapResult},
+    util::format_date,
+};
+
+// A printable sheet of single-use codes, as an alternative to the static recovery code.
+// Each code can be used once, either as the second factor of a login or to recover the account like the recovery code.
+// Only the hashes of the codes are stored, so they are returned to the user once, when the sheet is generated.
+
+const RECOVERY_CODES_COUNT: usize = 10;
+
+// Times a code is checked again when the sheet was changed by a concurrent request
+const CONSUME_ATTEMPTS: usize = 3;
+
+pub fn routes() -> Vec<Route> {
+    routes![get_recovery_codes, generate_recovery_codes, delete_recovery_codes]
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RecoveryCode {
+    /// SHA-256 of the normalized code
+    hash: String,
+    /// UNIX timestamp of its use
+    used_at: Option<i64>,
+}
+
+/// Data stored in the TwoFactor table in the db
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecoveryCodesData {
+    /// UNIX timestamp of the generation of the sheet
+    created_at: i64,
+    codes: Vec<RecoveryCode>,
+}
+
+impl RecoveryCodesData {
+    /// Generates a new sheet, returning it with the codes to show to the user.
+    fn generate() -> (Self, Vec<String>) {
+        let codes: Vec<String> = (0..RECOVERY_CODES_COUNT)
+            .map(|_| {
+                let code = crypto::encode_random_bytes::<10>(BASE32);
+                format!("{}-{}-{}-{}", &code[..4], &code[4..8], &code[8..12], &code[12..])
+            })
+            .collect();
+
+        let data = Self {
+            created_at: Utc::now().timestamp(),
+            codes: codes
+                .iter()
+                .map(|code| RecoveryCode {
+                    hash: hash_code(code),
+                    used_at: None,
+                })
+                .collect(),
+        };
+        (data, codes)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+
+    pub fn from_json(string: &str) -> Result<Self, Error> {
+        let res: Result<Self, crate::serde_json::Error> = serde_json::from_str(string);
+        match res {
+            Ok(x) => Ok(x),
+            Err(_) => err!("Could not decode RecoveryCodesData from string"),
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.codes.iter().filter(|c| c.used_at.is_none()).count()
+    }
+
+    fn to_json_status(&self) -> serde_json::Value {
+        json!({
+            "Enabled": true,
+            "CreationDate": DateTime::from_timestamp(self.created_at, 0).map(|dt| format_date(&dt.naive_utc())),
+            "Total": self.codes.len(),
+            "Remaining": self.remaining(),
+            "Object": "twoFactorRecoveryCodes",
+        })
+    }
+}
+
+// The codes are compared without the separators and whitespace, and case insensitively
+fn hash_code(code: &str) -> String {
+    let normalized: String = code.chars().filter(char::is_ascii_alphanumeric).collect::<String>().to_uppercase();
+    crypto::sha256_hex(normalized.as_bytes())
+}
+
+#[post("/two-factor/get-recovery-codes", data = "<data>")]
+async fn get_recovery_codes(data: JsonUpcase<PasswordOrOtpData>, headers: Headers, mut conn: DbConn) -> JsonResult {
+    let data: PasswordOrOtpData = data.into_inner().data;
+    data.validate(&headers.user, false, &mut conn).await?;
+
+    match TwoFactor::find_by_user_and_type(&headers.user.uuid, TwoFactorType::RecoveryCodes as i32, &mut conn).await {
+        Some(twofactor) => Ok(Json(RecoveryCodesData::from_json(&twofactor.data)?.to_json_status())),
+        None => Ok(Json(json!({
+            "Enabled": false,
+            "Object": "twoFactorRecoveryCodes",
+        }))),
+    }
+}
+
+#[post("/two-factor/recovery-codes", data = "<data>")]
+async fn generate_recovery_codes(
+    data: JsonUpcase<PasswordOrOtpData>,
+    headers: Headers,
+    mut conn: DbConn,
+) -> JsonResult {
+    let data: PasswordOrOtpData = data.into_inner().data;
+    let user = headers.user;
+    data.validate(&user, true, &mut conn).await?;
+
+    if TwoFactor::find_by_user(&user.uuid, &mut conn).await.is_empty() {
+        err!("Two-step login is not enabled for this account")
+    }
+
+    // A new sheet replaces the previous one, and invalidates its codes
+    if let Some(twofactor) =
+        TwoFactor::find_by_user_and_type(&user.uuid, TwoFactorType::RecoveryCodes as i32, &mut conn).await
+    {
+        twofactor.delete(&mut conn).await?;
+    }
+
+    let (recovery_data, codes) = RecoveryCodesData::generate();
+    let twofactor = TwoFactor::new(user.uuid.clone(), TwoFactorType::RecoveryCodes, recovery_data.to_json());
+    twofactor.save(&mut conn).await?;
+
+    log_user_event(EventType::UserUpdated2fa as i32, &user.uuid, headers.device.atype, &headers.ip.ip, &mut conn).await;
+
+    let mut result = recovery_data.to_json_status();
+    result["Codes"] = json!(codes);
+    Ok(Json(result))
+}
+
+#[delete("/two-factor/recovery-codes", data = "<data>")]
+async fn delete_recovery_codes(data: JsonUpcase<PasswordOrOtpData>, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    let data: PasswordOrOtpData = data.into_inner().data;
+    let user = headers.user;
+    data.validate(&user, true, &mut conn).await?;
+
+    let twofactor = TwoFactor::find_by_user_and_type(&user.uuid, TwoFactorType::RecoveryCodes as i32, &mut conn)
+        .await
+        .map_res("No recovery codes have been generated")?;
+    twofactor.delete(&mut conn).await?;
+
+    log_user_event(EventType::UserUpdated2fa as i32, &user.uuid, headers.device.atype, &headers.ip.ip, &mut conn).await;
+    Ok(())
+}
+
+/// Marks the matching unused code of the user as used, returns false when there is none.
+/// The sheet is only updated if no other request changed it in between, so concurrent logins can't use a code twice.
+pub async fn consume_recovery_code(user_uuid: &str, code: &str, conn: &mut DbConn) -> Result<bool, Error> {
+    let hash = hash_code(code);
+
+    for _ in 0..CONSUME_ATTEMPTS {
+        let twofactor =
+            match TwoFactor::find_by_user_and_type(user_uuid, TwoFactorType::RecoveryCodes as i32, conn).await {
+                Some(tf) => tf,
+                None => return Ok(false),
+            };
+        let mut recovery_data = RecoveryCodesData::from_json(&twofactor.data)?;
+
+        let recovery_code =
+            match recovery_data.codes.iter_mut().find(|c| c.used_at.is_none() && crypto::ct_eq(&c.hash, &hash)) {
+                Some(recovery_code) => recovery_code,
+                None => return Ok(false),
+            };
+        recovery_code.used_at = Some(Utc::now().timestamp());
+
+        if twofactor.update_data_if_unchanged(&recovery_data.to_json(), conn).await? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+pub async fn validate_recovery_code_login(user_uuid: &str, code: &str, conn: &mut DbConn) -> EmptyResult {
+    if !consume_recovery_code(user_uuid, code, conn).await? {
+        err!(
+            "Invalid recovery code",
+            ErrorEvent {
+                event: EventType::UserFailedLogIn2fa
+            }
+        )
+    }
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use chrono::TimeDelta;
+use rocket::serde::json::Json;
+use rocket::Route;
+use serde_json::Value;
+
+use crate::{
+    api::{core::log_user_event, EmptyResult},
+    auth::Headers,
+    db::{
+        models::{Device, EventType, OrgPolicy, OrgPolicyType, UserOrganization},
+        DbConn,
+    },
+    CONFIG,
+};
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        get_remembered_devices,
+        revoke_remembered_device,
+        post_revoke_remembered_device,
+        revoke_all_remembered_devices
+    ]
+}
+
+/// Returns how long a two-step login can be remembered for the user, if limited.
+/// This is the shortest of the global setting and the TwoFactorAuthentication policies of the user's organizations,
+/// which also apply to their owners and admins.
+pub async fn remember_max_duration(user_uuid: &str, conn: &mut DbConn) -> Option<TimeDelta> {
+    let mut max_days = Some(CONFIG.two_factor_remember_max_days()).filter(|days| *days > 0);
+
+    for member in
+        UserOrganization::find_by_user_and_policy(user_uuid, OrgPolicyType::TwoFactorAuthentication, conn).await
+    {
+        let policy_days =
+            match OrgPolicy::find_by_org_and_type(&member.org_uuid, OrgPolicyType::TwoFactorAuthentication, conn).await
+            {
+                Some(policy) => policy.twofactor_policy_data().RememberMaxDays,
+                None => None,
+            };
+        if let Some(days) = policy_days {
+            max_days = Some(max_days.map_or(days, |max_days| max_days.min(days)));
+        }
+    }
+
+    max_days.and_then(TimeDelta::try_days)
+}
+
+#[get("/two-factor/remember")]
+async fn get_remembered_devices(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let max_duration = remember_max_duration(&headers.user.uuid, &mut conn).await;
+    let devices_json: Vec<Value> = Device::find_remembered_by_user(&headers.user.uuid, &mut conn)
+        .await
+        .iter()
+        .map(|device| device.twofactor_remember_json(max_duration))
+        .collect();
+
+    Json(json!({
+        "Data": devices_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    }))
+}
+
+#[delete("/two-factor/remember/<device_id>")]
+async fn revoke_remembered_device(device_id: &str, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    let mut device = match Device::find_by_uuid_and_user(device_id, &headers.user.uuid, &mut conn).await {
+        Some(device) if device.twofactor_remember.is_some() => device,
+        _ => err!("Remembered device not found"),
+    };
+
+    device.delete_twofactor_remember();
+    device.save(&mut conn).await?;
+
+    log_user_event(
+        EventType::UserUpdated2fa as i32,
+        &headers.user.uuid,
+        headers.device.atype,
+        &headers.ip.ip,
+        &mut conn,
+    )
+    .await;
+    Ok(())
+}
+
+#[post("/two-factor/remember/<device_id>/delete")]
+async fn post_revoke_remembered_device(device_id: &str, headers: Headers, conn: DbConn) -> EmptyResult {
+    revoke_remembered_device(device_id, headers, conn).await
+}
+
+#[delete("/two-factor/remember")]
+async fn revoke_all_remembered_devices(headers: Headers, mut conn: DbConn) -> EmptyResult {
+    for mut device in Device::find_remembered_by_user(&headers.user.uuid, &mut conn).await {
+        device.delete_twofactor_remember();
+        device.save(&mut conn).await?;
+    }
+
+    log_user_event(
+        EventType::UserUpdated2fa as i32,
+        &headers.user.uuid,
+        headers.device.atype,
+        &headers.ip.ip,
+        &mut conn,
+    )
+    .await;
+    Ok(())
+}
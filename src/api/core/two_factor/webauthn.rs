@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use rocket::serde::json::Json;
 use rocket::Route;
 use serde_json::Value;
@@ -15,12 +16,19 @@ use crate::{
         DbConn,
     },
     error::Error,
-    util::NumberOrString,
+    util::{format_date, NumberOrString},
     CONFIG,
 };
 
 pub fn routes() -> Vec<Route> {
-    routes![get_webauthn, generate_webauthn_challenge, activate_webauthn, activate_webauthn_put, delete_webauthn,]
+    routes![
+        get_webauthn,
+        generate_webauthn_challenge,
+        activate_webauthn,
+        activate_webauthn_put,
+        rename_webauthn,
+        delete_webauthn,
+    ]
 }
 
 // Some old u2f structs still needed for migrating from u2f to WebAuthn
@@ -45,14 +53,14 @@ pub struct U2FRegistration {
     pub migrated: Option<bool>,
 }
 
-struct WebauthnConfig {
+pub struct WebauthnConfig {
     url: String,
     origin: Url,
     rpid: String,
 }
 
 impl WebauthnConfig {
-    fn load() -> Webauthn<Self> {
+    pub fn load() -> Webauthn<Self> {
         let domain = CONFIG.domain();
         let domain_origin = CONFIG.domain_origin();
         Webauthn::new(Self {
@@ -91,14 +99,31 @@ pub struct WebauthnRegistration {
     pub migrated: bool,
 
     pub credential: Credential,
+
+    // Metadata to help users tell their keys apart, not available for keys registered before it was stored
+    #[serde(default)]
+    pub created_at: Option<i64>,
+    #[serde(default)]
+    pub last_used: Option<i64>,
+    // The AAGUID identifies the model of the authenticator, it's all zeroes when the attestation isn't provided
+    #[serde(default)]
+    pub aaguid: Option<String>,
+    #[serde(default)]
+    pub user_verified: Option<bool>,
 }
 
 impl WebauthnRegistration {
     fn to_json(&self) -> Value {
+        let format_ts =
+            |ts: Option<i64>| ts.and_then(|ts| DateTime::from_timestamp(ts, 0)).map(|dt| format_date(&dt.naive_utc()));
         json!({
             "Id": self.id,
             "Name": self.name,
             "migrated": self.migrated,
+            "CreationDate": format_ts(self.created_at),
+            "LastUsedDate": format_ts(self.last_used),
+            "Aaguid": self.aaguid,
+            "UserVerified": self.user_verified,
         })
     }
 }
@@ -173,7 +198,7 @@ struct EnableWebauthnData {
 // This is copied from RegisterPublicKeyCredential to change the Response objects casing
 #[derive(Debug, Deserialize)]
 #[allow(non_snake_case)]
-struct RegisterPublicKeyCredentialCopy {
+pub struct RegisterPublicKeyCredentialCopy {
     pub Id: String,
     pub RawId: Base64UrlSafeData,
     pub Response: AuthenticatorAttestationResponseRawCopy,
@@ -185,6 +210,7 @@ struct RegisterPublicKeyCredentialCopy {
 #[allow(non_snake_case)]
 pub struct AuthenticatorAttestationResponseRawCopy {
     pub AttestationObject: Base64UrlSafeData,
+    #[serde(alias = "ClientDataJSON")]
     pub ClientDataJson: Base64UrlSafeData,
 }
 
@@ -218,6 +244,7 @@ pub struct PublicKeyCredentialCopy {
 #[allow(non_snake_case)]
 pub struct AuthenticatorAssertionResponseRawCopy {
     pub AuthenticatorData: Base64UrlSafeData,
+    #[serde(alias = "ClientDataJSON")]
     pub ClientDataJson: Base64UrlSafeData,
     pub Signature: Base64UrlSafeData,
     pub UserHandle: Option<Base64UrlSafeData>,
@@ -273,8 +300,9 @@ async fn activate_webauthn(data: JsonUpcase<EnableWebauthnData>, headers: Header
     };
 
     // Verify the credentials with the saved state
-    let (credential, _data) =
+    let (credential, auth_data) =
         WebauthnConfig::load().register_credential(&data.DeviceResponse.into(), &state, |_| Ok(false))?;
+    let aaguid = auth_data.acd.as_ref().and_then(|acd| uuid::Uuid::from_slice(&acd.aaguid).ok()).map(|a| a.to_string());
 
     let mut registrations: Vec<_> = get_webauthn_registrations(&user.uuid, &mut conn).await?.1;
     // TODO: Check for repeated ID's
@@ -284,6 +312,11 @@ async fn activate_webauthn(data: JsonUpcase<EnableWebauthnData>, headers: Header
         migrated: false,
 
         credential,
+
+        created_at: Some(Utc::now().timestamp()),
+        last_used: None,
+        aaguid,
+        user_verified: Some(auth_data.user_verified),
     });
 
     // Save the registrations and return them
@@ -307,6 +340,57 @@ async fn activate_webauthn_put(data: JsonUpcase<EnableWebauthnData>, headers: He
     activate_webauthn(data, headers, conn).await
 }
 
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct RenameWebauthnData {
+    Id: NumberOrString,
+    Name: String,
+    MasterPasswordHash: Option<String>,
+    Otp: Option<String>,
+}
+
+#[put("/two-factor/webauthn/name", data = "<data>")]
+async fn rename_webauthn(data: JsonUpcase<RenameWebauthnData>, headers: Headers, mut conn: DbConn) -> JsonResult {
+    let data: RenameWebauthnData = data.into_inner().data;
+    let user = headers.user;
+
+    PasswordOrOtpData {
+        MasterPasswordHash: data.MasterPasswordHash,
+        Otp: data.Otp,
+    }
+    .validate(&user, false, &mut conn)
+    .await?;
+
+    let name = data.Name.trim();
+    if name.is_empty() {
+        err!("A name is required for the security key")
+    }
+
+    let id = data.Id.into_i32()?;
+    let mut tf = match TwoFactor::find_by_user_and_type(&user.uuid, TwoFactorType::Webauthn as i32, &mut conn).await {
+        Some(tf) => tf,
+        None => err!("Webauthn data not found!"),
+    };
+
+    let mut registrations: Vec<WebauthnRegistration> = serde_json::from_str(&tf.data)?;
+    match registrations.iter_mut().find(|r| r.id == id) {
+        Some(reg) => reg.name = name.to_string(),
+        None => err!("Webauthn entry not found"),
+    }
+
+    tf.data = serde_json::to_string(&registrations)?;
+    tf.save(&mut conn).await?;
+
+    log_user_event(EventType::UserUpdated2fa as i32, &user.uuid, headers.device.atype, &headers.ip.ip, &mut conn).await;
+
+    let keys_json: Vec<Value> = registrations.iter().map(WebauthnRegistration::to_json).collect();
+    Ok(Json(json!({
+        "Enabled": tf.enabled,
+        "Keys": keys_json,
+        "Object": "twoFactorU2f"
+    })))
+}
+
 #[derive(Deserialize, Debug)]
 #[allow(non_snake_case)]
 struct DeleteU2FData {
@@ -425,6 +509,7 @@ pub async fn validate_webauthn_login(user_uuid: &str, response: &str, conn: &mut
     for reg in &mut registrations {
         if &reg.credential.cred_id == cred_id {
             reg.credential.counter = auth_data.counter;
+            reg.last_used = Some(Utc::now().timestamp());
 
             TwoFactor::new(user_uuid.to_string(), TwoFactorType::Webauthn, serde_json::to_string(&registrations)?)
                 .save(conn)
@@ -1,3 +1,7 @@
+use std::time::Duration;
+
+use data_encoding::{BASE64, HEXLOWER};
+use ring::hmac;
 use rocket::serde::json::Json;
 use rocket::Route;
 use serde_json::Value;
@@ -9,11 +13,13 @@ use crate::{
         EmptyResult, JsonResult, JsonUpcase, PasswordOrOtpData,
     },
     auth::Headers,
+    crypto,
     db::{
         models::{EventType, TwoFactor, TwoFactorType},
         DbConn,
     },
     error::{Error, MapResult},
+    http_client::{get_reqwest_client_builder, TracedSend},
     CONFIG,
 };
 
@@ -71,13 +77,95 @@ fn get_yubico_credentials() -> Result<(String, String), Error> {
 async fn verify_yubikey_otp(otp: String) -> EmptyResult {
     let (yubico_id, yubico_secret) = get_yubico_credentials()?;
 
-    let config = Config::default().set_client_id(yubico_id).set_key(yubico_secret);
-
     match CONFIG.yubico_server() {
-        Some(server) => verify_async(otp, config.set_api_hosts(vec![server])).await,
-        None => verify_async(otp, config).await,
+        Some(servers) => verify_otp_with_servers(&otp, &yubico_id, &yubico_secret, &servers).await,
+        None => {
+            let config = Config::default().set_client_id(yubico_id).set_key(yubico_secret);
+            verify_async(otp, config).await.map_res("Failed to verify OTP")
+        }
     }
-    .map_res("Failed to verify OTP")
+}
+
+// Statuses which only concern the server which returned them, the OTP can still be verified by another one of the cluster
+const YUBICO_RETRYABLE_STATUSES: [&str; 2] = ["BACKEND_ERROR", "NOT_ENOUGH_ANSWERS"];
+
+/// Verifies the OTP against self-hosted yubico-val servers, using the validation protocol 2.0.
+/// The servers are tried in the configured order, and the next one is only used when the previous one
+/// couldn't be reached or failed to process the request.
+async fn verify_otp_with_servers(otp: &str, client_id: &str, secret_key: &str, servers: &str) -> EmptyResult {
+    let key = match BASE64.decode(secret_key.as_bytes()) {
+        Ok(key) => hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &key),
+        Err(_) => err!("`YUBICO_SECRET_KEY` is not valid base64"),
+    };
+    let client = yubico_server_client()?;
+
+    for server in servers.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let nonce = crypto::encode_random_bytes::<16>(HEXLOWER);
+        match query_yubico_server(&client, server, &key, client_id, otp, &nonce).await {
+            Ok(status) if status == "OK" => return Ok(()),
+            Ok(status) if YUBICO_RETRYABLE_STATUSES.contains(&status.as_str()) => {
+                warn!("Yubico server {server} failed to verify the OTP: {status}");
+            }
+            Ok(status) => err!(format!("Failed to verify OTP: {status}")),
+            Err(e) => warn!("Error querying the Yubico server {server}: {e:#?}"),
+        }
+    }
+
+    err!("Failed to verify OTP, none of the Yubico servers could be used")
+}
+
+fn yubico_server_client() -> Result<reqwest::Client, Error> {
+    let mut builder = get_reqwest_client_builder().timeout(Duration::from_secs(CONFIG.yubico_server_timeout()));
+
+    if let Some(ca_cert) = CONFIG.yubico_server_ca_cert() {
+        let pem = std::fs::read(&ca_cert)?;
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+    }
+    if CONFIG.yubico_server_accept_invalid_certs() {
+        builder = builder.danger_accept_invalid_certs(true).danger_accept_invalid_hostnames(true);
+    }
+
+    Ok(builder.build()?)
+}
+
+async fn query_yubico_server(
+    client: &reqwest::Client,
+    server: &str,
+    key: &hmac::Key,
+    client_id: &str,
+    otp: &str,
+    nonce: &str,
+) -> Result<String, Error> {
+    let mut params = vec![("id", client_id), ("nonce", nonce), ("otp", otp)];
+    let signature = yubico_signature(key, &params);
+    params.push(("h", signature.as_str()));
+
+    let text = client.get(server).query(&params).send_traced().await?.error_for_status()?.text().await?;
+    let response: Vec<(&str, &str)> = text.lines().filter_map(|l| l.trim().split_once('=')).collect();
+    let field = |name: &str| response.iter().find(|(k, _)| *k == name).map(|(_, v)| *v);
+
+    // Without a valid signature the response could have been forged, the status can't be trusted
+    let unsigned: Vec<(&str, &str)> = response.iter().filter(|(k, _)| *k != "h").copied().collect();
+    match field("h") {
+        Some(h) if crypto::ct_eq(h, yubico_signature(key, &unsigned)) => (),
+        _ => err!("Invalid response signature"),
+    }
+
+    let status = field("status").map_res("Missing status in response")?;
+    if status == "OK" && (field("otp") != Some(otp) || field("nonce") != Some(nonce)) {
+        err!("Response doesn't match the request")
+    }
+
+    Ok(status.to_owned())
+}
+
+// The parameters are signed in alphabetical order, as `key1=value1&key2=value2`
+fn yubico_signature(key: &hmac::Key, params: &[(&str, &str)]) -> String {
+    let mut params = params.to_vec();
+    params.sort_unstable();
+    let data = params.iter().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>().join("&");
+
+    BASE64.encode(hmac::sign(key, data.as_bytes()).as_ref())
 }
 
 #[post("/two-factor/get-yubikey", data = "<data>")]
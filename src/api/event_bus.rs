@@ -0,0 +1,344 @@
+//
+// Internal event bus
+//
+// The API handlers emit a domain event once the change is saved, and each subscriber takes care of one of its side
+// effects: notifying the clients, writing the event log, calling the webhooks, exporting to the SIEM. New side effects
+// are added as subscribers, instead of in every handler making the change.
+//
+use std::net::IpAddr;
+
+use once_cell::sync::Lazy;
+
+use crate::{
+    api::{
+        core::{log_event, store_user_event},
+        UpdateType, WS_USERS,
+    },
+    auth::Headers,
+    db::{
+        models::{Cipher, EventType, User},
+        DbConn,
+    },
+    siem::{self, SecurityEvent, Severity},
+    webhook::{self, WebhookEvent},
+};
+
+/// Who made the change.
+pub struct EventContext {
+    pub user_uuid: String,
+    /// Empty when the event doesn't come from a device, like the failed logins and the admin actions
+    pub device_uuid: String,
+    pub device_type: i32,
+    pub ip: IpAddr,
+}
+
+impl From<&Headers> for EventContext {
+    fn from(headers: &Headers) -> Self {
+        Self {
+            user_uuid: headers.user.uuid.clone(),
+            device_uuid: headers.device.uuid.clone(),
+            device_type: headers.device.atype,
+            ip: headers.ip.ip,
+        }
+    }
+}
+
+pub enum DomainEvent<'a> {
+    /// A cipher, its attachments or its collections were changed. `collection_uuids` is set when the cipher was
+    /// shared or its collections changed, the clients then need to sync them too.
+    CipherUpdated {
+        cipher: &'a Cipher,
+        ut: UpdateType,
+        event_type: EventType,
+        collection_uuids: Option<Vec<String>>,
+    },
+    /// A cipher was deleted, or moved to the trash.
+    CipherDeleted {
+        cipher: &'a Cipher,
+        soft_delete: bool,
+    },
+    /// A cipher was moved to another folder, which only concerns the acting user.
+    CipherMoved {
+        cipher: &'a Cipher,
+    },
+    /// An event of the account of the user, like a login or a 2FA change, see `log_user_event`.
+    UserEvent {
+        event_type: i32,
+    },
+    /// An action of the admin panel. `target` is the user or the organization it changed.
+    AdminAction {
+        action: AdminAction,
+        target: Option<&'a str>,
+    },
+}
+
+#[derive(Copy, Clone)]
+pub enum AdminAction {
+    LoggedIn,
+    FailedLogIn,
+    UserDeleted,
+    UserDeauthorized,
+    UserDisabled,
+    UserEnabled,
+    User2faRemoved,
+    OrganizationDeleted,
+    ConfigUpdated,
+    ConfigDeleted,
+    DatabaseBackedUp,
+    DatabaseExported,
+}
+
+#[rocket::async_trait]
+pub trait Subscriber: Send + Sync {
+    async fn handle(&self, event: &DomainEvent<'_>, ctx: &EventContext, conn: &mut DbConn);
+}
+
+pub struct EventBus {
+    subscribers: Vec<Box<dyn Subscriber>>,
+}
+
+impl EventBus {
+    /// Runs the subscribers one after the other, in their order of registration.
+    /// The change is already saved, so they can't fail it, they log their own errors.
+    pub async fn emit(&self, event: DomainEvent<'_>, ctx: &EventContext, conn: &mut DbConn) {
+        for subscriber in &self.subscribers {
+            subscriber.handle(&event, ctx, conn).await;
+        }
+    }
+}
+
+pub static EVENT_BUS: Lazy<EventBus> = Lazy::new(|| EventBus {
+    subscribers: vec![
+        Box::new(ClientNotifier),
+        Box::new(EventLogWriter),
+        Box::new(WebhookSender),
+        Box::new(SecurityExporter),
+    ],
+});
+
+/// Emits an event for a change made by the user of the request.
+pub async fn emit(event: DomainEvent<'_>, headers: &Headers, conn: &mut DbConn) {
+    EVENT_BUS.emit(event, &EventContext::from(headers), conn).await;
+}
+
+// Updates the revision date of the users who can see the change, and sends them the WebSocket and push notifications
+struct ClientNotifier;
+
+#[rocket::async_trait]
+impl Subscriber for ClientNotifier {
+    async fn handle(&self, event: &DomainEvent<'_>, ctx: &EventContext, conn: &mut DbConn) {
+        match event {
+            DomainEvent::CipherUpdated {
+                cipher,
+                ut,
+                collection_uuids,
+                ..
+            } => {
+                let user_uuids = cipher.update_users_revision(conn).await;
+                WS_USERS
+                    .send_cipher_update(*ut, cipher, &user_uuids, &ctx.device_uuid, collection_uuids.clone(), conn)
+                    .await;
+            }
+            DomainEvent::CipherDeleted {
+                cipher,
+                soft_delete,
+            } => {
+                let ut = match soft_delete {
+                    true => UpdateType::SyncCipherUpdate,
+                    false => UpdateType::SyncCipherDelete,
+                };
+                let user_uuids = cipher.update_users_revision(conn).await;
+                WS_USERS.send_cipher_update(ut, cipher, &user_uuids, &ctx.device_uuid, None, conn).await;
+            }
+            DomainEvent::CipherMoved {
+                cipher,
+            } => {
+                let user_uuids = [ctx.user_uuid.clone()];
+                WS_USERS
+                    .send_cipher_update(UpdateType::SyncCipherUpdate, cipher, &user_uuids, &ctx.device_uuid, None, conn)
+                    .await;
+            }
+            DomainEvent::UserEvent {
+                ..
+            }
+            | DomainEvent::AdminAction {
+                ..
+            } => {}
+        }
+    }
+}
+
+// Only the changes to the organization ciphers are logged, the admin actions aren't
+struct EventLogWriter;
+
+#[rocket::async_trait]
+impl Subscriber for EventLogWriter {
+    async fn handle(&self, event: &DomainEvent<'_>, ctx: &EventContext, conn: &mut DbConn) {
+        let (cipher, event_type) = match event {
+            DomainEvent::CipherUpdated {
+                cipher,
+                event_type,
+                ..
+            } => (cipher, *event_type),
+            DomainEvent::CipherDeleted {
+                cipher,
+                soft_delete: true,
+            } => (cipher, EventType::CipherSoftDeleted),
+            DomainEvent::CipherDeleted {
+                cipher,
+                soft_delete: false,
+            } => (cipher, EventType::CipherDeleted),
+            DomainEvent::UserEvent {
+                event_type,
+            } => {
+                store_user_event(*event_type, &ctx.user_uuid, ctx.device_type, &ctx.ip, conn).await;
+                return;
+            }
+            DomainEvent::CipherMoved {
+                ..
+            }
+            | DomainEvent::AdminAction {
+                ..
+            } => return,
+        };
+
+        if let Some(org_uuid) = &cipher.organization_uuid {
+            log_event(event_type as i32, &cipher.uuid, org_uuid, &ctx.user_uuid, ctx.device_type, &ctx.ip, conn).await;
+        }
+    }
+}
+
+struct WebhookSender;
+
+#[rocket::async_trait]
+impl Subscriber for WebhookSender {
+    async fn handle(&self, event: &DomainEvent<'_>, ctx: &EventContext, conn: &mut DbConn) {
+        match event {
+            DomainEvent::CipherDeleted {
+                cipher,
+                soft_delete,
+            } => {
+                // Also sent for the personal ciphers, which have no organization event
+                webhook::send(
+                    WebhookEvent::CipherDeleted,
+                    json!({
+                        "cipherId": cipher.uuid,
+                        "organizationId": cipher.organization_uuid,
+                        "actingUserId": ctx.user_uuid,
+                        "permanent": !soft_delete,
+                        "deviceType": ctx.device_type,
+                        "ipAddress": ctx.ip.to_string(),
+                    }),
+                );
+            }
+            DomainEvent::UserEvent {
+                event_type,
+            } => {
+                webhook::send_user_event(*event_type, &ctx.user_uuid, ctx.device_type, &ctx.ip, conn).await;
+            }
+            _ => {}
+        }
+    }
+}
+
+// Sends the authentication and the admin events to the syslog target of the SIEM
+struct SecurityExporter;
+
+#[rocket::async_trait]
+impl Subscriber for SecurityExporter {
+    async fn handle(&self, event: &DomainEvent<'_>, ctx: &EventContext, conn: &mut DbConn) {
+        if !siem::enabled() {
+            return;
+        }
+
+        let (name, description, severity, failure, target) = match event {
+            DomainEvent::UserEvent {
+                event_type,
+            } => match user_event_details(*event_type) {
+                Some((name, description, severity, failure)) => (name, description, severity, failure, None),
+                None => return,
+            },
+            DomainEvent::AdminAction {
+                action,
+                target,
+            } => {
+                let (name, description, severity) = action.details();
+                let failure = matches!(action, AdminAction::FailedLogIn);
+                (name, description, severity, failure, *target)
+            }
+            _ => return,
+        };
+
+        let user_email = match event {
+            DomainEvent::UserEvent {
+                ..
+            } => User::find_by_uuid(&ctx.user_uuid, conn).await.map(|user| user.email),
+            _ => None,
+        };
+
+        siem::export(&SecurityEvent {
+            name,
+            description,
+            severity,
+            failure,
+            user_uuid: &ctx.user_uuid,
+            user_email: user_email.as_deref(),
+            target,
+            device_type: ctx.device_type,
+            ip: &ctx.ip,
+        });
+    }
+}
+
+// The name, the description, the severity and the outcome of the exported user events
+fn user_event_details(event_type: i32) -> Option<(&'static str, &'static str, Severity, bool)> {
+    let details = match event_type {
+        t if t == EventType::UserLoggedIn as i32 => ("user.login", "Logged in", Severity::Info, false),
+        t if t == EventType::UserFailedLogIn as i32 => ("user.login_failed", "Failed login", Severity::Warning, true),
+        t if t == EventType::UserFailedLogIn2fa as i32 => {
+            ("user.login_2fa_failed", "Failed two-step login", Severity::Warning, true)
+        }
+        t if t == EventType::UserChangedPassword as i32 => {
+            ("user.password_changed", "Changed the master password", Severity::Notice, false)
+        }
+        t if t == EventType::UserUpdated2fa as i32 => {
+            ("user.2fa_updated", "Updated the two-step login", Severity::Notice, false)
+        }
+        t if t == EventType::UserDisabled2fa as i32 => {
+            ("user.2fa_disabled", "Disabled the two-step login", Severity::Warning, false)
+        }
+        t if t == EventType::UserRecovered2fa as i32 => {
+            ("user.2fa_recovered", "Recovered the two-step login", Severity::Warning, false)
+        }
+        t if t == EventType::UserRotatedKeys as i32 => {
+            ("user.keys_rotated", "Rotated the encryption key", Severity::Notice, false)
+        }
+        _ => return None,
+    };
+    Some(details)
+}
+
+impl AdminAction {
+    fn details(self) -> (&'static str, &'static str, Severity) {
+        match self {
+            Self::LoggedIn => ("admin.login", "Admin logged in", Severity::Notice),
+            Self::FailedLogIn => ("admin.login_failed", "Failed admin login", Severity::Warning),
+            Self::UserDeleted => ("admin.user_deleted", "Admin deleted a user", Severity::Warning),
+            Self::UserDeauthorized => {
+                ("admin.user_deauthorized", "Admin deauthorized the sessions of a user", Severity::Notice)
+            }
+            Self::UserDisabled => ("admin.user_disabled", "Admin disabled a user", Severity::Notice),
+            Self::UserEnabled => ("admin.user_enabled", "Admin enabled a user", Severity::Notice),
+            Self::User2faRemoved => {
+                ("admin.user_2fa_removed", "Admin removed the two-step login of a user", Severity::Warning)
+            }
+            Self::OrganizationDeleted => {
+                ("admin.organization_deleted", "Admin deleted an organization", Severity::Warning)
+            }
+            Self::ConfigUpdated => ("admin.config_updated", "Admin updated the configuration", Severity::Notice),
+            Self::ConfigDeleted => ("admin.config_deleted", "Admin reset the configuration", Severity::Warning),
+            Self::DatabaseBackedUp => ("admin.database_backed_up", "Admin backed up the database", Severity::Notice),
+            Self::DatabaseExported => ("admin.database_exported", "Admin exported the database", Severity::Warning),
+        }
+    }
+}
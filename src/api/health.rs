@@ -0,0 +1,116 @@
+//
+// Health checks, for the probes of Kubernetes and the external monitoring
+//
+// `/healthz` only tells that the server answers. `/readyz` checks the dependencies needed to serve the requests and
+// answers with a 503 when one of them fails, with the result of each check.
+//
+use std::{
+    future::Future,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use data_encoding::HEXLOWER;
+use rocket::{http::Status, serde::json::Json, Route, State};
+use serde_json::Value;
+
+use crate::{
+    crypto::encode_random_bytes,
+    db::{self, DbPool},
+    mail, CONFIG,
+};
+
+// Short, a probe which doesn't get an answer in time fails anyway
+const DB_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn routes() -> Vec<Route> {
+    routes![healthz, readyz]
+}
+
+#[get("/healthz")]
+fn healthz() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+#[get("/readyz")]
+async fn readyz(pool: &State<DbPool>) -> (Status, Json<Value>) {
+    let checks = json!({
+        "database": check(check_database(pool)).await,
+        "migrations": migrations_check(),
+        "mail_queue": mail_queue_check(),
+        "storage": check(check_storage()).await,
+    });
+
+    let ready = checks.as_object().unwrap().values().all(|check| check["status"] != "fail");
+    let status = if ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    (
+        status,
+        Json(json!({
+            "status": if ready { "ok" } else { "fail" },
+            "checks": checks,
+        })),
+    )
+}
+
+async fn check(check: impl Future<Output = Result<(), String>>) -> Value {
+    let start = Instant::now();
+    let result = check.await;
+    let duration_ms = start.elapsed().as_millis() as u64;
+    match result {
+        Ok(()) => json!({ "status": "ok", "duration_ms": duration_ms }),
+        Err(error) => json!({ "status": "fail", "duration_ms": duration_ms, "error": error }),
+    }
+}
+
+// The endpoints don't need to be authenticated, so the errors only give their details in the logs
+async fn check_database(pool: &DbPool) -> Result<(), String> {
+    let mut conn = pool.get_timeout(DB_TIMEOUT).await.map_err(|e| {
+        warn!("Readiness check, error getting a database connection: {e:?}");
+        "No database connection is available".to_string()
+    })?;
+    match tokio::time::timeout(DB_TIMEOUT, db::ping(&mut conn)).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => {
+            warn!("Readiness check, error querying the database: {e:?}");
+            Err("The database query failed".to_string())
+        }
+        Err(_) => Err("The database didn't answer in time".to_string()),
+    }
+}
+
+// The pending migrations keep the server in maintenance, they're applied at startup otherwise
+fn migrations_check() -> Value {
+    if db::in_maintenance() {
+        json!({ "status": "fail", "error": "Database migrations are pending" })
+    } else {
+        json!({ "status": "ok" })
+    }
+}
+
+fn mail_queue_check() -> Value {
+    if !CONFIG.mail_enabled() {
+        json!({ "status": "disabled" })
+    } else if mail::mail_queue_alive() {
+        json!({ "status": "ok" })
+    } else {
+        json!({ "status": "fail", "error": "The mail queue worker stopped" })
+    }
+}
+
+// The folders where the uploads and the other data are written
+async fn check_storage() -> Result<(), String> {
+    let folders = [CONFIG.data_folder(), CONFIG.attachments_folder(), CONFIG.sends_folder(), CONFIG.tmp_folder()];
+    for folder in folders {
+        let path = Path::new(&folder).join(format!(".readyz-{}", encode_random_bytes::<8>(HEXLOWER)));
+        if let Err(e) = tokio::fs::write(&path, b"").await {
+            warn!("Readiness check, the folder `{folder}` is not writable: {e}");
+            return Err("A data folder is not writable".to_string());
+        }
+        tokio::fs::remove_file(&path).await.ok();
+    }
+    Ok(())
+}
@@ -1,7 +1,10 @@
 use std::{
     net::IpAddr,
-    sync::{Arc, Mutex},
-    time::{Duration, SystemTime},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use bytes::{Bytes, BytesMut};
@@ -10,25 +13,33 @@ use once_cell::sync::Lazy;
 use regex::Regex;
 use reqwest::{
     header::{self, HeaderMap, HeaderValue},
+    redirect::Policy,
     Client, Response,
 };
-use rocket::{http::ContentType, response::Redirect, Route};
-use tokio::{
-    fs::{create_dir_all, remove_file, symlink_metadata, File},
-    io::{AsyncReadExt, AsyncWriteExt},
-};
+use rocket::{http::ContentType, response::Redirect, Route, State};
+use serde_json::Value;
+use tokio::sync::{Semaphore, SemaphorePermit};
 
 use html5gum::{Emitter, HtmlString, InfallibleTokenizer, Readable, StringReader, Tokenizer};
 
 use crate::{
+    api::core::global_equivalent_domains,
+    config::generate_icon_service_url,
+    db::{
+        models::{IconDomain, IconStatus, User},
+        DbConn, DbPool,
+    },
     error::Error,
-    util::{get_reqwest_client_builder, Cached, CustomDnsResolver, CustomResolverError},
+    http_client::{get_reqwest_client_builder, ClientOptions, TracedSend},
+    icon_cache, icon_image,
+    util::{check_icon_url, Cached, CustomDnsResolver, CustomResolverError},
     CONFIG,
 };
 
 pub fn routes() -> Vec<Route> {
     match CONFIG.icon_service().as_str() {
         "internal" => routes![icon_internal],
+        _ if CONFIG.icon_service_proxy() => routes![icon_proxy],
         _ => routes![icon_external],
     }
 }
@@ -48,17 +59,64 @@ static CLIENT: Lazy<Client> = Lazy::new(|| {
     let icon_download_timeout = Duration::from_secs(CONFIG.icon_download_timeout());
     let pool_idle_timeout = Duration::from_secs(10);
     // Reuse the client between requests
-    get_reqwest_client_builder()
+    let builder = get_reqwest_client_builder()
         .cookie_provider(Arc::clone(&cookie_store))
         .timeout(icon_download_timeout)
         .pool_max_idle_per_host(5) // Configure the Hyper Pool to only have max 5 idle connections
         .pool_idle_timeout(pool_idle_timeout) // Configure the Hyper Pool to timeout after 10 seconds
         .dns_resolver(CustomDnsResolver::instance())
-        .default_headers(default_headers.clone())
+        .redirect(redirect_policy())
+        .default_headers(default_headers.clone());
+    // The proxy, minimum TLS version and CA bundle were checked with the config
+    CONFIG
+        .icon_client_options()
+        .apply(builder)
+        .expect("Invalid icon client settings")
         .build()
         .expect("Failed to build client")
 });
 
+// Limits the downloads running at the same time, across all the domains
+static DOWNLOAD_PERMITS: Lazy<Semaphore> =
+    Lazy::new(|| Semaphore::new(CONFIG.icon_max_concurrent_downloads() as usize));
+
+// Waits for a download permit, at most the download timeout
+async fn download_permit() -> Option<SemaphorePermit<'static>> {
+    let timeout = Duration::from_secs(CONFIG.icon_download_timeout());
+    match tokio::time::timeout(timeout, DOWNLOAD_PERMITS.acquire()).await {
+        Ok(Ok(permit)) => Some(permit),
+        _ => {
+            warn!("Too many icon downloads at the same time, see ICON_MAX_CONCURRENT_DOWNLOADS");
+            None
+        }
+    }
+}
+
+// Same as the default policy of reqwest
+const MAX_REDIRECTS: usize = 10;
+
+// Each redirect is checked like the first URL, as it could lead to the internal network
+fn redirect_policy() -> Policy {
+    Policy::custom(|attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if let Err(e) = check_icon_url(attempt.url()) {
+            attempt.error(e)
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+// The counters since the server started, for the admin diagnostics
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static NEGATIVE_HITS: AtomicU64 = AtomicU64::new(0);
+static DOWNLOADS: AtomicU64 = AtomicU64::new(0);
+static DOWNLOAD_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+// Only the last dead domains are listed in the diagnostics
+const DEAD_DOMAINS_LIMIT: i64 = 20;
+
 // Build Regex only once since this takes a lot of time.
 static ICON_SIZE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?x)(\d+)\D*(\d+)").unwrap());
 
@@ -87,7 +145,7 @@ fn icon_external(domain: &str) -> Option<Redirect> {
 }
 
 #[get("/<domain>/icon.png")]
-async fn icon_internal(domain: &str) -> Cached<(ContentType, Vec<u8>)> {
+async fn icon_internal(domain: &str, pool: &State<DbPool>) -> Cached<(ContentType, Vec<u8>)> {
     const FALLBACK_ICON: &[u8] = include_bytes!("../static/images/fallback-icon.png");
 
     if !is_valid_domain(domain) {
@@ -99,7 +157,7 @@ async fn icon_internal(domain: &str) -> Cached<(ContentType, Vec<u8>)> {
         );
     }
 
-    match get_icon(domain).await {
+    match get_icon(domain, pool).await {
         Some((icon, icon_type)) => {
             Cached::ttl((ContentType::new("image", icon_type), icon), CONFIG.icon_cache_ttl(), true)
         }
@@ -107,6 +165,77 @@ async fn icon_internal(domain: &str) -> Cached<(ContentType, Vec<u8>)> {
     }
 }
 
+// The icon is downloaded from the first external service which answers with a valid icon, and cached
+#[get("/<domain>/icon.png")]
+async fn icon_proxy(domain: &str) -> Cached<(ContentType, Vec<u8>)> {
+    const FALLBACK_ICON: &[u8] = include_bytes!("../static/images/fallback-icon.png");
+    let fallback =
+        || Cached::ttl((ContentType::new("image", "png"), FALLBACK_ICON.to_vec()), CONFIG.icon_cache_negttl(), true);
+
+    if !is_valid_domain(domain) {
+        warn!("Invalid domain: {}", domain);
+        return fallback();
+    }
+    if is_domain_blacklisted(domain) {
+        return fallback();
+    }
+
+    let store = icon_cache::selected_store();
+    match store.get(domain).await {
+        Ok(Some((icon, saved_at))) if is_fresh(saved_at) => {
+            let (icon, icon_type) = with_icon_type(icon);
+            return Cached::ttl((ContentType::new("image", icon_type), icon), CONFIG.icon_cache_ttl(), true);
+        }
+        Ok(_) => (),
+        Err(e) => warn!("Unable to read the cached icon of {domain}: {e:?}"),
+    }
+
+    let Some(_permit) = download_permit().await else {
+        return fallback();
+    };
+    let mut services = vec![CONFIG._icon_service_url()];
+    if let Some(fallbacks) = CONFIG.icon_service_fallbacks() {
+        services.extend(fallbacks.split(',').map(|fallback| generate_icon_service_url(fallback.trim())));
+    }
+    for service in services {
+        let url = service.replace("{}", domain);
+        match download_from_service(&url).await {
+            Ok((icon, icon_type)) => {
+                if let Err(e) = store.put(domain, &icon).await {
+                    warn!("Unable to save the icon of {domain}: {e:?}");
+                }
+                return Cached::ttl((ContentType::new("image", icon_type), icon), CONFIG.icon_cache_ttl(), true);
+            }
+            Err(e) => debug!("No valid icon from {url}: {e:?}"),
+        }
+    }
+    fallback()
+}
+
+async fn download_from_service(url: &str) -> Result<(Vec<u8>, &'static str), Error> {
+    let res = CLIENT.get(url).send_traced().await?.error_for_status()?;
+
+    let content_type =
+        res.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    if !content_type.trim_start().to_lowercase().starts_with("image/") {
+        err_silent!(format!("The icon service answered with the content type `{content_type}`"))
+    }
+    let max_size = CONFIG.icon_service_max_size() as usize * 1024;
+    if res.content_length().is_some_and(|length| length > max_size as u64) {
+        err_silent!("The icon is larger than `ICON_SERVICE_MAX_SIZE`")
+    }
+    // The length isn't always known, one more byte tells if the icon is too large
+    let icon = stream_to_bytes_limit(res, max_size + 1).await?;
+    if icon.len() > max_size {
+        err_silent!("The icon is larger than `ICON_SERVICE_MAX_SIZE`")
+    }
+
+    match icon_image::normalize(&icon) {
+        Some(icon) => Ok(icon),
+        None => err_silent!("The icon service answered with an invalid image"),
+    }
+}
+
 /// Returns if the domain provided is valid or not.
 ///
 /// This does some manual checks and makes use of Url to do some basic checking.
@@ -168,96 +297,258 @@ pub fn is_domain_blacklisted(domain: &str) -> bool {
     is_match
 }
 
-async fn get_icon(domain: &str) -> Option<(Vec<u8>, String)> {
-    let path = format!("{}/{}.png", CONFIG.icon_cache_folder(), domain);
-
-    // Check for expiration of negatively cached copy
-    if icon_is_negcached(&path).await {
-        return None;
-    }
+// The icon is served from the cache while it's fresh, and downloaded again unless the last attempts failed.
+// The state of the domains is kept in the database, so the failing sites aren't tried again after a restart
+// or by the other instances. A cached icon which is too old is still served when it can't be downloaded again.
+async fn get_icon(domain: &str, pool: &DbPool) -> Option<(Vec<u8>, String)> {
+    let store = icon_cache::selected_store();
+    let cached = match store.get(domain).await {
+        Ok(cached) => cached,
+        Err(e) => {
+            warn!("Unable to read the cached icon of {domain}: {e:?}");
+            None
+        }
+    };
+    let stale = match cached {
+        Some((icon, saved_at)) if is_fresh(saved_at) => {
+            CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+            return Some(with_icon_type(icon));
+        }
+        cached => cached.map(|(icon, _)| with_icon_type(icon)),
+    };
 
-    if let Some(icon) = get_cached_icon(&path).await {
-        let icon_type = match get_icon_type(&icon) {
-            Some(x) => x,
-            _ => "x-icon",
-        };
-        return Some((icon, icon_type.to_string()));
+    // An equivalent domain, like `amazon.com` for `amazon.de`, has the same icon
+    for equivalent in equivalent_domains(domain, pool).await {
+        match store.get(&equivalent).await {
+            Ok(Some((icon, saved_at))) if is_fresh(saved_at) => {
+                CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+                return Some(with_icon_type(icon));
+            }
+            Ok(_) => (),
+            Err(e) => warn!("Unable to read the cached icon of {equivalent}: {e:?}"),
+        }
     }
 
     if CONFIG.disable_icon_download() {
         return None;
     }
 
-    // Get the icon, or None in case of error
-    match download_icon(domain).await {
+    // The connection isn't held while downloading, which can take until the download timeout
+    let mut state = match pool.get().await {
+        Ok(mut conn) => IconDomain::find_by_domain(domain, &mut conn).await.unwrap_or_else(|| IconDomain::new(domain)),
+        Err(e) => {
+            warn!("Unable to load the icon state of {domain}: {e:?}");
+            return None;
+        }
+    };
+    if state.is_blocked() {
+        NEGATIVE_HITS.fetch_add(1, Ordering::Relaxed);
+        return stale;
+    }
+
+    let Some(_permit) = download_permit().await else {
+        return stale;
+    };
+    DOWNLOADS.fetch_add(1, Ordering::Relaxed);
+    let result = match get_icon_url(domain).await {
+        Ok(icon_result) => download_icon(domain, &icon_result).await.map_err(|e| (e, icon_result.reachable)),
+        Err(e) => Err((e, false)),
+    };
+    let icon = match result {
         Ok((icon, icon_type)) => {
-            save_icon(&path, &icon).await;
-            Some((icon.to_vec(), icon_type.unwrap_or("x-icon").to_string()))
+            if let Err(e) = store.put(domain, &icon).await {
+                warn!("Unable to save the icon of {domain}: {e:?}");
+            }
+            state.found();
+            Some((icon, icon_type.to_string()))
         }
-        Err(e) => {
+        Err((e, reachable)) => {
             // If this error comes from the custom resolver, this means this is a blacklisted domain
-            // or non global IP, don't save the miss file in this case to avoid leaking it
+            // or non global IP, don't save the state in this case to avoid leaking it
             if let Some(error) = CustomResolverError::downcast_ref(&e) {
                 warn!("{error}");
                 return None;
             }
 
             warn!("Unable to download icon: {:?}", e);
-            let miss_indicator = path + ".miss";
-            save_icon(&miss_indicator, &[]).await;
-            None
+            DOWNLOAD_FAILURES.fetch_add(1, Ordering::Relaxed);
+            if reachable {
+                state.missing(e.to_string());
+            } else {
+                state.unreachable(e.to_string());
+                if state.is_dead() {
+                    warn!(
+                        "{domain} couldn't be reached {} times, its icon isn't downloaded for a while",
+                        state.failures
+                    );
+                }
+            }
+            stale
+        }
+    };
+
+    match pool.get().await {
+        Ok(mut conn) => {
+            if let Err(e) = state.save(&mut conn).await {
+                warn!("Unable to save the icon state of {domain}: {e:?}");
+            }
         }
+        Err(e) => warn!("Unable to save the icon state of {domain}: {e:?}"),
     }
+    icon
 }
 
-async fn get_cached_icon(path: &str) -> Option<Vec<u8>> {
-    // Check for expiration of successfully cached copy
-    if icon_is_expired(path).await {
-        return None;
-    }
+static GLOBAL_DOMAIN_GROUPS: Lazy<Vec<Vec<String>>> = Lazy::new(global_equivalent_domains);
 
-    // Try to read the cached icon, and return it if it exists
-    if let Ok(mut f) = File::open(path).await {
-        let mut buffer = Vec::new();
+// The groups of the users are loaded again after this delay, to see their changes
+const USER_DOMAIN_GROUPS_TTL: Duration = Duration::from_secs(5 * 60);
+static USER_DOMAIN_GROUPS: Mutex<Option<(Instant, Arc<Vec<Vec<String>>>)>> = Mutex::new(None);
 
-        if f.read_to_end(&mut buffer).await.is_ok() {
-            return Some(buffer);
+async fn user_domain_groups(pool: &DbPool) -> Arc<Vec<Vec<String>>> {
+    if let Some((loaded_at, groups)) = &*USER_DOMAIN_GROUPS.lock().unwrap() {
+        if loaded_at.elapsed() < USER_DOMAIN_GROUPS_TTL {
+            return Arc::clone(groups);
+        }
+    }
+
+    let groups: Vec<Vec<String>> = match pool.get().await {
+        Ok(mut conn) => User::find_all_equivalent_domains(&mut conn)
+            .await
+            .iter()
+            .filter_map(|groups| serde_json::from_str::<Vec<Vec<String>>>(groups).ok())
+            .flatten()
+            .map(|group| group.iter().map(|domain| domain.trim().to_lowercase()).collect())
+            .collect(),
+        Err(e) => {
+            warn!("Unable to load the equivalent domains of the users: {e:?}");
+            Vec::new()
         }
+    };
+    let groups = Arc::new(groups);
+    *USER_DOMAIN_GROUPS.lock().unwrap() = Some((Instant::now(), Arc::clone(&groups)));
+    groups
+}
+
+// The domains equivalent to the domain, with the same subdomain: `www.amazon.com` for `www.amazon.de`
+async fn equivalent_domains(domain: &str, pool: &DbPool) -> Vec<String> {
+    let domain = domain.to_lowercase();
+    let mut equivalents = Vec::new();
+    add_equivalent_domains(&domain, &GLOBAL_DOMAIN_GROUPS, &mut equivalents);
+    if CONFIG.icon_user_equivalent_domains() {
+        add_equivalent_domains(&domain, &user_domain_groups(pool).await, &mut equivalents);
     }
+    equivalents
+}
 
-    None
+fn add_equivalent_domains(domain: &str, groups: &[Vec<String>], equivalents: &mut Vec<String>) {
+    for group in groups {
+        let subdomain = group.iter().find_map(|group_domain| match domain.strip_suffix(group_domain.as_str()) {
+            Some(subdomain) if subdomain.is_empty() || subdomain.ends_with('.') => Some(subdomain),
+            _ => None,
+        });
+        let Some(subdomain) = subdomain else {
+            continue;
+        };
+        for group_domain in group {
+            let equivalent = format!("{subdomain}{group_domain}");
+            if equivalent != domain && !equivalents.contains(&equivalent) && is_valid_domain(&equivalent) {
+                equivalents.push(equivalent);
+            }
+        }
+    }
 }
 
-async fn file_is_expired(path: &str, ttl: u64) -> Result<bool, Error> {
-    let meta = symlink_metadata(path).await?;
-    let modified = meta.modified()?;
-    let age = SystemTime::now().duration_since(modified)?;
+fn with_icon_type(icon: Vec<u8>) -> (Vec<u8>, String) {
+    let icon_type = icon_image::icon_type(&icon).unwrap_or("x-icon");
+    (icon, icon_type.to_string())
+}
 
-    Ok(ttl > 0 && ttl <= age.as_secs())
+// The icons saved in the future, with a clock skew, are fresh too
+fn is_fresh(saved_at: SystemTime) -> bool {
+    let ttl = CONFIG.icon_cache_ttl();
+    ttl == 0 || SystemTime::now().duration_since(saved_at).map_or(true, |age| age.as_secs() < ttl)
 }
 
-async fn icon_is_negcached(path: &str) -> bool {
-    let miss_indicator = path.to_owned() + ".miss";
-    let expired = file_is_expired(&miss_indicator, CONFIG.icon_cache_negttl()).await;
+/// The counters of the icon cache, and the domains by status, for the admin diagnostics.
+pub async fn icon_stats_json(conn: &mut DbConn) -> Value {
+    let mut domains = serde_json::Map::new();
+    for status in IconStatus::ALL {
+        domains.insert(status.name().to_string(), IconDomain::count_by_status(status, conn).await.into());
+    }
+    let dead_domains: Vec<Value> = IconDomain::find_by_status(IconStatus::Dead, DEAD_DOMAINS_LIMIT, conn)
+        .await
+        .iter()
+        .map(IconDomain::to_json)
+        .collect();
+
+    json!({
+        "enabled": CONFIG.icon_service() == "internal",
+        "backend": CONFIG.icon_cache_backend(),
+        "cache_hits": CACHE_HITS.load(Ordering::Relaxed),
+        "negative_hits": NEGATIVE_HITS.load(Ordering::Relaxed),
+        "downloads": DOWNLOADS.load(Ordering::Relaxed),
+        "download_failures": DOWNLOAD_FAILURES.load(Ordering::Relaxed),
+        "domains": domains,
+        "dead_domains": dead_domains,
+    })
+}
 
-    match expired {
-        // No longer negatively cached, drop the marker
-        Ok(true) => {
-            if let Err(e) = remove_file(&miss_indicator).await {
-                error!("Could not remove negative cache indicator for icon {:?}: {:?}", path, e);
-            }
-            false
+/// Queues the domains whose icon was already downloaded and the domain hints, which can be URIs, for the pre-warm
+/// job. Returns the number of pending domains.
+pub async fn queue_icon_prewarm(hints: &[String], conn: &mut DbConn) -> Result<i64, Error> {
+    IconDomain::set_found_pending(conn).await?;
+    for hint in hints.iter().map(|hint| hint.trim()).filter(|hint| !hint.is_empty()) {
+        let domain = match url::Url::parse(hint) {
+            Ok(url) => url.host_str().map(str::to_lowercase),
+            Err(_) => Some(hint.to_lowercase()),
+        };
+        let Some(domain) = domain.filter(|domain| is_valid_domain(domain) && !is_domain_blacklisted(domain)) else {
+            debug!("The icon hint '{hint}' isn't a valid domain");
+            continue;
+        };
+        // The domains which were already tried keep their state
+        if IconDomain::find_by_domain(&domain, conn).await.is_none() {
+            IconDomain::pending(&domain).save(conn).await?;
         }
-        // The marker hasn't expired yet.
-        Ok(false) => true,
-        // The marker is missing or inaccessible in some way.
-        Err(_) => false,
     }
+    Ok(IconDomain::count_by_status(IconStatus::Pending, conn).await)
 }
 
-async fn icon_is_expired(path: &str) -> bool {
-    let expired = file_is_expired(path, CONFIG.icon_cache_ttl()).await;
-    expired.unwrap_or(true)
+/// Downloads the icons of a batch of the pending domains, the ones which are still fresh in the cache aren't
+/// downloaded again.
+pub async fn icon_prewarm_job(pool: DbPool) {
+    debug!("Start icon_prewarm_job");
+    if CONFIG.icon_service() != "internal" || CONFIG.disable_icon_download() {
+        return;
+    }
+
+    let pending = match pool.get().await {
+        Ok(mut conn) => IconDomain::find_pending(CONFIG.icon_prewarm_batch_size() as i64, &mut conn).await,
+        Err(_) => {
+            error!("Failed to get DB connection while pre-warming the icons");
+            return;
+        }
+    };
+    for domain in pending {
+        let icon = get_icon(&domain.domain, &pool).await;
+
+        // The state isn't saved when the icon is served from the cache or when the domain is refused
+        let Ok(mut conn) = pool.get().await else {
+            error!("Failed to get DB connection while pre-warming the icons");
+            return;
+        };
+        if let Some(mut state) =
+            IconDomain::find_by_domain(&domain.domain, &mut conn).await.filter(IconDomain::is_pending)
+        {
+            match icon {
+                Some(_) => state.found(),
+                None => state.missing("The icon couldn't be pre-warmed".to_string()),
+            }
+            if let Err(e) = state.save(&mut conn).await {
+                warn!("Unable to save the icon state of {}: {e:?}", domain.domain);
+            }
+        }
+    }
 }
 
 struct Icon {
@@ -332,6 +623,8 @@ fn get_favicons_node(
 struct IconUrlResult {
     iconlist: Vec<Icon>,
     referer: String,
+    // Whether the site answered, the icons can still be missing
+    reachable: bool,
 }
 
 /// Returns a IconUrlResult which holds a Vector IconList and a string which holds the referer.
@@ -398,6 +691,7 @@ async fn get_icon_url(domain: &str) -> Result<IconUrlResult, Error> {
     // Create the iconlist
     let mut iconlist: Vec<Icon> = Vec::new();
     let mut referer = String::new();
+    let reachable = resp.is_ok();
 
     if let Ok(content) = resp {
         // Extract the URL from the response in case redirects occurred (like @ gitlab.com)
@@ -431,6 +725,7 @@ async fn get_icon_url(domain: &str) -> Result<IconUrlResult, Error> {
     Ok(IconUrlResult {
         iconlist,
         referer,
+        reachable,
     })
 }
 
@@ -439,12 +734,17 @@ async fn get_page(url: &str) -> Result<Response, Error> {
 }
 
 async fn get_page_with_referer(url: &str, referer: &str) -> Result<Response, Error> {
+    // The IPs in the URLs aren't resolved, so the resolver doesn't check them
+    if let Ok(parsed) = url::Url::parse(url) {
+        check_icon_url(&parsed)?;
+    }
+
     let mut client = CLIENT.get(url);
     if !referer.is_empty() {
         client = client.header("Referer", referer)
     }
 
-    Ok(client.send().await?.error_for_status()?)
+    Ok(client.send_traced().await?.error_for_status()?)
 }
 
 /// Returns a Integer with the priority of the type of the icon which to prefer.
@@ -526,12 +826,7 @@ fn parse_sizes(sizes: &str) -> (u16, u16) {
     (width, height)
 }
 
-async fn download_icon(domain: &str) -> Result<(Bytes, Option<&str>), Error> {
-    let icon_result = get_icon_url(domain).await?;
-
-    let mut buffer = Bytes::new();
-    let mut icon_type: Option<&str> = None;
-
+async fn download_icon(domain: &str, icon_result: &IconUrlResult) -> Result<(Vec<u8>, &'static str), Error> {
     use data_url::DataUrl;
 
     for icon in icon_result.iconlist.iter().take(5) {
@@ -549,14 +844,12 @@ async fn download_icon(domain: &str) -> Result<(Bytes, Option<&str>), Error> {
                     // Also check if the size is atleast 67 bytes, which seems to be the smallest png i could create
                     if body.len() >= 67 {
                         // Check if the icon type is allowed, else try an icon from the list.
-                        icon_type = get_icon_type(&body);
-                        if icon_type.is_none() {
+                        let Some(converted) = icon_image::normalize(&body) else {
                             debug!("Icon from {} data:image uri, is not a valid image type", domain);
                             continue;
-                        }
+                        };
                         info!("Extracted icon from data:image uri for {}", domain);
-                        buffer = body.freeze();
-                        break;
+                        return Ok(converted);
                     }
                 }
                 _ => debug!("Extracted icon from data:image uri is invalid"),
@@ -564,51 +857,19 @@ async fn download_icon(domain: &str) -> Result<(Bytes, Option<&str>), Error> {
         } else {
             let res = get_page_with_referer(&icon.href, &icon_result.referer).await?;
 
-            buffer = stream_to_bytes_limit(res, 5120 * 1024).await?; // 5120KB/5MB for each icon max (Same as icons.bitwarden.net)
+            let buffer = stream_to_bytes_limit(res, 5120 * 1024).await?; // 5120KB/5MB for each icon max (Same as icons.bitwarden.net)
 
             // Check if the icon type is allowed, else try an icon from the list.
-            icon_type = get_icon_type(&buffer);
-            if icon_type.is_none() {
-                buffer.clear();
+            let Some(converted) = icon_image::normalize(&buffer) else {
                 debug!("Icon from {}, is not a valid image type", icon.href);
                 continue;
-            }
+            };
             info!("Downloaded icon from {}", icon.href);
-            break;
+            return Ok(converted);
         }
     }
 
-    if buffer.is_empty() {
-        err_silent!("Empty response or unable find a valid icon", domain);
-    }
-
-    Ok((buffer, icon_type))
-}
-
-async fn save_icon(path: &str, icon: &[u8]) {
-    match File::create(path).await {
-        Ok(mut f) => {
-            f.write_all(icon).await.expect("Error writing icon file");
-        }
-        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
-            create_dir_all(&CONFIG.icon_cache_folder()).await.expect("Error creating icon cache folder");
-        }
-        Err(e) => {
-            warn!("Unable to save icon: {:?}", e);
-        }
-    }
-}
-
-fn get_icon_type(bytes: &[u8]) -> Option<&'static str> {
-    match bytes {
-        [137, 80, 78, 71, ..] => Some("png"),
-        [0, 0, 1, 0, ..] => Some("x-icon"),
-        [82, 73, 70, 70, ..] => Some("webp"),
-        [255, 216, 255, ..] => Some("jpeg"),
-        [71, 73, 70, 56, ..] => Some("gif"),
-        [66, 77, ..] => Some("bmp"),
-        _ => None,
-    }
+    err_silent!("Empty response or unable find a valid icon", domain);
 }
 
 /// Minimize the amount of bytes to be parsed from a reqwest result.
@@ -10,9 +10,12 @@ use serde_json::Value;
 use crate::{
     api::{
         core::{
-            accounts::{PreloginData, RegisterData, _prelogin, _register},
-            log_user_event,
-            two_factor::{authenticator, duo, email, enforce_2fa_policy, webauthn, yubikey},
+            accounts::{_prelogin, _register, PreloginData, RegisterData},
+            key_connector, log_user_event, passkeys,
+            two_factor::{
+                authenticator, delayed_recovery, duo, duo_oidc, email, enforce_2fa_policy, recovery_codes, remember,
+                webauthn, yubikey,
+            },
         },
         push::register_push_device,
         ApiResult, EmptyResult, JsonResult, JsonUpcase,
@@ -24,7 +27,7 @@ use crate::{
 };
 
 pub fn routes() -> Vec<Route> {
-    routes![login, prelogin, identity_register]
+    routes![login, prelogin, identity_register, get_passkey_login_options]
 }
 
 #[post("/connect/token", data = "<data>")]
@@ -36,7 +39,7 @@ async fn login(data: Form<ConnectData>, client_header: ClientHeaders, mut conn:
     let login_result = match data.grant_type.as_ref() {
         "refresh_token" => {
             _check_is_some(&data.refresh_token, "refresh_token cannot be blank")?;
-            _refresh_login(data, &mut conn).await
+            _refresh_login(data, &mut conn, &client_header.ip).await
         }
         "password" => {
             _check_is_some(&data.client_id, "client_id cannot be blank")?;
@@ -61,12 +64,28 @@ async fn login(data: Form<ConnectData>, client_header: ClientHeaders, mut conn:
 
             _api_key_login(data, &mut user_uuid, &mut conn, &client_header.ip).await
         }
+        "webauthn" => {
+            _check_is_some(&data.client_id, "client_id cannot be blank")?;
+            _check_is_some(&data.scope, "scope cannot be blank")?;
+            _check_is_some(&data.token, "token cannot be blank")?;
+            _check_is_some(&data.device_response, "device_response cannot be blank")?;
+
+            _check_is_some(&data.device_identifier, "device_identifier cannot be blank")?;
+            _check_is_some(&data.device_name, "device_name cannot be blank")?;
+            _check_is_some(&data.device_type, "device_type cannot be blank")?;
+
+            _passkey_login(data, &mut user_uuid, &mut conn, &client_header.ip).await
+        }
         t => err!("Invalid type", t),
     };
 
     if let Some(user_uuid) = user_uuid {
         match &login_result {
             Ok(_) => {
+                // The mails are sent in the language of the last client which logged in
+                if let Some(locale) = &client_header.locale {
+                    User::update_locale(&user_uuid, locale, &mut conn).await?;
+                }
                 log_user_event(
                     EventType::UserLoggedIn as i32,
                     &user_uuid,
@@ -94,7 +113,7 @@ async fn login(data: Form<ConnectData>, client_header: ClientHeaders, mut conn:
     login_result
 }
 
-async fn _refresh_login(data: ConnectData, conn: &mut DbConn) -> JsonResult {
+async fn _refresh_login(data: ConnectData, conn: &mut DbConn, ip: &ClientIp) -> JsonResult {
     // Extract token
     let token = data.refresh_token.unwrap();
 
@@ -106,6 +125,8 @@ async fn _refresh_login(data: ConnectData, conn: &mut DbConn) -> JsonResult {
 
     // Common
     let user = User::find_by_uuid(&device.user_uuid, conn).await.unwrap();
+    // The sessions of the users removed from the directory end with their next refresh
+    let user = authorize_directory_login(user, ip, conn).await?;
     // ---
     // Disabled this variable, it was used to generate the JWT
     // Because this might get used in the future, and is add by the Bitwarden Server, lets keep it, but then commented out
@@ -214,6 +235,8 @@ async fn _password_login(
         )
     }
 
+    let mut user = authorize_directory_login(user, ip, conn).await?;
+
     let now = Utc::now().naive_utc();
 
     if user.verified_at.is_none() && CONFIG.mail_enabled() && CONFIG.signups_verify() {
@@ -317,6 +340,124 @@ async fn _password_login(
     Ok(Json(result))
 }
 
+async fn _passkey_login(
+    data: ConnectData,
+    user_uuid: &mut Option<String>,
+    conn: &mut DbConn,
+    ip: &ClientIp,
+) -> JsonResult {
+    // Validate scope
+    let scope = data.scope.as_ref().unwrap();
+    if scope != "api offline_access" {
+        err!("Scope not supported")
+    }
+    let scope_vec = vec!["api".into(), "offline_access".into()];
+
+    // Ratelimit the login
+    crate::ratelimit::check_limit_login(&ip.ip)?;
+
+    // Check the passkey, this also finds the user it belongs to
+    let token = data.token.as_ref().unwrap();
+    let device_response = data.device_response.as_ref().unwrap();
+    let (user, passkey) = match passkeys::validate_passkey_login(token, device_response, conn).await {
+        Ok(result) => result,
+        Err(e) => err!("Passkey login failed. Try again", format!("IP: {}. {e}", ip.ip)),
+    };
+
+    // Set the user_uuid here to be passed back used for event logging.
+    *user_uuid = Some(user.uuid.clone());
+
+    // Check if the user is disabled
+    if !user.enabled {
+        err!(
+            "This user has been disabled",
+            format!("IP: {}. Username: {}.", ip.ip, user.email),
+            ErrorEvent {
+                event: EventType::UserFailedLogIn
+            }
+        )
+    }
+
+    if user.verified_at.is_none() && CONFIG.mail_enabled() && CONFIG.signups_verify() {
+        err!(
+            "Please verify your email before trying again.",
+            format!("IP: {}. Username: {}.", ip.ip, user.email),
+            ErrorEvent {
+                event: EventType::UserFailedLogIn
+            }
+        )
+    }
+
+    let user = authorize_directory_login(user, ip, conn).await?;
+
+    // A passkey requires user verification, so it already counts as a second factor and 2FA is not checked here
+    let (mut device, new_device) = get_device(&data, conn, &user).await;
+
+    if CONFIG.mail_enabled() && new_device {
+        let now = Utc::now().naive_utc();
+        if let Err(e) = mail::send_new_device_logged_in(&user.email, &ip.ip.to_string(), &now, &device.name).await {
+            error!("Error sending new device email: {:#?}", e);
+
+            if CONFIG.require_device_email() {
+                err!(
+                    "Could not send login notification email. Please contact your administrator.",
+                    ErrorEvent {
+                        event: EventType::UserFailedLogIn
+                    }
+                )
+            }
+        }
+    }
+
+    // register push device
+    if !new_device {
+        register_push_device(&mut device, conn).await?;
+    }
+
+    let (access_token, expires_in) = device.refresh_tokens(&user, scope_vec);
+    device.save(conn).await?;
+
+    let mut result = json!({
+        "access_token": access_token,
+        "expires_in": expires_in,
+        "token_type": "Bearer",
+        "refresh_token": device.refresh_token,
+        "Key": user.akey,
+        "PrivateKey": user.private_key,
+
+        "Kdf": user.client_kdf_type,
+        "KdfIterations": user.client_kdf_iter,
+        "KdfMemory": user.client_kdf_memory,
+        "KdfParallelism": user.client_kdf_parallelism,
+        "ResetMasterPassword": false, // TODO: Same as above
+        "ForcePasswordReset": false,
+        "MasterPasswordPolicy": {
+            "object": "masterPasswordPolicy",
+        },
+
+        "scope": scope,
+        "unofficialServer": true,
+        "UserDecryptionOptions": {
+            "HasMasterPassword": !user.password_hash.is_empty() && !user.uses_key_connector,
+            "Object": "userDecryptionOptions"
+        },
+    });
+
+    // Allows the clients to unlock the vault with the passkey, instead of asking for the master password
+    if let (Some(encrypted_user_key), Some(encrypted_private_key)) =
+        (&passkey.encrypted_user_key, &passkey.encrypted_private_key)
+    {
+        result["UserDecryptionOptions"]["WebAuthnPrfOption"] = json!({
+            "EncryptedPrivateKey": encrypted_private_key,
+            "EncryptedUserKey": encrypted_user_key,
+        });
+    }
+    key_connector::add_decryption_option(&user, &mut result);
+
+    info!("User {} logged in successfully with a passkey. IP: {}", user.email, ip.ip);
+    Ok(Json(result))
+}
+
 async fn _api_key_login(
     data: ConnectData,
     user_uuid: &mut Option<String>,
@@ -365,7 +506,7 @@ async fn _user_api_key_login(
         )
     }
 
-    // Check API key. Note that API key logins bypass 2FA.
+    // Check API key. Note that API key logins bypass 2FA, unless required by the config or a 2FA policy.
     let client_secret = data.client_secret.as_ref().unwrap();
     if !user.check_valid_api_key(client_secret) {
         err!(
@@ -377,6 +518,12 @@ async fn _user_api_key_login(
         )
     }
 
+    let user = authorize_directory_login(user, ip, conn).await?;
+
+    if api_key_twofactor_required(&user.uuid, conn).await {
+        api_key_twofactor_auth(&user, &data, ip, conn).await?;
+    }
+
     let (mut device, new_device) = get_device(&data, conn, &user).await;
 
     if CONFIG.mail_enabled() && new_device {
@@ -410,7 +557,7 @@ async fn _user_api_key_login(
 
     // Note: No refresh_token is returned. The CLI just repeats the
     // client_credentials login flow when the existing token expires.
-    let result = json!({
+    let mut result = json!({
         "access_token": access_token,
         "expires_in": expires_in,
         "token_type": "Bearer",
@@ -425,6 +572,7 @@ async fn _user_api_key_login(
         "scope": "api",
         "unofficialServer": true,
     });
+    key_connector::add_decryption_option(&user, &mut result);
 
     Ok(Json(result))
 }
@@ -502,7 +650,7 @@ async fn twofactor_auth(
 
     let twofactor_code = match data.two_factor_token {
         Some(ref code) => code,
-        None => err_json!(_json_err_twofactor(&twofactor_ids, &user.uuid, conn).await?, "2FA token not provided"),
+        None => err_json!(_json_err_twofactor(&twofactor_ids, &user.uuid, data, conn).await?, "2FA token not provided"),
     };
 
     let selected_twofactor = twofactors.into_iter().find(|tf| tf.atype == selected_id && tf.enabled);
@@ -519,20 +667,44 @@ async fn twofactor_auth(
         Some(TwoFactorType::Webauthn) => webauthn::validate_webauthn_login(&user.uuid, twofactor_code, conn).await?,
         Some(TwoFactorType::YubiKey) => yubikey::validate_yubikey_login(twofactor_code, &selected_data?).await?,
         Some(TwoFactorType::Duo) => {
-            duo::validate_duo_login(data.username.as_ref().unwrap().trim(), twofactor_code, conn).await?
+            let email = data.username.as_ref().unwrap().trim();
+            if CONFIG.duo_use_iframe() {
+                // Legacy iframe prompt flow
+                duo::validate_duo_login(email, twofactor_code, conn).await?
+            } else {
+                // OIDC based Universal Prompt flow
+                duo_oidc::validate_duo_login(
+                    email,
+                    twofactor_code,
+                    data.client_id.as_deref().unwrap_or_default(),
+                    data.device_identifier.as_deref().unwrap_or_default(),
+                    conn,
+                )
+                .await?
+            }
         }
         Some(TwoFactorType::Email) => {
             email::validate_email_code_str(&user.uuid, twofactor_code, &selected_data?, conn).await?
         }
 
+        Some(TwoFactorType::RecoveryCode) => {
+            recovery_codes::validate_recovery_code_login(&user.uuid, twofactor_code, conn).await?
+        }
+
         Some(TwoFactorType::Remember) => {
+            let max_duration = remember::remember_max_duration(&user.uuid, conn).await;
             match device.twofactor_remember {
-                Some(ref code) if !CONFIG.disable_2fa_remember() && ct_eq(code, twofactor_code) => {
+                Some(ref code)
+                    if !CONFIG.disable_2fa_remember()
+                        && ct_eq(code, twofactor_code)
+                        && !device.is_twofactor_remember_expired(max_duration) =>
+                {
                     remember = 1; // Make sure we also return the token here, otherwise it will only remember the first time
+                    device.twofactor_remember_last_used = Some(Utc::now().naive_utc());
                 }
                 _ => {
                     err_json!(
-                        _json_err_twofactor(&twofactor_ids, &user.uuid, conn).await?,
+                        _json_err_twofactor(&twofactor_ids, &user.uuid, data, conn).await?,
                         "2FA Remember token not provided"
                     )
                 }
@@ -548,7 +720,17 @@ async fn twofactor_auth(
 
     TwoFactorIncomplete::mark_complete(&user.uuid, &device.uuid, conn).await?;
 
+    // The user still has access to the two-step login methods, so a pending delayed recovery isn't needed anymore
+    delayed_recovery::cancel_delayed_recovery(user, conn).await?;
+
+    // Organizations can require specific providers, revoke the memberships where the enabled ones aren't sufficient
+    enforce_2fa_policy(user, &user.uuid, device.atype, &ip.ip, conn).await?;
+
     if !CONFIG.disable_2fa_remember() && remember == 1 {
+        // A new two-step login starts a new remember period
+        if selected_id != TwoFactorType::Remember as i32 {
+            device.delete_twofactor_remember();
+        }
         Ok(Some(device.refresh_twofactor_remember()))
     } else {
         device.delete_twofactor_remember();
@@ -556,11 +738,83 @@ async fn twofactor_auth(
     }
 }
 
+// Only the providers which can be used without a browser redirect or a sent code are supported for API key logins
+const API_KEY_TWOFACTOR_PROVIDERS: [i32; 2] = [TwoFactorType::Authenticator as i32, TwoFactorType::Webauthn as i32];
+
+async fn api_key_twofactor_required(user_uuid: &str, conn: &mut DbConn) -> bool {
+    if CONFIG.api_key_login_require_2fa() {
+        return true;
+    }
+
+    for member in
+        UserOrganization::find_by_user_and_policy(user_uuid, OrgPolicyType::TwoFactorAuthentication, conn).await
+    {
+        if let Some(policy) =
+            OrgPolicy::find_by_org_and_type(&member.org_uuid, OrgPolicyType::TwoFactorAuthentication, conn).await
+        {
+            if policy.twofactor_policy_data().RequireForApiKey {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+async fn api_key_twofactor_auth(user: &User, data: &ConnectData, ip: &ClientIp, conn: &mut DbConn) -> EmptyResult {
+    let twofactors = TwoFactor::find_by_user(&user.uuid, conn).await;
+
+    // Users without two-step login have nothing to bypass
+    if twofactors.is_empty() {
+        return Ok(());
+    }
+
+    let twofactor_ids: Vec<_> = twofactors
+        .iter()
+        .filter(|tf| tf.enabled && API_KEY_TWOFACTOR_PROVIDERS.contains(&tf.atype))
+        .map(|tf| tf.atype)
+        .collect();
+    if twofactor_ids.is_empty() {
+        err!(
+            "An authenticator app or a FIDO2 WebAuthn key is required to login with the API key",
+            format!("IP: {}. Username: {}.", ip.ip, user.email),
+            ErrorEvent {
+                event: EventType::UserFailedLogIn2fa
+            }
+        )
+    }
+
+    let selected_id = data.two_factor_provider.unwrap_or(twofactor_ids[0]);
+    let twofactor_code = match data.two_factor_token {
+        Some(ref code) if twofactor_ids.contains(&selected_id) => code,
+        _ => err_json!(_json_err_twofactor(&twofactor_ids, &user.uuid, data, conn).await?, "2FA token not provided"),
+    };
+
+    let selected_twofactor = twofactors.into_iter().find(|tf| tf.atype == selected_id && tf.enabled);
+    match TwoFactorType::from_i32(selected_id) {
+        Some(TwoFactorType::Authenticator) => {
+            let selected_data = _selected_data(selected_twofactor)?;
+            authenticator::validate_totp_code_str(&user.uuid, twofactor_code, &selected_data, ip, conn).await
+        }
+        Some(TwoFactorType::Webauthn) => webauthn::validate_webauthn_login(&user.uuid, twofactor_code, conn).await,
+        _ => err!(
+            "Invalid two factor provider",
+            ErrorEvent {
+                event: EventType::UserFailedLogIn2fa
+            }
+        ),
+    }
+}
+
 fn _selected_data(tf: Option<TwoFactor>) -> ApiResult<String> {
     tf.map(|t| t.data).map_res("Two factor doesn't exist")
 }
 
-async fn _json_err_twofactor(providers: &[i32], user_uuid: &str, conn: &mut DbConn) -> ApiResult<Value> {
+async fn _json_err_twofactor(
+    providers: &[i32],
+    user_uuid: &str,
+    data: &ConnectData,
+    conn: &mut DbConn,
+) -> ApiResult<Value> {
     let mut result = json!({
         "error" : "invalid_grant",
         "error_description" : "Two factor required.",
@@ -585,12 +839,26 @@ async fn _json_err_twofactor(providers: &[i32], user_uuid: &str, conn: &mut DbCo
                     None => err!("User does not exist"),
                 };
 
-                let (signature, host) = duo::generate_duo_signature(&email, conn).await?;
-
-                result["TwoFactorProviders2"][provider.to_string()] = json!({
-                    "Host": host,
-                    "Signature": signature,
-                });
+                if CONFIG.duo_use_iframe() {
+                    // Legacy iframe prompt flow
+                    let (signature, host) = duo::generate_duo_signature(&email, conn).await?;
+                    result["TwoFactorProviders2"][provider.to_string()] = json!({
+                        "Host": host,
+                        "Signature": signature,
+                    })
+                } else {
+                    // OIDC based Universal Prompt flow, the clients are redirected to Duo and back
+                    let auth_url = duo_oidc::get_duo_auth_url(
+                        &email,
+                        data.client_id.as_deref().unwrap_or_default(),
+                        data.device_identifier.as_deref().unwrap_or_default(),
+                        conn,
+                    )
+                    .await?;
+                    result["TwoFactorProviders2"][provider.to_string()] = json!({
+                        "AuthUrl": auth_url,
+                    })
+                }
             }
 
             Some(tf_type @ TwoFactorType::YubiKey) => {
@@ -612,12 +880,14 @@ async fn _json_err_twofactor(providers: &[i32], user_uuid: &str, conn: &mut DbCo
                     None => err!("No twofactor email registered"),
                 };
 
-                // Send email immediately if email is the only 2FA option
-                if providers.len() == 1 {
+                let email_data = email::EmailTokenData::from_json(&twofactor.data)?;
+
+                // Send email immediately if email is the only 2FA option,
+                // unless a token has just been sent, which can still be used
+                if providers.len() == 1 && email_data.resend_cooldown() == 0 {
                     email::send_token(user_uuid, conn).await?
                 }
 
-                let email_data = email::EmailTokenData::from_json(&twofactor.data)?;
                 result["TwoFactorProviders2"][provider.to_string()] = json!({
                     "Email": email::obscure_email(&email_data.email),
                 })
@@ -640,6 +910,11 @@ async fn identity_register(data: JsonUpcase<RegisterData>, conn: DbConn) -> Json
     _register(data, conn).await
 }
 
+#[get("/accounts/webauthn/assertion-options")]
+async fn get_passkey_login_options(mut conn: DbConn) -> JsonResult {
+    passkeys::generate_passkey_login_options(&mut conn).await
+}
+
 // https://github.com/bitwarden/jslib/blob/master/common/src/models/request/tokenRequest.ts
 // https://github.com/bitwarden/mobile/blob/master/src/Core/Models/Request/TokenRequest.cs
 #[derive(Debug, Clone, Default, FromForm)]
@@ -647,7 +922,7 @@ async fn identity_register(data: JsonUpcase<RegisterData>, conn: DbConn) -> Json
 struct ConnectData {
     #[field(name = uncased("grant_type"))]
     #[field(name = uncased("granttype"))]
-    grant_type: String, // refresh_token, password, client_credentials (API key)
+    grant_type: String, // refresh_token, password, client_credentials (API key), webauthn (passkey)
 
     // Needed for grant_type="refresh_token"
     #[field(name = uncased("refresh_token"))]
@@ -694,6 +969,27 @@ struct ConnectData {
     two_factor_remember: Option<i32>,
     #[field(name = uncased("authrequest"))]
     auth_request: Option<String>,
+
+    // Needed for grant_type="webauthn"
+    #[field(name = uncased("token"))]
+    token: Option<String>,
+    #[field(name = uncased("device_response"))]
+    #[field(name = uncased("deviceresponse"))]
+    device_response: Option<String>,
+}
+
+/// The directory decides which accounts are allowed to login, also with the grant types without a password.
+#[cfg(feature = "ldap")]
+async fn authorize_directory_login(mut user: User, ip: &ClientIp, conn: &mut DbConn) -> ApiResult<User> {
+    if crate::ldap::enabled() {
+        crate::ldap::authorize_login(&mut user, &ip.ip, conn).await?;
+    }
+    Ok(user)
+}
+
+#[cfg(not(feature = "ldap"))]
+async fn authorize_directory_login(user: User, _ip: &ClientIp, _conn: &mut DbConn) -> ApiResult<User> {
+    Ok(user)
 }
 
 fn _check_is_some<T>(value: &Option<T>, msg: &str) -> EmptyResult {
@@ -1,9 +1,14 @@
 mod admin;
+mod admin_cli;
+mod admin_setup;
 pub mod core;
+mod event_bus;
+mod health;
 mod icons;
 mod identity;
 mod notifications;
 mod push;
+mod push_direct;
 mod web;
 
 use rocket::serde::json::Json;
@@ -12,25 +17,35 @@ use serde_json::Value;
 pub use crate::api::{
     admin::catchers as admin_catchers,
     admin::routes as admin_routes,
+    admin_cli::{run_user_command, UserCommand},
+    admin_setup::log_setup_code,
     core::catchers as core_catchers,
+    core::directory_sync_job,
+    core::events_routes as core_events_routes,
     core::purge_auth_requests,
     core::purge_sends,
     core::purge_trashed_ciphers,
     core::routes as core_routes,
-    core::two_factor::send_incomplete_2fa_notifications,
-    core::{emergency_notification_reminder_job, emergency_request_timeout_job},
-    core::{event_cleanup_job, events_routes as core_events_routes},
-    icons::{is_domain_blacklisted, routes as icons_routes},
+    core::two_factor::{
+        delayed_recovery::delayed_2fa_recovery_job, duo_oidc::purge_duo_contexts, send_incomplete_2fa_notifications,
+        twofactor_policy_grace_job,
+    },
+    core::{emergency_access_validation_job, emergency_notification_reminder_job, emergency_request_timeout_job},
+    icons::{icon_prewarm_job, icon_stats_json, is_domain_blacklisted, queue_icon_prewarm, routes as icons_routes},
     identity::routes as identity_routes,
     notifications::routes as notifications_routes,
-    notifications::{AnonymousNotify, Notify, UpdateType, WS_ANONYMOUS_SUBSCRIPTIONS, WS_USERS},
+    notifications::{
+        init_notification_history, websocket_stats, AnonymousNotify, Notify, UpdateType, WS_ANONYMOUS_SUBSCRIPTIONS,
+        WS_USERS,
+    },
     push::{
         push_cipher_update, push_folder_update, push_logout, push_send_update, push_user_update, register_push_device,
         unregister_push_device,
     },
+    push_direct::init_direct_push,
     web::catchers as web_catchers,
     web::routes as web_routes,
-    web::static_files,
+    web::static_file,
 };
 use crate::db::{models::User, DbConn};
 use crate::util;
@@ -73,3 +88,145 @@ impl PasswordOrOtpData {
         Ok(())
     }
 }
+
+// The rows of a page when the listing doesn't give their number, and the most allowed
+const DEFAULT_PER_PAGE: usize = 50;
+const MAX_PER_PAGE: usize = 1000;
+
+/// The page, search and sorting of a listing, from the query of its request.
+/// The JSON listings requested without any of them still return all their rows, in their previous format.
+#[derive(Default, FromForm)]
+struct ListQuery {
+    page: Option<usize>,
+    per_page: Option<usize>,
+    search: Option<String>,
+    sort: Option<String>,
+    order: Option<String>,
+}
+
+impl ListQuery {
+    fn is_paginated(&self) -> bool {
+        self.page.is_some()
+            || self.per_page.is_some()
+            || self.search.is_some()
+            || self.sort.is_some()
+            || self.order.is_some()
+    }
+
+    /// The number of the page, from 1.
+    fn page(&self) -> usize {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    fn per_page(&self) -> usize {
+        self.per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE)
+    }
+
+    fn offset(&self) -> i64 {
+        i64::try_from((self.page() - 1).saturating_mul(self.per_page())).unwrap_or(i64::MAX)
+    }
+
+    fn limit(&self) -> i64 {
+        self.per_page() as i64
+    }
+
+    /// The searched text, the empty fields of the forms are sent too.
+    fn search(&self) -> Option<&str> {
+        self.search.as_deref().map(str::trim).filter(|s| !s.is_empty())
+    }
+
+    /// The name of the sorting column, the models use their default one when it's unknown.
+    fn sort(&self) -> &str {
+        self.sort.as_deref().unwrap_or_default()
+    }
+
+    fn descending(&self) -> bool {
+        self.order.as_deref() == Some("desc")
+    }
+
+    /// The query string of another page or sorting of the listing, keeping its search.
+    fn query_string(&self, page: usize, sort: &str, descending: bool) -> String {
+        let mut query = url::form_urlencoded::Serializer::for_suffix(String::from("?"), 1);
+        query.append_pair("page", &page.to_string());
+        query.append_pair("per_page", &self.per_page().to_string());
+        if let Some(search) = self.search() {
+            query.append_pair("search", search);
+        }
+        query.append_pair("sort", sort);
+        query.append_pair(
+            "order",
+            if descending {
+                "desc"
+            } else {
+                "asc"
+            },
+        );
+        query.finish()
+    }
+
+    /// The pagination of the admin pages, with the query strings of the previous and next pages and of the sortings.
+    /// A column already sorted by is sorted the other way by its link.
+    fn page_json(&self, total: i64, sort: &str, sorts: &[&str]) -> Value {
+        let (page, descending) = (self.page(), self.descending());
+        let pages = usize::try_from(total).unwrap_or_default().div_ceil(self.per_page()).max(1);
+        let sort_queries: serde_json::Map<String, Value> =
+            sorts.iter().map(|s| (s.to_string(), json!(self.query_string(1, s, *s == sort && !descending)))).collect();
+        json!({
+            "page": page,
+            "pages": pages,
+            "total": total,
+            "per_page": self.per_page(),
+            "search": self.search(),
+            "sort": sort,
+            "descending": descending,
+            "prev_query": (page > 1).then(|| self.query_string(page - 1, sort, descending)),
+            "next_query": (page < pages).then(|| self.query_string(page + 1, sort, descending)),
+            "sort_queries": sort_queries,
+        })
+    }
+
+    /// A page of a JSON listing, in the list format of the clients.
+    /// The continuation token is the number of the next page, null on the last one.
+    fn list_json(&self, data: Vec<Value>, total: i64) -> Value {
+        let next_page =
+            (self.offset().saturating_add(data.len() as i64) < total).then(|| (self.page() + 1).to_string());
+        json!({
+            "Data": data,
+            "Object": "list",
+            "ContinuationToken": next_page,
+            "Total": total,
+            "Page": self.page(),
+            "PerPage": self.per_page(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_query() {
+        let list = ListQuery {
+            page: Some(3),
+            per_page: Some(20),
+            search: Some(" a&b ".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(list.offset(), 40);
+        assert_eq!(list.search(), Some("a&b"));
+        assert_eq!(list.query_string(2, "email", true), "?page=2&per_page=20&search=a%26b&sort=email&order=desc");
+
+        let page = list.page_json(41, "email", &["email", "name"]);
+        assert_eq!(page["pages"], 3);
+        assert_eq!(page["next_query"], Value::Null);
+        assert_eq!(page["sort_queries"]["email"], "?page=1&per_page=20&search=a%26b&sort=email&order=desc");
+        assert_eq!(list.list_json(vec![json!({})], 41)["ContinuationToken"], Value::Null);
+        assert_eq!(list.list_json(vec![json!({})], 42)["ContinuationToken"], "4");
+
+        let all = ListQuery::default();
+        assert!(!all.is_paginated());
+        assert_eq!((all.page(), all.per_page(), all.offset()), (1, DEFAULT_PER_PAGE, 0));
+        assert_eq!(all.search(), None);
+    }
+}
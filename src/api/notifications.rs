@@ -1,22 +1,37 @@
-use std::{net::IpAddr, sync::Arc, time::Duration};
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use chrono::{NaiveDateTime, Utc};
 use rmpv::Value;
-use rocket::{futures::StreamExt, Route};
-use tokio::sync::mpsc::Sender;
+use rocket::{
+    futures::StreamExt,
+    request::{FromRequest, Outcome, Request},
+    response::stream::{Event, EventStream},
+    serde::json::Json,
+    Route,
+};
+use tokio::sync::mpsc::{error::TrySendError, Receiver, Sender};
 
 use rocket_ws::{Message, WebSocket};
 
 use crate::{
-    auth::{ClientIp, WsAccessTokenHeader},
+    api::{EmptyResult, JsonResult},
+    auth::{ClientIp, Headers, WsAccessTokenHeader},
     db::{
-        models::{Cipher, Folder, Send as DbSend, User},
-        DbConn,
+        models::{Cipher, Folder, NotificationEvent, Send as DbSend, User},
+        DbConn, DbPool,
     },
-    Error, CONFIG,
+    log_context, Error, CONFIG,
 };
 
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 
 pub static WS_USERS: Lazy<Arc<WebSocketUsers>> = Lazy::new(|| {
     Arc::new(WebSocketUsers {
@@ -31,19 +46,23 @@ pub static WS_ANONYMOUS_SUBSCRIPTIONS: Lazy<Arc<AnonymousWebSocketSubscriptions>
 });
 
 use super::{
-    push::push_auth_request, push::push_auth_response, push_cipher_update, push_folder_update, push_logout,
-    push_send_update, push_user_update,
+    push::push_auth_request, push::push_auth_response, push::push_vault_sync, push_cipher_update, push_folder_update,
+    push_logout, push_send_update, push_user_update,
 };
 
 static NOTIFICATIONS_DISABLED: Lazy<bool> = Lazy::new(|| !CONFIG.enable_websocket() && !CONFIG.push_enabled());
 
 pub fn routes() -> Vec<Route> {
-    if CONFIG.enable_websocket() {
-        routes![websockets_hub, anonymous_websockets_hub]
-    } else {
+    if !CONFIG.enable_websocket() {
         info!("WebSocket are disabled, realtime sync functionality will not work!");
-        routes![]
+        return routes![];
     }
+
+    let mut routes = routes![websockets_hub, anonymous_websockets_hub, missed_notifications];
+    if CONFIG.enable_sse() {
+        routes.append(&mut routes![sse_hub]);
+    }
+    routes
 }
 
 #[derive(FromForm, Debug)]
@@ -75,6 +94,8 @@ impl Drop for WSEntryMapGuard {
         if let Some(mut entry) = self.users.map.get_mut(&self.user_uuid) {
             entry.retain(|(uuid, _)| uuid != &self.entry_uuid);
         }
+        // Don't keep an empty channel list around for every user who ever connected
+        self.users.map.remove_if(&self.user_uuid, |_, senders| senders.is_empty());
     }
 }
 
@@ -101,6 +122,96 @@ impl Drop for WSAnonymousEntryMapGuard {
     }
 }
 
+// The open WebSocket and event stream connections of each IP
+static CONNECTIONS_PER_IP: Lazy<dashmap::DashMap<IpAddr, usize>> = Lazy::new(dashmap::DashMap::new);
+
+// Counts a connection of the IP while it is open
+struct IpConnectionGuard {
+    addr: IpAddr,
+}
+
+impl IpConnectionGuard {
+    // Refuses the connection when the IP already has too many open
+    fn acquire(addr: IpAddr) -> Result<Self, Error> {
+        let max_connections = CONFIG.websocket_max_connections_per_ip();
+        let mut connections = CONNECTIONS_PER_IP.entry(addr).or_insert(0);
+        if max_connections > 0 && *connections >= max_connections {
+            drop(connections);
+            WS_CONNECTIONS_REFUSED.fetch_add(1, Ordering::Relaxed);
+            err_code!(format!("Too many connections from {addr}"), 429)
+        }
+        *connections += 1;
+        Ok(Self {
+            addr,
+        })
+    }
+}
+
+impl Drop for IpConnectionGuard {
+    fn drop(&mut self) {
+        if let Some(mut connections) = CONNECTIONS_PER_IP.get_mut(&self.addr) {
+            *connections = connections.saturating_sub(1);
+        }
+        CONNECTIONS_PER_IP.remove_if(&self.addr, |_, connections| *connections == 0);
+    }
+}
+
+// The clients answer the pings, one which stays silent for longer than the idle timeout is gone
+fn is_idle(last_activity: Instant) -> bool {
+    let timeout = CONFIG.websocket_idle_timeout();
+    if timeout > 0 && last_activity.elapsed() > Duration::from_secs(timeout) {
+        WS_IDLE_CONNECTIONS_CLOSED.fetch_add(1, Ordering::Relaxed);
+        true
+    } else {
+        false
+    }
+}
+
+// Clients which don't send their token on the upgrade request have to send it with the handshake message
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct HandshakeToken {
+    access_token: Option<String>,
+}
+
+// Tokens in the query string end up in the logs of the proxies, the header or the handshake are preferred
+fn select_access_token(
+    header_token: WsAccessTokenHeader,
+    query_token: Option<String>,
+) -> Result<Option<String>, Error> {
+    if let Some(token) = header_token.access_token {
+        Ok(Some(token))
+    } else if let Some(token) = query_token {
+        if CONFIG.websocket_disable_query_token() {
+            err_code!("Access tokens in the query string are not allowed", 401)
+        }
+        Ok(Some(token))
+    } else {
+        Ok(None)
+    }
+}
+
+fn add_user_channel(user_uuid: String, addr: IpAddr) -> (Receiver<Arc<QueuedUpdate>>, WSEntryMapGuard) {
+    let users = Arc::clone(&WS_USERS);
+
+    // Add a channel to send messages to this client to the map
+    let entry_uuid = uuid::Uuid::new_v4();
+    let (tx, rx) = tokio::sync::mpsc::channel::<Arc<QueuedUpdate>>(CONFIG.websocket_queue_size());
+    users.add_sender(&user_uuid, entry_uuid, tx);
+
+    // Once the guard goes out of scope, the connection will have been closed and the entry will be deleted from the map
+    (rx, WSEntryMapGuard::new(users, user_uuid, entry_uuid, addr))
+}
+
+// Waits forever until the client is authenticated
+async fn recv_update(rx: &mut Option<Receiver<Arc<QueuedUpdate>>>) -> Option<Arc<QueuedUpdate>> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
 #[get("/hub?<data..>")]
 fn websockets_hub<'r>(
     ws: WebSocket,
@@ -109,42 +220,33 @@ fn websockets_hub<'r>(
     header_token: WsAccessTokenHeader,
 ) -> Result<rocket_ws::Stream!['r], Error> {
     let addr = ip.ip;
+    let ip_guard = IpConnectionGuard::acquire(addr)?;
     info!("Accepting Rocket WS connection from {addr}");
 
-    let token = if let Some(token) = data.access_token {
-        token
-    } else if let Some(token) = header_token.access_token {
-        token
-    } else {
-        err_code!("Invalid claim", 401)
-    };
-
-    let Ok(claims) = crate::auth::decode_login(&token) else {
-        err_code!("Invalid token", 401)
-    };
-
-    let (mut rx, guard) = {
-        let users = Arc::clone(&WS_USERS);
-
-        // Add a channel to send messages to this client to the map
-        let entry_uuid = uuid::Uuid::new_v4();
-        let (tx, rx) = tokio::sync::mpsc::channel::<Message>(100);
-        users.map.entry(claims.sub.clone()).or_default().push((entry_uuid, tx));
-
-        // Once the guard goes out of scope, the connection will have been closed and the entry will be deleted from the map
-        (rx, WSEntryMapGuard::new(users, claims.sub, entry_uuid, addr))
+    let (mut rx, mut guard) = match select_access_token(header_token, data.access_token)? {
+        Some(token) => {
+            let Ok(claims) = crate::auth::decode_login(&token) else {
+                err_code!("Invalid token", 401)
+            };
+            let (rx, guard) = add_user_channel(claims.sub, addr);
+            (Some(rx), Some(guard))
+        }
+        None => (None, None),
     };
 
     Ok({
         rocket_ws::Stream! { ws => {
             let mut ws = ws;
-            let _guard = guard;
+            let _ip_guard = ip_guard;
             let mut interval = tokio::time::interval(Duration::from_secs(15));
+            let mut handshake_timeout = std::pin::pin!(tokio::time::sleep(HANDSHAKE_TIMEOUT));
+            let mut last_activity = Instant::now();
             loop {
                 tokio::select! {
                     res = ws.next() =>  {
                         match res {
                             Some(Ok(message)) => {
+                                last_activity = Instant::now();
                                 match message {
                                     // Respond to any pings
                                     Message::Ping(ping) => yield Message::Pong(ping),
@@ -155,6 +257,23 @@ fn websockets_hub<'r>(
                                         let msg = message.strip_suffix(RECORD_SEPARATOR as char).unwrap_or(message);
 
                                         if serde_json::from_str(msg).ok() == Some(INITIAL_MESSAGE) {
+                                            if guard.is_none() {
+                                                let claims = serde_json::from_str::<HandshakeToken>(msg)
+                                                    .ok()
+                                                    .and_then(|h| h.access_token)
+                                                    .and_then(|token| crate::auth::decode_login(&token).ok());
+                                                match claims {
+                                                    Some(claims) => {
+                                                        let (user_rx, user_guard) = add_user_channel(claims.sub, addr);
+                                                        rx = Some(user_rx);
+                                                        guard = Some(user_guard);
+                                                    }
+                                                    None => {
+                                                        info!("Closing WS connection from {addr}, missing or invalid access token");
+                                                        break;
+                                                    }
+                                                }
+                                            }
                                             yield Message::binary(INITIAL_RESPONSE);
                                             continue;
                                         }
@@ -172,16 +291,28 @@ fn websockets_hub<'r>(
                         }
                     }
 
-                    res = rx.recv() => {
+                    res = recv_update(&mut rx) => {
                         match res {
-                            Some(res) => yield res,
+                            Some(update) => yield Message::binary(update.data.clone()),
                             None => break,
                         }
                     }
 
-                    _ = interval.tick() => yield Message::Ping(create_ping())
+                    _ = &mut handshake_timeout, if guard.is_none() => {
+                        info!("Closing WS connection from {addr}, no access token received after {}s", HANDSHAKE_TIMEOUT.as_secs());
+                        break;
+                    }
+
+                    _ = interval.tick() => {
+                        if is_idle(last_activity) {
+                            info!("Closing idle WS connection from {addr}");
+                            break;
+                        }
+                        yield Message::Ping(create_ping())
+                    }
                 }
             }
+            drop(guard);
         }}
     })
 }
@@ -189,13 +320,14 @@ fn websockets_hub<'r>(
 #[get("/anonymous-hub?<token..>")]
 fn anonymous_websockets_hub<'r>(ws: WebSocket, token: String, ip: ClientIp) -> Result<rocket_ws::Stream!['r], Error> {
     let addr = ip.ip;
+    let ip_guard = IpConnectionGuard::acquire(addr)?;
     info!("Accepting Anonymous Rocket WS connection from {addr}");
 
     let (mut rx, guard) = {
         let subscriptions = Arc::clone(&WS_ANONYMOUS_SUBSCRIPTIONS);
 
         // Add a channel to send messages to this client to the map
-        let (tx, rx) = tokio::sync::mpsc::channel::<Message>(100);
+        let (tx, rx) = tokio::sync::mpsc::channel::<Message>(CONFIG.websocket_queue_size());
         subscriptions.map.insert(token.clone(), tx);
 
         // Once the guard goes out of scope, the connection will have been closed and the entry will be deleted from the map
@@ -206,12 +338,15 @@ fn anonymous_websockets_hub<'r>(ws: WebSocket, token: String, ip: ClientIp) -> R
         rocket_ws::Stream! { ws => {
             let mut ws = ws;
             let _guard = guard;
+            let _ip_guard = ip_guard;
             let mut interval = tokio::time::interval(Duration::from_secs(15));
+            let mut last_activity = Instant::now();
             loop {
                 tokio::select! {
                     res = ws.next() =>  {
                         match res {
                             Some(Ok(message)) => {
+                                last_activity = Instant::now();
                                 match message {
                                     // Respond to any pings
                                     Message::Ping(ping) => yield Message::Pong(ping),
@@ -246,13 +381,276 @@ fn anonymous_websockets_hub<'r>(ws: WebSocket, token: String, ip: ClientIp) -> R
                         }
                     }
 
-                    _ = interval.tick() => yield Message::Ping(create_ping())
+                    _ = interval.tick() => {
+                        if is_idle(last_activity) {
+                            info!("Closing idle WS connection from {addr}");
+                            break;
+                        }
+                        yield Message::Ping(create_ping())
+                    }
                 }
             }
         }}
     })
 }
 
+//
+// Server-sent events, for the clients behind proxies which don't allow WebSockets
+//
+// The events carry the same updates as the WebSocket messages, base64 encoded, and have an id. After a disconnection,
+// the client sends the id of the last event it received in the `Last-Event-ID` header, and the missed updates are
+// sent again. When they aren't known anymore, a `resync` event tells the client to do a full sync instead.
+//
+
+// The number of updates kept per user, for how long after their last event stream closed
+const SSE_HISTORY_SIZE: usize = 100;
+const SSE_RESUME_WINDOW: Duration = Duration::from_secs(600);
+
+// The event ids include the startup time, the ids from before a restart are not resumable
+static INSTANCE_START: Lazy<i64> = Lazy::new(|| Utc::now().timestamp());
+
+struct SseHistory {
+    updates: VecDeque<Arc<QueuedUpdate>>,
+    // The streams can only be resumed from the updates after this one, the older ones may be missing
+    resumable_after: u64,
+    connections: usize,
+    disconnected_at: Instant,
+}
+
+static SSE_HISTORY: Lazy<dashmap::DashMap<String, SseHistory>> = Lazy::new(dashmap::DashMap::new);
+
+// Only the updates of the users who use the event streams are kept
+fn record_sse_update(user_uuid: &str, update: &Arc<QueuedUpdate>) {
+    if let Some(mut history) = SSE_HISTORY.get_mut(user_uuid) {
+        if history.updates.len() >= SSE_HISTORY_SIZE {
+            if let Some(evicted) = history.updates.pop_front() {
+                history.resumable_after = evicted.id;
+            }
+        }
+        history.updates.push_back(Arc::clone(update));
+    }
+}
+
+struct SseHistoryGuard {
+    user_uuid: String,
+}
+
+impl Drop for SseHistoryGuard {
+    fn drop(&mut self) {
+        if let Some(mut history) = SSE_HISTORY.get_mut(&self.user_uuid) {
+            history.connections -= 1;
+            history.disconnected_at = Instant::now();
+        }
+    }
+}
+
+struct LastEventId(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Self(request.headers().get_one("Last-Event-ID").map(String::from)))
+    }
+}
+
+enum Resume {
+    Fresh,
+    Replay(Vec<Arc<QueuedUpdate>>),
+    Resync,
+}
+
+fn resume_from(history: &SseHistory, last_event_id: Option<&str>) -> Resume {
+    let Some(last_event_id) = last_event_id else {
+        return Resume::Fresh;
+    };
+    let last_id = match last_event_id.split_once('-') {
+        Some((start, id)) if start == INSTANCE_START.to_string() => id.parse::<u64>().ok(),
+        _ => None,
+    };
+    match last_id {
+        Some(last_id) if last_id >= history.resumable_after => {
+            Resume::Replay(history.updates.iter().filter(|u| u.id > last_id).cloned().collect())
+        }
+        _ => Resume::Resync,
+    }
+}
+
+fn sse_event(update: &QueuedUpdate) -> Event {
+    Event::data(data_encoding::BASE64.encode(&update.data))
+        .event("notification")
+        .id(format!("{}-{}", *INSTANCE_START, update.id))
+}
+
+#[get("/sse?<data..>")]
+fn sse_hub(
+    data: WsAccessToken,
+    ip: ClientIp,
+    header_token: WsAccessTokenHeader,
+    last_event_id: LastEventId,
+) -> Result<EventStream![], Error> {
+    let addr = ip.ip;
+    let Some(token) = select_access_token(header_token, data.access_token)? else {
+        err_code!("Invalid claim", 401)
+    };
+    let Ok(claims) = crate::auth::decode_login(&token) else {
+        err_code!("Invalid token", 401)
+    };
+    let ip_guard = IpConnectionGuard::acquire(addr)?;
+    info!("Accepting SSE connection from {addr}");
+
+    // Forget the histories of the users who didn't come back
+    SSE_HISTORY.retain(|_, history| history.connections > 0 || history.disconnected_at.elapsed() < SSE_RESUME_WINDOW);
+
+    // The channel is added first so no update is missed, the ones also in the history are skipped
+    let (mut rx, guard) = add_user_channel(claims.sub.clone(), addr);
+    let resume = {
+        let mut history = SSE_HISTORY.entry(claims.sub.clone()).or_insert_with(|| SseHistory {
+            updates: VecDeque::new(),
+            resumable_after: UPDATE_SEQUENCE.load(Ordering::Relaxed),
+            connections: 0,
+            disconnected_at: Instant::now(),
+        });
+        history.connections += 1;
+        resume_from(&history, last_event_id.0.as_deref())
+    };
+    let history_guard = SseHistoryGuard {
+        user_uuid: claims.sub,
+    };
+
+    Ok(EventStream! {
+        let _guard = guard;
+        let _history_guard = history_guard;
+        let _ip_guard = ip_guard;
+
+        let mut last_sent = 0;
+        match resume {
+            Resume::Fresh => {}
+            Resume::Replay(updates) => {
+                for update in updates {
+                    last_sent = update.id;
+                    yield sse_event(&update);
+                }
+            }
+            Resume::Resync => yield Event::data("").event("resync"),
+        }
+
+        while let Some(update) = rx.recv().await {
+            if update.id > last_sent {
+                yield sse_event(&update);
+            }
+        }
+    }
+    .heartbeat(Duration::from_secs(15)))
+}
+
+//
+// Notifications history, for the clients which were disconnected
+//
+// When enabled, the last notifications of each user are kept in the database with an increasing cursor. A client
+// coming back sends the cursor of the last notification it got, and receives the ones it missed. When they aren't
+// kept anymore, it's told to do a full sync instead.
+//
+
+struct HistoryRecord {
+    user_uuid: String,
+    atype: i32,
+    data: Vec<u8>,
+    delivered: bool,
+}
+
+static HISTORY_QUEUE: OnceCell<Sender<HistoryRecord>> = OnceCell::new();
+
+/// Starts writing the notifications to the database, if the notifications history is enabled.
+pub fn init_notification_history(pool: DbPool) {
+    let size = CONFIG.notifications_history_size();
+    if size == 0 || !CONFIG.enable_websocket() {
+        return;
+    }
+
+    // A single writer, so the cursors of a user follow the order of the notifications
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<HistoryRecord>(1_000);
+    if HISTORY_QUEUE.set(tx).is_err() {
+        return;
+    }
+    tokio::spawn(async move {
+        while let Some(record) = rx.recv().await {
+            let mut conn = match pool.get().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("Error getting a database connection for the notifications history: {e:?}");
+                    continue;
+                }
+            };
+            if let Err(e) = save_history_record(record, size, &mut conn).await {
+                error!("Error saving a notification to the history: {e:?}");
+            }
+        }
+    });
+}
+
+async fn save_history_record(record: HistoryRecord, size: u32, conn: &mut DbConn) -> EmptyResult {
+    let seq = NotificationEvent::find_last_seq_by_user(&record.user_uuid, conn).await.unwrap_or(0) + 1;
+    let data = data_encoding::BASE64.encode(&record.data);
+    NotificationEvent::new(record.user_uuid.clone(), seq, record.atype, data, record.delivered).save(conn).await?;
+    NotificationEvent::delete_up_to(&record.user_uuid, seq - i64::from(size), conn).await
+}
+
+// Never waits for the database, the notification isn't recorded if the writer can't keep up
+fn record_notification(user_uuid: &str, ut: UpdateType, data: &[u8], delivered: bool) {
+    let Some(queue) = HISTORY_QUEUE.get() else {
+        return;
+    };
+    let record = HistoryRecord {
+        user_uuid: user_uuid.to_string(),
+        atype: ut as i32,
+        data: data.to_vec(),
+        delivered,
+    };
+    if queue.try_send(record).is_err() {
+        warn!("The notifications history is full, a notification of user {user_uuid} isn't recorded");
+    }
+}
+
+// Without a cursor, only the current one is returned, for the clients which just did a full sync
+#[get("/missed?<cursor>")]
+async fn missed_notifications(cursor: Option<i64>, headers: Headers, mut conn: DbConn) -> JsonResult {
+    if HISTORY_QUEUE.get().is_none() {
+        err!("The notifications history is disabled")
+    }
+    let user_uuid = &headers.user.uuid;
+
+    let last_seq = NotificationEvent::find_last_seq_by_user(user_uuid, &mut conn).await.unwrap_or(0);
+    let (events, resync) = match cursor {
+        None => (Vec::new(), false),
+        // The history was cleared since, the cursor doesn't mean anything anymore
+        Some(cursor) if cursor > last_seq => (Vec::new(), true),
+        Some(cursor) => {
+            let first_seq = NotificationEvent::find_first_seq_by_user(user_uuid, &mut conn).await.unwrap_or(last_seq);
+            if cursor + 1 < first_seq {
+                // Some of the missed notifications aren't kept anymore
+                (Vec::new(), true)
+            } else {
+                (NotificationEvent::find_by_user_since(user_uuid, cursor, &mut conn).await, false)
+            }
+        }
+    };
+
+    if !events.is_empty() {
+        NotificationEvent::mark_delivered_up_to(user_uuid, last_seq, &mut conn).await?;
+    }
+    let events_json: Vec<serde_json::Value> = events.iter().map(NotificationEvent::to_json).collect();
+
+    Ok(Json(json!({
+        "Data": events_json,
+        "Cursor": last_seq,
+        "Resync": resync,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
 //
 // Websockets server
 //
@@ -320,21 +718,179 @@ static INITIAL_MESSAGE: InitialMessage<'static> = InitialMessage {
     version: 1,
 };
 
+// Counters of the WebSocket notifications since startup, shown in the diagnostics of the admin panel
+static WS_MESSAGES_SENT: AtomicU64 = AtomicU64::new(0);
+static WS_MESSAGES_DROPPED: AtomicU64 = AtomicU64::new(0);
+static WS_SLOW_CONSUMERS_DISCONNECTED: AtomicU64 = AtomicU64::new(0);
+static WS_CONNECTIONS_REFUSED: AtomicU64 = AtomicU64::new(0);
+static WS_IDLE_CONNECTIONS_CLOSED: AtomicU64 = AtomicU64::new(0);
+
+// Queues an update for a client without waiting, returns false when the client has to be disconnected:
+// either it's already gone, or it doesn't read its messages fast enough and its queue is full.
+// Dropping the sender ends the stream of the client, which will reconnect and sync again.
+fn queue_update<T>(sender: &Sender<T>, update: T) -> bool {
+    match sender.try_send(update) {
+        Ok(()) => {
+            WS_MESSAGES_SENT.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+        Err(TrySendError::Full(_)) => {
+            WS_MESSAGES_DROPPED.fetch_add(1, Ordering::Relaxed);
+            WS_SLOW_CONSUMERS_DISCONNECTED.fetch_add(1, Ordering::Relaxed);
+            warn!("WS client queue is full, disconnecting the slow client");
+            false
+        }
+        Err(TrySendError::Closed(_)) => {
+            WS_MESSAGES_DROPPED.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+}
+
+/// The WebSocket connections and the counters of the notifications, for the admin diagnostics.
+pub fn websocket_stats() -> serde_json::Value {
+    let users = WS_USERS.map.len();
+    let connections: usize = WS_USERS.map.iter().map(|senders| senders.len()).sum();
+    json!({
+        "users": users,
+        "connections": connections,
+        "anonymous_connections": WS_ANONYMOUS_SUBSCRIPTIONS.map.len(),
+        "messages_sent": WS_MESSAGES_SENT.load(Ordering::Relaxed),
+        "messages_dropped": WS_MESSAGES_DROPPED.load(Ordering::Relaxed),
+        "slow_consumers_disconnected": WS_SLOW_CONSUMERS_DISCONNECTED.load(Ordering::Relaxed),
+        "connections_refused": WS_CONNECTIONS_REFUSED.load(Ordering::Relaxed),
+        "idle_connections_closed": WS_IDLE_CONNECTIONS_CLOSED.load(Ordering::Relaxed),
+        "client_ips": CONNECTIONS_PER_IP.len(),
+        "pubsub": crate::pubsub::stats(),
+    })
+}
+
+// The cipher updates of a user which are coalesced, while a bulk operation is running
+struct CoalescedUpdates {
+    suppressed: u32,
+    last_update: Instant,
+    // The device doing the operation, if all the suppressed updates come from the same one
+    acting_device_uuid: Option<String>,
+}
+
+static COALESCED_UPDATES: Lazy<dashmap::DashMap<String, CoalescedUpdates>> = Lazy::new(dashmap::DashMap::new);
+
+// Bulk operations like moving or deleting many ciphers would send a notification per cipher.
+// The first update of a user is sent right away, the next ones within the window are suppressed, and a single
+// SyncVault is sent once no update came for the whole window. Returns true if the update has to be sent now.
+fn coalesce_update(user_uuid: &str, acting_device_uuid: &str) -> bool {
+    let window = Duration::from_millis(CONFIG.notifications_coalesce_window_ms());
+    if window.is_zero() {
+        return true;
+    }
+
+    match COALESCED_UPDATES.entry(user_uuid.to_string()) {
+        dashmap::mapref::entry::Entry::Occupied(mut entry) => {
+            let updates = entry.get_mut();
+            if updates.suppressed == 0 {
+                updates.acting_device_uuid = Some(acting_device_uuid.to_string());
+            } else if updates.acting_device_uuid.as_deref() != Some(acting_device_uuid) {
+                updates.acting_device_uuid = None;
+            }
+            updates.suppressed += 1;
+            updates.last_update = Instant::now();
+            false
+        }
+        dashmap::mapref::entry::Entry::Vacant(entry) => {
+            entry.insert(CoalescedUpdates {
+                suppressed: 0,
+                last_update: Instant::now(),
+                acting_device_uuid: None,
+            });
+            let user_uuid = user_uuid.to_string();
+            log_context::spawn(async move {
+                loop {
+                    tokio::time::sleep(window).await;
+                    let ended =
+                        COALESCED_UPDATES.remove_if(&user_uuid, |_, updates| updates.last_update.elapsed() >= window);
+                    if let Some((_, updates)) = ended {
+                        if updates.suppressed > 0 {
+                            debug!("Coalesced {} updates of user {user_uuid} into a SyncVault", updates.suppressed);
+                            WS_USERS.send_vault_sync(&user_uuid, updates.acting_device_uuid);
+                        }
+                        break;
+                    }
+                }
+            });
+            true
+        }
+    }
+}
+
+// An update queued for the clients of a user, shared by all their connections.
+// The ids order the updates sent by this instance, they are used to resume the event streams.
+struct QueuedUpdate {
+    id: u64,
+    data: Vec<u8>,
+}
+
+static UPDATE_SEQUENCE: AtomicU64 = AtomicU64::new(1);
+
 // We attach the UUID to the sender so we can differentiate them when we need to remove them from the Vec
-type UserSenders = (uuid::Uuid, Sender<Message>);
+type UserSenders = (uuid::Uuid, Sender<Arc<QueuedUpdate>>);
 #[derive(Clone)]
 pub struct WebSocketUsers {
     map: Arc<dashmap::DashMap<String, Vec<UserSenders>>>,
 }
 
 impl WebSocketUsers {
-    async fn send_update(&self, user_uuid: &str, data: &[u8]) {
-        if let Some(user) = self.map.get(user_uuid).map(|v| v.clone()) {
-            for (_, sender) in user.iter() {
-                if let Err(e) = sender.send(Message::binary(data)).await {
-                    error!("Error sending WS update {e}");
-                }
-            }
+    // Each user has their own list of channels, one per connected client.
+    // Past the configured maximum, the oldest connections of the user are closed.
+    fn add_sender(&self, user_uuid: &str, entry_uuid: uuid::Uuid, sender: Sender<Arc<QueuedUpdate>>) {
+        let mut senders = self.map.entry(user_uuid.to_string()).or_default();
+        senders.push((entry_uuid, sender));
+
+        let max_connections = CONFIG.websocket_max_connections_per_user();
+        if max_connections > 0 && senders.len() > max_connections {
+            let excess = senders.len() - max_connections;
+            senders.drain(..excess);
+            debug!("Closed {excess} WS connection(s) of user {user_uuid}, over the maximum of {max_connections}");
+        }
+    }
+
+    // The updates are queued without waiting for the clients, a slow client can't hold up the others
+    // Returns true if the update was queued for at least one client connected to this instance
+    pub fn send_local_update(&self, user_uuid: &str, data: &[u8]) -> bool {
+        let update = Arc::new(QueuedUpdate {
+            id: UPDATE_SEQUENCE.fetch_add(1, Ordering::Relaxed),
+            data: data.to_vec(),
+        });
+        record_sse_update(user_uuid, &update);
+        let Some(mut senders) = self.map.get_mut(user_uuid) else {
+            return false;
+        };
+        senders.retain(|(_, sender)| queue_update(sender, Arc::clone(&update)));
+        !senders.is_empty()
+    }
+
+    // The clients of the user may also be connected to other instances
+    fn send_update(&self, user_uuid: &str, ut: UpdateType, data: &[u8]) {
+        let delivered = self.send_local_update(user_uuid, data);
+        crate::pubsub::publish_user_update(user_uuid, data);
+        // The auth responses are sent to the auth request, not to a user
+        if ut != UpdateType::AuthRequestResponse {
+            record_notification(user_uuid, ut, data, delivered);
+        }
+    }
+
+    fn send_vault_sync(&self, user_uuid: &str, acting_device_uuid: Option<String>) {
+        let data = create_update(
+            vec![("UserId".into(), user_uuid.into()), ("Date".into(), serialize_date(Utc::now().naive_utc()))],
+            UpdateType::SyncVault,
+            acting_device_uuid.clone(),
+        );
+
+        if CONFIG.enable_websocket() {
+            self.send_update(user_uuid, UpdateType::SyncVault, &data);
+        }
+
+        if CONFIG.push_enabled() {
+            push_vault_sync(user_uuid, acting_device_uuid);
         }
     }
 
@@ -351,7 +907,7 @@ impl WebSocketUsers {
         );
 
         if CONFIG.enable_websocket() {
-            self.send_update(&user.uuid, &data).await;
+            self.send_update(&user.uuid, ut, &data);
         }
 
         if CONFIG.push_enabled() {
@@ -371,7 +927,7 @@ impl WebSocketUsers {
         );
 
         if CONFIG.enable_websocket() {
-            self.send_update(&user.uuid, &data).await;
+            self.send_update(&user.uuid, UpdateType::LogOut, &data);
         }
 
         if CONFIG.push_enabled() {
@@ -401,7 +957,7 @@ impl WebSocketUsers {
         );
 
         if CONFIG.enable_websocket() {
-            self.send_update(&folder.user_uuid, &data).await;
+            self.send_update(&folder.user_uuid, ut, &data);
         }
 
         if CONFIG.push_enabled() {
@@ -447,13 +1003,16 @@ impl WebSocketUsers {
             Some(acting_device_uuid.into()),
         );
 
+        let notified_uuids: Vec<&String> =
+            user_uuids.iter().filter(|uuid| coalesce_update(uuid, acting_device_uuid)).collect();
+
         if CONFIG.enable_websocket() {
-            for uuid in user_uuids {
-                self.send_update(uuid, &data).await;
+            for uuid in &notified_uuids {
+                self.send_update(uuid, ut, &data);
             }
         }
 
-        if CONFIG.push_enabled() && user_uuids.len() == 1 {
+        if CONFIG.push_enabled() && user_uuids.len() == 1 && notified_uuids.len() == 1 {
             push_cipher_update(ut, cipher, acting_device_uuid, conn).await;
         }
     }
@@ -484,7 +1043,7 @@ impl WebSocketUsers {
 
         if CONFIG.enable_websocket() {
             for uuid in user_uuids {
-                self.send_update(uuid, &data).await;
+                self.send_update(uuid, ut, &data);
             }
         }
         if CONFIG.push_enabled() && user_uuids.len() == 1 {
@@ -509,7 +1068,7 @@ impl WebSocketUsers {
             Some(acting_device_uuid.to_string()),
         );
         if CONFIG.enable_websocket() {
-            self.send_update(user_uuid, &data).await;
+            self.send_update(user_uuid, UpdateType::AuthRequest, &data);
         }
 
         if CONFIG.push_enabled() {
@@ -534,7 +1093,7 @@ impl WebSocketUsers {
             approving_device_uuid.clone().into(),
         );
         if CONFIG.enable_websocket() {
-            self.send_update(auth_response_uuid, &data).await;
+            self.send_update(auth_response_uuid, UpdateType::AuthRequestResponse, &data);
         }
 
         if CONFIG.push_enabled() {
@@ -550,12 +1109,13 @@ pub struct AnonymousWebSocketSubscriptions {
 }
 
 impl AnonymousWebSocketSubscriptions {
-    async fn send_update(&self, token: &str, data: &[u8]) {
-        if let Some(sender) = self.map.get(token).map(|v| v.clone()) {
-            if let Err(e) = sender.send(Message::binary(data)).await {
-                error!("Error sending WS update {e}");
-            }
-        }
+    pub fn send_local_update(&self, token: &str, data: &[u8]) {
+        self.map.remove_if(token, |_, sender| !queue_update(sender, Message::binary(data)));
+    }
+
+    fn send_update(&self, token: &str, data: &[u8]) {
+        self.send_local_update(token, data);
+        crate::pubsub::publish_anonymous_update(token, data);
     }
 
     pub async fn send_auth_response(&self, user_uuid: &String, auth_response_uuid: &str) {
@@ -567,7 +1127,7 @@ impl AnonymousWebSocketSubscriptions {
             UpdateType::AuthRequestResponse,
             user_uuid.to_string(),
         );
-        self.send_update(auth_response_uuid, &data).await;
+        self.send_update(auth_response_uuid, &data);
     }
 }
 
@@ -3,10 +3,10 @@ use serde_json::Value;
 use tokio::sync::RwLock;
 
 use crate::{
-    api::{ApiResult, EmptyResult, UpdateType},
+    api::{push_direct, ApiResult, EmptyResult, UpdateType},
     db::models::{Cipher, Device, Folder, Send, User},
-    util::get_reqwest_client,
-    CONFIG,
+    http_client::{get_reqwest_client, TracedSend},
+    log_context, CONFIG,
 };
 
 use once_cell::sync::Lazy;
@@ -53,7 +53,7 @@ async fn get_auth_push_token() -> ApiResult<String> {
     let res = match get_reqwest_client()
         .post(&format!("{}/connect/token", CONFIG.push_identity_uri()))
         .form(&params)
-        .send()
+        .send_traced()
         .await
     {
         Ok(r) => r,
@@ -92,13 +92,21 @@ pub async fn register_push_device(device: &mut Device, conn: &mut crate::db::DbC
     // generate a random push_uuid so we know the device is registered
     device.push_uuid = Some(uuid::Uuid::new_v4().to_string());
 
+    // Without the relay, the push token stored with the device is all that's needed
+    if push_direct::enabled() {
+        if let Err(e) = device.save(conn).await {
+            err!(format!("An error occurred while trying to save the (registered) device push uuid: {e}"));
+        }
+        return Ok(());
+    }
+
     //Needed to register a device for push to bitwarden :
     let data = json!({
         "userId": device.user_uuid,
         "deviceId": device.push_uuid,
         "identifier": device.uuid,
         "type": device.atype,
-        "pushToken": device.push_token
+        "pushToken": device.push_token()
     });
 
     let auth_push_token = get_auth_push_token().await?;
@@ -110,7 +118,7 @@ pub async fn register_push_device(device: &mut Device, conn: &mut crate::db::DbC
         .header(ACCEPT, "application/json")
         .header(AUTHORIZATION, auth_header)
         .json(&data)
-        .send()
+        .send_traced()
         .await?
         .error_for_status()
     {
@@ -125,7 +133,7 @@ pub async fn register_push_device(device: &mut Device, conn: &mut crate::db::DbC
 }
 
 pub async fn unregister_push_device(push_uuid: Option<String>) -> EmptyResult {
-    if !CONFIG.push_enabled() || push_uuid.is_none() {
+    if !CONFIG.push_enabled() || push_uuid.is_none() || push_direct::enabled() {
         return Ok(());
     }
     let auth_push_token = get_auth_push_token().await?;
@@ -135,7 +143,7 @@ pub async fn unregister_push_device(push_uuid: Option<String>) -> EmptyResult {
     match get_reqwest_client()
         .delete(CONFIG.push_relay_uri() + "/push/" + &push_uuid.unwrap())
         .header(AUTHORIZATION, auth_header)
-        .send()
+        .send_traced()
         .await
     {
         Ok(r) => r,
@@ -183,7 +191,7 @@ pub async fn push_cipher_update(
 pub fn push_logout(user: &User, acting_device_uuid: Option<String>) {
     let acting_device_uuid: Value = acting_device_uuid.map(|v| v.into()).unwrap_or_else(|| Value::Null);
 
-    tokio::task::spawn(send_to_push_relay(json!({
+    log_context::spawn(send_to_push_relay(json!({
         "userId": user.uuid,
         "organizationId": (),
         "deviceId": acting_device_uuid,
@@ -197,7 +205,7 @@ pub fn push_logout(user: &User, acting_device_uuid: Option<String>) {
 }
 
 pub fn push_user_update(ut: UpdateType, user: &User) {
-    tokio::task::spawn(send_to_push_relay(json!({
+    log_context::spawn(send_to_push_relay(json!({
         "userId": user.uuid,
         "organizationId": (),
         "deviceId": (),
@@ -210,6 +218,22 @@ pub fn push_user_update(ut: UpdateType, user: &User) {
     })));
 }
 
+pub fn push_vault_sync(user_uuid: &str, acting_device_uuid: Option<String>) {
+    let acting_device_uuid: Value = acting_device_uuid.map(|v| v.into()).unwrap_or_else(|| Value::Null);
+
+    log_context::spawn(send_to_push_relay(json!({
+        "userId": user_uuid,
+        "organizationId": (),
+        "deviceId": acting_device_uuid,
+        "identifier": acting_device_uuid,
+        "type": UpdateType::SyncVault as i32,
+        "payload": {
+            "userId": user_uuid,
+            "date": chrono::Utc::now().naive_utc()
+        }
+    })));
+}
+
 pub async fn push_folder_update(
     ut: UpdateType,
     folder: &Folder,
@@ -217,7 +241,7 @@ pub async fn push_folder_update(
     conn: &mut crate::db::DbConn,
 ) {
     if Device::check_user_has_push_device(&folder.user_uuid, conn).await {
-        tokio::task::spawn(send_to_push_relay(json!({
+        log_context::spawn(send_to_push_relay(json!({
             "userId": folder.user_uuid,
             "organizationId": (),
             "deviceId": acting_device_uuid,
@@ -235,7 +259,7 @@ pub async fn push_folder_update(
 pub async fn push_send_update(ut: UpdateType, send: &Send, acting_device_uuid: &String, conn: &mut crate::db::DbConn) {
     if let Some(s) = &send.user_uuid {
         if Device::check_user_has_push_device(s, conn).await {
-            tokio::task::spawn(send_to_push_relay(json!({
+            log_context::spawn(send_to_push_relay(json!({
                 "userId": send.user_uuid,
                 "organizationId": (),
                 "deviceId": acting_device_uuid,
@@ -256,6 +280,11 @@ async fn send_to_push_relay(notification_data: Value) {
         return;
     }
 
+    if push_direct::enabled() {
+        push_direct::send(notification_data).await;
+        return;
+    }
+
     let auth_push_token = match get_auth_push_token().await {
         Ok(s) => s,
         Err(e) => {
@@ -272,7 +301,7 @@ async fn send_to_push_relay(notification_data: Value) {
         .header(CONTENT_TYPE, "application/json")
         .header(AUTHORIZATION, &auth_header)
         .json(&notification_data)
-        .send()
+        .send_traced()
         .await
     {
         error!("An error occurred while sending a send update to the push relay: {}", e);
@@ -281,7 +310,7 @@ async fn send_to_push_relay(notification_data: Value) {
 
 pub async fn push_auth_request(user_uuid: String, auth_request_uuid: String, conn: &mut crate::db::DbConn) {
     if Device::check_user_has_push_device(user_uuid.as_str(), conn).await {
-        tokio::task::spawn(send_to_push_relay(json!({
+        log_context::spawn(send_to_push_relay(json!({
             "userId": user_uuid,
             "organizationId": (),
             "deviceId": null,
@@ -302,7 +331,7 @@ pub async fn push_auth_response(
     conn: &mut crate::db::DbConn,
 ) {
     if Device::check_user_has_push_device(user_uuid.as_str(), conn).await {
-        tokio::task::spawn(send_to_push_relay(json!({
+        log_context::spawn(send_to_push_relay(json!({
             "userId": user_uuid,
             "organizationId": (),
             "deviceId": approving_device_uuid,
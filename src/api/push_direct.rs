@@ -0,0 +1,392 @@
+//
+// Direct push notifications, without the Bitwarden push relay
+//
+// Instead of registering the devices with the push relay of bitwarden.com, Vaultwarden sends the notifications
+// itself with the credentials of the operator: to Firebase Cloud Messaging for Android, to the Apple Push
+// Notification service for iOS, and to the UnifiedPush distributor of the device when its push token is an URL.
+//
+// The devices are only registered locally, their push token is kept in the `devices` table.
+// Note that the official apps are built with the credentials of Bitwarden: only the apps built with the
+// configured credentials, or using UnifiedPush, can receive these notifications.
+//
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use once_cell::sync::{Lazy, OnceCell};
+use reqwest::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    StatusCode,
+};
+use serde_json::Value;
+use tokio::sync::RwLock;
+use url::Url;
+
+use crate::{
+    api::ApiResult,
+    db::{
+        models::{Device, DeviceType},
+        DbPool,
+    },
+    http_client::{get_reqwest_client, TracedSend},
+    CONFIG,
+};
+
+const FCM_SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
+const APNS_URL: &str = "https://api.push.apple.com";
+const APNS_SANDBOX_URL: &str = "https://api.sandbox.push.apple.com";
+
+// The notifications are sent from background tasks, which need their own database connection
+static DB_POOL: OnceCell<DbPool> = OnceCell::new();
+
+/// Returns true if the notifications are sent directly instead of through the Bitwarden push relay.
+pub fn enabled() -> bool {
+    CONFIG.push_enabled() && CONFIG.push_mode().eq_ignore_ascii_case("direct")
+}
+
+/// Gives access to the database to the direct push notifications.
+pub fn init_direct_push(pool: DbPool) {
+    if enabled() {
+        DB_POOL.set(pool).ok();
+    }
+}
+
+// The result of a delivery attempt to a device
+enum Delivery {
+    Sent,
+    // The token isn't valid anymore, the app was uninstalled or the registration expired
+    InvalidToken,
+    // Worth trying again later, the push service is unavailable or rate limiting
+    Retry(String),
+    Failed(String),
+}
+
+/// Sends a notification, in the format of the push relay, to all the push devices of the user except the acting one.
+pub async fn send(notification: Value) {
+    let (Some(user_uuid), Some(pool)) = (notification["userId"].as_str(), DB_POOL.get()) else {
+        return;
+    };
+    let devices = match pool.get().await {
+        Ok(mut conn) => Device::find_push_devices_by_user(user_uuid, &mut conn).await,
+        Err(e) => {
+            error!("Error getting a database connection for the push notifications: {e:?}");
+            return;
+        }
+    };
+
+    let data = json!({
+        "type": notification["type"],
+        "payload": notification["payload"],
+    });
+    let acting_device_uuid = notification["identifier"].as_str();
+
+    let mut invalid_devices = Vec::new();
+    for device in devices {
+        if acting_device_uuid == Some(device.uuid.as_str()) {
+            continue;
+        }
+        let Some(token) = device.push_token() else {
+            continue;
+        };
+        match deliver_with_retries(&device, &token, &data).await {
+            Delivery::Sent => debug!("Push notification sent to device {}", device.uuid),
+            Delivery::InvalidToken => invalid_devices.push(device.uuid),
+            Delivery::Retry(e) | Delivery::Failed(e) => {
+                error!("Error sending a push notification to device {}: {e}", device.uuid)
+            }
+        }
+    }
+
+    if invalid_devices.is_empty() {
+        return;
+    }
+    if let Ok(mut conn) = pool.get().await {
+        for device_uuid in invalid_devices {
+            info!("Removing the expired push token of device {device_uuid}");
+            Device::clear_push_token_by_uuid(&device_uuid, &mut conn).await.ok();
+        }
+    }
+}
+
+// Retries with an exponential backoff, starting at one second
+async fn deliver_with_retries(device: &Device, token: &str, data: &Value) -> Delivery {
+    let mut attempt = 0;
+    loop {
+        match deliver(device, token, data).await {
+            Delivery::Retry(e) if attempt < CONFIG.push_max_retries() => {
+                let delay = Duration::from_secs(1 << attempt.min(6));
+                debug!("Push notification to device {} failed ({e}), retrying in {}s", device.uuid, delay.as_secs());
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            delivery => return delivery,
+        }
+    }
+}
+
+async fn deliver(device: &Device, token: &str, data: &Value) -> Delivery {
+    if token.starts_with("https://") {
+        return deliver_unifiedpush(token, data).await;
+    }
+    match DeviceType::from_i32(device.atype) {
+        DeviceType::Android => deliver_fcm(token, data).await,
+        DeviceType::Ios => deliver_apns(token, data).await,
+        device_type => Delivery::Failed(format!("Push notifications aren't supported for {device_type} devices")),
+    }
+}
+
+async fn classify_response(res: Result<reqwest::Response, reqwest::Error>) -> Delivery {
+    let res = match res {
+        Ok(res) => res,
+        Err(e) => return Delivery::Retry(e.to_string()),
+    };
+    let status = res.status();
+    if status.is_success() {
+        return Delivery::Sent;
+    }
+    let body = res.text().await.unwrap_or_default();
+    match status {
+        StatusCode::NOT_FOUND | StatusCode::GONE => Delivery::InvalidToken,
+        // Apple refuses malformed or foreign tokens with a 400
+        StatusCode::BAD_REQUEST if body.contains("BadDeviceToken") => Delivery::InvalidToken,
+        StatusCode::TOO_MANY_REQUESTS => Delivery::Retry(format!("{status}")),
+        status if status.is_server_error() => Delivery::Retry(format!("{status}: {body}")),
+        status => Delivery::Failed(format!("{status}: {body}")),
+    }
+}
+
+// Access tokens are cached for half of their validity
+struct CachedToken {
+    token: String,
+    valid_until: Instant,
+}
+
+async fn cached_token<F>(cache: &RwLock<Option<CachedToken>>, validity: Duration, new_token: F) -> ApiResult<String>
+where
+    F: std::future::Future<Output = ApiResult<String>>,
+{
+    if let Some(cached) = &*cache.read().await {
+        if cached.valid_until > Instant::now() {
+            return Ok(cached.token.clone());
+        }
+    }
+    let token = new_token.await?;
+    *cache.write().await = Some(CachedToken {
+        token: token.clone(),
+        valid_until: Instant::now() + validity / 2,
+    });
+    Ok(token)
+}
+
+//
+// Firebase Cloud Messaging
+//
+
+#[derive(Deserialize)]
+struct FcmServiceAccount {
+    project_id: String,
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Serialize)]
+struct FcmTokenClaims {
+    iss: String,
+    scope: String,
+    aud: String,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct FcmAccessToken {
+    access_token: String,
+}
+
+fn fcm_service_account() -> ApiResult<&'static FcmServiceAccount> {
+    static ACCOUNT: OnceCell<FcmServiceAccount> = OnceCell::new();
+    ACCOUNT.get_or_try_init(|| {
+        let path = match CONFIG.push_fcm_credentials() {
+            Some(path) => path,
+            None => err!("`PUSH_FCM_CREDENTIALS` is not set"),
+        };
+        let credentials = match std::fs::read_to_string(&path) {
+            Ok(credentials) => credentials,
+            Err(e) => err!(format!("Error reading the FCM service account key {path}: {e}")),
+        };
+        match serde_json::from_str(&credentials) {
+            Ok(account) => Ok(account),
+            Err(e) => err!(format!("Invalid FCM service account key {path}: {e}")),
+        }
+    })
+}
+
+async fn fcm_token() -> ApiResult<String> {
+    static FCM_TOKEN: Lazy<RwLock<Option<CachedToken>>> = Lazy::new(|| RwLock::new(None));
+    cached_token(&FCM_TOKEN, Duration::from_secs(3600), async {
+        let account = fcm_service_account()?;
+        let now = Utc::now().timestamp();
+        let claims = FcmTokenClaims {
+            iss: account.client_email.clone(),
+            scope: FCM_SCOPE.to_string(),
+            aud: account.token_uri.clone(),
+            iat: now,
+            exp: now + 3600,
+        };
+        let key = match jsonwebtoken::EncodingKey::from_rsa_pem(account.private_key.as_bytes()) {
+            Ok(key) => key,
+            Err(e) => err!(format!("Invalid private key in the FCM service account key: {e}")),
+        };
+        let assertion =
+            match jsonwebtoken::encode(&jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256), &claims, &key) {
+                Ok(assertion) => assertion,
+                Err(e) => err!(format!("Error signing the FCM token request: {e}")),
+            };
+
+        let params = [("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"), ("assertion", &assertion)];
+        match get_reqwest_client().post(&account.token_uri).form(&params).send_traced().await {
+            Ok(res) => match res.error_for_status() {
+                Ok(res) => match res.json::<FcmAccessToken>().await {
+                    Ok(token) => Ok(token.access_token),
+                    Err(e) => err!(format!("Unexpected FCM token received: {e}")),
+                },
+                Err(e) => err!(format!("Error getting a FCM token: {e}")),
+            },
+            Err(e) => err!(format!("Error getting a FCM token: {e}")),
+        }
+    })
+    .await
+}
+
+async fn deliver_fcm(token: &str, data: &Value) -> Delivery {
+    let (account, access_token) = match (fcm_service_account(), fcm_token().await) {
+        (Ok(account), Ok(access_token)) => (account, access_token),
+        (Err(e), _) | (_, Err(e)) => return Delivery::Failed(e.to_string()),
+    };
+    // The values of a data message have to be strings
+    let message = json!({
+        "message": {
+            "token": token,
+            "data": {
+                "type": data["type"].to_string(),
+                "payload": data["payload"].to_string(),
+            },
+            "android": {
+                "priority": "high",
+            },
+        }
+    });
+
+    let res = get_reqwest_client()
+        .post(format!("https://fcm.googleapis.com/v1/projects/{}/messages:send", account.project_id))
+        .header(AUTHORIZATION, format!("Bearer {access_token}"))
+        .json(&message)
+        .send_traced()
+        .await;
+    classify_response(res).await
+}
+
+//
+// Apple Push Notification service
+//
+
+#[derive(Serialize)]
+struct ApnsTokenClaims {
+    iss: String,
+    iat: i64,
+}
+
+// The provider tokens are signed with the .p8 key, Apple refuses tokens older than one hour
+async fn apns_token() -> ApiResult<String> {
+    static APNS_TOKEN: Lazy<RwLock<Option<CachedToken>>> = Lazy::new(|| RwLock::new(None));
+    cached_token(&APNS_TOKEN, Duration::from_secs(3600), async {
+        let path = CONFIG.push_apns_key().unwrap_or_default();
+        let pem = match tokio::fs::read(&path).await {
+            Ok(pem) => pem,
+            Err(e) => err!(format!("Error reading the APNs key {path}: {e}")),
+        };
+        let key = match jsonwebtoken::EncodingKey::from_ec_pem(&pem) {
+            Ok(key) => key,
+            Err(e) => err!(format!("Invalid APNs key {path}: {e}")),
+        };
+
+        let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::ES256);
+        header.kid = CONFIG.push_apns_key_id();
+        let claims = ApnsTokenClaims {
+            iss: CONFIG.push_apns_team_id().unwrap_or_default(),
+            iat: Utc::now().timestamp(),
+        };
+        match jsonwebtoken::encode(&header, &claims, &key) {
+            Ok(token) => Ok(token),
+            Err(e) => err!(format!("Error signing the APNs token: {e}")),
+        }
+    })
+    .await
+}
+
+async fn deliver_apns(token: &str, data: &Value) -> Delivery {
+    if CONFIG.push_apns_key().is_none() {
+        return Delivery::Failed("`PUSH_APNS_KEY` is not set".to_string());
+    }
+    let provider_token = match apns_token().await {
+        Ok(provider_token) => provider_token,
+        Err(e) => return Delivery::Failed(e.to_string()),
+    };
+    let base_url = if CONFIG.push_apns_sandbox() {
+        APNS_SANDBOX_URL
+    } else {
+        APNS_URL
+    };
+    // A background notification, the app syncs without showing anything
+    let message = json!({
+        "aps": {
+            "content-available": 1,
+        },
+        "data": data,
+    });
+
+    let res = get_reqwest_client()
+        .post(format!("{base_url}/3/device/{token}"))
+        .header(AUTHORIZATION, format!("bearer {provider_token}"))
+        .header("apns-topic", CONFIG.push_apns_topic().unwrap_or_default())
+        .header("apns-push-type", "background")
+        .header("apns-priority", "5")
+        .json(&message)
+        .send_traced()
+        .await;
+    classify_response(res).await
+}
+
+//
+// UnifiedPush
+//
+
+// The endpoint is chosen by the client, it must not be used to reach the internal network of the server
+async fn check_unifiedpush_endpoint(endpoint: &str) -> Result<(), String> {
+    let url = Url::parse(endpoint).map_err(|e| format!("Invalid UnifiedPush endpoint: {e}"))?;
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        return Err("Invalid UnifiedPush endpoint".to_string());
+    };
+    let addresses = tokio::net::lookup_host((host, port)).await.map_err(|e| format!("Error resolving {host}: {e}"))?;
+    for address in addresses {
+        if !crate::util::is_global(address.ip()) {
+            return Err(format!("The UnifiedPush endpoint {host} resolves to a non global address"));
+        }
+    }
+    Ok(())
+}
+
+async fn deliver_unifiedpush(endpoint: &str, data: &Value) -> Delivery {
+    if let Err(e) = check_unifiedpush_endpoint(endpoint).await {
+        return Delivery::Failed(e);
+    }
+
+    let res = get_reqwest_client()
+        .post(endpoint)
+        .header(CONTENT_TYPE, "application/json")
+        .header("TTL", "86400")
+        .json(data)
+        .send_traced()
+        .await;
+    classify_response(res).await
+}
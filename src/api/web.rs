@@ -1,20 +1,31 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
 
+use once_cell::sync::Lazy;
 use rocket::{fs::NamedFile, http::ContentType, response::content::RawHtml as Html, serde::json::Json, Catcher, Route};
 use serde_json::Value;
 
 use crate::{
     api::{core::now, ApiResult, EmptyResult},
     auth::decode_file_download,
+    crypto,
     error::Error,
-    util::{Cached, SafeString},
+    util::{Cached, RangedFile, Revalidated, SafeString},
     CONFIG,
 };
 
+// The files with a hash in their name never change, the new versions get a new name
+const IMMUTABLE_TTL: u64 = 31_536_000; // 1 year
+
 pub fn routes() -> Vec<Route> {
     // If adding more routes here, consider also adding them to
     // crate::utils::LOGGED_ROUTES to make sure they appear in the log
     let mut routes = routes![attachments, alive, alive_head, static_files];
+    routes.append(&mut super::health::routes());
     if CONFIG.web_vault_enabled() {
         routes.append(&mut routes![web_index, web_index_head, app_id, web_files]);
     }
@@ -29,9 +40,9 @@ pub fn routes() -> Vec<Route> {
 
 pub fn catchers() -> Vec<Catcher> {
     if CONFIG.web_vault_enabled() {
-        catchers![not_found]
+        catchers![not_found, service_unavailable]
     } else {
-        catchers![]
+        catchers![service_unavailable]
     }
 }
 
@@ -45,9 +56,19 @@ fn not_found() -> ApiResult<Html<String>> {
     Ok(Html(text))
 }
 
+#[catch(503)]
+fn service_unavailable() -> ApiResult<Html<String>> {
+    let json = json!({
+        "urlpath": CONFIG.domain_path(),
+        "maintenance": crate::db::in_maintenance(),
+    });
+    let text = CONFIG.render_template("503", &json)?;
+    Ok(Html(text))
+}
+
 #[get("/")]
-async fn web_index() -> Cached<Option<NamedFile>> {
-    Cached::short(NamedFile::open(Path::new(&CONFIG.web_vault_folder()).join("index.html")).await.ok(), false)
+async fn web_index() -> Option<Revalidated<NamedFile>> {
+    web_file(Path::new(&CONFIG.web_vault_folder()).join("index.html")).await
 }
 
 #[head("/")]
@@ -93,13 +114,64 @@ fn app_id() -> Cached<(ContentType, Json<Value>)> {
     )
 }
 
+#[derive(Responder)]
+enum WebFile {
+    Immutable(Cached<NamedFile>),
+    Revalidated(Revalidated<NamedFile>),
+}
+
 #[get("/<p..>", rank = 10)] // Only match this if the other routes don't match
-async fn web_files(p: PathBuf) -> Cached<Option<NamedFile>> {
-    Cached::long(NamedFile::open(Path::new(&CONFIG.web_vault_folder()).join(p)).await.ok(), true)
+async fn web_files(p: PathBuf) -> Option<WebFile> {
+    let path = Path::new(&CONFIG.web_vault_folder()).join(&p);
+    if is_content_hashed(&p) {
+        let file = NamedFile::open(path).await.ok()?;
+        Some(WebFile::Immutable(Cached::ttl(file, IMMUTABLE_TTL, true)))
+    } else {
+        web_file(path).await.map(WebFile::Revalidated)
+    }
+}
+
+// The bundles of the web vault are named like `main.3f2a9c8b1e4d5a6b7c8d.js`
+fn is_content_hashed(path: &Path) -> bool {
+    let Some(file_name) = path.file_name().and_then(|file_name| file_name.to_str()) else {
+        return false;
+    };
+    let stem = file_name.rsplit_once('.').map_or(file_name, |(stem, _extension)| stem);
+    stem.split(['.', '-', '_']).any(|part| {
+        part.len() >= 8 && part.chars().all(|c| c.is_ascii_hexdigit()) && part.chars().any(|c| c.is_ascii_digit())
+    })
+}
+
+// The content hashes of the files, with the modification time and the size they were computed for
+static FILE_HASHES: Lazy<Mutex<HashMap<PathBuf, (SystemTime, u64, String)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+async fn web_file(path: PathBuf) -> Option<Revalidated<NamedFile>> {
+    let metadata = tokio::fs::metadata(&path).await.ok()?;
+    let modified = metadata.modified().ok()?;
+    let cached = FILE_HASHES
+        .lock()
+        .unwrap()
+        .get(&path)
+        .filter(|(hash_modified, hash_len, _)| *hash_modified == modified && *hash_len == metadata.len())
+        .map(|(_, _, hash)| hash.clone());
+    let hash = match cached {
+        Some(hash) => hash,
+        None => {
+            let hash = content_hash(&tokio::fs::read(&path).await.ok()?);
+            FILE_HASHES.lock().unwrap().insert(path.clone(), (modified, metadata.len(), hash.clone()));
+            hash
+        }
+    };
+    let file = NamedFile::open(path).await.ok()?;
+    Some(Revalidated::new(file, &hash))
+}
+
+fn content_hash(content: &[u8]) -> String {
+    crypto::sha256_hex(content)[..32].to_string()
 }
 
 #[get("/attachments/<uuid>/<file_id>?<token>")]
-async fn attachments(uuid: SafeString, file_id: SafeString, token: String) -> Option<NamedFile> {
+async fn attachments(uuid: SafeString, file_id: SafeString, token: String) -> Option<RangedFile> {
     let Ok(claims) = decode_file_download(&token) else {
         return None;
     };
@@ -107,7 +179,7 @@ async fn attachments(uuid: SafeString, file_id: SafeString, token: String) -> Op
         return None;
     }
 
-    NamedFile::open(Path::new(&CONFIG.attachments_folder()).join(uuid).join(file_id)).await.ok()
+    RangedFile::open(&Path::new(&CONFIG.attachments_folder()).join(uuid).join(file_id)).await.ok()
 }
 
 // We use DbConn here to let the alive healthcheck also verify the database connection.
@@ -127,7 +199,7 @@ fn alive_head(_conn: DbConn) -> EmptyResult {
 // This endpoint/function is used during development and development only.
 // It allows to easily develop the admin interface by always loading the files from disk instead from a slice of bytes
 // This will only be active during a debug build and only when `RELOAD_TEMPLATES` is set to `true`
-// NOTE: Do not forget to add any new files added to the `static_files` function below!
+// NOTE: Do not forget to add any new files added to the `static_file` function below!
 #[cfg(debug_assertions)]
 #[get("/vw_static/<filename>", rank = 1)]
 pub async fn _static_files_dev(filename: PathBuf) -> Option<NamedFile> {
@@ -147,8 +219,18 @@ pub async fn _static_files_dev(filename: PathBuf) -> Option<NamedFile> {
     None
 }
 
+// The hashes of the bundled files, they only change with the binary
+static STATIC_FILE_HASHES: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
 #[get("/vw_static/<filename>", rank = 2)]
-pub fn static_files(filename: &str) -> Result<(ContentType, &'static [u8]), Error> {
+pub fn static_files(filename: &str) -> Result<Revalidated<(ContentType, &'static [u8])>, Error> {
+    let (content_type, content) = static_file(filename)?;
+    let hash =
+        STATIC_FILE_HASHES.lock().unwrap().entry(filename.to_string()).or_insert_with(|| content_hash(content)).clone();
+    Ok(Revalidated::new((content_type, content), &hash))
+}
+
+pub fn static_file(filename: &str) -> Result<(ContentType, &'static [u8]), Error> {
     match filename {
         "404.png" => Ok((ContentType::PNG, include_bytes!("../static/images/404.png"))),
         "mail-github.png" => Ok((ContentType::PNG, include_bytes!("../static/images/mail-github.png"))),
@@ -161,10 +243,13 @@ pub fn static_files(filename: &str) -> Result<(ContentType, &'static [u8]), Erro
         "admin.css" => Ok((ContentType::CSS, include_bytes!("../static/scripts/admin.css"))),
         "admin.js" => Ok((ContentType::JavaScript, include_bytes!("../static/scripts/admin.js"))),
         "admin_settings.js" => Ok((ContentType::JavaScript, include_bytes!("../static/scripts/admin_settings.js"))),
+        "admin_setup.js" => Ok((ContentType::JavaScript, include_bytes!("../static/scripts/admin_setup.js"))),
         "admin_users.js" => Ok((ContentType::JavaScript, include_bytes!("../static/scripts/admin_users.js"))),
         "admin_organizations.js" => {
             Ok((ContentType::JavaScript, include_bytes!("../static/scripts/admin_organizations.js")))
         }
+        "admin_mail_log.js" => Ok((ContentType::JavaScript, include_bytes!("../static/scripts/admin_mail_log.js"))),
+        "admin_jobs.js" => Ok((ContentType::JavaScript, include_bytes!("../static/scripts/admin_jobs.js"))),
         "admin_diagnostics.js" => {
             Ok((ContentType::JavaScript, include_bytes!("../static/scripts/admin_diagnostics.js")))
         }
@@ -179,3 +264,19 @@ pub fn static_files(filename: &str) -> Result<(ContentType, &'static [u8]), Erro
         _ => err!(format!("Static file not found: {filename}")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_content_hashed() {
+        assert!(is_content_hashed(Path::new("app/main.3f2a9c8b1e4d5a6b7c8d.js")));
+        assert!(is_content_hashed(Path::new("styles.0b1e2f3a4c5d6e7f.css")));
+        assert!(is_content_hashed(Path::new("bwi-font-1f3c2a9d.woff2")));
+        assert!(!is_content_hashed(Path::new("index.html")));
+        assert!(!is_content_hashed(Path::new("locales/en/messages.json")));
+        assert!(!is_content_hashed(Path::new("images/deadbeefcafe.png")));
+        assert!(!is_content_hashed(Path::new("app-id.json")));
+    }
+}
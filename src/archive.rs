@@ -0,0 +1,294 @@
+//
+// Archives of the whole instance, for the disaster recovery
+//
+// The rows of all the tables are exported as JSON lines into a gzip compressed archive, which doesn't depend on the
+// database backend. An archive can be imported into a new, empty, database of any backend with the same schema.
+// The whole archive is checked before anything is imported: the rows need to match the models, their foreign keys need
+// to reference rows of the archive, and the files of the attachments and Sends need to be in their folders.
+// The files, the config and the keys aren't part of the archive, and the encrypted columns stay encrypted with the
+// `DATABASE_ENCRYPTION_KEYS`, so they need to be restored separately.
+//
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde_json::Value;
+
+use crate::{
+    api::EmptyResult,
+    db::{
+        models::{ensure_empty, export_tables, import_rows, table_names, Attachment, Send, SendType},
+        DbConn,
+    },
+    error::Error,
+    CONFIG,
+};
+
+const FORMAT: &str = "vaultwarden-archive";
+// Needs to be increased when the archives of the previous versions can't be imported anymore
+const VERSION: u32 = 1;
+const FILE_PREFIX: &str = "archive_";
+const FILE_EXTENSION: &str = ".jsonl.gz";
+// Only the first errors are reported
+const MAX_ERRORS: usize = 20;
+
+// The foreign keys, as (table, column, referenced table), which all reference the `uuid` column
+const REFERENCES: &[(&str, &str, &str)] = &[
+    ("users_organizations", "user_uuid", "users"),
+    ("users_organizations", "org_uuid", "organizations"),
+    ("organization_api_key", "org_uuid", "organizations"),
+    ("org_policies", "org_uuid", "organizations"),
+    ("groups", "organizations_uuid", "organizations"),
+    ("groups_users", "groups_uuid", "groups"),
+    ("groups_users", "users_organizations_uuid", "users_organizations"),
+    ("collections", "org_uuid", "organizations"),
+    ("collections_groups", "collections_uuid", "collections"),
+    ("collections_groups", "groups_uuid", "groups"),
+    ("users_collections", "user_uuid", "users"),
+    ("users_collections", "collection_uuid", "collections"),
+    ("folders", "user_uuid", "users"),
+    ("ciphers", "user_uuid", "users"),
+    ("ciphers", "organization_uuid", "organizations"),
+    ("ciphers_collections", "cipher_uuid", "ciphers"),
+    ("ciphers_collections", "collection_uuid", "collections"),
+    ("folders_ciphers", "cipher_uuid", "ciphers"),
+    ("folders_ciphers", "folder_uuid", "folders"),
+    ("favorites", "user_uuid", "users"),
+    ("favorites", "cipher_uuid", "ciphers"),
+    ("attachments", "cipher_uuid", "ciphers"),
+    ("devices", "user_uuid", "users"),
+    ("twofactor", "user_uuid", "users"),
+    ("twofactor_incomplete", "user_uuid", "users"),
+    ("web_authn_credentials", "user_uuid", "users"),
+    ("sends", "user_uuid", "users"),
+    ("sends", "organization_uuid", "organizations"),
+    ("emergency_access", "grantor_uuid", "users"),
+    ("emergency_access", "grantee_uuid", "users"),
+    ("auth_requests", "user_uuid", "users"),
+    ("external_identities", "user_uuid", "users"),
+    ("notification_events", "user_uuid", "users"),
+    ("mail_preferences", "user_uuid", "users"),
+];
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Line {
+    Header {
+        format: String,
+        version: u32,
+        server_version: Option<String>,
+        created_at: String,
+    },
+    Rows {
+        table: String,
+        rows: Vec<Value>,
+    },
+    Trailer {
+        counts: Vec<(String, i64)>,
+    },
+}
+
+/// Exports all the tables into an archive in the backup folder, and returns its file name.
+pub async fn create_archive(conn: &mut DbConn) -> Result<String, Error> {
+    let folder = PathBuf::from(CONFIG.backup_folder());
+    tokio::fs::create_dir_all(&folder).await?;
+
+    let data = export_data(conn).await?;
+    let file_name = format!("{FILE_PREFIX}{}{FILE_EXTENSION}", Utc::now().format("%Y%m%d_%H%M%S"));
+    tokio::fs::write(folder.join(&file_name), data).await?;
+    info!("Archive {file_name} created");
+    Ok(file_name)
+}
+
+/// Exports all the tables into the data of an archive.
+pub async fn export_data(conn: &mut DbConn) -> Result<Vec<u8>, Error> {
+    // The archive is compressed in memory, so the connection isn't held while writing to the disk
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    write_line(
+        &mut encoder,
+        &Line::Header {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            server_version: crate::VERSION.map(String::from),
+            created_at: Utc::now().to_rfc3339(),
+        },
+    )?;
+    let counts = export_tables(conn, |table, rows| {
+        write_line(
+            &mut encoder,
+            &Line::Rows {
+                table: table.to_string(),
+                rows,
+            },
+        )
+    })
+    .await?;
+    let counts = counts.into_iter().map(|(table, count)| (table.to_string(), count)).collect();
+    write_line(
+        &mut encoder,
+        &Line::Trailer {
+            counts,
+        },
+    )?;
+    Ok(encoder.finish()?)
+}
+
+/// Imports the archive into the database, which needs to be empty. Returns the number of imported rows.
+pub async fn import_archive(path: &Path, ignore_missing_files: bool, conn: &mut DbConn) -> Result<i64, Error> {
+    let data = tokio::fs::read(path).await?;
+    import_data(&data, ignore_missing_files, conn).await
+}
+
+/// Imports the data of an archive into the database, which needs to be empty. Returns the number of imported rows.
+pub async fn import_data(data: &[u8], ignore_missing_files: bool, conn: &mut DbConn) -> Result<i64, Error> {
+    ensure_empty(conn).await?;
+
+    let mut errors = check_archive(data).await?;
+    let missing_files = check_files(data)?;
+    if ignore_missing_files {
+        for file in &missing_files {
+            warn!("{file} is missing");
+        }
+    } else {
+        errors.extend(missing_files.into_iter().map(|file| format!("{file} is missing")));
+    }
+    if !errors.is_empty() {
+        for error in errors.iter().take(MAX_ERRORS) {
+            error!("{error}");
+        }
+        err!(format!("The archive can't be imported, {} errors were found", errors.len()))
+    }
+
+    let mut imported = 0;
+    for line in lines(data) {
+        if let Line::Rows {
+            table,
+            rows,
+        } = line?
+        {
+            imported += rows.len() as i64;
+            import_rows(Some(conn), &table, rows).await?;
+        }
+    }
+    Ok(imported)
+}
+
+fn write_line(writer: &mut impl Write, line: &Line) -> EmptyResult {
+    serde_json::to_writer(&mut *writer, line)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+fn lines(data: &[u8]) -> impl Iterator<Item = Result<Line, Error>> + '_ {
+    BufReader::new(GzDecoder::new(data)).lines().map(|line| Ok(serde_json::from_str(&line?)?))
+}
+
+// Checks the format, the rows and their foreign keys, and that the archive is complete. Returns the invalid rows.
+async fn check_archive(data: &[u8]) -> Result<Vec<String>, Error> {
+    let mut lines = lines(data);
+    match lines.next().transpose()? {
+        Some(Line::Header {
+            format,
+            version,
+            ..
+        }) if format == FORMAT => {
+            if version != VERSION {
+                err!(format!("The archive version {version} isn't supported, only the version {VERSION} is"))
+            }
+        }
+        _ => err!("The file isn't a Vaultwarden archive"),
+    }
+
+    let known_tables = table_names();
+    let mut keys: HashMap<&str, HashSet<String>> = HashMap::new();
+    let mut counts: HashMap<String, i64> = HashMap::new();
+    let mut errors = Vec::new();
+    for line in lines {
+        match line? {
+            Line::Header {
+                ..
+            } => err!("The archive has more than one header"),
+            Line::Rows {
+                table,
+                rows,
+            } => {
+                let Some(table) = known_tables.iter().copied().find(|t| *t == table) else {
+                    err!(format!("The archive has rows of the unknown table `{table}`"))
+                };
+                for row in &rows {
+                    for (_, column, referenced) in REFERENCES.iter().filter(|(t, _, _)| *t == table) {
+                        if let Some(Value::String(uuid)) = row.get(column) {
+                            if !keys.get(referenced).is_some_and(|uuids| uuids.contains(uuid)) {
+                                errors.push(format!("{table}.{column} references the missing {referenced} row {uuid}"));
+                            }
+                        }
+                    }
+                    if REFERENCES.iter().any(|(_, _, referenced)| *referenced == table) {
+                        if let Some(Value::String(uuid)) = row.get("uuid") {
+                            keys.entry(table).or_default().insert(uuid.clone());
+                        }
+                    }
+                }
+                *counts.entry(table.to_string()).or_default() += rows.len() as i64;
+                // Without a connection, the rows are only converted to their model
+                import_rows(None, table, rows).await?;
+            }
+            Line::Trailer {
+                counts: expected,
+            } => {
+                for (table, count) in expected {
+                    let found = counts.get(&table).copied().unwrap_or_default();
+                    if found != count {
+                        errors.push(format!("{table}: the archive should have {count} rows, but has {found}"));
+                    }
+                }
+                return Ok(errors);
+            }
+        }
+    }
+    err!("The archive is incomplete, its end is missing")
+}
+
+// Returns the files of the attachments and Sends which aren't in their folders
+fn check_files(data: &[u8]) -> Result<Vec<String>, Error> {
+    let mut missing = Vec::new();
+    for line in lines(data) {
+        let Line::Rows {
+            table,
+            rows,
+        } = line?
+        else {
+            continue;
+        };
+        for row in rows {
+            let path = match table.as_str() {
+                "attachments" => serde_json::from_value::<Attachment>(row)?.get_file_path(),
+                "sends" if !sends_in_s3() => {
+                    let send = serde_json::from_value::<Send>(row)?;
+                    match send.file_id() {
+                        Some(file_id) if send.atype == SendType::File as i32 => {
+                            format!("{}/{}/{file_id}", CONFIG.sends_folder(), send.uuid)
+                        }
+                        _ => continue,
+                    }
+                }
+                _ => break,
+            };
+            if !Path::new(&path).is_file() {
+                missing.push(path);
+            }
+        }
+    }
+    Ok(missing)
+}
+
+pub fn sends_in_s3() -> bool {
+    #[cfg(feature = "s3")]
+    return crate::s3::sends_enabled();
+    #[cfg(not(feature = "s3"))]
+    false
+}
@@ -26,6 +26,7 @@ static JWT_ADMIN_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|admin", CONFIG.
 static JWT_SEND_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|send", CONFIG.domain_origin()));
 static JWT_ORG_API_KEY_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|api.organization", CONFIG.domain_origin()));
 static JWT_FILE_DOWNLOAD_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|file_download", CONFIG.domain_origin()));
+static JWT_WEBAUTHN_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|webauthn", CONFIG.domain_origin()));
 
 static PRIVATE_RSA_KEY: OnceCell<EncodingKey> = OnceCell::new();
 static PUBLIC_RSA_KEY: OnceCell<DecodingKey> = OnceCell::new();
@@ -127,6 +128,10 @@ pub fn decode_file_download(token: &str) -> Result<FileDownloadClaims, Error> {
     decode_jwt(token, JWT_FILE_DOWNLOAD_ISSUER.to_string())
 }
 
+pub fn decode_webauthn_state(token: &str) -> Result<WebAuthnStateClaims, Error> {
+    decode_jwt(token, JWT_WEBAUTHN_ISSUER.to_string())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginJwtClaims {
     // Not before
@@ -294,6 +299,32 @@ pub fn generate_file_download_claims(uuid: String, file_id: String) -> FileDownl
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebAuthnStateClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Subject, the user uuid or empty when the user is not known yet
+    pub sub: String,
+
+    // The serialized registration or authentication state of the ceremony
+    pub state: String,
+}
+
+pub fn generate_webauthn_state_claims(user_uuid: String, state: String) -> WebAuthnStateClaims {
+    let time_now = Utc::now();
+    WebAuthnStateClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + TimeDelta::try_minutes(5).unwrap()).timestamp(),
+        iss: JWT_WEBAUTHN_ISSUER.to_string(),
+        sub: user_uuid,
+        state,
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BasicJwtClaims {
     // Not before
@@ -357,9 +388,11 @@ use rocket::{
 };
 
 use crate::db::{
+    cache, coalesce,
     models::{Collection, Device, User, UserOrgStatus, UserOrgType, UserOrganization, UserStampException},
     DbConn,
 };
+use crate::log_context::{Phase, PhaseTimer};
 
 pub struct Host {
     pub host: String,
@@ -408,6 +441,7 @@ pub struct ClientHeaders {
     pub host: String,
     pub device_type: i32,
     pub ip: ClientIp,
+    pub locale: Option<String>,
 }
 
 #[rocket::async_trait]
@@ -423,17 +457,20 @@ impl<'r> FromRequest<'r> for ClientHeaders {
         // When unknown or unable to parse, return 14, which is 'Unknown Browser'
         let device_type: i32 =
             request.headers().get_one("device-type").map(|d| d.parse().unwrap_or(14)).unwrap_or_else(|| 14);
+        let locale = request.headers().get_one("Accept-Language").and_then(crate::util::parse_accept_language);
 
         Outcome::Success(ClientHeaders {
             host,
             device_type,
             ip,
+            locale,
         })
     }
 }
 
 pub struct Headers {
     pub host: String,
+    // Can come from `cache::SESSIONS` and be a few seconds old, reload it before saving it
     pub device: Device,
     pub user: User,
     pub ip: ClientIp,
@@ -444,6 +481,7 @@ impl<'r> FromRequest<'r> for Headers {
     type Error = &'static str;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let timer = PhaseTimer::start(Phase::Guards);
         let headers = request.headers();
 
         let host = try_outcome!(Host::from_request(request).await).host;
@@ -475,15 +513,24 @@ impl<'r> FromRequest<'r> for Headers {
             _ => err_handler!("Error getting DB"),
         };
 
-        let device = match Device::find_by_uuid_and_user(&device_uuid, &user_uuid, &mut conn).await {
+        // The security stamp is still checked with the user, which is removed from its cache when it changes
+        let session_key = cache::session_key(&user_uuid, &device_uuid, &claims.sstamp);
+        let cached_device = cache::SESSIONS.get(&session_key);
+        let generation = cache::SESSIONS.generation();
+        let cached = cached_device.is_some();
+        let device = match cached_device {
             Some(device) => device,
-            None => err_handler!("Invalid device id"),
+            None => match Device::find_by_uuid_and_user(&device_uuid, &user_uuid, &mut conn).await {
+                Some(device) => device,
+                None => err_handler!("Invalid device id"),
+            },
         };
 
         let user = match User::find_by_uuid(&user_uuid, &mut conn).await {
             Some(user) => user,
             None => err_handler!("Device has no user associated"),
         };
+        crate::log_context::set_user(request, &user.uuid).await;
 
         if user.security_stamp != claims.sstamp {
             if let Some(stamp_exception) =
@@ -514,7 +561,11 @@ impl<'r> FromRequest<'r> for Headers {
             } else {
                 err_handler!("Invalid security stamp")
             }
+        } else if !cached {
+            cache::SESSIONS.insert(&session_key, device.clone(), generation);
         }
+        crate::route_stats::record_auth(cached, timer.elapsed());
+        coalesce::touch_device(&user.uuid, &device.uuid);
 
         Outcome::Success(Headers {
             host,
@@ -0,0 +1,180 @@
+//
+// Database backups
+//
+// SQLite databases are copied with `VACUUM INTO`, which makes a consistent snapshot while the server keeps running.
+// PostgreSQL and MySQL/MariaDB databases are dumped with `pg_dump` and `mysqldump`, which need to be installed.
+// The snapshots are written to `BACKUP_FOLDER`, optionally uploaded to the S3 bucket, and the oldest ones are
+// deleted past `BACKUP_RETAIN`.
+//
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use chrono::Utc;
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use crate::{
+    db::{backup_database, run_blocking, DbConn, DbConnType, DbPool},
+    error::{Error, MapResult},
+    CONFIG,
+};
+
+const FILE_PREFIX: &str = "db_";
+const FILE_EXTENSIONS: [&str; 3] = [".sqlite3", ".pgdump", ".sql"];
+
+/// Creates a snapshot of the database, and returns its file name.
+pub async fn create_backup(conn: &mut DbConn) -> Result<String, Error> {
+    let folder = PathBuf::from(CONFIG.backup_folder());
+    tokio::fs::create_dir_all(&folder).await?;
+
+    let url = CONFIG.database_url();
+    let file_date = Utc::now().format("%Y%m%d_%H%M%S");
+    let file_name = match DbConnType::from_url(&url)? {
+        DbConnType::sqlite => {
+            let file_name = format!("{FILE_PREFIX}{file_date}.sqlite3");
+            backup_database(conn, &folder.join(&file_name)).await?;
+            file_name
+        }
+        DbConnType::postgresql => {
+            let file_name = format!("{FILE_PREFIX}{file_date}.pgdump");
+            run_dump(pg_dump_command(&url, &folder.join(&file_name))?, &folder.join(&file_name)).await?;
+            file_name
+        }
+        DbConnType::mysql => {
+            let file_name = format!("{FILE_PREFIX}{file_date}.sql");
+            run_dump(mysqldump_command(&url, &folder.join(&file_name))?, &folder.join(&file_name)).await?;
+            file_name
+        }
+    };
+
+    #[cfg(feature = "s3")]
+    if let Some(prefix) = CONFIG.backup_s3_prefix() {
+        crate::s3::upload_file(&s3_key(&prefix, &file_name), &folder.join(&file_name)).await?;
+    }
+
+    prune_backups(&folder).await;
+    info!("Database backup {file_name} created");
+    Ok(file_name)
+}
+
+pub async fn backup_job(pool: DbPool) {
+    debug!("Start database backup job");
+    let Ok(mut conn) = pool.get().await else {
+        error!("Failed to get DB connection while creating a database backup");
+        return;
+    };
+    if let Err(e) = create_backup(&mut conn).await {
+        error!("Error creating the database backup: {e:?}");
+    }
+}
+
+// The password is given in the environment, the command line can be seen by the other users of the system
+fn url_password(url: &Url) -> Option<String> {
+    url.password().map(|p| percent_decode_str(p).decode_utf8_lossy().into_owned())
+}
+
+fn pg_dump_command(database_url: &str, path: &Path) -> Result<Command, Error> {
+    let mut url = Url::parse(database_url).map_res("Invalid `DATABASE_URL`")?;
+    let password = url_password(&url);
+    let _ = url.set_password(None);
+
+    let mut command = Command::new(CONFIG.backup_pg_dump_command());
+    if let Some(password) = password {
+        command.env("PGPASSWORD", password);
+    }
+    command.arg("--format=custom").arg(format!("--file={}", path.display())).arg(format!("--dbname={url}"));
+    Ok(command)
+}
+
+fn mysqldump_command(database_url: &str, path: &Path) -> Result<Command, Error> {
+    let url = Url::parse(database_url).map_res("Invalid `DATABASE_URL`")?;
+    let Some(host) = url.host_str() else {
+        err!("`DATABASE_URL` has no host")
+    };
+    let database = url.path().trim_start_matches('/');
+    if database.is_empty() {
+        err!("`DATABASE_URL` has no database name")
+    }
+
+    let mut command = Command::new(CONFIG.backup_mysqldump_command());
+    if let Some(password) = url_password(&url) {
+        command.env("MYSQL_PWD", password);
+    }
+    command
+        .arg("--single-transaction")
+        .arg("--routines")
+        .arg(format!("--host={host}"))
+        .arg(format!("--port={}", url.port().unwrap_or(3306)))
+        .arg(format!("--user={}", percent_decode_str(url.username()).decode_utf8_lossy()))
+        .arg(format!("--result-file={}", path.display()))
+        .arg(percent_decode_str(database).decode_utf8_lossy().as_ref());
+    Ok(command)
+}
+
+async fn run_dump(mut command: Command, path: &Path) -> Result<(), Error> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = match run_blocking(move || command.output()).await {
+        Ok(output) => output,
+        Err(e) => err!(format!("Error running {program}: {e}")),
+    };
+
+    if !output.status.success() {
+        // Don't leave a partial dump around, it would look like a valid backup
+        tokio::fs::remove_file(path).await.ok();
+        err!(format!("{program} failed ({}): {}", output.status, String::from_utf8_lossy(&output.stderr).trim()))
+    }
+    Ok(())
+}
+
+#[cfg(feature = "s3")]
+fn s3_key(prefix: &str, file_name: &str) -> String {
+    format!("{}/{file_name}", prefix.trim_matches('/'))
+}
+
+fn is_backup_file(file_name: &str) -> bool {
+    file_name.starts_with(FILE_PREFIX) && FILE_EXTENSIONS.iter().any(|ext| file_name.ends_with(ext))
+}
+
+// Deletes the oldest snapshots past `BACKUP_RETAIN`, also from the S3 bucket
+async fn prune_backups(folder: &Path) {
+    let retain = CONFIG.backup_retain() as usize;
+    if retain == 0 {
+        return;
+    }
+
+    let mut file_names = Vec::new();
+    match tokio::fs::read_dir(folder).await {
+        Ok(mut entries) => {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let file_name = entry.file_name().to_string_lossy().into_owned();
+                if is_backup_file(&file_name) {
+                    file_names.push(file_name);
+                }
+            }
+        }
+        Err(e) => {
+            error!("Error listing the database backups: {e}");
+            return;
+        }
+    }
+
+    // The names start with the date, so they sort from the oldest to the newest
+    file_names.sort();
+    let excess = file_names.len().saturating_sub(retain);
+    for file_name in &file_names[..excess] {
+        if let Err(e) = tokio::fs::remove_file(folder.join(file_name)).await {
+            error!("Error deleting the database backup {file_name}: {e}");
+            continue;
+        }
+
+        #[cfg(feature = "s3")]
+        if let Some(prefix) = CONFIG.backup_s3_prefix() {
+            if let Err(e) = crate::s3::delete_object(&s3_key(&prefix, file_name)).await {
+                error!("Error deleting the database backup {file_name} from the S3 bucket: {e:?}");
+            }
+        }
+        debug!("Deleted the database backup {file_name}");
+    }
+}
@@ -0,0 +1,299 @@
+//
+// Compression of the responses, with Brotli or gzip
+//
+// The text responses, like the JSON of the API and the HTML, JavaScript and CSS of the web vault and the admin panel,
+// are compressed when the client accepts it. The bodies with an unknown size, like the streams, the partial responses
+// and the responses which are already encoded are sent like they are. The compressed responses get a weak ETag, since
+// they're only equivalent to the uncompressed ones.
+//
+// The large responses, like the sync of the large vaults, can be compressed while they're generated with `JsonEncoder`,
+// instead of being built at once and compressed by the fairing.
+//
+use std::io::{Cursor, Write};
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{ContentType, Status},
+    request::{self, FromRequest},
+    response::{self, Responder},
+    Request, Response,
+};
+
+use crate::CONFIG;
+
+// Smaller bodies don't get noticeably smaller, and fit in a packet anyway
+const MIN_SIZE: usize = 1024;
+// A fast level, the responses are compressed on each request
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn compress(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                writer.write_all(body)?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+// The encoding accepted by the client, Brotli is preferred when both have the same weight
+fn preferred_encoding(accept_encoding: &str) -> Option<Encoding> {
+    let mut preferred: Option<(Encoding, f32)> = None;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let encoding = match parts.next().map(|name| name.trim().to_ascii_lowercase()).as_deref() {
+            Some("br") => Encoding::Brotli,
+            Some("gzip") | Some("x-gzip") => Encoding::Gzip,
+            _ => continue,
+        };
+        let weight = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(Some(1.0), |weight| weight.trim().parse::<f32>().ok())
+            .unwrap_or(0.0);
+        if weight <= 0.0 {
+            continue;
+        }
+        match preferred {
+            Some((preferred_encoding, preferred_weight))
+                if preferred_weight > weight
+                    || (preferred_weight == weight && preferred_encoding == Encoding::Brotli) => {}
+            _ => preferred = Some((encoding, weight)),
+        }
+    }
+    preferred.map(|(encoding, _)| encoding)
+}
+
+/// The encoding accepted by the client, if the responses are compressed.
+pub struct AcceptEncoding(Option<Encoding>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AcceptEncoding {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let encoding = if CONFIG.response_compression() {
+            request.headers().get("Accept-Encoding").find_map(preferred_encoding)
+        } else {
+            None
+        };
+        request::Outcome::Success(Self(encoding))
+    }
+}
+
+enum EncoderWriter {
+    Plain(Vec<u8>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+}
+
+/// Writes a JSON body, compressed with the encoding accepted by the client.
+pub struct JsonEncoder {
+    writer: EncoderWriter,
+    encoding: Option<Encoding>,
+}
+
+impl JsonEncoder {
+    pub fn new(accept: &AcceptEncoding) -> Self {
+        let writer = match accept.0 {
+            Some(Encoding::Brotli) => EncoderWriter::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+            Some(Encoding::Gzip) => {
+                EncoderWriter::Gzip(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()))
+            }
+            None => EncoderWriter::Plain(Vec::new()),
+        };
+        Self {
+            writer,
+            encoding: accept.0,
+        }
+    }
+
+    pub fn finish(self) -> std::io::Result<EncodedJson> {
+        let body = match self.writer {
+            EncoderWriter::Plain(body) => body,
+            EncoderWriter::Brotli(writer) => (*writer).into_inner(),
+            EncoderWriter::Gzip(encoder) => encoder.finish()?,
+        };
+        Ok(EncodedJson {
+            body,
+            encoding: self.encoding,
+        })
+    }
+}
+
+impl Write for JsonEncoder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.writer {
+            EncoderWriter::Plain(body) => body.write(buf),
+            EncoderWriter::Brotli(writer) => writer.write(buf),
+            EncoderWriter::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.writer {
+            EncoderWriter::Plain(body) => body.flush(),
+            EncoderWriter::Brotli(writer) => writer.flush(),
+            EncoderWriter::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// A JSON body written by `JsonEncoder`, the fairing leaves it as is.
+pub struct EncodedJson {
+    body: Vec<u8>,
+    encoding: Option<Encoding>,
+}
+
+impl<'r> Responder<'r, 'static> for EncodedJson {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.header(ContentType::JSON);
+        if let Some(encoding) = self.encoding {
+            response.raw_header("Content-Encoding", encoding.name()).raw_header_adjoin("Vary", "Accept-Encoding");
+        }
+        response.sized_body(self.body.len(), Cursor::new(self.body)).ok()
+    }
+}
+
+fn is_compressible(content_type: &ContentType) -> bool {
+    let (top, sub) = (content_type.top().as_str(), content_type.sub().as_str());
+    top == "text"
+        || (top == "application"
+            && (sub == "javascript"
+                || sub == "json"
+                || sub.ends_with("+json")
+                || sub == "xml"
+                || sub.ends_with("+xml")
+                || sub == "wasm"))
+        || (top == "image" && sub == "svg+xml")
+}
+
+pub struct Compression;
+
+#[rocket::async_trait]
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response Compression",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if res.status() != Status::Ok
+            || res.headers().contains("Content-Encoding")
+            || req.headers().contains("Range")
+            || !res.content_type().is_some_and(|content_type| is_compressible(&content_type))
+        {
+            return;
+        }
+        let Some(encoding) = req.headers().get("Accept-Encoding").find_map(preferred_encoding) else {
+            return;
+        };
+        // The streamed bodies are never read, they could be endless
+        if !res.body().preset_size().is_some_and(|size| size >= MIN_SIZE) {
+            return;
+        }
+
+        let body = match res.body_mut().to_bytes().await {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Unable to read the response body of {} to compress it: {e}", req.uri().path());
+                res.set_status(Status::InternalServerError);
+                res.set_sized_body(0, Cursor::new(Vec::new()));
+                return;
+            }
+        };
+        let Ok((body, compressed)) = tokio::task::spawn_blocking(move || {
+            let compressed = encoding.compress(&body);
+            (body, compressed)
+        })
+        .await
+        else {
+            error!("Compressing the response of {} panicked", req.uri().path());
+            res.set_status(Status::InternalServerError);
+            res.set_sized_body(0, Cursor::new(Vec::new()));
+            return;
+        };
+
+        match compressed {
+            Ok(compressed) if compressed.len() < body.len() => {
+                if let Some(etag) = res.headers().get_one("ETag").filter(|etag| !etag.starts_with("W/")) {
+                    let weak_etag = format!("W/{etag}");
+                    res.set_raw_header("ETag", weak_etag);
+                }
+                res.set_raw_header("Content-Encoding", encoding.name());
+                res.adjoin_raw_header("Vary", "Accept-Encoding");
+                res.set_sized_body(compressed.len(), Cursor::new(compressed));
+            }
+            Ok(_) => res.set_sized_body(body.len(), Cursor::new(body)),
+            Err(e) => {
+                warn!("Unable to compress the response of {}: {e}", req.uri().path());
+                res.set_sized_body(body.len(), Cursor::new(body));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preferred_encoding() {
+        assert_eq!(preferred_encoding("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(preferred_encoding("gzip, deflate"), Some(Encoding::Gzip));
+        assert_eq!(preferred_encoding("br;q=0.5, gzip"), Some(Encoding::Gzip));
+        assert_eq!(preferred_encoding("br;q=0, gzip;q=0"), None);
+        assert_eq!(preferred_encoding("identity"), None);
+        assert_eq!(preferred_encoding(""), None);
+    }
+
+    #[test]
+    fn test_json_encoder() {
+        use std::io::Read;
+
+        let json = br#"{"Ciphers":[{"Name":"a"},{"Name":"b"}]}"#.repeat(100);
+        let encode = |encoding| {
+            let mut encoder = JsonEncoder::new(&AcceptEncoding(encoding));
+            encoder.write_all(&json).unwrap();
+            encoder.finish().unwrap().body
+        };
+
+        assert_eq!(encode(None), json);
+
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(encode(Some(Encoding::Gzip)).as_slice()).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, json);
+
+        let mut decoded = Vec::new();
+        brotli::Decompressor::new(encode(Some(Encoding::Brotli)).as_slice(), 4096).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, json);
+    }
+}
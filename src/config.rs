@@ -9,6 +9,7 @@ use reqwest::Url;
 use crate::{
     db::DbConnType,
     error::Error,
+    http_client::ClientOptions,
     util::{get_env, get_env_bool, parse_experimental_client_feature_flags},
 };
 
@@ -38,7 +39,7 @@ macro_rules! make_config {
         pub struct Config { inner: RwLock<Inner> }
 
         struct Inner {
-            rocket_shutdown_handle: Option<rocket::Shutdown>,
+            rocket_shutdown_handles: Vec<rocket::Shutdown>,
 
             templates: Handlebars<'static>,
             config: ConfigItems,
@@ -228,11 +229,19 @@ macro_rules! make_config {
                 const PRIVACY_CONFIG: &[&str] = &[
                     "allowed_iframe_ancestors",
                     "database_url",
+                    "database_replica_url",
                     "domain_origin",
                     "domain_path",
                     "domain",
+                    "emergency_access_notification_url",
                     "helo_name",
+                    "icon_cache_redis_url",
+                    "mail_api_domain",
+                    "mail_api_key_id",
                     "org_creation_users",
+                    "s3_access_key_id",
+                    "s3_bucket",
+                    "s3_endpoint",
                     "signups_domains_whitelist",
                     "smtp_from",
                     "smtp_host",
@@ -371,6 +380,26 @@ make_config! {
     ws {
         /// Enable websocket notifications
         enable_websocket:       bool,   false,  def,    true;
+        /// Client queue size |> Number of notifications which can be waiting for a client. A client whose queue is full is disconnected, it syncs again when reconnecting
+        websocket_queue_size:   usize,  false,  def,    64;
+        /// Max connections per user |> The oldest connections of a user are closed past this number, 0 means unlimited
+        websocket_max_connections_per_user: usize, false, def, 20;
+        /// Max connections per IP |> The WebSocket and event stream connections from an IP are refused past this number, 0 means unlimited
+        websocket_max_connections_per_ip: usize, false, def, 100;
+        /// Idle timeout (seconds) |> The WebSocket connections which didn't send anything, not even a reply to the pings sent every 15 seconds, are closed after this time. Set to 0 to disable
+        websocket_idle_timeout: u64,    false,  def,    60;
+        /// Enable server-sent events |> An event stream carrying the same notifications as the WebSockets, for the clients behind proxies which don't allow WebSockets
+        enable_sse:             bool,   false,  def,    false;
+        /// Disable query string tokens |> Refuse the access tokens given in the query string of the WebSocket requests, which can end up in the logs of the proxies. The clients have to send them in the Authorization header or in the handshake message
+        websocket_disable_query_token: bool, false, def, false;
+        /// Notifications coalescing window (ms) |> The cipher updates of a user following each other within this time, like during a bulk operation, are sent as a single full sync. Set to 0 to disable
+        notifications_coalesce_window_ms: u64, true, def,  1_000;
+        /// Notifications history size |> Number of the last notifications kept per user in the database, the clients can fetch the ones they missed while disconnected from /notifications/missed. Set to 0 to disable
+        notifications_history_size: u32, true, def,    0;
+        /// Pub/sub URL |> Redis (redis://[user:password@]host:port) or NATS (nats://[user:password@]host:port) server relaying the notifications and the database cache changes between several instances
+        websocket_pubsub_url:   String, false,  option;
+        /// Pub/sub channel |> The Redis channel or NATS subject used to relay the notifications
+        websocket_pubsub_channel: String, false, def,  "vaultwarden-notifications".to_string();
     },
     push {
         /// Enable push notifications
@@ -383,6 +412,22 @@ make_config! {
         push_installation_id:   Pass,   false,  def,    String::new();
         /// Installation key |> The installation key from https://bitwarden.com/host
         push_installation_key:  Pass,   false,  def,    String::new();
+        /// Push mode |> `relay` to send the notifications through the Bitwarden push relay, `direct` to send them with your own FCM/APNs credentials and to UnifiedPush endpoints
+        push_mode:              String, false,  def,    "relay".to_string();
+        /// FCM credentials |> Path to the JSON key of a Firebase service account, to send the notifications to the Android apps in direct mode
+        push_fcm_credentials:   String, false,  option;
+        /// APNs key |> Path to the .p8 authentication key, to send the notifications to the iOS apps in direct mode
+        push_apns_key:          String, false,  option;
+        /// APNs key id
+        push_apns_key_id:       String, false,  option;
+        /// APNs team id
+        push_apns_team_id:      String, false,  option;
+        /// APNs topic |> The bundle id of the iOS app
+        push_apns_topic:        String, false,  option;
+        /// Use the APNs sandbox |> For development builds of the iOS app
+        push_apns_sandbox:      bool,   false,  def,    false;
+        /// Max retries |> How many times a notification is retried when the push service is unavailable, with an exponential backoff
+        push_max_retries:       u32,    false,  def,    3;
     },
     jobs {
         /// Job scheduler poll interval |> How often the job scheduler thread checks for jobs to run.
@@ -397,18 +442,45 @@ make_config! {
         /// Incomplete 2FA login schedule |> Cron schedule of the job that checks for incomplete 2FA logins.
         /// Defaults to once every minute. Set blank to disable this job.
         incomplete_2fa_schedule: String, false,  def,   "30 * * * * *".to_string();
-        /// Emergency notification reminder schedule |> Cron schedule of the job that sends expiration reminders to emergency access grantors.
+        /// Emergency notification reminder schedule |> Cron schedule of the job that sends expiration reminders to emergency access grantors,
+        /// and notifies the escalation contacts when the grantor does not respond in time.
         /// Defaults to hourly. (3 minutes after the hour) Set blank to disable this job.
         emergency_notification_reminder_schedule:   String, false,  def,    "0 3 * * * *".to_string();
         /// Emergency request timeout schedule |> Cron schedule of the job that grants emergency access requests that have met the required wait time.
         /// Defaults to hourly. (7 minutes after the hour) Set blank to disable this job.
         emergency_request_timeout_schedule:   String, false,  def,    "0 7 * * * *".to_string();
-        /// Event cleanup schedule |> Cron schedule of the job that cleans old events from the event table.
+        /// Emergency access validation schedule |> Cron schedule of the job that checks if the stored keys of confirmed emergency access grants can still be used.
+        /// Defaults to daily. (20 minutes after 4 AM) Set blank to disable this job.
+        emergency_access_validation_schedule:   String, false,  def,    "0 20 4 * * *".to_string();
+        /// DEPRECATED event cleanup schedule |> DEPRECATED - Please use DATA_RETENTION_SCHEDULE
+        event_cleanup_schedule:   String, false,  option;
+        /// Data retention schedule |> Cron schedule of the job that deletes the data past the windows of the data retention settings.
         /// Defaults to daily. Set blank to disable this job.
-        event_cleanup_schedule:   String, false,  def,    "0 10 0 * * *".to_string();
+        data_retention_schedule:  String, false,  auto,   |c| c.event_cleanup_schedule.clone().unwrap_or_else(|| "0 10 0 * * *".to_string());
         /// Auth Request cleanup schedule |> Cron schedule of the job that cleans old auth requests from the auth request.
         /// Defaults to every minute. Set blank to disable this job.
         auth_request_purge_schedule:   String, false,  def,    "30 * * * * *".to_string();
+        /// Duo Auth context cleanup schedule |> Cron schedule of the job that cleans expired Duo contexts from the database. Does nothing if Duo MFA is disabled or set to use the legacy iframe prompt.
+        /// Defaults to once every minute. Set blank to disable this job.
+        duo_context_purge_schedule:   String, false,  def,    "30 * * * * *".to_string();
+        /// Delayed 2FA recovery schedule |> Cron schedule of the job that removes the two-step login methods of confirmed delayed recoveries past their cooling-off period.
+        /// Does nothing if the delayed 2FA recovery is disabled. Defaults to every 5 minutes. Set blank to disable this job.
+        two_factor_recovery_schedule:   String, false,  def,    "0 */5 * * * *".to_string();
+        /// 2FA policy grace period schedule |> Cron schedule of the job that reminds the organization members in their 2FA policy grace period
+        /// and revokes the ones who haven't enabled two-step login when it has passed. Defaults to hourly (25 minutes after the hour). Set blank to disable this job.
+        two_factor_policy_grace_schedule: String, false, def,   "0 25 * * * *".to_string();
+        /// Directory sync schedule |> Cron schedule of the job that syncs the organization members and groups with the directory.
+        /// Does nothing if the directory sync isn't configured. Defaults to every 30 minutes. Set blank to disable this job.
+        directory_sync_schedule: String, false, def,    "0 */30 * * * *".to_string();
+        /// Database backup schedule |> Cron schedule of the job that creates a snapshot of the database in the backup folder.
+        /// Disabled by default, set a schedule to enable this job.
+        backup_schedule:        String, false,  def,    String::new();
+        /// Database maintenance schedule |> Cron schedule of the job that vacuums the database and updates its statistics, only within the maintenance window when one is set.
+        /// Defaults to weekly (40 minutes after 3 AM on Sundays). Set blank to disable this job.
+        db_maintenance_schedule: String, false, def,    "0 40 3 * * Sun".to_string();
+        /// Icon pre-warm schedule |> Cron schedule of the job that downloads the icons of the pending domains, queued from the admin panel.
+        /// Defaults to every 5 minutes between 1 and 6 AM, to download them off-hours. Set blank to disable this job.
+        icon_prewarm_schedule:  String, false,  def,    "0 */5 1-5 * * *".to_string();
 
     },
 
@@ -439,6 +511,12 @@ make_config! {
         org_attachment_limit:   i64,    true,   option;
         /// Per-user send storage limit (KB) |> Max kilobytes of sends storage allowed per user. When this limit is reached, the user will not be allowed to upload further sends.
         user_send_limit:   i64,    true,   option;
+        /// Max Send file size (KB) |> Max kilobytes a single Send file is allowed to have. Uploads above this size, plus 5% for the encryption overhead, are rejected with a 413.
+        /// The default matches the maximum file size supported by the Bitwarden clients. Changing this requires a restart.
+        send_file_size_limit:   i64,    false,  def,    537_600;
+        /// Max attachment file size (KB) |> Max kilobytes a single attachment is allowed to have. Uploads above this size, plus 5% for the encryption overhead, are rejected with a 413.
+        /// The default matches the maximum file size supported by the Bitwarden clients. Changing this requires a restart.
+        attachment_file_size_limit: i64, false, def,    537_600;
 
         /// Trash auto-delete days |> Number of days to wait before auto-deleting a trashed item.
         /// If unset, trashed items are not auto-deleted. This setting applies globally, so make
@@ -452,6 +530,19 @@ make_config! {
         /// This setting applies globally to all users.
         incomplete_2fa_time_limit: i64, true,   def,    3;
 
+        /// Delayed 2FA recovery (hours) |> Allows users who lost their recovery code to remove their two-step login methods
+        /// with their master password and a confirmation code sent by email. The removal only happens after this number of hours,
+        /// and any successful two-step login in the meantime cancels it. Requires email to be enabled. Set to 0 to disable this feature.
+        two_factor_recovery_delay_hours: i64, true, def,    0;
+
+        /// 2FA policy grace period (days) |> Number of days the members of an organization have to enable two-step login when the 2FA policy is enabled,
+        /// before being revoked from it. They are reminded by email every day. Set to 0 to revoke them immediately. At most 365.
+        two_factor_policy_grace_days: i64, true, def,   0;
+
+        /// 2FA data encryption secret |> Deprecated, use DATABASE_ENCRYPTION_KEYS. Still needed to read the data it encrypted,
+        /// and used as the `2fa` key when DATABASE_ENCRYPTION_KEYS isn't set. Keep it safe, since losing it makes the stored two-step login methods unusable.
+        two_factor_data_secret: Pass,   false,  option;
+
         /// Disable icon downloads |> Set to true to disable icon downloading in the internal icon service.
         /// This still serves existing icons from $ICON_CACHE_FOLDER, without generating any external
         /// network requests. $ICON_CACHE_TTL must also be set to 0; otherwise, the existing icons
@@ -469,6 +560,11 @@ make_config! {
         signups_domains_whitelist: String, true, def,   String::new();
         /// Enable event logging |> Enables event logging for organizations.
         org_events_enabled:     bool,   false,  def,    false;
+        /// Enable personal event logging |> Stores the security events of each account, like the logins, the failed logins, the 2FA changes, the exports and the key rotations, even when the user isn't a member of an organization.
+        /// The users can see their own history, the admin panel shows the totals.
+        user_events_enabled:    bool,   true,   def,    false;
+        /// Anonymize the IPs of the personal events |> Only stores the network of the IP addresses of the personal events, the /24 for IPv4 and the /48 for IPv6
+        user_events_anonymize_ip: bool, true,   def,    true;
         /// Org creation users |> Allow org creation only by this list of comma-separated user emails.
         /// Blank or 'all' means all users can create orgs; 'none' means no users can create orgs.
         org_creation_users:     String, true,   def,    String::new();
@@ -479,6 +575,11 @@ make_config! {
         invitation_expiration_hours: u32, false, def, 120;
         /// Enable emergency access |> Controls whether users can enable emergency access to their accounts. This setting applies globally to all users.
         emergency_access_allowed:    bool,   true,   def,    true;
+        /// Emergency access notification URL |> When set, emergency access recovery requests, reminders, escalations and timeouts are also sent as a JSON POST to this URL,
+        /// which can be used to forward them to a webhook or an SMS gateway in addition to the emails
+        emergency_access_notification_url:   String, true,   option;
+        /// Emergency access notification token |> Sent as a Bearer token in the Authorization header of the emergency access notifications
+        emergency_access_notification_token: Pass,   true,   option;
         /// Allow email change |> Controls whether users can change their email. This setting applies globally to all users.
         email_change_allowed:    bool,   true,   def,    true;
         /// Password iterations |> Number of server-side passwords hashing iterations for the password hash.
@@ -496,9 +597,6 @@ make_config! {
 
         /// Invitation organization name |> Name shown in the invitation emails that don't come from a specific organization
         invitation_org_name:    String, true,   def,    "Vaultwarden".to_string();
-
-        /// Events days retain |> Number of days to retain events stored in the database. If unset, events are kept indefinitely.
-        events_days_retain:     i64,    false,   option;
     },
 
     /// Advanced settings
@@ -508,6 +606,10 @@ make_config! {
         ip_header:              String, true,   def,    "X-Real-IP".to_string();
         /// Internal IP header property, used to avoid recomputing each time
         _ip_header_enabled:     bool,   false,  gen,    |c| &c.ip_header.trim().to_lowercase() != "none";
+        /// Admin listen address |> Serve the admin page only on this separate `ip:port`, like `127.0.0.1:8081`, instead of the main listener of ROCKET_ADDRESS and ROCKET_PORT, so it can be firewalled on its own
+        admin_listen_address:   String, false,  option;
+        /// Compress the responses |> Compress the JSON of the API and the files of the web vault with Brotli or gzip, when the client accepts it. Disable it when a reverse proxy already compresses them
+        response_compression:   bool,   false,  def,    true;
         /// Icon service |> The predefined icon services are: internal, bitwarden, duckduckgo, google.
         /// To specify a custom icon service, set a URL template with exactly one instance of `{}`,
         /// which is replaced with the domain. For example: `https://icon.example.com/domain/{}`.
@@ -525,26 +627,74 @@ make_config! {
         /// has been decided on, consider using permanent redirects for cacheability. The legacy codes
         /// are currently better supported by the Bitwarden clients.
         icon_redirect_code:     u32,    true,   def,    302;
+        /// Proxy the icon service |> Download the icons from the external icon service instead of redirecting the clients to it. The responses need to be images within the maximum size, otherwise the fallback services are tried
+        icon_service_proxy:     bool,   false,  def,    false;
+        /// Icon service fallbacks |> Comma separated icon services tried in order when the icon service has no valid icon, only with the proxy. They are predefined services or URL templates, like the icon service
+        icon_service_fallbacks: String, true,   option;
+        /// Icon service max size |> The largest icon accepted from the external icon services with the proxy, in KB
+        icon_service_max_size:  u64,    true,   def,    512;
+        /// Icon user equivalent domains |> Use the equivalent domains defined by the users too, not only the global ones, to serve the cached icon of an equivalent domain. Any user can then change the icon served for the domains of their groups
+        icon_user_equivalent_domains: bool, true, def,  false;
         /// Positive icon cache expiry |> Number of seconds to consider that an already cached icon is fresh. After this period, the icon will be refreshed
         icon_cache_ttl:         u64,    true,   def,    2_592_000;
         /// Negative icon cache expiry |> Number of seconds before trying to download an icon that failed again.
         icon_cache_negttl:      u64,    true,   def,    259_200;
+        /// Icon retry budget |> Number of consecutive times a site can't be reached before its circuit breaker opens. Until then, it's tried again with a backoff
+        icon_retry_budget:      u32,    true,   def,    3;
+        /// Icon circuit breaker expiry |> Number of seconds before trying to download an icon again from a site whose circuit breaker is open
+        icon_circuit_breaker_ttl: u64,  true,   def,    604_800;
+        /// Icon size |> The largest width and height, in pixels, of the PNG and ICO icons, which are scaled down and converted to PNG. 0 keeps them as they are
+        icon_size:              u32,    true,   def,    32;
+        /// Icon pre-warm batch size |> Number of icons downloaded at each run of the pre-warm job
+        icon_prewarm_batch_size: u32,   true,   def,    20;
+        /// Icon cache backend |> ("disk", "redis", "s3") Where the downloaded icons are cached. With Redis or S3, the instances share the cache and the containers don't need a volume for it
+        icon_cache_backend:     String, true,   def,    "disk".to_string();
+        /// Icon cache Redis URL |> The redis:// URL of the icon cache, TLS isn't supported
+        icon_cache_redis_url:   String, true,   option;
+        /// Icon cache S3 prefix |> The prefix of the icons in the S3 bucket (see the S3 storage settings)
+        icon_cache_s3_prefix:   String, true,   def,    "icons".to_string();
         /// Icon download timeout |> Number of seconds when to stop attempting to download an icon.
         icon_download_timeout:  u64,    true,   def,    10;
+        /// Icon max concurrent downloads |> Number of icon downloads running at the same time, the other ones wait until the download timeout
+        icon_max_concurrent_downloads: u32, false, def,  20;
+        /// Icon HTTP proxy |> The http://, https:// or socks5:// proxy of the icon downloads
+        icon_http_proxy:        String, false,  option;
+        /// Icon minimum TLS version |> ("1.0", "1.1", "1.2") The minimum TLS version of the icon downloads
+        icon_tls_min_version:   String, false,  option;
+        /// Icon CA bundle |> The path of a PEM file with the CAs trusted by the icon downloads, in addition to the system ones
+        icon_ca_bundle:         String, false,  option;
         /// Icon blacklist Regex |> Any domains or IPs that match this regex won't be fetched by the icon service.
         /// Useful to hide other servers in the local network. Check the WIKI for more details
         icon_blacklist_regex:   String, true,   option;
         /// Icon blacklist non global IPs |> Any IP which is not defined as a global IP will be blacklisted.
         /// Useful to secure your internal environment: See https://en.wikipedia.org/wiki/Reserved_IP_addresses for a list of IPs which it will block
         icon_blacklist_non_global_ips:  bool,   true,   def,    true;
+        /// Icon allowed IP ranges |> Comma separated IP ranges which are fetched by the icon service even if they aren't global, like a server of the internal network (e.g. 10.1.2.0/24)
+        icon_allowed_ip_ranges: String, true,   option;
+        /// Icon denied IP ranges |> Comma separated IP ranges which are never fetched by the icon service, even if they are global
+        icon_denied_ip_ranges:  String, true,   option;
 
         /// Disable Two-Factor remember |> Enabling this would force the users to use a second factor to login every time.
         /// Note that the checkbox would still be present, but ignored.
         disable_2fa_remember:   bool,   true,   def,    false;
 
+        /// Two-Factor remember max days |> Number of days after which a remembered two-step login expires, and the second factor is required again.
+        /// Organizations can set a shorter duration for their members with the 2FA policy. Set to 0 to not limit it.
+        two_factor_remember_max_days: i64, true, def,   0;
+
+        /// Require 2FA for API key logins |> Users with two-step login enabled also need to provide a code of their authenticator app
+        /// or FIDO2 WebAuthn key to login with their personal API key. Organizations can require this for their members with the 2FA policy.
+        api_key_login_require_2fa: bool, true, def,     false;
+
         /// Disable authenticator time drifted codes to be valid |> Enabling this only allows the current TOTP code to be valid
         /// TOTP codes of the previous and next 30 seconds will be invalid.
         authenticator_disable_time_drift: bool, true, def, false;
+        /// Authenticator time drift steps |> Number of 30 second time steps before and after the current one for which TOTP codes are also valid.
+        /// Ignored when the time drift is disabled.
+        authenticator_time_drift_steps: i64, true, def, 1;
+        /// Authenticator reuse window |> Number of time steps following the last used TOTP code for which codes are rejected as well.
+        /// The last used code and the ones before it are always rejected, a larger window prevents relaying a freshly generated code right after a login.
+        authenticator_reuse_window: i64, true, def,   0;
 
         /// Customize the enabled feature flags on the clients |> This is a comma separated list of feature flags to enable.
         experimental_client_feature_flags: String, false, def, "fido2-vault-credentials".to_string();
@@ -566,6 +716,17 @@ make_config! {
         log_file:               String, false,  option;
         /// Log level
         log_level:              String, false,  def,    "Info".to_string();
+        /// Log format |> `text` for the usual lines, or `json` for JSON lines with the timestamp, the level, the target,
+        /// the message, and the request ID, the hash of the user ID, the IP and the route of the request
+        log_format:             String, false,  def,    "text".to_string();
+        /// Route error budget |> The 5xx errors allowed per million requests of a route, 1000 for 0.1% or an availability of 99.9%.
+        /// The routes statistics of the admin diagnostics show how much of it each route used in the last 15 minutes
+        route_stats_error_budget: u32,  true,   def,    1000;
+        /// Slow query threshold (ms) |> The database queries which take longer are logged as warnings, with where they're run from. 0 disables it
+        slow_query_threshold_ms: u64,   true,   def,    0;
+        /// Slow request threshold (ms) |> The requests which take longer are logged as warnings, with the time spent in the request guards,
+        /// in the database queries and building the response. 0 disables it
+        slow_request_threshold_ms: u64, true,   def,    0;
 
         /// Enable DB WAL |> Turning this off might lead to worse performance, but might help if using vaultwarden on some exotic filesystems,
         /// that do not support WAL. Please make sure you read project wiki on the topic before changing this setting.
@@ -574,18 +735,62 @@ make_config! {
         /// Max database connection retries |> Number of times to retry the database connection during startup, with 1 second between each retry, set to 0 to retry indefinitely
         db_connection_retries:  u32,    false,  def,    15;
 
+        /// Run the database migrations at startup |> When disabled, the server refuses the requests with a 503 maintenance page while migrations are pending,
+        /// until they're applied from the admin page or by another instance. Mainly useful with several instances sharing a MySQL or PostgreSQL database.
+        database_auto_migrate:  bool,   false,  def,    true;
+
         /// Timeout when acquiring database connection
         database_timeout:       u64,    false,  def,    30;
 
         /// Database connection pool size
         database_max_conns:     u32,    false,  def,    10;
 
+        /// Request connection timeout |> Seconds a request waits for a database connection, before trying again or failing with a 503 error
+        database_acquire_timeout: u64,  false,  def,    10;
+
+        /// Request connection retries |> Number of times a request tries again to get a database connection, after a random delay
+        database_acquire_retries: u32,  false,  def,    2;
+
+        /// Read replica URL |> A MySQL or PostgreSQL replica of the database, for the read-only requests like the sync and the event logs.
+        /// The clients can get data as old as the replication lag. Uses the same pool size and timeout as the primary database.
+        database_replica_url:   String, false,  option;
+
+        /// UUIDv7 for the new rows |> Time ordered ids for the new ciphers, attachments and events, which are inserted at the end of the primary key index instead of all over it.
+        /// Mainly useful for large MySQL or PostgreSQL databases. The existing rows keep their ids, the two kinds can be mixed.
+        database_uuid_v7:       bool,   false,  def,    false;
+
+        /// Database scope audit |> Checks that the rows loaded for a user or an organization belong to them, and drops the other rows with an error.
+        /// A paranoid mode against authorization regressions, the debug builds always check them and panic instead.
+        db_scope_audit:         bool,   false,  def,    false;
+
+        /// Database cache TTL |> Seconds the users and the organization policies, read by most requests, are kept in memory. 0 disables the cache.
+        /// The other instances sharing the database are told about the changes through `WEBSOCKET_PUBSUB_URL`, the cache is disabled by default without it.
+        db_cache_ttl:           u64,    false,  auto,   |c| if c.websocket_pubsub_url.is_some() { 30 } else { 0 };
+        /// Authenticated session cache TTL |> Seconds the device of an authenticated session is kept in memory, saving its query on the next requests. 0 disables the cache.
+        /// The sessions are removed when their device or the security stamp of their user changes, also on the other instances through `WEBSOCKET_PUBSUB_URL`. The cache is disabled by default without it.
+        auth_session_cache_ttl: u64,    false,  auto,   |c| if c.websocket_pubsub_url.is_some() { 10 } else { 0 };
+        /// Write coalescing window |> Milliseconds the revision bumps of the users and the last activity of the devices are buffered in memory, to write them in batches. 0 writes the revisions right away.
+        /// When set, the devices also record their last activity on every request, not only when they log in. The other instances sharing the database see the new revisions after the window.
+        write_coalesce_window_ms: u64,  false,  def,    0;
+
+        /// Database maintenance window |> Local time window, like `01:00-05:00`, outside of which the scheduled database maintenance is skipped.
+        /// SQLite blocks the writes while it's vacuumed. Unset by default, the maintenance then runs whenever it's scheduled.
+        db_maintenance_window:  String, false,  option;
+
         /// Database connection init |> SQL statements to run when creating a new database connection, mainly useful for connection-scoped pragmas. If empty, a database-specific default is used.
         database_conn_init:     String, false,  def,    String::new();
 
+        /// Database encryption keys |> Comma separated list of `<id>:<secret>` keys, used to encrypt the sensitive columns, like the two-step login secrets, the API keys and the push tokens.
+        /// The first key encrypts the new values, the others are only used to read the values they encrypted. Run `vaultwarden encrypt-data` after adding a new first key.
+        /// Keep them safe, since losing them makes the encrypted data unusable.
+        database_encryption_keys: Pass, false,  option;
+
         /// Bypass admin page security (Know the risks!) |> Disables the Admin Token for the admin page so you may use your own auth in-front
         disable_admin_token:    bool,   false,  def,    false;
 
+        /// Setup wizard |> Serves a setup page at /admin/setup until the admin token is set, to configure the domain, SMTP and the admin token. It's opened with a single-use code printed in the log at startup
+        setup_wizard:           bool,   false,  def,    false;
+
         /// Allowed iframe ancestors (Know the risks!) |> Allows other domains to embed the web vault into an iframe, useful for embedding into secure intranets
         allowed_iframe_ancestors: String, true, def,    String::new();
 
@@ -606,6 +811,149 @@ make_config! {
         org_groups_enabled:     bool,   false,  def,    false;
     },
 
+    /// S3 storage settings (requires the `s3` feature)
+    s3: _enable_s3 {
+        /// Enabled
+        _enable_s3:             bool,   true,   def,    true;
+        /// Endpoint |> The S3 endpoint URL, for example https://s3.eu-west-1.amazonaws.com
+        s3_endpoint:            String, true,   def,    "https://s3.amazonaws.com".to_string();
+        /// Bucket |> When set, Send files are stored in this bucket and downloaded by the clients using pre-signed URLs
+        s3_bucket:              String, true,   option;
+        /// Region
+        s3_region:              String, true,   def,    "us-east-1".to_string();
+        /// Access Key ID
+        s3_access_key_id:       String, true,   option;
+        /// Secret Access Key
+        s3_secret_access_key:   Pass,   true,   option;
+        /// Path-style URLs |> Use path-style (https://host/bucket/key) instead of virtual-hosted-style URLs. This is needed by most self-hosted S3 implementations.
+        s3_path_style:          bool,   true,   def,    false;
+        /// Upload timeout |> Number of seconds to wait for a file upload to the bucket to finish
+        s3_upload_timeout:      u64,    false,  def,    300;
+    },
+
+    /// LDAP settings (requires the `ldap` feature)
+    ldap: _enable_ldap {
+        /// Enabled
+        _enable_ldap:           bool,   true,   def,    true;
+        /// Server URL |> When set, only the users found in this directory can login with their master password, for example ldaps://ldap.example.com
+        ldap_url:               String, true,   option;
+        /// Bind DN |> DN of the service account used to search the users
+        ldap_bind_dn:           String, true,   option;
+        /// Bind password
+        ldap_bind_password:     Pass,   true,   option;
+        /// Search base |> DN under which the users are searched
+        ldap_search_base:       String, true,   option;
+        /// Email attribute |> Attribute of the user entries containing their email address
+        ldap_mail_attribute:    String, true,   def,    "mail".to_string();
+        /// Group attribute |> Attribute of the user entries listing the DNs of their groups
+        ldap_group_attribute:   String, true,   def,    "memberOf".to_string();
+        /// Group mapping |> Semicolon separated list of `group DN:organization id:role`, the role being owner, admin, manager or user.
+        /// At login, the members of the mapped organizations get the highest role of their groups. Members who aren't in any of them keep their role.
+        ldap_group_mapping:     String, true,   option;
+        /// Mapping rules file |> Path to a JSON file of rules assigning organizations, roles and collections from the `dn`, `email` and group claims of the users.
+        /// They can be tested with the dry-run evaluation of the admin panel before being enforced.
+        ldap_mapping_rules:     String, true,   option;
+        /// Enforce the mapping rules |> Apply the mapping rules at login. Rules only grant access, they never remove users from organizations or collections
+        ldap_mapping_enforce:   bool,   true,   def,    false;
+        /// Provisioning policy |> What happens when an unknown user of the directory registers: empty to apply the signup settings,
+        /// reject to refuse it, member to allow it and join the provisioning organization once the email address is verified,
+        /// or approval to do the same but disable the account until an admin enables it. member and approval need the mails to be enabled
+        ldap_provisioning:      String, true,   def,    String::new();
+        /// Provisioning organization |> Id of the organization the provisioned users join, they still have to be confirmed by an admin
+        ldap_provisioning_org:  String, true,   option;
+        /// Provisioning role |> Role of the provisioned users: owner, admin, manager or user
+        ldap_provisioning_role: String, true,   def,    "user".to_string();
+        /// Provisioning collections |> Comma separated list of the ids of the collections the provisioned users can access
+        ldap_provisioning_collections: String, true, option;
+        /// Disable missing users |> Disable the accounts which aren't found in the directory when they try to login, instead of only refusing the login
+        ldap_disable_missing_users: bool, true, def,    false;
+        /// Connection pool size |> Maximum number of idle connections kept open to the directory server
+        ldap_pool_size:         u32,    true,   def,    4;
+        /// Timeout |> Number of seconds to wait for the directory server
+        ldap_timeout:           u64,    true,   def,    10;
+        /// Accept invalid certificates |> Don't verify the TLS certificate of the directory server. This allows MITM attacks, only use it for testing!
+        ldap_accept_invalid_certs: bool, true,  def,    false;
+    },
+
+    /// Directory sync settings
+    directory_sync: _enable_directory_sync {
+        /// Enabled
+        _enable_directory_sync: bool,   true,   def,    true;
+        /// Provider |> Directory to sync the organizations with: ldap (uses the LDAP settings), azure or google
+        directory_sync_provider: String, true,  option;
+        /// Organizations |> Semicolon separated list of `organization id:group`, the group being a DN for ldap, an object id for azure and an email address or id for google.
+        /// An organization can be listed multiple times to sync several groups into it.
+        directory_sync_orgs:    String, true,   option;
+        /// Remove members |> Remove the synced members who aren't in the synced groups anymore, instead of revoking them
+        directory_sync_overwrite_existing: bool, true, def,     false;
+        /// Azure tenant id
+        directory_sync_azure_tenant_id: String, true, option;
+        /// Azure client id |> Client id of an app registration with the GroupMember.Read.All and User.Read.All application permissions
+        directory_sync_azure_client_id: String, true, option;
+        /// Azure client secret
+        directory_sync_azure_client_secret: Pass, true, option;
+        /// Google service account key |> Path to the JSON key of a service account with domain-wide delegation of the admin.directory.group.readonly scope
+        directory_sync_google_credentials: String, true, option;
+        /// Google admin email |> Email address of the Google Workspace administrator impersonated by the service account
+        directory_sync_google_admin_email: String, true, option;
+    },
+
+    /// Key Connector settings
+    key_connector {
+        /// Enabled |> Emulate the Key Connector, so the members of the listed organizations can unlock their vault without a master password
+        key_connector_enabled:  bool,   true,   def,    false;
+        /// Acknowledge the trust model |> The master key of the Key Connector users is only protected by an RSA key held by this server,
+        /// whoever controls the server can decrypt their vaults. This needs to be enabled to confirm it is understood and accepted.
+        key_connector_acknowledge_server_access: bool, true, def, false;
+        /// Organizations |> Comma separated list of the ids of the organizations whose members can use the Key Connector. Owners and admins can't use it.
+        key_connector_orgs:     String, true,   option;
+        /// Key Connector RSA key |> Losing this key makes the vaults of the Key Connector users unrecoverable, keep a backup of it
+        key_connector_key_filename: String, false, auto, |c| format!("{}/{}", c.data_folder, "key_connector_rsa_key");
+    },
+
+    /// Backup settings
+    backup {
+        /// Backup folder |> Where the database snapshots are written, by the backup job and the admin panel
+        backup_folder:          String, false,  auto,   |c| c.data_folder.clone();
+        /// Retained backups |> Number of snapshots kept, the older ones are deleted, also from the S3 bucket. 0 keeps all of them
+        backup_retain:          u32,    true,   def,    0;
+        /// S3 prefix |> When set, the snapshots are also uploaded to the S3 bucket, under this prefix
+        backup_s3_prefix:       String, true,   option;
+        /// pg_dump command |> Used to back up the PostgreSQL databases
+        backup_pg_dump_command: String, false,  def,    "pg_dump".to_string();
+        /// mysqldump command |> Used to back up the MySQL/MariaDB databases
+        backup_mysqldump_command: String, false, def,   "mysqldump".to_string();
+    },
+
+    /// Data retention settings
+    retention {
+        /// Events days retain |> Number of days to retain events stored in the database. If unset, events are kept indefinitely.
+        events_days_retain:     i64,    true,   option;
+        /// Devices days retain |> Number of days after which the devices which weren't used are removed, they then need to log in again. If unset, devices are kept indefinitely.
+        devices_days_retain:    i64,    true,   option;
+        /// Tombstones days retain |> Number of days to keep the records of the deleted objects, which let the clients remove them without a full sync.
+        /// Clients which didn't sync for longer need a full sync.
+        tombstones_days_retain: i64,    true,   def,    90;
+        /// Mail log days retain |> Number of days to keep the log of the sent mails, with their content, which can be searched and sent again from the admin panel
+        mail_log_days_retain:   i64,    true,   def,    30;
+    },
+
+    /// Webhooks settings
+    webhooks {
+        /// Webhooks |> JSON array of `{"url": "...", "secret": "...", "events": ["..."]}` objects. The events are POSTed as JSON, signed with the secret.
+        /// Without events, all of them are sent: user.registered, user.login_failed, organization.member_invited, organization.member_confirmed,
+        /// organization.member_updated, organization.member_removed, organization.member_revoked, organization.member_restored and cipher.deleted
+        webhooks:               Pass,   true,   option;
+    },
+
+    /// SIEM export settings
+    siem {
+        /// Syslog target |> The `host:port` of the syslog server receiving the authentication and the admin events over UDP, for the ingestion by a SIEM. Disabled when empty
+        siem_syslog_target:     String, true,   option;
+        /// Format |> `syslog` for RFC 5424 messages with the details as structured data, `cef` for messages in the Common Event Format of ArcSight
+        siem_format:            String, true,   def,    "syslog".to_string();
+    },
+
     /// Yubikey settings
     yubico: _enable_yubico {
         /// Enabled
@@ -614,14 +962,23 @@ make_config! {
         yubico_client_id:       String, true,   option;
         /// Secret Key
         yubico_secret_key:      Pass,   true,   option;
-        /// Server
+        /// Server(s) |> Comma-separated list of self-hosted validation server URLs (yubico-val), used instead of YubiCloud.
+        /// They are tried in order, failing over to the next one when a server can't be reached or reports a backend error.
         yubico_server:          String, true,   option;
+        /// Server CA certificate |> Path to a PEM file with the CA certificate used to verify the HTTPS certificates of the validation servers, in addition to the system ones
+        yubico_server_ca_cert:  String, true,   option;
+        /// Accept invalid server certificates |> Don't verify the HTTPS certificates of the validation servers. This allows MITM attacks, only use it for testing!
+        yubico_server_accept_invalid_certs: bool, true, def, false;
+        /// Server timeout |> Number of seconds to wait for a validation server to respond before trying the next one
+        yubico_server_timeout:  u64,    true,   def,    5;
     },
 
     /// Global Duo settings (Note that users can override them)
     duo: _enable_duo {
         /// Enabled
         _enable_duo:            bool,   true,   def,     true;
+        /// Attempt to use deprecated iframe-based Traditional Prompt (Duo WebSDK 2)
+        duo_use_iframe:         bool,   false,  def,     false;
         /// Integration Key
         duo_ikey:               String, true,   option;
         /// Secret Key
@@ -640,6 +997,16 @@ make_config! {
         use_sendmail:                  bool,   true,   def,     false;
         /// Sendmail Command |> Which sendmail command to use. The one found in the $PATH is used if not specified.
         sendmail_command:              String, true,   option;
+        /// Mail API |> ("sendgrid", "mailgun", "ses") Send the mails with the HTTP API of a mail provider instead of SMTP or sendmail, for the hosts which block the outbound SMTP connections
+        mail_api:                      String, true,   option;
+        /// Mail API key |> The API key of SendGrid or Mailgun, or the secret access key of SES
+        mail_api_key:                  Pass,   true,   option;
+        /// Mail API key id |> The access key id of SES
+        mail_api_key_id:               String, true,   option;
+        /// Mail API region |> The AWS region of SES, or "eu" for the EU region of Mailgun
+        mail_api_region:               String, true,   option;
+        /// Mail API domain |> The sending domain of Mailgun. The domain of the From Address is used if not specified.
+        mail_api_domain:               String, true,   option;
         /// Host
         smtp_host:                     String, true,   option;
         /// DEPRECATED smtp_ssl |> DEPRECATED - Please use SMTP_SECURITY
@@ -662,10 +1029,32 @@ make_config! {
         smtp_auth_mechanism:           String, true,   option;
         /// SMTP connection timeout |> Number of seconds when to stop trying to connect to the SMTP server
         smtp_timeout:                  u64,    true,   def,     15;
+        /// SMTP connection pool size |> Maximum number of connections to the SMTP server which are kept open and reused
+        smtp_pool_size:                u32,    true,   def,     4;
+        /// SMTP relays |> JSON array of `{"host": "...", "port": 587, "security": "starttls", "username": "...", "password": "..."}` objects, the relays used in their order when the SMTP host fails
+        smtp_relays:                   Pass,   true,   option;
+        /// Mail queue |> Queue the mails in the database, they are sent in the background and retried when the sending fails
+        mail_queue:                    bool,   true,   def,     true;
+        /// Mail queue attempts |> Number of attempts to send a queued mail, with an exponential backoff. The mails which still failed are kept as dead letters, shown in the diagnostics
+        mail_queue_max_attempts:       u32,    true,   def,     8;
+        /// Mail queue max per minute |> Maximum number of mails sent by the queue in a minute, the next ones wait for the next minute. 0 for no limit
+        mail_queue_max_per_minute:     u32,    true,   def,     0;
+        /// Mail digest delay |> Seconds during which the notification mails are held in the queue, the ones to the same recipient are then sent as a single digest. 0 to send them right away
+        mail_digest_delay:             u64,    true,   def,     0;
         /// Server name sent during HELO |> By default this value should be is on the machine's hostname, but might need to be changed in case it trips some anti-spam filters
         helo_name:                     String, true,   option;
+        /// DKIM private key |> Path to the PEM private key, RSA or Ed25519, used to sign the mails with DKIM. The mails aren't signed if not specified. Not used with SendGrid, which signs the mails itself
+        dkim_private_key:              String, true,   option;
+        /// DKIM selector |> The selector of the DNS TXT record with the public key, `<selector>._domainkey.<domain>`
+        dkim_selector:                 String, true,   option;
+        /// DKIM domain |> The signing domain. The domain of the From Address is used if not specified.
+        dkim_domain:                   String, true,   option;
         /// Embed images as email attachments.
         smtp_embed_images:             bool, true, def, true;
+        /// Logo path |> A PNG or JPEG image of at most 256 KiB, which replaces the Vaultwarden logo of the mails. Needs the images to be embedded
+        smtp_logo_path:                String, true,   option;
+        /// Calendar attachments |> Attach a calendar event to the mails about a deadline, like the end of the wait time of an emergency access or a delayed 2FA recovery
+        smtp_calendar_attachments:     bool,   true,   def,     true;
         /// _smtp_img_src
         _smtp_img_src:                 String, false, gen, |c| generate_smtp_img_src(c.smtp_embed_images, &c.domain);
         /// Enable SMTP debugging (Know the risks!) |> DANGEROUS: Enabling this will output very detailed SMTP messages. This could contain sensitive information like passwords and usernames! Only enable this during troubleshooting!
@@ -679,13 +1068,15 @@ make_config! {
     /// Email 2FA Settings
     email_2fa: _enable_email_2fa {
         /// Enabled |> Disabling will prevent users from setting up new email 2FA and using existing email 2FA configured
-        _enable_email_2fa:      bool,   true,   auto,    |c| c._enable_smtp && (c.smtp_host.is_some() || c.use_sendmail);
+        _enable_email_2fa:      bool,   true,   auto,    |c| c._enable_smtp && (c.smtp_host.is_some() || c.use_sendmail || c.mail_api.is_some());
         /// Email token size |> Number of digits in an email 2FA token (min: 6, max: 255). Note that the Bitwarden clients are hardcoded to mention 6 digit codes regardless of this setting.
         email_token_size:       u8,     true,   def,      6;
         /// Token expiration time |> Maximum time in seconds a token is valid. The time the user has to open email client and copy token.
         email_expiration_time:  u64,    true,   def,      600;
         /// Maximum attempts |> Maximum attempts before an email token is reset and a new email will need to be sent
         email_attempts_limit:   u64,    true,   def,      3;
+        /// Resend cooldown |> Minimum time in seconds between two tokens sent to the same user. The previous token stays valid in the meantime.
+        email_resend_cooldown:  u64,    true,   def,      60;
         /// Automatically enforce at login |> Setup email 2FA provider regardless of any organization policy
         email_2fa_enforce_on_verified_invite: bool,   true,   def,      false;
         /// Auto-enable 2FA (Know the risks!) |> Automatically setup email 2FA as fallback provider when needed
@@ -705,6 +1096,11 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         }
     }
 
+    // The clients of the other instances wouldn't see the changes for too long
+    if cfg.write_coalesce_window_ms > 60_000 {
+        err!("`WRITE_COALESCE_WINDOW_MS` can't be more than 60000, one minute")
+    }
+
     if cfg.password_iterations < 100_000 {
         err!("PASSWORD_ITERATIONS should be at least 100000 or higher. The default is 600000!");
     }
@@ -714,6 +1110,35 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         err!(format!("`DATABASE_MAX_CONNS` contains an invalid value. Ensure it is between 1 and {limit}.",));
     }
 
+    if cfg.database_acquire_timeout == 0 {
+        err!("`DATABASE_ACQUIRE_TIMEOUT` needs to be at least 1 second")
+    }
+
+    if cfg.websocket_queue_size < 1 {
+        err!("`WEBSOCKET_QUEUE_SIZE` should be at least 1");
+    }
+
+    if cfg.websocket_idle_timeout > 0 && cfg.websocket_idle_timeout < 30 {
+        err!("`WEBSOCKET_IDLE_TIMEOUT` should be at least 30 seconds, the pings are sent every 15 seconds");
+    }
+
+    if let Some(url) = &cfg.websocket_pubsub_url {
+        if let Err(e) = crate::pubsub::validate_url(url) {
+            err!(e);
+        }
+        if cfg.websocket_pubsub_channel.is_empty() || cfg.websocket_pubsub_channel.contains(char::is_whitespace) {
+            err!("`WEBSOCKET_PUBSUB_CHANNEL` can't be empty or contain spaces");
+        }
+    }
+
+    if !["text", "json"].contains(&cfg.log_format.as_str()) {
+        err!("`LOG_FORMAT` needs to be `text` or `json`");
+    }
+
+    if cfg.route_stats_error_budget > 1_000_000 {
+        err!("`ROUTE_STATS_ERROR_BUDGET` is in errors per million requests, it can't be above 1000000");
+    }
+
     if let Some(log_file) = &cfg.log_file {
         if std::fs::OpenOptions::new().append(true).create(true).open(log_file).is_err() {
             err!("Unable to write to log file", log_file);
@@ -746,7 +1171,21 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         }
     }
 
-    if cfg.push_enabled && (cfg.push_installation_id == String::new() || cfg.push_installation_key == String::new()) {
+    if let Some(address) = &cfg.admin_listen_address {
+        if address.parse::<std::net::SocketAddr>().is_err() {
+            err!(format!("`ADMIN_LISTEN_ADDRESS` must be an `ip:port`, like `127.0.0.1:8081`, not `{address}`"))
+        }
+    }
+
+    let push_mode = cfg.push_mode.to_lowercase();
+    if push_mode != "relay" && push_mode != "direct" {
+        err!("`PUSH_MODE` must be `relay` or `direct`")
+    }
+
+    if cfg.push_enabled
+        && push_mode == "relay"
+        && (cfg.push_installation_id == String::new() || cfg.push_installation_key == String::new())
+    {
         err!(
             "Misconfigured Push Notification service\n\
             ########################################################################################\n\
@@ -757,7 +1196,20 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         )
     }
 
-    if cfg.push_enabled {
+    if cfg.push_enabled && push_mode == "direct" {
+        if cfg.push_apns_key.is_some()
+            && (cfg.push_apns_key_id.is_none() || cfg.push_apns_team_id.is_none() || cfg.push_apns_topic.is_none())
+        {
+            err!("`PUSH_APNS_KEY` requires `PUSH_APNS_KEY_ID`, `PUSH_APNS_TEAM_ID` and `PUSH_APNS_TOPIC`")
+        }
+        if let Some(path) = &cfg.push_fcm_credentials {
+            if !std::path::Path::new(path).is_file() {
+                err!(format!("`PUSH_FCM_CREDENTIALS` file `{path}` doesn't exist"))
+            }
+        }
+    }
+
+    if cfg.push_enabled && push_mode == "relay" {
         let push_relay_uri = cfg.push_relay_uri.to_lowercase();
         if !push_relay_uri.starts_with("https://") {
             err!("`PUSH_RELAY_URI` must start with 'https://'.")
@@ -808,6 +1260,14 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         }
     }
 
+    if !(1i64..=MAX_FILESIZE_KB).contains(&cfg.send_file_size_limit) {
+        err!("`SEND_FILE_SIZE_LIMIT` is out of bounds");
+    }
+
+    if !(1i64..=MAX_FILESIZE_KB).contains(&cfg.attachment_file_size_limit) {
+        err!("`ATTACHMENT_FILE_SIZE_LIMIT` is out of bounds");
+    }
+
     if cfg._enable_duo
         && (cfg.duo_host.is_some() || cfg.duo_ikey.is_some() || cfg.duo_skey.is_some())
         && !(cfg.duo_host.is_some() && cfg.duo_ikey.is_some() && cfg.duo_skey.is_some())
@@ -820,14 +1280,177 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
             err!("Both `YUBICO_CLIENT_ID` and `YUBICO_SECRET_KEY` must be set for Yubikey OTP support")
         }
 
-        if let Some(yubico_server) = &cfg.yubico_server {
-            let yubico_server = yubico_server.to_lowercase();
-            if !yubico_server.starts_with("https://") {
-                err!("`YUBICO_SERVER` must be a valid URL and start with 'https://'. Either unset this variable or provide a valid URL.")
+        if let Some(yubico_servers) = &cfg.yubico_server {
+            for yubico_server in yubico_servers.split(',').map(str::trim) {
+                if !yubico_server.to_lowercase().starts_with("https://") {
+                    err!("Every `YUBICO_SERVER` must be a valid URL and start with 'https://'. Either unset this variable or provide valid URLs.")
+                }
+            }
+        }
+
+        if let Some(ca_cert) = &cfg.yubico_server_ca_cert {
+            if !std::path::Path::new(ca_cert).is_file() {
+                err!(format!("`YUBICO_SERVER_CA_CERT` file `{ca_cert}` doesn't exist"))
+            }
+        }
+    }
+
+    if cfg._enable_ldap {
+        if let Some(ldap_url) = &cfg.ldap_url {
+            if !cfg!(feature = "ldap") {
+                err!("`LDAP_URL` is set, but the 'ldap' feature is not enabled")
+            }
+
+            let ldap_url = ldap_url.to_lowercase();
+            if !ldap_url.starts_with("ldap://") && !ldap_url.starts_with("ldaps://") {
+                err!("`LDAP_URL` must start with 'ldap://' or 'ldaps://'")
+            }
+
+            if cfg.ldap_bind_dn.is_none() || cfg.ldap_bind_password.is_none() || cfg.ldap_search_base.is_none() {
+                err!("`LDAP_BIND_DN`, `LDAP_BIND_PASSWORD` and `LDAP_SEARCH_BASE` need to be set to use LDAP")
+            }
+
+            match cfg.ldap_provisioning.to_lowercase().as_str() {
+                "" => (),
+                "reject" | "member" | "approval" => {
+                    if cfg.ldap_provisioning_org.is_none() {
+                        err!("`LDAP_PROVISIONING_ORG` needs to be set to use `LDAP_PROVISIONING`")
+                    }
+                    // The provisioned users only join the organization once they have verified their email address
+                    let mail_enabled =
+                        cfg._enable_smtp && (cfg.smtp_host.is_some() || cfg.use_sendmail || cfg.mail_api.is_some());
+                    if !mail_enabled && !cfg.ldap_provisioning.eq_ignore_ascii_case("reject") {
+                        err!("`LDAP_PROVISIONING` needs the mails to be enabled to verify the email addresses")
+                    }
+                }
+                _ => err!("`LDAP_PROVISIONING` must be empty, reject, member or approval"),
+            }
+
+            #[cfg(feature = "ldap")]
+            if crate::ldap::parse_role(&cfg.ldap_provisioning_role).is_none() {
+                err!("`LDAP_PROVISIONING_ROLE` must be owner, admin, manager or user")
+            }
+
+            if let Some(path) = &cfg.ldap_mapping_rules {
+                match std::fs::read_to_string(path) {
+                    Ok(json) => {
+                        if let Err(e) = crate::mapping_rules::parse_rules(&json) {
+                            err!(format!("`LDAP_MAPPING_RULES` file {path} is invalid: {e}"))
+                        }
+                    }
+                    Err(e) => err!(format!("Error reading the `LDAP_MAPPING_RULES` file {path}: {e}")),
+                }
+            } else if cfg.ldap_mapping_enforce {
+                err!("`LDAP_MAPPING_ENFORCE` is enabled, but `LDAP_MAPPING_RULES` is not set")
+            }
+
+            #[cfg(feature = "ldap")]
+            if let Some(mapping) = &cfg.ldap_group_mapping {
+                if let Err(e) = crate::ldap::parse_group_mapping(mapping) {
+                    err!(format!("`LDAP_GROUP_MAPPING` is invalid: {e}"))
+                }
+            }
+        }
+    }
+
+    if cfg._enable_directory_sync {
+        if let Some(provider) = &cfg.directory_sync_provider {
+            match provider.to_lowercase().as_str() {
+                "ldap" => {
+                    if !cfg!(feature = "ldap") || !cfg._enable_ldap || cfg.ldap_url.is_none() {
+                        err!("The `ldap` directory sync provider requires the 'ldap' feature and the LDAP settings")
+                    }
+                }
+                "azure" => {
+                    if cfg.directory_sync_azure_tenant_id.is_none()
+                        || cfg.directory_sync_azure_client_id.is_none()
+                        || cfg.directory_sync_azure_client_secret.is_none()
+                    {
+                        err!("The `azure` directory sync provider requires `DIRECTORY_SYNC_AZURE_TENANT_ID`, `DIRECTORY_SYNC_AZURE_CLIENT_ID` and `DIRECTORY_SYNC_AZURE_CLIENT_SECRET`")
+                    }
+                }
+                "google" => match &cfg.directory_sync_google_credentials {
+                    Some(path) if cfg.directory_sync_google_admin_email.is_some() => {
+                        if !std::path::Path::new(path).is_file() {
+                            err!(format!("`DIRECTORY_SYNC_GOOGLE_CREDENTIALS` file {path} does not exist"))
+                        }
+                    }
+                    _ => err!("The `google` directory sync provider requires `DIRECTORY_SYNC_GOOGLE_CREDENTIALS` and `DIRECTORY_SYNC_GOOGLE_ADMIN_EMAIL`"),
+                },
+                _ => err!("`DIRECTORY_SYNC_PROVIDER` must be one of ldap, azure or google"),
+            }
+
+            match &cfg.directory_sync_orgs {
+                Some(orgs) => {
+                    if let Err(e) = crate::api::core::parse_sync_orgs(orgs) {
+                        err!(format!("`DIRECTORY_SYNC_ORGS` is invalid: {e}"))
+                    }
+                }
+                None => err!("`DIRECTORY_SYNC_ORGS` needs to be set to use the directory sync"),
             }
         }
     }
 
+    if let Some(keys) = &cfg.database_encryption_keys {
+        if let Err(e) = crate::db::encryption::validate_config(keys) {
+            err!(e);
+        }
+    }
+
+    if let Some(webhooks) = &cfg.webhooks {
+        if let Err(e) = crate::webhook::validate_config(webhooks) {
+            err!(e);
+        }
+    }
+
+    if let Some(target) = &cfg.siem_syslog_target {
+        if let Err(e) = crate::siem::validate_config(target, &cfg.siem_format) {
+            err!(e);
+        }
+    }
+
+    if cfg.key_connector_enabled {
+        if !cfg.key_connector_acknowledge_server_access {
+            err!("`KEY_CONNECTOR_ENABLED` lets the server decrypt the vaults of the Key Connector users, `KEY_CONNECTOR_ACKNOWLEDGE_SERVER_ACCESS` needs to be enabled to accept it")
+        }
+        if cfg.key_connector_orgs.as_deref().map_or(true, |orgs| orgs.trim().is_empty()) {
+            err!("`KEY_CONNECTOR_ORGS` needs to be set to use the Key Connector")
+        }
+        if !cfg.domain_set {
+            err!("`DOMAIN` needs to be set to use the Key Connector")
+        }
+    }
+
+    if cfg._enable_s3 && cfg.s3_bucket.is_some() {
+        if !cfg!(feature = "s3") {
+            err!("`S3_BUCKET` is set, but the 's3' feature is not enabled")
+        }
+
+        if cfg.s3_access_key_id.is_none() || cfg.s3_secret_access_key.is_none() {
+            err!("Both `S3_ACCESS_KEY_ID` and `S3_SECRET_ACCESS_KEY` need to be set to use S3 storage")
+        }
+
+        let s3_endpoint = cfg.s3_endpoint.to_lowercase();
+        if !s3_endpoint.starts_with("https://") && !s3_endpoint.starts_with("http://") {
+            err!("`S3_ENDPOINT` must start with 'http://' or 'https://'")
+        }
+
+        if Url::parse(&s3_endpoint).is_err() {
+            err!("Invalid URL format for `S3_ENDPOINT`.");
+        }
+    }
+
+    if let Some(ref notification_url) = cfg.emergency_access_notification_url {
+        let notification_url = notification_url.to_lowercase();
+        if !notification_url.starts_with("https://") && !notification_url.starts_with("http://") {
+            err!("`EMERGENCY_ACCESS_NOTIFICATION_URL` must start with 'http://' or 'https://'")
+        }
+
+        if Url::parse(&notification_url).is_err() {
+            err!("Invalid URL format for `EMERGENCY_ACCESS_NOTIFICATION_URL`.");
+        }
+    }
+
     if cfg._enable_smtp {
         match cfg.smtp_security.as_str() {
             "off" | "starttls" | "force_tls" => (),
@@ -836,7 +1459,27 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
             ),
         }
 
-        if cfg.use_sendmail {
+        if let Some(mail_api) = &cfg.mail_api {
+            match mail_api.as_str() {
+                "sendgrid" | "mailgun" => (),
+                "ses" => {
+                    if cfg.mail_api_key_id.is_none() || cfg.mail_api_region.is_none() {
+                        err!("Both `MAIL_API_KEY_ID` and `MAIL_API_REGION` need to be set to send the mails with SES")
+                    }
+                }
+                _ => {
+                    err!("`MAIL_API` is invalid. It needs to be one of the following options: sendgrid, mailgun or ses")
+                }
+            }
+
+            if cfg.mail_api_key.is_none() {
+                err!("`MAIL_API_KEY` needs to be set to send the mails with `MAIL_API`")
+            }
+
+            if cfg.smtp_from.is_empty() {
+                err!("`SMTP_FROM` needs to be set to send the mails with `MAIL_API`")
+            }
+        } else if cfg.use_sendmail {
             let command = cfg.sendmail_command.clone().unwrap_or_else(|| format!("sendmail{EXE_SUFFIX}"));
 
             let mut path = std::path::PathBuf::from(&command);
@@ -879,16 +1522,52 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
             }
         }
 
-        if (cfg.smtp_host.is_some() || cfg.use_sendmail) && !cfg.smtp_from.contains('@') {
+        if (cfg.smtp_host.is_some() || cfg.use_sendmail || cfg.mail_api.is_some()) && !cfg.smtp_from.contains('@') {
             err!("SMTP_FROM does not contain a mandatory @ sign")
         }
 
         if cfg._enable_email_2fa && cfg.email_token_size < 6 {
             err!("`EMAIL_TOKEN_SIZE` has a minimum size of 6")
         }
+
+        if cfg.dkim_private_key.is_some() != cfg.dkim_selector.is_some() {
+            err!("Both `DKIM_PRIVATE_KEY` and `DKIM_SELECTOR` need to be set to sign the mails with DKIM")
+        }
+
+        if let Some(dkim_private_key) = &cfg.dkim_private_key {
+            if let Err(e) = crate::dkim::load_key(dkim_private_key) {
+                err!(format!("The DKIM private key `{dkim_private_key}` can't be loaded: {e}"))
+            }
+        }
+
+        if let Some(smtp_logo_path) = &cfg.smtp_logo_path {
+            if !cfg.smtp_embed_images {
+                err!("`SMTP_LOGO_PATH` needs `SMTP_EMBED_IMAGES`, the logo is attached to the mails")
+            }
+            if let Err(e) = crate::mail::load_logo(smtp_logo_path) {
+                err!(format!("The logo `{smtp_logo_path}` can't be used: {e}"))
+            }
+        }
+
+        if let Some(smtp_relays) = &cfg.smtp_relays {
+            if cfg.smtp_host.is_none() {
+                err!("`SMTP_RELAYS` needs `SMTP_HOST`, they are only used when it fails")
+            }
+            if let Err(e) = crate::smtp::validate_config(smtp_relays) {
+                err!(e);
+            }
+        }
+
+        if cfg.smtp_pool_size < 1 {
+            err!("`SMTP_POOL_SIZE` should be at least 1")
+        }
+
+        if cfg.mail_queue_max_attempts < 1 {
+            err!("`MAIL_QUEUE_MAX_ATTEMPTS` should be at least 1")
+        }
     }
 
-    if cfg._enable_email_2fa && !(cfg.smtp_host.is_some() || cfg.use_sendmail) {
+    if cfg._enable_email_2fa && !(cfg.smtp_host.is_some() || cfg.use_sendmail || cfg.mail_api.is_some()) {
         err!("To enable email 2FA, a mail transport must be configured")
     }
 
@@ -908,28 +1587,83 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         }
     }
 
+    for (name, ranges) in
+        [("ICON_ALLOWED_IP_RANGES", &cfg.icon_allowed_ip_ranges), ("ICON_DENIED_IP_RANGES", &cfg.icon_denied_ip_ranges)]
+    {
+        if let Some(ranges) = ranges {
+            if let Err(e) = crate::util::parse_ip_ranges(ranges) {
+                err!(format!("`{name}` is invalid: {e}"))
+            }
+        }
+    }
+
     // Check if the icon service is valid
     let icon_service = cfg.icon_service.as_str();
     match icon_service {
         "internal" | "bitwarden" | "duckduckgo" | "google" => (),
-        _ => {
-            if !icon_service.starts_with("http") {
-                err!(format!("Icon service URL `{icon_service}` must start with \"http\""))
-            }
-            match icon_service.matches("{}").count() {
-                1 => (), // nominal
-                0 => err!(format!("Icon service URL `{icon_service}` has no placeholder \"{{}}\"")),
-                _ => err!(format!("Icon service URL `{icon_service}` has more than one placeholder \"{{}}\"")),
+        _ => validate_icon_service_url(icon_service)?,
+    }
+
+    if let Some(fallbacks) = &cfg.icon_service_fallbacks {
+        if !cfg.icon_service_proxy {
+            err!("`ICON_SERVICE_FALLBACKS` needs `ICON_SERVICE_PROXY`, the fallback services are tried by the server")
+        }
+        for fallback in fallbacks.split(',').map(str::trim) {
+            match fallback {
+                "bitwarden" | "duckduckgo" | "google" => (),
+                "internal" => err!("`ICON_SERVICE_FALLBACKS` can only have external icon services"),
+                _ => validate_icon_service_url(fallback)?,
             }
         }
     }
 
+    if cfg.icon_max_concurrent_downloads < 1 {
+        err!("`ICON_MAX_CONCURRENT_DOWNLOADS` needs to be at least 1")
+    }
+
+    if let Err(e) = icon_client_options(cfg).apply(reqwest::Client::builder()) {
+        err!(format!("The icon client settings are invalid: {e}"))
+    }
+
+    if cfg.icon_service_max_size == 0 {
+        err!("`ICON_SERVICE_MAX_SIZE` needs to be at least 1")
+    }
+
     // Check if the icon redirect code is valid
     match cfg.icon_redirect_code {
         301 | 302 | 307 | 308 => (),
         _ => err!("Only HTTP 301/302 and 307/308 redirects are supported"),
     }
 
+    if cfg.icon_retry_budget < 1 {
+        err!("`ICON_RETRY_BUDGET` needs to be at least 1")
+    }
+
+    if cfg.icon_size > 512 {
+        err!("`ICON_SIZE` can't be larger than 512")
+    }
+
+    match cfg.icon_cache_backend.as_str() {
+        "disk" => (),
+        "redis" => match &cfg.icon_cache_redis_url {
+            Some(url) => {
+                if let Err(e) = crate::icon_cache::validate_redis_url(url) {
+                    err!(e);
+                }
+            }
+            None => err!("`ICON_CACHE_REDIS_URL` needs to be set to cache the icons in Redis"),
+        },
+        "s3" => {
+            if !cfg!(feature = "s3") {
+                err!("`ICON_CACHE_BACKEND` is s3, but the 's3' feature is not enabled")
+            }
+            if !cfg._enable_s3 || cfg.s3_bucket.is_none() {
+                err!("Caching the icons in S3 needs the S3 storage to be configured")
+            }
+        }
+        _ => err!("`ICON_CACHE_BACKEND` is invalid. It needs to be one of the following options: disk, redis or s3"),
+    }
+
     if cfg.invitation_expiration_hours < 1 {
         err!("`INVITATION_EXPIRATION_HOURS` has a minimum duration of 1 hour")
     }
@@ -959,14 +1693,107 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         err!("`EMERGENCY_REQUEST_TIMEOUT_SCHEDULE` is not a valid cron expression")
     }
 
-    if !cfg.event_cleanup_schedule.is_empty() && cfg.event_cleanup_schedule.parse::<Schedule>().is_err() {
-        err!("`EVENT_CLEANUP_SCHEDULE` is not a valid cron expression")
+    if !cfg.emergency_access_validation_schedule.is_empty()
+        && cfg.emergency_access_validation_schedule.parse::<Schedule>().is_err()
+    {
+        err!("`EMERGENCY_ACCESS_VALIDATION_SCHEDULE` is not a valid cron expression")
+    }
+
+    if !cfg.data_retention_schedule.is_empty() && cfg.data_retention_schedule.parse::<Schedule>().is_err() {
+        err!("`DATA_RETENTION_SCHEDULE` is not a valid cron expression")
+    }
+
+    if cfg.events_days_retain.is_some_and(|days| days < 1) {
+        err!("`EVENTS_DAYS_RETAIN` should be at least 1")
+    }
+
+    if cfg.devices_days_retain.is_some_and(|days| days < 1) {
+        err!("`DEVICES_DAYS_RETAIN` should be at least 1")
+    }
+
+    if cfg.tombstones_days_retain < 1 {
+        err!("`TOMBSTONES_DAYS_RETAIN` should be at least 1")
+    }
+
+    if cfg.mail_log_days_retain < 1 {
+        err!("`MAIL_LOG_DAYS_RETAIN` should be at least 1")
+    }
+
+    if !cfg.db_maintenance_schedule.is_empty() && cfg.db_maintenance_schedule.parse::<Schedule>().is_err() {
+        err!("`DB_MAINTENANCE_SCHEDULE` is not a valid cron expression")
+    }
+
+    if let Some(window) = &cfg.db_maintenance_window {
+        if let Err(e) = crate::db_maintenance::parse_window(window) {
+            err!(format!("`DB_MAINTENANCE_WINDOW`: {e}"))
+        }
+    }
+
+    if !cfg.icon_prewarm_schedule.is_empty() && cfg.icon_prewarm_schedule.parse::<Schedule>().is_err() {
+        err!("`ICON_PREWARM_SCHEDULE` is not a valid cron expression")
+    }
+
+    if !cfg.backup_schedule.is_empty() && cfg.backup_schedule.parse::<Schedule>().is_err() {
+        err!("`BACKUP_SCHEDULE` is not a valid cron expression")
+    }
+
+    if cfg.backup_s3_prefix.is_some() {
+        if !cfg!(feature = "s3") {
+            err!("`BACKUP_S3_PREFIX` is set, but the 's3' feature is not enabled")
+        }
+        if !cfg._enable_s3 || cfg.s3_bucket.is_none() {
+            err!("`BACKUP_S3_PREFIX` needs the S3 storage to be configured")
+        }
     }
 
     if !cfg.auth_request_purge_schedule.is_empty() && cfg.auth_request_purge_schedule.parse::<Schedule>().is_err() {
         err!("`AUTH_REQUEST_PURGE_SCHEDULE` is not a valid cron expression")
     }
 
+    if !cfg.duo_context_purge_schedule.is_empty() && cfg.duo_context_purge_schedule.parse::<Schedule>().is_err() {
+        err!("`DUO_CONTEXT_PURGE_SCHEDULE` is not a valid cron expression")
+    }
+
+    if !cfg.two_factor_recovery_schedule.is_empty() && cfg.two_factor_recovery_schedule.parse::<Schedule>().is_err() {
+        err!("`TWO_FACTOR_RECOVERY_SCHEDULE` is not a valid cron expression")
+    }
+
+    if cfg.two_factor_recovery_delay_hours < 0 {
+        err!("`TWO_FACTOR_RECOVERY_DELAY_HOURS` can't be negative")
+    }
+
+    if !cfg.two_factor_policy_grace_schedule.is_empty()
+        && cfg.two_factor_policy_grace_schedule.parse::<Schedule>().is_err()
+    {
+        err!("`TWO_FACTOR_POLICY_GRACE_SCHEDULE` is not a valid cron expression")
+    }
+
+    if !cfg.directory_sync_schedule.is_empty() && cfg.directory_sync_schedule.parse::<Schedule>().is_err() {
+        err!("`DIRECTORY_SYNC_SCHEDULE` is not a valid cron expression")
+    }
+
+    if !(0..=10).contains(&cfg.authenticator_time_drift_steps) {
+        err!("`AUTHENTICATOR_TIME_DRIFT_STEPS` should be between 0 and 10")
+    }
+
+    if !(0..=10).contains(&cfg.authenticator_reuse_window) {
+        err!("`AUTHENTICATOR_REUSE_WINDOW` should be between 0 and 10")
+    }
+
+    if cfg.two_factor_remember_max_days < 0 {
+        err!("`TWO_FACTOR_REMEMBER_MAX_DAYS` can't be negative")
+    }
+
+    if !(0..=365).contains(&cfg.two_factor_policy_grace_days) {
+        err!("`TWO_FACTOR_POLICY_GRACE_DAYS` must be between 0 and 365")
+    }
+
+    if let Some(ref secret) = cfg.two_factor_data_secret {
+        if secret.len() < 16 {
+            err!("`TWO_FACTOR_DATA_SECRET` should be at least 16 characters long")
+        }
+    }
+
     if !cfg.disable_admin_token {
         match cfg.admin_token.as_ref() {
             Some(t) if t.starts_with("$argon2") => {
@@ -1020,7 +1847,15 @@ fn generate_smtp_img_src(embed_images: bool, domain: &str) -> String {
 
 /// Generate the correct URL for the icon service.
 /// This will be used within icons.rs to call the external icon service.
-fn generate_icon_service_url(icon_service: &str) -> String {
+fn icon_client_options(cfg: &ConfigItems) -> ClientOptions {
+    ClientOptions {
+        proxy: cfg.icon_http_proxy.clone(),
+        min_tls_version: cfg.icon_tls_min_version.clone(),
+        ca_bundle: cfg.icon_ca_bundle.clone(),
+    }
+}
+
+pub fn generate_icon_service_url(icon_service: &str) -> String {
     match icon_service {
         "internal" => String::new(),
         "bitwarden" => "https://icons.bitwarden.net/{}/icon.png".to_string(),
@@ -1030,6 +1865,17 @@ fn generate_icon_service_url(icon_service: &str) -> String {
     }
 }
 
+fn validate_icon_service_url(icon_service: &str) -> Result<(), Error> {
+    if !icon_service.starts_with("http") {
+        err!(format!("Icon service URL `{icon_service}` must start with \"http\""))
+    }
+    match icon_service.matches("{}").count() {
+        1 => Ok(()), // nominal
+        0 => err!(format!("Icon service URL `{icon_service}` has no placeholder \"{{}}\"")),
+        _ => err!(format!("Icon service URL `{icon_service}` has more than one placeholder \"{{}}\"")),
+    }
+}
+
 /// Generate the CSP string needed to allow redirected icon fetching
 fn generate_icon_service_csp(icon_service: &str, icon_service_url: &str) -> String {
     // We split on the first '{', since that is the variable delimiter for an icon service URL.
@@ -1060,6 +1906,12 @@ fn smtp_convert_deprecated_ssl_options(smtp_ssl: Option<bool>, smtp_explicit_tls
     "starttls".to_string()
 }
 
+// The encrypted files are a bit larger than the files the users chose, they're allowed 5% more than the limits
+fn with_encryption_slack(limit_kb: i64) -> i64 {
+    let bytes = limit_kb << 10;
+    bytes.saturating_add(bytes / 20)
+}
+
 impl Config {
     pub fn load() -> Result<Self, Error> {
         // Loading from env and file
@@ -1076,7 +1928,7 @@ impl Config {
 
         Ok(Config {
             inner: RwLock::new(Inner {
-                rocket_shutdown_handle: None,
+                rocket_shutdown_handles: Vec::new(),
                 templates: load_templates(&config.templates_folder),
                 config,
                 _env,
@@ -1120,7 +1972,8 @@ impl Config {
         Ok(())
     }
 
-    fn update_config_partial(&self, other: ConfigBuilder) -> Result<(), Error> {
+    /// Merges the values into the saved config, the other saved values are kept.
+    pub fn update_config_partial(&self, other: ConfigBuilder) -> Result<(), Error> {
         let builder = {
             let usr = &self.inner.read().unwrap()._usr;
             let mut _overrides = Vec::new();
@@ -1191,12 +2044,28 @@ impl Config {
         Ok(())
     }
 
+    /// The maximum size in bytes of a single Send file, with the slack for the encryption overhead.
+    pub fn send_file_size_limit_bytes(&self) -> i64 {
+        with_encryption_slack(self.send_file_size_limit())
+    }
+
+    /// The maximum size in bytes of a single cipher attachment, with the slack for the encryption overhead.
+    pub fn attachment_file_size_limit_bytes(&self) -> i64 {
+        with_encryption_slack(self.attachment_file_size_limit())
+    }
+
     pub fn private_rsa_key(&self) -> String {
         format!("{}.pem", CONFIG.rsa_key_filename())
     }
+    pub fn key_connector_rsa_key(&self) -> String {
+        format!("{}.pem", CONFIG.key_connector_key_filename())
+    }
+    pub fn config_file(&self) -> String {
+        CONFIG_FILE.clone()
+    }
     pub fn mail_enabled(&self) -> bool {
         let inner = &self.inner.read().unwrap().config;
-        inner._enable_smtp && (inner.smtp_host.is_some() || inner.use_sendmail)
+        inner._enable_smtp && (inner.smtp_host.is_some() || inner.use_sendmail || inner.mail_api.is_some())
     }
 
     pub fn get_duo_akey(&self) -> String {
@@ -1216,6 +2085,11 @@ impl Config {
         }
     }
 
+    /// The options of the icon client, which only apply to the icon downloads.
+    pub fn icon_client_options(&self) -> ClientOptions {
+        icon_client_options(&self.inner.read().unwrap().config)
+    }
+
     /// Tests whether the admin token is set to a non-empty value.
     pub fn is_admin_token_set(&self) -> bool {
         let token = self.admin_token();
@@ -1238,23 +2112,44 @@ impl Config {
         }
     }
 
-    pub fn set_rocket_shutdown_handle(&self, handle: rocket::Shutdown) {
-        self.inner.write().unwrap().rocket_shutdown_handle = Some(handle);
+    pub fn has_template(&self, name: &str) -> bool {
+        if CONFIG.reload_templates() {
+            load_templates(CONFIG.templates_folder()).has_template(name)
+        } else {
+            self.inner.read().unwrap().templates.has_template(name)
+        }
+    }
+
+    /// The names of the mail templates which can be previewed, without their `email/` prefix.
+    pub fn email_template_names(&self) -> Vec<String> {
+        let inner = &self.inner.read().unwrap();
+        let mut names: Vec<String> = inner
+            .templates
+            .get_templates()
+            .keys()
+            .filter_map(|name| name.strip_prefix("email/")?.strip_suffix(".html"))
+            // Without the translations, which are previewed as their built-in template
+            .filter(|name| !name.contains('/'))
+            .map(String::from)
+            .collect();
+        names.sort();
+        names
+    }
+
+    pub fn add_rocket_shutdown_handle(&self, handle: rocket::Shutdown) {
+        self.inner.write().unwrap().rocket_shutdown_handles.push(handle);
     }
 
     pub fn shutdown(&self) {
         if let Ok(mut c) = self.inner.write() {
-            if let Some(handle) = c.rocket_shutdown_handle.take() {
+            for handle in c.rocket_shutdown_handles.drain(..) {
                 handle.notify();
             }
         }
     }
 }
 
-use handlebars::{
-    Context, DirectorySourceOptions, Handlebars, Helper, HelperResult, Output, RenderContext, RenderErrorReason,
-    Renderable,
-};
+use handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderErrorReason, Renderable};
 
 fn load_templates<P>(path: P) -> Handlebars<'static>
 where
@@ -1284,16 +2179,22 @@ where
     reg!("email/email_footer");
     reg!("email/email_footer_text");
 
+    reg!("email/admin_reset_2fa", ".html");
     reg!("email/admin_reset_password", ".html");
     reg!("email/change_email", ".html");
     reg!("email/delete_account", ".html");
+    reg!("email/digest", ".html");
     reg!("email/emergency_access_invite_accepted", ".html");
     reg!("email/emergency_access_invite_confirmed", ".html");
+    reg!("email/emergency_access_key_invalid", ".html");
+    reg!("email/emergency_access_reconfirm_required", ".html");
     reg!("email/emergency_access_recovery_approved", ".html");
+    reg!("email/emergency_access_recovery_escalation", ".html");
     reg!("email/emergency_access_recovery_initiated", ".html");
     reg!("email/emergency_access_recovery_rejected", ".html");
     reg!("email/emergency_access_recovery_reminder", ".html");
     reg!("email/emergency_access_recovery_timed_out", ".html");
+    reg!("email/emergency_access_revoked", ".html");
     reg!("email/incomplete_2fa_login", ".html");
     reg!("email/invite_accepted", ".html");
     reg!("email/invite_confirmed", ".html");
@@ -1301,6 +2202,12 @@ where
     reg!("email/protected_action", ".html");
     reg!("email/pw_hint_none", ".html");
     reg!("email/pw_hint_some", ".html");
+    reg!("email/send_2fa_policy_reminder", ".html");
+    reg!("email/send_2fa_provider_required_by_org", ".html");
+    reg!("email/send_2fa_recovery_canceled", ".html");
+    reg!("email/send_2fa_recovery_completed", ".html");
+    reg!("email/send_2fa_recovery_request", ".html");
+    reg!("email/send_2fa_recovery_scheduled", ".html");
     reg!("email/send_2fa_removed_from_org", ".html");
     reg!("email/send_emergency_access_invite", ".html");
     reg!("email/send_org_invite", ".html");
@@ -1313,28 +2220,69 @@ where
 
     reg!("admin/base");
     reg!("admin/login");
+    reg!("admin/setup");
     reg!("admin/settings");
     reg!("admin/users");
     reg!("admin/organizations");
+    reg!("admin/mail_log");
+    reg!("admin/jobs");
     reg!("admin/diagnostics");
+    reg!("admin/route_stats");
+    reg!("admin/user_events");
 
     reg!("404");
+    reg!("503");
 
     // And then load user templates to overwrite the defaults
-    // Use .hbs extension for the files
-    // Templates get registered with their relative name
-    hb.register_templates_directory(
-        path,
-        DirectorySourceOptions {
-            tpl_extension: ".hbs".to_owned(),
-            ..Default::default()
-        },
-    )
-    .unwrap();
+    register_template_overrides(&mut hb, path.as_ref());
 
     hb
 }
 
+// The templates of the folder are registered with their relative path as name, without the `.hbs` extension.
+// The invalid ones are skipped, so the built-in templates are still used instead of failing to start.
+fn register_template_overrides(hb: &mut Handlebars<'static>, folder: &std::path::Path) {
+    let mut dirs = vec![folder.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if path.file_name().is_some_and(|file_name| file_name.to_string_lossy().starts_with('.')) {
+                continue;
+            }
+            if path.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            let Some(name) =
+                path.strip_prefix(folder).ok().and_then(|p| p.to_str()).and_then(|p| p.strip_suffix(".hbs"))
+            else {
+                continue;
+            };
+            let name = name.replace(std::path::MAIN_SEPARATOR, "/");
+
+            // The translations of the mails are `email/<locale>/<name>`, they fall back to the built-in `email/<name>`
+            let builtin = hb.has_template(&name)
+                || name
+                    .strip_prefix("email/")
+                    .and_then(|name| name.split_once('/'))
+                    .is_some_and(|(_, name)| hb.has_template(&format!("email/{name}")));
+            let result = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|template| hb.register_template_string(&name, template).map_err(|e| e.to_string()));
+            match result {
+                Err(e) if builtin => {
+                    println!("[ERROR] The template `{name}` is invalid, the built-in one is used: {e}")
+                }
+                Err(e) => println!("[ERROR] The template `{name}` is invalid: {e}"),
+                Ok(()) if !builtin => println!("[WARNING] The template `{name}` doesn't replace a built-in template"),
+                Ok(()) => (),
+            }
+        }
+    }
+}
+
 fn case_helper<'reg, 'rc>(
     h: &Helper<'rc>,
     r: &'reg Handlebars<'_>,
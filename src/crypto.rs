@@ -33,6 +33,76 @@ pub fn hmac_sign(key: &str, data: &str) -> String {
     HEXLOWER.encode(signature.as_ref())
 }
 
+pub fn hmac_sha256_sign(key: &str, data: &str) -> String {
+    HEXLOWER.encode(&hmac_sha256(key.as_bytes(), data.as_bytes()))
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, data).as_ref().to_vec()
+}
+
+//
+// Symmetric encryption
+//
+
+/// Encrypts data with AES-256-GCM. The additional data isn't encrypted, but needs to match during the decryption.
+/// Returns the nonce followed by the ciphertext and tag, base64 encoded.
+pub fn encrypt_aes_gcm(key: &[u8], plaintext: &[u8], additional_data: &[u8]) -> String {
+    use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+
+    let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).expect("Invalid AES-256-GCM key length"));
+    let nonce_bytes = get_random_bytes::<NONCE_LEN>();
+
+    let mut in_out = plaintext.to_vec();
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce_bytes), Aad::from(additional_data), &mut in_out)
+        .expect("Error encrypting data");
+
+    let mut out = nonce_bytes.to_vec();
+    out.extend(in_out);
+    data_encoding::BASE64.encode(&out)
+}
+
+/// Decrypts data encrypted by `encrypt_aes_gcm`, returns None if the data, key or additional data are not valid.
+pub fn decrypt_aes_gcm(key: &[u8], encrypted: &str, additional_data: &[u8]) -> Option<Vec<u8>> {
+    use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+
+    let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).ok()?);
+    let data = data_encoding::BASE64.decode(encrypted.as_bytes()).ok()?;
+    if data.len() < NONCE_LEN {
+        return None;
+    }
+
+    let (nonce_bytes, ciphertext) = data.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce_bytes).ok()?;
+    let mut in_out = ciphertext.to_vec();
+    let plaintext = key.open_in_place(nonce, Aad::from(additional_data), &mut in_out).ok()?;
+    Some(plaintext.to_vec())
+}
+
+//
+// Public keys
+//
+
+/// Returns the size in bytes of a base64 encoded RSA public key (SPKI DER), as created by the clients.
+pub fn rsa_public_key_size(public_key: &str) -> Option<usize> {
+    let der = data_encoding::BASE64.decode(public_key.as_bytes()).ok()?;
+    let rsa = openssl::pkey::PKey::public_key_from_der(&der).ok()?.rsa().ok()?;
+    Some(rsa.size() as usize)
+}
+
+/// Returns a fingerprint of a public key, which can be stored to detect if the key has changed.
+pub fn public_key_fingerprint(public_key: &str) -> String {
+    sha256_hex(public_key.as_bytes())
+}
+
+//
+// Hashing
+//
+pub fn sha256_hex(data: &[u8]) -> String {
+    HEXLOWER.encode(digest::digest(&digest::SHA256, data).as_ref())
+}
+
 //
 // Random values
 //
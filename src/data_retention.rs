@@ -0,0 +1,48 @@
+//
+// Retention of the data which accumulates over time
+//
+// A single scheduled job deletes the rows past the retention window of their data class. The windows are read on
+// each run, so they can be changed from the admin panel without a restart. The auth requests have no window, they
+// expire after a few minutes and the expired ones are always deleted. The Sends only keep their access count.
+//
+use chrono::{TimeDelta, Utc};
+
+use crate::{
+    db::{
+        models::{AuthRequest, Device, Event, MailLog, Tombstone},
+        DbPool,
+    },
+    CONFIG,
+};
+
+pub async fn retention_job(pool: DbPool) {
+    debug!("Start data retention job");
+    let Ok(mut conn) = pool.get().await else {
+        error!("Failed to get DB connection while applying the data retention");
+        return;
+    };
+
+    if CONFIG.events_days_retain().is_some() {
+        if let Err(e) = Event::clean_events(&mut conn).await {
+            error!("Error cleaning old events: {e}");
+        }
+    }
+
+    if let Some(days) = CONFIG.devices_days_retain() {
+        let dt = Utc::now().naive_utc() - TimeDelta::try_days(days).unwrap();
+        if let Err(e) = Device::delete_inactive_before(&dt, &mut conn).await {
+            error!("Error removing inactive devices: {e}");
+        }
+    }
+
+    AuthRequest::purge_expired_auth_requests(&mut conn).await;
+
+    if let Err(e) = Tombstone::purge_old(&mut conn).await {
+        error!("Error purging old tombstones: {e}");
+    }
+
+    let dt = Utc::now().naive_utc() - TimeDelta::try_days(CONFIG.mail_log_days_retain()).unwrap();
+    if let Err(e) = MailLog::delete_older_than(&dt, &mut conn).await {
+        error!("Error removing old mail log entries: {e}");
+    }
+}
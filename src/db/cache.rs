@@ -0,0 +1,164 @@
+//
+// In-memory cache of the rows read by most requests
+//
+// The user of each authenticated request and the policies of the organizations are kept for `DB_CACHE_TTL` seconds.
+// The models remove the entries after they write the rows, and the removals are published on the pub/sub bus for the
+// other instances sharing the database. Without the bus their changes are only seen once the entries expire, so the
+// cache is disabled by default then. The changes made by the commands like `encrypt-data` are also only seen once the
+// entries expire. The devices of the authenticated sessions are kept for `AUTH_SESSION_CACHE_TTL` seconds, and removed
+// the same way when a device is deleted or logged out. The cached devices are only read, the handlers load the device
+// again before saving it.
+//
+// A row read before an entry is removed can be older than the write which removed it. Each cache counts its removals,
+// and a row is only cached if none happened since before it was read.
+//
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+
+use super::models::{Device, OrgPolicy, User};
+use crate::CONFIG;
+
+// The expired entries are only removed once a cache is full
+const MAX_ENTRIES: usize = 10_000;
+
+// A removal, as published for the other instances
+#[derive(Serialize, Deserialize)]
+pub enum Invalidation {
+    Key(String),
+    Prefix(String),
+    All,
+}
+
+pub struct TtlCache<V> {
+    // Identifies the cache in the published removals
+    name: &'static str,
+    entries: Mutex<HashMap<String, (Instant, V)>>,
+    // Incremented by every removal, while holding the lock of the entries
+    generation: AtomicU64,
+    // The TTL in seconds, read from the config
+    ttl_secs: fn() -> u64,
+}
+
+impl<V: Clone> TtlCache<V> {
+    fn new(name: &'static str, ttl_secs: fn() -> u64) -> Self {
+        Self {
+            name,
+            entries: Mutex::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+            ttl_secs,
+        }
+    }
+
+    fn ttl(&self) -> Option<Duration> {
+        Some(Duration::from_secs((self.ttl_secs)())).filter(|ttl| !ttl.is_zero())
+    }
+
+    pub fn get(&self, key: &str) -> Option<V> {
+        let ttl = self.ttl()?;
+        let entries = self.entries.lock().unwrap();
+        entries.get(key).filter(|(cached_at, _)| cached_at.elapsed() < ttl).map(|(_, value)| value.clone())
+    }
+
+    /// To be read before reading the row which is then inserted.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Caches the value, unless an entry was removed since `generation` was read, the value could then be stale.
+    pub fn insert(&self, key: &str, value: V, generation: u64) {
+        let Some(ttl) = self.ttl() else {
+            return;
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if self.generation.load(Ordering::Acquire) != generation {
+            return;
+        }
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, (cached_at, _)| cached_at.elapsed() < ttl);
+            if entries.len() >= MAX_ENTRIES {
+                entries.clear();
+            }
+        }
+        entries.insert(key.to_string(), (Instant::now(), value));
+    }
+
+    pub fn invalidate(&self, key: &str) {
+        self.invalidate_and_publish(Invalidation::Key(key.to_string()));
+    }
+
+    /// Removes the entries whose key starts with the prefix.
+    pub fn invalidate_prefix(&self, prefix: &str) {
+        self.invalidate_and_publish(Invalidation::Prefix(prefix.to_string()));
+    }
+
+    pub fn clear(&self) {
+        self.invalidate_and_publish(Invalidation::All);
+    }
+
+    fn invalidate_and_publish(&self, invalidation: Invalidation) {
+        self.invalidate_local(&invalidation);
+        // Also when this cache is disabled, the other instances may have enabled it
+        crate::pubsub::publish_cache_invalidation(self.name, invalidation);
+    }
+
+    fn invalidate_local(&self, invalidation: &Invalidation) {
+        let mut entries = self.entries.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        match invalidation {
+            Invalidation::Key(key) => {
+                entries.remove(key);
+            }
+            Invalidation::Prefix(prefix) => entries.retain(|key, _| !key.starts_with(prefix.as_str())),
+            Invalidation::All => entries.clear(),
+        }
+    }
+}
+
+/// The users, by uuid.
+pub static USERS: Lazy<TtlCache<User>> = Lazy::new(|| TtlCache::new("users", || CONFIG.db_cache_ttl()));
+
+/// The policies of the organizations, by organization uuid.
+pub static ORG_POLICIES: Lazy<TtlCache<Vec<OrgPolicy>>> =
+    Lazy::new(|| TtlCache::new("org_policies", || CONFIG.db_cache_ttl()));
+
+/// The devices of the authenticated sessions, by `session_key`.
+pub static SESSIONS: Lazy<TtlCache<Device>> =
+    Lazy::new(|| TtlCache::new("sessions", || CONFIG.auth_session_cache_ttl()));
+
+pub fn session_key(user_uuid: &str, device_uuid: &str, security_stamp: &str) -> String {
+    format!("{user_uuid}/{device_uuid}/{security_stamp}")
+}
+
+/// Removes the sessions of the user, or only the ones of a device.
+pub fn invalidate_sessions(user_uuid: &str, device_uuid: Option<&str>) {
+    match device_uuid {
+        Some(device_uuid) => SESSIONS.invalidate_prefix(&format!("{user_uuid}/{device_uuid}/")),
+        None => SESSIONS.invalidate_prefix(&format!("{user_uuid}/")),
+    }
+}
+
+/// Applies a removal published by another instance.
+pub fn invalidate_remote(cache: &str, invalidation: &Invalidation) {
+    match cache {
+        "users" => USERS.invalidate_local(invalidation),
+        "org_policies" => ORG_POLICIES.invalidate_local(invalidation),
+        "sessions" => SESSIONS.invalidate_local(invalidation),
+        // Not kept by this version
+        _ => {}
+    }
+}
+
+/// Empties the caches without publishing it, when the removals published by the other instances could have been missed.
+pub fn clear_local() {
+    USERS.invalidate_local(&Invalidation::All);
+    ORG_POLICIES.invalidate_local(&Invalidation::All);
+    SESSIONS.invalidate_local(&Invalidation::All);
+}
@@ -0,0 +1,184 @@
+//
+// Coalescing of the frequent small writes
+//
+// With `WRITE_COALESCE_WINDOW_MS`, the revision bumps of the users and the last activity of the devices are kept in
+// memory and written in batches once per window, instead of one UPDATE per change. The changes of the same row within
+// a window are written once. The users read by uuid or email on this instance get their pending revision right away,
+// the other instances sharing the database only see it after the flush.
+// The pending writes are flushed when the server stops. They're lost if it crashes, the clients then only notice the
+// changes of the last window on their next sync.
+//
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    sync::Mutex,
+    time::Duration,
+};
+
+use chrono::{NaiveDateTime, Utc};
+use once_cell::sync::{Lazy, OnceCell};
+
+use super::{cache, DbConn, DbPool};
+use crate::{api::EmptyResult, error::MapResult, CONFIG};
+
+// The rows updated by each statement
+const FLUSH_BATCH_SIZE: usize = 500;
+
+#[derive(Default)]
+struct Pending {
+    // The latest revision of the users, by uuid
+    revisions: HashMap<String, NaiveDateTime>,
+    // The devices used since the last flush, by user uuid
+    devices: HashMap<String, HashSet<String>>,
+}
+
+static PENDING: Lazy<Mutex<Pending>> = Lazy::new(Mutex::default);
+static POOL: OnceCell<DbPool> = OnceCell::new();
+
+fn window() -> Option<Duration> {
+    Some(Duration::from_millis(CONFIG.write_coalesce_window_ms())).filter(|window| !window.is_zero())
+}
+
+pub fn enabled() -> bool {
+    window().is_some()
+}
+
+/// Starts flushing the pending writes once per window, nothing when the coalescing is disabled.
+pub fn init(pool: DbPool) {
+    let Some(window) = window() else {
+        return;
+    };
+    if POOL.set(pool.clone()).is_err() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(window);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            flush_with(&pool).await;
+        }
+    });
+}
+
+/// Buffers a new revision of the user, the caller checked that the coalescing is enabled.
+pub fn bump_revision(user_uuid: &str, date: NaiveDateTime) {
+    let mut pending = PENDING.lock().unwrap();
+    let revision = pending.revisions.entry(user_uuid.to_string()).or_insert(date);
+    *revision = (*revision).max(date);
+}
+
+/// The revision of the user which isn't written yet.
+pub fn pending_revision(user_uuid: &str) -> Option<NaiveDateTime> {
+    if !enabled() {
+        return None;
+    }
+    PENDING.lock().unwrap().revisions.get(user_uuid).copied()
+}
+
+/// Buffers the use of a device by an authenticated request, its last activity is written on the next flush.
+pub fn touch_device(user_uuid: &str, device_uuid: &str) {
+    if !enabled() {
+        return;
+    }
+    let mut pending = PENDING.lock().unwrap();
+    pending.devices.entry(user_uuid.to_string()).or_default().insert(device_uuid.to_string());
+}
+
+/// Writes the pending writes before the server stops.
+pub async fn flush() {
+    if let Some(pool) = POOL.get() {
+        flush_with(pool).await;
+    }
+}
+
+async fn flush_with(pool: &DbPool) {
+    let (revisions, devices) = {
+        let mut pending = PENDING.lock().unwrap();
+        // The revisions stay pending until they're written, for the users read in the meantime
+        (pending.revisions.clone(), mem::take(&mut pending.devices))
+    };
+    if revisions.is_empty() && devices.is_empty() {
+        return;
+    }
+
+    let mut conn = match pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get DB connection while flushing the coalesced writes: {e}");
+            return;
+        }
+    };
+
+    if !revisions.is_empty() {
+        let uuids: Vec<String> = revisions.keys().cloned().collect();
+        match write_revisions(&uuids, &mut conn).await {
+            Ok(()) => {
+                let mut pending = PENDING.lock().unwrap();
+                // The users bumped again since the copy keep their new revision pending
+                pending.revisions.retain(|uuid, date| revisions.get(uuid).map_or(true, |written| date > written));
+                drop(pending);
+                for uuid in &uuids {
+                    cache::USERS.invalidate(uuid);
+                }
+            }
+            Err(e) => error!("Error writing the coalesced revisions of {} users: {e:#?}", uuids.len()),
+        }
+    }
+
+    let device_count: usize = devices.values().map(HashSet::len).sum();
+    let failed = write_devices_activity(devices, &mut conn).await;
+    if !failed.is_empty() {
+        let failed_count: usize = failed.values().map(HashSet::len).sum();
+        error!("Failed to write the coalesced activity of {failed_count} devices, retrying on the next flush");
+        // Merged with the devices used since the copy
+        let mut pending = PENDING.lock().unwrap();
+        for (user_uuid, device_uuids) in failed {
+            pending.devices.entry(user_uuid).or_default().extend(device_uuids);
+        }
+    }
+    debug!("Flushed the coalesced writes of {} users and {device_count} devices", revisions.len());
+}
+
+// The flush is later than every buffered change, the clients which synced in between will sync once more
+async fn write_revisions(uuids: &[String], conn: &mut DbConn) -> EmptyResult {
+    let now = Utc::now().naive_utc();
+    for batch in uuids.chunks(FLUSH_BATCH_SIZE) {
+        db_run! {conn: {
+            crate::util::retry(|| {
+                diesel::update(users::table.filter(users::uuid.eq_any(batch)))
+                    .set(users::updated_at.eq(now))
+                    .execute(conn)
+            }, 10)
+            .map_res("Error updating the revision of the users")
+        }}?;
+    }
+    Ok(())
+}
+
+// Returns the devices which couldn't be written, by user uuid
+async fn write_devices_activity(
+    devices: HashMap<String, HashSet<String>>,
+    conn: &mut DbConn,
+) -> HashMap<String, HashSet<String>> {
+    let now = Utc::now().naive_utc();
+    let mut failed = HashMap::new();
+    for (user_uuid, device_uuids) in devices {
+        let uuids: Vec<String> = device_uuids.iter().cloned().collect();
+        let res: EmptyResult = db_run! {conn: {
+            diesel::update(
+                devices::table
+                    .filter(devices::user_uuid.eq(&user_uuid))
+                    .filter(devices::uuid.eq_any(&uuids)),
+            )
+            .set(devices::updated_at.eq(now))
+            .execute(conn)
+            .map_res("Error updating the activity of the devices")
+        }};
+        if let Err(e) = res {
+            error!("Error writing the coalesced activity of the devices of {user_uuid}: {e:#?}");
+            failed.insert(user_uuid, device_uuids);
+        }
+    }
+    failed
+}
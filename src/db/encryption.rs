@@ -0,0 +1,175 @@
+//
+// Encryption at rest of the sensitive columns
+//
+// The values are encrypted with AES-256-GCM by the first key of `DATABASE_ENCRYPTION_KEYS`, and stored as
+// `enc.v2:<key id>:<base64>`. The other keys are only used to read the values they encrypted, until
+// `vaultwarden encrypt-data` re-encrypts them with the first key. The values are bound to their column and row, so
+// they can't be copied to another row.
+//
+// `TWO_FACTOR_DATA_SECRET` is still supported, as the `2fa` key, and is used to read the `enc.v1:` values it
+// encrypted before, which were only bound to the user.
+//
+use once_cell::sync::Lazy;
+
+use crate::{crypto, CONFIG};
+
+const PREFIX_V1: &str = "enc.v1:";
+const PREFIX_V2: &str = "enc.v2:";
+const LEGACY_KEY_ID: &str = "2fa";
+
+#[derive(Copy, Clone)]
+pub enum EncryptedColumn {
+    TwoFactorData,
+    UserApiKey,
+    DevicePushToken,
+    OrganizationApiKey,
+}
+
+impl EncryptedColumn {
+    fn name(self) -> &'static str {
+        match self {
+            Self::TwoFactorData => "twofactor.data",
+            Self::UserApiKey => "users.api_key",
+            Self::DevicePushToken => "devices.push_token",
+            Self::OrganizationApiKey => "organization_api_key.api_key",
+        }
+    }
+}
+
+struct Key {
+    id: String,
+    key: Vec<u8>,
+}
+
+// The configured keys, the first one encrypts the new values
+static KEYS: Lazy<Vec<Key>> = Lazy::new(|| {
+    let mut keys: Vec<Key> = match CONFIG.database_encryption_keys() {
+        Some(config) => parse_keys(&config)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(id, secret)| Key {
+                id,
+                key: crypto::hash_password(secret.as_bytes(), b"vaultwarden_database_encryption", 100_000),
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    if let Some(secret) = CONFIG.two_factor_data_secret() {
+        keys.push(Key {
+            id: LEGACY_KEY_ID.to_string(),
+            key: crypto::hash_password(secret.as_bytes(), b"vaultwarden_twofactor_data", 100_000),
+        });
+    }
+    keys
+});
+
+fn parse_keys(config: &str) -> Result<Vec<(String, String)>, String> {
+    let mut keys: Vec<(String, String)> = Vec::new();
+    for entry in config.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((id, secret)) = entry.split_once(':') else {
+            return Err("`DATABASE_ENCRYPTION_KEYS` entries need to be formatted as `<id>:<secret>`".to_string());
+        };
+        // The id is stored with each value, some of the columns are limited to 255 characters
+        if id.is_empty() || id.len() > 32 || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            return Err(format!("The key id `{id}` needs up to 32 letters, digits, `_` or `-`"));
+        }
+        if id == LEGACY_KEY_ID {
+            return Err(format!("The key id `{LEGACY_KEY_ID}` is reserved for the `TWO_FACTOR_DATA_SECRET`"));
+        }
+        if keys.iter().any(|(other, _)| other == id) {
+            return Err(format!("The key id `{id}` is used more than once"));
+        }
+        if secret.len() < 16 {
+            return Err(format!("The secret of the key `{id}` should be at least 16 characters long"));
+        }
+        keys.push((id.to_string(), secret.to_string()));
+    }
+    Ok(keys)
+}
+
+/// Checks the `DATABASE_ENCRYPTION_KEYS` setting, a comma separated list of `<id>:<secret>` keys.
+pub fn validate_config(config: &str) -> Result<(), String> {
+    if parse_keys(config)?.is_empty() {
+        return Err("`DATABASE_ENCRYPTION_KEYS` doesn't contain any key".to_string());
+    }
+    Ok(())
+}
+
+fn additional_data(column: EncryptedColumn, row_uuid: &str) -> String {
+    format!("{}:{row_uuid}", column.name())
+}
+
+pub fn is_encrypted(stored: &str) -> bool {
+    stored.starts_with(PREFIX_V1) || stored.starts_with(PREFIX_V2)
+}
+
+/// Whether the value was encrypted by `TWO_FACTOR_DATA_SECRET`, it's then bound to the user instead of the row.
+pub fn is_legacy(stored: &str) -> bool {
+    stored.starts_with(PREFIX_V1)
+}
+
+/// Encrypts a value to store it, when a key is configured.
+/// Values which are already encrypted, like the ones which couldn't be decrypted, are returned as is.
+pub fn encrypt(column: EncryptedColumn, row_uuid: &str, value: &str) -> String {
+    match KEYS.first() {
+        Some(key) if !is_encrypted(value) => {
+            let aad = additional_data(column, row_uuid);
+            format!("{PREFIX_V2}{}:{}", key.id, crypto::encrypt_aes_gcm(&key.key, value.as_bytes(), aad.as_bytes()))
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Decrypts a stored value, the values stored before a key was configured are returned as is.
+/// The `enc.v1:` values are checked against the given uuid as is, it needs to be the user of the 2FA row.
+/// Returns None when the key which encrypted it isn't configured anymore, or the value was altered.
+pub fn decrypt(column: EncryptedColumn, row_uuid: &str, stored: &str) -> Option<String> {
+    let (key_id, encrypted, aad) = if let Some(rest) = stored.strip_prefix(PREFIX_V2) {
+        let (key_id, encrypted) = rest.split_once(':')?;
+        (key_id, encrypted, additional_data(column, row_uuid))
+    } else if let Some(encrypted) = stored.strip_prefix(PREFIX_V1) {
+        (LEGACY_KEY_ID, encrypted, row_uuid.to_string())
+    } else {
+        return Some(stored.to_string());
+    };
+
+    let key = KEYS.iter().find(|k| k.id == key_id)?;
+    crypto::decrypt_aes_gcm(&key.key, encrypted, aad.as_bytes()).and_then(|data| String::from_utf8(data).ok())
+}
+
+/// Same as `decrypt`, but logs the error and returns the stored value when it can't be decrypted.
+pub fn decrypt_or_log(column: EncryptedColumn, row_uuid: &str, stored: &str) -> String {
+    decrypt(column, row_uuid, stored).unwrap_or_else(|| {
+        error!("Unable to decrypt {} of {row_uuid}, check the DATABASE_ENCRYPTION_KEYS", column.name());
+        stored.to_string()
+    })
+}
+
+/// The `LIKE` pattern of the stored values which don't need to be encrypted again, None when no key is configured.
+/// Without `rotate`, only the plain values need to be encrypted, else also the ones encrypted by an older key.
+pub fn up_to_date_pattern(rotate: bool) -> Option<String> {
+    let key = KEYS.first()?;
+    Some(match rotate {
+        true => format!("{PREFIX_V2}{}:%", key.id),
+        false => "enc.v_:%".to_string(),
+    })
+}
+
+/// Encrypts the values stored before a key was configured, and with `rotate` also re-encrypts the ones encrypted by
+/// an older key. Returns the number of values which were updated.
+pub async fn encrypt_all_data(rotate: bool, conn: &mut super::DbConn) -> Result<usize, crate::Error> {
+    use super::models::{Device, OrganizationApiKey, TwoFactor, User};
+
+    if up_to_date_pattern(rotate).is_none() {
+        return Ok(0);
+    }
+
+    let count = TwoFactor::encrypt_stored_data(rotate, conn).await?
+        + User::encrypt_stored_data(rotate, conn).await?
+        + Device::encrypt_stored_data(rotate, conn).await?
+        + OrganizationApiKey::encrypt_stored_data(rotate, conn).await?;
+    if count > 0 {
+        info!("Encrypted {count} values stored in the database");
+    }
+    Ok(count)
+}
@@ -1,4 +1,10 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use diesel::{
     connection::SimpleConnection,
@@ -16,8 +22,12 @@ use tokio::{
     time::timeout,
 };
 
+use rand::Rng;
+use serde_json::Value;
+
 use crate::{
     error::{Error, MapResult},
+    log_context::{Phase, PhaseTimer},
     CONFIG,
 };
 
@@ -60,6 +70,8 @@ macro_rules! generate_connections {
         pub struct DbConn {
             conn: Arc<Mutex<Option<DbConnInner>>>,
             permit: Option<OwnedSemaphorePermit>,
+            // The span of the request, the parent of the spans of the queries
+            span: tracing::Span,
         }
 
         #[allow(non_camel_case_types)]
@@ -85,13 +97,27 @@ macro_rules! generate_connections {
         pub struct DbPool {
             // This is an 'Option' so that we can drop the pool in a 'spawn_blocking'.
             pool: Option<DbPoolInner>,
-            semaphore: Arc<Semaphore>
+            semaphore: Arc<Semaphore>,
+            stats: Arc<PoolStats>,
+            // The read replica, for the read-only queries
+            replica: Option<DbPoolInner>,
+            replica_semaphore: Arc<Semaphore>,
+            replica_stats: Arc<PoolStats>,
         }
 
         #[allow(non_camel_case_types)]
         #[derive(Clone)]
         pub enum DbPoolInner { $( #[cfg($name)] $name(Pool<ConnectionManager< $ty >>), )+ }
 
+        impl DbPoolInner {
+            fn state(&self) -> diesel::r2d2::State {
+                match self { $(
+                    #[cfg($name)]
+                    DbPoolInner::$name(p) => p.state(),
+                )+ }
+            }
+        }
+
         impl Drop for DbConn {
             fn drop(&mut self) {
                 let conn = Arc::clone(&self.conn);
@@ -116,33 +142,61 @@ macro_rules! generate_connections {
         impl Drop for DbPool {
             fn drop(&mut self) {
                 let pool = self.pool.take();
-                tokio::task::spawn_blocking(move || drop(pool));
+                let replica = self.replica.take();
+                tokio::task::spawn_blocking(move || {
+                    drop(pool);
+                    drop(replica);
+                });
             }
         }
 
         impl DbPool {
             // For the given database URL, guess its type, run migrations, create pool, and return it
             pub fn from_config() -> Result<Self, Error> {
-                let url = CONFIG.database_url();
-                let conn_type = DbConnType::from_url(&url)?;
+                Self::from_url(&CONFIG.database_url(), CONFIG.database_replica_url(), CONFIG.database_auto_migrate())
+            }
+
+            pub fn from_url(url: &str, replica_url: Option<String>, migrate: bool) -> Result<Self, Error> {
+                let conn_type = DbConnType::from_url(url)?;
 
                 match conn_type { $(
                     DbConnType::$name => {
                         #[cfg($name)]
                         {
-                            paste::paste!{ [< $name _migrations >]::run_migrations()?; }
-                            let manager = ConnectionManager::new(&url);
-                            let pool = Pool::builder()
-                                .max_size(CONFIG.database_max_conns())
-                                .connection_timeout(Duration::from_secs(CONFIG.database_timeout()))
-                                .connection_customizer(Box::new(DbConnOptions{
-                                    init_stmts: conn_type.get_init_stmts()
-                                }))
-                                .build(manager)
-                                .map_res("Failed to create pool")?;
+                            if migrate {
+                                paste::paste!{ [< $name _migrations >]::run_migrations(url)?; }
+                            }
+                            let build_pool = |url: &str| {
+                                let manager = ConnectionManager::<$ty>::new(url);
+                                Pool::builder()
+                                    .max_size(CONFIG.database_max_conns())
+                                    .connection_timeout(Duration::from_secs(CONFIG.database_timeout()))
+                                    .connection_customizer(Box::new(DbConnOptions{
+                                        init_stmts: conn_type.get_init_stmts()
+                                    }))
+                                    .build(manager)
+                                    .map_res("Failed to create pool")
+                            };
+                            let pool = build_pool(url)?;
+
+                            // The migrations reach the replica through the replication
+                            let replica = match replica_url {
+                                Some(replica_url) => {
+                                    if conn_type == DbConnType::sqlite || DbConnType::from_url(&replica_url)? != conn_type {
+                                        err!("`DATABASE_REPLICA_URL` needs to be a MySQL or PostgreSQL URL, of the same type as `DATABASE_URL`")
+                                    }
+                                    Some(DbPoolInner::$name(build_pool(&replica_url)?))
+                                }
+                                None => None,
+                            };
+
                             Ok(DbPool {
                                 pool: Some(DbPoolInner::$name(pool)),
                                 semaphore: Arc::new(Semaphore::new(CONFIG.database_max_conns() as usize)),
+                                stats: Arc::new(PoolStats::default()),
+                                replica,
+                                replica_semaphore: Arc::new(Semaphore::new(CONFIG.database_max_conns() as usize)),
+                                replica_stats: Arc::new(PoolStats::default()),
                             })
                         }
                         #[cfg(not($name))]
@@ -152,27 +206,78 @@ macro_rules! generate_connections {
             }
             // Get a connection from the pool
             pub async fn get(&self) -> Result<DbConn, Error> {
-                let duration = Duration::from_secs(CONFIG.database_timeout());
-                let permit = match timeout(duration, Arc::clone(&self.semaphore).acquire_owned()).await {
+                self.get_timeout(Duration::from_secs(CONFIG.database_timeout())).await
+            }
+
+            pub async fn get_timeout(&self, duration: Duration) -> Result<DbConn, Error> {
+                let pool = self.pool.as_ref().expect("DbPool.pool should always be Some()");
+                Self::get_from(pool, &self.semaphore, &self.stats, duration).await
+            }
+
+            // Get a connection for read-only queries, from the read replica when there is one.
+            // Falls back to the primary database when the replica is unavailable.
+            pub async fn get_read(&self) -> Result<DbConn, Error> {
+                self.get_read_timeout(Duration::from_secs(CONFIG.database_timeout())).await
+            }
+
+            pub async fn get_read_timeout(&self, duration: Duration) -> Result<DbConn, Error> {
+                if let Some(replica) = &self.replica {
+                    match Self::get_from(replica, &self.replica_semaphore, &self.replica_stats, duration).await {
+                        Ok(conn) => return Ok(conn),
+                        Err(e) => warn!("Error getting a read replica connection, using the primary database: {e:?}"),
+                    }
+                }
+                self.get_timeout(duration).await
+            }
+
+            async fn get_from(
+                pool: &DbPoolInner,
+                semaphore: &Arc<Semaphore>,
+                stats: &PoolStats,
+                duration: Duration,
+            ) -> Result<DbConn, Error> {
+                let start = Instant::now();
+                let permit = match timeout(duration, Arc::clone(semaphore).acquire_owned()).await {
                     Ok(p) => p.expect("Semaphore should be open"),
                     Err(_) => {
+                        stats.timeouts.fetch_add(1, Ordering::Relaxed);
                         err!("Timeout waiting for database connection");
                     }
                 };
 
-                match self.pool.as_ref().expect("DbPool.pool should always be Some()") {  $(
+                match pool {  $(
                     #[cfg($name)]
                     DbPoolInner::$name(p) => {
                         let pool = p.clone();
-                        let c = run_blocking(move || pool.get_timeout(duration)).await.map_res("Error retrieving connection from pool")?;
+                        // The permit was maybe only given after a while, the pool only gets the time left
+                        let left = duration.saturating_sub(start.elapsed());
+                        let c = match run_blocking(move || pool.get_timeout(left)).await {
+                            Ok(c) => c,
+                            Err(e) => {
+                                stats.timeouts.fetch_add(1, Ordering::Relaxed);
+                                err!(format!("Error retrieving connection from pool: {e}"))
+                            }
+                        };
+                        stats.record_wait(start.elapsed());
 
                         Ok(DbConn {
                             conn: Arc::new(Mutex::new(Some(DbConnInner::$name(c)))),
-                            permit: Some(permit)
+                            permit: Some(permit),
+                            span: tracing::Span::none(),
                         })
                     },
                 )+ }
             }
+
+            /// The state of the connection pools, for the diagnostics page.
+            pub fn stats_json(&self) -> Value {
+                let pool = self.pool.as_ref().expect("DbPool.pool should always be Some()");
+                json!({
+                    "primary": self.stats.to_json(pool.state()),
+                    "replica": self.replica.as_ref().map(|replica| self.replica_stats.to_json(replica.state())),
+                    "retries": self.stats.retries.load(Ordering::Relaxed),
+                })
+            }
         }
     };
 }
@@ -191,6 +296,41 @@ generate_connections! {
     postgresql: diesel_logger::LoggingConnection<diesel::pg::PgConnection>
 }
 
+/// Counters of a connection pool, since the start of the server
+#[derive(Default)]
+pub struct PoolStats {
+    acquired: AtomicU64,
+    timeouts: AtomicU64,
+    // The attempts of the requests to get a connection again, see `get_request_conn`
+    retries: AtomicU64,
+    wait_micros_total: AtomicU64,
+    wait_micros_max: AtomicU64,
+}
+
+impl PoolStats {
+    fn record_wait(&self, wait: Duration) {
+        let micros = u64::try_from(wait.as_micros()).unwrap_or(u64::MAX);
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        self.wait_micros_total.fetch_add(micros, Ordering::Relaxed);
+        self.wait_micros_max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    fn to_json(&self, state: diesel::r2d2::State) -> Value {
+        let acquired = self.acquired.load(Ordering::Relaxed);
+        let wait_micros_total = self.wait_micros_total.load(Ordering::Relaxed);
+        json!({
+            "connections": state.connections,
+            "in_use": state.connections - state.idle_connections,
+            "idle": state.idle_connections,
+            "max": CONFIG.database_max_conns(),
+            "acquired": acquired,
+            "timeouts": self.timeouts.load(Ordering::Relaxed),
+            "avg_wait_ms": if acquired > 0 { wait_micros_total / acquired / 1000 } else { 0 },
+            "max_wait_ms": self.wait_micros_max.load(Ordering::Relaxed) / 1000,
+        })
+    }
+}
+
 impl DbConnType {
     pub fn from_url(url: &str) -> Result<DbConnType, Error> {
         // Mysql
@@ -253,6 +393,7 @@ macro_rules! db_run {
         #[allow(unused)] use diesel::prelude::*;
         #[allow(unused)] use $crate::db::FromDb;
 
+        let span = $conn.query_span(module_path!(), line!());
         let conn = $conn.conn.clone();
         let mut conn = conn.lock_owned().await;
         match conn.as_mut().expect("internal invariant broken: self.connection is Some") {
@@ -264,7 +405,9 @@ macro_rules! db_run {
                         #[allow(unused)] use [<__ $db _model>]::*;
                     }
 
-                    tokio::task::block_in_place(move || { $body }) // Run blocking can't be used due to the 'static limitation, use block_in_place instead
+                    let _timer = $crate::db::QueryTimer::start(module_path!(), line!());
+                    // Run blocking can't be used due to the 'static limitation, use block_in_place instead
+                    tokio::task::block_in_place(move || { let _span = span.enter(); $body })
                 },
             )+)+
         }
@@ -274,6 +417,7 @@ macro_rules! db_run {
         #[allow(unused)] use diesel::prelude::*;
         #[allow(unused)] use $crate::db::FromDb;
 
+        let span = $conn.query_span(module_path!(), line!());
         let conn = $conn.conn.clone();
         let mut conn = conn.lock_owned().await;
         match conn.as_mut().expect("internal invariant broken: self.connection is Some") {
@@ -285,7 +429,9 @@ macro_rules! db_run {
                         // @ RAW: #[allow(unused)] use [<__ $db _model>]::*;
                     }
 
-                    tokio::task::block_in_place(move || { $body }) // Run blocking can't be used due to the 'static limitation, use block_in_place instead
+                    let _timer = $crate::db::QueryTimer::start(module_path!(), line!());
+                    // Run blocking can't be used due to the 'static limitation, use block_in_place instead
+                    tokio::task::block_in_place(move || { let _span = span.enter(); $body })
                 },
             )+)+
         }
@@ -327,8 +473,12 @@ macro_rules! db_object {
             $(,)?
         }
     )+ ) => {
-        // Create the normal struct, without attributes
-        $( pub struct $name { $( /*$( #[$field_attr] )**/ $vis $field : $typ, )+ } )+
+        // Create the normal struct, without attributes. It can be serialized for the archives, see `models::transfer`
+        $(
+            #[derive(Clone, serde::Serialize, serde::Deserialize)]
+            #[serde(deny_unknown_fields)]
+            pub struct $name { $( /*$( #[$field_attr] )**/ $vis $field : $typ, )+ }
+        )+
 
         #[cfg(sqlite)]
         pub mod __sqlite_model     { $( db_object! { @db sqlite     |  $( #[$attr] )* | $name |  $( $( #[$field_attr] )* $field : $typ ),+ } )+ }
@@ -364,27 +514,83 @@ macro_rules! db_object {
 }
 
 // Reexport the models, needs to be after the macros are defined so it can access them
+pub mod cache;
+pub mod coalesce;
+pub mod encryption;
 pub mod models;
+pub mod scope;
 
-/// Creates a back-up of the sqlite database
+/// Creates a back-up of the sqlite database at the given path, see `crate::backup` for the other databases
 /// MySQL/MariaDB and PostgreSQL are not supported.
-pub async fn backup_database(conn: &mut DbConn) -> Result<(), Error> {
+pub async fn backup_database(conn: &mut DbConn, path: &std::path::Path) -> Result<(), Error> {
     db_run! {@raw conn:
         postgresql, mysql {
-            let _ = conn;
+            let _ = (conn, path);
             err!("PostgreSQL and MySQL/MariaDB do not support this backup feature");
         }
         sqlite {
-            use std::path::Path;
-            let db_url = CONFIG.database_url();
-            let db_path = Path::new(&db_url).parent().unwrap().to_string_lossy();
-            let file_date = chrono::Utc::now().format("%Y%m%d_%H%M%S").to_string();
-            diesel::sql_query(format!("VACUUM INTO '{db_path}/db_{file_date}.sqlite3'")).execute(conn)?;
+            let path = path.to_string_lossy().replace('\'', "''");
+            diesel::sql_query(format!("VACUUM INTO '{path}'")).execute(conn)?;
             Ok(())
         }
     }
 }
 
+// The tables which grow the most, MySQL/MariaDB can only analyze the listed tables
+#[cfg(mysql)]
+const MYSQL_ANALYZE_TABLES: &str = "ciphers, attachments, folders, folders_ciphers, ciphers_collections, favorites, \
+    users_collections, collections_groups, groups_users, devices, sends, event, notification_events, tombstones";
+
+/// Reclaims the free space and updates the query planner statistics, see `crate::db_maintenance`.
+pub async fn optimize_database(conn: &mut DbConn) -> Result<(), Error> {
+    db_run! {@raw conn:
+        sqlite {
+            // The WAL is written back first, so VACUUM has all the pages and the WAL file is truncated afterwards
+            conn.batch_execute("PRAGMA wal_checkpoint(TRUNCATE); VACUUM; PRAGMA wal_checkpoint(TRUNCATE); \
+                PRAGMA optimize;")
+                .map_res("Error optimizing the database")
+        }
+        mysql {
+            conn.batch_execute(&format!("ANALYZE TABLE {MYSQL_ANALYZE_TABLES};"))
+                .map_res("Error optimizing the database")
+        }
+        postgresql {
+            conn.batch_execute("VACUUM (ANALYZE);")
+                .map_res("Error optimizing the database")
+        }
+    }
+}
+
+/// Runs the simplest query, to check that the database answers.
+pub async fn ping(conn: &mut DbConn) -> Result<(), Error> {
+    db_run! {@raw conn: {
+        diesel::sql_query("SELECT 1").execute(conn)?;
+        Ok(())
+    }}
+}
+
+sql_function! {
+    /// Lowercases the text, the searches of the listings ignore the case on every database
+    fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text;
+}
+
+/// The escape character of `like_pattern`, a backslash would need escaping itself in the MySQL string literals.
+pub const LIKE_ESCAPE: char = '!';
+
+/// The LIKE pattern matching the text anywhere, with its wildcards escaped by `LIKE_ESCAPE`.
+pub fn like_pattern(text: &str) -> String {
+    let mut pattern = String::with_capacity(text.len() + 2);
+    pattern.push('%');
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | LIKE_ESCAPE) {
+            pattern.push(LIKE_ESCAPE);
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
 /// Get the SQL Server version
 pub async fn get_sql_server_version(conn: &mut DbConn) -> String {
     db_run! {@raw conn:
@@ -403,6 +609,217 @@ pub async fn get_sql_server_version(conn: &mut DbConn) -> String {
     }
 }
 
+// Set while migrations are pending and `DATABASE_AUTO_MIGRATE` is disabled
+static MAINTENANCE: AtomicBool = AtomicBool::new(false);
+
+/// True when the requests needing the database are refused, until the pending migrations are applied.
+pub fn in_maintenance() -> bool {
+    MAINTENANCE.load(Ordering::Relaxed)
+}
+
+#[derive(Default, Serialize)]
+pub struct MigrationStatus {
+    pub backend: &'static str,
+    // The version of the last migration applied to the database, which can be newer than the ones known by this server
+    pub schema_version: Option<String>,
+    pub applied: Vec<String>,
+    pub pending: Vec<String>,
+}
+
+fn migration_error(e: Box<dyn std::error::Error + Send + Sync>) -> Error {
+    Error::new("Error reading the database migrations", e.to_string())
+}
+
+fn read_migration_status<DB, C>(
+    conn: &mut C,
+    migrations: &diesel_migrations::EmbeddedMigrations,
+) -> Result<MigrationStatus, Error>
+where
+    DB: diesel::backend::Backend,
+    C: diesel_migrations::MigrationHarness<DB>,
+    diesel_migrations::EmbeddedMigrations: diesel_migrations::MigrationSource<DB>,
+{
+    use diesel_migrations::MigrationSource;
+
+    let applied = conn.applied_migrations().map_err(migration_error)?;
+    let mut status = MigrationStatus {
+        schema_version: applied.iter().map(ToString::to_string).max(),
+        ..Default::default()
+    };
+    for migration in migrations.migrations().map_err(migration_error)? {
+        let version = migration.name().version();
+        match applied.iter().any(|v| *v == version) {
+            true => status.applied.push(migration.name().to_string()),
+            false => status.pending.push(migration.name().to_string()),
+        }
+    }
+    Ok(status)
+}
+
+/// The applied and pending migrations of the configured database.
+pub async fn migration_status() -> Result<MigrationStatus, Error> {
+    let url = CONFIG.database_url();
+    let conn_type = DbConnType::from_url(&url)?;
+    let mut status = run_blocking(move || match conn_type {
+        #[cfg(sqlite)]
+        DbConnType::sqlite => sqlite_migrations::migration_status(&url),
+        #[cfg(mysql)]
+        DbConnType::mysql => mysql_migrations::migration_status(&url),
+        #[cfg(postgresql)]
+        DbConnType::postgresql => postgresql_migrations::migration_status(&url),
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("Trying to use a DB backend when it's feature is disabled"),
+    })
+    .await?;
+    status.backend = match conn_type {
+        DbConnType::sqlite => "SQLite",
+        DbConnType::mysql => "MySQL",
+        DbConnType::postgresql => "PostgreSQL",
+    };
+    Ok(status)
+}
+
+/// Applies the pending migrations, and ends the maintenance.
+pub async fn apply_migrations(pool: &DbPool) -> Result<(), Error> {
+    let url = CONFIG.database_url();
+    let conn_type = DbConnType::from_url(&url)?;
+    run_blocking(move || match conn_type {
+        #[cfg(sqlite)]
+        DbConnType::sqlite => sqlite_migrations::run_migrations(&url),
+        #[cfg(mysql)]
+        DbConnType::mysql => mysql_migrations::run_migrations(&url),
+        #[cfg(postgresql)]
+        DbConnType::postgresql => postgresql_migrations::run_migrations(&url),
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("Trying to use a DB backend when it's feature is disabled"),
+    })
+    .await?;
+    end_maintenance(pool).await;
+    Ok(())
+}
+
+/// Checks the migrations at startup. When `DATABASE_AUTO_MIGRATE` is disabled and some are pending, the server is
+/// kept in maintenance until they're applied, from the admin page or by another instance.
+pub async fn check_migrations(pool: &DbPool) -> Result<(), Error> {
+    if !CONFIG.database_auto_migrate() {
+        let status = migration_status().await?;
+        if !status.pending.is_empty() {
+            warn!(
+                "{} database migrations are pending, the requests are refused until they're applied: {}",
+                status.pending.len(),
+                status.pending.join(", ")
+            );
+            MAINTENANCE.store(true, Ordering::Relaxed);
+            tokio::spawn(wait_for_migrations(pool.clone()));
+            return Ok(());
+        }
+    }
+    run_data_migrations(pool).await
+}
+
+// Ends the maintenance once the migrations were applied by another instance
+async fn wait_for_migrations(pool: DbPool) {
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    while in_maintenance() {
+        interval.tick().await;
+        match migration_status().await {
+            Ok(status) if status.pending.is_empty() => end_maintenance(&pool).await,
+            Ok(_) => {}
+            Err(e) => warn!("Error checking the pending database migrations: {e:?}"),
+        }
+    }
+}
+
+async fn end_maintenance(pool: &DbPool) {
+    if MAINTENANCE.swap(false, Ordering::Relaxed) {
+        info!("The database migrations are applied, the requests are served again");
+        if let Err(e) = run_data_migrations(pool).await {
+            error!("Error migrating the stored data: {e:?}");
+        }
+    }
+}
+
+// The changes to the stored data which need the latest schema
+async fn run_data_migrations(pool: &DbPool) -> Result<(), Error> {
+    let mut conn = pool.get().await?;
+    models::TwoFactor::migrate_u2f_to_webauthn(&mut conn).await?;
+    encryption::encrypt_all_data(false, &mut conn).await?;
+    Ok(())
+}
+
+// Gets a connection for a request, waiting at most `DATABASE_ACQUIRE_TIMEOUT` for each attempt.
+// The attempts are spaced by a random delay, so the requests waiting on an unavailable database don't all retry at once.
+async fn get_request_conn(pool: &DbPool, read: bool) -> Result<DbConn, Error> {
+    let duration = Duration::from_secs(CONFIG.database_acquire_timeout());
+    let mut attempt = 0;
+    loop {
+        let result = match read {
+            true => pool.get_read_timeout(duration).await,
+            false => pool.get_timeout(duration).await,
+        };
+        match result {
+            Ok(conn) => return Ok(conn),
+            Err(e) if attempt >= CONFIG.database_acquire_retries() => return Err(e),
+            Err(e) => {
+                attempt += 1;
+                pool.stats.retries.fetch_add(1, Ordering::Relaxed);
+                let delay: u64 = 100 * (1 << attempt.min(5)) + rand::thread_rng().gen_range(0..100);
+                warn!("Error getting a database connection, trying again in {delay}ms: {e:?}");
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+            }
+        }
+    }
+}
+
+/// Times a query run with `db_run!` for the request, and logs it when it's slower than `SLOW_QUERY_THRESHOLD_MS`.
+pub struct QueryTimer {
+    module: &'static str,
+    line: u32,
+    timer: PhaseTimer,
+}
+
+impl QueryTimer {
+    pub fn start(module: &'static str, line: u32) -> Self {
+        Self {
+            module,
+            line,
+            timer: PhaseTimer::start(Phase::Db),
+        }
+    }
+}
+
+impl Drop for QueryTimer {
+    fn drop(&mut self) {
+        let threshold = CONFIG.slow_query_threshold_ms();
+        let elapsed = self.timer.elapsed();
+        if threshold > 0 && elapsed >= Duration::from_millis(threshold) {
+            warn!("Slow query in {}:{}, it took {} ms", self.module, self.line, elapsed.as_millis());
+        }
+    }
+}
+
+impl DbConn {
+    /// The span of a query run with `db_run!`, a child of the span of the request when there is one.
+    pub fn query_span(&self, module: &'static str, line: u32) -> tracing::Span {
+        if !crate::otel::enabled() {
+            return tracing::Span::none();
+        }
+        let name = format!("db {}", module.rsplit("::").next().unwrap_or(module));
+        let name = name.as_str();
+        if self.span.is_none() {
+            tracing::info_span!("db.query", otel.name = name, code.namespace = module, code.lineno = line)
+        } else {
+            tracing::info_span!(
+                parent: &self.span,
+                "db.query",
+                otel.name = name,
+                code.namespace = module,
+                code.lineno = line
+            )
+        }
+    }
+}
+
 /// Attempts to retrieve a single connection from the managed database pool. If
 /// no pool is currently managed, fails with an `InternalServerError` status. If
 /// no connections are available, fails with a `ServiceUnavailable` status.
@@ -411,9 +828,58 @@ impl<'r> FromRequest<'r> for DbConn {
     type Error = ();
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        // Mostly the wait for a free connection of the pool
+        let _timer = PhaseTimer::start(Phase::Guards);
+        if in_maintenance() {
+            return Outcome::Error((Status::ServiceUnavailable, ()));
+        }
+        match request.rocket().state::<DbPool>() {
+            Some(p) => match get_request_conn(p, false).await {
+                Ok(mut dbconn) => {
+                    dbconn.span = crate::otel::request_span(request);
+                    Outcome::Success(dbconn)
+                }
+                _ => Outcome::Error((Status::ServiceUnavailable, ())),
+            },
+            None => Outcome::Error((Status::InternalServerError, ())),
+        }
+    }
+}
+
+/// A connection to the read replica when one is configured, else to the primary database.
+/// Only for the requests which don't write anything, the replica can lag behind the primary database.
+pub struct DbReadConn(DbConn);
+
+impl std::ops::Deref for DbReadConn {
+    type Target = DbConn;
+
+    fn deref(&self) -> &DbConn {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for DbReadConn {
+    fn deref_mut(&mut self) -> &mut DbConn {
+        &mut self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DbReadConn {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        // Mostly the wait for a free connection of the pool
+        let _timer = PhaseTimer::start(Phase::Guards);
+        if in_maintenance() {
+            return Outcome::Error((Status::ServiceUnavailable, ()));
+        }
         match request.rocket().state::<DbPool>() {
-            Some(p) => match p.get().await {
-                Ok(dbconn) => Outcome::Success(dbconn),
+            Some(p) => match get_request_conn(p, true).await {
+                Ok(mut dbconn) => {
+                    dbconn.span = crate::otel::request_span(request);
+                    Outcome::Success(DbReadConn(dbconn))
+                }
                 _ => Outcome::Error((Status::ServiceUnavailable, ())),
             },
             None => Outcome::Error((Status::InternalServerError, ())),
@@ -429,13 +895,12 @@ mod sqlite_migrations {
     use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
     pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/sqlite");
 
-    pub fn run_migrations() -> Result<(), super::Error> {
+    pub fn run_migrations(url: &str) -> Result<(), super::Error> {
         use diesel::{Connection, RunQueryDsl};
-        let url = crate::CONFIG.database_url();
 
         // Establish a connection to the sqlite database (this will create a new one, if it does
         // not exist, and exit if there is an error).
-        let mut connection = diesel::sqlite::SqliteConnection::establish(&url)?;
+        let mut connection = diesel::sqlite::SqliteConnection::establish(url)?;
 
         // Run the migrations after successfully establishing a connection
         // Disable Foreign Key Checks during migration
@@ -449,9 +914,15 @@ mod sqlite_migrations {
             diesel::sql_query("PRAGMA journal_mode=wal").execute(&mut connection).expect("Failed to turn on WAL");
         }
 
-        connection.run_pending_migrations(MIGRATIONS).expect("Error running migrations");
+        connection.run_pending_migrations(MIGRATIONS).map_err(super::migration_error)?;
         Ok(())
     }
+
+    pub fn migration_status(url: &str) -> Result<super::MigrationStatus, super::Error> {
+        use diesel::Connection;
+        let mut connection = diesel::sqlite::SqliteConnection::establish(url)?;
+        super::read_migration_status(&mut connection, &MIGRATIONS)
+    }
 }
 
 #[cfg(mysql)]
@@ -459,10 +930,10 @@ mod mysql_migrations {
     use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
     pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/mysql");
 
-    pub fn run_migrations() -> Result<(), super::Error> {
+    pub fn run_migrations(url: &str) -> Result<(), super::Error> {
         use diesel::{Connection, RunQueryDsl};
         // Make sure the database is up to date (create if it doesn't exist, or run the migrations)
-        let mut connection = diesel::mysql::MysqlConnection::establish(&crate::CONFIG.database_url())?;
+        let mut connection = diesel::mysql::MysqlConnection::establish(url)?;
         // Disable Foreign Key Checks during migration
 
         // Scoped to a connection/session.
@@ -470,9 +941,15 @@ mod mysql_migrations {
             .execute(&mut connection)
             .expect("Failed to disable Foreign Key Checks during migrations");
 
-        connection.run_pending_migrations(MIGRATIONS).expect("Error running migrations");
+        connection.run_pending_migrations(MIGRATIONS).map_err(super::migration_error)?;
         Ok(())
     }
+
+    pub fn migration_status(url: &str) -> Result<super::MigrationStatus, super::Error> {
+        use diesel::Connection;
+        let mut connection = diesel::mysql::MysqlConnection::establish(url)?;
+        super::read_migration_status(&mut connection, &MIGRATIONS)
+    }
 }
 
 #[cfg(postgresql)]
@@ -480,11 +957,17 @@ mod postgresql_migrations {
     use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
     pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/postgresql");
 
-    pub fn run_migrations() -> Result<(), super::Error> {
+    pub fn run_migrations(url: &str) -> Result<(), super::Error> {
         use diesel::Connection;
         // Make sure the database is up to date (create if it doesn't exist, or run the migrations)
-        let mut connection = diesel::pg::PgConnection::establish(&crate::CONFIG.database_url())?;
-        connection.run_pending_migrations(MIGRATIONS).expect("Error running migrations");
+        let mut connection = diesel::pg::PgConnection::establish(url)?;
+        connection.run_pending_migrations(MIGRATIONS).map_err(super::migration_error)?;
         Ok(())
     }
+
+    pub fn migration_status(url: &str) -> Result<super::MigrationStatus, super::Error> {
+        use diesel::Connection;
+        let mut connection = diesel::pg::PgConnection::establish(url)?;
+        super::read_migration_status(&mut connection, &MIGRATIONS)
+    }
 }
@@ -21,6 +21,15 @@ db_object! {
 
 /// Local methods
 impl Attachment {
+    /// A UUIDv7 with `DATABASE_UUID_V7`, else a shorter random id, they only need to be unique for the cipher.
+    pub fn generate_id() -> String {
+        if CONFIG.database_uuid_v7() {
+            crate::util::UuidV7::now().to_string()
+        } else {
+            crate::crypto::generate_attachment_id()
+        }
+    }
+
     pub const fn new(id: String, cipher_uuid: String, file_name: String, file_size: i64, akey: Option<String>) -> Self {
         Self {
             id,
@@ -3,7 +3,8 @@ use chrono::{NaiveDateTime, TimeDelta, Utc};
 use serde_json::Value;
 
 use super::{
-    Attachment, CollectionCipher, Favorite, FolderCipher, Group, User, UserOrgStatus, UserOrgType, UserOrganization,
+    Attachment, CollectionCipher, Favorite, FolderCipher, Group, Tombstone, User, UserOrgStatus, UserOrgType,
+    UserOrganization,
 };
 
 use crate::api::core::{CipherData, CipherSyncData, CipherSyncType};
@@ -56,7 +57,7 @@ impl Cipher {
         let now = Utc::now().naive_utc();
 
         Self {
-            uuid: crate::util::get_uuid(),
+            uuid: crate::util::get_row_uuid(),
             created_at: now,
             updated_at: now,
 
@@ -106,7 +107,10 @@ impl Cipher {
     }
 }
 
-use crate::db::DbConn;
+use crate::db::{
+    scope::{audit, audit_user_and_orgs, Scope},
+    DbConn,
+};
 
 use crate::api::EmptyResult;
 use crate::error::MapResult;
@@ -327,6 +331,20 @@ impl Cipher {
         }
     }
 
+    /// Inserts the new ciphers at once, without updating the revision of their users.
+    pub async fn insert_all(ciphers: &[Self], conn: &mut DbConn) -> EmptyResult {
+        if ciphers.is_empty() {
+            return Ok(());
+        }
+        db_run! { conn: {
+            let values: Vec<CipherDb> = ciphers.iter().map(CipherDb::to_db).collect();
+            diesel::insert_into(ciphers::table)
+                .values(&values)
+                .execute(conn)
+                .map_res("Error saving ciphers")
+        }}
+    }
+
     pub async fn delete(&self, conn: &mut DbConn) -> EmptyResult {
         self.update_users_revision(conn).await;
 
@@ -334,6 +352,7 @@ impl Cipher {
         CollectionCipher::delete_all_by_cipher(&self.uuid, conn).await?;
         Attachment::delete_all_by_cipher(&self.uuid, conn).await?;
         Favorite::delete_all_by_cipher(&self.uuid, conn).await?;
+        Tombstone::for_cipher(self).save(conn).await?;
 
         db_run! { conn: {
             diesel::delete(ciphers::table.filter(ciphers::uuid.eq(&self.uuid)))
@@ -608,7 +627,7 @@ impl Cipher {
     // result, those ciphers will not appear in "My Vault" for the org
     // owner/admin, but they can still be accessed via the org vault view.
     pub async fn find_by_user(user_uuid: &str, visible_only: bool, conn: &mut DbConn) -> Vec<Self> {
-        if CONFIG.org_groups_enabled() {
+        let ciphers = if CONFIG.org_groups_enabled() {
             db_run! {conn: {
                 let mut query = ciphers::table
                     .left_join(ciphers_collections::table.on(
@@ -685,7 +704,8 @@ impl Cipher {
                     .distinct()
                     .load::<CipherDb>(conn).expect("Error loading ciphers").from_db()
             }}
-        }
+        };
+        audit_user_and_orgs(ciphers, user_uuid, "Cipher::find_by_user", conn).await
     }
 
     // Find all ciphers visible to the specified user.
@@ -695,14 +715,15 @@ impl Cipher {
 
     // Find all ciphers directly owned by the specified user.
     pub async fn find_owned_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
-        db_run! {conn: {
+        let ciphers = db_run! {conn: {
             ciphers::table
                 .filter(
                     ciphers::user_uuid.eq(user_uuid)
                     .and(ciphers::organization_uuid.is_null())
                 )
                 .load::<CipherDb>(conn).expect("Error loading ciphers").from_db()
-        }}
+        }};
+        audit(ciphers, Scope::User(user_uuid), "Cipher::find_owned_by_user")
     }
 
     pub async fn count_owned_by_user(user_uuid: &str, conn: &mut DbConn) -> i64 {
@@ -717,11 +738,12 @@ impl Cipher {
     }
 
     pub async fn find_by_org(org_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
-        db_run! {conn: {
+        let ciphers = db_run! {conn: {
             ciphers::table
                 .filter(ciphers::organization_uuid.eq(org_uuid))
                 .load::<CipherDb>(conn).expect("Error loading ciphers").from_db()
-        }}
+        }};
+        audit(ciphers, Scope::Org(org_uuid), "Cipher::find_by_org")
     }
 
     pub async fn count_by_org(org_uuid: &str, conn: &mut DbConn) -> i64 {
@@ -744,6 +766,15 @@ impl Cipher {
         }}
     }
 
+    pub async fn find_by_collection(collection_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! {conn: {
+            ciphers_collections::table.inner_join(ciphers::table)
+                .filter(ciphers_collections::collection_uuid.eq(collection_uuid))
+                .select(ciphers::all_columns)
+                .load::<CipherDb>(conn).expect("Error loading ciphers").from_db()
+        }}
+    }
+
     /// Find all ciphers that were deleted before the specified datetime.
     pub async fn find_deleted_before(dt: &NaiveDateTime, conn: &mut DbConn) -> Vec<Self> {
         db_run! {conn: {
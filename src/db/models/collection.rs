@@ -1,6 +1,6 @@
 use serde_json::Value;
 
-use super::{CollectionGroup, GroupUser, User, UserOrgStatus, UserOrgType, UserOrganization};
+use super::{CollectionGroup, GroupUser, Tombstone, User, UserOrgStatus, UserOrgType, UserOrganization};
 use crate::CONFIG;
 
 db_object! {
@@ -113,7 +113,10 @@ impl Collection {
     }
 }
 
-use crate::db::DbConn;
+use crate::db::{
+    scope::{audit, audit_user_and_orgs, Scope},
+    DbConn,
+};
 
 use crate::api::EmptyResult;
 use crate::error::MapResult;
@@ -159,6 +162,7 @@ impl Collection {
         CollectionCipher::delete_all_by_collection(&self.uuid, conn).await?;
         CollectionUser::delete_all_by_collection(&self.uuid, conn).await?;
         CollectionGroup::delete_all_by_collection(&self.uuid, conn).await?;
+        Tombstone::for_collection(&self).save(conn).await?;
 
         db_run! { conn: {
             diesel::delete(collections::table.filter(collections::uuid.eq(self.uuid)))
@@ -190,18 +194,18 @@ impl Collection {
         }}
     }
 
-    pub async fn find_by_user_uuid(user_uuid: String, conn: &mut DbConn) -> Vec<Self> {
-        if CONFIG.org_groups_enabled() {
+    pub async fn find_by_user_uuid(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        let collections = if CONFIG.org_groups_enabled() {
             db_run! { conn: {
                 collections::table
                 .left_join(users_collections::table.on(
                     users_collections::collection_uuid.eq(collections::uuid).and(
-                        users_collections::user_uuid.eq(user_uuid.clone())
+                        users_collections::user_uuid.eq(user_uuid)
                     )
                 ))
                 .left_join(users_organizations::table.on(
                     collections::org_uuid.eq(users_organizations::org_uuid).and(
-                        users_organizations::user_uuid.eq(user_uuid.clone())
+                        users_organizations::user_uuid.eq(user_uuid)
                     )
                 ))
                 .left_join(groups_users::table.on(
@@ -238,12 +242,12 @@ impl Collection {
                 collections::table
                 .left_join(users_collections::table.on(
                     users_collections::collection_uuid.eq(collections::uuid).and(
-                        users_collections::user_uuid.eq(user_uuid.clone())
+                        users_collections::user_uuid.eq(user_uuid)
                     )
                 ))
                 .left_join(users_organizations::table.on(
                     collections::org_uuid.eq(users_organizations::org_uuid).and(
-                        users_organizations::user_uuid.eq(user_uuid.clone())
+                        users_organizations::user_uuid.eq(user_uuid)
                     )
                 ))
                 .filter(
@@ -258,25 +262,23 @@ impl Collection {
                 .distinct()
                 .load::<CollectionDb>(conn).expect("Error loading collections").from_db()
             }}
-        }
+        };
+        audit_user_and_orgs(collections, user_uuid, "Collection::find_by_user_uuid", conn).await
     }
 
     pub async fn find_by_organization_and_user_uuid(org_uuid: &str, user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
-        Self::find_by_user_uuid(user_uuid.to_owned(), conn)
-            .await
-            .into_iter()
-            .filter(|c| c.org_uuid == org_uuid)
-            .collect()
+        Self::find_by_user_uuid(user_uuid, conn).await.into_iter().filter(|c| c.org_uuid == org_uuid).collect()
     }
 
     pub async fn find_by_organization(org_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
-        db_run! { conn: {
+        let collections = db_run! { conn: {
             collections::table
                 .filter(collections::org_uuid.eq(org_uuid))
                 .load::<CollectionDb>(conn)
                 .expect("Error loading collections")
                 .from_db()
-        }}
+        }};
+        audit(collections, Scope::Org(org_uuid), "Collection::find_by_organization")
     }
 
     pub async fn count_by_org(org_uuid: &str, conn: &mut DbConn) -> i64 {
@@ -680,6 +682,29 @@ impl CollectionCipher {
         }
     }
 
+    /// Adds the new ciphers to the collections at once, the pairs are the uuids of a cipher and of a collection.
+    /// The revision of the users isn't updated.
+    pub async fn insert_all(pairs: &[(String, String)], conn: &mut DbConn) -> EmptyResult {
+        if pairs.is_empty() {
+            return Ok(());
+        }
+        db_run! { conn: {
+            let values: Vec<CollectionCipherDb> = pairs
+                .iter()
+                .map(|(cipher_uuid, collection_uuid)| {
+                    CollectionCipherDb::to_db(&CollectionCipher {
+                        cipher_uuid: cipher_uuid.clone(),
+                        collection_uuid: collection_uuid.clone(),
+                    })
+                })
+                .collect();
+            diesel::insert_into(ciphers_collections::table)
+                .values(&values)
+                .execute(conn)
+                .map_res("Error adding ciphers to collections")
+        }}
+    }
+
     pub async fn delete(cipher_uuid: &str, collection_uuid: &str, conn: &mut DbConn) -> EmptyResult {
         Self::update_users_revision(collection_uuid, conn).await;
 
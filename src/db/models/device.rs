@@ -1,6 +1,12 @@
-use chrono::{NaiveDateTime, Utc};
-
-use crate::{crypto, CONFIG};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use serde_json::Value;
+
+use crate::{
+    crypto,
+    db::encryption::{self, EncryptedColumn},
+    util::format_date,
+    CONFIG,
+};
 use core::fmt;
 
 db_object! {
@@ -23,6 +29,8 @@ db_object! {
         pub refresh_token: String,
 
         pub twofactor_remember: Option<String>,
+        pub twofactor_remember_created_at: Option<NaiveDateTime>,
+        pub twofactor_remember_last_used: Option<NaiveDateTime>,
     }
 }
 
@@ -44,19 +52,65 @@ impl Device {
             push_token: None,
             refresh_token: String::new(),
             twofactor_remember: None,
+            twofactor_remember_created_at: None,
+            twofactor_remember_last_used: None,
         }
     }
 
+    // The push token is encrypted at rest when a key is configured, see `encryption`
+    pub fn push_token(&self) -> Option<String> {
+        self.push_token
+            .as_deref()
+            .map(|token| encryption::decrypt_or_log(EncryptedColumn::DevicePushToken, &self.uuid, token))
+    }
+
+    pub fn set_push_token(&mut self, push_token: &str) {
+        self.push_token = Some(encryption::encrypt(EncryptedColumn::DevicePushToken, &self.uuid, push_token));
+    }
+
     pub fn refresh_twofactor_remember(&mut self) -> String {
         use data_encoding::BASE64;
         let twofactor_remember = crypto::encode_random_bytes::<180>(BASE64);
         self.twofactor_remember = Some(twofactor_remember.clone());
+        // The token is rotated on every remembered login, keep the date of the two-step login which started remembering
+        if self.twofactor_remember_created_at.is_none() {
+            self.twofactor_remember_created_at = Some(Utc::now().naive_utc());
+        }
 
         twofactor_remember
     }
 
     pub fn delete_twofactor_remember(&mut self) {
         self.twofactor_remember = None;
+        self.twofactor_remember_created_at = None;
+        self.twofactor_remember_last_used = None;
+    }
+
+    pub fn twofactor_remember_expiration(&self, max_duration: Option<TimeDelta>) -> Option<NaiveDateTime> {
+        match (self.twofactor_remember_created_at, max_duration) {
+            (Some(created_at), Some(max_duration)) => Some(created_at + max_duration),
+            _ => None,
+        }
+    }
+
+    pub fn is_twofactor_remember_expired(&self, max_duration: Option<TimeDelta>) -> bool {
+        match self.twofactor_remember_expiration(max_duration) {
+            Some(expiration) => expiration <= Utc::now().naive_utc(),
+            // Tokens from before the creation date was stored have no date, they can only be limited by revoking them
+            None => false,
+        }
+    }
+
+    pub fn twofactor_remember_json(&self, max_duration: Option<TimeDelta>) -> Value {
+        json!({
+            "Id": self.uuid,
+            "Name": self.name,
+            "Type": self.atype,
+            "CreationDate": self.twofactor_remember_created_at.as_ref().map(format_date),
+            "LastUsedDate": self.twofactor_remember_last_used.as_ref().map(format_date),
+            "ExpirationDate": self.twofactor_remember_expiration(max_duration).as_ref().map(format_date),
+            "Object": "twoFactorRememberedDevice",
+        })
     }
 
     pub fn refresh_tokens(&mut self, user: &super::User, scope: Vec<String>) -> (String, i64) {
@@ -123,17 +177,58 @@ impl Device {
     }
 }
 
-use crate::db::DbConn;
+use crate::db::{
+    cache,
+    scope::{audit, Scope},
+    DbConn,
+};
 
 use crate::api::EmptyResult;
 use crate::error::MapResult;
 
 /// Database methods
 impl Device {
+    /// Encrypts the push tokens which aren't encrypted with the current key, see `encryption::encrypt_all_data`.
+    pub async fn encrypt_stored_data(rotate: bool, conn: &mut DbConn) -> Result<usize, crate::Error> {
+        let Some(pattern) = encryption::up_to_date_pattern(rotate) else {
+            return Ok(0);
+        };
+
+        let push_tokens: Vec<(String, String, Option<String>)> = db_run! { conn: {
+            devices::table
+                .filter(devices::push_token.is_not_null())
+                .filter(devices::push_token.not_like(pattern))
+                .select((devices::uuid, devices::user_uuid, devices::push_token))
+                .load(conn)
+                .expect("Error loading devices")
+        }};
+
+        let mut count = 0;
+        for (uuid, user_uuid, stored) in push_tokens {
+            let Some(token) = stored.and_then(|s| encryption::decrypt(EncryptedColumn::DevicePushToken, &uuid, &s))
+            else {
+                error!("Unable to decrypt the push token of device {uuid}, check the DATABASE_ENCRYPTION_KEYS");
+                continue;
+            };
+            let encrypted = encryption::encrypt(EncryptedColumn::DevicePushToken, &uuid, &token);
+            db_run! { conn: {
+                diesel::update(devices::table)
+                    .filter(devices::uuid.eq(&uuid))
+                    .filter(devices::user_uuid.eq(&user_uuid))
+                    .set(devices::push_token.eq(encrypted))
+                    .execute(conn)
+                    .map_res("Error encrypting the device push token")
+            }}?;
+            count += 1;
+        }
+        cache::SESSIONS.clear();
+        Ok(count)
+    }
+
     pub async fn save(&mut self, conn: &mut DbConn) -> EmptyResult {
         self.updated_at = Utc::now().naive_utc();
 
-        db_run! { conn:
+        let res = db_run! { conn:
             sqlite, mysql {
                 crate::util::retry(
                     || diesel::replace_into(devices::table).values(DeviceDb::to_db(self)).execute(conn),
@@ -147,7 +242,9 @@ impl Device {
                     10,
                 ).map_res("Error saving device")
             }
-        }
+        };
+        cache::invalidate_sessions(&self.user_uuid, Some(&self.uuid));
+        res
     }
 
     pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
@@ -155,7 +252,20 @@ impl Device {
             diesel::delete(devices::table.filter(devices::user_uuid.eq(user_uuid)))
                 .execute(conn)
                 .map_res("Error removing devices for user")
-        }}
+        }}?;
+        cache::invalidate_sessions(user_uuid, None);
+        Ok(())
+    }
+
+    /// Deletes the devices which weren't used since the given date, they need to log in again.
+    pub async fn delete_inactive_before(dt: &NaiveDateTime, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(devices::table.filter(devices::updated_at.lt(dt)))
+                .execute(conn)
+                .map_res("Error removing inactive devices")
+        }}?;
+        cache::SESSIONS.clear();
+        Ok(())
     }
 
     pub async fn find_by_uuid_and_user(uuid: &str, user_uuid: &str, conn: &mut DbConn) -> Option<Self> {
@@ -170,12 +280,24 @@ impl Device {
     }
 
     pub async fn find_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
-        db_run! { conn: {
+        let devices = db_run! { conn: {
             devices::table
                 .filter(devices::user_uuid.eq(user_uuid))
                 .load::<DeviceDb>(conn)
                 .expect("Error loading devices")
                 .from_db()
+        }};
+        audit(devices, Scope::User(user_uuid), "Device::find_by_user")
+    }
+
+    pub async fn find_remembered_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            devices::table
+                .filter(devices::user_uuid.eq(user_uuid))
+                .filter(devices::twofactor_remember.is_not_null())
+                .load::<DeviceDb>(conn)
+                .expect("Error loading remembered devices")
+                .from_db()
         }}
     }
 
@@ -196,7 +318,10 @@ impl Device {
                 .set(devices::push_token.eq::<Option<String>>(None))
                 .execute(conn)
                 .map_res("Error removing push token")
-        }}
+        }}?;
+        // The user of the device isn't known, this is rare enough to drop all the sessions
+        cache::SESSIONS.clear();
+        Ok(())
     }
     pub async fn find_by_refresh_token(refresh_token: &str, conn: &mut DbConn) -> Option<Self> {
         db_run! { conn: {
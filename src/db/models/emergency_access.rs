@@ -1,4 +1,4 @@
-use chrono::{NaiveDateTime, Utc};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
 use serde_json::Value;
 
 use crate::{api::EmptyResult, db::DbConn, error::MapResult};
@@ -23,6 +23,14 @@ db_object! {
         pub last_notification_at: Option<NaiveDateTime>,
         pub updated_at: NaiveDateTime,
         pub created_at: NaiveDateTime,
+        pub reminder_interval_hours: Option<i32>,
+        pub escalation_after_hours: Option<i32>,
+        pub escalation_contacts: Option<String>, // Comma separated list of email addresses
+        pub escalated_at: Option<NaiveDateTime>,
+        pub org_uuid: Option<String>, // Set when the grant was created through an organization emergency access policy
+        pub scope_folders: Option<String>, // Comma separated list of folder uuids
+        pub key_fingerprint: Option<String>, // Fingerprint of the grantee public key used to encrypt key_encrypted
+        pub key_invalid_at: Option<NaiveDateTime>,
     }
 }
 
@@ -45,6 +53,14 @@ impl EmergencyAccess {
             updated_at: now,
             key_encrypted: None,
             last_notification_at: None,
+            reminder_interval_hours: None,
+            escalation_after_hours: None,
+            escalation_contacts: None,
+            escalated_at: None,
+            org_uuid: None,
+            scope_folders: None,
+            key_fingerprint: None,
+            key_invalid_at: None,
         }
     }
 
@@ -56,12 +72,80 @@ impl EmergencyAccess {
         }
     }
 
+    pub fn escalation_contacts_list(&self) -> Vec<String> {
+        split_list(&self.escalation_contacts)
+    }
+
+    pub fn scope_folders_list(&self) -> Vec<String> {
+        split_list(&self.scope_folders)
+    }
+
+    /// Returns true if the grantee is only allowed to view a part of the vault.
+    pub fn has_scope(&self) -> bool {
+        self.scope_folders.is_some()
+    }
+
+    /// Checks if the grantor needs to be reminded about a pending recovery request.
+    /// Without a custom interval, a daily reminder is only sent during the last day before the recovery is approved.
+    pub fn is_reminder_due(&self, now: &NaiveDateTime) -> bool {
+        let Some(recovery_initiated_at) = self.recovery_initiated_at else {
+            return false;
+        };
+
+        let (first_reminder_at, interval) = match self.reminder_interval_hours {
+            Some(hours) if hours > 0 => {
+                let interval = TimeDelta::try_hours(i64::from(hours)).unwrap();
+                (recovery_initiated_at + interval, interval)
+            }
+            _ => (
+                recovery_initiated_at + TimeDelta::try_days(i64::from(self.wait_time_days - 1)).unwrap(),
+                TimeDelta::try_days(1).unwrap(),
+            ),
+        };
+
+        // Check if the interval has passed since the previous notification, else no notification has been sent before
+        let next_reminder_at = match self.last_notification_at {
+            Some(last_notification_at) => last_notification_at + interval,
+            None => *now,
+        };
+
+        first_reminder_at.le(now) && next_reminder_at.le(now)
+    }
+
+    /// Checks if the escalation contacts need to be notified about a recovery request the grantor did not act upon.
+    /// Escalation only happens once per recovery request.
+    pub fn is_escalation_due(&self, now: &NaiveDateTime) -> bool {
+        match (self.recovery_initiated_at, self.escalation_after_hours, self.escalated_at) {
+            (Some(recovery_initiated_at), Some(hours), None) if hours > 0 && self.escalation_contacts.is_some() => {
+                (recovery_initiated_at + TimeDelta::try_hours(i64::from(hours)).unwrap()).le(now)
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the number of full days left before the recovery request is approved automatically.
+    pub fn days_until_recovery(&self, now: &NaiveDateTime) -> i64 {
+        match self.recovery_initiated_at {
+            Some(recovery_initiated_at) => {
+                let recovery_allowed_at =
+                    recovery_initiated_at + TimeDelta::try_days(i64::from(self.wait_time_days)).unwrap();
+                (recovery_allowed_at - *now).num_days().max(0)
+            }
+            None => i64::from(self.wait_time_days),
+        }
+    }
+
     pub fn to_json(&self) -> Value {
         json!({
             "Id": self.uuid,
             "Status": self.status,
             "Type": self.atype,
             "WaitTimeDays": self.wait_time_days,
+            "ReminderIntervalHours": self.reminder_interval_hours,
+            "EscalationAfterHours": self.escalation_after_hours,
+            "EscalationContacts": self.escalation_contacts_list(),
+            "OrganizationId": self.org_uuid,
+            "ScopeFolderIds": self.scope_folders_list(),
             "Object": "emergencyAccess",
         })
     }
@@ -77,6 +161,7 @@ impl EmergencyAccess {
             "GrantorId": grantor_user.uuid,
             "Email": grantor_user.email,
             "Name": grantor_user.name,
+            "OrganizationId": self.org_uuid,
             "Object": "emergencyAccessGrantorDetails",
         })
     }
@@ -105,11 +190,23 @@ impl EmergencyAccess {
             "GranteeId": grantee_user.uuid,
             "Email": grantee_user.email,
             "Name": grantee_user.name,
+            "ReminderIntervalHours": self.reminder_interval_hours,
+            "EscalationAfterHours": self.escalation_after_hours,
+            "EscalationContacts": self.escalation_contacts_list(),
+            "OrganizationId": self.org_uuid,
+            "ScopeFolderIds": self.scope_folders_list(),
             "Object": "emergencyAccessGranteeDetails",
         }))
     }
 }
 
+fn split_list(value: &Option<String>) -> Vec<String> {
+    match value {
+        Some(value) => value.split(',').map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect(),
+        None => Vec::new(),
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum EmergencyAccessType {
     View = 0,
@@ -211,6 +308,37 @@ impl EmergencyAccess {
         }}
     }
 
+    pub async fn update_key_invalid_date_and_save(
+        &mut self,
+        date: Option<NaiveDateTime>,
+        conn: &mut DbConn,
+    ) -> EmptyResult {
+        self.key_invalid_at = date;
+
+        db_run! {conn: {
+            crate::util::retry(|| {
+                diesel::update(emergency_access::table.filter(emergency_access::uuid.eq(&self.uuid)))
+                    .set(emergency_access::key_invalid_at.eq(date))
+                    .execute(conn)
+            }, 10)
+            .map_res("Error updating emergency access key validation date")
+        }}
+    }
+
+    pub async fn update_escalation_date_and_save(&mut self, date: &NaiveDateTime, conn: &mut DbConn) -> EmptyResult {
+        self.escalated_at = Some(date.to_owned());
+        date.clone_into(&mut self.updated_at);
+
+        db_run! {conn: {
+            crate::util::retry(|| {
+                diesel::update(emergency_access::table.filter(emergency_access::uuid.eq(&self.uuid)))
+                    .set((emergency_access::escalated_at.eq(date), emergency_access::updated_at.eq(date)))
+                    .execute(conn)
+            }, 10)
+            .map_res("Error updating emergency access escalation date")
+        }}
+    }
+
     pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
         for ea in Self::find_all_by_grantor_uuid(user_uuid, conn).await {
             ea.delete(conn).await?;
@@ -228,6 +356,32 @@ impl EmergencyAccess {
         Ok(())
     }
 
+    /// Removes the grants created through the organization policy where the user is either the grantor or the grantee.
+    pub async fn delete_all_by_org_and_user(org_uuid: &str, user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        for ea in Self::find_all_by_org(org_uuid, conn).await {
+            if ea.grantor_uuid == user_uuid || ea.grantee_uuid.as_deref() == Some(user_uuid) {
+                ea.delete(conn).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn delete_all_by_org_and_grantee(org_uuid: &str, grantee_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        for ea in Self::find_all_by_org(org_uuid, conn).await {
+            if ea.grantee_uuid.as_deref() == Some(grantee_uuid) {
+                ea.delete(conn).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn delete_all_by_org(org_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        for ea in Self::find_all_by_org(org_uuid, conn).await {
+            ea.delete(conn).await?;
+        }
+        Ok(())
+    }
+
     pub async fn delete(self, conn: &mut DbConn) -> EmptyResult {
         User::update_uuid_revision(&self.grantor_uuid, conn).await;
 
@@ -271,6 +425,14 @@ impl EmergencyAccess {
         }}
     }
 
+    pub async fn find_all_confirmed(conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            emergency_access::table
+                .filter(emergency_access::status.ge(EmergencyAccessStatus::Confirmed as i32))
+                .load::<EmergencyAccessDb>(conn).expect("Error loading emergency_access").from_db()
+        }}
+    }
+
     pub async fn find_by_uuid_and_grantor_uuid(uuid: &str, grantor_uuid: &str, conn: &mut DbConn) -> Option<Self> {
         db_run! { conn: {
             emergency_access::table
@@ -308,6 +470,23 @@ impl EmergencyAccess {
         }}
     }
 
+    pub async fn find_all_by_org(org_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            emergency_access::table
+                .filter(emergency_access::org_uuid.eq(org_uuid))
+                .load::<EmergencyAccessDb>(conn).expect("Error loading emergency_access").from_db()
+        }}
+    }
+
+    pub async fn find_all_by_org_and_grantor_uuid(org_uuid: &str, grantor_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            emergency_access::table
+                .filter(emergency_access::org_uuid.eq(org_uuid))
+                .filter(emergency_access::grantor_uuid.eq(grantor_uuid))
+                .load::<EmergencyAccessDb>(conn).expect("Error loading emergency_access").from_db()
+        }}
+    }
+
     pub async fn find_all_by_grantor_uuid(grantor_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
         db_run! { conn: {
             emergency_access::table
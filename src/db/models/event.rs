@@ -1,4 +1,7 @@
-use crate::db::DbConn;
+use crate::db::{
+    scope::{audit, Scope},
+    DbConn,
+};
 use serde_json::Value;
 
 use crate::{api::EmptyResult, error::MapResult, CONFIG};
@@ -32,9 +35,23 @@ db_object! {
         pub provider_uuid: Option<String>,
         pub provider_user_uuid: Option<String>,
         pub provider_org_uuid: Option<String>,
+        pub emergency_access_uuid: Option<String>,
+        pub grantee_user_uuid: Option<String>,
     }
 }
 
+/// The filters of `Event::find_by_organization_filtered`.
+pub struct EventFilter {
+    pub start: Option<NaiveDateTime>,
+    pub end: Option<NaiveDateTime>,
+    pub act_user_uuid: Option<String>,
+    pub cipher_uuid: Option<String>,
+    /// All the types when empty
+    pub event_types: Vec<i32>,
+    /// The date and the UUID of the last event already returned, the next ones come after it in the order of the query
+    pub cursor: Option<(NaiveDateTime, String)>,
+}
+
 // Upstream enum: https://github.com/bitwarden/server/blob/8a22c0479e987e756ce7412c48a732f9002f0a2d/src/Core/Enums/EventType.cs
 #[derive(Debug, Copy, Clone)]
 pub enum EventType {
@@ -49,6 +66,8 @@ pub enum EventType {
     UserClientExportedVault = 1007,
     // UserUpdatedTempPassword = 1008, // Not supported
     // UserMigratedKeyToKeyConnector = 1009, // Not supported
+    // Vaultwarden specific
+    UserRotatedKeys = 1090,
 
     // Cipher
     CipherCreated = 1100,
@@ -70,6 +89,19 @@ pub enum EventType {
     CipherRestored = 1116,
     CipherClientToggledCardNumberVisible = 1117,
 
+    // Emergency access (Vaultwarden specific)
+    EmergencyAccessInvited = 1200,
+    EmergencyAccessAccepted = 1201,
+    EmergencyAccessConfirmed = 1202,
+    EmergencyAccessRecoveryInitiated = 1203,
+    EmergencyAccessRecoveryApproved = 1204,
+    EmergencyAccessRecoveryRejected = 1205,
+    EmergencyAccessRecoveryTimedOut = 1206,
+    EmergencyAccessViewed = 1207,
+    EmergencyAccessTakeover = 1208,
+    EmergencyAccessDeleted = 1209,
+    EmergencyAccessRevoked = 1210,
+
     // Collection
     CollectionCreated = 1300,
     CollectionUpdated = 1301,
@@ -94,6 +126,17 @@ pub enum EventType {
     // OrganizationUserFirstSsoLogin = 1510, // Not supported
     OrganizationUserRevoked = 1511,
     OrganizationUserRestored = 1512,
+    // Vaultwarden specific
+    OrganizationUserEmergencyAccessEnroll = 1590,
+    OrganizationUserEmergencyAccessWithdraw = 1591,
+    OrganizationUserEmergencyAccessInitiated = 1592,
+    OrganizationUserEmergencyAccessApproved = 1593,
+    OrganizationUserEmergencyAccessRejected = 1594,
+    OrganizationUserEmergencyAccessUsed = 1595,
+    OrganizationUserAdminReset2fa = 1596,
+    OrganizationUserProvisioned = 1597,
+    OrganizationUserProvisionedPendingApproval = 1598,
+    OrganizationUserProvisioningRejected = 1599,
 
     // Organization
     OrganizationUpdated = 1600,
@@ -128,7 +171,7 @@ impl Event {
         };
 
         Self {
-            uuid: crate::util::get_uuid(),
+            uuid: crate::util::get_row_uuid(),
             event_type,
             user_uuid: None,
             org_uuid: None,
@@ -144,6 +187,8 @@ impl Event {
             provider_uuid: None,
             provider_user_uuid: None,
             provider_org_uuid: None,
+            emergency_access_uuid: None,
+            grantee_user_uuid: None,
         }
     }
 
@@ -166,6 +211,8 @@ impl Event {
             "providerId": self.provider_uuid,
             "providerUserId": self.provider_user_uuid,
             "providerOrganizationId": self.provider_org_uuid,
+            "emergencyAccessId": self.emergency_access_uuid,
+            "granteeUserId": self.grantee_user_uuid,
             // "installationId": null, // Not supported
         })
     }
@@ -175,6 +222,8 @@ impl Event {
 /// https://github.com/bitwarden/server/blob/8a22c0479e987e756ce7412c48a732f9002f0a2d/src/Core/Services/Implementations/EventService.cs
 impl Event {
     pub const PAGE_SIZE: i64 = 30;
+    /// The range of the event types of the accounts themselves.
+    pub const USER_EVENT_TYPES: (i32, i32) = (1000, 1099);
 
     /// #############
     /// Basic Queries
@@ -245,22 +294,51 @@ impl Event {
 
     /// ##############
     /// Custom Queries
-    pub async fn find_by_organization_uuid(
+    /// Find the events of an organization which match the filter, the oldest first when `ascending`.
+    pub async fn find_by_organization_filtered(
         org_uuid: &str,
-        start: &NaiveDateTime,
-        end: &NaiveDateTime,
+        filter: &EventFilter,
+        ascending: bool,
+        limit: i64,
         conn: &mut DbConn,
     ) -> Vec<Self> {
-        db_run! { conn: {
-            event::table
-                .filter(event::org_uuid.eq(org_uuid))
-                .filter(event::event_date.between(start, end))
-                .order_by(event::event_date.desc())
-                .limit(Self::PAGE_SIZE)
+        let events = db_run! { conn: {
+            let mut query = event::table.filter(event::org_uuid.eq(org_uuid)).into_boxed();
+            if let Some(start) = &filter.start {
+                query = query.filter(event::event_date.ge(start));
+            }
+            if let Some(end) = &filter.end {
+                query = query.filter(event::event_date.le(end));
+            }
+            if let Some(act_user_uuid) = &filter.act_user_uuid {
+                query = query.filter(event::act_user_uuid.eq(act_user_uuid));
+            }
+            if let Some(cipher_uuid) = &filter.cipher_uuid {
+                query = query.filter(event::cipher_uuid.eq(cipher_uuid));
+            }
+            if !filter.event_types.is_empty() {
+                query = query.filter(event::event_type.eq_any(&filter.event_types));
+            }
+            // The events with the same date are ordered by their UUID, so a page never skips nor repeats one
+            if let Some((date, uuid)) = &filter.cursor {
+                query = if ascending {
+                    query.filter(event::event_date.gt(date).or(event::event_date.eq(date).and(event::uuid.gt(uuid))))
+                } else {
+                    query.filter(event::event_date.lt(date).or(event::event_date.eq(date).and(event::uuid.lt(uuid))))
+                };
+            }
+            query = if ascending {
+                query.order_by((event::event_date.asc(), event::uuid.asc()))
+            } else {
+                query.order_by((event::event_date.desc(), event::uuid.desc()))
+            };
+            query
+                .limit(limit)
                 .load::<EventDb>(conn)
                 .expect("Error filtering events")
                 .from_db()
-        }}
+        }};
+        audit(events, Scope::Org(org_uuid), "Event::find_by_organization_filtered")
     }
 
     pub async fn count_by_org(org_uuid: &str, conn: &mut DbConn) -> i64 {
@@ -314,6 +392,106 @@ impl Event {
         }}
     }
 
+    /// Find the events of an emergency access where the user is either the grantor or the grantee.
+    /// They're kept after the emergency access is deleted.
+    pub async fn find_by_emergency_access_uuid_and_user(
+        emergency_access_uuid: &str,
+        user_uuid: &str,
+        start: &NaiveDateTime,
+        end: &NaiveDateTime,
+        conn: &mut DbConn,
+    ) -> Vec<Self> {
+        db_run! { conn: {
+            event::table
+                .filter(event::emergency_access_uuid.eq(emergency_access_uuid))
+                .filter(event::user_uuid.eq(user_uuid).or(event::grantee_user_uuid.eq(user_uuid)))
+                .filter(event::event_date.between(start, end))
+                .order_by(event::event_date.desc())
+                .limit(Self::PAGE_SIZE)
+                .load::<EventDb>(conn)
+                .expect("Error filtering events")
+                .from_db()
+        }}
+    }
+
+    /// Find the emergency access events where the user is either the grantor or the grantee.
+    pub async fn find_emergency_access_by_user_uuid(
+        user_uuid: &str,
+        start: &NaiveDateTime,
+        end: &NaiveDateTime,
+        conn: &mut DbConn,
+    ) -> Vec<Self> {
+        db_run! { conn: {
+            event::table
+                .filter(event::emergency_access_uuid.is_not_null())
+                .filter(event::user_uuid.eq(user_uuid).or(event::grantee_user_uuid.eq(user_uuid)))
+                .filter(event::event_date.between(start, end))
+                .order_by(event::event_date.desc())
+                .limit(Self::PAGE_SIZE)
+                .load::<EventDb>(conn)
+                .expect("Error filtering events")
+                .from_db()
+        }}
+    }
+
+    /// Find the personal events of a user, the ones of the account which aren't linked to an organization.
+    pub async fn find_personal_by_user_uuid(
+        user_uuid: &str,
+        start: &NaiveDateTime,
+        end: &NaiveDateTime,
+        conn: &mut DbConn,
+    ) -> Vec<Self> {
+        db_run! { conn: {
+            event::table
+                .filter(event::user_uuid.eq(user_uuid))
+                .filter(event::org_uuid.is_null())
+                .filter(event::event_type.between(Self::USER_EVENT_TYPES.0, Self::USER_EVENT_TYPES.1))
+                .filter(event::event_date.between(start, end))
+                .order_by(event::event_date.desc())
+                .limit(Self::PAGE_SIZE)
+                .load::<EventDb>(conn)
+                .expect("Error filtering events")
+                .from_db()
+        }}
+    }
+
+    /// Count the personal events of all the users since a date, per event type.
+    pub async fn count_personal_by_type(since: &NaiveDateTime, conn: &mut DbConn) -> Vec<(i32, i64)> {
+        db_run! { conn: {
+            event::table
+                .filter(event::org_uuid.is_null())
+                .filter(event::event_type.between(Self::USER_EVENT_TYPES.0, Self::USER_EVENT_TYPES.1))
+                .filter(event::event_date.ge(since))
+                .group_by(event::event_type)
+                .select((event::event_type, diesel::dsl::count_star()))
+                .order_by(event::event_type)
+                .load::<(i32, i64)>(conn)
+                .unwrap_or_default()
+        }}
+    }
+
+    /// The users with the most personal events of a type since a date, with their number of events.
+    pub async fn top_personal_users_by_type(
+        event_type: i32,
+        since: &NaiveDateTime,
+        limit: i64,
+        conn: &mut DbConn,
+    ) -> Vec<(String, i64)> {
+        db_run! { conn: {
+            event::table
+                .inner_join(users::table.on(event::user_uuid.eq(users::uuid.nullable())))
+                .filter(event::org_uuid.is_null())
+                .filter(event::event_type.eq(event_type))
+                .filter(event::event_date.ge(since))
+                .group_by(users::email)
+                .select((users::email, diesel::dsl::count_star()))
+                .order_by(diesel::dsl::count_star().desc())
+                .limit(limit)
+                .load::<(String, i64)>(conn)
+                .unwrap_or_default()
+        }}
+    }
+
     pub async fn clean_events(conn: &mut DbConn) -> EmptyResult {
         if let Some(days_to_retain) = CONFIG.events_days_retain() {
             let dt = Utc::now().naive_utc() - TimeDelta::try_days(days_to_retain).unwrap();
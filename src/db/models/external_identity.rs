@@ -0,0 +1,148 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use crate::util::format_date;
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = external_identities)]
+    #[diesel(treat_none_as_null = true)]
+    #[diesel(primary_key(uuid))]
+    pub struct ExternalIdentity {
+        pub uuid: String,
+        pub user_uuid: String,
+        pub issuer: String,  // The identity provider, for example the URL of the LDAP server
+        pub subject: String, // The identifier of the user at the issuer, for example their DN
+        pub email: String,   // The email address of the user when the identity was linked
+        pub creation_date: NaiveDateTime,
+        pub last_used_date: NaiveDateTime,
+    }
+}
+
+/// Local methods
+impl ExternalIdentity {
+    pub fn new(user_uuid: String, issuer: String, subject: String, email: String) -> Self {
+        let now = Utc::now().naive_utc();
+        Self {
+            uuid: crate::util::get_uuid(),
+            user_uuid,
+            issuer,
+            subject,
+            email,
+            creation_date: now,
+            last_used_date: now,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "Id": self.uuid,
+            "Issuer": self.issuer,
+            "Subject": self.subject,
+            "Email": self.email,
+            "CreationDate": format_date(&self.creation_date),
+            "LastUsedDate": format_date(&self.last_used_date),
+            "Object": "externalIdentity",
+        })
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl ExternalIdentity {
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                match diesel::replace_into(external_identities::table)
+                    .values(ExternalIdentityDb::to_db(self))
+                    .execute(conn)
+                {
+                    Ok(_) => Ok(()),
+                    // Record already exists and causes a Foreign Key Violation because replace_into() wants to delete the record first.
+                    Err(diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::ForeignKeyViolation, _)) => {
+                        diesel::update(external_identities::table)
+                            .filter(external_identities::uuid.eq(&self.uuid))
+                            .set(ExternalIdentityDb::to_db(self))
+                            .execute(conn)
+                            .map_res("Error saving external identity")
+                    }
+                    Err(e) => Err(e.into()),
+                }.map_res("Error saving external identity")
+            }
+            postgresql {
+                let value = ExternalIdentityDb::to_db(self);
+                diesel::insert_into(external_identities::table)
+                    .values(&value)
+                    .on_conflict(external_identities::uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving external identity")
+            }
+        }
+    }
+
+    pub async fn delete(self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(external_identities::table.filter(external_identities::uuid.eq(self.uuid)))
+                .execute(conn)
+                .map_res("Error deleting external identity")
+        }}
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(external_identities::table.filter(external_identities::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting external identities")
+        }}
+    }
+
+    pub async fn find_by_uuid_and_user(uuid: &str, user_uuid: &str, conn: &mut DbConn) -> Option<Self> {
+        db_run! { conn: {
+            external_identities::table
+                .filter(external_identities::uuid.eq(uuid))
+                .filter(external_identities::user_uuid.eq(user_uuid))
+                .first::<ExternalIdentityDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub async fn find_by_issuer_and_subject(issuer: &str, subject: &str, conn: &mut DbConn) -> Option<Self> {
+        db_run! { conn: {
+            external_identities::table
+                .filter(external_identities::issuer.eq(issuer))
+                .filter(external_identities::subject.eq(subject))
+                .first::<ExternalIdentityDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub async fn find_by_user_and_issuer(user_uuid: &str, issuer: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            external_identities::table
+                .filter(external_identities::user_uuid.eq(user_uuid))
+                .filter(external_identities::issuer.eq(issuer))
+                .load::<ExternalIdentityDb>(conn)
+                .expect("Error loading external identities")
+                .from_db()
+        }}
+    }
+
+    pub async fn find_all_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            external_identities::table
+                .filter(external_identities::user_uuid.eq(user_uuid))
+                .order(external_identities::creation_date)
+                .load::<ExternalIdentityDb>(conn)
+                .expect("Error loading external identities")
+                .from_db()
+        }}
+    }
+}
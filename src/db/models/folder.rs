@@ -1,7 +1,7 @@
 use chrono::{NaiveDateTime, Utc};
 use serde_json::Value;
 
-use super::User;
+use super::{Tombstone, User};
 
 db_object! {
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
@@ -60,7 +60,10 @@ impl FolderCipher {
     }
 }
 
-use crate::db::DbConn;
+use crate::db::{
+    scope::{audit, Scope},
+    DbConn,
+};
 
 use crate::api::EmptyResult;
 use crate::error::MapResult;
@@ -105,6 +108,7 @@ impl Folder {
     pub async fn delete(&self, conn: &mut DbConn) -> EmptyResult {
         User::update_uuid_revision(&self.user_uuid, conn).await;
         FolderCipher::delete_all_by_folder(&self.uuid, conn).await?;
+        Tombstone::for_folder(self).save(conn).await?;
 
         db_run! { conn: {
             diesel::delete(folders::table.filter(folders::uuid.eq(&self.uuid)))
@@ -131,13 +135,14 @@ impl Folder {
     }
 
     pub async fn find_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
-        db_run! { conn: {
+        let folders = db_run! { conn: {
             folders::table
                 .filter(folders::user_uuid.eq(user_uuid))
                 .load::<FolderDb>(conn)
                 .expect("Error loading folders")
                 .from_db()
-        }}
+        }};
+        audit(folders, Scope::User(user_uuid), "Folder::find_by_user")
     }
 }
 
@@ -122,7 +122,10 @@ impl GroupUser {
     }
 }
 
-use crate::db::DbConn;
+use crate::db::{
+    scope::{audit, Scope},
+    DbConn,
+};
 
 use crate::api::EmptyResult;
 use crate::error::MapResult;
@@ -173,13 +176,14 @@ impl Group {
     }
 
     pub async fn find_by_organization(organizations_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
-        db_run! { conn: {
+        let groups = db_run! { conn: {
             groups::table
                 .filter(groups::organizations_uuid.eq(organizations_uuid))
                 .load::<GroupDb>(conn)
                 .expect("Error loading groups")
                 .from_db()
-        }}
+        }};
+        audit(groups, Scope::Org(organizations_uuid), "Group::find_by_organization")
     }
 
     pub async fn count_by_org(organizations_uuid: &str, conn: &mut DbConn) -> i64 {
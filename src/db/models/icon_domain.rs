@@ -0,0 +1,225 @@
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use serde_json::Value;
+
+use crate::{util::format_naive_datetime_local, CONFIG};
+
+db_object! {
+    // The state of the icon downloads of a domain, which decides when it's downloaded again
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = icon_domains)]
+    #[diesel(treat_none_as_null = true)]
+    #[diesel(primary_key(domain))]
+    pub struct IconDomain {
+        pub domain: String,
+        pub status: i32,        // IconStatus
+        pub failures: i32,      // The consecutive times the site couldn't be reached
+        pub last_error: Option<String>,
+        pub checked_at: NaiveDateTime,
+        pub retry_at: Option<NaiveDateTime>, // Never when None, the TTLs of 0 are "forever"
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum IconStatus {
+    // The icon was downloaded, it's refreshed when the cached file is older than `ICON_CACHE_TTL`
+    Found = 0,
+    // The site answered, but without a valid icon, it's tried again after `ICON_CACHE_NEGTTL`
+    Missing = 1,
+    // The site couldn't be reached, it's tried again with a backoff until `ICON_RETRY_BUDGET` is spent
+    Unreachable = 2,
+    // The circuit breaker is open, the site isn't tried again before `ICON_CIRCUIT_BREAKER_TTL`
+    Dead = 3,
+    // A domain hint given to the pre-warm job, its icon is downloaded by `ICON_PREWARM_SCHEDULE`
+    Pending = 4,
+}
+
+impl IconStatus {
+    pub const ALL: [IconStatus; 5] =
+        [IconStatus::Found, IconStatus::Missing, IconStatus::Unreachable, IconStatus::Dead, IconStatus::Pending];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            IconStatus::Found => "found",
+            IconStatus::Missing => "missing",
+            IconStatus::Unreachable => "unreachable",
+            IconStatus::Dead => "dead",
+            IconStatus::Pending => "pending",
+        }
+    }
+}
+
+// The first retry of an unreachable site is after 15 minutes, the delay then doubles up to `ICON_CACHE_NEGTTL`
+const RETRY_BASE_SECONDS: i64 = 15 * 60;
+
+fn after_seconds(seconds: u64) -> Option<NaiveDateTime> {
+    if seconds == 0 {
+        return None;
+    }
+    let delay = TimeDelta::try_seconds(i64::try_from(seconds).ok()?)?;
+    Utc::now().naive_utc().checked_add_signed(delay)
+}
+
+/// Local methods
+impl IconDomain {
+    pub fn new(domain: &str) -> Self {
+        let now = Utc::now().naive_utc();
+        Self {
+            domain: domain.to_string(),
+            status: IconStatus::Found as i32,
+            failures: 0,
+            last_error: None,
+            checked_at: now,
+            retry_at: Some(now),
+        }
+    }
+
+    pub fn pending(domain: &str) -> Self {
+        Self {
+            status: IconStatus::Pending as i32,
+            ..Self::new(domain)
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.status == IconStatus::Pending as i32
+    }
+
+    /// Whether the icon can't be downloaded yet, after a failure.
+    pub fn is_blocked(&self) -> bool {
+        self.status != IconStatus::Found as i32
+            && self.retry_at.map_or(true, |retry_at| retry_at > Utc::now().naive_utc())
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.status == IconStatus::Dead as i32
+    }
+
+    pub fn found(&mut self) {
+        self.set(IconStatus::Found, None, after_seconds(CONFIG.icon_cache_ttl()));
+        self.failures = 0;
+    }
+
+    pub fn missing(&mut self, error: String) {
+        self.set(IconStatus::Missing, Some(error), after_seconds(CONFIG.icon_cache_negttl()));
+        self.failures = 0;
+    }
+
+    /// Records a failure to reach the site, and opens the circuit breaker once the retry budget is spent.
+    pub fn unreachable(&mut self, error: String) {
+        self.failures += 1;
+        if self.failures >= CONFIG.icon_retry_budget() as i32 {
+            self.set(IconStatus::Dead, Some(error), after_seconds(CONFIG.icon_circuit_breaker_ttl()));
+        } else {
+            let delay = RETRY_BASE_SECONDS.saturating_mul(1 << (self.failures - 1).min(16)) as u64;
+            let max_delay = CONFIG.icon_cache_negttl();
+            let delay = if max_delay == 0 {
+                delay
+            } else {
+                delay.min(max_delay)
+            };
+            self.set(IconStatus::Unreachable, Some(error), after_seconds(delay));
+        }
+    }
+
+    fn set(&mut self, status: IconStatus, error: Option<String>, retry_at: Option<NaiveDateTime>) {
+        self.status = status as i32;
+        self.last_error = error;
+        self.checked_at = Utc::now().naive_utc();
+        self.retry_at = retry_at;
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "domain": self.domain,
+            "failures": self.failures,
+            "last_error": self.last_error,
+            "checked_at": format_naive_datetime_local(&self.checked_at, "%Y-%m-%d %H:%M:%S %Z"),
+            "retry_at": self.retry_at.map(|dt| format_naive_datetime_local(&dt, "%Y-%m-%d %H:%M:%S %Z")),
+        })
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl IconDomain {
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(icon_domains::table)
+                    .values(IconDomainDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving icon domain")
+            }
+            postgresql {
+                let value = IconDomainDb::to_db(self);
+                diesel::insert_into(icon_domains::table)
+                    .values(&value)
+                    .on_conflict(icon_domains::domain)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving icon domain")
+            }
+        }
+    }
+
+    pub async fn find_by_domain(domain: &str, conn: &mut DbConn) -> Option<Self> {
+        db_run! { conn: {
+            icon_domains::table
+                .filter(icon_domains::domain.eq(domain))
+                .first::<IconDomainDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    /// The domains with the status, the last checked first.
+    pub async fn find_by_status(status: IconStatus, limit: i64, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            icon_domains::table
+                .filter(icon_domains::status.eq(status as i32))
+                .order(icon_domains::checked_at.desc())
+                .limit(limit)
+                .load::<IconDomainDb>(conn)
+                .expect("Error loading icon domains")
+                .from_db()
+        }}
+    }
+
+    /// The pending domains, the longest waiting first.
+    pub async fn find_pending(limit: i64, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            icon_domains::table
+                .filter(icon_domains::status.eq(IconStatus::Pending as i32))
+                .order(icon_domains::checked_at.asc())
+                .limit(limit)
+                .load::<IconDomainDb>(conn)
+                .expect("Error loading pending icon domains")
+                .from_db()
+        }}
+    }
+
+    /// Makes the domains whose icon was found pending again, to download the icons which aren't cached anymore.
+    pub async fn set_found_pending(conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::update(icon_domains::table.filter(icon_domains::status.eq(IconStatus::Found as i32)))
+                .set(icon_domains::status.eq(IconStatus::Pending as i32))
+                .execute(conn)
+                .map_res("Error updating icon domains")
+        }}
+    }
+
+    pub async fn count_by_status(status: IconStatus, conn: &mut DbConn) -> i64 {
+        db_run! { conn: {
+            icon_domains::table
+                .filter(icon_domains::status.eq(status as i32))
+                .count()
+                .first::<i64>(conn)
+                .unwrap_or(0)
+        }}
+    }
+}
@@ -0,0 +1,157 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use crate::util::{format_naive_datetime_local, get_uuid};
+
+db_object! {
+    // The settings of a scheduled job changed in the admin panel, the jobs without one use the config
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = jobs)]
+    #[diesel(treat_none_as_null = true)]
+    #[diesel(primary_key(name))]
+    pub struct JobSetting {
+        pub name: String,
+        pub enabled: bool,
+        pub schedule: Option<String>, // The schedule of the config when None
+        pub updated_at: NaiveDateTime,
+    }
+
+    // A run of a scheduled job, only the latest ones of each job are kept
+    #[derive(Identifiable, Queryable, Insertable)]
+    #[diesel(table_name = job_runs)]
+    #[diesel(primary_key(uuid))]
+    pub struct JobRun {
+        pub uuid: String,
+        pub job_name: String,
+        pub manual: bool, // Started from the admin panel
+        pub started_at: NaiveDateTime,
+        pub duration_ms: i64,
+        pub error: Option<String>,
+    }
+}
+
+/// Local methods
+impl JobSetting {
+    pub fn new(name: &str, enabled: bool, schedule: Option<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            enabled,
+            schedule,
+            updated_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+impl JobRun {
+    pub fn new(
+        job_name: &str,
+        manual: bool,
+        started_at: NaiveDateTime,
+        duration_ms: i64,
+        error: Option<String>,
+    ) -> Self {
+        Self {
+            uuid: get_uuid(),
+            job_name: job_name.to_string(),
+            manual,
+            started_at,
+            duration_ms,
+            error,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "started_at": format_naive_datetime_local(&self.started_at, "%Y-%m-%d %H:%M:%S %Z"),
+            "duration_ms": self.duration_ms,
+            "manual": self.manual,
+            "error": self.error,
+        })
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl JobSetting {
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(jobs::table)
+                    .values(JobSettingDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving job settings")
+            }
+            postgresql {
+                let value = JobSettingDb::to_db(self);
+                diesel::insert_into(jobs::table)
+                    .values(&value)
+                    .on_conflict(jobs::name)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving job settings")
+            }
+        }
+    }
+
+    pub async fn get_all(conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            jobs::table
+                .load::<JobSettingDb>(conn)
+                .expect("Error loading job settings")
+                .from_db()
+        }}
+    }
+}
+
+impl JobRun {
+    pub async fn insert(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::insert_into(job_runs::table)
+                .values(JobRunDb::to_db(self))
+                .execute(conn)
+                .map_res("Error saving job run")
+        }}
+    }
+
+    /// The latest runs of the job, the last first.
+    pub async fn find_by_job(job_name: &str, limit: i64, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            job_runs::table
+                .filter(job_runs::job_name.eq(job_name))
+                .order(job_runs::started_at.desc())
+                .limit(limit)
+                .load::<JobRunDb>(conn)
+                .expect("Error loading job runs")
+                .from_db()
+        }}
+    }
+
+    /// Deletes the runs of the job older than the `keep` latest ones.
+    pub async fn delete_all_but_latest(job_name: &str, keep: i64, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            let oldest_kept = job_runs::table
+                .filter(job_runs::job_name.eq(job_name))
+                .order(job_runs::started_at.desc())
+                .offset(keep - 1)
+                .select(job_runs::started_at)
+                .first::<NaiveDateTime>(conn)
+                .optional()
+                .map_res("Error loading job runs")?;
+            match oldest_kept {
+                Some(oldest_kept) => diesel::delete(
+                    job_runs::table
+                        .filter(job_runs::job_name.eq(job_name))
+                        .filter(job_runs::started_at.lt(oldest_kept)),
+                )
+                .execute(conn)
+                .map_res("Error deleting job runs"),
+                None => Ok(()),
+            }
+        }}
+    }
+}
@@ -0,0 +1,192 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use crate::util::format_naive_datetime_local;
+
+db_object! {
+    // The outgoing mails, with their content so they can be sent again from the admin panel
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = mail_log)]
+    #[diesel(treat_none_as_null = true)]
+    #[diesel(primary_key(uuid))]
+    pub struct MailLog {
+        pub uuid: String,
+        pub recipient: String,
+        pub template: String,
+        pub subject: String,
+        pub body_html: String,
+        pub body_text: String,
+        pub status: i32,        // MailLogStatus
+        pub error: Option<String>,
+        pub created_at: NaiveDateTime,
+        pub updated_at: NaiveDateTime,
+        pub attachments: Option<String>, // JSON, see mail::encode_attachments
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum MailLogStatus {
+    // Waiting in the queue, the error is the one of the last attempt
+    Queued = 0,
+    Sent = 1,
+    Failed = 2,
+    // Not sent, the recipient turned off this type of mail
+    Skipped = 3,
+}
+
+impl MailLogStatus {
+    pub const ALL: [MailLogStatus; 4] =
+        [MailLogStatus::Queued, MailLogStatus::Sent, MailLogStatus::Failed, MailLogStatus::Skipped];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MailLogStatus::Queued => "queued",
+            MailLogStatus::Sent => "sent",
+            MailLogStatus::Failed => "failed",
+            MailLogStatus::Skipped => "skipped",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|status| status.name() == name)
+    }
+
+    fn from_i32(status: i32) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| *s as i32 == status)
+    }
+}
+
+/// Local methods
+impl MailLog {
+    pub fn new(
+        uuid: String,
+        recipient: &str,
+        template: &str,
+        subject: &str,
+        body_html: &str,
+        body_text: &str,
+        status: MailLogStatus,
+    ) -> Self {
+        let now = Utc::now().naive_utc();
+        Self {
+            uuid,
+            recipient: recipient.to_string(),
+            template: template.to_string(),
+            subject: subject.to_string(),
+            body_html: body_html.to_string(),
+            body_text: body_text.to_string(),
+            status: status as i32,
+            error: None,
+            created_at: now,
+            updated_at: now,
+            attachments: None,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let fmt = "%Y-%m-%d %H:%M:%S %Z";
+        json!({
+            "id": self.uuid,
+            "recipient": self.recipient,
+            "template": self.template,
+            "subject": self.subject,
+            "status": MailLogStatus::from_i32(self.status).map(MailLogStatus::name),
+            "error": self.error,
+            "created_at": format_naive_datetime_local(&self.created_at, fmt),
+            "updated_at": format_naive_datetime_local(&self.updated_at, fmt),
+        })
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl MailLog {
+    pub async fn insert(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::insert_into(mail_log::table)
+                .values(MailLogDb::to_db(self))
+                .execute(conn)
+                .map_res("Error saving mail log")
+        }}
+    }
+
+    pub async fn update_status(
+        uuid: &str,
+        status: MailLogStatus,
+        error: Option<String>,
+        conn: &mut DbConn,
+    ) -> EmptyResult {
+        let now = Utc::now().naive_utc();
+        db_run! { conn: {
+            diesel::update(mail_log::table.filter(mail_log::uuid.eq(uuid)))
+                .set((
+                    mail_log::status.eq(status as i32),
+                    mail_log::error.eq(error),
+                    mail_log::updated_at.eq(now),
+                ))
+                .execute(conn)
+                .map_res("Error updating mail log")
+        }}
+    }
+
+    pub async fn find_by_uuid(uuid: &str, conn: &mut DbConn) -> Option<Self> {
+        db_run! { conn: {
+            mail_log::table
+                .filter(mail_log::uuid.eq(uuid))
+                .first::<MailLogDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    /// The latest mails, filtered by a part of their recipient, their template and their status.
+    pub async fn find_filtered(
+        recipient: Option<&str>,
+        template: Option<&str>,
+        status: Option<MailLogStatus>,
+        limit: i64,
+        conn: &mut DbConn,
+    ) -> Vec<Self> {
+        db_run! { conn: {
+            let mut query = mail_log::table.into_boxed();
+            if let Some(recipient) = recipient {
+                query = query.filter(mail_log::recipient.like(format!("%{recipient}%")));
+            }
+            if let Some(template) = template {
+                query = query.filter(mail_log::template.eq(template));
+            }
+            if let Some(status) = status {
+                query = query.filter(mail_log::status.eq(status as i32));
+            }
+            query
+                .order(mail_log::created_at.desc())
+                .limit(limit)
+                .load::<MailLogDb>(conn)
+                .expect("Error loading mail log")
+                .from_db()
+        }}
+    }
+
+    pub async fn find_templates(conn: &mut DbConn) -> Vec<String> {
+        db_run! { conn: {
+            mail_log::table
+                .select(mail_log::template)
+                .distinct()
+                .order(mail_log::template.asc())
+                .load::<String>(conn)
+                .unwrap_or_default()
+        }}
+    }
+
+    pub async fn delete_older_than(dt: &NaiveDateTime, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(mail_log::table.filter(mail_log::created_at.lt(dt)))
+                .execute(conn)
+                .map_res("Error removing old mail log entries")
+        }}
+    }
+}
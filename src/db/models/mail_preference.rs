@@ -0,0 +1,98 @@
+db_object! {
+    // The notification mails a user turned on or off, they get all of them when there is no preference
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = mail_preferences)]
+    #[diesel(primary_key(user_uuid, mail_type))]
+    pub struct MailPreference {
+        pub user_uuid: String,
+        pub mail_type: i32,     // MailType
+        pub enabled: bool,
+    }
+}
+
+// The mails which users can turn off, the other ones are needed to use their account and are always sent
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum MailType {
+    NewDeviceLogin = 0,
+    PasswordHint = 1,
+    // The updates of the emergency accesses, the alerts about a recovery of the account are always sent
+    EmergencyAccess = 2,
+    AdminAnnouncement = 3,
+}
+
+impl MailType {
+    pub const ALL: [MailType; 4] =
+        [MailType::NewDeviceLogin, MailType::PasswordHint, MailType::EmergencyAccess, MailType::AdminAnnouncement];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MailType::NewDeviceLogin => "NewDeviceLogin",
+            MailType::PasswordHint => "PasswordHint",
+            MailType::EmergencyAccess => "EmergencyAccess",
+            MailType::AdminAnnouncement => "AdminAnnouncement",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|mail_type| mail_type.name() == name)
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl MailPreference {
+    pub async fn is_enabled(user_uuid: &str, mail_type: MailType, conn: &mut DbConn) -> bool {
+        db_run! { conn: {
+            mail_preferences::table
+                .filter(mail_preferences::user_uuid.eq(user_uuid))
+                .filter(mail_preferences::mail_type.eq(mail_type as i32))
+                .select(mail_preferences::enabled)
+                .first::<bool>(conn)
+                .unwrap_or(true)
+        }}
+    }
+
+    pub async fn set(user_uuid: &str, mail_type: MailType, enabled: bool, conn: &mut DbConn) -> EmptyResult {
+        let preference = Self {
+            user_uuid: user_uuid.to_string(),
+            mail_type: mail_type as i32,
+            enabled,
+        };
+        db_run! { conn: {
+            diesel::delete(
+                mail_preferences::table
+                    .filter(mail_preferences::user_uuid.eq(user_uuid))
+                    .filter(mail_preferences::mail_type.eq(mail_type as i32))
+            )
+            .execute(conn)
+            .map_res("Error removing mail preference")?;
+
+            diesel::insert_into(mail_preferences::table)
+                .values(MailPreferenceDb::to_db(&preference))
+                .execute(conn)
+                .map_res("Error saving mail preference")
+        }}
+    }
+
+    pub async fn find_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            mail_preferences::table
+                .filter(mail_preferences::user_uuid.eq(user_uuid))
+                .load::<MailPreferenceDb>(conn)
+                .expect("Error loading mail preferences")
+                .from_db()
+        }}
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(mail_preferences::table.filter(mail_preferences::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error removing mail preferences for user")
+        }}
+    }
+}
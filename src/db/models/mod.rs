@@ -5,15 +5,27 @@ mod collection;
 mod device;
 mod emergency_access;
 mod event;
+mod external_identity;
 mod favorite;
 mod folder;
 mod group;
+mod icon_domain;
+mod job;
+mod mail_log;
+mod mail_preference;
+mod notification_event;
 mod org_policy;
 mod organization;
+mod queued_mail;
 mod send;
+mod tombstone;
+mod transfer;
 mod two_factor;
+mod two_factor_duo_context;
 mod two_factor_incomplete;
 mod user;
+mod web_authn_credential;
+mod web_authn_login_challenge;
 
 pub use self::attachment::Attachment;
 pub use self::auth_request::AuthRequest;
@@ -21,13 +33,27 @@ pub use self::cipher::Cipher;
 pub use self::collection::{Collection, CollectionCipher, CollectionUser};
 pub use self::device::{Device, DeviceType};
 pub use self::emergency_access::{EmergencyAccess, EmergencyAccessStatus, EmergencyAccessType};
-pub use self::event::{Event, EventType};
+pub use self::event::{Event, EventFilter, EventType};
+pub use self::external_identity::ExternalIdentity;
 pub use self::favorite::Favorite;
 pub use self::folder::{Folder, FolderCipher};
 pub use self::group::{CollectionGroup, Group, GroupUser};
-pub use self::org_policy::{OrgPolicy, OrgPolicyErr, OrgPolicyType};
-pub use self::organization::{Organization, OrganizationApiKey, UserOrgStatus, UserOrgType, UserOrganization};
+pub use self::icon_domain::{IconDomain, IconStatus};
+pub use self::job::{JobRun, JobSetting};
+pub use self::mail_log::{MailLog, MailLogStatus};
+pub use self::mail_preference::{MailPreference, MailType};
+pub use self::notification_event::NotificationEvent;
+pub use self::org_policy::{EmergencyAccessPolicyData, OrgPolicy, OrgPolicyErr, OrgPolicyType, TwoFactorPolicyData};
+pub use self::organization::{
+    MemberSort, OrgSort, Organization, OrganizationApiKey, UserOrgStatus, UserOrgType, UserOrganization,
+};
+pub use self::queued_mail::{MailStatus, QueuedMail};
 pub use self::send::{Send, SendType};
+pub use self::tombstone::{Tombstone, TombstoneType};
+pub use self::transfer::{ensure_empty, export_tables, import_rows, table_names, transfer_database};
 pub use self::two_factor::{TwoFactor, TwoFactorType};
+pub use self::two_factor_duo_context::TwoFactorDuoContext;
 pub use self::two_factor_incomplete::TwoFactorIncomplete;
-pub use self::user::{Invitation, User, UserKdfType, UserStampException};
+pub use self::user::{Invitation, User, UserKdfType, UserSort, UserStampException};
+pub use self::web_authn_credential::WebAuthnCredential;
+pub use self::web_authn_login_challenge::WebAuthnLoginChallenge;
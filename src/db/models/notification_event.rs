@@ -0,0 +1,132 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use crate::util::format_date;
+
+db_object! {
+    // The last notifications sent to a user, so the clients can get the ones they missed while disconnected
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = notification_events)]
+    #[diesel(primary_key(uuid))]
+    pub struct NotificationEvent {
+        pub uuid: String,
+        pub user_uuid: String,
+        pub seq: i64,       // Increasing for each notification of the user, used as the cursor
+        pub atype: i32,     // UpdateType
+        pub data: String,   // The notification, as sent to the WebSocket clients, base64 encoded
+        pub created_at: NaiveDateTime,
+        pub delivered: bool, // True once sent to a connected client, or returned as a missed notification
+    }
+}
+
+/// Local methods
+impl NotificationEvent {
+    pub fn new(user_uuid: String, seq: i64, atype: i32, data: String, delivered: bool) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            user_uuid,
+            seq,
+            atype,
+            data,
+            created_at: Utc::now().naive_utc(),
+            delivered,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "Cursor": self.seq,
+            "Type": self.atype,
+            "Data": self.data,
+            "CreatedAt": format_date(&self.created_at),
+            "Delivered": self.delivered,
+            "Object": "notificationEvent",
+        })
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl NotificationEvent {
+    // The events are never updated, only added and removed
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::insert_into(notification_events::table)
+                .values(NotificationEventDb::to_db(self))
+                .execute(conn)
+                .map_res("Error saving notification event")
+        }}
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(notification_events::table.filter(notification_events::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting notification events")
+        }}
+    }
+
+    /// Deletes the events of the user up to the given cursor, to only keep the last ones.
+    pub async fn delete_up_to(user_uuid: &str, seq: i64, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(
+                notification_events::table
+                    .filter(notification_events::user_uuid.eq(user_uuid))
+                    .filter(notification_events::seq.le(seq)),
+            )
+            .execute(conn)
+            .map_res("Error deleting notification events")
+        }}
+    }
+
+    pub async fn mark_delivered_up_to(user_uuid: &str, seq: i64, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::update(
+                notification_events::table
+                    .filter(notification_events::user_uuid.eq(user_uuid))
+                    .filter(notification_events::seq.le(seq)),
+            )
+            .set(notification_events::delivered.eq(true))
+            .execute(conn)
+            .map_res("Error updating notification events")
+        }}
+    }
+
+    pub async fn find_last_seq_by_user(user_uuid: &str, conn: &mut DbConn) -> Option<i64> {
+        db_run! { conn: {
+            notification_events::table
+                .filter(notification_events::user_uuid.eq(user_uuid))
+                .select(notification_events::seq)
+                .order(notification_events::seq.desc())
+                .first::<i64>(conn)
+                .ok()
+        }}
+    }
+
+    pub async fn find_first_seq_by_user(user_uuid: &str, conn: &mut DbConn) -> Option<i64> {
+        db_run! { conn: {
+            notification_events::table
+                .filter(notification_events::user_uuid.eq(user_uuid))
+                .select(notification_events::seq)
+                .order(notification_events::seq.asc())
+                .first::<i64>(conn)
+                .ok()
+        }}
+    }
+
+    pub async fn find_by_user_since(user_uuid: &str, seq: i64, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            notification_events::table
+                .filter(notification_events::user_uuid.eq(user_uuid))
+                .filter(notification_events::seq.gt(seq))
+                .order(notification_events::seq.asc())
+                .load::<NotificationEventDb>(conn)
+                .expect("Error loading notification events")
+                .from_db()
+        }}
+    }
+}
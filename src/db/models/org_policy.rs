@@ -2,7 +2,11 @@ use serde::Deserialize;
 use serde_json::Value;
 
 use crate::api::EmptyResult;
-use crate::db::DbConn;
+use crate::db::{
+    cache,
+    scope::{audit, audit_user_and_orgs, Scope},
+    DbConn,
+};
 use crate::error::MapResult;
 use crate::util::UpCase;
 
@@ -35,6 +39,7 @@ pub enum OrgPolicyType {
     ResetPassword = 8,
     // MaximumVaultTimeout = 9, // Not supported (Not AGPLv3 Licensed)
     // DisablePersonalVaultExport = 10, // Not supported (Not AGPLv3 Licensed)
+    EmergencyAccess = 1000, // Vaultwarden specific, allows owners to become emergency contacts of members
 }
 
 // https://github.com/bitwarden/server/blob/5cbdee137921a19b1f722920f0fa3cd45af2ef0f/src/Core/Models/Data/Organizations/Policies/SendOptionsPolicyData.cs
@@ -51,6 +56,35 @@ pub struct ResetPasswordDataModel {
     pub AutoEnrollEnabled: bool,
 }
 
+// Vaultwarden specific, the settings used for the emergency access grants members create for the owners
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+pub struct EmergencyAccessPolicyData {
+    pub Type: i32,
+    pub WaitTimeDays: i32,
+}
+
+// Vaultwarden specific, restricts the two-step login providers which satisfy the TwoFactorAuthentication policy
+// When no providers are listed, any enabled provider satisfies the policy
+#[derive(Default, Deserialize)]
+#[allow(non_snake_case)]
+pub struct TwoFactorPolicyData {
+    #[serde(default)]
+    pub Providers: Vec<i32>,
+    /// Maximum number of days a two-step login can be remembered on a device
+    #[serde(default)]
+    pub RememberMaxDays: Option<i64>,
+    /// Requires the second factor for personal API key logins too
+    #[serde(default)]
+    pub RequireForApiKey: bool,
+}
+
+impl TwoFactorPolicyData {
+    pub fn is_satisfied_by(&self, twofactors: &[TwoFactor]) -> bool {
+        twofactors.iter().any(|tf| tf.enabled && (self.Providers.is_empty() || self.Providers.contains(&tf.atype)))
+    }
+}
+
 pub type OrgPolicyResult = Result<(), OrgPolicyErr>;
 
 #[derive(Debug)]
@@ -75,6 +109,18 @@ impl OrgPolicy {
         self.atype == policy_type as i32
     }
 
+    /// Returns the providers required by a TwoFactorAuthentication policy.
+    /// Policies saved without any data, like the ones from the web-vault, allow all providers.
+    pub fn twofactor_policy_data(&self) -> TwoFactorPolicyData {
+        match serde_json::from_str::<Option<UpCase<TwoFactorPolicyData>>>(&self.data) {
+            Ok(opts) => opts.map(|o| o.data).unwrap_or_default(),
+            _ => {
+                error!("Failed to deserialize TwoFactorPolicyData: {}", self.data);
+                TwoFactorPolicyData::default()
+            }
+        }
+    }
+
     pub fn to_json(&self) -> Value {
         let data_json: Value = serde_json::from_str(&self.data).unwrap_or(Value::Null);
         json!({
@@ -91,7 +137,7 @@ impl OrgPolicy {
 /// Database methods
 impl OrgPolicy {
     pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
-        db_run! { conn:
+        let res = db_run! { conn:
             sqlite, mysql {
                 match diesel::replace_into(org_policies::table)
                     .values(OrgPolicyDb::to_db(self))
@@ -130,15 +176,19 @@ impl OrgPolicy {
                     .execute(conn)
                     .map_res("Error saving org_policy")
             }
-        }
+        };
+        cache::ORG_POLICIES.invalidate(&self.org_uuid);
+        res
     }
 
     pub async fn delete(self, conn: &mut DbConn) -> EmptyResult {
-        db_run! { conn: {
+        let res = db_run! { conn: {
             diesel::delete(org_policies::table.filter(org_policies::uuid.eq(self.uuid)))
                 .execute(conn)
                 .map_res("Error deleting org_policy")
-        }}
+        }};
+        cache::ORG_POLICIES.invalidate(&self.org_uuid);
+        res
     }
 
     pub async fn find_by_uuid(uuid: &str, conn: &mut DbConn) -> Option<Self> {
@@ -152,17 +202,24 @@ impl OrgPolicy {
     }
 
     pub async fn find_by_org(org_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
-        db_run! { conn: {
+        if let Some(policies) = cache::ORG_POLICIES.get(org_uuid) {
+            return policies;
+        }
+        let generation = cache::ORG_POLICIES.generation();
+        let policies = db_run! { conn: {
             org_policies::table
                 .filter(org_policies::org_uuid.eq(org_uuid))
                 .load::<OrgPolicyDb>(conn)
                 .expect("Error loading org_policy")
                 .from_db()
-        }}
+        }};
+        let policies = audit(policies, Scope::Org(org_uuid), "OrgPolicy::find_by_org");
+        cache::ORG_POLICIES.insert(org_uuid, policies.clone(), generation);
+        policies
     }
 
     pub async fn find_confirmed_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
-        db_run! { conn: {
+        let policies = db_run! { conn: {
             org_policies::table
                 .inner_join(
                     users_organizations::table.on(
@@ -176,26 +233,23 @@ impl OrgPolicy {
                 .load::<OrgPolicyDb>(conn)
                 .expect("Error loading org_policy")
                 .from_db()
-        }}
+        }};
+        audit_user_and_orgs(policies, user_uuid, "OrgPolicy::find_confirmed_by_user", conn).await
     }
 
     pub async fn find_by_org_and_type(org_uuid: &str, policy_type: OrgPolicyType, conn: &mut DbConn) -> Option<Self> {
-        db_run! { conn: {
-            org_policies::table
-                .filter(org_policies::org_uuid.eq(org_uuid))
-                .filter(org_policies::atype.eq(policy_type as i32))
-                .first::<OrgPolicyDb>(conn)
-                .ok()
-                .from_db()
-        }}
+        // From the cached policies of the organization, it only has one of each type
+        Self::find_by_org(org_uuid, conn).await.into_iter().find(|policy| policy.atype == policy_type as i32)
     }
 
     pub async fn delete_all_by_organization(org_uuid: &str, conn: &mut DbConn) -> EmptyResult {
-        db_run! { conn: {
+        let res = db_run! { conn: {
             diesel::delete(org_policies::table.filter(org_policies::org_uuid.eq(org_uuid)))
                 .execute(conn)
                 .map_res("Error deleting org_policy")
-        }}
+        }};
+        cache::ORG_POLICIES.invalidate(org_uuid);
+        res
     }
 
     pub async fn find_accepted_and_confirmed_by_user_and_active_policy(
@@ -281,15 +335,16 @@ impl OrgPolicy {
         exclude_current_org: bool,
         conn: &mut DbConn,
     ) -> OrgPolicyResult {
-        // Enforce TwoFactor/TwoStep login
-        if TwoFactor::find_by_user(user_uuid, conn).await.is_empty() {
-            match Self::find_by_org_and_type(org_uuid, OrgPolicyType::TwoFactorAuthentication, conn).await {
-                Some(p) if p.enabled => {
+        // Enforce TwoFactor/TwoStep login, using one of the providers required by the organization
+        match Self::find_by_org_and_type(org_uuid, OrgPolicyType::TwoFactorAuthentication, conn).await {
+            Some(p) if p.enabled => {
+                let twofactors = TwoFactor::find_by_user(user_uuid, conn).await;
+                if !p.twofactor_policy_data().is_satisfied_by(&twofactors) {
                     return Err(OrgPolicyErr::TwoFactorMissing);
                 }
-                _ => {}
-            };
-        }
+            }
+            _ => {}
+        };
 
         // Enforce Single Organization Policy of other organizations user is a member of
         // This check here needs to exclude this current org-id, else an accepted user can not be confirmed.
@@ -319,6 +374,22 @@ impl OrgPolicy {
         false
     }
 
+    /// Returns the emergency access settings of the organization, if the policy is enabled.
+    pub async fn org_emergency_access_data(org_uuid: &str, conn: &mut DbConn) -> Option<EmergencyAccessPolicyData> {
+        match OrgPolicy::find_by_org_and_type(org_uuid, OrgPolicyType::EmergencyAccess, conn).await {
+            Some(policy) if policy.enabled => {
+                match serde_json::from_str::<UpCase<EmergencyAccessPolicyData>>(&policy.data) {
+                    Ok(opts) => Some(opts.data),
+                    _ => {
+                        error!("Failed to deserialize EmergencyAccessPolicyData: {}", policy.data);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        }
+    }
+
     /// Returns true if the user belongs to an org that has enabled the `DisableHideEmail`
     /// option of the `Send Options` policy, and the user is not an owner or admin of that org.
     pub async fn is_hide_email_disabled(user_uuid: &str, conn: &mut DbConn) -> bool {
@@ -3,8 +3,12 @@ use num_traits::FromPrimitive;
 use serde_json::Value;
 use std::cmp::Ordering;
 
-use super::{CollectionUser, Group, GroupUser, OrgPolicy, OrgPolicyType, TwoFactor, User};
-use crate::CONFIG;
+use super::{CollectionUser, EmergencyAccess, Group, GroupUser, OrgPolicy, OrgPolicyType, TwoFactor, User};
+use crate::{
+    db::encryption::{self, EncryptedColumn},
+    util::format_date,
+    CONFIG,
+};
 
 db_object! {
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
@@ -32,6 +36,10 @@ db_object! {
         pub atype: i32,
         pub reset_password_key: Option<String>,
         pub external_id: Option<String>,
+
+        // Set when the member has to enable two-step login before this date, to comply with the TwoFactorAuthentication policy
+        pub twofactor_grace_until: Option<NaiveDateTime>,
+        pub twofactor_reminder_at: Option<NaiveDateTime>,
     }
 
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
@@ -46,6 +54,52 @@ db_object! {
     }
 }
 
+/// The sortings of the paginated listing of the organizations.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum OrgSort {
+    Name,
+    BillingEmail,
+}
+
+impl OrgSort {
+    pub const ALL: [OrgSort; 2] = [OrgSort::Name, OrgSort::BillingEmail];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OrgSort::Name => "name",
+            OrgSort::BillingEmail => "billing_email",
+        }
+    }
+
+    /// The sorting by name when the name is unknown.
+    pub fn from_name(name: &str) -> Self {
+        Self::ALL.into_iter().find(|sort| sort.name() == name).unwrap_or(OrgSort::Name)
+    }
+}
+
+/// The sortings of the paginated listing of the members of an organization, by their user.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum MemberSort {
+    Email,
+    Name,
+}
+
+impl MemberSort {
+    pub const ALL: [MemberSort; 2] = [MemberSort::Email, MemberSort::Name];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MemberSort::Email => "email",
+            MemberSort::Name => "name",
+        }
+    }
+
+    /// The sorting by email when the name is unknown.
+    pub fn from_name(name: &str) -> Self {
+        Self::ALL.into_iter().find(|sort| sort.name() == name).unwrap_or(MemberSort::Email)
+    }
+}
+
 // https://github.com/bitwarden/server/blob/b86a04cef9f1e1b82cf18e49fc94e017c641130c/src/Core/Enums/OrganizationUserStatusType.cs
 pub enum UserOrgStatus {
     Revoked = -1,
@@ -210,6 +264,9 @@ impl UserOrganization {
             atype: UserOrgType::User as i32,
             reset_password_key: None,
             external_id: None,
+
+            twofactor_grace_until: None,
+            twofactor_reminder_at: None,
         }
     }
 
@@ -244,23 +301,38 @@ impl UserOrganization {
 }
 
 impl OrganizationApiKey {
-    pub fn new(org_uuid: String, api_key: String) -> Self {
-        Self {
+    pub fn new(org_uuid: String, api_key: &str) -> Self {
+        let mut org_api_key = Self {
             uuid: crate::util::get_uuid(),
 
             org_uuid,
             atype: 0, // Type 0 is the default and only type we support currently
-            api_key,
+            api_key: String::new(),
             revision_date: Utc::now().naive_utc(),
-        }
+        };
+        org_api_key.set_api_key(api_key);
+        org_api_key
     }
 
     pub fn check_valid_api_key(&self, api_key: &str) -> bool {
-        crate::crypto::ct_eq(&self.api_key, api_key)
+        crate::crypto::ct_eq(self.api_key(), api_key)
+    }
+
+    // The API key is encrypted at rest when a key is configured, see `encryption`
+    pub fn api_key(&self) -> String {
+        encryption::decrypt_or_log(EncryptedColumn::OrganizationApiKey, &self.uuid, &self.api_key)
+    }
+
+    pub fn set_api_key(&mut self, api_key: &str) {
+        self.api_key = encryption::encrypt(EncryptedColumn::OrganizationApiKey, &self.uuid, api_key);
     }
 }
 
-use crate::db::DbConn;
+use crate::db::{
+    like_pattern, lower,
+    scope::{audit, Scope},
+    DbConn, LIKE_ESCAPE,
+};
 
 use crate::api::EmptyResult;
 use crate::error::MapResult;
@@ -309,7 +381,7 @@ impl Organization {
     }
 
     pub async fn delete(self, conn: &mut DbConn) -> EmptyResult {
-        use super::{Cipher, Collection};
+        use super::{Cipher, Collection, Tombstone};
 
         Cipher::delete_all_by_organization(&self.uuid, conn).await?;
         Collection::delete_all_by_organization(&self.uuid, conn).await?;
@@ -317,6 +389,7 @@ impl Organization {
         OrgPolicy::delete_all_by_organization(&self.uuid, conn).await?;
         Group::delete_all_by_organization(&self.uuid, conn).await?;
         OrganizationApiKey::delete_all_by_organization(&self.uuid, conn).await?;
+        Tombstone::delete_all_by_organization(&self.uuid, conn).await?;
 
         db_run! { conn: {
             diesel::delete(organizations::table.filter(organizations::uuid.eq(self.uuid)))
@@ -339,12 +412,56 @@ impl Organization {
             organizations::table.load::<OrganizationDb>(conn).expect("Error loading organizations").from_db()
         }}
     }
+
+    /// A page of the organizations whose name or billing email contains the search, and the number of these.
+    pub async fn find_page(
+        search: Option<&str>,
+        sort: OrgSort,
+        descending: bool,
+        offset: i64,
+        limit: i64,
+        conn: &mut DbConn,
+    ) -> (Vec<Self>, i64) {
+        let pattern = search.map(|search| like_pattern(&search.to_lowercase()));
+        db_run! { conn: {
+            let mut count_query = organizations::table.count().into_boxed();
+            let mut query = organizations::table.into_boxed();
+            if let Some(pattern) = pattern {
+                count_query = count_query.filter(
+                    lower(organizations::name)
+                        .like(pattern.clone())
+                        .escape(LIKE_ESCAPE)
+                        .or(lower(organizations::billing_email).like(pattern.clone()).escape(LIKE_ESCAPE)),
+                );
+                query = query.filter(
+                    lower(organizations::name)
+                        .like(pattern.clone())
+                        .escape(LIKE_ESCAPE)
+                        .or(lower(organizations::billing_email).like(pattern).escape(LIKE_ESCAPE)),
+                );
+            }
+            let total = count_query.first::<i64>(conn).unwrap_or(0);
+
+            query = match (sort, descending) {
+                (OrgSort::Name, false) => query.order(organizations::name.asc()),
+                (OrgSort::Name, true) => query.order(organizations::name.desc()),
+                (OrgSort::BillingEmail, false) => query.order(organizations::billing_email.asc()),
+                (OrgSort::BillingEmail, true) => query.order(organizations::billing_email.desc()),
+            };
+            let organizations = query
+                .then_order_by(organizations::uuid.asc())
+                .offset(offset)
+                .limit(limit)
+                .load::<OrganizationDb>(conn)
+                .expect("Error loading organizations")
+                .from_db();
+            (organizations, total)
+        }}
+    }
 }
 
 impl UserOrganization {
-    pub async fn to_json(&self, conn: &mut DbConn) -> Value {
-        let org = Organization::find_by_uuid(&self.org_uuid, conn).await.unwrap();
-
+    pub fn to_json(&self, org: &Organization) -> Value {
         let permissions = json!({
                 // TODO: Add support for Custom User Roles
                 // See: https://bitwarden.com/help/article/user-types-access-control/#custom-role
@@ -383,13 +500,15 @@ impl UserOrganization {
             "SelfHost": true,
             "HasPublicAndPrivateKeys": org.private_key.is_some() && org.public_key.is_some(),
             "ResetPasswordEnrolled": self.reset_password_key.is_some(),
+            // Vaultwarden specific, the date before which two-step login needs to be enabled to keep access to this organization
+            "TwoFactorGraceUntil": self.twofactor_grace_until.as_ref().map(format_date),
             "UseResetPassword": CONFIG.mail_enabled(),
             "SsoBound": false, // Not supported
             "UseSso": false, // Not supported
             "ProviderId": null,
             "ProviderName": null,
-            // "KeyConnectorEnabled": false,
-            // "KeyConnectorUrl": null,
+            "KeyConnectorEnabled": crate::api::core::key_connector::enabled_for_org(&self.org_uuid),
+            "KeyConnectorUrl": crate::api::core::key_connector::url(),
 
             "permissions": permissions,
 
@@ -406,6 +525,34 @@ impl UserOrganization {
         })
     }
 
+    /// Vaultwarden specific, the enabled two-step login providers of the member, without any of their data
+    pub async fn to_json_twofactor_report(&self, conn: &mut DbConn) -> Value {
+        let user = User::find_by_uuid(&self.user_uuid, conn).await.unwrap();
+
+        // Same as above, revoked members have a status of -1 for the clients
+        let status = if self.status < UserOrgStatus::Revoked as i32 {
+            UserOrgStatus::Revoked as i32
+        } else {
+            self.status
+        };
+
+        let providers: Vec<i32> =
+            TwoFactor::find_by_user(&user.uuid, conn).await.iter().filter(|tf| tf.enabled).map(|tf| tf.atype).collect();
+
+        json!({
+            "Id": self.uuid,
+            "UserId": self.user_uuid,
+            "Name": user.name,
+            "Email": user.email,
+            "Status": status,
+            "Type": self.atype,
+            "TwoFactorEnabled": !providers.is_empty(),
+            "TwoFactorProviders": providers,
+
+            "Object": "organizationUserTwoFactorReport",
+        })
+    }
+
     pub async fn to_json_user_details(
         &self,
         include_collections: bool,
@@ -552,6 +699,7 @@ impl UserOrganization {
 
         CollectionUser::delete_all_by_user_and_org(&self.user_uuid, &self.org_uuid, conn).await?;
         GroupUser::delete_all_by_user(&self.uuid, conn).await?;
+        EmergencyAccess::delete_all_by_org_and_user(&self.org_uuid, &self.user_uuid, conn).await?;
 
         db_run! { conn: {
             diesel::delete(users_organizations::table.filter(users_organizations::uuid.eq(self.uuid)))
@@ -625,6 +773,22 @@ impl UserOrganization {
         }}
     }
 
+    /// The confirmed memberships of the user with their organization, in a single query for the profile.
+    pub async fn find_confirmed_with_org_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<(Self, Organization)> {
+        db_run! { conn: {
+            users_organizations::table
+                .inner_join(organizations::table.on(organizations::uuid.eq(users_organizations::org_uuid)))
+                .filter(users_organizations::user_uuid.eq(user_uuid))
+                .filter(users_organizations::status.eq(UserOrgStatus::Confirmed as i32))
+                .select((users_organizations::all_columns, organizations::all_columns))
+                .load::<(UserOrganizationDb, OrganizationDb)>(conn)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(user_org, org)| (user_org.from_db(), org.from_db()))
+                .collect()
+        }}
+    }
+
     pub async fn find_invited_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
         db_run! { conn: {
             users_organizations::table
@@ -656,11 +820,77 @@ impl UserOrganization {
     }
 
     pub async fn find_by_org(org_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
-        db_run! { conn: {
+        let user_orgs = db_run! { conn: {
             users_organizations::table
                 .filter(users_organizations::org_uuid.eq(org_uuid))
                 .load::<UserOrganizationDb>(conn)
                 .expect("Error loading user organizations").from_db()
+        }};
+        audit(user_orgs, Scope::Org(org_uuid), "UserOrganization::find_by_org")
+    }
+
+    /// A page of the members of the organization whose email or name contains the search, and the number of these.
+    pub async fn find_page_by_org(
+        org_uuid: &str,
+        search: Option<&str>,
+        sort: MemberSort,
+        descending: bool,
+        offset: i64,
+        limit: i64,
+        conn: &mut DbConn,
+    ) -> (Vec<Self>, i64) {
+        let pattern = search.map(|search| like_pattern(&search.to_lowercase()));
+        let (user_orgs, total) = db_run! { conn: {
+            let mut count_query = users_organizations::table
+                .inner_join(users::table)
+                .filter(users_organizations::org_uuid.eq(org_uuid))
+                .count()
+                .into_boxed();
+            let mut query = users_organizations::table
+                .inner_join(users::table)
+                .filter(users_organizations::org_uuid.eq(org_uuid))
+                .select(users_organizations::all_columns)
+                .into_boxed();
+            if let Some(pattern) = pattern {
+                count_query = count_query.filter(
+                    lower(users::email)
+                        .like(pattern.clone())
+                        .escape(LIKE_ESCAPE)
+                        .or(lower(users::name).like(pattern.clone()).escape(LIKE_ESCAPE)),
+                );
+                query = query.filter(
+                    lower(users::email)
+                        .like(pattern.clone())
+                        .escape(LIKE_ESCAPE)
+                        .or(lower(users::name).like(pattern).escape(LIKE_ESCAPE)),
+                );
+            }
+            let total = count_query.first::<i64>(conn).unwrap_or(0);
+
+            query = match (sort, descending) {
+                (MemberSort::Email, false) => query.order(users::email.asc()),
+                (MemberSort::Email, true) => query.order(users::email.desc()),
+                (MemberSort::Name, false) => query.order(users::name.asc()),
+                (MemberSort::Name, true) => query.order(users::name.desc()),
+            };
+            let user_orgs = query
+                .then_order_by(users_organizations::uuid.asc())
+                .offset(offset)
+                .limit(limit)
+                .load::<UserOrganizationDb>(conn)
+                .expect("Error loading user organizations")
+                .from_db();
+            (user_orgs, total)
+        }};
+        (audit(user_orgs, Scope::Org(org_uuid), "UserOrganization::find_page_by_org"), total)
+    }
+
+    pub async fn find_in_twofactor_grace(conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            users_organizations::table
+                .filter(users_organizations::twofactor_grace_until.is_not_null())
+                .load::<UserOrganizationDb>(conn)
+                .expect("Error loading user organizations").from_db()
         }}
     }
 
@@ -718,12 +948,13 @@ impl UserOrganization {
     }
 
     pub async fn find_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
-        db_run! { conn: {
+        let user_orgs = db_run! { conn: {
             users_organizations::table
                 .filter(users_organizations::user_uuid.eq(user_uuid))
                 .load::<UserOrganizationDb>(conn)
                 .expect("Error loading user organizations").from_db()
-        }}
+        }};
+        audit(user_orgs, Scope::User(user_uuid), "UserOrganization::find_by_user")
     }
 
     pub async fn get_org_uuid_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<String> {
@@ -895,6 +1126,38 @@ impl OrganizationApiKey {
                 .map_res("Error removing organization api key from organization")
         }}
     }
+
+    /// Encrypts the API keys which aren't encrypted with the current key, see `encryption::encrypt_all_data`.
+    pub async fn encrypt_stored_data(rotate: bool, conn: &mut DbConn) -> Result<usize, crate::Error> {
+        let Some(pattern) = encryption::up_to_date_pattern(rotate) else {
+            return Ok(0);
+        };
+
+        let api_keys: Vec<Self> = db_run! { conn: {
+            organization_api_key::table
+                .filter(organization_api_key::api_key.not_like(pattern))
+                .load::<OrganizationApiKeyDb>(conn)
+                .expect("Error loading organization api keys")
+                .from_db()
+        }};
+
+        let mut count = 0;
+        for mut org_api_key in api_keys {
+            let Some(api_key) =
+                encryption::decrypt(EncryptedColumn::OrganizationApiKey, &org_api_key.uuid, &org_api_key.api_key)
+            else {
+                error!(
+                    "Unable to decrypt the API key of organization {}, check the DATABASE_ENCRYPTION_KEYS",
+                    org_api_key.org_uuid
+                );
+                continue;
+            };
+            org_api_key.set_api_key(&api_key);
+            org_api_key.save(conn).await?;
+            count += 1;
+        }
+        Ok(count)
+    }
 }
 
 #[cfg(test)]
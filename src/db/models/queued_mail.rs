@@ -0,0 +1,190 @@
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use serde_json::Value;
+
+use crate::{util::format_naive_datetime_local, CONFIG};
+
+db_object! {
+    // The outgoing mails, until they are sent or given up on
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = mail_queue)]
+    #[diesel(treat_none_as_null = true)]
+    #[diesel(primary_key(uuid))]
+    pub struct QueuedMail {
+        pub uuid: String,
+        pub recipient: String,
+        pub subject: String,
+        pub body_html: String,
+        pub body_text: String,
+        pub status: i32,        // MailStatus
+        pub attempts: i32,
+        pub next_attempt_at: NaiveDateTime,
+        pub last_error: Option<String>,
+        pub created_at: NaiveDateTime,
+        pub attachments: Option<String>, // JSON, see mail::encode_attachments
+        // Sent with the other ones to the same recipient in a single mail
+        pub digest: bool,
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum MailStatus {
+    Pending = 0,
+    // All the attempts failed, the mail is kept until it's retried or deleted from the admin panel
+    DeadLetter = 1,
+}
+
+// The first retry is after a minute, the delay then doubles up to six hours
+const RETRY_BASE_SECONDS: i64 = 60;
+const RETRY_MAX_SECONDS: i64 = 6 * 3600;
+
+// A mail is claimed for that long while it's being sent, so another instance doesn't send it too
+const CLAIM_SECONDS: i64 = 300;
+
+/// Local methods
+impl QueuedMail {
+    pub fn new(recipient: &str, subject: &str, body_html: String, body_text: String) -> Self {
+        let now = Utc::now().naive_utc();
+        Self {
+            uuid: crate::util::get_uuid(),
+            recipient: recipient.to_string(),
+            subject: subject.to_string(),
+            body_html,
+            body_text,
+            status: MailStatus::Pending as i32,
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            created_at: now,
+            attachments: None,
+            digest: false,
+        }
+    }
+
+    /// Records a failed attempt, and schedules the next one with an exponential backoff.
+    pub fn failed(&mut self, error: String) {
+        self.attempts += 1;
+        self.last_error = Some(error);
+        if self.attempts >= CONFIG.mail_queue_max_attempts() as i32 {
+            self.status = MailStatus::DeadLetter as i32;
+        } else {
+            let delay = RETRY_BASE_SECONDS.saturating_mul(1 << (self.attempts - 1).min(16)).min(RETRY_MAX_SECONDS);
+            self.next_attempt_at = Utc::now().naive_utc() + TimeDelta::try_seconds(delay).unwrap();
+        }
+    }
+
+    /// Puts a dead letter back in the queue, for a new series of attempts.
+    pub fn retry(&mut self) {
+        self.status = MailStatus::Pending as i32;
+        self.attempts = 0;
+        self.next_attempt_at = Utc::now().naive_utc();
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.uuid,
+            "recipient": self.recipient,
+            "subject": self.subject,
+            "attempts": self.attempts,
+            "last_error": self.last_error,
+            "created_at": format_naive_datetime_local(&self.created_at, "%Y-%m-%d %H:%M:%S %Z"),
+        })
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl QueuedMail {
+    pub async fn insert(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::insert_into(mail_queue::table)
+                .values(QueuedMailDb::to_db(self))
+                .execute(conn)
+                .map_res("Error queuing mail")
+        }}
+    }
+
+    pub async fn update(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::update(mail_queue::table.filter(mail_queue::uuid.eq(&self.uuid)))
+                .set(QueuedMailDb::to_db(self))
+                .execute(conn)
+                .map_res("Error updating queued mail")
+        }}
+    }
+
+    pub async fn delete(self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(mail_queue::table.filter(mail_queue::uuid.eq(self.uuid)))
+                .execute(conn)
+                .map_res("Error deleting queued mail")
+        }}
+    }
+
+    /// Claims the mail for this instance, returns false when another one already did.
+    pub async fn claim(&mut self, conn: &mut DbConn) -> bool {
+        let claimed_until = Utc::now().naive_utc() + TimeDelta::try_seconds(CLAIM_SECONDS).unwrap();
+        let previous = self.next_attempt_at;
+        let updated = db_run! { conn: {
+            diesel::update(
+                mail_queue::table
+                    .filter(mail_queue::uuid.eq(&self.uuid))
+                    .filter(mail_queue::next_attempt_at.eq(previous))
+            )
+            .set(mail_queue::next_attempt_at.eq(claimed_until))
+            .execute(conn)
+            .unwrap_or_default()
+        }};
+        self.next_attempt_at = claimed_until;
+        updated == 1
+    }
+
+    pub async fn find_by_uuid(uuid: &str, conn: &mut DbConn) -> Option<Self> {
+        db_run! { conn: {
+            mail_queue::table
+                .filter(mail_queue::uuid.eq(uuid))
+                .first::<QueuedMailDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    /// The pending mails whose next attempt is due, the oldest first.
+    pub async fn find_due(limit: i64, conn: &mut DbConn) -> Vec<Self> {
+        let now = Utc::now().naive_utc();
+        db_run! { conn: {
+            mail_queue::table
+                .filter(mail_queue::status.eq(MailStatus::Pending as i32))
+                .filter(mail_queue::next_attempt_at.le(now))
+                .order(mail_queue::next_attempt_at.asc())
+                .limit(limit)
+                .load::<QueuedMailDb>(conn)
+                .expect("Error loading queued mails")
+                .from_db()
+        }}
+    }
+
+    pub async fn find_dead_letters(conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            mail_queue::table
+                .filter(mail_queue::status.eq(MailStatus::DeadLetter as i32))
+                .order(mail_queue::created_at.desc())
+                .load::<QueuedMailDb>(conn)
+                .expect("Error loading dead letters")
+                .from_db()
+        }}
+    }
+
+    pub async fn count_by_status(status: MailStatus, conn: &mut DbConn) -> i64 {
+        db_run! { conn: {
+            mail_queue::table
+                .filter(mail_queue::status.eq(status as i32))
+                .count()
+                .first::<i64>(conn)
+                .unwrap_or(0)
+        }}
+    }
+}
@@ -1,7 +1,7 @@
 use chrono::{NaiveDateTime, Utc};
 use serde_json::Value;
 
-use super::User;
+use super::{Tombstone, User};
 
 db_object! {
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
@@ -101,6 +101,12 @@ impl Send {
         }
     }
 
+    /// Returns the id of the file stored for a file Send.
+    pub fn file_id(&self) -> Option<String> {
+        let data: Value = serde_json::from_str(&self.data).ok()?;
+        data["Id"].as_str().map(String::from)
+    }
+
     pub async fn creator_identifier(&self, conn: &mut DbConn) -> Option<String> {
         if let Some(hide_email) = self.hide_email {
             if hide_email {
@@ -168,7 +174,10 @@ impl Send {
     }
 }
 
-use crate::db::DbConn;
+use crate::db::{
+    scope::{audit, Scope},
+    DbConn,
+};
 
 use crate::api::EmptyResult;
 use crate::error::MapResult;
@@ -215,7 +224,20 @@ impl Send {
 
         if self.atype == SendType::File as i32 {
             std::fs::remove_dir_all(std::path::Path::new(&crate::CONFIG.sends_folder()).join(&self.uuid)).ok();
+
+            #[cfg(feature = "s3")]
+            {
+                if crate::s3::sends_enabled() {
+                    if let Some(file_id) = self.file_id() {
+                        let key = crate::s3::send_file_key(&self.uuid, &file_id);
+                        if let Err(e) = crate::s3::delete_object(&key).await {
+                            warn!("Failed to delete Send file from S3: {e:?}");
+                        }
+                    }
+                }
+            }
         }
+        Tombstone::for_send(self).save(conn).await?;
 
         db_run! { conn: {
             diesel::delete(sends::table.filter(sends::uuid.eq(&self.uuid)))
@@ -280,11 +302,12 @@ impl Send {
     }
 
     pub async fn find_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
-        db_run! {conn: {
+        let sends = db_run! {conn: {
             sends::table
                 .filter(sends::user_uuid.eq(user_uuid))
                 .load::<SendDb>(conn).expect("Error loading sends").from_db()
-        }}
+        }};
+        audit(sends, Scope::User(user_uuid), "Send::find_by_user")
     }
 
     pub async fn size_by_user(user_uuid: &str, conn: &mut DbConn) -> Option<i64> {
@@ -318,11 +341,12 @@ impl Send {
     }
 
     pub async fn find_by_org(org_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
-        db_run! {conn: {
+        let sends = db_run! {conn: {
             sends::table
                 .filter(sends::organization_uuid.eq(org_uuid))
                 .load::<SendDb>(conn).expect("Error loading sends").from_db()
-        }}
+        }};
+        audit(sends, Scope::Org(org_uuid), "Send::find_by_org")
     }
 
     pub async fn find_by_past_deletion_date(conn: &mut DbConn) -> Vec<Self> {
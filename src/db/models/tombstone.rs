@@ -0,0 +1,138 @@
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use serde_json::Value;
+
+use super::{Cipher, Collection, Folder, Send};
+use crate::{util::format_date, CONFIG};
+
+db_object! {
+    // The objects deleted from the database, so the clients can remove them from their cache without a full sync
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = tombstones)]
+    #[diesel(primary_key(uuid))]
+    pub struct Tombstone {
+        pub uuid: String,
+        pub atype: i32,         // TombstoneType
+        pub object_uuid: String,
+        pub user_uuid: Option<String>,
+        pub org_uuid: Option<String>,
+        pub deleted_at: NaiveDateTime,
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum TombstoneType {
+    Cipher = 0,
+    Folder = 1,
+    Send = 2,
+    Collection = 3,
+}
+
+/// Local methods
+impl Tombstone {
+    pub fn new(atype: TombstoneType, object_uuid: &str, user_uuid: Option<&str>, org_uuid: Option<&str>) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            atype: atype as i32,
+            object_uuid: object_uuid.to_string(),
+            user_uuid: user_uuid.map(String::from),
+            org_uuid: org_uuid.map(String::from),
+            deleted_at: Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn for_cipher(cipher: &Cipher) -> Self {
+        Self::new(TombstoneType::Cipher, &cipher.uuid, cipher.user_uuid.as_deref(), cipher.organization_uuid.as_deref())
+    }
+
+    pub fn for_folder(folder: &Folder) -> Self {
+        Self::new(TombstoneType::Folder, &folder.uuid, Some(&folder.user_uuid), None)
+    }
+
+    pub fn for_send(send: &Send) -> Self {
+        Self::new(TombstoneType::Send, &send.uuid, send.user_uuid.as_deref(), send.organization_uuid.as_deref())
+    }
+
+    pub fn for_collection(collection: &Collection) -> Self {
+        Self::new(TombstoneType::Collection, &collection.uuid, None, Some(&collection.org_uuid))
+    }
+
+    /// The oldest date from which the deletions are still all recorded, older syncs need a full sync.
+    pub fn retained_since() -> NaiveDateTime {
+        Utc::now().naive_utc() - TimeDelta::try_days(CONFIG.tombstones_days_retain()).unwrap()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "Id": self.object_uuid,
+            "Type": self.atype,
+            "OrganizationId": self.org_uuid,
+            "DeletedDate": format_date(&self.deleted_at),
+            "Object": "deletion",
+        })
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl Tombstone {
+    // The tombstones are never updated, only added and removed
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::insert_into(tombstones::table)
+                .values(TombstoneDb::to_db(self))
+                .execute(conn)
+                .map_res("Error saving tombstone")
+        }}
+    }
+
+    /// The deletions since the given date of the user's objects, and of the objects of the given organizations.
+    pub async fn find_visible_since(
+        user_uuid: &str,
+        org_uuids: &[String],
+        since: &NaiveDateTime,
+        conn: &mut DbConn,
+    ) -> Vec<Self> {
+        db_run! { conn: {
+            tombstones::table
+                .filter(tombstones::deleted_at.gt(since))
+                .filter(
+                    tombstones::user_uuid.eq(user_uuid)
+                        .or(tombstones::org_uuid.eq_any(org_uuids))
+                )
+                .order(tombstones::deleted_at.asc())
+                .load::<TombstoneDb>(conn)
+                .expect("Error loading tombstones")
+                .from_db()
+        }}
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(tombstones::table.filter(tombstones::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting tombstones")
+        }}
+    }
+
+    pub async fn delete_all_by_organization(org_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(tombstones::table.filter(tombstones::org_uuid.eq(org_uuid)))
+                .execute(conn)
+                .map_res("Error deleting tombstones")
+        }}
+    }
+
+    /// Deletes the tombstones older than `TOMBSTONES_DAYS_RETAIN`.
+    pub async fn purge_old(conn: &mut DbConn) -> EmptyResult {
+        let dt = Self::retained_since();
+        db_run! { conn: {
+            diesel::delete(tombstones::table.filter(tombstones::deleted_at.lt(dt)))
+                .execute(conn)
+                .map_res("Error purging old tombstones")
+        }}
+    }
+}
@@ -0,0 +1,241 @@
+//
+// Copy of all the data to another database, like from SQLite to PostgreSQL or MySQL/MariaDB
+//
+// The tables are copied in batches, in the order of their foreign keys. The rows go through the models, so Diesel
+// converts the column types between the backends, like the booleans and dates which SQLite stores as numbers and text.
+// Each table is then counted in both databases, to make sure nothing was lost.
+// The same rows can be exported as JSON and imported back into any backend, see `crate::archive`.
+//
+use serde_json::Value;
+
+use crate::{
+    api::EmptyResult,
+    db::DbConn,
+    error::{Error, MapResult},
+};
+
+const BATCH_SIZE: i64 = 500;
+
+// Same as `db_run!`, but with the model of the given module, which isn't imported here
+macro_rules! with_model {
+    ( $conn:ident, $module:ident, $body:block ) => {{
+        #[allow(unused)]
+        use diesel::prelude::*;
+        #[allow(unused)]
+        use $crate::db::FromDb;
+
+        let conn = $conn.conn.clone();
+        let mut conn = conn.lock_owned().await;
+        match conn.as_mut().expect("internal invariant broken: self.connection is Some") {
+            #[cfg(sqlite)]
+            $crate::db::DbConnInner::sqlite($conn) => {
+                #[allow(unused)]
+                use super::$module::__sqlite_model::*;
+                #[allow(unused)]
+                use $crate::db::__sqlite_schema::*;
+                tokio::task::block_in_place(move || $body)
+            }
+            #[cfg(mysql)]
+            $crate::db::DbConnInner::mysql($conn) => {
+                #[allow(unused)]
+                use super::$module::__mysql_model::*;
+                #[allow(unused)]
+                use $crate::db::__mysql_schema::*;
+                tokio::task::block_in_place(move || $body)
+            }
+            #[cfg(postgresql)]
+            $crate::db::DbConnInner::postgresql($conn) => {
+                #[allow(unused)]
+                use super::$module::__postgresql_model::*;
+                #[allow(unused)]
+                use $crate::db::__postgresql_schema::*;
+                tokio::task::block_in_place(move || $body)
+            }
+        }
+    }};
+}
+
+macro_rules! count_rows {
+    ( $conn:ident, $module:ident, $table:ident ) => {
+        with_model!($conn, $module, {
+            $table::table.count().get_result::<i64>($conn).map_res(concat!("Error counting ", stringify!($table)))
+        })
+    };
+}
+
+// Calls the macro for all the tables, the parents before their children to satisfy the foreign keys
+macro_rules! for_each_table {
+    ( $mac:ident!( $( $arg:ident ),* ) ) => {{
+        $mac!($( $arg, )* user, users, User, (uuid));
+        $mac!($( $arg, )* organization, organizations, Organization, (uuid));
+        $mac!($( $arg, )* organization, users_organizations, UserOrganization, (uuid));
+        $mac!($( $arg, )* organization, organization_api_key, OrganizationApiKey, (uuid, org_uuid));
+        $mac!($( $arg, )* org_policy, org_policies, OrgPolicy, (uuid));
+        $mac!($( $arg, )* group, groups, Group, (uuid));
+        $mac!($( $arg, )* group, groups_users, GroupUser, (groups_uuid, users_organizations_uuid));
+        $mac!($( $arg, )* collection, collections, Collection, (uuid));
+        $mac!($( $arg, )* group, collections_groups, CollectionGroup, (collections_uuid, groups_uuid));
+        $mac!($( $arg, )* collection, users_collections, CollectionUser, (user_uuid, collection_uuid));
+        $mac!($( $arg, )* folder, folders, Folder, (uuid));
+        $mac!($( $arg, )* cipher, ciphers, Cipher, (uuid));
+        $mac!($( $arg, )* collection, ciphers_collections, CollectionCipher, (cipher_uuid, collection_uuid));
+        $mac!($( $arg, )* folder, folders_ciphers, FolderCipher, (cipher_uuid, folder_uuid));
+        $mac!($( $arg, )* favorite, favorites, Favorite, (user_uuid, cipher_uuid));
+        $mac!($( $arg, )* attachment, attachments, Attachment, (id));
+        $mac!($( $arg, )* device, devices, Device, (uuid, user_uuid));
+        $mac!($( $arg, )* two_factor, twofactor, TwoFactor, (uuid));
+        $mac!($( $arg, )* two_factor_incomplete, twofactor_incomplete, TwoFactorIncomplete, (user_uuid, device_uuid));
+        $mac!($( $arg, )* two_factor_duo_context, twofactor_duo_ctx, TwoFactorDuoContext, (state));
+        $mac!($( $arg, )* web_authn_credential, web_authn_credentials, WebAuthnCredential, (uuid));
+        $mac!($( $arg, )* web_authn_login_challenge, web_authn_login_challenges, WebAuthnLoginChallenge, (uuid));
+        $mac!($( $arg, )* send, sends, Send, (uuid));
+        $mac!($( $arg, )* emergency_access, emergency_access, EmergencyAccess, (uuid));
+        $mac!($( $arg, )* user, invitations, Invitation, (email));
+        $mac!($( $arg, )* auth_request, auth_requests, AuthRequest, (uuid));
+        $mac!($( $arg, )* external_identity, external_identities, ExternalIdentity, (uuid));
+        $mac!($( $arg, )* event, event, Event, (uuid));
+        $mac!($( $arg, )* notification_event, notification_events, NotificationEvent, (uuid));
+        $mac!($( $arg, )* tombstone, tombstones, Tombstone, (uuid));
+        $mac!($( $arg, )* queued_mail, mail_queue, QueuedMail, (uuid));
+        $mac!($( $arg, )* mail_log, mail_log, MailLog, (uuid));
+        $mac!($( $arg, )* mail_preference, mail_preferences, MailPreference, (user_uuid, mail_type));
+        $mac!($( $arg, )* icon_domain, icon_domains, IconDomain, (domain));
+        $mac!($( $arg, )* job, jobs, JobSetting, (name));
+        $mac!($( $arg, )* job, job_runs, JobRun, (uuid));
+    }};
+}
+
+// Loads a batch of rows of the table, ordered by its primary key so the batches don't overlap
+macro_rules! load_batch {
+    ( $conn:ident, $offset:ident, $module:ident, $table:ident, $model:ident, ( $( $key:ident ),+ ) ) => {
+        paste::paste! {
+            with_model!($conn, $module, {
+                $table::table
+                    .order(( $( $table::$key, )+ ))
+                    .limit(BATCH_SIZE)
+                    .offset($offset)
+                    .load::<[<$model Db>]>($conn)
+                    .map_res(concat!("Error loading ", stringify!($table)))
+                    .map(FromDb::from_db)
+            })
+        }
+    };
+}
+
+macro_rules! insert_batch {
+    ( $conn:ident, $rows:ident, $module:ident, $table:ident, $model:ident ) => {
+        paste::paste! {
+            with_model!($conn, $module, {
+                let rows: Vec<[<$model Db>]> = $rows.iter().map([<$model Db>]::to_db).collect();
+                diesel::insert_into($table::table)
+                    .values(&rows)
+                    .execute($conn)
+                    .map_res(concat!("Error inserting into ", stringify!($table)))
+            })
+        }
+    };
+}
+
+// Copies a table, and adds the number of copied rows to the total
+macro_rules! copy_table {
+    ( $source:ident, $target:ident, $total:ident, $module:ident, $table:ident, $model:ident, $keys:tt ) => {{
+        let expected = count_rows!($source, $module, $table)?;
+        let mut offset = 0;
+        loop {
+            let rows = load_batch!($source, offset, $module, $table, $model, $keys)?;
+            if rows.is_empty() {
+                break;
+            }
+            offset += rows.len() as i64;
+            insert_batch!($target, rows, $module, $table, $model)?;
+        }
+
+        let copied = count_rows!($target, $module, $table)?;
+        if copied != expected {
+            err!(format!("{}: {expected} rows in the source database, but {copied} were copied", stringify!($table)))
+        }
+        info!("{}: {copied} rows copied", stringify!($table));
+        $total += copied;
+    }};
+}
+
+// Passes the rows of a table to the sink in batches, and records the number of rows
+macro_rules! export_table {
+    ( $conn:ident, $sink:ident, $counts:ident, $module:ident, $table:ident, $model:ident, $keys:tt ) => {{
+        let mut offset = 0;
+        loop {
+            let rows = load_batch!($conn, offset, $module, $table, $model, $keys)?;
+            if rows.is_empty() {
+                break;
+            }
+            offset += rows.len() as i64;
+            let rows = rows.iter().map(serde_json::to_value).collect::<Result<Vec<Value>, _>>()?;
+            $sink(stringify!($table), rows)?;
+        }
+        $counts.push((stringify!($table), offset));
+    }};
+}
+
+// Converts the rows when they belong to this table, the conversion fails when the columns don't match the model
+macro_rules! import_table {
+    ( $conn:ident, $name:ident, $rows:ident, $module:ident, $table:ident, $model:ident, $keys:tt ) => {
+        if $name == stringify!($table) {
+            let rows = $rows
+                .into_iter()
+                .map(serde_json::from_value::<super::$module::$model>)
+                .collect::<Result<Vec<_>, _>>()
+                .map_res(concat!("Invalid rows for ", stringify!($table)))?;
+            if let Some(conn) = $conn {
+                insert_batch!(conn, rows, $module, $table, $model)?;
+            }
+            return Ok(());
+        }
+    };
+}
+
+macro_rules! table_name {
+    ( $names:ident, $module:ident, $table:ident, $model:ident, $keys:tt ) => {
+        $names.push(stringify!($table));
+    };
+}
+
+/// Copies all the tables to the target database, which needs to have the same schema version and to be empty.
+/// Returns the total number of copied rows.
+pub async fn transfer_database(source: &mut DbConn, target: &mut DbConn) -> Result<i64, Error> {
+    ensure_empty(target).await?;
+
+    let mut copied = 0;
+    for_each_table!(copy_table!(source, target, copied));
+    Ok(copied)
+}
+
+/// Checks that the database is empty, the data can only be copied or imported into a new database.
+pub async fn ensure_empty(conn: &mut DbConn) -> EmptyResult {
+    if count_rows!(conn, user, users)? > 0 || count_rows!(conn, organization, organizations)? > 0 {
+        err!("The target database isn't empty, the data can only be copied or imported into a new database")
+    }
+    Ok(())
+}
+
+/// The names of all the tables, the parents before their children.
+pub fn table_names() -> Vec<&'static str> {
+    let mut names = Vec::new();
+    for_each_table!(table_name!(names));
+    names
+}
+
+/// Passes the rows of all the tables to the sink, converted to JSON, and returns the number of rows of each table.
+pub async fn export_tables<F>(conn: &mut DbConn, mut sink: F) -> Result<Vec<(&'static str, i64)>, Error>
+where
+    F: FnMut(&'static str, Vec<Value>) -> EmptyResult,
+{
+    let mut counts = Vec::new();
+    for_each_table!(export_table!(conn, sink, counts));
+    Ok(counts)
+}
+
+/// Inserts the JSON rows into the table, or only checks that they match its model without a connection.
+pub async fn import_rows(conn: Option<&mut DbConn>, table: &str, rows: Vec<Value>) -> EmptyResult {
+    for_each_table!(import_table!(conn, table, rows));
+    err!(format!("Unknown table `{table}`"))
+}
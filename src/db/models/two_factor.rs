@@ -1,6 +1,14 @@
 use serde_json::Value;
 
-use crate::{api::EmptyResult, db::DbConn, error::MapResult};
+use crate::{
+    api::EmptyResult,
+    db::{
+        encryption::{self, EncryptedColumn},
+        scope::{audit, Scope},
+        DbConn,
+    },
+    error::MapResult,
+};
 
 db_object! {
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
@@ -27,6 +35,8 @@ pub enum TwoFactorType {
     Remember = 5,
     OrganizationDuo = 6,
     Webauthn = 7,
+    // Only used to login with one of the single-use recovery codes, never stored
+    RecoveryCode = 8,
 
     // These are implementation details
     U2fRegisterChallenge = 1000,
@@ -37,6 +47,10 @@ pub enum TwoFactorType {
 
     // Special type for Protected Actions verification via email
     ProtectedActions = 2000,
+    // Special type for a pending delayed 2FA recovery
+    DelayedRecovery = 2001,
+    // Special type for the sheet of single-use recovery codes
+    RecoveryCodes = 2002,
 }
 
 /// Local methods
@@ -67,15 +81,49 @@ impl TwoFactor {
             "Object": "twoFactorProvider"
         })
     }
+
+    // Returns a copy with the data encrypted, as it should be stored in the database.
+    // The data is bound to the row, so it can't be moved to another 2FA method or account.
+    fn to_stored(&self) -> Self {
+        Self {
+            uuid: self.uuid.clone(),
+            user_uuid: self.user_uuid.clone(),
+            atype: self.atype,
+            enabled: self.enabled,
+            data: self.encrypt_data(&self.data),
+            last_used: self.last_used,
+        }
+    }
+
+    // Decrypts the data loaded from the database, the rows stored before a key was configured are returned as is.
+    fn from_stored(mut self) -> Self {
+        self.data = self.decrypt_data(&self.data);
+        self
+    }
+
+    fn encrypt_data(&self, data: &str) -> String {
+        encryption::encrypt(EncryptedColumn::TwoFactorData, &self.uuid, data)
+    }
+
+    // The values encrypted by `TWO_FACTOR_DATA_SECRET` were only bound to the user
+    fn decrypt_data(&self, stored: &str) -> String {
+        let bound_uuid = if encryption::is_legacy(stored) {
+            &self.user_uuid
+        } else {
+            &self.uuid
+        };
+        encryption::decrypt_or_log(EncryptedColumn::TwoFactorData, bound_uuid, stored)
+    }
 }
 
 /// Database methods
 impl TwoFactor {
     pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        let stored = self.to_stored();
         db_run! { conn:
             sqlite, mysql {
                 match diesel::replace_into(twofactor::table)
-                    .values(TwoFactorDb::to_db(self))
+                    .values(TwoFactorDb::to_db(&stored))
                     .execute(conn)
                 {
                     Ok(_) => Ok(()),
@@ -83,7 +131,7 @@ impl TwoFactor {
                     Err(diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::ForeignKeyViolation, _)) => {
                         diesel::update(twofactor::table)
                             .filter(twofactor::uuid.eq(&self.uuid))
-                            .set(TwoFactorDb::to_db(self))
+                            .set(TwoFactorDb::to_db(&stored))
                             .execute(conn)
                             .map_res("Error saving twofactor")
                     }
@@ -91,7 +139,7 @@ impl TwoFactor {
                 }.map_res("Error saving twofactor")
             }
             postgresql {
-                let value = TwoFactorDb::to_db(self);
+                let value = TwoFactorDb::to_db(&stored);
                 // We need to make sure we're not going to violate the unique constraint on user_uuid and atype.
                 // This happens automatically on other DBMS backends due to replace_into(). PostgreSQL does
                 // not support multiple constraints on ON CONFLICT clauses.
@@ -110,6 +158,34 @@ impl TwoFactor {
         }
     }
 
+    /// Replaces the data of the row, unless it was changed since it was loaded into `self`.
+    /// Returns false when it was, so a value read and then updated, like a single-use code, is only used once.
+    pub async fn update_data_if_unchanged(&self, data: &str, conn: &mut DbConn) -> Result<bool, crate::Error> {
+        let stored: Option<String> = db_run! { conn: {
+            twofactor::table
+                .filter(twofactor::uuid.eq(&self.uuid))
+                .select(twofactor::data)
+                .first::<String>(conn)
+                .ok()
+        }};
+        let Some(stored) = stored else {
+            return Ok(false);
+        };
+        if self.decrypt_data(&stored) != self.data {
+            return Ok(false);
+        }
+
+        // The encryption uses a random nonce, so the stored value only matches when nobody saved the row in between
+        let data = self.encrypt_data(data);
+        let updated = db_run! { conn: {
+            diesel::update(twofactor::table.filter(twofactor::uuid.eq(&self.uuid)).filter(twofactor::data.eq(&stored)))
+                .set(twofactor::data.eq(&data))
+                .execute(conn)
+                .map_res("Error updating twofactor data")
+        }}?;
+        Ok(updated == 1)
+    }
+
     pub async fn delete(self, conn: &mut DbConn) -> EmptyResult {
         db_run! { conn: {
             diesel::delete(twofactor::table.filter(twofactor::uuid.eq(self.uuid)))
@@ -119,14 +195,18 @@ impl TwoFactor {
     }
 
     pub async fn find_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
-        db_run! { conn: {
+        let twofactors = db_run! { conn: {
             twofactor::table
                 .filter(twofactor::user_uuid.eq(user_uuid))
                 .filter(twofactor::atype.lt(1000)) // Filter implementation types
                 .load::<TwoFactorDb>(conn)
                 .expect("Error loading twofactor")
                 .from_db()
-        }}
+                .into_iter()
+                .map(Self::from_stored)
+                .collect()
+        }};
+        audit(twofactors, Scope::User(user_uuid), "TwoFactor::find_by_user")
     }
 
     pub async fn find_by_user_and_type(user_uuid: &str, atype: i32, conn: &mut DbConn) -> Option<Self> {
@@ -137,6 +217,20 @@ impl TwoFactor {
                 .first::<TwoFactorDb>(conn)
                 .ok()
                 .from_db()
+                .map(Self::from_stored)
+        }}
+    }
+
+    pub async fn find_by_type(atype: i32, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            twofactor::table
+                .filter(twofactor::atype.eq(atype))
+                .load::<TwoFactorDb>(conn)
+                .expect("Error loading twofactor")
+                .from_db()
+                .into_iter()
+                .map(Self::from_stored)
+                .collect()
         }}
     }
 
@@ -148,6 +242,30 @@ impl TwoFactor {
         }}
     }
 
+    /// Encrypts the data of the rows which aren't encrypted with the current key, see `encryption::encrypt_all_data`.
+    pub async fn encrypt_stored_data(rotate: bool, conn: &mut DbConn) -> Result<usize, crate::Error> {
+        let Some(pattern) = encryption::up_to_date_pattern(rotate) else {
+            return Ok(0);
+        };
+
+        let twofactors = db_run! { conn: {
+            twofactor::table
+                .filter(twofactor::data.not_like(pattern))
+                .load::<TwoFactorDb>(conn)
+                .expect("Error loading twofactor")
+                .from_db()
+        }};
+
+        let mut count = 0;
+        for twofactor in twofactors.into_iter().map(Self::from_stored) {
+            if !encryption::is_encrypted(&twofactor.data) {
+                twofactor.save(conn).await?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
     pub async fn migrate_u2f_to_webauthn(conn: &mut DbConn) -> EmptyResult {
         let u2f_factors = db_run! { conn: {
             twofactor::table
@@ -155,6 +273,9 @@ impl TwoFactor {
                 .load::<TwoFactorDb>(conn)
                 .expect("Error loading twofactor")
                 .from_db()
+                .into_iter()
+                .map(Self::from_stored)
+                .collect::<Vec<Self>>()
         }};
 
         use crate::api::core::two_factor::webauthn::U2FRegistration;
@@ -199,6 +320,10 @@ impl TwoFactor {
                         cred_id: reg.reg.key_handle.clone(),
                         registration_policy: UserVerificationPolicy::Discouraged,
                     },
+                    created_at: None,
+                    last_used: None,
+                    aaguid: None,
+                    user_verified: None,
                 };
 
                 webauthn_regs.push(new_reg);
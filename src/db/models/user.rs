@@ -2,6 +2,7 @@ use chrono::{NaiveDateTime, TimeDelta, Utc};
 use serde_json::Value;
 
 use crate::crypto;
+use crate::db::encryption::{self, EncryptedColumn};
 use crate::CONFIG;
 
 db_object! {
@@ -52,6 +53,16 @@ db_object! {
         pub avatar_color: Option<String>,
 
         pub external_id: Option<String>, // Todo: Needs to be removed in the future, this is not used anymore.
+
+        // The last TOTP time step used to login, kept when the authenticator is removed so its codes can't be replayed
+        pub totp_last_step: i64,
+
+        // The master key of the users who unlock with the Key Connector, encrypted with the RSA key of the server
+        pub uses_key_connector: bool,
+        pub key_connector_key: Option<String>,
+
+        // The language of the clients, the mails are sent in this language when the templates are translated
+        pub locale: Option<String>,
     }
 
     #[derive(Identifiable, Queryable, Insertable)]
@@ -73,6 +84,31 @@ enum UserStatus {
     _Disabled = 2,
 }
 
+/// The sortings of the paginated listing of the users.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum UserSort {
+    Email,
+    Name,
+    CreatedAt,
+}
+
+impl UserSort {
+    pub const ALL: [UserSort; 3] = [UserSort::Email, UserSort::Name, UserSort::CreatedAt];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            UserSort::Email => "email",
+            UserSort::Name => "name",
+            UserSort::CreatedAt => "created_at",
+        }
+    }
+
+    /// The sorting by email when the name is unknown.
+    pub fn from_name(name: &str) -> Self {
+        Self::ALL.into_iter().find(|sort| sort.name() == name).unwrap_or(UserSort::Email)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UserStampException {
     pub routes: Vec<String>,
@@ -130,6 +166,13 @@ impl User {
             avatar_color: None,
 
             external_id: None, // Todo: Needs to be removed in the future, this is not used anymore.
+
+            totp_last_step: 0,
+
+            uses_key_connector: false,
+            key_connector_key: None,
+
+            locale: None,
         }
     }
 
@@ -151,7 +194,18 @@ impl User {
     }
 
     pub fn check_valid_api_key(&self, key: &str) -> bool {
-        matches!(self.api_key, Some(ref api_key) if crate::crypto::ct_eq(api_key, key))
+        matches!(self.api_key(), Some(ref api_key) if crate::crypto::ct_eq(api_key, key))
+    }
+
+    // The API key is encrypted at rest when a key is configured, see `encryption`
+    pub fn api_key(&self) -> Option<String> {
+        self.api_key
+            .as_deref()
+            .map(|api_key| encryption::decrypt_or_log(EncryptedColumn::UserApiKey, &self.uuid, api_key))
+    }
+
+    pub fn set_api_key(&mut self, api_key: &str) {
+        self.api_key = Some(encryption::encrypt(EncryptedColumn::UserApiKey, &self.uuid, api_key));
     }
 
     /// Set the password hash generated
@@ -214,26 +268,61 @@ impl User {
 }
 
 use super::{
-    Cipher, Device, EmergencyAccess, Favorite, Folder, Send, TwoFactor, TwoFactorIncomplete, UserOrgType,
-    UserOrganization,
+    Cipher, Device, EmergencyAccess, ExternalIdentity, Favorite, Folder, MailPreference, NotificationEvent, Send,
+    Tombstone, TwoFactor, TwoFactorIncomplete, UserOrgType, UserOrganization, WebAuthnCredential,
 };
-use crate::db::DbConn;
+use crate::db::{cache, coalesce, like_pattern, lower, DbConn, LIKE_ESCAPE};
 
 use crate::api::EmptyResult;
 use crate::error::MapResult;
 
 /// Database methods
 impl User {
-    pub async fn to_json(&self, conn: &mut DbConn) -> Value {
-        let mut orgs_json = Vec::new();
-        for c in UserOrganization::find_confirmed_by_user(&self.uuid, conn).await {
-            orgs_json.push(c.to_json(conn).await);
+    /// Encrypts the API keys which aren't encrypted with the current key, see `encryption::encrypt_all_data`.
+    pub async fn encrypt_stored_data(rotate: bool, conn: &mut DbConn) -> Result<usize, crate::Error> {
+        let Some(pattern) = encryption::up_to_date_pattern(rotate) else {
+            return Ok(0);
+        };
+
+        let api_keys: Vec<(String, Option<String>)> = db_run! { conn: {
+            users::table
+                .filter(users::api_key.is_not_null())
+                .filter(users::api_key.not_like(pattern))
+                .select((users::uuid, users::api_key))
+                .load(conn)
+                .expect("Error loading users")
+        }};
+
+        let mut count = 0;
+        for (uuid, stored) in api_keys {
+            let Some(api_key) = stored.and_then(|s| encryption::decrypt(EncryptedColumn::UserApiKey, &uuid, &s)) else {
+                error!("Unable to decrypt the API key of user {uuid}, check the DATABASE_ENCRYPTION_KEYS");
+                continue;
+            };
+            let encrypted = encryption::encrypt(EncryptedColumn::UserApiKey, &uuid, &api_key);
+            db_run! { conn: {
+                diesel::update(users::table.filter(users::uuid.eq(&uuid)))
+                    .set(users::api_key.eq(encrypted))
+                    .execute(conn)
+                    .map_res("Error encrypting the user API key")
+            }}?;
+            count += 1;
         }
+        cache::USERS.clear();
+        Ok(count)
+    }
+
+    pub async fn to_json(&self, conn: &mut DbConn) -> Value {
+        let orgs_json: Vec<Value> = UserOrganization::find_confirmed_with_org_by_user(&self.uuid, conn)
+            .await
+            .iter()
+            .map(|(user_org, org)| user_org.to_json(org))
+            .collect();
 
         let twofactor_enabled = !TwoFactor::find_by_user(&self.uuid, conn).await.is_empty();
 
         // TODO: Might want to save the status field in the DB
-        let status = if self.password_hash.is_empty() {
+        let status = if self.password_hash.is_empty() && !self.uses_key_connector {
             UserStatus::Invited
         } else {
             UserStatus::Enabled
@@ -258,7 +347,7 @@ impl User {
             "ProviderOrganizations": [],
             "ForcePasswordReset": false,
             "AvatarColor": self.avatar_color,
-            "UsesKeyConnector": false,
+            "UsesKeyConnector": self.uses_key_connector,
             "Object": "profile",
         })
     }
@@ -270,7 +359,7 @@ impl User {
 
         self.updated_at = Utc::now().naive_utc();
 
-        db_run! {conn:
+        let res = db_run! {conn:
             sqlite, mysql {
                 match diesel::replace_into(users::table)
                     .values(UserDb::to_db(self))
@@ -298,7 +387,11 @@ impl User {
                     .execute(conn)
                     .map_res("Error saving user")
             }
-        }
+        };
+        cache::USERS.invalidate(&self.uuid);
+        // The security stamp may have changed
+        cache::invalidate_sessions(&self.uuid, None);
+        res
     }
 
     pub async fn delete(self, conn: &mut DbConn) -> EmptyResult {
@@ -321,13 +414,21 @@ impl User {
         Device::delete_all_by_user(&self.uuid, conn).await?;
         TwoFactor::delete_all_by_user(&self.uuid, conn).await?;
         TwoFactorIncomplete::delete_all_by_user(&self.uuid, conn).await?;
+        WebAuthnCredential::delete_all_by_user(&self.uuid, conn).await?;
+        ExternalIdentity::delete_all_by_user(&self.uuid, conn).await?;
+        NotificationEvent::delete_all_by_user(&self.uuid, conn).await?;
+        Tombstone::delete_all_by_user(&self.uuid, conn).await?;
+        MailPreference::delete_all_by_user(&self.uuid, conn).await?;
         Invitation::take(&self.email, conn).await; // Delete invitation if any
 
-        db_run! {conn: {
-            diesel::delete(users::table.filter(users::uuid.eq(self.uuid)))
+        let res = db_run! { conn: {
+            diesel::delete(users::table.filter(users::uuid.eq(&self.uuid)))
                 .execute(conn)
                 .map_res("Error deleting user")
-        }}
+        }};
+        cache::USERS.invalidate(&self.uuid);
+        cache::invalidate_sessions(&self.uuid, None);
+        res
     }
 
     pub async fn update_uuid_revision(uuid: &str, conn: &mut DbConn) {
@@ -339,14 +440,16 @@ impl User {
     pub async fn update_all_revisions(conn: &mut DbConn) -> EmptyResult {
         let updated_at = Utc::now().naive_utc();
 
-        db_run! {conn: {
+        let res = db_run! {conn: {
             crate::util::retry(|| {
                 diesel::update(users::table)
                     .set(users::updated_at.eq(updated_at))
                     .execute(conn)
             }, 10)
             .map_res("Error updating revision date for all users")
-        }}
+        }};
+        cache::USERS.clear();
+        res
     }
 
     pub async fn update_revision(&mut self, conn: &mut DbConn) -> EmptyResult {
@@ -356,31 +459,76 @@ impl User {
     }
 
     async fn _update_revision(uuid: &str, date: &NaiveDateTime, conn: &mut DbConn) -> EmptyResult {
-        db_run! {conn: {
+        if coalesce::enabled() {
+            coalesce::bump_revision(uuid, *date);
+            return Ok(());
+        }
+        let res = db_run! {conn: {
             crate::util::retry(|| {
                 diesel::update(users::table.filter(users::uuid.eq(uuid)))
                     .set(users::updated_at.eq(date))
                     .execute(conn)
             }, 10)
             .map_res("Error updating user revision")
-        }}
+        }};
+        cache::USERS.invalidate(uuid);
+        res
+    }
+
+    pub async fn update_totp_last_step(uuid: &str, totp_last_step: i64, conn: &mut DbConn) -> EmptyResult {
+        let res = db_run! {conn: {
+            diesel::update(users::table.filter(users::uuid.eq(uuid)))
+                .set(users::totp_last_step.eq(totp_last_step))
+                .execute(conn)
+                .map_res("Error updating the last used TOTP step")
+        }};
+        cache::USERS.invalidate(uuid);
+        res
+    }
+
+    pub async fn update_locale(uuid: &str, locale: &str, conn: &mut DbConn) -> EmptyResult {
+        let res = db_run! {conn: {
+            diesel::update(users::table.filter(users::uuid.eq(uuid)))
+                .set(users::locale.eq(locale))
+                .execute(conn)
+                .map_res("Error updating the locale of the user")
+        }};
+        cache::USERS.invalidate(uuid);
+        res
+    }
+
+    // The revision bumped since the user was written, when the writes are coalesced
+    fn with_pending_revision(mut self) -> Self {
+        if let Some(revision) = coalesce::pending_revision(&self.uuid) {
+            self.updated_at = self.updated_at.max(revision);
+        }
+        self
     }
 
     pub async fn find_by_mail(mail: &str, conn: &mut DbConn) -> Option<Self> {
         let lower_mail = mail.to_lowercase();
-        db_run! {conn: {
+        let user: Option<Self> = db_run! {conn: {
             users::table
                 .filter(users::email.eq(lower_mail))
                 .first::<UserDb>(conn)
                 .ok()
                 .from_db()
-        }}
+        }};
+        user.map(Self::with_pending_revision)
     }
 
     pub async fn find_by_uuid(uuid: &str, conn: &mut DbConn) -> Option<Self> {
-        db_run! {conn: {
+        if let Some(user) = cache::USERS.get(uuid) {
+            return Some(user.with_pending_revision());
+        }
+        let generation = cache::USERS.generation();
+        let user: Option<Self> = db_run! {conn: {
             users::table.filter(users::uuid.eq(uuid)).first::<UserDb>(conn).ok().from_db()
-        }}
+        }};
+        if let Some(user) = &user {
+            cache::USERS.insert(uuid, user.clone(), generation);
+        }
+        user.map(Self::with_pending_revision)
     }
 
     pub async fn get_all(conn: &mut DbConn) -> Vec<Self> {
@@ -389,6 +537,66 @@ impl User {
         }}
     }
 
+    /// A page of the users whose email or name contains the search, and the number of these users.
+    pub async fn find_page(
+        search: Option<&str>,
+        sort: UserSort,
+        descending: bool,
+        offset: i64,
+        limit: i64,
+        conn: &mut DbConn,
+    ) -> (Vec<Self>, i64) {
+        let pattern = search.map(|search| like_pattern(&search.to_lowercase()));
+        db_run! {conn: {
+            let mut count_query = users::table.count().into_boxed();
+            let mut query = users::table.into_boxed();
+            if let Some(pattern) = pattern {
+                count_query = count_query.filter(
+                    lower(users::email)
+                        .like(pattern.clone())
+                        .escape(LIKE_ESCAPE)
+                        .or(lower(users::name).like(pattern.clone()).escape(LIKE_ESCAPE)),
+                );
+                query = query.filter(
+                    lower(users::email)
+                        .like(pattern.clone())
+                        .escape(LIKE_ESCAPE)
+                        .or(lower(users::name).like(pattern).escape(LIKE_ESCAPE)),
+                );
+            }
+            let total = count_query.first::<i64>(conn).unwrap_or(0);
+
+            query = match (sort, descending) {
+                (UserSort::Email, false) => query.order(users::email.asc()),
+                (UserSort::Email, true) => query.order(users::email.desc()),
+                (UserSort::Name, false) => query.order(users::name.asc()),
+                (UserSort::Name, true) => query.order(users::name.desc()),
+                (UserSort::CreatedAt, false) => query.order(users::created_at.asc()),
+                (UserSort::CreatedAt, true) => query.order(users::created_at.desc()),
+            };
+            // The uuid keeps the pages stable when the sorted values are equal
+            let users = query
+                .then_order_by(users::uuid.asc())
+                .offset(offset)
+                .limit(limit)
+                .load::<UserDb>(conn)
+                .expect("Error loading users")
+                .from_db();
+            (users, total)
+        }}
+    }
+
+    /// The equivalent domains defined by the users, as their JSON lists of domain groups.
+    pub async fn find_all_equivalent_domains(conn: &mut DbConn) -> Vec<String> {
+        db_run! {conn: {
+            users::table
+                .filter(users::equivalent_domains.ne("[]"))
+                .select(users::equivalent_domains)
+                .load::<String>(conn)
+                .unwrap_or_default()
+        }}
+    }
+
     pub async fn last_active(&self, conn: &mut DbConn) -> Option<NaiveDateTime> {
         match Device::find_latest_active_by_user(&self.uuid, conn).await {
             Some(device) => Some(device.updated_at),
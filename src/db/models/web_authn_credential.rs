@@ -0,0 +1,140 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = web_authn_credentials)]
+    #[diesel(treat_none_as_null = true)]
+    #[diesel(primary_key(uuid))]
+    pub struct WebAuthnCredential {
+        pub uuid: String,
+        pub user_uuid: String,
+        pub name: String,
+        pub credential: String, // The serialized webauthn-rs Credential
+        pub supports_prf: bool,
+
+        // Only set when the credential supports the PRF extension and can be used to unlock the vault
+        pub encrypted_user_key: Option<String>,
+        pub encrypted_public_key: Option<String>,
+        pub encrypted_private_key: Option<String>,
+
+        pub creation_date: NaiveDateTime,
+    }
+}
+
+/// Local methods
+impl WebAuthnCredential {
+    pub fn new(user_uuid: String, name: String, credential: String, supports_prf: bool) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            user_uuid,
+            name,
+            credential,
+            supports_prf,
+
+            encrypted_user_key: None,
+            encrypted_public_key: None,
+            encrypted_private_key: None,
+
+            creation_date: Utc::now().naive_utc(),
+        }
+    }
+
+    // https://github.com/bitwarden/server/blob/main/src/Core/Auth/Enums/WebAuthnPrfStatus.cs
+    pub fn prf_status(&self) -> i32 {
+        match (self.supports_prf, self.encrypted_user_key.is_some()) {
+            (true, true) => 0,  // Enabled
+            (true, false) => 1, // Supported
+            (false, _) => 2,    // Unsupported
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "Id": self.uuid,
+            "Name": self.name,
+            "PrfStatus": self.prf_status(),
+            "EncryptedUserKey": self.encrypted_user_key,
+            "EncryptedPublicKey": self.encrypted_public_key,
+            "Object": "webauthnCredential",
+        })
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl WebAuthnCredential {
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                match diesel::replace_into(web_authn_credentials::table)
+                    .values(WebAuthnCredentialDb::to_db(self))
+                    .execute(conn)
+                {
+                    Ok(_) => Ok(()),
+                    // Record already exists and causes a Foreign Key Violation because replace_into() wants to delete the record first.
+                    Err(diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::ForeignKeyViolation, _)) => {
+                        diesel::update(web_authn_credentials::table)
+                            .filter(web_authn_credentials::uuid.eq(&self.uuid))
+                            .set(WebAuthnCredentialDb::to_db(self))
+                            .execute(conn)
+                            .map_res("Error saving webauthn credential")
+                    }
+                    Err(e) => Err(e.into()),
+                }.map_res("Error saving webauthn credential")
+            }
+            postgresql {
+                let value = WebAuthnCredentialDb::to_db(self);
+                diesel::insert_into(web_authn_credentials::table)
+                    .values(&value)
+                    .on_conflict(web_authn_credentials::uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving webauthn credential")
+            }
+        }
+    }
+
+    pub async fn delete(self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(web_authn_credentials::table.filter(web_authn_credentials::uuid.eq(self.uuid)))
+                .execute(conn)
+                .map_res("Error deleting webauthn credential")
+        }}
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(web_authn_credentials::table.filter(web_authn_credentials::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting webauthn credentials")
+        }}
+    }
+
+    pub async fn find_by_uuid_and_user(uuid: &str, user_uuid: &str, conn: &mut DbConn) -> Option<Self> {
+        db_run! { conn: {
+            web_authn_credentials::table
+                .filter(web_authn_credentials::uuid.eq(uuid))
+                .filter(web_authn_credentials::user_uuid.eq(user_uuid))
+                .first::<WebAuthnCredentialDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub async fn find_all_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            web_authn_credentials::table
+                .filter(web_authn_credentials::user_uuid.eq(user_uuid))
+                .order(web_authn_credentials::creation_date)
+                .load::<WebAuthnCredentialDb>(conn)
+                .expect("Error loading webauthn credentials")
+                .from_db()
+        }}
+    }
+}
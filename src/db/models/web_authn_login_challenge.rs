@@ -0,0 +1,66 @@
+use chrono::Utc;
+
+use crate::{api::EmptyResult, db::DbConn, error::MapResult};
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = web_authn_login_challenges)]
+    #[diesel(primary_key(uuid))]
+    pub struct WebAuthnLoginChallenge {
+        pub uuid: String,
+        pub state: String, // The serialized webauthn-rs AuthenticationState
+        pub exp: i64,
+    }
+}
+
+// The challenge of a passkey login, kept until it's used once or expires.
+// The user isn't known when it's created, so unlike the 2FA challenges it's found by its uuid, which the clients get as the token.
+impl WebAuthnLoginChallenge {
+    pub fn new(state: String, ttl: i64) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            state,
+            exp: Utc::now().timestamp() + ttl,
+        }
+    }
+
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::insert_into(web_authn_login_challenges::table)
+                .values(WebAuthnLoginChallengeDb::to_db(self))
+                .execute(conn)
+                .map_res("Error saving passkey login challenge")
+        }}
+    }
+
+    /// Removes the challenge and returns it, unless it expired or was already taken by a concurrent request.
+    pub async fn take(uuid: &str, conn: &mut DbConn) -> Option<Self> {
+        let challenge: Self = db_run! { conn: {
+            web_authn_login_challenges::table
+                .filter(web_authn_login_challenges::uuid.eq(uuid))
+                .first::<WebAuthnLoginChallengeDb>(conn)
+                .ok()
+                .from_db()
+        }}?;
+
+        // Only the request which deleted the row can use it
+        let deleted: usize = db_run! { conn: {
+            diesel::delete(web_authn_login_challenges::table.filter(web_authn_login_challenges::uuid.eq(uuid)))
+                .execute(conn)
+                .unwrap_or(0)
+        }};
+        if deleted != 1 || challenge.exp < Utc::now().timestamp() {
+            return None;
+        }
+        Some(challenge)
+    }
+
+    pub async fn purge_expired(conn: &mut DbConn) -> EmptyResult {
+        let now = Utc::now().timestamp();
+        db_run! { conn: {
+            diesel::delete(web_authn_login_challenges::table.filter(web_authn_login_challenges::exp.lt(now)))
+                .execute(conn)
+                .map_res("Error deleting expired passkey login challenges")
+        }}
+    }
+}
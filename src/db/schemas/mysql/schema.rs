@@ -55,6 +55,8 @@ table! {
         push_token -> Nullable<Text>,
         refresh_token -> Text,
         twofactor_remember -> Nullable<Text>,
+        twofactor_remember_created_at -> Nullable<Timestamp>,
+        twofactor_remember_last_used -> Nullable<Timestamp>,
     }
 }
 
@@ -76,6 +78,8 @@ table! {
         provider_uuid -> Nullable<Varchar>,
         provider_user_uuid -> Nullable<Varchar>,
         provider_org_uuid -> Nullable<Varchar>,
+        emergency_access_uuid -> Nullable<Varchar>,
+        grantee_user_uuid -> Nullable<Varchar>,
     }
 }
 
@@ -174,6 +178,23 @@ table! {
     }
 }
 
+table! {
+    twofactor_duo_ctx (state) {
+        state -> Text,
+        user_email -> Text,
+        nonce -> Text,
+        exp -> BigInt,
+    }
+}
+
+table! {
+    web_authn_login_challenges (uuid) {
+        uuid -> Text,
+        state -> Text,
+        exp -> BigInt,
+    }
+}
+
 table! {
     users (uuid) {
         uuid -> Text,
@@ -207,6 +228,10 @@ table! {
         api_key -> Nullable<Text>,
         avatar_color -> Nullable<Text>,
         external_id -> Nullable<Text>,
+        totp_last_step -> BigInt,
+        uses_key_connector -> Bool,
+        key_connector_key -> Nullable<Text>,
+        locale -> Nullable<Text>,
     }
 }
 
@@ -230,6 +255,8 @@ table! {
         atype -> Integer,
         reset_password_key -> Nullable<Text>,
         external_id -> Nullable<Text>,
+        twofactor_grace_until -> Nullable<Timestamp>,
+        twofactor_reminder_at -> Nullable<Timestamp>,
     }
 }
 
@@ -257,6 +284,14 @@ table! {
         last_notification_at -> Nullable<Timestamp>,
         updated_at -> Timestamp,
         created_at -> Timestamp,
+        reminder_interval_hours -> Nullable<Integer>,
+        escalation_after_hours -> Nullable<Integer>,
+        escalation_contacts -> Nullable<Text>,
+        escalated_at -> Nullable<Timestamp>,
+        org_uuid -> Nullable<Text>,
+        scope_folders -> Nullable<Text>,
+        key_fingerprint -> Nullable<Text>,
+        key_invalid_at -> Nullable<Timestamp>,
     }
 }
 
@@ -308,6 +343,127 @@ table! {
     }
 }
 
+table! {
+    web_authn_credentials (uuid) {
+        uuid -> Text,
+        user_uuid -> Text,
+        name -> Text,
+        credential -> Text,
+        supports_prf -> Bool,
+        encrypted_user_key -> Nullable<Text>,
+        encrypted_public_key -> Nullable<Text>,
+        encrypted_private_key -> Nullable<Text>,
+        creation_date -> Timestamp,
+    }
+}
+
+table! {
+    external_identities (uuid) {
+        uuid -> Text,
+        user_uuid -> Text,
+        issuer -> Text,
+        subject -> Text,
+        email -> Text,
+        creation_date -> Timestamp,
+        last_used_date -> Timestamp,
+    }
+}
+
+table! {
+    icon_domains (domain) {
+        domain -> Text,
+        status -> Integer,
+        failures -> Integer,
+        last_error -> Nullable<Text>,
+        checked_at -> Timestamp,
+        retry_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    jobs (name) {
+        name -> Text,
+        enabled -> Bool,
+        schedule -> Nullable<Text>,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    job_runs (uuid) {
+        uuid -> Text,
+        job_name -> Text,
+        manual -> Bool,
+        started_at -> Timestamp,
+        duration_ms -> BigInt,
+        error -> Nullable<Text>,
+    }
+}
+
+table! {
+    mail_log (uuid) {
+        uuid -> Text,
+        recipient -> Text,
+        template -> Text,
+        subject -> Text,
+        body_html -> Text,
+        body_text -> Text,
+        status -> Integer,
+        error -> Nullable<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        attachments -> Nullable<Text>,
+    }
+}
+
+table! {
+    mail_preferences (user_uuid, mail_type) {
+        user_uuid -> Text,
+        mail_type -> Integer,
+        enabled -> Bool,
+    }
+}
+
+table! {
+    mail_queue (uuid) {
+        uuid -> Text,
+        recipient -> Text,
+        subject -> Text,
+        body_html -> Text,
+        body_text -> Text,
+        status -> Integer,
+        attempts -> Integer,
+        next_attempt_at -> Timestamp,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamp,
+        attachments -> Nullable<Text>,
+        digest -> Bool,
+    }
+}
+
+table! {
+    notification_events (uuid) {
+        uuid -> Text,
+        user_uuid -> Text,
+        seq -> BigInt,
+        atype -> Integer,
+        data -> Text,
+        created_at -> Timestamp,
+        delivered -> Bool,
+    }
+}
+
+table! {
+    tombstones (uuid) {
+        uuid -> Text,
+        atype -> Integer,
+        object_uuid -> Text,
+        user_uuid -> Nullable<Text>,
+        org_uuid -> Nullable<Text>,
+        deleted_at -> Timestamp,
+    }
+}
+
 joinable!(attachments -> ciphers (cipher_uuid));
 joinable!(ciphers -> organizations (organization_uuid));
 joinable!(ciphers -> users (user_uuid));
@@ -336,6 +492,10 @@ joinable!(collections_groups -> collections (collections_uuid));
 joinable!(collections_groups -> groups (groups_uuid));
 joinable!(event -> users_organizations (uuid));
 joinable!(auth_requests -> users (user_uuid));
+joinable!(web_authn_credentials -> users (user_uuid));
+joinable!(external_identities -> users (user_uuid));
+joinable!(notification_events -> users (user_uuid));
+joinable!(mail_preferences -> users (user_uuid));
 
 allow_tables_to_appear_in_same_query!(
     attachments,
@@ -360,4 +520,16 @@ allow_tables_to_appear_in_same_query!(
     collections_groups,
     event,
     auth_requests,
+    web_authn_credentials,
+    external_identities,
+    mail_queue,
+    mail_log,
+    mail_preferences,
+    notification_events,
+    tombstones,
+    twofactor_duo_ctx,
+    web_authn_login_challenges,
+    icon_domains,
+    jobs,
+    job_runs,
 );
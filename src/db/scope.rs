@@ -0,0 +1,273 @@
+//
+// Scoping audit of the loaded rows
+//
+// The finders of the user and organization data need to filter on the user or the organization, a missing predicate
+// would hand the rows of another tenant to the API. The finders pass their rows through `audit`, which checks that
+// each row belongs to the requested scope. Debug builds panic on a violation, so the regressions are caught by the
+// tests and CI. Release builds only check the rows with `DB_SCOPE_AUDIT`, they then log the violations and drop the rows.
+//
+use super::{
+    models::{Cipher, Collection, Device, Event, Folder, Group, OrgPolicy, Send, TwoFactor, UserOrganization},
+    DbConn,
+};
+use crate::CONFIG;
+
+/// A row which belongs to a user or an organization.
+pub trait Scoped {
+    const TABLE: &'static str;
+
+    fn row_uuid(&self) -> &str;
+
+    fn scope_user_uuid(&self) -> Option<&str> {
+        None
+    }
+
+    fn scope_org_uuid(&self) -> Option<&str> {
+        None
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Scope<'a> {
+    User(&'a str),
+    Org(&'a str),
+    /// The rows of the user, and the ones of the organizations they are a confirmed member of
+    UserAndOrgs(&'a str, &'a [String]),
+}
+
+impl Scope<'_> {
+    fn contains<T: Scoped>(self, row: &T) -> bool {
+        match self {
+            Scope::User(user_uuid) => row.scope_user_uuid() == Some(user_uuid),
+            Scope::Org(org_uuid) => row.scope_org_uuid() == Some(org_uuid),
+            Scope::UserAndOrgs(user_uuid, org_uuids) => {
+                row.scope_user_uuid() == Some(user_uuid)
+                    || row.scope_org_uuid().is_some_and(|org_uuid| org_uuids.iter().any(|o| o == org_uuid))
+            }
+        }
+    }
+}
+
+fn enabled() -> bool {
+    cfg!(debug_assertions) || CONFIG.db_scope_audit()
+}
+
+/// Checks that all the rows loaded by `finder` belong to the scope.
+pub fn audit<T: Scoped>(rows: Vec<T>, scope: Scope<'_>, finder: &str) -> Vec<T> {
+    if !enabled() {
+        return rows;
+    }
+
+    rows.into_iter()
+        .filter(|row| {
+            if scope.contains(row) {
+                return true;
+            }
+            let msg = format!("{finder} loaded the {} row {} outside of its scope", T::TABLE, row.row_uuid());
+            if cfg!(debug_assertions) {
+                panic!("{msg}");
+            }
+            error!("{msg}, the row was dropped");
+            false
+        })
+        .collect()
+}
+
+/// Same as `audit` with `Scope::UserAndOrgs`, the organizations of the user are only loaded when the audit is enabled.
+pub async fn audit_user_and_orgs<T: Scoped>(rows: Vec<T>, user_uuid: &str, finder: &str, conn: &mut DbConn) -> Vec<T> {
+    if !enabled() {
+        return rows;
+    }
+
+    let org_uuids: Vec<String> = UserOrganization::find_confirmed_by_user(user_uuid, conn)
+        .await
+        .into_iter()
+        .map(|user_org| user_org.org_uuid)
+        .collect();
+    audit(rows, Scope::UserAndOrgs(user_uuid, &org_uuids), finder)
+}
+
+impl Scoped for Cipher {
+    const TABLE: &'static str = "ciphers";
+
+    fn row_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    fn scope_user_uuid(&self) -> Option<&str> {
+        self.user_uuid.as_deref()
+    }
+
+    fn scope_org_uuid(&self) -> Option<&str> {
+        self.organization_uuid.as_deref()
+    }
+}
+
+impl Scoped for Folder {
+    const TABLE: &'static str = "folders";
+
+    fn row_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    fn scope_user_uuid(&self) -> Option<&str> {
+        Some(&self.user_uuid)
+    }
+}
+
+impl Scoped for Send {
+    const TABLE: &'static str = "sends";
+
+    fn row_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    fn scope_user_uuid(&self) -> Option<&str> {
+        self.user_uuid.as_deref()
+    }
+
+    fn scope_org_uuid(&self) -> Option<&str> {
+        self.organization_uuid.as_deref()
+    }
+}
+
+impl Scoped for Device {
+    const TABLE: &'static str = "devices";
+
+    fn row_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    fn scope_user_uuid(&self) -> Option<&str> {
+        Some(&self.user_uuid)
+    }
+}
+
+impl Scoped for TwoFactor {
+    const TABLE: &'static str = "twofactor";
+
+    fn row_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    fn scope_user_uuid(&self) -> Option<&str> {
+        Some(&self.user_uuid)
+    }
+}
+
+impl Scoped for Collection {
+    const TABLE: &'static str = "collections";
+
+    fn row_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    fn scope_org_uuid(&self) -> Option<&str> {
+        Some(&self.org_uuid)
+    }
+}
+
+impl Scoped for UserOrganization {
+    const TABLE: &'static str = "users_organizations";
+
+    fn row_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    fn scope_user_uuid(&self) -> Option<&str> {
+        Some(&self.user_uuid)
+    }
+
+    fn scope_org_uuid(&self) -> Option<&str> {
+        Some(&self.org_uuid)
+    }
+}
+
+impl Scoped for OrgPolicy {
+    const TABLE: &'static str = "org_policies";
+
+    fn row_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    fn scope_org_uuid(&self) -> Option<&str> {
+        Some(&self.org_uuid)
+    }
+}
+
+impl Scoped for Group {
+    const TABLE: &'static str = "groups";
+
+    fn row_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    fn scope_org_uuid(&self) -> Option<&str> {
+        Some(&self.organizations_uuid)
+    }
+}
+
+impl Scoped for Event {
+    const TABLE: &'static str = "event";
+
+    fn row_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    fn scope_user_uuid(&self) -> Option<&str> {
+        self.user_uuid.as_deref()
+    }
+
+    fn scope_org_uuid(&self) -> Option<&str> {
+        self.org_uuid.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Row {
+        user_uuid: Option<&'static str>,
+        org_uuid: Option<&'static str>,
+    }
+
+    impl Scoped for Row {
+        const TABLE: &'static str = "rows";
+
+        fn row_uuid(&self) -> &str {
+            "row"
+        }
+
+        fn scope_user_uuid(&self) -> Option<&str> {
+            self.user_uuid
+        }
+
+        fn scope_org_uuid(&self) -> Option<&str> {
+            self.org_uuid
+        }
+    }
+
+    #[test]
+    fn test_scope_contains() {
+        let personal = Row {
+            user_uuid: Some("user"),
+            org_uuid: None,
+        };
+        let shared = Row {
+            user_uuid: None,
+            org_uuid: Some("org"),
+        };
+        let orgs = ["org".to_string()];
+
+        assert!(Scope::User("user").contains(&personal));
+        assert!(!Scope::User("other").contains(&personal));
+        assert!(!Scope::User("user").contains(&shared));
+        assert!(Scope::Org("org").contains(&shared));
+        assert!(!Scope::Org("org").contains(&personal));
+        assert!(Scope::UserAndOrgs("user", &orgs).contains(&personal));
+        assert!(Scope::UserAndOrgs("other", &orgs).contains(&shared));
+        assert!(!Scope::UserAndOrgs("other", &[]).contains(&personal));
+        assert!(!Scope::UserAndOrgs("other", &[]).contains(&shared));
+    }
+}
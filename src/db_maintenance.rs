@@ -0,0 +1,137 @@
+//
+// Periodic database maintenance
+//
+// Long running instances accumulate free pages and outdated query planner statistics. SQLite databases are written back
+// from the WAL and vacuumed, PostgreSQL databases are vacuumed and analyzed, and MySQL/MariaDB only analyzes the
+// largest tables. SQLite blocks the writes while vacuuming, so the scheduled job can be limited to the quiet hours
+// of `DB_MAINTENANCE_WINDOW`. The last run is kept in memory, to be shown in the admin diagnostics.
+//
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use chrono::{Local, NaiveDateTime, NaiveTime, Utc};
+use serde_json::Value;
+
+use crate::{
+    db::{optimize_database, DbConn, DbPool},
+    error::Error,
+    util::format_naive_datetime_local,
+    CONFIG,
+};
+
+struct MaintenanceRun {
+    trigger: &'static str,
+    started_at: NaiveDateTime,
+    duration_ms: u64,
+    error: Option<String>,
+}
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static LAST_RUN: Mutex<Option<MaintenanceRun>> = Mutex::new(None);
+
+/// Parses a `HH:MM-HH:MM` window of the local time, which can span midnight.
+pub fn parse_window(window: &str) -> Result<(NaiveTime, NaiveTime), String> {
+    let parse = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M");
+    match window.split_once('-') {
+        Some((start, end)) => match (parse(start), parse(end)) {
+            (Ok(start), Ok(end)) if start != end => Ok((start, end)),
+            _ => Err(format!("`{window}` isn't a valid `HH:MM-HH:MM` window")),
+        },
+        None => Err(format!("`{window}` isn't a valid `HH:MM-HH:MM` window")),
+    }
+}
+
+fn in_window(window: &str, now: NaiveTime) -> bool {
+    match parse_window(window) {
+        Ok((start, end)) if start < end => start <= now && now < end,
+        Ok((start, end)) => now >= start || now < end,
+        Err(_) => false,
+    }
+}
+
+/// Runs the maintenance of the database, one run at a time.
+pub async fn run_maintenance(conn: &mut DbConn, trigger: &'static str) -> Result<Value, Error> {
+    if RUNNING.swap(true, Ordering::AcqRel) {
+        err!("The database maintenance is already running")
+    }
+
+    info!("Starting the database maintenance");
+    let started_at = Utc::now().naive_utc();
+    let start = Instant::now();
+    let result = optimize_database(conn).await;
+    let run = MaintenanceRun {
+        trigger,
+        started_at,
+        duration_ms: start.elapsed().as_millis() as u64,
+        error: result.as_ref().err().map(|e| e.to_string()),
+    };
+    match &run.error {
+        None => info!("Database maintenance done in {}ms", run.duration_ms),
+        Some(e) => error!("Error running the database maintenance: {e}"),
+    }
+
+    let run_json = to_json(&run);
+    *LAST_RUN.lock().unwrap() = Some(run);
+    RUNNING.store(false, Ordering::Release);
+
+    result.map(|_| run_json)
+}
+
+pub async fn maintenance_job(pool: DbPool) {
+    if let Some(window) = CONFIG.db_maintenance_window() {
+        if !in_window(&window, Local::now().time()) {
+            debug!("Outside of the database maintenance window, skipping");
+            return;
+        }
+    }
+
+    debug!("Start database maintenance job");
+    let Ok(mut conn) = pool.get().await else {
+        error!("Failed to get DB connection while running the database maintenance");
+        return;
+    };
+    run_maintenance(&mut conn, "schedule").await.ok();
+}
+
+fn to_json(run: &MaintenanceRun) -> Value {
+    json!({
+        "trigger": run.trigger,
+        "started_at": format_naive_datetime_local(&run.started_at, "%Y-%m-%d %H:%M:%S %Z"),
+        "duration_ms": run.duration_ms,
+        "success": run.error.is_none(),
+        "error": run.error,
+    })
+}
+
+/// The last run since the server started, for the admin diagnostics.
+pub fn last_run_json() -> Value {
+    json!({
+        "running": RUNNING.load(Ordering::Acquire),
+        "last_run": LAST_RUN.lock().unwrap().as_ref().map(to_json),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_maintenance_window() {
+        let time = |t: &str| NaiveTime::parse_from_str(t, "%H:%M").unwrap();
+
+        assert!(in_window("01:00-05:00", time("03:30")));
+        assert!(!in_window("01:00-05:00", time("05:00")));
+        assert!(in_window("22:00-04:00", time("23:15")));
+        assert!(in_window("22:00-04:00", time("02:00")));
+        assert!(!in_window("22:00-04:00", time("12:00")));
+
+        assert!(parse_window("01:00").is_err());
+        assert!(parse_window("01:00-01:00").is_err());
+        assert!(parse_window("25:00-04:00").is_err());
+    }
+}
@@ -0,0 +1,168 @@
+//
+// DKIM signatures of the outgoing mails
+//
+// The mails are signed with the relaxed canonicalization of their headers and body, which isn't broken by the relays
+// folding the headers again or changing the whitespace. The public key needs to be published in the DNS TXT record
+// `<selector>._domainkey.<domain>`. RSA and Ed25519 keys are supported, Ed25519 isn't verified by all the providers.
+// Ref: https://datatracker.ietf.org/doc/html/rfc6376
+// Ref: https://datatracker.ietf.org/doc/html/rfc8463
+//
+use chrono::Utc;
+use data_encoding::BASE64;
+use openssl::{
+    hash::MessageDigest,
+    pkey::{Id, PKey, Private},
+    sign::Signer,
+};
+use ring::digest;
+
+use crate::{error::Error, CONFIG};
+
+// The headers which are signed, when the message has them
+const SIGNED_HEADERS: [&str; 7] = ["from", "to", "subject", "date", "message-id", "mime-version", "content-type"];
+
+/// Loads the PEM private key, in the PKCS#1 or PKCS#8 format.
+pub fn load_key(path: &str) -> Result<PKey<Private>, Error> {
+    let key = PKey::private_key_from_pem(&std::fs::read(path)?)?;
+    match key.id() {
+        Id::RSA | Id::ED25519 => Ok(key),
+        _ => err!("The DKIM key needs to be an RSA or Ed25519 key"),
+    }
+}
+
+/// Adds the `DKIM-Signature` header to the message, when `DKIM_PRIVATE_KEY` is set.
+pub fn sign(message: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let (Some(key_path), Some(selector)) = (CONFIG.dkim_private_key(), CONFIG.dkim_selector()) else {
+        return Ok(message);
+    };
+    let domain = match CONFIG.dkim_domain() {
+        Some(domain) => domain,
+        None => CONFIG.smtp_from().split('@').nth(1).unwrap_or_default().to_string(),
+    };
+
+    let header = signature_header(&message, &load_key(&key_path)?, &domain, &selector, Utc::now().timestamp())?;
+    let mut signed = header.into_bytes();
+    signed.extend(message);
+    Ok(signed)
+}
+
+fn signature_header(
+    message: &[u8],
+    key: &PKey<Private>,
+    domain: &str,
+    selector: &str,
+    timestamp: i64,
+) -> Result<String, Error> {
+    let (headers, body) = split_message(message);
+    let headers = parse_headers(&headers);
+    // The last occurrence of a header is the one which is signed
+    let signed: Vec<&(String, String)> = SIGNED_HEADERS
+        .iter()
+        .filter_map(|signed_name| headers.iter().rev().find(|(name, _)| name.eq_ignore_ascii_case(signed_name)))
+        .collect();
+
+    let algorithm = match key.id() {
+        Id::ED25519 => "ed25519-sha256",
+        _ => "rsa-sha256",
+    };
+    let body_hash = BASE64.encode(digest::digest(&digest::SHA256, canonicalize_body(&body).as_bytes()).as_ref());
+    let names = signed.iter().map(|(name, _)| name.to_lowercase()).collect::<Vec<_>>().join(":");
+    let value = format!(
+        "v=1; a={algorithm}; c=relaxed/relaxed; d={domain}; s={selector}; t={timestamp}; h={names}; bh={body_hash}; b="
+    );
+
+    // The signature header is signed too, with an empty signature and without the line break
+    let mut data = String::new();
+    for (name, value) in signed {
+        data.push_str(&canonicalize_header(name, value));
+        data.push_str("\r\n");
+    }
+    data.push_str(&canonicalize_header("DKIM-Signature", &value));
+
+    let signature = match key.id() {
+        // Ed25519 signs the hash of the data instead of the data itself
+        Id::ED25519 => Signer::new_without_digest(key)?
+            .sign_oneshot_to_vec(digest::digest(&digest::SHA256, data.as_bytes()).as_ref())?,
+        _ => {
+            let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+            signer.update(data.as_bytes())?;
+            signer.sign_to_vec()?
+        }
+    };
+    Ok(format!("DKIM-Signature: {value}{}\r\n", BASE64.encode(&signature)))
+}
+
+// The headers and the body are separated by the first empty line
+fn split_message(message: &[u8]) -> (String, String) {
+    let message = String::from_utf8_lossy(message);
+    match message.split_once("\r\n\r\n") {
+        Some((headers, body)) => (format!("{headers}\r\n"), body.to_string()),
+        None => (message.to_string(), String::new()),
+    }
+}
+
+// Returns the name and the value of each header, the folded lines are kept in the value
+fn parse_headers(headers: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in headers.split("\r\n").filter(|line| !line.is_empty()) {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push_str("\r\n");
+                value.push_str(line);
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            fields.push((name.to_string(), value.to_string()));
+        }
+    }
+    fields
+}
+
+fn collapse_whitespace(value: &str) -> String {
+    let mut collapsed = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == ' ' || c == '\t' {
+            if !collapsed.ends_with(' ') {
+                collapsed.push(' ');
+            }
+        } else {
+            collapsed.push(c);
+        }
+    }
+    collapsed
+}
+
+fn canonicalize_header(name: &str, value: &str) -> String {
+    let value = collapse_whitespace(&value.replace("\r\n", ""));
+    format!("{}:{}", name.trim_end().to_lowercase(), value.trim_matches(' '))
+}
+
+fn canonicalize_body(body: &str) -> String {
+    let mut canonical = String::with_capacity(body.len());
+    for line in body.split("\r\n") {
+        canonical.push_str(collapse_whitespace(line).trim_end_matches(' '));
+        canonical.push_str("\r\n");
+    }
+    // The empty lines at the end are ignored, a body which isn't empty ends with a single line break
+    canonical.truncate(canonical.trim_end_matches("\r\n").len());
+    if !canonical.is_empty() {
+        canonical.push_str("\r\n");
+    }
+    canonical
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example of https://datatracker.ietf.org/doc/html/rfc6376#section-3.4.5
+    #[test]
+    fn test_relaxed_canonicalization() {
+        let (headers, body) = split_message(b"A: X\r\nB : Y\t\r\n\tZ  \r\n\r\n C \r\nD \t E\r\n\r\n\r\n");
+        let headers: Vec<String> =
+            parse_headers(&headers).iter().map(|(name, value)| canonicalize_header(name, value)).collect();
+
+        assert_eq!(headers, ["a:X", "b:Y Z"]);
+        assert_eq!(canonicalize_body(&body), " C\r\nD E\r\n");
+        assert_eq!(canonicalize_body("\r\n\r\n"), "");
+    }
+}
@@ -2,6 +2,7 @@
 // Error generator macro
 //
 use crate::db::models::EventType;
+use crate::util::CustomResolverError as ResolverErr;
 use std::error::Error as StdError;
 
 macro_rules! make_error {
@@ -90,6 +91,7 @@ make_error! {
 
     DieselCon(DieselConErr): _has_source, _api_error,
     Webauthn(WebauthnErr):   _has_source, _api_error,
+    Resolver(ResolverErr):   _has_source, _api_error,
 }
 
 impl std::fmt::Debug for Error {
@@ -213,7 +215,16 @@ impl<'r> Responder<'r, 'static> for Error {
         };
 
         let code = Status::from_code(self.error_code).unwrap_or(Status::BadRequest);
-        let body = self.to_string();
+        let mut body = self.to_string();
+        // The ID of the request is added to the errors of the API, to find their logs
+        if let Some(request_id) = crate::log_context::current_request_id() {
+            if let Ok(Value::Object(mut error)) = serde_json::from_str::<Value>(&body) {
+                if error.get("Object").is_some_and(|object| object == "error") {
+                    error.insert("RequestId".into(), request_id.into());
+                    body = Value::Object(error).to_string();
+                }
+            }
+        }
         Response::build().status(code).header(ContentType::JSON).sized_body(Some(body.len()), Cursor::new(body)).ok()
     }
 }
@@ -0,0 +1,121 @@
+//
+// The HTTP clients of the outgoing requests
+//
+// The requests to the push relay, HIBP and the other services share one client. The icon downloads have their own
+// client, built from the same builder with their proxy, minimum TLS version and CA bundle.
+// The requests sent with `send_traced` get a span when the traces are exported.
+//
+use std::{future::Future, time::Duration};
+
+use once_cell::sync::Lazy;
+use reqwest::{header, tls, Certificate, Client, ClientBuilder, Proxy, RequestBuilder, Response};
+use tracing::{field::Empty, Instrument};
+
+pub fn get_reqwest_client() -> &'static Client {
+    static INSTANCE: Lazy<Client> = Lazy::new(|| get_reqwest_client_builder().build().expect("Failed to build client"));
+    &INSTANCE
+}
+
+pub fn get_reqwest_client_builder() -> ClientBuilder {
+    let mut headers = header::HeaderMap::new();
+    headers.insert(header::USER_AGENT, header::HeaderValue::from_static("Vaultwarden"));
+    Client::builder().default_headers(headers).timeout(Duration::from_secs(10))
+}
+
+pub trait TracedSend {
+    /// Sends the request like `send`, in a span with the method, the host and the status of the response.
+    fn send_traced(self) -> impl Future<Output = reqwest::Result<Response>> + Send;
+}
+
+impl TracedSend for RequestBuilder {
+    async fn send_traced(self) -> reqwest::Result<Response> {
+        if !crate::otel::enabled() {
+            return self.send().await;
+        }
+        let (client, request) = self.build_split();
+        let request = request?;
+        let method = request.method().as_str().to_string();
+        // The path and the query aren't recorded, some of them have email addresses or tokens
+        let span = tracing::info_span!(
+            "http.client",
+            otel.name = method.as_str(),
+            otel.kind = "client",
+            otel.status_code = Empty,
+            http.request.method = method.as_str(),
+            server.address = request.url().host_str().unwrap_or_default(),
+            http.response.status_code = Empty,
+        );
+
+        let result = client.execute(request).instrument(span.clone()).await;
+        match &result {
+            Ok(response) => {
+                span.record("http.response.status_code", response.status().as_u16());
+                if response.status().is_server_error() {
+                    span.record("otel.status_code", "error");
+                }
+            }
+            Err(_) => {
+                span.record("otel.status_code", "error");
+            }
+        }
+        result
+    }
+}
+
+/// The options of a client which aren't the same for all the requests, unset when they are None.
+#[derive(Default)]
+pub struct ClientOptions {
+    /// An http://, https:// or socks5:// URL
+    pub proxy: Option<String>,
+    /// `1.0`, `1.1` or `1.2`, TLS 1.3 can't be required with the native TLS of all the platforms
+    pub min_tls_version: Option<String>,
+    /// The path of a PEM file with the certificates of the CAs trusted in addition to the system ones
+    pub ca_bundle: Option<String>,
+}
+
+impl ClientOptions {
+    pub fn apply(&self, mut builder: ClientBuilder) -> Result<ClientBuilder, String> {
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy).map_err(|e| format!("The proxy `{proxy}` is invalid: {e}"))?);
+        }
+        if let Some(version) = &self.min_tls_version {
+            builder = builder.min_tls_version(match version.as_str() {
+                "1.0" => tls::Version::TLS_1_0,
+                "1.1" => tls::Version::TLS_1_1,
+                "1.2" => tls::Version::TLS_1_2,
+                _ => return Err(format!("The minimum TLS version `{version}` needs to be one of 1.0, 1.1 or 1.2")),
+            });
+        }
+        if let Some(path) = &self.ca_bundle {
+            let bundle =
+                std::fs::read_to_string(path).map_err(|e| format!("The CA bundle `{path}` can't be read: {e}"))?;
+            let certificates = parse_pem_certificates(&bundle);
+            if certificates.is_empty() {
+                return Err(format!("The CA bundle `{path}` has no certificate"));
+            }
+            for pem in certificates {
+                let certificate = Certificate::from_pem(pem.as_bytes())
+                    .map_err(|e| format!("The CA bundle `{path}` has an invalid certificate: {e}"))?;
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        Ok(builder)
+    }
+}
+
+// The certificates of a PEM bundle, each one with its BEGIN and END lines
+fn parse_pem_certificates(bundle: &str) -> Vec<&str> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let mut certificates = Vec::new();
+    let mut rest = bundle;
+    while let Some(start) = rest.find(BEGIN) {
+        let Some(end) = rest[start..].find(END) else {
+            break;
+        };
+        let end = start + end + END.len();
+        certificates.push(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    certificates
+}
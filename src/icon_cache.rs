@@ -0,0 +1,154 @@
+//
+// Storage of the downloaded icons
+//
+// The icons are cached on the disk by default, in `ICON_CACHE_FOLDER`. With several instances, or containers without a
+// persistent volume, they can be cached in Redis or in the S3 bucket instead, so they are only downloaded once.
+// The age of a cached icon decides when it's downloaded again: in Redis the time it was saved is stored before the
+// icon, on the disk and in S3 it's the modification time of the file or of the object.
+//
+use std::time::{Duration, SystemTime};
+
+use once_cell::sync::Lazy;
+use tokio::sync::Mutex;
+use url::Url;
+
+use crate::{
+    api::EmptyResult,
+    error::{Error, MapResult},
+    pubsub::{RedisConnection, MAX_MESSAGE_SIZE},
+    CONFIG,
+};
+
+const REDIS_KEY_PREFIX: &str = "vaultwarden:icon:";
+
+/// A place to cache the icons, selected with `ICON_CACHE_BACKEND`.
+#[rocket::async_trait]
+pub trait IconStore: Send + Sync {
+    /// The icon and the time it was saved, if it's cached.
+    async fn get(&self, domain: &str) -> Result<Option<(Vec<u8>, SystemTime)>, Error>;
+    async fn put(&self, domain: &str, icon: &[u8]) -> EmptyResult;
+}
+
+pub fn selected_store() -> Box<dyn IconStore> {
+    match CONFIG.icon_cache_backend().as_str() {
+        "redis" => Box::new(Redis),
+        #[cfg(feature = "s3")]
+        "s3" => Box::new(S3),
+        _ => Box::new(Disk),
+    }
+}
+
+/// Checks the `ICON_CACHE_REDIS_URL` config value.
+pub fn validate_redis_url(url: &str) -> Result<(), String> {
+    let url = Url::parse(url).map_err(|e| format!("`ICON_CACHE_REDIS_URL` is not a valid URL: {e}"))?;
+    if url.scheme() != "redis" {
+        return Err("`ICON_CACHE_REDIS_URL` must start with redis://, TLS isn't supported".to_string());
+    }
+    if url.host_str().is_none() {
+        return Err("`ICON_CACHE_REDIS_URL` must contain a host".to_string());
+    }
+    Ok(())
+}
+
+struct Disk;
+
+fn file_path(domain: &str) -> String {
+    format!("{}/{domain}.png", CONFIG.icon_cache_folder())
+}
+
+#[rocket::async_trait]
+impl IconStore for Disk {
+    async fn get(&self, domain: &str) -> Result<Option<(Vec<u8>, SystemTime)>, Error> {
+        let path = file_path(domain);
+        let icon = match tokio::fs::read(&path).await {
+            Ok(icon) => icon,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let modified = tokio::fs::symlink_metadata(&path).await?.modified()?;
+        Ok(Some((icon, modified)))
+    }
+
+    async fn put(&self, domain: &str, icon: &[u8]) -> EmptyResult {
+        tokio::fs::create_dir_all(CONFIG.icon_cache_folder()).await?;
+        tokio::fs::write(file_path(domain), icon).await?;
+        Ok(())
+    }
+}
+
+struct Redis;
+
+// A single connection is kept, with the URL it was opened with
+static REDIS_CONNECTION: Lazy<Mutex<Option<(String, RedisConnection)>>> = Lazy::new(|| Mutex::new(None));
+
+// The connection is only kept after a successful command, as it could be in the middle of a reply after an error
+async fn redis_command(args: &[&[u8]]) -> Result<Vec<Vec<u8>>, Error> {
+    let Some(url) = CONFIG.icon_cache_redis_url() else {
+        err!("`ICON_CACHE_REDIS_URL` is not set")
+    };
+
+    let mut cached = REDIS_CONNECTION.lock().await;
+    let mut connection = match cached.take() {
+        Some((connected_url, connection)) if connected_url == url => connection,
+        _ => RedisConnection::connect(&Url::parse(&url).map_res("Invalid `ICON_CACHE_REDIS_URL`")?).await?,
+    };
+    let reply = connection.command(args).await;
+    if reply.is_ok() {
+        *cached = Some((url, connection));
+    }
+    reply
+}
+
+#[rocket::async_trait]
+impl IconStore for Redis {
+    async fn get(&self, domain: &str) -> Result<Option<(Vec<u8>, SystemTime)>, Error> {
+        let key = format!("{REDIS_KEY_PREFIX}{domain}");
+        let reply = redis_command(&[b"GET", key.as_bytes()]).await?;
+        // A missing key is returned as an empty value
+        let Some(value) = reply.first().filter(|value| value.len() >= 8) else {
+            return Ok(None);
+        };
+        let (saved_at, icon) = value.split_at(8);
+        let saved_at = u64::from_be_bytes(saved_at.try_into().unwrap_or_default());
+        let saved_at = SystemTime::UNIX_EPOCH + Duration::from_secs(saved_at);
+        Ok(Some((icon.to_vec(), saved_at)))
+    }
+
+    async fn put(&self, domain: &str, icon: &[u8]) -> EmptyResult {
+        // The replies larger than that can't be read
+        if icon.len() + 8 > MAX_MESSAGE_SIZE {
+            debug!("The icon of {domain} is too large to be cached in Redis");
+            return Ok(());
+        }
+        let saved_at = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut value = saved_at.to_be_bytes().to_vec();
+        value.extend_from_slice(icon);
+
+        let key = format!("{REDIS_KEY_PREFIX}{domain}");
+        redis_command(&[b"SET", key.as_bytes(), &value]).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "s3")]
+struct S3;
+
+#[cfg(feature = "s3")]
+fn object_key(domain: &str) -> String {
+    format!("{}/{domain}.png", CONFIG.icon_cache_s3_prefix().trim_end_matches('/'))
+}
+
+#[cfg(feature = "s3")]
+#[rocket::async_trait]
+impl IconStore for S3 {
+    async fn get(&self, domain: &str) -> Result<Option<(Vec<u8>, SystemTime)>, Error> {
+        // Without its modification time, the icon is handled as expired
+        Ok(crate::s3::get_object(&object_key(domain))
+            .await?
+            .map(|(icon, modified)| (icon, modified.unwrap_or(SystemTime::UNIX_EPOCH))))
+    }
+
+    async fn put(&self, domain: &str, icon: &[u8]) -> EmptyResult {
+        crate::s3::put_object(&object_key(domain), icon.to_vec()).await
+    }
+}
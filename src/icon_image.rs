@@ -0,0 +1,724 @@
+//
+// Conversion of the downloaded icons, before they are cached and served
+//
+// The PNG and ICO icons are decoded, scaled down to fit in `ICON_SIZE` and encoded again as PNG, which drops their
+// metadata and the other images of the ICO files. The SVG icons stay vectors, but only with the elements and
+// attributes which draw them, so they can't run scripts or load other resources. The other formats are served as
+// they are, there is no decoder for them.
+//
+use std::io::{Read, Write};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression, Crc};
+
+use crate::CONFIG;
+
+const PNG_SIGNATURE: &[u8] = &[137, 80, 78, 71, 13, 10, 26, 10];
+
+// The larger images aren't decoded, they would take too much memory for an icon
+const MAX_DIMENSION: usize = 2048;
+
+// The elements and attributes which draw an icon, the other ones are removed with their content
+const SVG_ELEMENTS: &[&str] = &[
+    "svg",
+    "g",
+    "defs",
+    "symbol",
+    "use",
+    "path",
+    "rect",
+    "circle",
+    "ellipse",
+    "line",
+    "polyline",
+    "polygon",
+    "linearGradient",
+    "radialGradient",
+    "stop",
+    "clipPath",
+    "mask",
+    "title",
+    "desc",
+    "text",
+    "tspan",
+];
+const SVG_ATTRIBUTES: &[&str] = &[
+    "xmlns",
+    "xmlns:xlink",
+    "version",
+    "id",
+    "viewBox",
+    "preserveAspectRatio",
+    "width",
+    "height",
+    "x",
+    "y",
+    "x1",
+    "y1",
+    "x2",
+    "y2",
+    "cx",
+    "cy",
+    "r",
+    "rx",
+    "ry",
+    "fx",
+    "fy",
+    "dx",
+    "dy",
+    "d",
+    "points",
+    "pathLength",
+    "transform",
+    "fill",
+    "fill-opacity",
+    "fill-rule",
+    "stroke",
+    "stroke-width",
+    "stroke-opacity",
+    "stroke-linecap",
+    "stroke-linejoin",
+    "stroke-miterlimit",
+    "stroke-dasharray",
+    "stroke-dashoffset",
+    "opacity",
+    "color",
+    "display",
+    "visibility",
+    "clip-path",
+    "clip-rule",
+    "clipPathUnits",
+    "mask",
+    "maskUnits",
+    "maskContentUnits",
+    "offset",
+    "stop-color",
+    "stop-opacity",
+    "gradientUnits",
+    "gradientTransform",
+    "spreadMethod",
+    "href",
+    "xlink:href",
+    "font-family",
+    "font-size",
+    "font-weight",
+    "font-style",
+    "text-anchor",
+    "dominant-baseline",
+    "letter-spacing",
+];
+
+/// Returns the image subtype of the icon, when it's one of the supported formats.
+pub fn icon_type(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [137, 80, 78, 71, ..] => Some("png"),
+        [0, 0, 1, 0, ..] => Some("x-icon"),
+        [82, 73, 70, 70, ..] => Some("webp"),
+        [255, 216, 255, ..] => Some("jpeg"),
+        [71, 73, 70, 56, ..] => Some("gif"),
+        [66, 77, ..] => Some("bmp"),
+        _ if is_svg(bytes) => Some("svg+xml"),
+        _ => None,
+    }
+}
+
+/// Converts the icon to the format it's served with, and returns its image subtype.
+/// Returns None when it isn't an icon, or when it's an SVG which can't be sanitized.
+pub fn normalize(icon: &[u8]) -> Option<(Vec<u8>, &'static str)> {
+    let icon_type = icon_type(icon)?;
+    let size = CONFIG.icon_size() as usize;
+    let image = match icon_type {
+        "svg+xml" => return sanitize_svg(std::str::from_utf8(icon).ok()?).map(|svg| (svg.into_bytes(), icon_type)),
+        "png" if size > 0 => decode_png(icon),
+        "x-icon" if size > 0 => decode_ico(icon, size),
+        _ => return Some((icon.to_vec(), icon_type)),
+    };
+    match image.and_then(|image| encode_png(&image.scale_down(size)).ok()) {
+        Some(png) => Some((png, "png")),
+        None => {
+            // Like the interlaced PNGs or the compressed BMPs, they are still valid icons
+            debug!("Unable to convert the {icon_type} icon, it's kept as it is");
+            Some((icon.to_vec(), icon_type))
+        }
+    }
+}
+
+// The pixels are in RGBA, with 8 bits per channel
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    // Scales the image down to fit in a square of the size, each pixel is the average of the pixels it covers.
+    // The colors are weighted by their alpha, so the transparent pixels don't darken the edges.
+    fn scale_down(self, size: usize) -> Image {
+        let largest = self.width.max(self.height);
+        if largest <= size {
+            return self;
+        }
+        let width = (self.width * size / largest).max(1);
+        let height = (self.height * size / largest).max(1);
+
+        let premultiplied: Vec<f32> = self
+            .pixels
+            .chunks_exact(4)
+            .flat_map(|p| {
+                let alpha = f32::from(p[3]) / 255.0;
+                [f32::from(p[0]) * alpha, f32::from(p[1]) * alpha, f32::from(p[2]) * alpha, f32::from(p[3])]
+            })
+            .collect();
+
+        let columns = weights(self.width, width);
+        let mut horizontal = vec![0f32; width * self.height * 4];
+        for y in 0..self.height {
+            for (x, column) in columns.iter().enumerate() {
+                for &(source, weight) in column {
+                    for c in 0..4 {
+                        horizontal[(y * width + x) * 4 + c] +=
+                            premultiplied[(y * self.width + source) * 4 + c] * weight;
+                    }
+                }
+            }
+        }
+
+        let rows = weights(self.height, height);
+        let mut pixels = Vec::with_capacity(width * height * 4);
+        for row in &rows {
+            for x in 0..width {
+                let mut pixel = [0f32; 4];
+                for &(source, weight) in row {
+                    for (c, value) in pixel.iter_mut().enumerate() {
+                        *value += horizontal[(source * width + x) * 4 + c] * weight;
+                    }
+                }
+                let alpha = pixel[3] / 255.0;
+                for value in &mut pixel[..3] {
+                    *value = if alpha > 0.0 {
+                        *value / alpha
+                    } else {
+                        0.0
+                    };
+                }
+                pixels.extend(pixel.map(|value| value.round().clamp(0.0, 255.0) as u8));
+            }
+        }
+
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+}
+
+// The source pixels covered by each target pixel, with the part of the target pixel they cover
+fn weights(source: usize, target: usize) -> Vec<Vec<(usize, f32)>> {
+    let scale = source as f32 / target as f32;
+    (0..target)
+        .map(|i| {
+            let (start, end) = (i as f32 * scale, (i + 1) as f32 * scale);
+            (start.floor() as usize..(end.ceil() as usize).min(source))
+                .map(|s| {
+                    let covered = (end.min((s + 1) as f32) - start.max(s as f32)).max(0.0);
+                    (s, covered / scale)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn u16_le(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_le(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn u32_be(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+// The sample at the index of a row, the samples smaller than a byte are packed from the highest bits
+fn sample(row: &[u8], index: usize, depth: usize) -> u16 {
+    match depth {
+        8 => u16::from(row[index]),
+        16 => u16::from_be_bytes([row[index * 2], row[index * 2 + 1]]),
+        _ => {
+            let bit = index * depth;
+            u16::from((row[bit / 8] >> (8 - depth - bit % 8)) & ((1 << depth) - 1))
+        }
+    }
+}
+
+fn to_8_bits(value: u16, depth: usize) -> u8 {
+    match depth {
+        16 => (value >> 8) as u8,
+        8 => value as u8,
+        _ => (u32::from(value) * 255 / ((1 << depth) - 1)) as u8,
+    }
+}
+
+// Ref: https://www.w3.org/TR/png-3/
+fn decode_png(data: &[u8]) -> Option<Image> {
+    let mut chunks = data.strip_prefix(PNG_SIGNATURE)?;
+    let mut header = None;
+    let mut palette: &[u8] = &[];
+    let mut transparency: &[u8] = &[];
+    let mut compressed = Vec::new();
+    while chunks.len() >= 12 {
+        let length = u32_be(chunks, 0)? as usize;
+        let body = chunks.get(8..8 + length)?;
+        match &chunks[4..8] {
+            b"IHDR" if length == 13 => header = Some(body),
+            b"PLTE" => palette = body,
+            b"tRNS" => transparency = body,
+            b"IDAT" => compressed.extend_from_slice(body),
+            b"IEND" => break,
+            _ => (),
+        }
+        chunks = chunks.get(12 + length..)?;
+    }
+
+    let header = header?;
+    let (width, height) = (u32_be(header, 0)? as usize, u32_be(header, 4)? as usize);
+    let (depth, color_type, interlace) = (header[8] as usize, header[9], header[12]);
+    let channels = match (color_type, depth) {
+        (0, 1 | 2 | 4 | 8 | 16) => 1,
+        (2, 8 | 16) => 3,
+        (3, 1 | 2 | 4 | 8) => 1,
+        (4, 8 | 16) => 2,
+        (6, 8 | 16) => 4,
+        _ => return None,
+    };
+    // The interlaced images are rare for the icons, they are kept as they are
+    if interlace != 0 || width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return None;
+    }
+
+    let stride = (width * channels * depth + 7) / 8;
+    // The filters use the bytes of the previous pixel, or of the previous byte when the pixels are smaller
+    let pixel_bytes = (channels * depth + 7) / 8;
+    let expected = height * (stride + 1);
+    let mut filtered = Vec::with_capacity(expected);
+    ZlibDecoder::new(compressed.as_slice()).take(expected as u64).read_to_end(&mut filtered).ok()?;
+    if filtered.len() < expected {
+        return None;
+    }
+
+    let mut rows = vec![0u8; height * stride];
+    for y in 0..height {
+        let line = &filtered[y * (stride + 1)..(y + 1) * (stride + 1)];
+        let (previous, current) = rows.split_at_mut(y * stride);
+        let previous = previous.get(previous.len().saturating_sub(stride)..).filter(|_| y > 0);
+        let current = &mut current[..stride];
+        for x in 0..stride {
+            let a = if x >= pixel_bytes {
+                current[x - pixel_bytes]
+            } else {
+                0
+            };
+            let b = previous.map_or(0, |previous| previous[x]);
+            let c = match previous {
+                Some(previous) if x >= pixel_bytes => previous[x - pixel_bytes],
+                _ => 0,
+            };
+            let prediction = match line[0] {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((u16::from(a) + u16::from(b)) / 2) as u8,
+                4 => paeth(a, b, c),
+                _ => return None,
+            };
+            current[x] = line[x + 1].wrapping_add(prediction);
+        }
+    }
+
+    // The transparent color of the images without alpha, and the alpha of the palette entries
+    let transparent_gray = (color_type == 0 && transparency.len() >= 2).then(|| sample(transparency, 0, 16));
+    let transparent_rgb = (color_type == 2 && transparency.len() >= 6)
+        .then(|| [sample(transparency, 0, 16), sample(transparency, 1, 16), sample(transparency, 2, 16)]);
+
+    let mut pixels = Vec::with_capacity(width * height * 4);
+    for row in rows.chunks_exact(stride) {
+        for x in 0..width {
+            let s = |i| sample(row, x * channels + i, depth);
+            let b = |i| to_8_bits(s(i), depth);
+            let pixel = match color_type {
+                0 => {
+                    let alpha = if transparent_gray == Some(s(0)) {
+                        0
+                    } else {
+                        255
+                    };
+                    [b(0), b(0), b(0), alpha]
+                }
+                2 => {
+                    let alpha = if transparent_rgb == Some([s(0), s(1), s(2)]) {
+                        0
+                    } else {
+                        255
+                    };
+                    [b(0), b(1), b(2), alpha]
+                }
+                3 => {
+                    let index = s(0) as usize;
+                    let color = palette.get(index * 3..index * 3 + 3)?;
+                    [color[0], color[1], color[2], transparency.get(index).copied().unwrap_or(255)]
+                }
+                4 => [b(0), b(0), b(0), b(1)],
+                _ => [b(0), b(1), b(2), b(3)],
+            };
+            pixels.extend(pixel);
+        }
+    }
+
+    Some(Image {
+        width,
+        height,
+        pixels,
+    })
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = i16::from(a) + i16::from(b) - i16::from(c);
+    let (pa, pb, pc) = ((p - i16::from(a)).abs(), (p - i16::from(b)).abs(), (p - i16::from(c)).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// Decodes the smallest image which is at least as large as the icon, or the largest one.
+// Ref: https://learn.microsoft.com/en-us/previous-versions/ms997538(v=msdn.10)
+fn decode_ico(data: &[u8], size: usize) -> Option<Image> {
+    let count = u16_le(data, 4)? as usize;
+    let entries: Vec<(usize, u16, &[u8])> = (0..count)
+        .filter_map(|i| {
+            let entry = data.get(6 + i * 16..6 + (i + 1) * 16)?;
+            // A dimension of 0 is 256 pixels
+            let dimension = |byte: u8| {
+                if byte == 0 {
+                    256
+                } else {
+                    byte as usize
+                }
+            };
+            let (length, offset) = (u32_le(entry, 8)? as usize, u32_le(entry, 12)? as usize);
+            let image = data.get(offset..offset.checked_add(length)?)?;
+            Some((dimension(entry[0]).max(dimension(entry[1])), u16_le(entry, 6)?, image))
+        })
+        .collect();
+
+    let (_, _, image) = entries
+        .iter()
+        .filter(|(dimension, _, _)| *dimension >= size)
+        .min_by_key(|(dimension, bit_count, _)| (*dimension, std::cmp::Reverse(*bit_count)))
+        .or_else(|| entries.iter().max_by_key(|(dimension, bit_count, _)| (*dimension, *bit_count)))?;
+    if image.starts_with(PNG_SIGNATURE) {
+        decode_png(image)
+    } else {
+        decode_dib(image)
+    }
+}
+
+// A BMP without its file header, whose height includes the AND mask which follows the pixels.
+// Ref: https://learn.microsoft.com/en-us/windows/win32/api/wingdi/ns-wingdi-bitmapinfoheader
+fn decode_dib(data: &[u8]) -> Option<Image> {
+    let header_size = u32_le(data, 0)? as usize;
+    let width = usize::try_from(i32::from_le_bytes(data.get(4..8)?.try_into().ok()?)).ok()?;
+    let height = usize::try_from(i32::from_le_bytes(data.get(8..12)?.try_into().ok()?) / 2).ok()?;
+    let bit_count = u16_le(data, 14)? as usize;
+    // Only the uncompressed images, the compression is very rare in the icons
+    if u32_le(data, 16)? != 0 || width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return None;
+    }
+    let palette_length = match (bit_count, u32_le(data, 32)? as usize) {
+        (1 | 4 | 8, 0) => 1 << bit_count,
+        (1 | 4 | 8, used) => used.min(256),
+        (24 | 32, _) => 0,
+        _ => return None,
+    };
+    let palette = data.get(header_size..header_size + palette_length * 4)?;
+
+    let stride = (width * bit_count + 31) / 32 * 4;
+    let mask_stride = (width + 31) / 32 * 4;
+    let start = header_size + palette_length * 4;
+    let rows = data.get(start..start + stride * height)?;
+    // The AND mask is sometimes missing with the alpha of the 32 bits images
+    let mask = data.get(start + stride * height..start + stride * height + mask_stride * height);
+
+    let mut pixels = Vec::with_capacity(width * height * 4);
+    // The rows are stored from the bottom
+    for row in rows.chunks_exact(stride).rev() {
+        for x in 0..width {
+            let [blue, green, red, alpha] = match bit_count {
+                32 => [row[x * 4], row[x * 4 + 1], row[x * 4 + 2], row[x * 4 + 3]],
+                24 => [row[x * 3], row[x * 3 + 1], row[x * 3 + 2], 255],
+                _ => {
+                    let index = sample(row, x, bit_count) as usize;
+                    let color = palette.get(index * 4..index * 4 + 3)?;
+                    [color[0], color[1], color[2], 255]
+                }
+            };
+            pixels.extend([red, green, blue, alpha]);
+        }
+    }
+
+    // The AND mask makes the pixels transparent, unless the image has its own alpha
+    if bit_count != 32 || pixels.chunks_exact(4).all(|pixel| pixel[3] == 0) {
+        for (y, row) in mask.into_iter().flat_map(|mask| mask.chunks_exact(mask_stride).rev()).enumerate() {
+            for x in 0..width {
+                pixels[(y * width + x) * 4 + 3] = if sample(row, x, 1) == 1 {
+                    0
+                } else {
+                    255
+                };
+            }
+        }
+        if mask.is_none() {
+            pixels.chunks_exact_mut(4).for_each(|pixel| pixel[3] = 255);
+        }
+    }
+
+    Some(Image {
+        width,
+        height,
+        pixels,
+    })
+}
+
+// The rows aren't filtered, the icons are too small for the filters to make a difference
+fn encode_png(image: &Image) -> std::io::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    for row in image.pixels.chunks_exact(image.width * 4) {
+        encoder.write_all(&[0])?;
+        encoder.write_all(row)?;
+    }
+    let compressed = encoder.finish()?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend((image.width as u32).to_be_bytes());
+    header.extend((image.height as u32).to_be_bytes());
+    // 8 bits RGBA, without interlacing
+    header.extend([8, 6, 0, 0, 0]);
+
+    let mut png = PNG_SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &compressed);
+    write_chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    png.extend(kind);
+    png.extend(data);
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    png.extend(crc.sum().to_be_bytes());
+}
+
+enum Token<'a> {
+    Start {
+        name: &'a str,
+        attributes: Vec<(&'a str, &'a str)>,
+        self_closing: bool,
+    },
+    End(&'a str),
+    Text(&'a str),
+}
+
+// Splits the XML document into its elements and text. The comments, the processing instructions and the CDATA
+// sections are skipped, and the documents which define entities are refused.
+fn tokenize(mut text: &str) -> Option<Vec<Token<'_>>> {
+    let mut tokens = Vec::new();
+    text = text.trim_start_matches('\u{feff}');
+    while !text.is_empty() {
+        if let Some(rest) = text.strip_prefix("<!--") {
+            text = &rest[rest.find("-->")? + 3..];
+        } else if let Some(rest) = text.strip_prefix("<![CDATA[") {
+            text = &rest[rest.find("]]>")? + 3..];
+        } else if text.starts_with("<?") || text.starts_with("<!") {
+            let end = text.find('>')?;
+            if text[..end].contains('[') {
+                return None;
+            }
+            text = &text[end + 1..];
+        } else if let Some(rest) = text.strip_prefix("</") {
+            let end = rest.find('>')?;
+            tokens.push(Token::End(rest[..end].trim_end()));
+            text = &rest[end + 1..];
+        } else if let Some(rest) = text.strip_prefix('<') {
+            let (token, rest) = tokenize_start(rest)?;
+            tokens.push(token);
+            text = rest;
+        } else {
+            let end = text.find('<').unwrap_or(text.len());
+            tokens.push(Token::Text(&text[..end]));
+            text = &text[end..];
+        }
+    }
+    Some(tokens)
+}
+
+fn tokenize_start(text: &str) -> Option<(Token<'_>, &str)> {
+    let name_end = text.find(|c: char| c.is_whitespace() || c == '/' || c == '>')?;
+    let name = &text[..name_end];
+    let mut rest = &text[name_end..];
+    let mut attributes = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        for (end, self_closing) in [("/>", true), (">", false)] {
+            if let Some(rest) = rest.strip_prefix(end) {
+                let token = Token::Start {
+                    name,
+                    attributes,
+                    self_closing,
+                };
+                return Some((token, rest));
+            }
+        }
+        let (attribute, value) = rest.split_once('=')?;
+        let attribute = attribute.trim_end();
+        if attribute.is_empty() || attribute.contains(|c: char| c.is_whitespace() || "<>/\"'".contains(c)) {
+            return None;
+        }
+        let value = value.trim_start();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let end = value[1..].find(quote)? + 1;
+        attributes.push((attribute, &value[1..end]));
+        rest = &value[end + 1..];
+    }
+}
+
+fn is_svg(bytes: &[u8]) -> bool {
+    let Ok(text) = std::str::from_utf8(bytes) else {
+        return false;
+    };
+    if !text.trim_start_matches('\u{feff}').trim_start().starts_with('<') {
+        return false;
+    }
+    let root = tokenize(text).and_then(|tokens| {
+        tokens.into_iter().find_map(|token| match token {
+            Token::Start {
+                name,
+                ..
+            } => Some(name),
+            _ => None,
+        })
+    });
+    root == Some("svg")
+}
+
+fn is_allowed_attribute(attribute: &str, value: &str) -> bool {
+    // The entities could hide a reference, the values which draw an icon don't need them
+    if !SVG_ATTRIBUTES.contains(&attribute) || value.contains('&') {
+        return false;
+    }
+    let value = value.to_lowercase();
+    match attribute {
+        "href" | "xlink:href" => value.trim_start().starts_with('#'),
+        // Only the references to the elements of the icon itself, like the gradients
+        _ => value
+            .match_indices("url(")
+            .all(|(i, _)| value[i + 4..].trim_start_matches([' ', '"', '\'']).starts_with('#')),
+    }
+}
+
+// Rebuilds the SVG with the allowed elements and attributes, returns None when it's invalid or its root isn't `svg`
+fn sanitize_svg(text: &str) -> Option<String> {
+    let mut svg = String::with_capacity(text.len());
+    let mut open = Vec::new();
+    // The depth inside a removed element
+    let mut removed = 0;
+    let mut root_done = false;
+    for token in tokenize(text)? {
+        match token {
+            Token::Start {
+                name,
+                attributes,
+                self_closing,
+            } => {
+                if open.is_empty() && !root_done && name != "svg" {
+                    return None;
+                }
+                if removed > 0 || root_done || !SVG_ELEMENTS.contains(&name) {
+                    if !self_closing {
+                        removed += 1;
+                    }
+                    continue;
+                }
+                svg.push('<');
+                svg.push_str(name);
+                for (attribute, value) in attributes.into_iter().filter(|(a, v)| is_allowed_attribute(a, v)) {
+                    svg.push_str(&format!(" {attribute}=\"{}\"", value.replace('<', "&lt;").replace('"', "&quot;")));
+                }
+                if self_closing {
+                    svg.push_str("/>");
+                    root_done = open.is_empty();
+                } else {
+                    svg.push('>');
+                    open.push(name);
+                }
+            }
+            Token::End(name) => {
+                if removed > 0 {
+                    removed -= 1;
+                } else if open.last() == Some(&name) {
+                    open.pop();
+                    svg.push_str("</");
+                    svg.push_str(name);
+                    svg.push('>');
+                    root_done = open.is_empty();
+                } else {
+                    return None;
+                }
+            }
+            Token::Text(text) => {
+                if removed == 0 && !open.is_empty() {
+                    svg.push_str(text);
+                }
+            }
+        }
+    }
+    (root_done && removed == 0).then_some(svg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_png_is_scaled_down() {
+        let image = Image {
+            width: 64,
+            height: 32,
+            pixels: [255, 0, 0, 255].repeat(64 * 32),
+        };
+        let image = decode_png(&encode_png(&image.scale_down(32)).unwrap()).unwrap();
+
+        assert_eq!((image.width, image.height), (32, 16));
+        assert!(image.pixels.chunks_exact(4).all(|pixel| pixel == [255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn test_svg_is_sanitized() {
+        let svg = r##"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg" onload="alert(1)">
+<script>alert(2)</script><a href="https://example.com"><path d="M0 0"/></a>
+<rect fill="url(#g)" stroke="url(https://example.com/x)"/><use xlink:href="data:image/svg+xml,x"/></svg>"##;
+
+        assert_eq!(
+            sanitize_svg(svg).unwrap(),
+            "<svg xmlns=\"http://www.w3.org/2000/svg\">\n\n<rect fill=\"url(#g)\"/><use/></svg>"
+        );
+        assert!(sanitize_svg("<html><svg></svg></html>").is_none());
+        assert!(sanitize_svg("<!DOCTYPE svg [<!ENTITY x \"y\">]><svg></svg>").is_none());
+    }
+}
@@ -0,0 +1,382 @@
+//
+// Backups of the whole instance, for the `backup` and `restore` commands
+//
+// A backup is a gzip compressed tar archive with the data of the database, as an archive of `export-db` which doesn't
+// depend on the backend, the files of the attachments and Sends, the config file and the private keys. It ends with a
+// manifest, which has the size and the SHA-256 hash of each of its files.
+// The whole backup is checked against its manifest before anything is restored. The files are restored to the paths of
+// the current configuration, then the data is imported into the database, which needs to be empty.
+// The Sends stored in the S3 bucket aren't part of the backup.
+//
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use chrono::Utc;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+use crate::{
+    api::EmptyResult,
+    archive,
+    crypto::sha256_hex,
+    db::{models::ensure_empty, run_blocking, DbConn},
+    error::Error,
+    CONFIG,
+};
+
+const FORMAT: &str = "vaultwarden-backup";
+// Needs to be increased when the backups of the previous versions can't be restored anymore
+const VERSION: u32 = 1;
+const FILE_PREFIX: &str = "instance_";
+const FILE_EXTENSION: &str = ".tar.gz";
+const MANIFEST: &str = "manifest.json";
+const DATABASE: &str = "database.jsonl.gz";
+const BLOCK_SIZE: usize = 512;
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    format: String,
+    version: u32,
+    server_version: Option<String>,
+    created_at: String,
+    files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestFile {
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+impl ManifestFile {
+    fn new(path: &str, data: &[u8]) -> Self {
+        Self {
+            path: path.to_string(),
+            size: data.len() as u64,
+            sha256: sha256_hex(data),
+        }
+    }
+}
+
+// The single files of the backup, as (path in the backup, path of the current configuration)
+fn single_files() -> Vec<(&'static str, String)> {
+    let mut files = vec![
+        ("config.json", CONFIG.config_file()),
+        ("keys/rsa_key.pem", CONFIG.private_rsa_key()),
+        ("keys/key_connector_rsa_key.pem", CONFIG.key_connector_rsa_key()),
+    ];
+    if let Some(dkim_private_key) = CONFIG.dkim_private_key() {
+        files.push(("keys/dkim_private_key.pem", dkim_private_key));
+    }
+    files
+}
+
+// The folders whose files are in the backup, under the name of the folder
+fn folders() -> Vec<(&'static str, String)> {
+    let mut folders = vec![("attachments", CONFIG.attachments_folder())];
+    if !archive::sends_in_s3() {
+        folders.push(("sends", CONFIG.sends_folder()));
+    }
+    folders
+}
+
+/// Writes a backup of the instance to the path, or to the backup folder, and returns the path of the backup.
+pub async fn create_backup(path: Option<PathBuf>, conn: &mut DbConn) -> Result<PathBuf, Error> {
+    let path = match path {
+        Some(path) => path,
+        None => {
+            let folder = PathBuf::from(CONFIG.backup_folder());
+            tokio::fs::create_dir_all(&folder).await?;
+            folder.join(format!("{FILE_PREFIX}{}{FILE_EXTENSION}", Utc::now().format("%Y%m%d_%H%M%S")))
+        }
+    };
+
+    let database = archive::export_data(conn).await?;
+    let write_path = path.clone();
+    if let Err(e) = run_blocking(move || write_backup(&write_path, &database)).await {
+        // Don't leave a partial backup around, it would look like a valid one
+        tokio::fs::remove_file(&path).await.ok();
+        return Err(e);
+    }
+    info!("Backup {} created", path.display());
+    Ok(path)
+}
+
+fn write_backup(path: &Path, database: &[u8]) -> EmptyResult {
+    let mut writer = GzEncoder::new(BufWriter::new(File::create(path)?), Compression::default());
+    let mut files = vec![write_entry(&mut writer, DATABASE, database)?];
+
+    for (name, file_path) in single_files() {
+        if Path::new(&file_path).is_file() {
+            files.push(write_entry(&mut writer, name, &std::fs::read(&file_path)?)?);
+        }
+    }
+    for (name, folder) in folders() {
+        let mut folder_files = Vec::new();
+        list_files(Path::new(&folder), name, &mut folder_files)?;
+        for (entry_path, file_path) in folder_files {
+            files.push(write_entry(&mut writer, &entry_path, &std::fs::read(file_path)?)?);
+        }
+    }
+
+    let manifest = Manifest {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        server_version: crate::VERSION.map(String::from),
+        created_at: Utc::now().to_rfc3339(),
+        files,
+    };
+    write_entry(&mut writer, MANIFEST, &serde_json::to_vec_pretty(&manifest)?)?;
+
+    // The end of the archive is marked by two empty blocks
+    writer.write_all(&[0; 2 * BLOCK_SIZE])?;
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+// The files of the folder and its subfolders, as (path in the backup, path on the disk), sorted by path
+fn list_files(folder: &Path, name: &str, files: &mut Vec<(String, PathBuf)>) -> EmptyResult {
+    if !folder.is_dir() {
+        return Ok(());
+    }
+    let mut entries: Vec<_> = std::fs::read_dir(folder)?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let entry_name = format!("{name}/{}", entry.file_name().to_string_lossy());
+        let path = entry.path();
+        if path.is_dir() {
+            list_files(&path, &entry_name, files)?;
+        } else if path.is_file() {
+            files.push((entry_name, path));
+        }
+    }
+    Ok(())
+}
+
+/// Restores a backup into this instance, whose database needs to be empty. Returns the number of restored files and
+/// imported rows.
+pub async fn restore_backup(path: &Path, conn: &mut DbConn) -> Result<(usize, i64), Error> {
+    ensure_empty(conn).await?;
+
+    let check_path = path.to_path_buf();
+    let database = run_blocking(move || check_backup(&check_path)).await?;
+    let restore_path = path.to_path_buf();
+    let restored = run_blocking(move || restore_files(&restore_path)).await?;
+    // The files are restored first, the import checks that the ones of the attachments and Sends are there
+    let imported = archive::import_data(&database, false, conn).await?;
+    Ok((restored, imported))
+}
+
+// Checks every file of the backup against the manifest, and returns the data of the database
+fn check_backup(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut found: HashMap<String, ManifestFile> = HashMap::new();
+    let mut manifest = None;
+    let mut database = None;
+    read_entries(path, |entry_path, data| {
+        if entry_path == MANIFEST {
+            manifest = Some(serde_json::from_slice::<Manifest>(&data)?);
+            return Ok(());
+        }
+        if entry_path != DATABASE && destination(&entry_path).is_none() {
+            err!(format!("The file `{entry_path}` of the backup has no path in the current configuration"))
+        }
+        if found.contains_key(&entry_path) {
+            err!(format!("The backup has the file `{entry_path}` more than once"))
+        }
+        found.insert(entry_path.clone(), ManifestFile::new(&entry_path, &data));
+        if entry_path == DATABASE {
+            database = Some(data);
+        }
+        Ok(())
+    })?;
+
+    let Some(manifest) = manifest else {
+        err!("The file isn't a Vaultwarden backup, or it's incomplete, its manifest is missing")
+    };
+    if manifest.format != FORMAT {
+        err!("The file isn't a Vaultwarden backup")
+    }
+    if manifest.version != VERSION {
+        err!(format!("The backup version {} isn't supported, only the version {VERSION} is", manifest.version))
+    }
+    for expected in &manifest.files {
+        match found.remove(&expected.path) {
+            Some(file) if file.size == expected.size && file.sha256 == expected.sha256 => {}
+            Some(_) => err!(format!("The file `{}` of the backup is corrupted", expected.path)),
+            None => err!(format!("The file `{}` is missing from the backup", expected.path)),
+        }
+    }
+    if let Some(path) = found.keys().next() {
+        err!(format!("The file `{path}` of the backup isn't in its manifest"))
+    }
+
+    match database {
+        Some(database) => Ok(database),
+        None => err!("The backup has no database"),
+    }
+}
+
+fn restore_files(path: &Path) -> Result<usize, Error> {
+    let mut restored = 0;
+    read_entries(path, |entry_path, data| {
+        if let Some(destination) = destination(&entry_path) {
+            if let Some(parent) = destination.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&destination, data)?;
+            restored += 1;
+        }
+        Ok(())
+    })?;
+    Ok(restored)
+}
+
+// Where a file of the backup is restored, None for the database, the manifest and the files without a path, like the
+// DKIM key when `DKIM_PRIVATE_KEY` isn't set
+fn destination(entry_path: &str) -> Option<PathBuf> {
+    if let Some((_, file_path)) = single_files().into_iter().find(|(name, _)| *name == entry_path) {
+        return Some(PathBuf::from(file_path));
+    }
+    let (name, relative) = entry_path.split_once('/')?;
+    let (_, folder) = folders().into_iter().find(|(folder_name, _)| *folder_name == name)?;
+    // The files need to stay in their folder
+    let relative = Path::new(relative);
+    if relative.as_os_str().is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    Some(Path::new(&folder).join(relative))
+}
+
+//
+// A minimal tar (ustar) format, with only regular files, which can be listed and extracted with the `tar` command
+//
+
+fn write_entry(writer: &mut impl Write, path: &str, data: &[u8]) -> Result<ManifestFile, Error> {
+    writer.write_all(&tar_header(path, data.len() as u64, Utc::now().timestamp())?)?;
+    writer.write_all(data)?;
+    writer.write_all(&vec![0; padding(data.len())])?;
+    Ok(ManifestFile::new(path, data))
+}
+
+fn padding(size: usize) -> usize {
+    (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE
+}
+
+// Writes the number in octal, right aligned with leading zeros and followed by a NUL
+fn write_octal(field: &mut [u8], value: u64) {
+    let octal = format!("{value:0width$o}", width = field.len() - 1);
+    field[..field.len() - 1].copy_from_slice(octal.as_bytes());
+}
+
+fn tar_header(path: &str, size: u64, mtime: i64) -> Result<[u8; BLOCK_SIZE], Error> {
+    // The paths longer than the name field are split into a prefix and a name at a `/`
+    let (prefix, name) = if path.len() <= 100 {
+        ("", path)
+    } else {
+        match path
+            .match_indices('/')
+            .map(|(i, _)| (&path[..i], &path[i + 1..]))
+            .find(|(p, n)| p.len() <= 155 && n.len() <= 100)
+        {
+            Some(split) => split,
+            None => err!(format!("The path `{path}` is too long for the backup")),
+        }
+    };
+    if size >= 8 << 30 {
+        err!(format!("The file `{path}` is too large for the backup"))
+    }
+
+    let mut header = [0u8; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], 0o600);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size);
+    write_octal(&mut header[136..148], mtime.max(0) as u64);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // The checksum is computed with its own field filled with spaces
+    header[148..156].fill(b' ');
+    let checksum: u64 = header.iter().map(|b| *b as u64).sum();
+    write_octal(&mut header[148..155], checksum);
+    Ok(header)
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let text = std::str::from_utf8(field).ok()?.trim_matches(|c| c == '\0' || c == ' ');
+    u64::from_str_radix(text, 8).ok()
+}
+
+fn parse_text(field: &[u8]) -> Result<&str, Error> {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    match std::str::from_utf8(&field[..end]) {
+        Ok(text) => Ok(text),
+        Err(_) => err!("The backup has an invalid path"),
+    }
+}
+
+// Calls `f` with the path and the data of each file of the backup
+fn read_entries(path: &Path, mut f: impl FnMut(String, Vec<u8>) -> EmptyResult) -> EmptyResult {
+    let mut reader = GzDecoder::new(BufReader::new(File::open(path)?));
+    let mut header = [0u8; BLOCK_SIZE];
+    loop {
+        reader.read_exact(&mut header)?;
+        if header.iter().all(|b| *b == 0) {
+            return Ok(());
+        }
+
+        let expected_checksum = parse_octal(&header[148..156]);
+        header[148..156].fill(b' ');
+        if expected_checksum != Some(header.iter().map(|b| *b as u64).sum()) {
+            err!("The backup is corrupted, a header has an invalid checksum")
+        }
+        if header[156] != b'0' && header[156] != 0 {
+            err!("The backup has an entry which isn't a file")
+        }
+        let (name, prefix) = (parse_text(&header[..100])?, parse_text(&header[345..500])?);
+        let entry_path = if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{prefix}/{name}")
+        };
+        let Some(size) = parse_octal(&header[124..136]) else {
+            err!(format!("The backup has an invalid size for `{entry_path}`"))
+        };
+
+        let mut data = vec![0; size as usize];
+        reader.read_exact(&mut data)?;
+        let mut padding_data = vec![0; padding(data.len())];
+        reader.read_exact(&mut padding_data)?;
+        f(entry_path, data)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tar_header() {
+        let header = tar_header("attachments/1f0e/4b2a", 1000, 1_700_000_000).unwrap();
+        assert_eq!(parse_text(&header[..100]).unwrap(), "attachments/1f0e/4b2a");
+        assert_eq!(parse_octal(&header[124..136]), Some(1000));
+        assert_eq!(&header[257..263], b"ustar\0");
+
+        let long_path = format!("attachments/{}/{}", "a".repeat(80), "b".repeat(40));
+        let header = tar_header(&long_path, 0, 0).unwrap();
+        assert_eq!(
+            format!("{}/{}", parse_text(&header[345..500]).unwrap(), parse_text(&header[..100]).unwrap()),
+            long_path
+        );
+        assert!(tar_header(&"c".repeat(101), 0, 0).is_err());
+        assert_eq!(padding(1000), 24);
+        assert_eq!(padding(1024), 0);
+    }
+}
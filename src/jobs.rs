@@ -0,0 +1,374 @@
+//
+// Scheduled jobs
+//
+// The jobs run on the cron schedules of their `*_SCHEDULE` config. They can be turned off or given another schedule
+// in the admin panel, these settings are saved in the database so all the instances use them, and the scheduler
+// picks them up on its next check. A job isn't started again while its previous run is still running on this instance.
+// Each run is recorded with its duration and the errors logged by the job, and the admin panel can also start a run.
+//
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+
+use chrono::Utc;
+use job_scheduler_ng::{Job, JobScheduler, Schedule};
+use num_traits::ToPrimitive;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+use crate::{
+    api::{self, EmptyResult},
+    backup, data_retention,
+    db::{
+        models::{JobRun, JobSetting},
+        DbConn, DbPool,
+    },
+    db_maintenance, CONFIG,
+};
+
+// The runs kept for each job
+const RUNS_KEPT: i64 = 100;
+// The errors of a run beyond these are only in the logs
+const MAX_RUN_ERRORS: usize = 20;
+
+type JobFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+struct JobDef {
+    name: &'static str,
+    description: &'static str,
+    config_schedule: fn() -> String,
+    // Whether the job is needed with this config, like the purge of the Duo contexts when Duo is used
+    available: fn() -> bool,
+    run: fn(DbPool) -> JobFuture,
+}
+
+// When two jobs are both due at a check, the one listed first starts first
+static JOBS: &[JobDef] = &[
+    JobDef {
+        name: "send_purge",
+        description: "Purge the Sends past their deletion date",
+        config_schedule: || CONFIG.send_purge_schedule(),
+        available: || true,
+        run: |pool| Box::pin(api::purge_sends(pool)),
+    },
+    JobDef {
+        name: "trash_purge",
+        description: "Purge the trashed items old enough to be deleted",
+        config_schedule: || CONFIG.trash_purge_schedule(),
+        available: || true,
+        run: |pool| Box::pin(api::purge_trashed_ciphers(pool)),
+    },
+    JobDef {
+        name: "incomplete_2fa",
+        description: "Notify the users of the incomplete 2FA logins, which may mean their master password leaked",
+        config_schedule: || CONFIG.incomplete_2fa_schedule(),
+        available: || true,
+        run: |pool| Box::pin(api::send_incomplete_2fa_notifications(pool)),
+    },
+    // Before the reminders, so no reminder is sent for the requests about to be granted
+    JobDef {
+        name: "emergency_request_timeout",
+        description: "Grant the emergency access requests past their wait time",
+        config_schedule: || CONFIG.emergency_request_timeout_schedule(),
+        available: || true,
+        run: |pool| Box::pin(api::emergency_request_timeout_job(pool)),
+    },
+    JobDef {
+        name: "emergency_notification_reminder",
+        description: "Remind the grantors of their pending emergency access requests",
+        config_schedule: || CONFIG.emergency_notification_reminder_schedule(),
+        available: || true,
+        run: |pool| Box::pin(api::emergency_notification_reminder_job(pool)),
+    },
+    JobDef {
+        name: "emergency_access_validation",
+        description: "Check the key material of the confirmed emergency access grants is still usable",
+        config_schedule: || CONFIG.emergency_access_validation_schedule(),
+        available: || true,
+        run: |pool| Box::pin(api::emergency_access_validation_job(pool)),
+    },
+    JobDef {
+        name: "auth_request_purge",
+        description: "Purge the expired login with device requests",
+        config_schedule: || CONFIG.auth_request_purge_schedule(),
+        available: || true,
+        run: |pool| Box::pin(api::purge_auth_requests(pool)),
+    },
+    JobDef {
+        name: "duo_context_purge",
+        description: "Purge the unused and expired Duo authentication contexts",
+        config_schedule: || CONFIG.duo_context_purge_schedule(),
+        available: || CONFIG._enable_duo() && !CONFIG.duo_use_iframe(),
+        run: |pool| Box::pin(api::purge_duo_contexts(pool)),
+    },
+    JobDef {
+        name: "two_factor_recovery",
+        description: "Remove the two-step login methods of the delayed recoveries past their cooling-off period",
+        config_schedule: || CONFIG.two_factor_recovery_schedule(),
+        available: || CONFIG.two_factor_recovery_delay_hours() > 0,
+        run: |pool| Box::pin(api::delayed_2fa_recovery_job(pool)),
+    },
+    JobDef {
+        name: "two_factor_policy_grace",
+        description: "Remind the members in their 2FA policy grace period, and revoke them once it has passed",
+        config_schedule: || CONFIG.two_factor_policy_grace_schedule(),
+        available: || true,
+        run: |pool| Box::pin(api::twofactor_policy_grace_job(pool)),
+    },
+    JobDef {
+        name: "directory_sync",
+        description: "Sync the organization members and groups with the directory",
+        config_schedule: || CONFIG.directory_sync_schedule(),
+        available: || CONFIG.directory_sync_provider().is_some(),
+        run: |pool| Box::pin(api::directory_sync_job(pool)),
+    },
+    JobDef {
+        name: "db_maintenance",
+        description: "Vacuum the database and update its statistics",
+        config_schedule: || CONFIG.db_maintenance_schedule(),
+        available: || true,
+        run: |pool| Box::pin(db_maintenance::maintenance_job(pool)),
+    },
+    JobDef {
+        name: "icon_prewarm",
+        description: "Download the icons of the pending domains",
+        config_schedule: || CONFIG.icon_prewarm_schedule(),
+        available: || true,
+        run: |pool| Box::pin(api::icon_prewarm_job(pool)),
+    },
+    JobDef {
+        name: "backup",
+        description: "Create a snapshot of the database",
+        config_schedule: || CONFIG.backup_schedule(),
+        available: || true,
+        run: |pool| Box::pin(backup::backup_job(pool)),
+    },
+    JobDef {
+        name: "data_retention",
+        description: "Delete the events, devices and deleted objects records past their retention",
+        config_schedule: || CONFIG.data_retention_schedule(),
+        available: || true,
+        run: |pool| Box::pin(data_retention::retention_job(pool)),
+    },
+];
+
+fn find_job(name: &str) -> Option<&'static JobDef> {
+    JOBS.iter().find(|job| job.name == name)
+}
+
+// The schedule used with the settings of the admin panel, empty when the job is turned off
+fn effective_schedule(job: &JobDef, setting: Option<&JobSetting>) -> String {
+    match setting {
+        _ if !(job.available)() => String::new(),
+        Some(setting) if !setting.enabled => String::new(),
+        Some(JobSetting {
+            schedule: Some(schedule),
+            ..
+        }) => schedule.clone(),
+        _ => (job.config_schedule)(),
+    }
+}
+
+async fn load_settings(pool: &DbPool) -> Option<HashMap<String, JobSetting>> {
+    let mut conn = pool.get().await.ok()?;
+    Some(JobSetting::get_all(&mut conn).await.into_iter().map(|setting| (setting.name.clone(), setting)).collect())
+}
+
+//
+// Runs
+//
+
+static RUNNING: Lazy<Mutex<HashSet<&'static str>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+struct RunningGuard(&'static str);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().remove(self.0);
+    }
+}
+
+tokio::task_local! {
+    static RUN_ERRORS: Arc<Mutex<Vec<String>>>;
+}
+
+/// Adds the errors logged by a job to its run, called for every error log line.
+pub fn record_log(record: &log::Record<'_>) {
+    if record.level() == log::Level::Error {
+        RUN_ERRORS
+            .try_with(|errors| {
+                let mut errors = errors.lock().unwrap();
+                if errors.len() < MAX_RUN_ERRORS {
+                    errors.push(record.args().to_string());
+                }
+            })
+            .ok();
+    }
+}
+
+async fn run_job(job: &'static JobDef, pool: DbPool, manual: bool) {
+    if !RUNNING.lock().unwrap().insert(job.name) {
+        warn!("The previous run of the {} job is still running, skipping this one", job.name);
+        return;
+    }
+    let _running = RunningGuard(job.name);
+
+    let started_at = Utc::now().naive_utc();
+    let start = Instant::now();
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    // In its own task, so a panic is recorded like the other errors
+    let result = tokio::spawn(RUN_ERRORS.scope(Arc::clone(&errors), (job.run)(pool.clone()))).await;
+    let duration_ms = start.elapsed().as_millis().to_i64().unwrap_or(i64::MAX);
+
+    let mut errors = std::mem::take(&mut *errors.lock().unwrap());
+    if let Err(e) = result {
+        errors.push(format!("The job panicked: {e}"));
+    }
+    let error = (!errors.is_empty()).then(|| errors.join("\n"));
+    let run = JobRun::new(job.name, manual, started_at, duration_ms, error);
+
+    let Ok(mut conn) = pool.get().await else {
+        error!("Failed to get DB connection while saving the run of the {} job", job.name);
+        return;
+    };
+    if let Err(e) = run.insert(&mut conn).await {
+        error!("Error saving the run of the {} job: {e:#?}", job.name);
+    }
+    if let Err(e) = JobRun::delete_all_but_latest(job.name, RUNS_KEPT, &mut conn).await {
+        error!("Error deleting the old runs of the {} job: {e:#?}", job.name);
+    }
+}
+
+/// Starts a run of the job right away, even when it's turned off.
+pub fn trigger(name: &str, pool: DbPool) -> EmptyResult {
+    let Some(job) = find_job(name) else {
+        err!("Unknown job")
+    };
+    if !(job.available)() {
+        err!("This job isn't used with the current config")
+    }
+    if RUNNING.lock().unwrap().contains(job.name) {
+        err!("This job is already running")
+    }
+    info!("Starting the {} job from the admin panel", job.name);
+    tokio::spawn(run_job(job, pool, true));
+    Ok(())
+}
+
+//
+// Admin panel
+//
+
+/// Saves the settings of the job, an empty schedule uses the one of the config.
+pub async fn update_settings(name: &str, enabled: bool, schedule: Option<String>, conn: &mut DbConn) -> EmptyResult {
+    let Some(job) = find_job(name) else {
+        err!("Unknown job")
+    };
+    let schedule = schedule.map(|s| s.trim().to_string()).filter(|s| !s.is_empty());
+    if let Some(schedule) = &schedule {
+        if let Err(e) = schedule.parse::<Schedule>() {
+            err!(format!("Invalid cron schedule: {e}"))
+        }
+    }
+    JobSetting::new(job.name, enabled, schedule).save(conn).await
+}
+
+pub async fn jobs_json(conn: &mut DbConn) -> Value {
+    let settings: HashMap<String, JobSetting> =
+        JobSetting::get_all(conn).await.into_iter().map(|setting| (setting.name.clone(), setting)).collect();
+    let running = RUNNING.lock().unwrap().clone();
+
+    let mut jobs_json = Vec::with_capacity(JOBS.len());
+    for job in JOBS {
+        let setting = settings.get(job.name);
+        let runs: Vec<Value> = JobRun::find_by_job(job.name, 10, conn).await.iter().map(JobRun::to_json).collect();
+        jobs_json.push(json!({
+            "name": job.name,
+            "description": job.description,
+            "available": (job.available)(),
+            "enabled": setting.map_or(true, |setting| setting.enabled),
+            "schedule": setting.and_then(|setting| setting.schedule.clone()),
+            "config_schedule": (job.config_schedule)(),
+            "effective_schedule": effective_schedule(job, setting),
+            "running": running.contains(job.name),
+            "runs": runs,
+        }));
+    }
+    json!({
+        "jobs": jobs_json,
+        "scheduler_enabled": CONFIG.job_poll_interval_ms() > 0,
+        "runs_kept": RUNS_KEPT,
+    })
+}
+
+//
+// Scheduler
+//
+
+pub fn schedule_jobs(pool: DbPool) {
+    if CONFIG.job_poll_interval_ms() == 0 {
+        info!("Job scheduler disabled.");
+        return;
+    }
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    thread::Builder::new()
+        .name("job-scheduler".to_string())
+        .spawn(move || {
+            let _runtime_guard = runtime.enter();
+
+            let mut sched = JobScheduler::new();
+            let mut scheduled: Option<Vec<(&'static str, String)>> = None;
+
+            // Periodically check for jobs to run. We probably won't need any
+            // jobs that run more often than once a minute, so a default poll
+            // interval of 30 seconds should be sufficient. Users who want to
+            // schedule jobs to run more frequently for some reason can reduce
+            // the poll interval accordingly.
+            loop {
+                // The settings may have been changed in the admin panel, of this instance or another one.
+                // When they can't be loaded, the jobs keep their schedule, or use the config at the start.
+                let settings = runtime.block_on(load_settings(&pool));
+                if settings.is_some() || scheduled.is_none() {
+                    let settings = settings.unwrap_or_default();
+                    let schedules: Vec<(&'static JobDef, String)> = JOBS
+                        .iter()
+                        .map(|job| (job, effective_schedule(job, settings.get(job.name))))
+                        .filter(|(_, schedule)| !schedule.is_empty())
+                        .collect();
+                    let names: Vec<(&'static str, String)> =
+                        schedules.iter().map(|(job, schedule)| (job.name, schedule.clone())).collect();
+                    if scheduled.as_ref() != Some(&names) {
+                        if scheduled.is_some() {
+                            info!("The settings of the scheduled jobs changed, rescheduling them");
+                        }
+                        sched = JobScheduler::new();
+                        for (job, schedule) in schedules {
+                            let schedule = match schedule.parse::<Schedule>() {
+                                Ok(schedule) => schedule,
+                                Err(e) => {
+                                    error!("Invalid schedule of the {} job, it won't run: {e}", job.name);
+                                    continue;
+                                }
+                            };
+                            let pool = pool.clone();
+                            sched.add(Job::new(schedule, move || {
+                                tokio::spawn(run_job(job, pool.clone(), false));
+                            }));
+                        }
+                        scheduled = Some(names);
+                    }
+                }
+
+                sched.tick();
+                runtime.block_on(tokio::time::sleep(tokio::time::Duration::from_millis(CONFIG.job_poll_interval_ms())));
+            }
+        })
+        .expect("Error spawning job scheduler thread");
+}
@@ -0,0 +1,861 @@
+//
+// LDAP/Active Directory integration, lets a directory control which accounts are allowed to login
+//
+// With end-to-end encryption the master password never reaches the server, only a hash derived from it,
+// so it can't be used to bind as the user. Instead Vaultwarden binds with a service account, and looks up
+// the user by email address on every password login: accounts which aren't in the directory are refused,
+// and the directory groups are mapped to the roles of the user in the organizations they are a member of.
+// The directory entry is linked to the account on the first login (see `ExternalIdentity`), and a login
+// from another entry is refused until the link is removed by the user or an admin.
+//
+// The members of directory groups can also be listed, for the directory sync (see `api::core::directory_sync`).
+//
+// This is a minimal LDAPv3 client, only supporting the simple bind and the search operations,
+// over plain TCP (ldap://) or TLS (ldaps://). The blocking connections are kept in a small pool.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4511
+//
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    time::Duration,
+};
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use url::Url;
+
+use crate::{
+    api::{core::log_event, EmptyResult},
+    db::{
+        models::{
+            Collection, CollectionUser, DeviceType, Event, EventType, ExternalIdentity, Organization, User,
+            UserOrgStatus, UserOrgType, UserOrganization,
+        },
+        DbConn,
+    },
+    error::Error,
+    mapping_rules::{self, Claims, OrgAssignment},
+    CONFIG,
+};
+
+// LDAP result code of a successful operation
+const LDAP_SUCCESS: i64 = 0;
+
+// BER tags of the elements and protocol operations used here
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_ENUMERATED: u8 = 0x0a;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_BIND_REQUEST: u8 = 0x60;
+const TAG_BIND_RESPONSE: u8 = 0x61;
+const TAG_SEARCH_REQUEST: u8 = 0x63;
+const TAG_SEARCH_RESULT_ENTRY: u8 = 0x64;
+const TAG_SEARCH_RESULT_DONE: u8 = 0x65;
+const TAG_SEARCH_RESULT_REFERENCE: u8 = 0x73;
+const TAG_AUTH_SIMPLE: u8 = 0x80;
+const TAG_FILTER_EQUALITY: u8 = 0xa3;
+
+// Responses larger than this are refused, a single entry is expected to be much smaller
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Returns true if the directory has been configured.
+pub fn enabled() -> bool {
+    CONFIG._enable_ldap() && CONFIG.ldap_url().is_some()
+}
+
+/// The entry of a user in the directory.
+pub struct DirectoryUser {
+    pub dn: String,
+    /// The DNs of the groups of the user, in lowercase
+    pub groups: Vec<String>,
+}
+
+impl DirectoryUser {
+    /// The claims evaluated by the mapping rules: `dn`, `email` and the group attribute.
+    pub fn claims(&self, email: &str) -> Claims {
+        Claims::from([
+            ("dn".to_string(), vec![self.dn.clone()]),
+            ("email".to_string(), vec![email.to_string()]),
+            (CONFIG.ldap_group_attribute().to_lowercase(), self.groups.clone()),
+        ])
+    }
+}
+
+/// Searches the user with the given email address in the directory.
+pub async fn find_user(email: &str) -> Result<Option<DirectoryUser>, Error> {
+    let email = email.to_string();
+    match tokio::task::spawn_blocking(move || with_connection(|conn| conn.find_user(&email))).await {
+        Ok(Ok(user)) => Ok(user),
+        Ok(Err(e)) => err!("Error querying the directory server", e),
+        Err(e) => err!("Error querying the directory server", e.to_string()),
+    }
+}
+
+/// A user entry which is a member of a directory group.
+pub struct GroupMember {
+    pub dn: String,
+    pub email: String,
+}
+
+/// Lists the users under the search base which are members of the given group.
+pub async fn find_group_members(group_dn: &str) -> Result<Vec<GroupMember>, Error> {
+    let group_dn = group_dn.to_string();
+    match tokio::task::spawn_blocking(move || with_connection(|conn| conn.find_group_members(&group_dn))).await {
+        Ok(Ok(members)) => Ok(members),
+        Ok(Err(e)) => err!("Error querying the directory server", e),
+        Err(e) => err!("Error querying the directory server", e.to_string()),
+    }
+}
+
+/// Checks that the user is allowed to login by the directory, and applies the organization roles of their groups.
+pub async fn authorize_login(user: &mut User, ip: &IpAddr, conn: &mut DbConn) -> EmptyResult {
+    let directory_user = match find_user(&user.email).await? {
+        Some(directory_user) => directory_user,
+        None => {
+            if CONFIG.ldap_disable_missing_users() && user.enabled {
+                user.enabled = false;
+                user.save(conn).await?;
+                info!("User {} has been disabled, it isn't in the directory anymore", user.email);
+            }
+            err!(
+                "This user is not allowed to login",
+                format!("IP: {ip}. Username: {} not found in the directory.", user.email),
+                ErrorEvent {
+                    event: EventType::UserFailedLogIn
+                }
+            )
+        }
+    };
+    debug!("User {} found in the directory as {}", user.email, directory_user.dn);
+
+    link_identity(user, &directory_user, ip, conn).await?;
+    apply_group_mapping(user, &directory_user, conn).await?;
+
+    if CONFIG.ldap_mapping_enforce() {
+        let rules = mapping_rules::load_rules()?;
+        let assignments = mapping_rules::evaluate(&rules, &directory_user.claims(&user.email));
+        apply_mapping_rules(user, &assignments, conn).await?;
+    }
+    Ok(())
+}
+
+/// Decides if an unknown email address which is in the directory can register, according to `LDAP_PROVISIONING`.
+/// Returns false when the normal signup rules apply: no provisioning policy, or not in the directory.
+pub async fn provisioning_allowed(email: &str, conn: &mut DbConn) -> Result<bool, Error> {
+    let policy = CONFIG.ldap_provisioning().to_lowercase();
+    if policy.is_empty() || find_user(email).await?.is_none() {
+        return Ok(false);
+    }
+
+    if policy == "reject" {
+        // There is no user nor membership yet, the event is only linked to the provisioning organization
+        if CONFIG.org_events_enabled() {
+            let mut event = Event::new(EventType::OrganizationUserProvisioningRejected as i32, None);
+            event.org_uuid = CONFIG.ldap_provisioning_org();
+            event.device_type = Some(DeviceType::Server as i32);
+            event.ip_address = Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED).to_string());
+            event.save(conn).await.unwrap_or(());
+        }
+        info!("Registration of {email} rejected by the LDAP provisioning policy");
+        err!("Registration not allowed or user already exists")
+    }
+    Ok(true)
+}
+
+/// Provisions a user of the directory once they have verified their email address: joins the provisioning
+/// organization with the default role and collections. Nothing is done for the users who aren't in the directory,
+/// or already joined it. The email address links the account to the directory entry, it's only trusted once verified.
+/// With `approval`, the account was disabled on registration until an admin approves it.
+pub async fn provision_user(user: &User, conn: &mut DbConn) -> EmptyResult {
+    let policy = CONFIG.ldap_provisioning().to_lowercase();
+    if policy != "member" && policy != "approval" {
+        return Ok(());
+    }
+    let approval = policy == "approval";
+    let org_uuid = match CONFIG.ldap_provisioning_org() {
+        Some(org_uuid) => org_uuid,
+        None => err!("`LDAP_PROVISIONING_ORG` is not set"),
+    };
+    if Organization::find_by_uuid(&org_uuid, conn).await.is_none() {
+        err!("The LDAP provisioning organization doesn't exist")
+    }
+    if UserOrganization::find_by_user_and_org(&user.uuid, &org_uuid, conn).await.is_some()
+        || find_user(&user.email).await?.is_none()
+    {
+        return Ok(());
+    }
+
+    // The membership still has to be confirmed by an admin, the organization key can't be shared by the server
+    let mut member = UserOrganization::new(user.uuid.clone(), org_uuid.clone());
+    member.access_all = false;
+    member.atype = parse_role(&CONFIG.ldap_provisioning_role()).unwrap_or(UserOrgType::User) as i32;
+    member.status = UserOrgStatus::Accepted as i32;
+    member.save(conn).await?;
+
+    for collection_uuid in CONFIG.ldap_provisioning_collections().unwrap_or_default().split(',').map(str::trim) {
+        if collection_uuid.is_empty() {
+            continue;
+        }
+        if Collection::find_by_uuid_and_org(collection_uuid, &org_uuid, conn).await.is_none() {
+            warn!("Collection {collection_uuid} of the LDAP provisioning not found in organization {org_uuid}");
+            continue;
+        }
+        CollectionUser::save(&user.uuid, collection_uuid, false, false, conn).await?;
+    }
+
+    let event_type = if approval {
+        info!("User {} provisioned from the directory, pending the approval of an admin", user.email);
+        EventType::OrganizationUserProvisionedPendingApproval
+    } else {
+        info!("User {} provisioned from the directory", user.email);
+        EventType::OrganizationUserProvisioned
+    };
+    log_event(
+        event_type as i32,
+        &member.uuid,
+        &org_uuid,
+        &user.uuid,
+        DeviceType::Server as i32,
+        &IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        conn,
+    )
+    .await;
+
+    Ok(())
+}
+
+/// Parses the name of an organization role: owner, admin, manager or user.
+pub fn parse_role(role: &str) -> Option<UserOrgType> {
+    match role.trim().to_lowercase().as_str() {
+        "owner" => Some(UserOrgType::Owner),
+        "admin" => Some(UserOrgType::Admin),
+        "manager" => Some(UserOrgType::Manager),
+        "user" => Some(UserOrgType::User),
+        _ => None,
+    }
+}
+
+pub struct GroupMapping {
+    group_dn: String,
+    org_uuid: String,
+    atype: UserOrgType,
+}
+
+/// Parses the `LDAP_GROUP_MAPPING` setting, a semicolon separated list of `group DN:organization id:role`.
+pub fn parse_group_mapping(mapping: &str) -> Result<Vec<GroupMapping>, String> {
+    mapping
+        .split(';')
+        .map(str::trim)
+        .filter(|m| !m.is_empty())
+        .map(|m| {
+            let mut parts = m.rsplitn(3, ':');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(role), Some(org_uuid), Some(group_dn)) => {
+                    let atype = match parse_role(role) {
+                        Some(atype) => atype,
+                        None => return Err(format!("Invalid role `{role}` in `{m}`")),
+                    };
+                    Ok(GroupMapping {
+                        group_dn: group_dn.trim().to_lowercase(),
+                        org_uuid: org_uuid.trim().to_string(),
+                        atype,
+                    })
+                }
+                _ => Err(format!("Invalid group mapping `{m}`, expected `group DN:organization id:role`")),
+            }
+        })
+        .collect()
+}
+
+// The role of the user in the mapped organizations is the highest one of their groups.
+// Members of a mapped organization who aren't in any of its groups keep their current role.
+// Links the directory entry to the user on the first login, and refuses the login when the links don't match anymore:
+// the entry is linked to another user, or the email address now belongs to another entry (it may have been recycled).
+// The links can be reviewed and removed by the user or in the admin panel, to allow linking another entry.
+async fn link_identity(user: &User, directory_user: &DirectoryUser, ip: &IpAddr, conn: &mut DbConn) -> EmptyResult {
+    let issuer = CONFIG.ldap_url();
+    let subject = directory_user.dn.to_lowercase();
+
+    let mut identity = match ExternalIdentity::find_by_issuer_and_subject(&issuer, &subject, conn).await {
+        Some(identity) if identity.user_uuid != user.uuid => err!(
+            "This user is not allowed to login",
+            format!("IP: {ip}. Username: {}. {} is linked to another user.", user.email, directory_user.dn),
+            ErrorEvent {
+                event: EventType::UserFailedLogIn
+            }
+        ),
+        Some(identity) => identity,
+        None => {
+            if !ExternalIdentity::find_by_user_and_issuer(&user.uuid, &issuer, conn).await.is_empty() {
+                err!(
+                    "This user is not allowed to login",
+                    format!(
+                        "IP: {ip}. Username: {} is linked to another directory entry than {}.",
+                        user.email, directory_user.dn
+                    ),
+                    ErrorEvent {
+                        event: EventType::UserFailedLogIn
+                    }
+                )
+            }
+            info!("Linking user {} to the directory entry {}", user.email, directory_user.dn);
+            ExternalIdentity::new(user.uuid.clone(), issuer, subject, user.email.clone())
+        }
+    };
+
+    identity.last_used_date = Utc::now().naive_utc();
+    identity.save(conn).await
+}
+
+async fn apply_group_mapping(user: &User, directory_user: &DirectoryUser, conn: &mut DbConn) -> EmptyResult {
+    let mappings = match CONFIG.ldap_group_mapping() {
+        Some(mapping) => match parse_group_mapping(&mapping) {
+            Ok(mappings) => mappings,
+            Err(e) => err!("Invalid LDAP group mapping", e),
+        },
+        None => return Ok(()),
+    };
+
+    for mut member in UserOrganization::find_by_user(&user.uuid, conn).await {
+        if member.status != UserOrgStatus::Confirmed as i32 {
+            continue;
+        }
+
+        let atype = mappings
+            .iter()
+            .filter(|m| m.org_uuid == member.org_uuid && directory_user.groups.contains(&m.group_dn))
+            .map(|m| m.atype)
+            .max();
+        let atype = match atype {
+            Some(atype) if atype as i32 != member.atype => atype,
+            _ => continue,
+        };
+
+        // Never remove the last owner of an organization
+        if member.atype == UserOrgType::Owner
+            && UserOrganization::count_confirmed_by_org_and_type(&member.org_uuid, UserOrgType::Owner, conn).await <= 1
+        {
+            warn!("Not changing the role of {}, the last owner of organization {}", user.email, member.org_uuid);
+            continue;
+        }
+
+        member.atype = atype as i32;
+        member.save(conn).await?;
+
+        log_event(
+            EventType::OrganizationUserUpdated as i32,
+            &member.uuid,
+            &member.org_uuid,
+            &user.uuid,
+            DeviceType::Server as i32,
+            &IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            conn,
+        )
+        .await;
+    }
+
+    Ok(())
+}
+
+// Applies the assignments of the mapping rules: joins the organizations, raises the roles and grants the collections
+async fn apply_mapping_rules(user: &User, assignments: &[OrgAssignment], conn: &mut DbConn) -> EmptyResult {
+    let ip = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+    for assignment in assignments {
+        if Organization::find_by_uuid(&assignment.org_uuid, conn).await.is_none() {
+            warn!("Organization {} of the mapping rules not found", assignment.org_uuid);
+            continue;
+        }
+
+        let mut member = match UserOrganization::find_by_user_and_org(&user.uuid, &assignment.org_uuid, conn).await {
+            Some(member) => member,
+            None => {
+                // The membership still has to be confirmed by an admin, the organization key can't be shared by the server
+                let mut member = UserOrganization::new(user.uuid.clone(), assignment.org_uuid.clone());
+                member.access_all = false;
+                member.atype = assignment.atype as i32;
+                member.status = UserOrgStatus::Accepted as i32;
+                member.save(conn).await?;
+
+                log_event(
+                    EventType::OrganizationUserInvited as i32,
+                    &member.uuid,
+                    &member.org_uuid,
+                    &user.uuid,
+                    DeviceType::Server as i32,
+                    &ip,
+                    conn,
+                )
+                .await;
+                member
+            }
+        };
+
+        // Revoked members stay revoked, that is the decision of an admin
+        if member.status == UserOrgStatus::Revoked as i32 {
+            continue;
+        }
+
+        if member.atype != assignment.atype as i32 {
+            // Never remove the last owner of an organization
+            if member.atype == UserOrgType::Owner
+                && UserOrganization::count_confirmed_by_org_and_type(&member.org_uuid, UserOrgType::Owner, conn).await
+                    <= 1
+            {
+                warn!("Not changing the role of {}, the last owner of organization {}", user.email, member.org_uuid);
+            } else {
+                member.atype = assignment.atype as i32;
+                member.save(conn).await?;
+
+                log_event(
+                    EventType::OrganizationUserUpdated as i32,
+                    &member.uuid,
+                    &member.org_uuid,
+                    &user.uuid,
+                    DeviceType::Server as i32,
+                    &ip,
+                    conn,
+                )
+                .await;
+            }
+        }
+
+        for (collection_uuid, (read_only, hide_passwords)) in &assignment.collections {
+            if Collection::find_by_uuid_and_org(collection_uuid, &member.org_uuid, conn).await.is_none() {
+                warn!(
+                    "Collection {collection_uuid} of the mapping rules not found in organization {}",
+                    member.org_uuid
+                );
+                continue;
+            }
+            if CollectionUser::find_by_collection_and_user(collection_uuid, &user.uuid, conn).await.is_none() {
+                CollectionUser::save(&user.uuid, collection_uuid, *read_only, *hide_passwords, conn).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//
+// Connections
+//
+
+trait Transport: Read + Write + Send {}
+impl<T: Read + Write + Send> Transport for T {}
+
+struct LdapConn {
+    stream: Box<dyn Transport>,
+    message_id: i32,
+}
+
+static POOL: Lazy<Mutex<Vec<LdapConn>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// Runs the operation on a pooled connection, falling back to a new one if the pooled connection has been closed by the server.
+fn with_connection<T>(op: impl Fn(&mut LdapConn) -> Result<T, String>) -> Result<T, String> {
+    let pooled = POOL.lock().unwrap().pop();
+    let (mut conn, result) = match pooled {
+        Some(mut conn) => match op(&mut conn) {
+            Ok(result) => (conn, result),
+            Err(e) => {
+                debug!("Pooled LDAP connection failed, reconnecting: {e}");
+                let mut conn = LdapConn::connect()?;
+                let result = op(&mut conn)?;
+                (conn, result)
+            }
+        },
+        None => {
+            let mut conn = LdapConn::connect()?;
+            let result = op(&mut conn)?;
+            (conn, result)
+        }
+    };
+
+    let mut pool = POOL.lock().unwrap();
+    if pool.len() < CONFIG.ldap_pool_size() as usize {
+        pool.push(conn);
+    }
+    Ok(result)
+}
+
+impl LdapConn {
+    /// Connects to the directory server, and binds with the service account.
+    fn connect() -> Result<Self, String> {
+        let url = CONFIG.ldap_url().ok_or("`LDAP_URL` is not set")?;
+        let url = Url::parse(&url).map_err(|e| format!("Invalid `LDAP_URL`: {e}"))?;
+        let host = url.host_str().ok_or("`LDAP_URL` has no host")?.to_string();
+        let tls = match url.scheme() {
+            "ldap" => false,
+            "ldaps" => true,
+            scheme => return Err(format!("Unsupported `LDAP_URL` scheme {scheme}")),
+        };
+        let port = url.port().unwrap_or(if tls {
+            636
+        } else {
+            389
+        });
+
+        let timeout = Duration::from_secs(CONFIG.ldap_timeout());
+        let addr = (host.as_str(), port)
+            .to_socket_addrs()
+            .map_err(|e| format!("Error resolving {host}: {e}"))?
+            .next()
+            .ok_or_else(|| format!("No address found for {host}"))?;
+        let tcp = TcpStream::connect_timeout(&addr, timeout).map_err(|e| format!("Error connecting to {addr}: {e}"))?;
+        tcp.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+        tcp.set_write_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+
+        let stream: Box<dyn Transport> = if tls {
+            let mut builder = SslConnector::builder(SslMethod::tls_client()).map_err(|e| e.to_string())?;
+            if CONFIG.ldap_accept_invalid_certs() {
+                builder.set_verify(SslVerifyMode::NONE);
+            }
+            let stream = builder.build().connect(&host, tcp).map_err(|e| format!("TLS error: {e}"))?;
+            Box::new(stream)
+        } else {
+            Box::new(tcp)
+        };
+
+        let mut conn = Self {
+            stream,
+            message_id: 0,
+        };
+        let bind_dn = CONFIG.ldap_bind_dn().unwrap_or_default();
+        let bind_password = CONFIG.ldap_bind_password().unwrap_or_default();
+        conn.simple_bind(&bind_dn, &bind_password)?;
+        Ok(conn)
+    }
+
+    fn send(&mut self, op: Vec<u8>) -> Result<i32, String> {
+        self.message_id += 1;
+        let message = ber(TAG_SEQUENCE, &[ber_int(TAG_INTEGER, self.message_id), op].concat());
+        self.stream.write_all(&message).map_err(|e| format!("Error sending LDAP request: {e}"))?;
+        Ok(self.message_id)
+    }
+
+    /// Reads the next message, returning the tag and content of its protocol operation.
+    fn receive(&mut self, message_id: i32) -> Result<(u8, Vec<u8>), String> {
+        let mut header = [0u8; 2];
+        self.stream.read_exact(&mut header).map_err(|e| format!("Error reading LDAP response: {e}"))?;
+        if header[0] != TAG_SEQUENCE {
+            return Err("Invalid LDAP response".into());
+        }
+
+        let length = if header[1] & 0x80 == 0 {
+            header[1] as usize
+        } else {
+            let count = (header[1] & 0x7f) as usize;
+            if count == 0 || count > 4 {
+                return Err("Invalid LDAP response length".into());
+            }
+            let mut bytes = [0u8; 4];
+            self.stream.read_exact(&mut bytes[4 - count..]).map_err(|e| format!("Error reading LDAP response: {e}"))?;
+            u32::from_be_bytes(bytes) as usize
+        };
+        if length > MAX_MESSAGE_SIZE {
+            return Err("LDAP response too large".into());
+        }
+
+        let mut content = vec![0u8; length];
+        self.stream.read_exact(&mut content).map_err(|e| format!("Error reading LDAP response: {e}"))?;
+
+        let elements = parse_elements(&content)?;
+        match elements.as_slice() {
+            [id, op, ..] if id.tag == TAG_INTEGER && parse_int(id.content) == message_id as i64 => {
+                Ok((op.tag, op.content.to_vec()))
+            }
+            _ => Err("Unexpected LDAP response".into()),
+        }
+    }
+
+    fn simple_bind(&mut self, dn: &str, password: &str) -> Result<(), String> {
+        let op = ber(
+            TAG_BIND_REQUEST,
+            &[ber_int(TAG_INTEGER, 3), ber(TAG_OCTET_STRING, dn.as_bytes()), ber(TAG_AUTH_SIMPLE, password.as_bytes())]
+                .concat(),
+        );
+        let message_id = self.send(op)?;
+
+        match self.receive(message_id)? {
+            (TAG_BIND_RESPONSE, content) => check_result(&content).map_err(|e| format!("LDAP bind failed: {e}")),
+            _ => Err("Unexpected LDAP bind response".into()),
+        }
+    }
+
+    fn find_user(&mut self, email: &str) -> Result<Option<DirectoryUser>, String> {
+        let mail_attribute = CONFIG.ldap_mail_attribute();
+        let group_attribute = CONFIG.ldap_group_attribute();
+
+        // More than one entry is an error
+        let mut entries = self.search(&mail_attribute, email, &group_attribute, 2)?;
+        if entries.len() > 1 {
+            return Err(format!("Multiple directory entries found for {email}"));
+        }
+
+        Ok(entries.pop().map(|entry| DirectoryUser {
+            groups: entry.values.iter().map(|group| group.to_lowercase()).collect(),
+            dn: entry.dn,
+        }))
+    }
+
+    fn find_group_members(&mut self, group_dn: &str) -> Result<Vec<GroupMember>, String> {
+        let mail_attribute = CONFIG.ldap_mail_attribute();
+        let group_attribute = CONFIG.ldap_group_attribute();
+
+        let mut members = Vec::new();
+        for entry in self.search(&group_attribute, group_dn, &mail_attribute, 0)? {
+            // Entries without an email address can't be matched to an account
+            if let Some(email) = entry.values.into_iter().next() {
+                members.push(GroupMember {
+                    dn: entry.dn,
+                    email,
+                });
+            }
+        }
+        Ok(members)
+    }
+
+    // Searches the entries under the search base whose `attribute` equals `value`, returning the values of `wanted`
+    fn search(&mut self, attribute: &str, value: &str, wanted: &str, size_limit: i32) -> Result<Vec<Entry>, String> {
+        let base = CONFIG.ldap_search_base().unwrap_or_default();
+
+        let filter = ber(
+            TAG_FILTER_EQUALITY,
+            &[ber(TAG_OCTET_STRING, attribute.as_bytes()), ber(TAG_OCTET_STRING, value.as_bytes())].concat(),
+        );
+        let attributes = ber(TAG_SEQUENCE, &ber(TAG_OCTET_STRING, wanted.as_bytes()));
+        let op = ber(
+            TAG_SEARCH_REQUEST,
+            &[
+                ber(TAG_OCTET_STRING, base.as_bytes()),
+                ber_int(TAG_ENUMERATED, 2), // wholeSubtree
+                ber_int(TAG_ENUMERATED, 0), // neverDerefAliases
+                ber_int(TAG_INTEGER, size_limit),
+                ber_int(TAG_INTEGER, CONFIG.ldap_timeout() as i32),
+                ber(TAG_BOOLEAN, &[0x00]),
+                filter,
+                attributes,
+            ]
+            .concat(),
+        );
+        let message_id = self.send(op)?;
+
+        let mut entries = Vec::new();
+        loop {
+            match self.receive(message_id)? {
+                (TAG_SEARCH_RESULT_ENTRY, content) => entries.push(parse_entry(&content, wanted)?),
+                (TAG_SEARCH_RESULT_REFERENCE, _) => (),
+                (TAG_SEARCH_RESULT_DONE, content) => {
+                    check_result(&content)?;
+                    break;
+                }
+                _ => return Err("Unexpected LDAP search response".into()),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+//
+// BER encoding
+//
+
+fn ber(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    if content.len() < 0x80 {
+        out.push(content.len() as u8);
+    } else {
+        let length = (content.len() as u32).to_be_bytes();
+        let skip = length.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (length.len() - skip) as u8);
+        out.extend_from_slice(&length[skip..]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+// Only used for non-negative values
+fn ber_int(tag: u8, value: i32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let mut skip = 0;
+    while skip < 3 && bytes[skip] == 0 && bytes[skip + 1] & 0x80 == 0 {
+        skip += 1;
+    }
+    ber(tag, &bytes[skip..])
+}
+
+struct Element<'a> {
+    tag: u8,
+    content: &'a [u8],
+}
+
+fn parse_elements(mut data: &[u8]) -> Result<Vec<Element<'_>>, String> {
+    let mut elements = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 2 {
+            return Err("Truncated BER element".into());
+        }
+        let tag = data[0];
+        let (length, header) = if data[1] & 0x80 == 0 {
+            (data[1] as usize, 2)
+        } else {
+            let count = (data[1] & 0x7f) as usize;
+            if count == 0 || count > 4 || data.len() < 2 + count {
+                return Err("Invalid BER length".into());
+            }
+            (data[2..2 + count].iter().fold(0usize, |acc, b| (acc << 8) | *b as usize), 2 + count)
+        };
+        if data.len() < header + length {
+            return Err("Truncated BER element".into());
+        }
+        elements.push(Element {
+            tag,
+            content: &data[header..header + length],
+        });
+        data = &data[header + length..];
+    }
+    Ok(elements)
+}
+
+fn parse_int(content: &[u8]) -> i64 {
+    content.iter().fold(
+        if content.first().is_some_and(|b| b & 0x80 != 0) {
+            -1
+        } else {
+            0
+        },
+        |acc, b| (acc << 8) | *b as i64,
+    )
+}
+
+// LDAPResult ::= SEQUENCE { resultCode ENUMERATED, matchedDN LDAPDN, diagnosticMessage LDAPString, ... }
+fn check_result(content: &[u8]) -> Result<(), String> {
+    let elements = parse_elements(content)?;
+    match elements.as_slice() {
+        [code, _, message, ..] if code.tag == TAG_ENUMERATED => match parse_int(code.content) {
+            LDAP_SUCCESS => Ok(()),
+            code => Err(format!("LDAP error {code}: {}", String::from_utf8_lossy(message.content))),
+        },
+        _ => Err("Invalid LDAP result".into()),
+    }
+}
+
+struct Entry {
+    dn: String,
+    values: Vec<String>,
+}
+
+// SearchResultEntry ::= SEQUENCE { objectName LDAPDN, attributes SEQUENCE OF SEQUENCE { type, vals SET OF value } }
+fn parse_entry(content: &[u8], wanted: &str) -> Result<Entry, String> {
+    let elements = parse_elements(content)?;
+    let (dn, attributes) = match elements.as_slice() {
+        [dn, attributes, ..] => (String::from_utf8_lossy(dn.content).to_string(), parse_elements(attributes.content)?),
+        _ => return Err("Invalid LDAP search entry".into()),
+    };
+
+    let mut values = Vec::new();
+    for attribute in attributes {
+        if let [name, vals, ..] = parse_elements(attribute.content)?.as_slice() {
+            if String::from_utf8_lossy(name.content).eq_ignore_ascii_case(wanted) {
+                for value in parse_elements(vals.content)? {
+                    values.push(String::from_utf8_lossy(value.content).to_string());
+                }
+            }
+        }
+    }
+
+    Ok(Entry {
+        dn,
+        values,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ber_length() {
+        assert_eq!(ber(TAG_OCTET_STRING, b"abc"), [0x04, 0x03, b'a', b'b', b'c']);
+        assert_eq!(ber(TAG_OCTET_STRING, &[0; 0x7f])[..2], [0x04, 0x7f]);
+        assert_eq!(ber(TAG_OCTET_STRING, &[0; 0x80])[..3], [0x04, 0x81, 0x80]);
+        assert_eq!(ber(TAG_OCTET_STRING, &[0; 300])[..4], [0x04, 0x82, 0x01, 0x2c]);
+    }
+
+    #[test]
+    fn test_ber_int() {
+        assert_eq!(ber_int(TAG_INTEGER, 0), [0x02, 0x01, 0x00]);
+        assert_eq!(ber_int(TAG_INTEGER, 127), [0x02, 0x01, 0x7f]);
+        // The high bit would make it negative
+        assert_eq!(ber_int(TAG_INTEGER, 128), [0x02, 0x02, 0x00, 0x80]);
+        assert_eq!(ber_int(TAG_INTEGER, 256), [0x02, 0x02, 0x01, 0x00]);
+        assert_eq!(ber_int(TAG_ENUMERATED, i32::MAX), [0x0a, 0x04, 0x7f, 0xff, 0xff, 0xff]);
+
+        for value in [0, 1, 127, 128, 255, 256, 65_535, i32::MAX] {
+            let encoded = ber_int(TAG_INTEGER, value);
+            let elements = parse_elements(&encoded).unwrap();
+            assert_eq!(parse_int(elements[0].content), value as i64);
+        }
+        assert_eq!(parse_int(&[0xff]), -1);
+        assert_eq!(parse_int(&[0x80]), -128);
+    }
+
+    #[test]
+    fn test_parse_elements() {
+        let data = [ber(TAG_OCTET_STRING, &[7; 300]), ber_int(TAG_INTEGER, 5), ber(TAG_SEQUENCE, &[])].concat();
+        let elements = parse_elements(&data).unwrap();
+        assert_eq!(elements.iter().map(|e| e.tag).collect::<Vec<_>>(), [TAG_OCTET_STRING, TAG_INTEGER, TAG_SEQUENCE]);
+        assert_eq!(elements[0].content, [7; 300]);
+        assert_eq!(elements[1].content, [5]);
+        assert!(elements[2].content.is_empty());
+
+        assert!(parse_elements(&[0x04]).is_err());
+        assert!(parse_elements(&[0x04, 0x05, b'a']).is_err());
+        // Indefinite and too long lengths aren't supported
+        assert!(parse_elements(&[0x04, 0x80]).is_err());
+        assert!(parse_elements(&[0x04, 0x85, 0x01, 0x00, 0x00, 0x00, 0x00]).is_err());
+        assert!(parse_elements(&[0x04, 0x82, 0x01]).is_err());
+    }
+
+    #[test]
+    fn test_check_result() {
+        let success = [ber_int(TAG_ENUMERATED, 0), ber(TAG_OCTET_STRING, b""), ber(TAG_OCTET_STRING, b"")].concat();
+        assert!(check_result(&success).is_ok());
+
+        let failure =
+            [ber_int(TAG_ENUMERATED, 49), ber(TAG_OCTET_STRING, b""), ber(TAG_OCTET_STRING, b"invalid credentials")]
+                .concat();
+        assert_eq!(check_result(&failure).unwrap_err(), "LDAP error 49: invalid credentials");
+
+        assert!(check_result(&ber_int(TAG_INTEGER, 0)).is_err());
+    }
+
+    #[test]
+    fn test_parse_entry() {
+        let attribute = |name: &str, values: &[&str]| {
+            let values: Vec<u8> = values.iter().flat_map(|v| ber(TAG_OCTET_STRING, v.as_bytes())).collect();
+            ber(TAG_SEQUENCE, &[ber(TAG_OCTET_STRING, name.as_bytes()), ber(0x31, &values)].concat())
+        };
+        let content = [
+            ber(TAG_OCTET_STRING, b"uid=alice,dc=example,dc=org"),
+            ber(
+                TAG_SEQUENCE,
+                &[
+                    attribute("memberOf", &["cn=admins,dc=example,dc=org", "cn=dev,dc=example,dc=org"]),
+                    attribute("mail", &["alice@example.org"]),
+                ]
+                .concat(),
+            ),
+        ]
+        .concat();
+
+        let entry = parse_entry(&content, "memberof").unwrap();
+        assert_eq!(entry.dn, "uid=alice,dc=example,dc=org");
+        assert_eq!(entry.values, ["cn=admins,dc=example,dc=org", "cn=dev,dc=example,dc=org"]);
+        assert!(parse_entry(&content, "cn").unwrap().values.is_empty());
+        assert!(parse_entry(&ber(TAG_OCTET_STRING, b"uid=alice"), "mail").is_err());
+    }
+}
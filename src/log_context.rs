@@ -0,0 +1,317 @@
+//
+// The context of the requests in the logs
+//
+// The handlers of the routes run with the context of their request, the fields of the JSON log lines are taken from
+// it. The log lines outside of a request, like the ones of the scheduled jobs, have these fields set to null.
+// Each request has an ID, the one of its `X-Request-Id` header or a new one, which is sent back in the same header.
+// The tasks spawned with `spawn` keep the context of the request which spawned them.
+// The context also adds up the time spent in the guards, the database queries and building the response, to log the
+// requests slower than `SLOW_REQUEST_THRESHOLD_MS` with where their time went.
+//
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{SecondsFormat, Utc};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    request::FromRequest,
+    route::{self, Handler},
+    Data, Request, Response, Route,
+};
+use tokio::task::JoinHandle;
+
+use crate::{auth::ClientIp, crypto::sha256_hex, util::get_uuid, CONFIG};
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+#[derive(Clone, Default)]
+pub struct LogContext {
+    pub request_id: Option<String>,
+    pub user_id_hash: Option<String>,
+    pub ip: Option<String>,
+    pub route: Option<String>,
+    pub started: Option<Instant>,
+    pub timings: Timings,
+}
+
+/// The time spent in the phases of a request. They overlap, the guards and the responses also run queries.
+#[derive(Clone, Copy, Default)]
+pub struct Timings {
+    pub guards: Duration,
+    pub db: Duration,
+    pub db_queries: u32,
+    pub serialization: Duration,
+    // The nested timers of a phase, like a guard using another guard, are only counted once
+    guards_depth: u32,
+    db_depth: u32,
+    serialization_depth: u32,
+}
+
+impl Timings {
+    fn depth_mut(&mut self, phase: Phase) -> &mut u32 {
+        match phase {
+            Phase::Guards => &mut self.guards_depth,
+            Phase::Db => &mut self.db_depth,
+            Phase::Serialization => &mut self.serialization_depth,
+        }
+    }
+
+    fn add(&mut self, phase: Phase, elapsed: Duration) {
+        match phase {
+            Phase::Guards => self.guards += elapsed,
+            Phase::Db => {
+                self.db += elapsed;
+                self.db_queries += 1;
+            }
+            Phase::Serialization => self.serialization += elapsed,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Phase {
+    Guards,
+    Db,
+    /// Building the response
+    Serialization,
+}
+
+/// Adds the time until it's dropped to a phase of the current request, nothing outside of a request.
+pub struct PhaseTimer {
+    phase: Phase,
+    start: Instant,
+    outermost: bool,
+}
+
+impl PhaseTimer {
+    pub fn start(phase: Phase) -> Self {
+        let outermost = CONTEXT
+            .try_with(|context| {
+                let mut context = context.lock().unwrap();
+                let depth = context.timings.depth_mut(phase);
+                *depth += 1;
+                *depth == 1
+            })
+            .unwrap_or(false);
+        Self {
+            phase,
+            start: Instant::now(),
+            outermost,
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+impl Drop for PhaseTimer {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        CONTEXT
+            .try_with(|context| {
+                let timings = &mut context.lock().unwrap().timings;
+                let depth = timings.depth_mut(self.phase);
+                *depth = depth.saturating_sub(1);
+                if self.outermost {
+                    timings.add(self.phase, elapsed);
+                }
+            })
+            .ok();
+    }
+}
+
+pub type SharedLogContext = Arc<Mutex<LogContext>>;
+
+tokio::task_local! {
+    static CONTEXT: SharedLogContext;
+}
+
+struct RequestLogContext(SharedLogContext);
+
+/// The context of the request, shared by its handler and the fairings.
+pub async fn request_context(request: &Request<'_>) -> SharedLogContext {
+    let context = request
+        .local_cache_async(async {
+            let ip = ClientIp::from_request(request).await.succeeded().map(|client_ip| client_ip.ip.to_string());
+            let request_id = match request.headers().get_one(REQUEST_ID_HEADER) {
+                Some(id) if is_valid_request_id(id) => id.to_string(),
+                _ => get_uuid(),
+            };
+            RequestLogContext(Arc::new(Mutex::new(LogContext {
+                request_id: Some(request_id),
+                ip,
+                started: Some(Instant::now()),
+                ..Default::default()
+            })))
+        })
+        .await;
+    Arc::clone(&context.0)
+}
+
+/// Adds the hash of the user to the context, the user IDs themselves aren't logged.
+pub async fn set_user(request: &Request<'_>, user_uuid: &str) {
+    let context = request_context(request).await;
+    context.lock().unwrap().user_id_hash = Some(sha256_hex(user_uuid.as_bytes())[..16].to_string());
+}
+
+/// Logs with the context of a request outside of its handler, like in the fairings.
+pub fn in_context<R>(context: SharedLogContext, f: impl FnOnce() -> R) -> R {
+    CONTEXT.sync_scope(context, f)
+}
+
+fn current() -> LogContext {
+    CONTEXT.try_with(|context| context.lock().unwrap().clone()).unwrap_or_default()
+}
+
+/// The ID of the current request, None outside of a request.
+pub fn current_request_id() -> Option<String> {
+    CONTEXT.try_with(|context| context.lock().unwrap().request_id.clone()).ok().flatten()
+}
+
+/// Spawns a task which keeps the context of the current request in its logs.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match CONTEXT.try_with(Arc::clone) {
+        Ok(context) => tokio::spawn(CONTEXT.scope(context, future)),
+        Err(_) => tokio::spawn(future),
+    }
+}
+
+// The IDs of the clients and the proxies are accepted when they can't mess up the logs
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+/// Gives an ID to each request, before the other fairings log it, and sends it back in the response.
+pub struct RequestId;
+
+#[rocket::async_trait]
+impl Fairing for RequestId {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ID",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        request_context(request).await;
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let request_id = request_context(request).await.lock().unwrap().request_id.clone();
+        if let Some(request_id) = request_id {
+            response.set_raw_header(REQUEST_ID_HEADER, request_id);
+        }
+    }
+}
+
+/// Logs the requests slower than `SLOW_REQUEST_THRESHOLD_MS`, with the time spent in each phase.
+pub struct SlowRequestLog;
+
+#[rocket::async_trait]
+impl Fairing for SlowRequestLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Slow Request Log",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let threshold = CONFIG.slow_request_threshold_ms();
+        if threshold == 0 {
+            return;
+        }
+        let context = request_context(request).await;
+        let (started, timings) = {
+            let context = context.lock().unwrap();
+            (context.started, context.timings)
+        };
+        let Some(total) = started.map(|started| started.elapsed()) else {
+            return;
+        };
+        if total < Duration::from_millis(threshold) {
+            return;
+        }
+
+        // The route template, the IDs of the path aren't logged
+        let route = match request.route() {
+            Some(route) => route.uri.to_string(),
+            None => String::from("(no route)"),
+        };
+        in_context(context, || {
+            warn!(
+                "Slow request {} {route} ({}): {} ms, guards {} ms, database {} ms in {} queries, response {} ms",
+                request.method(),
+                response.status().code,
+                total.as_millis(),
+                timings.guards.as_millis(),
+                timings.db.as_millis(),
+                timings.db_queries,
+                timings.serialization.as_millis()
+            );
+        });
+    }
+}
+
+// Runs the handler of a route with the context of its request
+#[derive(Clone)]
+struct ContextHandler(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for ContextHandler {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        let context = request_context(request).await;
+        context.lock().unwrap().route = request.route().map(|route| route.uri.to_string());
+        CONTEXT.scope(context, self.0.handle(request, data)).await
+    }
+}
+
+/// Makes the handlers of the routes run with the context of their request.
+pub fn with_context(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(ContextHandler(route.handler));
+            route
+        })
+        .collect()
+}
+
+/// A JSON log line, always with the same fields so they can be parsed without knowing where the line comes from.
+pub fn json_line(message: &std::fmt::Arguments<'_>, record: &log::Record<'_>) -> String {
+    let context = current();
+    let line = json!({
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": message.to_string(),
+        "request_id": context.request_id,
+        "user_id_hash": context.user_id_hash,
+        "ip": context.ip,
+        "route": context.route,
+    });
+    line.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_request_id() {
+        assert!(is_valid_request_id("5d0f4a1e-7c2b-4a8e-9f4e-2b1c3d4e5f60"));
+        assert!(is_valid_request_id("edge-01:req_42.7"));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("id\nINFO forged line"));
+        assert!(!is_valid_request_id(&"a".repeat(129)));
+    }
+}
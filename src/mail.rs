@@ -1,14 +1,20 @@
-use std::str::FromStr;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use data_encoding::BASE64;
+use once_cell::sync::OnceCell;
 use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use tokio::{sync::Notify, task::JoinHandle};
+use tracing::{field::Empty, Instrument, Span};
 
 use lettre::{
+    address::Envelope,
     message::{Attachment, Body, Mailbox, Message, MultiPart, SinglePart},
-    transport::smtp::authentication::{Credentials, Mechanism as SmtpAuthMechanism},
-    transport::smtp::client::{Tls, TlsParameters},
-    transport::smtp::extension::ClientId,
-    Address, AsyncSendmailTransport, AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+    Address, AsyncSendmailTransport, AsyncTransport, Tokio1Executor,
 };
 
 use crate::{
@@ -17,10 +23,36 @@ use crate::{
         encode_jwt, generate_delete_claims, generate_emergency_access_invite_claims, generate_invite_claims,
         generate_verify_email_claims,
     },
+    db::{
+        models::{MailLog, MailLogStatus, MailPreference, MailStatus, MailType, QueuedMail, User},
+        DbConn, DbPool,
+    },
     error::Error,
+    mail_api, smtp,
+    util::get_uuid,
     CONFIG,
 };
 
+// Set at startup, for the mail queue and the mail preferences and locales of the recipients
+static DB_POOL: OnceCell<DbPool> = OnceCell::new();
+static QUEUE_NOTIFY: Notify = Notify::const_new();
+static QUEUE_WORKER: OnceCell<JoinHandle<()>> = OnceCell::new();
+const QUEUE_BATCH_SIZE: i64 = 50;
+// The retries which are due are picked up at this interval, the new mails are sent right away
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+// The notifications which are combined into a digest with `MAIL_DIGEST_DELAY`, they have no links or codes which expire
+const DIGEST_TEMPLATES: [&str; 9] = [
+    "invite_accepted",
+    "invite_confirmed",
+    "send_2fa_removed_from_org",
+    "send_single_org_removed_from_org",
+    "emergency_access_invite_accepted",
+    "emergency_access_invite_confirmed",
+    "emergency_access_revoked",
+    "admin_reset_password",
+    "admin_reset_2fa",
+];
+
 fn sendmail_transport() -> AsyncSendmailTransport<Tokio1Executor> {
     if let Some(command) = CONFIG.sendmail_command() {
         AsyncSendmailTransport::new_with_command(command)
@@ -29,76 +61,37 @@ fn sendmail_transport() -> AsyncSendmailTransport<Tokio1Executor> {
     }
 }
 
-fn smtp_transport() -> AsyncSmtpTransport<Tokio1Executor> {
-    use std::time::Duration;
-    let host = CONFIG.smtp_host().unwrap();
-
-    let smtp_client = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host.as_str())
-        .port(CONFIG.smtp_port())
-        .timeout(Some(Duration::from_secs(CONFIG.smtp_timeout())));
-
-    // Determine security
-    let smtp_client = if CONFIG.smtp_security() != *"off" {
-        let mut tls_parameters = TlsParameters::builder(host);
-        if CONFIG.smtp_accept_invalid_hostnames() {
-            tls_parameters = tls_parameters.dangerous_accept_invalid_hostnames(true);
-        }
-        if CONFIG.smtp_accept_invalid_certs() {
-            tls_parameters = tls_parameters.dangerous_accept_invalid_certs(true);
-        }
-        let tls_parameters = tls_parameters.build().unwrap();
-
-        if CONFIG.smtp_security() == *"force_tls" {
-            smtp_client.tls(Tls::Wrapper(tls_parameters))
-        } else {
-            smtp_client.tls(Tls::Required(tls_parameters))
-        }
-    } else {
-        smtp_client
-    };
-
-    let smtp_client = match (CONFIG.smtp_username(), CONFIG.smtp_password()) {
-        (Some(user), Some(pass)) => smtp_client.credentials(Credentials::new(user, pass)),
-        _ => smtp_client,
-    };
-
-    let smtp_client = match CONFIG.helo_name() {
-        Some(helo_name) => smtp_client.hello_name(ClientId::Domain(helo_name)),
-        None => smtp_client,
-    };
+async fn get_text(
+    address: &str,
+    template_name: &'static str,
+    data: serde_json::Value,
+) -> Result<(String, String, String), Error> {
+    let locale = recipient_locale(address).await;
+    let html_name = localized_template_name(&format!("{template_name}.html"), locale.as_deref());
+    let text_name = localized_template_name(template_name, locale.as_deref());
+    let (subject_html, body_html) = get_template(&html_name, &data)?;
+    let (_subject_text, body_text) = get_template(&text_name, &data)?;
+    Ok((subject_html, body_html, body_text))
+}
 
-    let smtp_client = match CONFIG.smtp_auth_mechanism() {
-        Some(mechanism) => {
-            let allowed_mechanisms = [SmtpAuthMechanism::Plain, SmtpAuthMechanism::Login, SmtpAuthMechanism::Xoauth2];
-            let mut selected_mechanisms = vec![];
-            for wanted_mechanism in mechanism.split(',') {
-                for m in &allowed_mechanisms {
-                    if m.to_string().to_lowercase()
-                        == wanted_mechanism.trim_matches(|c| c == '"' || c == '\'' || c == ' ').to_lowercase()
-                    {
-                        selected_mechanisms.push(*m);
-                    }
-                }
-            }
+// The locale of the clients of the recipient, when they have an account
+async fn recipient_locale(address: &str) -> Option<String> {
+    let mut conn = DB_POOL.get()?.get().await.ok()?;
+    User::find_by_mail(address, &mut conn).await?.locale
+}
 
-            if !selected_mechanisms.is_empty() {
-                smtp_client.authentication(selected_mechanisms)
-            } else {
-                // Only show a warning, and return without setting an actual authentication mechanism
-                warn!("No valid SMTP Auth mechanism found for '{}', using default values", mechanism);
-                smtp_client
+// The translated template for the locale, then for its language (`pt` for `pt-BR`), otherwise the built-in one
+fn localized_template_name(template_name: &str, locale: Option<&str>) -> String {
+    if let (Some(locale), Some(name)) = (locale, template_name.strip_prefix("email/")) {
+        let language = locale.split('-').next().unwrap_or(locale);
+        for candidate in [locale, language] {
+            let localized = format!("email/{candidate}/{name}");
+            if CONFIG.has_template(&localized) {
+                return localized;
             }
         }
-        _ => smtp_client,
-    };
-
-    smtp_client.build()
-}
-
-fn get_text(template_name: &'static str, data: serde_json::Value) -> Result<(String, String, String), Error> {
-    let (subject_html, body_html) = get_template(&format!("{template_name}.html"), &data)?;
-    let (_subject_text, body_text) = get_template(template_name, &data)?;
-    Ok((subject_html, body_html, body_text))
+    }
+    template_name.to_string()
 }
 
 fn get_template(template_name: &str, data: &serde_json::Value) -> Result<(String, String), Error> {
@@ -126,15 +119,17 @@ pub async fn send_password_hint(address: &str, hint: Option<String>) -> EmptyRes
     };
 
     let (subject, body_html, body_text) = get_text(
+        address,
         template_name,
         json!({
             "url": CONFIG.domain(),
             "img_src": CONFIG._smtp_img_src(),
             "hint": hint,
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_optional_email(MailType::PasswordHint, address, template_name, &subject, body_html, body_text).await
 }
 
 pub async fn send_delete_account(address: &str, uuid: &str) -> EmptyResult {
@@ -142,6 +137,7 @@ pub async fn send_delete_account(address: &str, uuid: &str) -> EmptyResult {
     let delete_token = encode_jwt(&claims);
 
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/delete_account",
         json!({
             "url": CONFIG.domain(),
@@ -150,9 +146,10 @@ pub async fn send_delete_account(address: &str, uuid: &str) -> EmptyResult {
             "email": percent_encode(address.as_bytes(), NON_ALPHANUMERIC).to_string(),
             "token": delete_token,
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_email(address, "email/delete_account", &subject, body_html, body_text).await
 }
 
 pub async fn send_verify_email(address: &str, uuid: &str) -> EmptyResult {
@@ -160,6 +157,7 @@ pub async fn send_verify_email(address: &str, uuid: &str) -> EmptyResult {
     let verify_email_token = encode_jwt(&claims);
 
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/verify_email",
         json!({
             "url": CONFIG.domain(),
@@ -168,21 +166,24 @@ pub async fn send_verify_email(address: &str, uuid: &str) -> EmptyResult {
             "email": percent_encode(address.as_bytes(), NON_ALPHANUMERIC).to_string(),
             "token": verify_email_token,
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_email(address, "email/verify_email", &subject, body_html, body_text).await
 }
 
 pub async fn send_welcome(address: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/welcome",
         json!({
             "url": CONFIG.domain(),
             "img_src": CONFIG._smtp_img_src(),
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_email(address, "email/welcome", &subject, body_html, body_text).await
 }
 
 pub async fn send_welcome_must_verify(address: &str, uuid: &str) -> EmptyResult {
@@ -190,6 +191,7 @@ pub async fn send_welcome_must_verify(address: &str, uuid: &str) -> EmptyResult
     let verify_email_token = encode_jwt(&claims);
 
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/welcome_must_verify",
         json!({
             "url": CONFIG.domain(),
@@ -197,35 +199,153 @@ pub async fn send_welcome_must_verify(address: &str, uuid: &str) -> EmptyResult
             "user_id": uuid,
             "token": verify_email_token,
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_email(address, "email/welcome_must_verify", &subject, body_html, body_text).await
 }
 
 pub async fn send_2fa_removed_from_org(address: &str, org_name: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/send_2fa_removed_from_org",
         json!({
             "url": CONFIG.domain(),
             "img_src": CONFIG._smtp_img_src(),
             "org_name": org_name,
         }),
-    )?;
+    )
+    .await?;
+
+    send_email(address, "email/send_2fa_removed_from_org", &subject, body_html, body_text).await
+}
+
+pub async fn send_2fa_policy_reminder(
+    address: &str,
+    org_name: &str,
+    deadline: &NaiveDateTime,
+    providers: &[&str],
+) -> EmptyResult {
+    let fmt = "%A, %B %_d, %Y at %r %Z";
+    let (subject, body_html, body_text) = get_text(
+        address,
+        "email/send_2fa_policy_reminder",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "org_name": org_name,
+            "deadline": crate::util::format_naive_datetime_local(deadline, fmt),
+            "providers": providers.join(", "),
+        }),
+    )
+    .await?;
+
+    let attachments = calendar_event(&subject, deadline);
+    send_email_with_attachments(address, "email/send_2fa_policy_reminder", &subject, body_html, body_text, attachments)
+        .await
+}
+
+pub async fn send_2fa_provider_required_by_org(address: &str, org_name: &str, providers: &[&str]) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        address,
+        "email/send_2fa_provider_required_by_org",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "org_name": org_name,
+            "providers": providers.join(", "),
+        }),
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_email(address, "email/send_2fa_provider_required_by_org", &subject, body_html, body_text).await
+}
+
+pub async fn send_2fa_recovery_request(address: &str, token: &str, ip: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        address,
+        "email/send_2fa_recovery_request",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "token": token,
+            "ip": ip,
+            "delay_hours": CONFIG.two_factor_recovery_delay_hours(),
+            "expiration_minutes": CONFIG.email_expiration_time() / 60,
+        }),
+    )
+    .await?;
+
+    send_email(address, "email/send_2fa_recovery_request", &subject, body_html, body_text).await
+}
+
+pub async fn send_2fa_recovery_scheduled(address: &str, recovery_at: &NaiveDateTime, ip: &str) -> EmptyResult {
+    let fmt = "%A, %B %_d, %Y at %r %Z";
+    let (subject, body_html, body_text) = get_text(
+        address,
+        "email/send_2fa_recovery_scheduled",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "ip": ip,
+            "datetime": crate::util::format_naive_datetime_local(recovery_at, fmt),
+        }),
+    )
+    .await?;
+
+    let attachments = calendar_event(&subject, recovery_at);
+    send_email_with_attachments(
+        address,
+        "email/send_2fa_recovery_scheduled",
+        &subject,
+        body_html,
+        body_text,
+        attachments,
+    )
+    .await
+}
+
+pub async fn send_2fa_recovery_canceled(address: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        address,
+        "email/send_2fa_recovery_canceled",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+        }),
+    )
+    .await?;
+
+    send_email(address, "email/send_2fa_recovery_canceled", &subject, body_html, body_text).await
+}
+
+pub async fn send_2fa_recovery_completed(address: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        address,
+        "email/send_2fa_recovery_completed",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+        }),
+    )
+    .await?;
+
+    send_email(address, "email/send_2fa_recovery_completed", &subject, body_html, body_text).await
 }
 
 pub async fn send_single_org_removed_from_org(address: &str, org_name: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/send_single_org_removed_from_org",
         json!({
             "url": CONFIG.domain(),
             "img_src": CONFIG._smtp_img_src(),
             "org_name": org_name,
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_email(address, "email/send_single_org_removed_from_org", &subject, body_html, body_text).await
 }
 
 pub async fn send_invite(
@@ -246,6 +366,7 @@ pub async fn send_invite(
     let invite_token = encode_jwt(&claims);
 
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/send_org_invite",
         json!({
             "url": CONFIG.domain(),
@@ -257,9 +378,10 @@ pub async fn send_invite(
             "org_name": org_name,
             "token": invite_token,
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_email(address, "email/send_org_invite", &subject, body_html, body_text).await
 }
 
 pub async fn send_emergency_access_invite(
@@ -268,6 +390,7 @@ pub async fn send_emergency_access_invite(
     emer_id: &str,
     grantor_name: &str,
     grantor_email: &str,
+    new_user: bool,
 ) -> EmptyResult {
     let claims = generate_emergency_access_invite_claims(
         String::from(uuid),
@@ -280,6 +403,7 @@ pub async fn send_emergency_access_invite(
     let invite_token = encode_jwt(&claims);
 
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/send_emergency_access_invite",
         json!({
             "url": CONFIG.domain(),
@@ -288,49 +412,161 @@ pub async fn send_emergency_access_invite(
             "email": percent_encode(address.as_bytes(), NON_ALPHANUMERIC).to_string(),
             "grantor_name": grantor_name,
             "token": invite_token,
+            "new_user": new_user,
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_email(address, "email/send_emergency_access_invite", &subject, body_html, body_text).await
 }
 
 pub async fn send_emergency_access_invite_accepted(address: &str, grantee_email: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/emergency_access_invite_accepted",
         json!({
             "url": CONFIG.domain(),
             "img_src": CONFIG._smtp_img_src(),
             "grantee_email": grantee_email,
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_optional_email(
+        MailType::EmergencyAccess,
+        address,
+        "email/emergency_access_invite_accepted",
+        &subject,
+        body_html,
+        body_text,
+    )
+    .await
 }
 
 pub async fn send_emergency_access_invite_confirmed(address: &str, grantor_name: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/emergency_access_invite_confirmed",
         json!({
             "url": CONFIG.domain(),
             "img_src": CONFIG._smtp_img_src(),
             "grantor_name": grantor_name,
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_optional_email(
+        MailType::EmergencyAccess,
+        address,
+        "email/emergency_access_invite_confirmed",
+        &subject,
+        body_html,
+        body_text,
+    )
+    .await
+}
+
+pub async fn send_emergency_access_key_invalid(address: &str, grantee_name: &str, errors: &[&str]) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        address,
+        "email/emergency_access_key_invalid",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "grantee_name": grantee_name,
+            "errors": errors,
+        }),
+    )
+    .await?;
+
+    send_email(address, "email/emergency_access_key_invalid", &subject, body_html, body_text).await
+}
+
+pub async fn send_emergency_access_reconfirm_required(address: &str, grantee_names: &[String]) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        address,
+        "email/emergency_access_reconfirm_required",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "grantee_names": grantee_names,
+        }),
+    )
+    .await?;
+
+    send_email(address, "email/emergency_access_reconfirm_required", &subject, body_html, body_text).await
+}
+
+pub async fn send_emergency_access_revoked(address: &str, grantor_name: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        address,
+        "email/emergency_access_revoked",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "grantor_name": grantor_name,
+        }),
+    )
+    .await?;
+
+    send_optional_email(
+        MailType::EmergencyAccess,
+        address,
+        "email/emergency_access_revoked",
+        &subject,
+        body_html,
+        body_text,
+    )
+    .await
 }
 
 pub async fn send_emergency_access_recovery_approved(address: &str, grantor_name: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/emergency_access_recovery_approved",
         json!({
             "url": CONFIG.domain(),
             "img_src": CONFIG._smtp_img_src(),
             "grantor_name": grantor_name,
         }),
-    )?;
+    )
+    .await?;
+
+    send_optional_email(
+        MailType::EmergencyAccess,
+        address,
+        "email/emergency_access_recovery_approved",
+        &subject,
+        body_html,
+        body_text,
+    )
+    .await
+}
+
+pub async fn send_emergency_access_recovery_escalation(
+    address: &str,
+    grantor_name: &str,
+    grantor_email: &str,
+    grantee_name: &str,
+    atype: &str,
+    days_left: &str,
+) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        address,
+        "email/emergency_access_recovery_escalation",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "grantor_name": grantor_name,
+            "grantor_email": grantor_email,
+            "grantee_name": grantee_name,
+            "atype": atype,
+            "days_left": days_left,
+        }),
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_email(address, "email/emergency_access_recovery_escalation", &subject, body_html, body_text).await
 }
 
 pub async fn send_emergency_access_recovery_initiated(
@@ -340,6 +576,7 @@ pub async fn send_emergency_access_recovery_initiated(
     wait_time_days: &i32,
 ) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/emergency_access_recovery_initiated",
         json!({
             "url": CONFIG.domain(),
@@ -348,9 +585,21 @@ pub async fn send_emergency_access_recovery_initiated(
             "atype": atype,
             "wait_time_days": wait_time_days,
         }),
-    )?;
-
-    send_email(address, &subject, body_html, body_text).await
+    )
+    .await?;
+
+    // The event is when the access is granted, unless the recovery is rejected before
+    let granted_at = Utc::now().naive_utc() + TimeDelta::try_days(i64::from(*wait_time_days)).unwrap_or_default();
+    let attachments = calendar_event(&subject, &granted_at);
+    send_email_with_attachments(
+        address,
+        "email/emergency_access_recovery_initiated",
+        &subject,
+        body_html,
+        body_text,
+        attachments,
+    )
+    .await
 }
 
 pub async fn send_emergency_access_recovery_reminder(
@@ -360,6 +609,7 @@ pub async fn send_emergency_access_recovery_reminder(
     days_left: &str,
 ) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/emergency_access_recovery_reminder",
         json!({
             "url": CONFIG.domain(),
@@ -368,26 +618,38 @@ pub async fn send_emergency_access_recovery_reminder(
             "atype": atype,
             "days_left": days_left,
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_email(address, "email/emergency_access_recovery_reminder", &subject, body_html, body_text).await
 }
 
 pub async fn send_emergency_access_recovery_rejected(address: &str, grantor_name: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/emergency_access_recovery_rejected",
         json!({
             "url": CONFIG.domain(),
             "img_src": CONFIG._smtp_img_src(),
             "grantor_name": grantor_name,
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_optional_email(
+        MailType::EmergencyAccess,
+        address,
+        "email/emergency_access_recovery_rejected",
+        &subject,
+        body_html,
+        body_text,
+    )
+    .await
 }
 
 pub async fn send_emergency_access_recovery_timed_out(address: &str, grantee_name: &str, atype: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/emergency_access_recovery_timed_out",
         json!({
             "url": CONFIG.domain(),
@@ -395,13 +657,15 @@ pub async fn send_emergency_access_recovery_timed_out(address: &str, grantee_nam
             "grantee_name": grantee_name,
             "atype": atype,
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_email(address, "email/emergency_access_recovery_timed_out", &subject, body_html, body_text).await
 }
 
 pub async fn send_invite_accepted(new_user_email: &str, address: &str, org_name: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/invite_accepted",
         json!({
             "url": CONFIG.domain(),
@@ -409,22 +673,25 @@ pub async fn send_invite_accepted(new_user_email: &str, address: &str, org_name:
             "email": new_user_email,
             "org_name": org_name,
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_email(address, "email/invite_accepted", &subject, body_html, body_text).await
 }
 
 pub async fn send_invite_confirmed(address: &str, org_name: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/invite_confirmed",
         json!({
             "url": CONFIG.domain(),
             "img_src": CONFIG._smtp_img_src(),
             "org_name": org_name,
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_email(address, "email/invite_confirmed", &subject, body_html, body_text).await
 }
 
 pub async fn send_new_device_logged_in(address: &str, ip: &str, dt: &NaiveDateTime, device: &str) -> EmptyResult {
@@ -433,6 +700,7 @@ pub async fn send_new_device_logged_in(address: &str, ip: &str, dt: &NaiveDateTi
 
     let fmt = "%A, %B %_d, %Y at %r %Z";
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/new_device_logged_in",
         json!({
             "url": CONFIG.domain(),
@@ -441,9 +709,11 @@ pub async fn send_new_device_logged_in(address: &str, ip: &str, dt: &NaiveDateTi
             "device": device,
             "datetime": crate::util::format_naive_datetime_local(dt, fmt),
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_optional_email(MailType::NewDeviceLogin, address, "email/new_device_logged_in", &subject, body_html, body_text)
+        .await
 }
 
 pub async fn send_incomplete_2fa_login(address: &str, ip: &str, dt: &NaiveDateTime, device: &str) -> EmptyResult {
@@ -452,6 +722,7 @@ pub async fn send_incomplete_2fa_login(address: &str, ip: &str, dt: &NaiveDateTi
 
     let fmt = "%A, %B %_d, %Y at %r %Z";
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/incomplete_2fa_login",
         json!({
             "url": CONFIG.domain(),
@@ -461,51 +732,106 @@ pub async fn send_incomplete_2fa_login(address: &str, ip: &str, dt: &NaiveDateTi
             "datetime": crate::util::format_naive_datetime_local(dt, fmt),
             "time_limit": CONFIG.incomplete_2fa_time_limit(),
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_email(address, "email/incomplete_2fa_login", &subject, body_html, body_text).await
 }
 
 pub async fn send_token(address: &str, token: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/twofactor_email",
         json!({
             "url": CONFIG.domain(),
             "img_src": CONFIG._smtp_img_src(),
             "token": token,
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_email(address, "email/twofactor_email", &subject, body_html, body_text).await
 }
 
 pub async fn send_change_email(address: &str, token: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/change_email",
         json!({
             "url": CONFIG.domain(),
             "img_src": CONFIG._smtp_img_src(),
             "token": token,
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    send_email(address, "email/change_email", &subject, body_html, body_text).await
 }
 
 pub async fn send_test(address: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/smtp_test",
         json!({
             "url": CONFIG.domain(),
             "img_src": CONFIG._smtp_img_src(),
         }),
-    )?;
+    )
+    .await?;
 
-    send_email(address, &subject, body_html, body_text).await
+    // Not queued, the result is shown in the admin panel
+    send_now(address, &subject, body_html, body_text, Vec::new()).await
+}
+
+/// Renders a mail template with sample data, so the overrides can be checked from the admin panel.
+pub fn preview_template(name: &str) -> Result<(String, String, String), Error> {
+    // The templates are rendered in strict mode, so all the variables used by any of them are needed
+    let data = json!({
+        "url": CONFIG.domain(),
+        "img_src": CONFIG._smtp_img_src(),
+        "email": "user@example.com",
+        "user_id": "00000000-0000-0000-0000-000000000000",
+        "user_name": "Jane Doe",
+        "org_name": "Example Organization",
+        "org_name_encoded": percent_encode(b"Example Organization", NON_ALPHANUMERIC).to_string(),
+        "org_id": "00000000-0000-0000-0000-000000000000",
+        "org_user_id": "00000000-0000-0000-0000-000000000000",
+        "grantor_name": "Jane Doe",
+        "grantor_email": "jane@example.com",
+        "grantee_name": "John Doe",
+        "grantee_email": "john@example.com",
+        "grantee_names": ["John Doe"],
+        "emer_id": "00000000-0000-0000-0000-000000000000",
+        "atype": "view",
+        "new_user": true,
+        "errors": ["John Doe"],
+        "token": "123456",
+        "ip": "192.0.2.1",
+        "device": "Firefox",
+        "datetime": "Monday, January 1, 2024 at 12:00:00 PM UTC",
+        "providers": "Authenticator app, Email",
+        "deadline": "Monday, January 1, 2024",
+        "days_left": 7,
+        "wait_time_days": 7,
+        "delay_hours": 24,
+        "time_limit": 10,
+        "expiration_minutes": 10,
+        "hint": "The name of my first pet",
+        "count": 2,
+        "notifications": [
+            { "subject": "Invitation to Example Organization accepted", "body": "jane@example.com has accepted." },
+            { "subject": "Invitation to Example Organization confirmed", "body": "You can now use Example Organization." },
+        ],
+    });
+    let template_name = format!("email/{name}");
+    let (subject, body_html) = get_template(&format!("{template_name}.html"), &data)?;
+    let (_subject_text, body_text) = get_template(&template_name, &data)?;
+    Ok((subject, body_html, body_text))
 }
 
 pub async fn send_admin_reset_password(address: &str, user_name: &str, org_name: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/admin_reset_password",
         json!({
             "url": CONFIG.domain(),
@@ -513,26 +839,254 @@ pub async fn send_admin_reset_password(address: &str, user_name: &str, org_name:
             "user_name": user_name,
             "org_name": org_name,
         }),
-    )?;
-    send_email(address, &subject, body_html, body_text).await
+    )
+    .await?;
+    send_email(address, "email/admin_reset_password", &subject, body_html, body_text).await
+}
+
+pub async fn send_admin_reset_2fa(address: &str, user_name: &str, org_name: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        address,
+        "email/admin_reset_2fa",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "user_name": user_name,
+            "org_name": org_name,
+        }),
+    )
+    .await?;
+    send_email(address, "email/admin_reset_2fa", &subject, body_html, body_text).await
 }
 
 pub async fn send_protected_action_token(address: &str, token: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
+        address,
         "email/protected_action",
         json!({
             "url": CONFIG.domain(),
             "img_src": CONFIG._smtp_img_src(),
             "token": token,
         }),
-    )?;
+    )
+    .await?;
+
+    send_email(address, "email/protected_action", &subject, body_html, body_text).await
+}
 
-    send_email(address, &subject, body_html, body_text).await
+/// A rendered mail, which is sent by the transport selected in the config.
+pub struct Mail {
+    pub address: String,
+    pub subject: String,
+    pub body_html: String,
+    pub body_text: String,
+    pub attachments: Vec<MailAttachment>,
 }
 
-async fn send_with_selected_transport(email: Message) -> EmptyResult {
-    if CONFIG.use_sendmail() {
-        match sendmail_transport().send(email).await {
+/// A file attached to a mail, like a calendar event.
+pub struct MailAttachment {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+// The attachments of a mail are dropped when they are larger, the mail is still sent
+const MAX_ATTACHMENTS_SIZE: usize = 1024 * 1024;
+// The logo is attached to every mail
+const MAX_LOGO_SIZE: usize = 256 * 1024;
+
+// The images which are attached to the mails when `SMTP_EMBED_IMAGES` is set, the templates reference them by `cid:`
+pub const EMBEDDED_IMAGES: [&str; 2] = ["logo-gray.png", "mail-github.png"];
+
+/// The data and the content type of an embedded image, the logo is replaced by `SMTP_LOGO_PATH` when it's set.
+pub fn embedded_image(name: &str) -> (Vec<u8>, &'static str) {
+    if let (Some(path), "logo-gray.png") = (CONFIG.smtp_logo_path(), name) {
+        match load_logo(&path) {
+            Ok(logo) => return logo,
+            Err(e) => warn!("The logo `{path}` can't be loaded, the built-in one is used: {e}"),
+        }
+    }
+    (crate::api::static_file(name).unwrap().1.to_vec(), "image/png")
+}
+
+/// Loads a PNG or JPEG logo, with its content type.
+pub fn load_logo(path: &str) -> Result<(Vec<u8>, &'static str), Error> {
+    let data = std::fs::read(path)?;
+    if data.len() > MAX_LOGO_SIZE {
+        err!(format!("The logo is larger than {} KiB", MAX_LOGO_SIZE / 1024))
+    }
+    let content_type = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        "image/jpeg"
+    } else {
+        err!("The logo needs to be a PNG or JPEG image")
+    };
+    Ok((data, content_type))
+}
+
+// The attachments of the queued and logged mails are stored as JSON, with their data in base64
+fn encode_attachments(attachments: &[MailAttachment]) -> Option<String> {
+    if attachments.is_empty() {
+        return None;
+    }
+    let attachments: Vec<serde_json::Value> = attachments
+        .iter()
+        .map(|attachment| {
+            json!({
+                "filename": attachment.filename,
+                "content_type": attachment.content_type,
+                "data": BASE64.encode(&attachment.data),
+            })
+        })
+        .collect();
+    Some(serde_json::Value::from(attachments).to_string())
+}
+
+fn decode_attachments(attachments: Option<&str>) -> Vec<MailAttachment> {
+    let Some(Ok(serde_json::Value::Array(attachments))) = attachments.map(serde_json::from_str) else {
+        return Vec::new();
+    };
+    attachments
+        .iter()
+        .filter_map(|attachment| {
+            Some(MailAttachment {
+                filename: attachment["filename"].as_str()?.to_string(),
+                content_type: attachment["content_type"].as_str()?.to_string(),
+                data: BASE64.decode(attachment["data"].as_str()?.as_bytes()).ok()?,
+            })
+        })
+        .collect()
+}
+
+// The format of the dates of the calendar events, in UTC
+const ICS_DATETIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// A calendar event at the given time, titled with the subject of the mail, when `SMTP_CALENDAR_ATTACHMENTS` is set.
+/// Ref: https://datatracker.ietf.org/doc/html/rfc5545
+fn calendar_event(summary: &str, start: &NaiveDateTime) -> Vec<MailAttachment> {
+    if !CONFIG.smtp_calendar_attachments() {
+        return Vec::new();
+    }
+    let start = start.format(ICS_DATETIME_FORMAT);
+    let domain = CONFIG.smtp_from().split('@').nth(1).unwrap_or_default().to_string();
+    let lines = [
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//Vaultwarden//Vaultwarden//EN".to_string(),
+        "METHOD:PUBLISH".to_string(),
+        "BEGIN:VEVENT".to_string(),
+        format!("UID:{}@{domain}", get_uuid()),
+        format!("DTSTAMP:{}", Utc::now().format(ICS_DATETIME_FORMAT)),
+        format!("DTSTART:{start}"),
+        format!("DTEND:{start}"),
+        format!("SUMMARY:{}", escape_ics_text(summary)),
+        format!("URL:{}", CONFIG.domain()),
+        "END:VEVENT".to_string(),
+        "END:VCALENDAR".to_string(),
+    ];
+    vec![MailAttachment {
+        filename: "event.ics".to_string(),
+        content_type: "text/calendar; charset=utf-8; method=PUBLISH".to_string(),
+        data: lines.iter().map(|line| fold_ics_line(line)).collect::<String>().into_bytes(),
+    }]
+}
+
+fn escape_ics_text(text: &str) -> String {
+    text.replace('\\', "\\\\").replace(';', "\\;").replace(',', "\\,").replace('\n', "\\n")
+}
+
+// The lines are folded at 75 bytes, without splitting the characters
+fn fold_ics_line(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 2);
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+impl Mail {
+    /// Builds the MIME message, for the transports which send it as is.
+    pub fn to_message(&self) -> Result<Message, Error> {
+        let smtp_from = &CONFIG.smtp_from();
+
+        let body = if CONFIG.smtp_embed_images() {
+            let related = EMBEDDED_IMAGES.iter().fold(
+                MultiPart::related().singlepart(SinglePart::html(self.body_html.clone())),
+                |related, name| {
+                    let (data, content_type) = embedded_image(name);
+                    related.singlepart(
+                        Attachment::new_inline(String::from(*name))
+                            .body(Body::new(data), content_type.parse().unwrap()),
+                    )
+                },
+            );
+            MultiPart::alternative().singlepart(SinglePart::plain(self.body_text.clone())).multipart(related)
+        } else {
+            MultiPart::alternative_plain_html(self.body_text.clone(), self.body_html.clone())
+        };
+
+        let body = if self.attachments.is_empty() {
+            body
+        } else {
+            let mut mixed = MultiPart::mixed().multipart(body);
+            for attachment in &self.attachments {
+                let Ok(content_type) = attachment.content_type.parse() else {
+                    err!(format!("The attachment {} has an invalid content type", attachment.filename))
+                };
+                mixed = mixed.singlepart(
+                    Attachment::new(attachment.filename.clone()).body(Body::new(attachment.data.clone()), content_type),
+                );
+            }
+            mixed
+        };
+
+        let email = Message::builder()
+            .message_id(Some(format!("<{}@{}>", get_uuid(), smtp_from.split('@').collect::<Vec<&str>>()[1])))
+            .to(Mailbox::new(None, Address::from_str(&self.address)?))
+            .from(Mailbox::new(Some(CONFIG.smtp_from_name()), Address::from_str(smtp_from)?))
+            .subject(&self.subject)
+            .multipart(body)?;
+        Ok(email)
+    }
+
+    /// The formatted MIME message and its envelope, signed with DKIM when it's configured.
+    pub fn to_signed_message(&self) -> Result<(Envelope, Vec<u8>), Error> {
+        let message = self.to_message()?;
+        Ok((message.envelope().clone(), crate::dkim::sign(message.formatted())?))
+    }
+}
+
+/// A way to deliver the mails, selected with `USE_SENDMAIL` and `MAIL_API`.
+#[rocket::async_trait]
+pub trait MailTransport: Send + Sync {
+    async fn send(&self, mail: &Mail) -> EmptyResult;
+}
+
+fn selected_transport() -> Box<dyn MailTransport> {
+    match CONFIG.mail_api().as_deref() {
+        Some("sendgrid") => Box::new(mail_api::SendGrid),
+        Some("mailgun") => Box::new(mail_api::Mailgun),
+        Some("ses") => Box::new(mail_api::Ses),
+        _ if CONFIG.use_sendmail() => Box::new(Sendmail),
+        _ => Box::new(smtp::Smtp),
+    }
+}
+
+struct Sendmail;
+
+#[rocket::async_trait]
+impl MailTransport for Sendmail {
+    async fn send(&self, mail: &Mail) -> EmptyResult {
+        let (envelope, message) = mail.to_signed_message()?;
+        match sendmail_transport().send_raw(&envelope, &message).await {
             Ok(_) => Ok(()),
             // Match some common errors and make them more user friendly
             Err(e) => {
@@ -548,68 +1102,343 @@ async fn send_with_selected_transport(email: Message) -> EmptyResult {
                 }
             }
         }
+    }
+}
+
+// Sends a mail of a type the recipient can turn off, unless they did
+async fn send_optional_email(
+    mail_type: MailType,
+    address: &str,
+    template: &str,
+    subject: &str,
+    body_html: String,
+    body_text: String,
+) -> EmptyResult {
+    if let Some(pool) = DB_POOL.get() {
+        let mut conn = pool.get().await?;
+        if let Some(user) = User::find_by_mail(address, &mut conn).await {
+            if !MailPreference::is_enabled(&user.uuid, mail_type, &mut conn).await {
+                debug!("Not sending the {} mail to {address}, it was turned off", mail_type.name());
+                let template = template.trim_start_matches("email/");
+                let log = MailLog::new(
+                    get_uuid(),
+                    address,
+                    template,
+                    subject,
+                    &body_html,
+                    &body_text,
+                    MailLogStatus::Skipped,
+                );
+                save_log(&log).await;
+                return Ok(());
+            }
+        }
+    }
+    send_email(address, template, subject, body_html, body_text).await
+}
+
+async fn send_email(address: &str, template: &str, subject: &str, body_html: String, body_text: String) -> EmptyResult {
+    send_email_with_attachments(address, template, subject, body_html, body_text, Vec::new()).await
+}
+
+async fn send_email_with_attachments(
+    address: &str,
+    template: &str,
+    subject: &str,
+    body_html: String,
+    body_text: String,
+    mut attachments: Vec<MailAttachment>,
+) -> EmptyResult {
+    let size: usize = attachments.iter().map(|attachment| attachment.data.len()).sum();
+    if size > MAX_ATTACHMENTS_SIZE {
+        warn!("The attachments of the mail to {address} are too large ({size} bytes), it's sent without them");
+        attachments.clear();
+    }
+    let encoded_attachments = encode_attachments(&attachments);
+
+    // The templates are logged without their `email/` prefix, like they are listed in the admin panel
+    let template = template.trim_start_matches("email/");
+    if let (true, Some(pool)) = (CONFIG.mail_queue(), DB_POOL.get()) {
+        // The invalid addresses are still reported to the caller
+        Address::from_str(address)?;
+        let mut mail = QueuedMail::new(address, subject, body_html, body_text);
+        mail.attachments.clone_from(&encoded_attachments);
+        let digest_delay = CONFIG.mail_digest_delay();
+        if digest_delay > 0 && attachments.is_empty() && DIGEST_TEMPLATES.contains(&template) {
+            mail.digest = true;
+            mail.next_attempt_at += TimeDelta::try_seconds(digest_delay as i64).unwrap_or_default();
+        }
+        mail.insert(&mut pool.get().await?).await?;
+        QUEUE_NOTIFY.notify_one();
+        // The log has the id of the queued mail, so the queue can update it
+        let mut log = MailLog::new(
+            mail.uuid.clone(),
+            address,
+            template,
+            subject,
+            &mail.body_html,
+            &mail.body_text,
+            MailLogStatus::Queued,
+        );
+        log.attachments = encoded_attachments;
+        save_log(&log).await;
+        return Ok(());
+    }
+
+    let mut log = MailLog::new(get_uuid(), address, template, subject, &body_html, &body_text, MailLogStatus::Sent);
+    log.attachments = encoded_attachments;
+    let result = send_now(address, subject, body_html, body_text, attachments).await;
+    if let Err(e) = &result {
+        log.status = MailLogStatus::Failed as i32;
+        log.error = Some(e.to_string());
+    }
+    save_log(&log).await;
+    result
+}
+
+// A mail which can't be logged is still sent
+async fn save_log(log: &MailLog) {
+    let Some(pool) = DB_POOL.get() else {
+        return;
+    };
+    let result = match pool.get().await {
+        Ok(mut conn) => log.insert(&mut conn).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        warn!("Error logging the mail to {}: {e}", log.recipient);
+    }
+}
+
+/// Sends a logged mail again, with the same content. It's logged again too.
+pub async fn resend_logged_mail(uuid: &str, conn: &mut DbConn) -> EmptyResult {
+    let Some(log) = MailLog::find_by_uuid(uuid, conn).await else {
+        err!("Mail not found")
+    };
+    let attachments = decode_attachments(log.attachments.as_deref());
+    send_email_with_attachments(&log.recipient, &log.template, &log.subject, log.body_html, log.body_text, attachments)
+        .await
+}
+
+async fn send_now(
+    address: &str,
+    subject: &str,
+    body_html: String,
+    body_text: String,
+    attachments: Vec<MailAttachment>,
+) -> EmptyResult {
+    // Checked here, as not all the transports build the message themselves
+    Address::from_str(address)?;
+    let mail = Mail {
+        address: address.to_string(),
+        subject: subject.to_string(),
+        body_html,
+        body_text,
+        attachments,
+    };
+    let span = if crate::otel::enabled() {
+        tracing::info_span!("mail.send", otel.kind = "client", otel.status_code = Empty)
     } else {
-        match smtp_transport().send(email).await {
-            Ok(_) => Ok(()),
-            // Match some common errors and make them more user friendly
-            Err(e) => {
-                if e.is_client() {
-                    debug!("SMTP client error: {:#?}", e);
-                    err!(format!("SMTP client error: {e}"));
-                } else if e.is_transient() {
-                    debug!("SMTP 4xx error: {:#?}", e);
-                    err!(format!("SMTP 4xx error: {e}"));
-                } else if e.is_permanent() {
-                    debug!("SMTP 5xx error: {:#?}", e);
-                    let mut msg = e.to_string();
-                    // Add a special check for 535 to add a more descriptive message
-                    if msg.contains("(535)") {
-                        msg = format!("{msg} - Authentication credentials invalid");
-                    }
-                    err!(format!("SMTP 5xx error: {msg}"));
-                } else if e.is_timeout() {
-                    debug!("SMTP timeout error: {:#?}", e);
-                    err!(format!("SMTP timeout error: {e}"));
-                } else if e.is_tls() {
-                    debug!("SMTP encryption error: {:#?}", e);
-                    err!(format!("SMTP encryption error: {e}"));
-                } else {
-                    debug!("SMTP error: {:#?}", e);
-                    err!(format!("SMTP error: {e}"));
+        Span::none()
+    };
+    let result = selected_transport().send(&mail).instrument(span.clone()).await;
+    if result.is_err() {
+        span.record("otel.status_code", "error");
+    }
+    result
+}
+
+/// Starts the worker which sends the queued mails, and retries them with an exponential backoff.
+pub fn init_mail_queue(pool: DbPool) {
+    if DB_POOL.set(pool.clone()).is_err() {
+        return;
+    }
+    let worker = tokio::spawn(async move {
+        let mut throttle = Throttle {
+            window_start: Instant::now(),
+            sent: 0,
+        };
+        loop {
+            if let Err(e) = send_queued_mails(&pool, &mut throttle).await {
+                error!("Error sending the queued mails: {e:?}");
+            }
+            tokio::time::timeout(QUEUE_POLL_INTERVAL, QUEUE_NOTIFY.notified()).await.ok();
+        }
+    });
+    QUEUE_WORKER.set(worker).ok();
+}
+
+/// Whether the worker of the mail queue is still running, it stops only when it panicked.
+pub fn mail_queue_alive() -> bool {
+    QUEUE_WORKER.get().is_some_and(|worker| !worker.is_finished())
+}
+
+// Limits the mails sent by the queue to `MAIL_QUEUE_MAX_PER_MINUTE`, a digest counts as a single mail
+struct Throttle {
+    window_start: Instant,
+    sent: u32,
+}
+
+impl Throttle {
+    async fn wait(&mut self) {
+        let max_per_minute = CONFIG.mail_queue_max_per_minute();
+        if max_per_minute == 0 {
+            return;
+        }
+        let window = Duration::from_secs(60);
+        if self.sent >= max_per_minute {
+            tokio::time::sleep_until((self.window_start + window).into()).await;
+        }
+        if self.window_start.elapsed() >= window {
+            self.window_start = Instant::now();
+            self.sent = 0;
+        }
+        self.sent += 1;
+    }
+}
+
+async fn send_queued_mails(pool: &DbPool, throttle: &mut Throttle) -> EmptyResult {
+    let mails = QueuedMail::find_due(QUEUE_BATCH_SIZE, &mut pool.get().await?).await;
+    if mails.len() as i64 == QUEUE_BATCH_SIZE {
+        // Come back right away for the next batch
+        QUEUE_NOTIFY.notify_one();
+    }
+
+    // The connection isn't held while sending, which can take until the SMTP timeout
+    for group in group_digests(mails) {
+        let mut claimed = Vec::with_capacity(group.len());
+        for mut mail in group {
+            if mail.claim(&mut pool.get().await?).await {
+                claimed.push(mail);
+            }
+        }
+        if claimed.is_empty() {
+            continue;
+        }
+
+        throttle.wait().await;
+        let result = match claimed.as_slice() {
+            [mail] => {
+                let attachments = decode_attachments(mail.attachments.as_deref());
+                send_now(&mail.recipient, &mail.subject, mail.body_html.clone(), mail.body_text.clone(), attachments)
+                    .await
+            }
+            mails => send_digest(mails).await,
+        };
+
+        let mut conn = pool.get().await?;
+        for mut mail in claimed {
+            match &result {
+                Ok(()) => {
+                    MailLog::update_status(&mail.uuid, MailLogStatus::Sent, None, &mut conn).await?;
+                    mail.delete(&mut conn).await?;
+                }
+                Err(e) => {
+                    mail.failed(e.to_string());
+                    let status = if mail.status == MailStatus::DeadLetter as i32 {
+                        error!(
+                            "Giving up sending the mail to {} after {} attempts: {e}",
+                            mail.recipient, mail.attempts
+                        );
+                        MailLogStatus::Failed
+                    } else {
+                        MailLogStatus::Queued
+                    };
+                    MailLog::update_status(&mail.uuid, status, mail.last_error.clone(), &mut conn).await?;
+                    mail.update(&mut conn).await?;
                 }
             }
         }
     }
+    Ok(())
 }
 
-async fn send_email(address: &str, subject: &str, body_html: String, body_text: String) -> EmptyResult {
-    let smtp_from = &CONFIG.smtp_from();
-
-    let body = if CONFIG.smtp_embed_images() {
-        let logo_gray_body = Body::new(crate::api::static_files("logo-gray.png").unwrap().1.to_vec());
-        let mail_github_body = Body::new(crate::api::static_files("mail-github.png").unwrap().1.to_vec());
-        MultiPart::alternative().singlepart(SinglePart::plain(body_text)).multipart(
-            MultiPart::related()
-                .singlepart(SinglePart::html(body_html))
-                .singlepart(
-                    Attachment::new_inline(String::from("logo-gray.png"))
-                        .body(logo_gray_body, "image/png".parse().unwrap()),
-                )
-                .singlepart(
-                    Attachment::new_inline(String::from("mail-github.png"))
-                        .body(mail_github_body, "image/png".parse().unwrap()),
-                ),
-        )
-    } else {
-        MultiPart::alternative_plain_html(body_text, body_html)
+// Groups the digest mails by recipient, the other ones are sent on their own. The digest mails are only combined on
+// their first attempt, their retries are sent on their own too.
+fn group_digests(mails: Vec<QueuedMail>) -> Vec<Vec<QueuedMail>> {
+    let mut groups: Vec<Vec<QueuedMail>> = Vec::with_capacity(mails.len());
+    let mut digests: HashMap<String, usize> = HashMap::new();
+    for mail in mails {
+        if mail.digest && mail.attempts == 0 {
+            match digests.entry(mail.recipient.to_lowercase()) {
+                Entry::Occupied(entry) => groups[*entry.get()].push(mail),
+                Entry::Vacant(entry) => {
+                    entry.insert(groups.len());
+                    groups.push(vec![mail]);
+                }
+            }
+        } else {
+            groups.push(vec![mail]);
+        }
+    }
+    groups
+}
+
+// A single mail which lists the notifications, with their text without the footer
+async fn send_digest(mails: &[QueuedMail]) -> EmptyResult {
+    let recipient = &mails[0].recipient;
+    let notifications: Vec<serde_json::Value> = mails
+        .iter()
+        .map(|mail| {
+            let body = mail.body_text.rsplit_once("\n===").map_or(mail.body_text.as_str(), |(body, _)| body);
+            json!({
+                "subject": mail.subject,
+                "body": body.trim(),
+            })
+        })
+        .collect();
+
+    let (subject, body_html, body_text) = get_text(
+        recipient,
+        "email/digest",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "count": mails.len(),
+            "notifications": notifications,
+        }),
+    )
+    .await?;
+    send_now(recipient, &subject, body_html, body_text, Vec::new()).await
+}
+
+/// The state of the queue, for the admin diagnostics.
+pub async fn queue_json(conn: &mut DbConn) -> serde_json::Value {
+    let dead_letters: Vec<serde_json::Value> =
+        QueuedMail::find_dead_letters(conn).await.iter().map(QueuedMail::to_json).collect();
+    json!({
+        "enabled": CONFIG.mail_queue(),
+        "pending": QueuedMail::count_by_status(MailStatus::Pending, conn).await,
+        "dead_letter_count": dead_letters.len(),
+        "dead_letters": dead_letters,
+        "relays": smtp::relays_json(),
+    })
+}
+
+/// Puts a dead letter back in the queue.
+pub async fn retry_dead_letter(uuid: &str, conn: &mut DbConn) -> EmptyResult {
+    let Some(mut mail) = QueuedMail::find_by_uuid(uuid, conn).await else {
+        err!("Queued mail not found")
     };
+    mail.retry();
+    mail.update(conn).await?;
+    MailLog::update_status(uuid, MailLogStatus::Queued, mail.last_error.clone(), conn).await?;
+    QUEUE_NOTIFY.notify_one();
+    Ok(())
+}
 
-    let email = Message::builder()
-        .message_id(Some(format!("<{}@{}>", crate::util::get_uuid(), smtp_from.split('@').collect::<Vec<&str>>()[1])))
-        .to(Mailbox::new(None, Address::from_str(address)?))
-        .from(Mailbox::new(Some(CONFIG.smtp_from_name()), Address::from_str(smtp_from)?))
-        .subject(subject)
-        .multipart(body)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    send_with_selected_transport(email).await
+    #[test]
+    fn test_ics_text() {
+        assert_eq!(escape_ics_text("a;b,c\\d\ne"), "a\\;b\\,c\\\\d\\ne");
+
+        let folded = fold_ics_line(&format!("SUMMARY:{}", "é".repeat(40)));
+        let lines: Vec<&str> = folded.trim_end_matches("\r\n").split("\r\n").collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| line.len() <= 75));
+        assert_eq!(folded.replace("\r\n ", ""), format!("SUMMARY:{}\r\n", "é".repeat(40)));
+    }
 }
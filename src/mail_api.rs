@@ -0,0 +1,167 @@
+//
+// HTTP APIs of the mail providers, for the hosts which block the outbound SMTP connections
+//
+// SendGrid gets the parts of the mail as JSON, with the embedded images as inline attachments, and signs the mails
+// with DKIM itself. Mailgun and SES get the same MIME message as the SMTP and sendmail transports.
+// Ref: https://docs.sendgrid.com/api-reference/mail-send/mail-send
+// Ref: https://documentation.mailgun.com/docs/mailgun/api-reference/openapi-final/tag/Messages/
+// Ref: https://docs.aws.amazon.com/ses/latest/APIReference-V2/API_SendEmail.html
+//
+use chrono::Utc;
+use data_encoding::{BASE64, HEXLOWER};
+use reqwest::{header, RequestBuilder};
+
+use crate::{
+    api::EmptyResult,
+    crypto::encode_random_bytes,
+    http_client::{get_reqwest_client, TracedSend},
+    mail::{embedded_image, Mail, MailTransport, EMBEDDED_IMAGES},
+    sigv4, CONFIG,
+};
+
+pub struct SendGrid;
+
+#[rocket::async_trait]
+impl MailTransport for SendGrid {
+    async fn send(&self, mail: &Mail) -> EmptyResult {
+        let mut attachments: Vec<_> = if CONFIG.smtp_embed_images() {
+            EMBEDDED_IMAGES
+                .iter()
+                .map(|name| {
+                    let (data, content_type) = embedded_image(name);
+                    json!({
+                        "content": BASE64.encode(&data),
+                        "filename": name,
+                        "type": content_type,
+                        "disposition": "inline",
+                        "content_id": name,
+                    })
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        attachments.extend(mail.attachments.iter().map(|attachment| {
+            json!({
+                "content": BASE64.encode(&attachment.data),
+                "filename": attachment.filename,
+                "type": attachment.content_type,
+                "disposition": "attachment",
+            })
+        }));
+
+        let mut data = json!({
+            "personalizations": [{ "to": [{ "email": mail.address }] }],
+            "from": { "email": CONFIG.smtp_from(), "name": CONFIG.smtp_from_name() },
+            "subject": mail.subject,
+            "content": [
+                { "type": "text/plain", "value": mail.body_text },
+                { "type": "text/html", "value": mail.body_html },
+            ],
+        });
+        if !attachments.is_empty() {
+            data["attachments"] = attachments.into();
+        }
+
+        let request = get_reqwest_client()
+            .post("https://api.sendgrid.com/v3/mail/send")
+            .bearer_auth(CONFIG.mail_api_key().unwrap_or_default())
+            .json(&data);
+        send_request("SendGrid", request).await
+    }
+}
+
+pub struct Mailgun;
+
+#[rocket::async_trait]
+impl MailTransport for Mailgun {
+    async fn send(&self, mail: &Mail) -> EmptyResult {
+        let domain = match CONFIG.mail_api_domain() {
+            Some(domain) => domain,
+            None => CONFIG.smtp_from().split('@').nth(1).unwrap_or_default().to_string(),
+        };
+        let base_url = match CONFIG.mail_api_region().as_deref() {
+            Some("eu") => "https://api.eu.mailgun.net",
+            _ => "https://api.mailgun.net",
+        };
+
+        // The message is sent as a file of a multipart form, which is built here as it's the only one
+        let boundary = encode_random_bytes::<16>(HEXLOWER);
+        let mut body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"to\"\r\n\r\n{}\r\n\
+             --{boundary}\r\nContent-Disposition: form-data; name=\"message\"; filename=\"message.mime\"\r\n\
+             Content-Type: message/rfc822\r\n\r\n",
+            mail.address
+        )
+        .into_bytes();
+        body.extend(mail.to_signed_message()?.1);
+        body.extend(format!("\r\n--{boundary}--\r\n").into_bytes());
+
+        let request = get_reqwest_client()
+            .post(format!("{base_url}/v3/{domain}/messages.mime"))
+            .basic_auth("api", CONFIG.mail_api_key())
+            .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={boundary}"))
+            .body(body);
+        send_request("Mailgun", request).await
+    }
+}
+
+pub struct Ses;
+
+#[rocket::async_trait]
+impl MailTransport for Ses {
+    async fn send(&self, mail: &Mail) -> EmptyResult {
+        let (Some(access_key_id), Some(secret_access_key), Some(region)) =
+            (CONFIG.mail_api_key_id(), CONFIG.mail_api_key(), CONFIG.mail_api_region())
+        else {
+            err!("SES is not configured")
+        };
+        let host = format!("email.{region}.amazonaws.com");
+        let path = "/v2/email/outbound-emails";
+
+        let body = serde_json::to_vec(&json!({
+            "Content": { "Raw": { "Data": BASE64.encode(&mail.to_signed_message()?.1) } },
+            "Destination": { "ToAddresses": [mail.address] },
+        }))?;
+
+        let now = Utc::now();
+        let amz_date = sigv4::amz_date(&now);
+        let creds = sigv4::Credentials {
+            access_key_id: &access_key_id,
+            secret_access_key: &secret_access_key,
+            region: &region,
+            service: "ses",
+        };
+        let req = sigv4::SignRequest {
+            method: "POST",
+            host: &host,
+            canonical_uri: path,
+            headers: &[("content-type", "application/json"), ("x-amz-date", amz_date.as_str())],
+            now,
+        };
+        let authorization = sigv4::authorization(&creds, &req, "", &body);
+
+        let request = get_reqwest_client()
+            .post(format!("https://{host}{path}"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-Amz-Date", amz_date)
+            .header(header::AUTHORIZATION, authorization)
+            .body(body);
+        send_request("SES", request).await
+    }
+}
+
+// The error responses are included in the error, they explain what the provider refused
+async fn send_request(provider: &str, request: RequestBuilder) -> EmptyResult {
+    let response = match request.send_traced().await {
+        Ok(response) => response,
+        Err(e) => err!(format!("{provider} request error: {e}")),
+    };
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        debug!("{provider} error response: {body}");
+        err!(format!("{provider} error {status}: {body}"))
+    }
+    Ok(())
+}
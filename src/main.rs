@@ -27,8 +27,9 @@ extern crate diesel_migrations;
 
 use std::{
     fs::{canonicalize, create_dir_all},
+    net::SocketAddr,
     panic,
-    path::Path,
+    path::{Path, PathBuf},
     process::exit,
     str::FromStr,
     thread,
@@ -42,16 +43,43 @@ use tokio::{
 #[macro_use]
 mod error;
 mod api;
+mod archive;
 mod auth;
+mod backup;
+mod compression;
 mod config;
+mod config_check;
 mod crypto;
+mod data_retention;
 #[macro_use]
 mod db;
+mod db_maintenance;
+mod dkim;
+mod http_client;
+mod icon_cache;
+mod icon_image;
+mod instance_backup;
+mod jobs;
+#[cfg(feature = "ldap")]
+mod ldap;
+mod log_context;
 mod mail;
+mod mail_api;
+mod mapping_rules;
+mod otel;
+mod pubsub;
 mod ratelimit;
+mod route_stats;
+#[cfg(feature = "s3")]
+mod s3;
+mod secrets;
+mod siem;
+mod sigv4;
+mod smtp;
+mod systemd;
 mod util;
+mod webhook;
 
-use crate::api::purge_auth_requests;
 use crate::api::{WS_ANONYMOUS_SUBSCRIPTIONS, WS_USERS};
 pub use config::CONFIG;
 pub use error::{Error, MapResult};
@@ -61,7 +89,10 @@ pub use util::is_running_in_container;
 
 #[rocket::main]
 async fn main() -> Result<(), Error> {
-    parse_args();
+    let db_command = parse_args();
+    if let Some(DbCommand::CheckConfig) = db_command {
+        exit(config_check::run().await);
+    }
     launch_info();
 
     use log::LevelFilter as LF;
@@ -71,6 +102,7 @@ async fn main() -> Result<(), Error> {
         exit(1);
     });
     init_logging(level).ok();
+    otel::init();
 
     let extra_debug = matches!(level, LF::Trace | LF::Debug);
 
@@ -86,9 +118,17 @@ async fn main() -> Result<(), Error> {
     create_dir(&CONFIG.sends_folder(), "sends folder");
     create_dir(&CONFIG.attachments_folder(), "attachments folder");
 
+    systemd::check_socket_activation();
     let pool = create_db_pool().await;
-    schedule_jobs(pool.clone());
-    crate::db::models::TwoFactor::migrate_u2f_to_webauthn(&mut pool.get().await.unwrap()).await.unwrap();
+    if let Some(command) = db_command {
+        run_db_command(command, &pool).await;
+    }
+    jobs::schedule_jobs(pool.clone());
+    api::init_direct_push(pool.clone());
+    api::init_notification_history(pool.clone());
+    mail::init_mail_queue(pool.clone());
+    db::coalesce::init(pool.clone());
+    db::check_migrations(&pool).await?;
 
     launch_rocket(pool, extra_debug).await // Blocks until program termination.
 }
@@ -102,9 +142,22 @@ USAGE:
 FLAGS:
     -h, --help       Prints help information
     -v, --version    Prints the app version
+    --check-config   Checks the configuration, the database and the SMTP connections, without starting the server
 
 COMMAND:
     hash [--preset {bitwarden|owasp}]  Generate an Argon2id PHC ADMIN_TOKEN
+    encrypt-data                       Encrypt all the sensitive data with the first DATABASE_ENCRYPTION_KEYS key
+    migrate-db <TARGET_URL>            Copy all the data to a new, empty, PostgreSQL or MySQL/MariaDB database
+    export-db                          Export all the data into an archive in the backup folder
+    import-db <ARCHIVE>                Import an archive into a new, empty, database of any backend
+        [--ignore-missing-files]       Even when the files of the attachments or Sends weren't restored
+    backup [<FILE>]                    Back up the data, the files, the config and the keys into one archive
+    restore <FILE>                     Restore a backup into a new, empty, database and the configured folders
+    user list                          List the users, with their status
+    user disable <EMAIL>               Disable a user and delete their devices
+    user delete <EMAIL>                Delete a user and their vault
+    user remove-2fa <EMAIL>            Remove the two-step login methods of a user
+    invite <EMAIL>                     Invite a user, by mail when it's enabled
 
 PRESETS:                  m=         t=          p=
     bitwarden (default) 64MiB, 3 Iterations, 4 Threads
@@ -114,7 +167,20 @@ PRESETS:                  m=         t=          p=
 
 pub const VERSION: Option<&str> = option_env!("VW_VERSION");
 
-fn parse_args() {
+// The commands which need the database, they run once it's available
+enum DbCommand {
+    // Runs before the startup instead, it only connects to the database without changing it
+    CheckConfig,
+    EncryptData,
+    MigrateDb(String),
+    ExportDb,
+    ImportDb(PathBuf, bool),
+    Backup(Option<PathBuf>),
+    Restore(PathBuf),
+    User(api::UserCommand),
+}
+
+fn parse_args() -> Option<DbCommand> {
     let mut pargs = pico_args::Arguments::from_env();
     let version = VERSION.unwrap_or("(Version info from Git not present)");
 
@@ -125,6 +191,8 @@ fn parse_args() {
     } else if pargs.contains(["-v", "--version"]) {
         println!("vaultwarden {version}");
         exit(0);
+    } else if pargs.contains("--check-config") {
+        return Some(DbCommand::CheckConfig);
     }
 
     if let Some(command) = pargs.subcommand().unwrap_or_default() {
@@ -181,10 +249,140 @@ fn parse_args() {
                 error!("Unable to generate Argon2id PHC hash.");
                 exit(1);
             }
+        } else if command == "encrypt-data" {
+            if CONFIG.database_encryption_keys().is_none() && CONFIG.two_factor_data_secret().is_none() {
+                println!("DATABASE_ENCRYPTION_KEYS needs to be set to encrypt the data");
+                exit(1);
+            }
+            return Some(DbCommand::EncryptData);
+        } else if command == "migrate-db" {
+            let Ok(target_url) = pargs.free_from_str::<String>() else {
+                println!("The URL of the target database is missing: vaultwarden migrate-db <TARGET_URL>");
+                exit(1);
+            };
+            return Some(DbCommand::MigrateDb(target_url));
+        } else if command == "export-db" {
+            return Some(DbCommand::ExportDb);
+        } else if command == "import-db" {
+            let ignore_missing_files = pargs.contains("--ignore-missing-files");
+            let Ok(archive) = pargs.free_from_str::<PathBuf>() else {
+                println!("The path of the archive is missing: vaultwarden import-db <ARCHIVE>");
+                exit(1);
+            };
+            return Some(DbCommand::ImportDb(archive, ignore_missing_files));
+        } else if command == "backup" {
+            let path = pargs.opt_free_from_str::<PathBuf>().unwrap_or_default();
+            return Some(DbCommand::Backup(path));
+        } else if command == "restore" {
+            let Ok(path) = pargs.free_from_str::<PathBuf>() else {
+                println!("The path of the backup is missing: vaultwarden restore <FILE>");
+                exit(1);
+            };
+            return Some(DbCommand::Restore(path));
+        } else if command == "user" {
+            let action = pargs.subcommand().unwrap_or_default().unwrap_or_default();
+            let email = pargs.opt_free_from_str::<String>().unwrap_or_default();
+            let user_command = match (action.as_str(), email) {
+                ("list", _) => api::UserCommand::List,
+                ("disable", Some(email)) => api::UserCommand::Disable(email),
+                ("delete", Some(email)) => api::UserCommand::Delete(email),
+                ("remove-2fa", Some(email)) => api::UserCommand::Remove2fa(email),
+                _ => {
+                    println!("Usage: vaultwarden user {{list|disable <EMAIL>|delete <EMAIL>|remove-2fa <EMAIL>}}");
+                    exit(1);
+                }
+            };
+            return Some(DbCommand::User(user_command));
+        } else if command == "invite" {
+            let Ok(email) = pargs.free_from_str::<String>() else {
+                println!("The email of the user is missing: vaultwarden invite <EMAIL>");
+                exit(1);
+            };
+            return Some(DbCommand::User(api::UserCommand::Invite(email)));
         }
         exit(0);
     }
+    None
+}
+
+// Runs the command and exits
+async fn run_db_command(command: DbCommand, pool: &db::DbPool) {
+    let result = match command {
+        DbCommand::CheckConfig => unreachable!("`--check-config` runs before the startup"),
+        DbCommand::EncryptData => match pool.get().await {
+            Ok(mut conn) => db::encryption::encrypt_all_data(true, &mut conn).await.map(|count| {
+                println!("Encrypted {count} values");
+            }),
+            Err(e) => Err(e),
+        },
+        DbCommand::MigrateDb(target_url) => migrate_db(pool, &target_url).await,
+        DbCommand::ExportDb => match pool.get().await {
+            Ok(mut conn) => archive::create_archive(&mut conn).await.map(|file_name| {
+                println!("Exported the data into {file_name}, in the backup folder");
+            }),
+            Err(e) => Err(e),
+        },
+        DbCommand::ImportDb(path, ignore_missing_files) => import_db(pool, &path, ignore_missing_files).await,
+        DbCommand::Backup(path) => match pool.get().await {
+            Ok(mut conn) => instance_backup::create_backup(path, &mut conn).await.map(|path| {
+                println!("Backed up the instance into {}", path.display());
+            }),
+            Err(e) => Err(e),
+        },
+        DbCommand::Restore(path) => restore(pool, &path).await,
+        DbCommand::User(command) => match pool.get().await {
+            Ok(mut conn) => api::run_user_command(command, &mut conn).await.map(|output| println!("{output}")),
+            Err(e) => Err(e),
+        },
+    };
+
+    if let Err(e) = result {
+        error!("{e:?}");
+        exit(1);
+    }
+    exit(0);
+}
+
+async fn migrate_db(pool: &db::DbPool, target_url: &str) -> Result<(), Error> {
+    if target_url == CONFIG.database_url() {
+        err!("The target database needs to be different from DATABASE_URL")
+    }
+
+    // The source and the target need the same schema, the target one is created by the migrations
+    if !db::migration_status().await?.pending.is_empty() {
+        err!("The pending migrations of DATABASE_URL need to be applied first")
+    }
+    let target = db::DbPool::from_url(target_url, None, true)?;
+
+    println!("Copying the data to the target database, the server should be stopped meanwhile...");
+    let copied = db::models::transfer_database(&mut pool.get().await?, &mut target.get().await?).await?;
+    println!("Copied {copied} rows, DATABASE_URL can now be set to the target database");
+    Ok(())
+}
+async fn import_db(pool: &db::DbPool, path: &Path, ignore_missing_files: bool) -> Result<(), Error> {
+    // The rows of the archive need the schema of this version
+    if !db::migration_status().await?.pending.is_empty() {
+        err!("The pending migrations of DATABASE_URL need to be applied first")
+    }
+
+    println!("Importing the archive, the server should be stopped meanwhile...");
+    let imported = archive::import_archive(path, ignore_missing_files, &mut pool.get().await?).await?;
+    println!("Imported {imported} rows");
+    Ok(())
+}
+
+async fn restore(pool: &db::DbPool, path: &Path) -> Result<(), Error> {
+    // The rows of the backup need the schema of this version
+    if !db::migration_status().await?.pending.is_empty() {
+        err!("The pending migrations of DATABASE_URL need to be applied first")
+    }
+
+    println!("Restoring the backup, the server should be stopped meanwhile...");
+    let (files, rows) = instance_backup::restore_backup(path, &mut pool.get().await?).await?;
+    println!("Restored {files} files and imported {rows} rows, the restored config file is used from the next start");
+    Ok(())
 }
+
 fn launch_info() {
     println!(
         "\
@@ -284,13 +482,19 @@ fn init_logging(level: log::LevelFilter) -> Result<(), fern::InitError> {
         logger = logger.level_for("lettre::transport::smtp", log::LevelFilter::Off)
     }
 
-    if CONFIG.extended_logging() {
+    if CONFIG.log_format() == "json" {
+        logger = logger
+            .format(|out, message, record| out.finish(format_args!("{}", log_context::json_line(message, record))));
+    } else if CONFIG.extended_logging() {
         logger = logger.format(|out, message, record| {
+            // The lines of a request end their prefix with its ID
+            let request_id = log_context::current_request_id().map(|id| format!("[{id}]")).unwrap_or_default();
             out.finish(format_args!(
-                "[{}][{}][{}] {}",
+                "[{}][{}][{}]{} {}",
                 chrono::Local::now().format(&CONFIG.log_timestamp_format()),
                 record.target(),
                 record.level(),
+                request_id,
                 message
             ))
         });
@@ -318,7 +522,11 @@ fn init_logging(level: log::LevelFilter) -> Result<(), fern::InitError> {
         }
     }
 
-    logger.apply()?;
+    // The errors of the scheduled jobs are also added to their runs, without the format of the logs
+    fern::Dispatch::new()
+        .chain(logger)
+        .chain(fern::Dispatch::new().level(log::LevelFilter::Error).chain(fern::Output::call(jobs::record_log)))
+        .apply()?;
 
     // Catch panics and log them instead of default output to StdErr
     panic::set_hook(Box::new(|info| {
@@ -482,34 +690,56 @@ async fn launch_rocket(pool: db::DbPool, extra_debug: bool) -> Result<(), Error>
     let mut config = rocket::Config::from(rocket::Config::figment());
     config.temp_dir = canonicalize(CONFIG.tmp_folder()).unwrap().into();
     config.cli_colors = false; // Make sure Rocket does not color any values for logging.
+    // The multipart form limits need to be large enough for both Send files and attachments.
+    // The exact per-type limits are enforced in the upload handlers, which return a 413 when exceeded.
+    let max_upload_kb =
+        (CONFIG.send_file_size_limit_bytes().max(CONFIG.attachment_file_size_limit_bytes()) >> 10) as u64 + 1;
     config.limits = Limits::new()
         .limit("json", 20.megabytes()) // 20MB should be enough for very large imports, something like 5000+ vault entries
-        .limit("data-form", 525.megabytes()) // This needs to match the maximum allowed file size for Send
-        .limit("file", 525.megabytes()); // This needs to match the maximum allowed file size for attachments
+        .limit("data-form", (max_upload_kb + 1024).kibibytes()) // Add some room for the other form fields
+        .limit("file", max_upload_kb.kibibytes());
+
+    api::log_setup_code();
+
+    // The admin page can have its own listener, then it's not served by the main one
+    let admin_address: Option<SocketAddr> = CONFIG.admin_listen_address().and_then(|address| address.parse().ok());
 
     // If adding more paths here, consider also adding them to
     // crate::utils::LOGGED_ROUTES to make sure they appear in the log
-    let instance = rocket::custom(config)
-        .mount([basepath, "/"].concat(), api::web_routes())
-        .mount([basepath, "/api"].concat(), api::core_routes())
-        .mount([basepath, "/admin"].concat(), api::admin_routes())
-        .mount([basepath, "/events"].concat(), api::core_events_routes())
-        .mount([basepath, "/identity"].concat(), api::identity_routes())
-        .mount([basepath, "/icons"].concat(), api::icons_routes())
-        .mount([basepath, "/notifications"].concat(), api::notifications_routes())
+    let mut instance = rocket::custom(config.clone())
+        .mount([basepath, "/"].concat(), log_context::with_context(api::web_routes()))
+        .mount([basepath, "/api"].concat(), log_context::with_context(api::core_routes()))
+        .mount([basepath, "/events"].concat(), log_context::with_context(api::core_events_routes()))
+        .mount([basepath, "/identity"].concat(), log_context::with_context(api::identity_routes()))
+        .mount([basepath, "/icons"].concat(), log_context::with_context(api::icons_routes()))
+        .mount([basepath, "/notifications"].concat(), log_context::with_context(api::notifications_routes()))
         .register([basepath, "/"].concat(), api::web_catchers())
         .register([basepath, "/api"].concat(), api::core_catchers())
-        .register([basepath, "/admin"].concat(), api::admin_catchers())
-        .manage(pool)
-        .manage(Arc::clone(&WS_USERS))
-        .manage(Arc::clone(&WS_ANONYMOUS_SUBSCRIPTIONS))
-        .attach(util::AppHeaders())
-        .attach(util::Cors())
-        .attach(util::BetterLogging(extra_debug))
-        .ignite()
-        .await?;
-
-    CONFIG.set_rocket_shutdown_handle(instance.shutdown());
+        .attach(systemd::SystemdNotify);
+    if admin_address.is_none() {
+        instance = mount_admin(instance, basepath);
+    }
+    let instance = with_state_and_fairings(instance, pool.clone(), extra_debug).ignite().await?;
+    CONFIG.add_rocket_shutdown_handle(instance.shutdown());
+
+    // The static files of the admin page are served with the web vault routes
+    let admin_instance = match admin_address {
+        Some(address) => {
+            let mut admin_config = config;
+            admin_config.address = address.ip();
+            admin_config.port = address.port();
+            let admin_instance = rocket::custom(admin_config)
+                .mount([basepath, "/"].concat(), log_context::with_context(api::web_routes()))
+                .register([basepath, "/"].concat(), api::web_catchers());
+            let admin_instance =
+                with_state_and_fairings(mount_admin(admin_instance, basepath), pool, extra_debug).ignite().await?;
+            CONFIG.add_rocket_shutdown_handle(admin_instance.shutdown());
+            info!("The admin page is only served on {address}");
+            Some(admin_instance)
+        }
+        None => None,
+    };
+    pubsub::start();
 
     tokio::spawn(async move {
         tokio::signal::ctrl_c().await.expect("Error setting Ctrl-C handler");
@@ -517,96 +747,66 @@ async fn launch_rocket(pool: db::DbPool, extra_debug: bool) -> Result<(), Error>
         CONFIG.shutdown();
     });
 
-    let _ = instance.launch().await?;
+    match admin_instance {
+        Some(admin_instance) => {
+            // When one of them stops, like when its address can't be bound, the other one is stopped too
+            let (main, admin) = tokio::join!(
+                async {
+                    let result = instance.launch().await;
+                    CONFIG.shutdown();
+                    result
+                },
+                async {
+                    let result = admin_instance.launch().await;
+                    CONFIG.shutdown();
+                    result
+                }
+            );
+            db::coalesce::flush().await;
+            main?;
+            admin?;
+        }
+        None => {
+            let result = instance.launch().await;
+            db::coalesce::flush().await;
+            result?;
+        }
+    }
 
     info!("Vaultwarden process exited!");
     Ok(())
 }
 
-fn schedule_jobs(pool: db::DbPool) {
-    if CONFIG.job_poll_interval_ms() == 0 {
-        info!("Job scheduler disabled.");
-        return;
-    }
-
-    let runtime = tokio::runtime::Runtime::new().unwrap();
-
-    thread::Builder::new()
-        .name("job-scheduler".to_string())
-        .spawn(move || {
-            use job_scheduler_ng::{Job, JobScheduler};
-            let _runtime_guard = runtime.enter();
-
-            let mut sched = JobScheduler::new();
-
-            // Purge sends that are past their deletion date.
-            if !CONFIG.send_purge_schedule().is_empty() {
-                sched.add(Job::new(CONFIG.send_purge_schedule().parse().unwrap(), || {
-                    runtime.spawn(api::purge_sends(pool.clone()));
-                }));
-            }
-
-            // Purge trashed items that are old enough to be auto-deleted.
-            if !CONFIG.trash_purge_schedule().is_empty() {
-                sched.add(Job::new(CONFIG.trash_purge_schedule().parse().unwrap(), || {
-                    runtime.spawn(api::purge_trashed_ciphers(pool.clone()));
-                }));
-            }
-
-            // Send email notifications about incomplete 2FA logins, which potentially
-            // indicates that a user's master password has been compromised.
-            if !CONFIG.incomplete_2fa_schedule().is_empty() {
-                sched.add(Job::new(CONFIG.incomplete_2fa_schedule().parse().unwrap(), || {
-                    runtime.spawn(api::send_incomplete_2fa_notifications(pool.clone()));
-                }));
-            }
-
-            // Grant emergency access requests that have met the required wait time.
-            // This job should run before the emergency access reminders job to avoid
-            // sending reminders for requests that are about to be granted anyway.
-            if !CONFIG.emergency_request_timeout_schedule().is_empty() {
-                sched.add(Job::new(CONFIG.emergency_request_timeout_schedule().parse().unwrap(), || {
-                    runtime.spawn(api::emergency_request_timeout_job(pool.clone()));
-                }));
-            }
-
-            // Send reminders to emergency access grantors that there are pending
-            // emergency access requests.
-            if !CONFIG.emergency_notification_reminder_schedule().is_empty() {
-                sched.add(Job::new(CONFIG.emergency_notification_reminder_schedule().parse().unwrap(), || {
-                    runtime.spawn(api::emergency_notification_reminder_job(pool.clone()));
-                }));
-            }
-
-            if !CONFIG.auth_request_purge_schedule().is_empty() {
-                sched.add(Job::new(CONFIG.auth_request_purge_schedule().parse().unwrap(), || {
-                    runtime.spawn(purge_auth_requests(pool.clone()));
-                }));
-            }
-
-            // Cleanup the event table of records x days old.
-            if CONFIG.org_events_enabled()
-                && !CONFIG.event_cleanup_schedule().is_empty()
-                && CONFIG.events_days_retain().is_some()
-            {
-                sched.add(Job::new(CONFIG.event_cleanup_schedule().parse().unwrap(), || {
-                    runtime.spawn(api::event_cleanup_job(pool.clone()));
-                }));
-            }
+fn mount_admin(instance: rocket::Rocket<rocket::Build>, basepath: &str) -> rocket::Rocket<rocket::Build> {
+    instance
+        .mount([basepath, "/admin"].concat(), log_context::with_context(api::admin_routes()))
+        .register([basepath, "/admin"].concat(), api::admin_catchers())
+}
 
-            // Periodically check for jobs to run. We probably won't need any
-            // jobs that run more often than once a minute, so a default poll
-            // interval of 30 seconds should be sufficient. Users who want to
-            // schedule jobs to run more frequently for some reason can reduce
-            // the poll interval accordingly.
-            //
-            // Note that the scheduler checks jobs in the order in which they
-            // were added, so if two jobs are both eligible to run at a given
-            // tick, the one that was added earlier will run first.
-            loop {
-                sched.tick();
-                runtime.block_on(tokio::time::sleep(tokio::time::Duration::from_millis(CONFIG.job_poll_interval_ms())));
-            }
-        })
-        .expect("Error spawning job scheduler thread");
+// The state and the fairings of the listeners
+fn with_state_and_fairings(
+    instance: rocket::Rocket<rocket::Build>,
+    pool: db::DbPool,
+    extra_debug: bool,
+) -> rocket::Rocket<rocket::Build> {
+    let instance = instance
+        .manage(pool)
+        .manage(Arc::clone(&WS_USERS))
+        .manage(Arc::clone(&WS_ANONYMOUS_SUBSCRIPTIONS))
+        .attach(route_stats::RouteStats)
+        .attach(util::AppHeaders())
+        .attach(util::Cors())
+        .attach(log_context::RequestId)
+        .attach(log_context::SlowRequestLog)
+        .attach(util::BetterLogging(extra_debug));
+    let instance = if CONFIG.response_compression() {
+        instance.attach(compression::Compression)
+    } else {
+        instance
+    };
+    if otel::enabled() {
+        instance.attach(otel::RequestTracing)
+    } else {
+        instance
+    }
 }
@@ -0,0 +1,195 @@
+//
+// Login mapping rules, assigning organizations, roles and collections from the claims of an external identity
+//
+// The rules are read from a JSON file (`LDAP_MAPPING_RULES`), for example:
+//
+//   [
+//     {
+//       "claim": "memberOf",
+//       "pattern": "^cn=vault-admins,",
+//       "org_id": "<organization id>",
+//       "role": "admin",
+//       "collections": ["<collection id>"],
+//       "read_only": false,
+//       "hide_passwords": false
+//     }
+//   ]
+//
+// A rule matches when any value of its claim matches its pattern, case-insensitively. When several rules match
+// for the same organization, the user gets the highest of their roles and all of their collections.
+// Rules only grant access: organizations and collections which no rule matches are left untouched.
+//
+// The rules are only applied at login when `LDAP_MAPPING_ENFORCE` is enabled, otherwise they can be tested
+// with the dry-run evaluation of the admin panel.
+//
+use std::collections::{BTreeMap, HashMap};
+
+use regex::{Regex, RegexBuilder};
+use serde_json::Value;
+
+use crate::{
+    db::{
+        models::{Collection, CollectionUser, Organization, User, UserOrgType, UserOrganization},
+        DbConn,
+    },
+    error::Error,
+    CONFIG,
+};
+
+/// The claims of an identity, by lowercase claim name.
+pub type Claims = HashMap<String, Vec<String>>;
+
+#[derive(Deserialize)]
+struct MappingRuleData {
+    claim: String,
+    pattern: String,
+    org_id: String,
+    role: String,
+    #[serde(default)]
+    collections: Vec<String>,
+    #[serde(default)]
+    read_only: bool,
+    #[serde(default)]
+    hide_passwords: bool,
+}
+
+pub struct MappingRule {
+    claim: String,
+    pattern: Regex,
+    org_uuid: String,
+    atype: UserOrgType,
+    collections: Vec<String>,
+    read_only: bool,
+    hide_passwords: bool,
+}
+
+/// The access granted in an organization by the matching rules.
+pub struct OrgAssignment {
+    pub org_uuid: String,
+    pub atype: UserOrgType,
+    /// The collections by uuid, with their `read_only` and `hide_passwords` flags
+    pub collections: BTreeMap<String, (bool, bool)>,
+    /// The indexes of the matching rules in the rules file
+    pub rules: Vec<usize>,
+}
+
+/// Parses the content of the mapping rules file.
+pub fn parse_rules(json: &str) -> Result<Vec<MappingRule>, String> {
+    let data: Vec<MappingRuleData> = serde_json::from_str(json).map_err(|e| format!("Invalid JSON: {e}"))?;
+    data.into_iter()
+        .enumerate()
+        .map(|(i, rule)| {
+            let pattern = RegexBuilder::new(&rule.pattern)
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| format!("Invalid pattern in rule {i}: {e}"))?;
+            let atype = match rule.role.to_lowercase().as_str() {
+                "owner" => UserOrgType::Owner,
+                "admin" => UserOrgType::Admin,
+                "manager" => UserOrgType::Manager,
+                "user" => UserOrgType::User,
+                role => return Err(format!("Invalid role `{role}` in rule {i}")),
+            };
+            Ok(MappingRule {
+                claim: rule.claim.to_lowercase(),
+                pattern,
+                org_uuid: rule.org_id,
+                atype,
+                collections: rule.collections,
+                read_only: rule.read_only,
+                hide_passwords: rule.hide_passwords,
+            })
+        })
+        .collect()
+}
+
+/// Loads the configured mapping rules, an empty list if there aren't any.
+pub fn load_rules() -> Result<Vec<MappingRule>, Error> {
+    let path = match CONFIG.ldap_mapping_rules() {
+        Some(path) => path,
+        None => return Ok(Vec::new()),
+    };
+    let json = match std::fs::read_to_string(&path) {
+        Ok(json) => json,
+        Err(e) => err!(format!("Error reading the mapping rules file {path}: {e}")),
+    };
+    match parse_rules(&json) {
+        Ok(rules) => Ok(rules),
+        Err(e) => err!(format!("Invalid mapping rules file {path}: {e}")),
+    }
+}
+
+/// Evaluates the rules against the claims, returning the access granted in each organization.
+pub fn evaluate(rules: &[MappingRule], claims: &Claims) -> Vec<OrgAssignment> {
+    let mut assignments: Vec<OrgAssignment> = Vec::new();
+    for (i, rule) in rules.iter().enumerate() {
+        let matches = claims.get(&rule.claim).is_some_and(|values| values.iter().any(|v| rule.pattern.is_match(v)));
+        if !matches {
+            continue;
+        }
+
+        let assignment = match assignments.iter().position(|a| a.org_uuid == rule.org_uuid) {
+            Some(pos) => &mut assignments[pos],
+            None => {
+                assignments.push(OrgAssignment {
+                    org_uuid: rule.org_uuid.clone(),
+                    atype: rule.atype,
+                    collections: BTreeMap::new(),
+                    rules: Vec::new(),
+                });
+                assignments.last_mut().unwrap()
+            }
+        };
+
+        assignment.atype = assignment.atype.max(rule.atype);
+        assignment.rules.push(i);
+        // The most permissive access wins when several rules grant the same collection
+        for collection in &rule.collections {
+            let access = assignment.collections.entry(collection.clone()).or_insert((true, true));
+            access.0 &= rule.read_only;
+            access.1 &= rule.hide_passwords;
+        }
+    }
+    assignments
+}
+
+/// Describes the assignments and the changes they would make for the user, for the dry-run evaluation.
+pub async fn assignments_json(user: Option<&User>, assignments: &[OrgAssignment], conn: &mut DbConn) -> Value {
+    let mut result = Vec::with_capacity(assignments.len());
+    for assignment in assignments {
+        let org_name = Organization::find_by_uuid(&assignment.org_uuid, conn).await.map(|org| org.name);
+        let member = match user {
+            Some(user) => UserOrganization::find_by_user_and_org(&user.uuid, &assignment.org_uuid, conn).await,
+            None => None,
+        };
+
+        let mut collections = Vec::with_capacity(assignment.collections.len());
+        for (collection_uuid, (read_only, hide_passwords)) in &assignment.collections {
+            let collection = Collection::find_by_uuid_and_org(collection_uuid, &assignment.org_uuid, conn).await;
+            let granted = match user {
+                Some(user) => {
+                    CollectionUser::find_by_collection_and_user(collection_uuid, &user.uuid, conn).await.is_some()
+                }
+                None => false,
+            };
+            collections.push(json!({
+                "id": collection_uuid,
+                "found": collection.is_some(),
+                "readOnly": read_only,
+                "hidePasswords": hide_passwords,
+                "alreadyGranted": granted,
+            }));
+        }
+
+        result.push(json!({
+            "orgId": assignment.org_uuid,
+            "orgName": org_name,
+            "role": assignment.atype as i32,
+            "currentRole": member.as_ref().map(|m| m.atype),
+            "currentStatus": member.as_ref().map(|m| m.status),
+            "collections": collections,
+            "rules": assignment.rules,
+        }));
+    }
+    Value::Array(result)
+}
@@ -0,0 +1,543 @@
+//
+// Distributed tracing, with the spans exported to an OpenTelemetry collector
+//
+// The request handling, the database queries, the mails and the outgoing HTTP requests are traced with `tracing`
+// spans, which are exported with OTLP/HTTP and JSON when `OTEL_EXPORTER_OTLP_ENDPOINT` is set. The standard `OTEL_*`
+// variables configure the exporter, the resource and the sampler. A `traceparent` header of a request is continued.
+// Ref: https://opentelemetry.io/docs/specs/otel/configuration/sdk-environment-variables/
+// Ref: https://opentelemetry.io/docs/specs/otlp/#otlphttp
+// Ref: https://www.w3.org/TR/trace-context/
+//
+use std::{
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use once_cell::sync::Lazy;
+use percent_encoding::percent_decode_str;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Data, Request, Response,
+};
+use serde_json::Value;
+use tracing::{
+    field::{Empty, Field, Visit},
+    info_span,
+    span::{Attributes, Id, Record},
+    Event, Level, Metadata, Span, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    Layer,
+};
+
+use crate::{crypto::get_random_bytes, http_client::get_reqwest_client};
+
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const EXPORT_BATCH_SIZE: usize = 512;
+// The spans which are finished while the collector can't be reached are dropped above this
+const MAX_QUEUED_SPANS: usize = 2048;
+
+// OTLP span kinds and status codes
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+const KIND_CLIENT: u8 = 3;
+const STATUS_UNSET: u8 = 0;
+const STATUS_OK: u8 = 1;
+const STATUS_ERROR: u8 = 2;
+
+static SETTINGS: Lazy<Option<Settings>> = Lazy::new(Settings::from_env);
+static QUEUE: Lazy<Mutex<Vec<Value>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static DROPPED_SPANS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, PartialEq)]
+enum Sampler {
+    AlwaysOn,
+    AlwaysOff,
+    Ratio(f64),
+}
+
+struct Settings {
+    endpoint: String,
+    headers: Vec<(String, String)>,
+    timeout: Duration,
+    resource: Value,
+    sampler: Sampler,
+    // With a parent based sampler, the sampling decision of the `traceparent` header is kept
+    parent_based: bool,
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().map(|value| value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+// The `key1=value1,key2=value2` lists of the headers and the resource attributes, with percent encoded values
+fn parse_key_values(list: &str) -> Vec<(String, String)> {
+    list.split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), percent_decode_str(value.trim()).decode_utf8_lossy().into_owned()))
+        .filter(|(key, _)| !key.is_empty())
+        .collect()
+}
+
+fn parse_sampler(name: Option<&str>, arg: Option<&str>) -> (Sampler, bool) {
+    let ratio = || Sampler::Ratio(arg.and_then(|arg| arg.parse::<f64>().ok()).unwrap_or(1.0).clamp(0.0, 1.0));
+    match name.unwrap_or("parentbased_always_on") {
+        "always_on" => (Sampler::AlwaysOn, false),
+        "always_off" => (Sampler::AlwaysOff, false),
+        "traceidratio" => (ratio(), false),
+        "parentbased_always_off" => (Sampler::AlwaysOff, true),
+        "parentbased_traceidratio" => (ratio(), true),
+        "parentbased_always_on" => (Sampler::AlwaysOn, true),
+        other => {
+            warn!("The OpenTelemetry sampler `{other}` is not supported, using `parentbased_always_on`");
+            (Sampler::AlwaysOn, true)
+        }
+    }
+}
+
+impl Settings {
+    fn from_env() -> Option<Self> {
+        if env("OTEL_SDK_DISABLED").is_some_and(|disabled| disabled.eq_ignore_ascii_case("true"))
+            || env("OTEL_TRACES_EXPORTER").is_some_and(|exporter| exporter == "none")
+        {
+            return None;
+        }
+        let endpoint = match env("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+            Some(endpoint) => endpoint,
+            None => format!("{}/v1/traces", env("OTEL_EXPORTER_OTLP_ENDPOINT")?.trim_end_matches('/')),
+        };
+
+        if let Some(protocol) = env("OTEL_EXPORTER_OTLP_TRACES_PROTOCOL").or_else(|| env("OTEL_EXPORTER_OTLP_PROTOCOL"))
+        {
+            if protocol != "http/json" {
+                warn!("The OTLP protocol `{protocol}` is not supported, the traces are exported with `http/json`");
+            }
+        }
+
+        let mut headers = parse_key_values(&env("OTEL_EXPORTER_OTLP_HEADERS").unwrap_or_default());
+        headers.extend(parse_key_values(&env("OTEL_EXPORTER_OTLP_TRACES_HEADERS").unwrap_or_default()));
+
+        let timeout = env("OTEL_EXPORTER_OTLP_TRACES_TIMEOUT")
+            .or_else(|| env("OTEL_EXPORTER_OTLP_TIMEOUT"))
+            .and_then(|timeout| timeout.parse().ok())
+            .unwrap_or(10_000);
+
+        let mut attributes = parse_key_values(&env("OTEL_RESOURCE_ATTRIBUTES").unwrap_or_default());
+        attributes.retain(|(key, _)| key != "service.name" || env("OTEL_SERVICE_NAME").is_none());
+        if !attributes.iter().any(|(key, _)| key == "service.name") {
+            attributes.push(("service.name".into(), env("OTEL_SERVICE_NAME").unwrap_or_else(|| "vaultwarden".into())));
+        }
+        if let Some(version) = crate::VERSION {
+            attributes.push(("service.version".into(), version.into()));
+        }
+        let resource = json!({
+            "attributes": attributes
+                .into_iter()
+                .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+                .collect::<Vec<_>>(),
+        });
+
+        let (sampler, parent_based) =
+            parse_sampler(env("OTEL_TRACES_SAMPLER").as_deref(), env("OTEL_TRACES_SAMPLER_ARG").as_deref());
+
+        Some(Self {
+            endpoint,
+            headers,
+            timeout: Duration::from_millis(timeout),
+            resource,
+            sampler,
+            parent_based,
+        })
+    }
+
+    fn sample(&self, trace_id: &[u8; 16]) -> bool {
+        match self.sampler {
+            Sampler::AlwaysOn => true,
+            Sampler::AlwaysOff => false,
+            // Like the SDKs, the decision only depends on the trace ID to be the same for all the spans of a trace
+            Sampler::Ratio(ratio) => {
+                let value = u64::from_be_bytes(trace_id[8..].try_into().unwrap()) >> 1;
+                (value as f64) < ratio * (u64::MAX >> 1) as f64
+            }
+        }
+    }
+}
+
+/// Whether the spans are exported, the spans are only created then.
+pub fn enabled() -> bool {
+    SETTINGS.is_some()
+}
+
+/// Starts to export the spans, when an OTLP endpoint is configured.
+pub fn init() {
+    let Some(settings) = &*SETTINGS else {
+        return;
+    };
+    let subscriber = tracing_subscriber::registry().with(OtelLayer);
+    if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
+        error!("Error setting up the OpenTelemetry tracing: {e}");
+        return;
+    }
+    info!("Exporting the traces to {}", settings.endpoint);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+        loop {
+            interval.tick().await;
+            export_spans(settings).await;
+        }
+    });
+}
+
+async fn export_spans(settings: &Settings) {
+    let spans = std::mem::take(&mut *QUEUE.lock().unwrap());
+    let dropped = DROPPED_SPANS.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        warn!("{dropped} OpenTelemetry spans were dropped, the export queue was full");
+    }
+
+    for batch in spans.chunks(EXPORT_BATCH_SIZE) {
+        let body = json!({
+            "resourceSpans": [{
+                "resource": settings.resource,
+                "scopeSpans": [{
+                    "scope": { "name": "vaultwarden", "version": crate::VERSION.unwrap_or_default() },
+                    "spans": batch,
+                }],
+            }],
+        });
+
+        // Not sent with `send_traced`, the export would be traced again
+        let mut request = get_reqwest_client().post(&settings.endpoint).timeout(settings.timeout).json(&body);
+        for (name, value) in &settings.headers {
+            request = request.header(name, value);
+        }
+        match request.send().await {
+            Ok(response) if response.status().is_success() => (),
+            Ok(response) => {
+                warn!("Error exporting {} OpenTelemetry spans: {}", batch.len(), response.status());
+                break;
+            }
+            Err(e) => {
+                warn!("Error exporting {} OpenTelemetry spans: {e}", batch.len());
+                break;
+            }
+        }
+    }
+}
+
+fn queue_span(span: Value) {
+    let mut queue = QUEUE.lock().unwrap();
+    if queue.len() < MAX_QUEUED_SPANS {
+        queue.push(span);
+    } else {
+        DROPPED_SPANS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Parses `00-<trace id>-<parent id>-<flags>`, the IDs made only of zeros are invalid
+fn parse_traceparent(header: &str) -> Option<([u8; 16], [u8; 8], bool)> {
+    let mut parts = header.trim().split('-');
+    let (version, trace_id, parent_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    let trace_id: [u8; 16] = HEXLOWER_PERMISSIVE.decode(trace_id.as_bytes()).ok()?.try_into().ok()?;
+    let parent_id: [u8; 8] = HEXLOWER_PERMISSIVE.decode(parent_id.as_bytes()).ok()?.try_into().ok()?;
+    let flags = HEXLOWER_PERMISSIVE.decode(flags.as_bytes()).ok()?;
+    if flags.len() != 1 || trace_id == [0; 16] || parent_id == [0; 8] {
+        return None;
+    }
+    Some((trace_id, parent_id, flags[0] & 1 == 1))
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+// The data of a span until it's closed, kept in the extensions of the span.
+// The `otel.name`, `otel.kind`, `otel.status_code` and `traceparent` fields aren't attributes.
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_id: Option<[u8; 8]>,
+    sampled: bool,
+    name: String,
+    kind: u8,
+    status: u8,
+    start: SystemTime,
+    attributes: Vec<Value>,
+    traceparent: Option<String>,
+}
+
+impl SpanData {
+    fn record_value(&mut self, field: &Field, value: Value) {
+        let as_str = || value.get("stringValue").and_then(Value::as_str).unwrap_or_default();
+        match field.name() {
+            "otel.name" => self.name = as_str().to_string(),
+            "otel.kind" => {
+                self.kind = match as_str() {
+                    "server" => KIND_SERVER,
+                    "client" => KIND_CLIENT,
+                    _ => KIND_INTERNAL,
+                }
+            }
+            "otel.status_code" => {
+                self.status = match as_str() {
+                    "ok" => STATUS_OK,
+                    "error" => STATUS_ERROR,
+                    _ => STATUS_UNSET,
+                }
+            }
+            "traceparent" => self.traceparent = Some(as_str().to_string()),
+            name => {
+                self.attributes.retain(|attribute| attribute["key"] != name);
+                self.attributes.push(json!({ "key": name, "value": value }));
+            }
+        }
+    }
+
+    fn to_json(&self, end: SystemTime) -> Value {
+        let mut span = json!({
+            "traceId": HEXLOWER.encode(&self.trace_id),
+            "spanId": HEXLOWER.encode(&self.span_id),
+            "name": self.name,
+            "kind": self.kind,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(end),
+            "attributes": self.attributes,
+            "status": { "code": self.status },
+        });
+        if let Some(parent_id) = &self.parent_id {
+            span["parentSpanId"] = HEXLOWER.encode(parent_id).into();
+        }
+        span
+    }
+}
+
+impl Visit for SpanData {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record_value(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record_value(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record_value(field, json!({ "doubleValue": value }));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record_value(field, json!({ "boolValue": value }));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_value(field, json!({ "stringValue": value }));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_value(field, json!({ "stringValue": format!("{value:?}") }));
+    }
+}
+
+// The message of an event with its other fields, to forward it to the logs
+#[derive(Default)]
+struct EventMessage(String);
+
+impl Visit for EventMessage {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.0.insert_str(0, &format!("{value:?}"));
+        } else {
+            let _ = write!(self.0, " {}={value:?}", field.name());
+        }
+    }
+}
+
+struct OtelLayer;
+
+impl<S> Layer<S> for OtelLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    // Only the spans of vaultwarden are exported, the events of all the crates are forwarded to the logs
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        metadata.is_event() || metadata.target().starts_with("vaultwarden")
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let (Some(span), Some(settings)) = (ctx.span(id), &*SETTINGS) else {
+            return;
+        };
+        let mut data = SpanData {
+            trace_id: [0; 16],
+            span_id: get_random_bytes::<8>(),
+            parent_id: None,
+            sampled: false,
+            name: attrs.metadata().name().to_string(),
+            kind: KIND_INTERNAL,
+            status: STATUS_UNSET,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            traceparent: None,
+        };
+        attrs.record(&mut data);
+
+        let parent = span.parent().and_then(|parent| {
+            parent.extensions().get::<SpanData>().map(|parent| (parent.trace_id, parent.span_id, parent.sampled))
+        });
+        let remote_parent = || data.traceparent.as_deref().and_then(parse_traceparent);
+        match parent.or_else(remote_parent) {
+            Some((trace_id, parent_id, sampled)) => {
+                data.trace_id = trace_id;
+                data.parent_id = Some(parent_id);
+                data.sampled = if parent.is_some() || settings.parent_based {
+                    sampled
+                } else {
+                    settings.sample(&trace_id)
+                };
+            }
+            None => {
+                data.trace_id = get_random_bytes::<16>();
+                data.sampled = settings.sample(&data.trace_id);
+            }
+        }
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(data);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            if let Some(data) = span.extensions_mut().remove::<SpanData>() {
+                if data.sampled {
+                    queue_span(data.to_json(SystemTime::now()));
+                }
+            }
+        }
+    }
+
+    // Without a subscriber, `tracing` sends the events to the logs itself, like the ones of lettre and webauthn-rs
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let level = match *metadata.level() {
+            Level::ERROR => log::Level::Error,
+            Level::WARN => log::Level::Warn,
+            Level::INFO => log::Level::Info,
+            Level::DEBUG => log::Level::Debug,
+            Level::TRACE => log::Level::Trace,
+        };
+        let log_metadata = log::Metadata::builder().level(level).target(metadata.target()).build();
+        let logger = log::logger();
+        if !logger.enabled(&log_metadata) {
+            return;
+        }
+        let mut message = EventMessage::default();
+        event.record(&mut message);
+        logger.log(
+            &log::Record::builder()
+                .metadata(log_metadata)
+                .args(format_args!("{}", message.0.trim_start()))
+                .module_path(metadata.module_path())
+                .file(metadata.file())
+                .line(metadata.line())
+                .build(),
+        );
+    }
+}
+
+/// The span of a request, created by the `RequestTracing` fairing.
+struct RequestSpan(Span);
+
+/// The span of the request, which is the parent of the spans of its database queries.
+pub fn request_span(request: &Request<'_>) -> Span {
+    request.local_cache(|| RequestSpan(Span::none())).0.clone()
+}
+
+/// Traces the requests, this fairing is only attached when the spans are exported.
+pub struct RequestTracing;
+
+#[rocket::async_trait]
+impl Fairing for RequestTracing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Tracing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        let method = request.method().as_str();
+        // The path isn't recorded, some of them have IDs or tokens, the route is recorded once it's known
+        let span = info_span!(
+            "http.request",
+            otel.name = method,
+            otel.kind = "server",
+            otel.status_code = Empty,
+            http.request.method = method,
+            http.route = Empty,
+            http.response.status_code = Empty,
+            traceparent = request.headers().get_one("traceparent").unwrap_or_default(),
+        );
+        request.local_cache(|| RequestSpan(span));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let span = request_span(request);
+        if let Some(route) = request.route() {
+            let route = route.uri.to_string();
+            span.record("otel.name", format!("{} {route}", request.method()).as_str());
+            span.record("http.route", route.as_str());
+        }
+        let status = response.status();
+        span.record("http.response.status_code", status.code);
+        if status.code >= 500 {
+            span.record("otel.status_code", "error");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_traceparent() {
+        let (trace_id, parent_id, sampled) =
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(HEXLOWER.encode(&trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(HEXLOWER.encode(&parent_id), "00f067aa0ba902b7");
+        assert!(sampled);
+
+        assert!(parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra").is_none());
+        assert!(parse_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none());
+        assert!(parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba9-01").is_none());
+    }
+
+    #[test]
+    fn test_parse_key_values() {
+        assert_eq!(
+            parse_key_values("api-key=secret%20value, deployment.environment = prod,invalid"),
+            [
+                ("api-key".to_string(), "secret value".to_string()),
+                ("deployment.environment".to_string(), "prod".to_string())
+            ]
+        );
+        assert_eq!(parse_sampler(Some("traceidratio"), Some("2")), (Sampler::Ratio(1.0), false));
+    }
+}
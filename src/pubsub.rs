@@ -0,0 +1,442 @@
+//
+// Pub/sub bridge relaying the WebSocket notifications between several Vaultwarden instances
+//
+// When running several instances behind a load balancer, the clients of a user are connected to different
+// instances. Every WebSocket notification is also published on a Redis channel or a NATS subject, and the
+// notifications published by the other instances are sent to the clients connected to this one.
+// Only the WebSocket notifications are relayed, the push notifications are already sent by the instance
+// handling the request. The rows removed from the database cache are also published, so the other instances
+// don't keep them, see `crate::db::cache`.
+//
+// These are minimal clients of the Redis (RESP2) and NATS protocols, over plain TCP only.
+// Ref: https://redis.io/docs/reference/protocol-spec/
+// Ref: https://docs.nats.io/reference/reference-protocols/nats-protocol
+//
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use once_cell::sync::{Lazy, OnceCell};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::{mpsc, Mutex},
+};
+use url::Url;
+
+use crate::{
+    api::{WS_ANONYMOUS_SUBSCRIPTIONS, WS_USERS},
+    db::cache::{self, Invalidation},
+    error::Error,
+    CONFIG,
+};
+
+// Messages larger than this are refused, a notification is expected to be much smaller
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+// Number of notifications waiting to be published, they are dropped when the broker can't keep up
+const PUBLISH_QUEUE_SIZE: usize = 1000;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// Identifies this instance, so the notifications it published itself are ignored
+static INSTANCE_ID: Lazy<String> = Lazy::new(crate::util::get_uuid);
+
+static PUBLISH_QUEUE: OnceCell<mpsc::Sender<Vec<u8>>> = OnceCell::new();
+
+static PUBSUB_MESSAGES_PUBLISHED: AtomicU64 = AtomicU64::new(0);
+static PUBSUB_MESSAGES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static PUBSUB_MESSAGES_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Copy, Clone)]
+enum Broker {
+    Redis,
+    Nats,
+}
+
+impl Broker {
+    fn from_url(url: &Url) -> Option<Self> {
+        match url.scheme() {
+            "redis" => Some(Self::Redis),
+            "nats" => Some(Self::Nats),
+            _ => None,
+        }
+    }
+
+    fn default_port(self) -> u16 {
+        match self {
+            Self::Redis => 6379,
+            Self::Nats => 4222,
+        }
+    }
+}
+
+// The notifications are relayed as they are sent to the clients, with the user or the anonymous token they are for.
+// A removal from the database cache has no data, only the name of the cache.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    origin: String,
+    user: Option<String>,
+    anonymous: Option<String>,
+    #[serde(default)]
+    data: String,
+    cache: Option<(String, Invalidation)>,
+}
+
+/// Checks the `WEBSOCKET_PUBSUB_URL` config value.
+pub fn validate_url(url: &str) -> Result<(), String> {
+    let url = Url::parse(url).map_err(|e| format!("`WEBSOCKET_PUBSUB_URL` is not a valid URL: {e}"))?;
+    if Broker::from_url(&url).is_none() {
+        return Err("`WEBSOCKET_PUBSUB_URL` must start with redis:// or nats://, TLS isn't supported".to_string());
+    }
+    if url.host_str().is_none() {
+        return Err("`WEBSOCKET_PUBSUB_URL` must contain a host".to_string());
+    }
+    Ok(())
+}
+
+/// Starts relaying the notifications and the cache removals, if a broker is configured.
+/// The cache removals are relayed even when WebSockets are disabled.
+pub fn start() {
+    let Some(url) = CONFIG.websocket_pubsub_url() else {
+        return;
+    };
+    // Already validated with the config
+    let Some((url, broker)) = Url::parse(&url).ok().and_then(|url| Broker::from_url(&url).map(|b| (url, b))) else {
+        return;
+    };
+
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(PUBLISH_QUEUE_SIZE);
+    if PUBLISH_QUEUE.set(tx).is_err() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        loop {
+            match run_session(&url, broker, &mut rx).await {
+                Ok(()) => return,
+                Err(e) => error!("Error relaying the notifications: {e:?}"),
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    });
+    info!("Relaying the notifications through {}", url.host_str().unwrap_or_default());
+}
+
+/// Publishes a notification sent to the clients of a user, for the other instances.
+pub fn publish_user_update(user_uuid: &str, data: &[u8]) {
+    publish(Some(user_uuid), None, data);
+}
+
+/// Publishes a notification sent to an anonymous client, for the other instances.
+pub fn publish_anonymous_update(token: &str, data: &[u8]) {
+    publish(None, Some(token), data);
+}
+
+/// Publishes a removal from a database cache, for the other instances.
+pub fn publish_cache_invalidation(cache: &str, invalidation: Invalidation) {
+    if PUBLISH_QUEUE.get().is_none() {
+        return;
+    }
+    send(Envelope {
+        origin: INSTANCE_ID.clone(),
+        user: None,
+        anonymous: None,
+        data: String::new(),
+        cache: Some((cache.to_string(), invalidation)),
+    });
+}
+
+fn publish(user: Option<&str>, anonymous: Option<&str>, data: &[u8]) {
+    if PUBLISH_QUEUE.get().is_none() {
+        return;
+    }
+    send(Envelope {
+        origin: INSTANCE_ID.clone(),
+        user: user.map(str::to_string),
+        anonymous: anonymous.map(str::to_string),
+        data: data_encoding::BASE64.encode(data),
+        cache: None,
+    });
+}
+
+fn send(envelope: Envelope) {
+    let Some(queue) = PUBLISH_QUEUE.get() else {
+        return;
+    };
+    let message = serde_json::to_vec(&envelope).expect("Error encoding pub/sub message");
+    if queue.try_send(message).is_err() {
+        PUBSUB_MESSAGES_DROPPED.fetch_add(1, Ordering::Relaxed);
+        warn!("The pub/sub broker can't keep up, dropping a notification");
+    }
+}
+
+/// The counters of the relayed notifications, for the admin diagnostics.
+pub fn stats() -> serde_json::Value {
+    json!({
+        "enabled": PUBLISH_QUEUE.get().is_some(),
+        "messages_published": PUBSUB_MESSAGES_PUBLISHED.load(Ordering::Relaxed),
+        "messages_received": PUBSUB_MESSAGES_RECEIVED.load(Ordering::Relaxed),
+        "messages_dropped": PUBSUB_MESSAGES_DROPPED.load(Ordering::Relaxed),
+    })
+}
+
+// Sends a notification published by another instance to the clients connected here
+fn receive(message: &[u8]) {
+    let envelope: Envelope = match serde_json::from_slice(message) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            warn!("Invalid pub/sub message: {e}");
+            return;
+        }
+    };
+    if envelope.origin == *INSTANCE_ID {
+        return;
+    }
+    if let Some((cache_name, invalidation)) = &envelope.cache {
+        PUBSUB_MESSAGES_RECEIVED.fetch_add(1, Ordering::Relaxed);
+        cache::invalidate_remote(cache_name, invalidation);
+        return;
+    }
+    let Ok(data) = data_encoding::BASE64.decode(envelope.data.as_bytes()) else {
+        warn!("Invalid pub/sub message data");
+        return;
+    };
+
+    PUBSUB_MESSAGES_RECEIVED.fetch_add(1, Ordering::Relaxed);
+    if let Some(user_uuid) = envelope.user {
+        WS_USERS.send_local_update(&user_uuid, &data);
+    }
+    if let Some(token) = envelope.anonymous {
+        WS_ANONYMOUS_SUBSCRIPTIONS.send_local_update(&token, &data);
+    }
+}
+
+type Reader = BufReader<OwnedReadHalf>;
+
+/// A plain Redis connection, also used by the icon cache.
+pub struct RedisConnection {
+    reader: Reader,
+    writer: OwnedWriteHalf,
+}
+
+impl RedisConnection {
+    pub async fn connect(url: &Url) -> Result<Self, Error> {
+        let (reader, writer) = connect(url, Broker::Redis).await?;
+        Ok(Self {
+            reader,
+            writer,
+        })
+    }
+
+    /// Sends the command and returns its reply.
+    pub async fn command(&mut self, args: &[&[u8]]) -> Result<Vec<Vec<u8>>, Error> {
+        self.writer.write_all(&redis_command(args)).await?;
+        read_redis_reply(&mut self.reader).await
+    }
+}
+
+// Subscribes, then publishes the queued notifications until the connection fails.
+// With Redis a subscribed connection can't publish, so a second connection is used for that.
+async fn run_session(url: &Url, broker: Broker, queue: &mut mpsc::Receiver<Vec<u8>>) -> Result<(), Error> {
+    let channel = CONFIG.websocket_pubsub_channel();
+
+    let (reader, mut writer) = connect(url, broker).await?;
+    match broker {
+        Broker::Redis => writer.write_all(&redis_command(&[b"SUBSCRIBE", channel.as_bytes()])).await?,
+        Broker::Nats => writer.write_all(format!("SUB {channel} 1\r\n").as_bytes()).await?,
+    }
+    let writer = Mutex::new(writer);
+
+    let publisher = match broker {
+        Broker::Redis => Some(connect(url, broker).await?),
+        Broker::Nats => None,
+    };
+    info!("Connected to the pub/sub broker {}", url.host_str().unwrap_or_default());
+    // The removals published while this instance wasn't subscribed are lost
+    cache::clear_local();
+
+    tokio::select! {
+        res = receive_loop(broker, reader, &writer) => res,
+        res = publish_loop(broker, &channel, publisher, &writer, queue) => res,
+    }
+}
+
+async fn receive_loop(broker: Broker, mut reader: Reader, writer: &Mutex<OwnedWriteHalf>) -> Result<(), Error> {
+    loop {
+        match broker {
+            Broker::Redis => {
+                let reply = read_redis_reply(&mut reader).await?;
+                // A message is ["message", channel, payload], the rest are the confirmations of the subscription
+                if reply.len() == 3 && reply[0] == b"message" {
+                    receive(&reply[2]);
+                }
+            }
+            Broker::Nats => {
+                let line = read_line(&mut reader).await?;
+                let line = String::from_utf8_lossy(&line);
+                if line.starts_with("MSG ") {
+                    // MSG <subject> <sid> [reply-to] <#bytes>
+                    let Some(Ok(len)) = line.split_whitespace().last().map(str::parse::<usize>) else {
+                        err!("Invalid NATS message", line.to_string())
+                    };
+                    receive(&read_payload(&mut reader, len).await?);
+                } else if line == "PING" {
+                    writer.lock().await.write_all(b"PONG\r\n").await?;
+                } else if line.starts_with("-ERR") {
+                    err!("NATS error", line.to_string())
+                }
+            }
+        }
+    }
+}
+
+async fn publish_loop(
+    broker: Broker,
+    channel: &str,
+    mut publisher: Option<(Reader, OwnedWriteHalf)>,
+    writer: &Mutex<OwnedWriteHalf>,
+    queue: &mut mpsc::Receiver<Vec<u8>>,
+) -> Result<(), Error> {
+    while let Some(message) = queue.recv().await {
+        match (broker, publisher.as_mut()) {
+            (Broker::Redis, Some((reader, redis_writer))) => {
+                redis_writer.write_all(&redis_command(&[b"PUBLISH", channel.as_bytes(), &message])).await?;
+                read_redis_reply(reader).await?;
+            }
+            _ => {
+                let mut command = format!("PUB {channel} {}\r\n", message.len()).into_bytes();
+                command.extend_from_slice(&message);
+                command.extend_from_slice(b"\r\n");
+                writer.lock().await.write_all(&command).await?;
+            }
+        }
+        PUBSUB_MESSAGES_PUBLISHED.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+async fn connect(url: &Url, broker: Broker) -> Result<(Reader, OwnedWriteHalf), Error> {
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port().unwrap_or(broker.default_port());
+    let stream = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port))).await {
+        Ok(stream) => stream?,
+        Err(_) => err!(format!("Timed out connecting to the pub/sub broker {host}:{port}")),
+    };
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    match broker {
+        Broker::Redis => {
+            if let Some(password) = url.password() {
+                let mut auth: Vec<&[u8]> = vec![b"AUTH".as_slice()];
+                if !url.username().is_empty() {
+                    auth.push(url.username().as_bytes());
+                }
+                auth.push(password.as_bytes());
+                writer.write_all(&redis_command(&auth)).await?;
+                read_redis_reply(&mut reader).await?;
+            }
+        }
+        Broker::Nats => {
+            // The server starts with an INFO message
+            let info = read_line(&mut reader).await?;
+            if !info.starts_with(b"INFO") {
+                err!("Unexpected NATS server greeting", String::from_utf8_lossy(&info).to_string())
+            }
+            let mut options = json!({
+                "verbose": false,
+                "pedantic": false,
+                "name": "vaultwarden",
+            });
+            if let Some(password) = url.password() {
+                options["user"] = json!(url.username());
+                options["pass"] = json!(password);
+            }
+            writer.write_all(format!("CONNECT {options}\r\n").as_bytes()).await?;
+        }
+    }
+    Ok((reader, writer))
+}
+
+fn redis_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+    command
+}
+
+// Reads a line, without the line ending
+async fn read_line(reader: &mut Reader) -> Result<Vec<u8>, Error> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line).await? == 0 {
+        err!("Connection closed by the pub/sub broker")
+    }
+    while line.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+// Reads a payload of the given size, followed by a line ending
+async fn read_payload(reader: &mut Reader, len: usize) -> Result<Vec<u8>, Error> {
+    if len > MAX_MESSAGE_SIZE {
+        err!(format!("Pub/sub message too large ({len} bytes)"))
+    }
+    let mut payload = vec![0u8; len + 2];
+    reader.read_exact(&mut payload).await?;
+    payload.truncate(len);
+    Ok(payload)
+}
+
+// Reads a reply, the elements of an array being returned as a list.
+// Nested arrays aren't supported, they aren't used by the commands sent here.
+async fn read_redis_reply(reader: &mut Reader) -> Result<Vec<Vec<u8>>, Error> {
+    let line = read_line(reader).await?;
+    let Some((kind, value)) = line.split_first() else {
+        err!("Invalid Redis reply")
+    };
+    match kind {
+        b'*' => {
+            let count = parse_redis_length(value)?.unwrap_or(0);
+            let mut items = Vec::with_capacity(count.min(16));
+            for _ in 0..count {
+                let line = read_line(reader).await?;
+                match line.split_first() {
+                    Some((b'$', len)) => items.push(read_redis_bulk(reader, len).await?),
+                    Some((b'-', error)) => err!("Redis error", String::from_utf8_lossy(error).to_string()),
+                    Some((_, value)) => items.push(value.to_vec()),
+                    None => err!("Invalid Redis reply"),
+                }
+            }
+            Ok(items)
+        }
+        b'$' => Ok(vec![read_redis_bulk(reader, value).await?]),
+        b'-' => err!("Redis error", String::from_utf8_lossy(value).to_string()),
+        _ => Ok(vec![value.to_vec()]),
+    }
+}
+
+// A null bulk string is handled as an empty one
+async fn read_redis_bulk(reader: &mut Reader, len: &[u8]) -> Result<Vec<u8>, Error> {
+    match parse_redis_length(len)? {
+        Some(len) => read_payload(reader, len).await,
+        None => Ok(Vec::new()),
+    }
+}
+
+// A negative length is a null value
+fn parse_redis_length(value: &[u8]) -> Result<Option<usize>, Error> {
+    match std::str::from_utf8(value).ok().and_then(|v| v.parse::<i64>().ok()) {
+        Some(len) => Ok(usize::try_from(len).ok()),
+        None => err!("Invalid Redis reply length"),
+    }
+}
@@ -0,0 +1,181 @@
+//
+// Latency and error statistics of the routes, for the admin diagnostics
+//
+// The duration and the status of the latest requests of each route are kept in memory for a rolling window, the
+// percentiles and the error rate are computed when they're shown. The errors are the 5xx responses, the error rate is
+// compared with `ROUTE_STATS_ERROR_BUDGET`. The statistics are reset when the server restarts.
+// The authentication of the requests is also timed, with and without a cached session (see `AUTH_SESSION_CACHE_TTL`),
+// to show the time saved by the cache.
+//
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Data, Request, Response,
+};
+use serde_json::Value;
+
+use crate::CONFIG;
+
+pub const WINDOW: Duration = Duration::from_secs(15 * 60);
+// The oldest requests of the busiest routes are dropped above this, to bound the memory used
+const MAX_SAMPLES_PER_ROUTE: usize = 1000;
+
+struct Sample {
+    at: Instant,
+    duration: Duration,
+    error: bool,
+}
+
+static SAMPLES: Lazy<Mutex<HashMap<String, VecDeque<Sample>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn record(route: String, duration: Duration, error: bool) {
+    let now = Instant::now();
+    let mut samples = SAMPLES.lock().unwrap();
+    let route_samples = samples.entry(route).or_default();
+    while route_samples.front().is_some_and(|s| now.duration_since(s.at) > WINDOW)
+        || route_samples.len() >= MAX_SAMPLES_PER_ROUTE
+    {
+        route_samples.pop_front();
+    }
+    route_samples.push_back(Sample {
+        at: now,
+        duration,
+        error,
+    });
+}
+
+// The number and total duration of the authentications, without and with a cached session
+static AUTH_TIMINGS: Mutex<[(u64, Duration); 2]> = Mutex::new([(0, Duration::ZERO); 2]);
+
+/// Records the time taken to authenticate a request, from its token to its device and user.
+pub fn record_auth(cached: bool, duration: Duration) {
+    let timing = &mut AUTH_TIMINGS.lock().unwrap()[usize::from(cached)];
+    timing.0 += 1;
+    timing.1 += duration;
+}
+
+fn auth_json() -> Value {
+    let timings = *AUTH_TIMINGS.lock().unwrap();
+    let average = |(count, total): (u64, Duration)| match count {
+        0 => Duration::ZERO,
+        count => Duration::from_secs_f64(total.as_secs_f64() / count as f64),
+    };
+    let (uncached, cached) = (average(timings[0]), average(timings[1]));
+    let saved = if timings[0].0 > 0 && timings[1].0 > 0 {
+        uncached.saturating_sub(cached)
+    } else {
+        Duration::ZERO
+    };
+    json!({
+        "uncached_requests": timings[0].0,
+        "uncached_avg_ms": millis(uncached),
+        "cached_requests": timings[1].0,
+        "cached_avg_ms": millis(cached),
+        "saved_avg_ms": millis(saved),
+    })
+}
+
+// The nearest-rank percentile of the sorted durations
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+fn millis(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 10_000.0).round() / 10.0
+}
+
+/// The statistics of the routes with requests in the window, the slowest ones first.
+pub fn stats_json() -> Value {
+    let now = Instant::now();
+    // In errors per million requests
+    let budget = CONFIG.route_stats_error_budget() as f64 / 1_000_000.0;
+
+    let mut routes: Vec<Value> = {
+        let samples = SAMPLES.lock().unwrap();
+        samples
+            .iter()
+            .filter_map(|(route, samples)| {
+                let recent: Vec<&Sample> = samples.iter().filter(|s| now.duration_since(s.at) <= WINDOW).collect();
+                if recent.is_empty() {
+                    return None;
+                }
+                let mut durations: Vec<Duration> = recent.iter().map(|s| s.duration).collect();
+                durations.sort_unstable();
+                let errors = recent.iter().filter(|s| s.error).count();
+                let error_rate = errors as f64 / recent.len() as f64;
+                Some(json!({
+                    "route": route,
+                    "requests": recent.len(),
+                    "p50_ms": millis(percentile(&durations, 50)),
+                    "p95_ms": millis(percentile(&durations, 95)),
+                    "max_ms": millis(*durations.last().unwrap()),
+                    "errors": errors,
+                    "error_rate_percent": (error_rate * 10_000.0).round() / 100.0,
+                    // Above 100, the errors of the route used more than its budget
+                    "budget_used_percent": if budget > 0.0 { (error_rate / budget * 100.0).round() } else { 0.0 },
+                }))
+            })
+            .collect()
+    };
+    routes.sort_by(|a, b| b["p95_ms"].as_f64().partial_cmp(&a["p95_ms"].as_f64()).unwrap());
+
+    json!({
+        "window_minutes": WINDOW.as_secs() / 60,
+        "error_budget_percent": budget * 100.0,
+        "routes": routes,
+        "auth": auth_json(),
+    })
+}
+
+struct RequestStart(Instant);
+
+/// Measures the duration of the requests, until their response is ready to be sent.
+pub struct RouteStats;
+
+#[rocket::async_trait]
+impl Fairing for RouteStats {
+    fn info(&self) -> Info {
+        Info {
+            name: "Route Statistics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let start = request.local_cache(|| RequestStart(Instant::now())).0;
+        // The routes are grouped by their template, the IDs in the paths don't make a new group
+        let route = match request.route() {
+            Some(route) => format!("{} {}", route.method, route.uri),
+            None => format!("{} (no route)", request.method()),
+        };
+        record(route, start.elapsed(), response.status().code >= 500);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let durations: Vec<Duration> = (1..=20).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&durations, 50), Duration::from_millis(10));
+        assert_eq!(percentile(&durations, 95), Duration::from_millis(19));
+        assert_eq!(percentile(&durations[..1], 95), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 50), Duration::ZERO);
+    }
+}
@@ -0,0 +1,144 @@
+//
+// S3 compatible object storage, used to store the Send files, the backups and the icon cache
+//
+// Requests are authenticated using AWS Signature Version 4 pre-signed URLs, see `crate::sigv4`.
+// This allows handing out time-limited URLs to the clients, so large downloads
+// go directly to the object storage instead of passing through Vaultwarden.
+//
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+use percent_encoding::{utf8_percent_encode, AsciiSet};
+use reqwest::{header, Method, StatusCode, Url};
+
+use crate::{
+    error::{Error, MapResult},
+    http_client::{get_reqwest_client_builder, TracedSend},
+    sigv4, CONFIG,
+};
+
+// Same as the encoding of the signature, but keep the `/` path separators intact
+const PATH_ENCODE_SET: &AsciiSet = &sigv4::URI_ENCODE_SET.remove(b'/');
+// Uploads and deletes are done by Vaultwarden itself, so the URL is used right away
+const INTERNAL_PRESIGN_EXPIRATION: i64 = 300;
+
+/// Returns true if the S3 storage for Sends has been configured.
+pub fn sends_enabled() -> bool {
+    CONFIG._enable_s3() && CONFIG.s3_bucket().is_some()
+}
+
+/// The object key used to store a Send file.
+pub fn send_file_key(send_uuid: &str, file_id: &str) -> String {
+    format!("sends/{send_uuid}/{file_id}")
+}
+
+/// Generates a pre-signed URL for the given method and object key, valid for `expires_in` seconds.
+pub fn presign_url(method: &Method, key: &str, expires_in: i64) -> Result<String, Error> {
+    presign(method, key, expires_in, None)
+}
+
+/// Generates a pre-signed PUT URL for the given object key, valid for `expires_in` seconds.
+/// The `Content-Length` is signed too, so the object storage rejects the uploads of another size.
+pub fn presign_upload_url(key: &str, content_length: u64, expires_in: i64) -> Result<String, Error> {
+    presign(&Method::PUT, key, expires_in, Some(content_length))
+}
+
+fn presign(method: &Method, key: &str, expires_in: i64, content_length: Option<u64>) -> Result<String, Error> {
+    let (Some(bucket), Some(access_key_id), Some(secret_access_key)) =
+        (CONFIG.s3_bucket(), CONFIG.s3_access_key_id(), CONFIG.s3_secret_access_key())
+    else {
+        err!("S3 storage is not configured")
+    };
+
+    let endpoint = Url::parse(&CONFIG.s3_endpoint()).map_res("Invalid S3 endpoint")?;
+    let Some(endpoint_host) = endpoint.host_str() else {
+        err!("S3 endpoint has no host")
+    };
+    let host = match endpoint.port() {
+        Some(port) => format!("{endpoint_host}:{port}"),
+        None => endpoint_host.to_string(),
+    };
+
+    let key = utf8_percent_encode(key.trim_start_matches('/'), PATH_ENCODE_SET).to_string();
+    let (host, canonical_uri) = if CONFIG.s3_path_style() {
+        (host, format!("/{bucket}/{key}"))
+    } else {
+        (format!("{bucket}.{host}"), format!("/{key}"))
+    };
+
+    let content_length = content_length.map(|length| length.to_string());
+    let headers: Vec<(&str, &str)> = content_length.iter().map(|length| ("content-length", length.as_str())).collect();
+    let creds = sigv4::Credentials {
+        access_key_id: &access_key_id,
+        secret_access_key: &secret_access_key,
+        region: &CONFIG.s3_region(),
+        service: "s3",
+    };
+    let req = sigv4::SignRequest {
+        method: method.as_str(),
+        host: &host,
+        canonical_uri: &canonical_uri,
+        headers: &headers,
+        now: Utc::now(),
+    };
+    Ok(sigv4::presign_url(&creds, &req, endpoint.scheme(), expires_in))
+}
+
+fn get_s3_client() -> &'static reqwest::Client {
+    use once_cell::sync::Lazy;
+    // Uploads can be large, so don't use the default 10 second timeout
+    static INSTANCE: Lazy<reqwest::Client> = Lazy::new(|| {
+        get_reqwest_client_builder()
+            .timeout(std::time::Duration::from_secs(CONFIG.s3_upload_timeout()))
+            .build()
+            .expect("Failed to build S3 client")
+    });
+    &INSTANCE
+}
+
+/// Uploads a local file to the object storage using a pre-signed PUT URL.
+pub async fn upload_file(key: &str, path: &std::path::Path) -> Result<(), Error> {
+    let file = tokio::fs::File::open(path).await?;
+    let size = file.metadata().await?.len();
+
+    let url = presign_url(&Method::PUT, key, INTERNAL_PRESIGN_EXPIRATION)?;
+    get_s3_client().put(url).header(header::CONTENT_LENGTH, size).body(file).send_traced().await?.error_for_status()?;
+    Ok(())
+}
+
+/// Deletes an object from the object storage using a pre-signed DELETE URL.
+pub async fn delete_object(key: &str) -> Result<(), Error> {
+    let url = presign_url(&Method::DELETE, key, INTERNAL_PRESIGN_EXPIRATION)?;
+    get_s3_client().delete(url).send_traced().await?.error_for_status()?;
+    Ok(())
+}
+
+/// Uploads the data to the object storage using a pre-signed PUT URL.
+pub async fn put_object(key: &str, data: Vec<u8>) -> Result<(), Error> {
+    let url = presign_url(&Method::PUT, key, INTERNAL_PRESIGN_EXPIRATION)?;
+    get_s3_client()
+        .put(url)
+        .header(header::CONTENT_LENGTH, data.len())
+        .body(data)
+        .send_traced()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Downloads an object using a pre-signed GET URL, with its last modification time. Returns None if it doesn't exist.
+pub async fn get_object(key: &str) -> Result<Option<(Vec<u8>, Option<SystemTime>)>, Error> {
+    let url = presign_url(&Method::GET, key, INTERNAL_PRESIGN_EXPIRATION)?;
+    let response = get_s3_client().get(url).send_traced().await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let response = response.error_for_status()?;
+    let modified = response
+        .headers()
+        .get(header::LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+        .map(SystemTime::from);
+    Ok(Some((response.bytes().await?.to_vec(), modified)))
+}
@@ -0,0 +1,255 @@
+//
+// Config values fetched from an external secret store at startup
+//
+// The value of any setting, from the environment or from its `_FILE`, can reference a secret instead:
+// - `vault://<path>#<key>`, a key of a secret of HashiCorp Vault, read from `SECRETS_VAULT_ADDR` with
+//   `SECRETS_VAULT_TOKEN`. The KV version 1 and 2 engines are supported, the path includes the mount, like
+//   `secret/data/vaultwarden` for the version 2.
+// - `aws-sm://<secret id>[#<key>]`, a secret of AWS Secrets Manager, or a key of its JSON, read in
+//   `SECRETS_AWS_REGION` with `SECRETS_AWS_ACCESS_KEY_ID` and `SECRETS_AWS_SECRET_ACCESS_KEY`.
+// The secrets are fetched while the configuration is loaded, before `CONFIG` exists, so the settings of the stores are
+// only read from the environment. Each secret is fetched once, a secret which can't be fetched stops the startup.
+//
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+use crate::{http_client::get_reqwest_client_builder, sigv4, util::read_env_str_value};
+
+const VAULT_PREFIX: &str = "vault://";
+const AWS_PREFIX: &str = "aws-sm://";
+
+// The fetched secrets, by their reference without the key
+static SECRETS: Lazy<Mutex<HashMap<String, Value>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, PartialEq)]
+enum Reference<'a> {
+    Vault {
+        path: &'a str,
+        key: &'a str,
+    },
+    Aws {
+        secret_id: &'a str,
+        key: Option<&'a str>,
+    },
+}
+
+fn parse_reference(value: &str) -> Option<Result<Reference<'_>, String>> {
+    if let Some(reference) = value.strip_prefix(VAULT_PREFIX) {
+        return Some(match reference.split_once('#') {
+            Some((path, key)) if !path.is_empty() && !key.is_empty() => Ok(Reference::Vault {
+                path: path.trim_matches('/'),
+                key,
+            }),
+            _ => Err(format!("`{value}` needs to be like `{VAULT_PREFIX}<path>#<key>`")),
+        });
+    }
+    if let Some(reference) = value.strip_prefix(AWS_PREFIX) {
+        let (secret_id, key) = match reference.split_once('#') {
+            Some((secret_id, key)) => (secret_id, Some(key).filter(|key| !key.is_empty())),
+            None => (reference, None),
+        };
+        if secret_id.is_empty() {
+            return Some(Err(format!("`{value}` needs to be like `{AWS_PREFIX}<secret id>#<key>`")));
+        }
+        return Some(Ok(Reference::Aws {
+            secret_id,
+            key,
+        }));
+    }
+    None
+}
+
+/// Replaces the value of the setting by its secret when it references one, and stops the startup when it can't be
+/// fetched.
+pub fn resolve(name: &str, value: String) -> String {
+    let reference = match parse_reference(&value) {
+        None => return value,
+        Some(Ok(reference)) => reference,
+        Some(Err(e)) => panic!("Invalid secret reference for {name}: {e}"),
+    };
+    match fetch(&reference) {
+        Ok(secret) => secret,
+        Err(e) => panic!("Failed to fetch the secret of {name}: {e}"),
+    }
+}
+
+// The settings of the stores can't reference secrets themselves
+fn env(name: &str) -> Result<String, String> {
+    read_env_str_value(name).filter(|value| !value.is_empty()).ok_or_else(|| format!("`{name}` needs to be set"))
+}
+
+fn fetch(reference: &Reference<'_>) -> Result<String, String> {
+    let (cache_key, key) = match reference {
+        Reference::Vault {
+            path,
+            key,
+        } => (format!("{VAULT_PREFIX}{path}"), Some(*key)),
+        Reference::Aws {
+            secret_id,
+            key,
+        } => (format!("{AWS_PREFIX}{secret_id}"), *key),
+    };
+
+    let cached = SECRETS.lock().unwrap().get(&cache_key).cloned();
+    let secret = match cached {
+        Some(secret) => secret,
+        None => {
+            let secret = match reference {
+                Reference::Vault {
+                    path,
+                    ..
+                } => block_on(fetch_vault(path.to_string()))?,
+                Reference::Aws {
+                    secret_id,
+                    ..
+                } => block_on(fetch_aws(secret_id.to_string()))?,
+            };
+            SECRETS.lock().unwrap().insert(cache_key, secret.clone());
+            secret
+        }
+    };
+
+    match (key, &secret) {
+        (None, Value::String(value)) => Ok(value.clone()),
+        (None, _) => Err(String::from("The secret is JSON, the key of the value is missing")),
+        (Some(key), secret) => match secret.get(key) {
+            Some(Value::String(value)) => Ok(value.clone()),
+            Some(value) if !value.is_null() => Ok(value.to_string()),
+            _ => Err(format!("The secret has no `{key}` key")),
+        },
+    }
+}
+
+// The configuration can be loaded in the async runtime, which can't be blocked, so the requests run in their own thread
+fn block_on<F>(future: F) -> Result<Value, String>
+where
+    F: std::future::Future<Output = Result<Value, String>> + Send + 'static,
+{
+    std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().map_err(|e| e.to_string())?.block_on(future)
+    })
+    .join()
+    .unwrap_or_else(|_| Err(String::from("The request panicked")))
+}
+
+async fn fetch_vault(path: String) -> Result<Value, String> {
+    let addr = env("SECRETS_VAULT_ADDR")?;
+    let token = env("SECRETS_VAULT_TOKEN")?;
+
+    let client = get_reqwest_client_builder().build().map_err(|e| e.to_string())?;
+    let response = client
+        .get(format!("{}/v1/{path}", addr.trim_end_matches('/')))
+        .header("X-Vault-Token", token)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("Vault request failed: {e}"))?;
+    let body: Value = response.json().await.map_err(|e| format!("Invalid Vault response: {e}"))?;
+
+    // The KV version 2 engine nests the values of the secret in its metadata
+    match &body["data"]["data"] {
+        Value::Object(_) => Ok(body["data"]["data"].clone()),
+        _ => match &body["data"] {
+            Value::Object(_) => Ok(body["data"].clone()),
+            _ => Err(String::from("The Vault response has no data")),
+        },
+    }
+}
+
+async fn fetch_aws(secret_id: String) -> Result<Value, String> {
+    let region = env("SECRETS_AWS_REGION")?;
+    let access_key_id = env("SECRETS_AWS_ACCESS_KEY_ID")?;
+    let secret_access_key = env("SECRETS_AWS_SECRET_ACCESS_KEY")?;
+    let session_token = read_env_str_value("SECRETS_AWS_SESSION_TOKEN").filter(|token| !token.is_empty());
+
+    let host = format!("secretsmanager.{region}.amazonaws.com");
+    let body = json!({ "SecretId": secret_id }).to_string();
+    let target = "secretsmanager.GetSecretValue";
+    let now = Utc::now();
+    let amz_date = sigv4::amz_date(&now);
+
+    let mut headers = vec![
+        ("content-type", "application/x-amz-json-1.1"),
+        ("x-amz-date", amz_date.as_str()),
+        ("x-amz-target", target),
+    ];
+    if let Some(token) = &session_token {
+        headers.push(("x-amz-security-token", token.as_str()));
+    }
+    let creds = sigv4::Credentials {
+        access_key_id: &access_key_id,
+        secret_access_key: &secret_access_key,
+        region: &region,
+        service: "secretsmanager",
+    };
+    let req = sigv4::SignRequest {
+        method: "POST",
+        host: &host,
+        canonical_uri: "/",
+        headers: &headers,
+        now,
+    };
+    let authorization = sigv4::authorization(&creds, &req, "", body.as_bytes());
+
+    let client = get_reqwest_client_builder().build().map_err(|e| e.to_string())?;
+    let mut request = client
+        .post(format!("https://{host}/"))
+        .header("Content-Type", "application/x-amz-json-1.1")
+        .header("X-Amz-Date", &amz_date)
+        .header("X-Amz-Target", target)
+        .header("Authorization", authorization);
+    if let Some(token) = &session_token {
+        request = request.header("X-Amz-Security-Token", token);
+    }
+    let response = request
+        .body(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("AWS Secrets Manager request failed: {e}"))?;
+    let body: Value = response.json().await.map_err(|e| format!("Invalid AWS Secrets Manager response: {e}"))?;
+
+    // A secret with keys is stored as a JSON object in its string
+    match body["SecretString"].as_str() {
+        Some(secret) => Ok(serde_json::from_str::<Value>(secret)
+            .ok()
+            .filter(Value::is_object)
+            .unwrap_or_else(|| Value::String(secret.to_string()))),
+        None => Err(String::from("The secret has no string value, binary secrets aren't supported")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reference() {
+        assert_eq!(parse_reference("hunter2"), None);
+        assert_eq!(
+            parse_reference("vault://secret/data/vaultwarden#smtp_password"),
+            Some(Ok(Reference::Vault {
+                path: "secret/data/vaultwarden",
+                key: "smtp_password"
+            }))
+        );
+        assert!(matches!(parse_reference("vault://secret/data/vaultwarden"), Some(Err(_))));
+        assert_eq!(
+            parse_reference("aws-sm://prod/vaultwarden#admin_token"),
+            Some(Ok(Reference::Aws {
+                secret_id: "prod/vaultwarden",
+                key: Some("admin_token")
+            }))
+        );
+        assert_eq!(
+            parse_reference("aws-sm://arn:aws:secretsmanager:eu-west-1:123456789012:secret:smtp"),
+            Some(Ok(Reference::Aws {
+                secret_id: "arn:aws:secretsmanager:eu-west-1:123456789012:secret:smtp",
+                key: None
+            }))
+        );
+    }
+}
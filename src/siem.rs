@@ -0,0 +1,271 @@
+//
+// Export of the security events to a SIEM, over syslog
+//
+// The authentication and the admin events of the event bus are sent as UDP syslog messages (RFC 5424) to
+// `SIEM_SYSLOG_TARGET`, with their details as structured data or in the Common Event Format (CEF) of ArcSight.
+// The messages are queued and sent in the background, they are dropped when the queue is full or the target can't be
+// reached, a SIEM which needs every event should use the event log instead.
+//
+use std::net::{IpAddr, SocketAddr};
+
+use chrono::{SecondsFormat, Utc};
+use once_cell::sync::Lazy;
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, error::TrySendError, Receiver, Sender},
+};
+use url::Url;
+
+use crate::{CONFIG, VERSION};
+
+// The security and authorization messages
+const FACILITY_AUTHPRIV: u8 = 10;
+// The example enterprise number of RFC 5612, for the structured data of the messages
+const SD_ID: &str = "vaultwarden@32473";
+const QUEUE_SIZE: usize = 1024;
+
+#[derive(Copy, Clone)]
+pub enum Severity {
+    Info,
+    Notice,
+    Warning,
+}
+
+impl Severity {
+    fn syslog(self) -> u8 {
+        match self {
+            Self::Info => 6,
+            Self::Notice => 5,
+            Self::Warning => 4,
+        }
+    }
+
+    fn cef(self) -> u8 {
+        match self {
+            Self::Info => 3,
+            Self::Notice => 5,
+            Self::Warning => 7,
+        }
+    }
+}
+
+pub struct SecurityEvent<'a> {
+    /// Like `user.login_failed`, the syslog MSGID and the CEF signature ID
+    pub name: &'static str,
+    pub description: &'static str,
+    pub severity: Severity,
+    pub failure: bool,
+    /// The acting user, or the admin
+    pub user_uuid: &'a str,
+    pub user_email: Option<&'a str>,
+    /// The user or the organization the admin changed
+    pub target: Option<&'a str>,
+    pub device_type: i32,
+    pub ip: &'a IpAddr,
+}
+
+pub fn enabled() -> bool {
+    CONFIG.siem_syslog_target().is_some()
+}
+
+/// Checks the `SIEM_SYSLOG_TARGET` and `SIEM_FORMAT` settings.
+pub fn validate_config(target: &str, format: &str) -> Result<(), String> {
+    match target.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok_and(|port| port > 0) => {}
+        _ => return Err(format!("`SIEM_SYSLOG_TARGET` must be a `host:port`, not `{target}`")),
+    }
+    if format != "syslog" && format != "cef" {
+        return Err(format!("`SIEM_FORMAT` must be `syslog` or `cef`, not `{format}`"));
+    }
+    Ok(())
+}
+
+static QUEUE: Lazy<Sender<String>> = Lazy::new(|| {
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    tokio::spawn(send_messages(receiver));
+    sender
+});
+
+/// Queues the event for the syslog target, when one is configured.
+pub fn export(event: &SecurityEvent<'_>) {
+    if !enabled() {
+        return;
+    }
+
+    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let hostname = hostname();
+    let message = if CONFIG.siem_format() == "cef" {
+        cef_message(event, &timestamp, &hostname)
+    } else {
+        syslog_message(event, &timestamp, &hostname)
+    };
+
+    match QUEUE.try_send(message) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => warn!("The SIEM export queue is full, the {} event is dropped", event.name),
+        Err(TrySendError::Closed(_)) => error!("The SIEM export stopped, the {} event is dropped", event.name),
+    }
+}
+
+async fn send_messages(mut receiver: Receiver<String>) {
+    let mut socket: Option<UdpSocket> = None;
+    while let Some(message) = receiver.recv().await {
+        // Resolved again for each message, the SIEM can move without restarting the server
+        let Some(target) = CONFIG.siem_syslog_target() else {
+            continue;
+        };
+        let addr = match tokio::net::lookup_host(&target).await.map(|mut addrs| addrs.next()) {
+            Ok(Some(addr)) => addr,
+            Ok(None) | Err(_) => {
+                warn!("Unable to resolve the SIEM syslog target `{target}`, the event is dropped");
+                continue;
+            }
+        };
+
+        if !socket.as_ref().is_some_and(|s| s.local_addr().is_ok_and(|local| local.is_ipv4() == addr.is_ipv4())) {
+            let bind: SocketAddr = if addr.is_ipv4() {
+                ([0, 0, 0, 0], 0).into()
+            } else {
+                ([0u16; 8], 0).into()
+            };
+            socket = match UdpSocket::bind(bind).await {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    error!("Unable to open a socket for the SIEM export: {e}");
+                    continue;
+                }
+            };
+        }
+
+        if let Some(socket) = &socket {
+            if let Err(e) = socket.send_to(message.as_bytes(), addr).await {
+                warn!("Unable to send an event to the SIEM syslog target `{target}`: {e}");
+            }
+        }
+    }
+}
+
+fn hostname() -> String {
+    Url::parse(&CONFIG.domain_origin())
+        .ok()
+        .and_then(|url| url.host_str().map(String::from))
+        .unwrap_or_else(|| String::from("-"))
+}
+
+fn outcome(event: &SecurityEvent<'_>) -> &'static str {
+    if event.failure {
+        "failure"
+    } else {
+        "success"
+    }
+}
+
+// PRI VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID
+fn header(event: &SecurityEvent<'_>, timestamp: &str, hostname: &str) -> String {
+    let pri = FACILITY_AUTHPRIV * 8 + event.severity.syslog();
+    format!("<{pri}>1 {timestamp} {hostname} vaultwarden {} {}", std::process::id(), event.name)
+}
+
+fn syslog_message(event: &SecurityEvent<'_>, timestamp: &str, hostname: &str) -> String {
+    let mut params = vec![
+        ("outcome", outcome(event).to_string()),
+        ("userId", event.user_uuid.to_string()),
+        ("ip", event.ip.to_string()),
+        ("deviceType", event.device_type.to_string()),
+    ];
+    if let Some(email) = event.user_email {
+        params.push(("userEmail", email.to_string()));
+    }
+    if let Some(target) = event.target {
+        params.push(("target", target.to_string()));
+    }
+
+    let params: Vec<String> = params.iter().map(|(name, value)| format!("{name}=\"{}\"", escape_sd(value))).collect();
+    format!("{} [{SD_ID} {}] {}", header(event, timestamp, hostname), params.join(" "), event.description)
+}
+
+fn cef_message(event: &SecurityEvent<'_>, timestamp: &str, hostname: &str) -> String {
+    let mut extension = vec![
+        format!("rt={}", Utc::now().timestamp_millis()),
+        format!("src={}", event.ip),
+        format!("suid={}", escape_cef_extension(event.user_uuid)),
+        format!("outcome={}", outcome(event)),
+        format!("cn1Label=deviceType cn1={}", event.device_type),
+    ];
+    if let Some(email) = event.user_email {
+        extension.push(format!("suser={}", escape_cef_extension(email)));
+    }
+    if let Some(target) = event.target {
+        extension.push(format!("cs1Label=target cs1={}", escape_cef_extension(target)));
+    }
+
+    format!(
+        "{} - CEF:0|Vaultwarden|Vaultwarden|{}|{}|{}|{}|{}",
+        header(event, timestamp, hostname),
+        escape_cef_header(VERSION.unwrap_or("unknown")),
+        event.name,
+        escape_cef_header(event.description),
+        event.severity.cef(),
+        extension.join(" ")
+    )
+}
+
+// The param values of the structured data escape `"`, `\` and `]`
+fn escape_sd(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]")
+}
+
+fn escape_cef_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+fn escape_cef_extension(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('=', "\\=").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let event = SecurityEvent {
+            name: "user.login_failed",
+            description: "Failed login",
+            severity: Severity::Warning,
+            failure: true,
+            user_uuid: "6b8d2f4e",
+            user_email: Some("a\"b]@example.com"),
+            target: None,
+            device_type: 9,
+            ip: &ip,
+        };
+
+        let syslog = syslog_message(&event, "2024-01-01T00:00:00.000Z", "vw.example.com");
+        let pid = std::process::id();
+        assert_eq!(
+            syslog,
+            format!(
+                "<84>1 2024-01-01T00:00:00.000Z vw.example.com vaultwarden {pid} user.login_failed [vaultwarden@32473 \
+                outcome=\"failure\" userId=\"6b8d2f4e\" ip=\"192.0.2.1\" deviceType=\"9\" \
+                userEmail=\"a\\\"b\\]@example.com\"] Failed login"
+            )
+        );
+
+        let cef = cef_message(&event, "2024-01-01T00:00:00.000Z", "vw.example.com");
+        assert!(cef.contains(" - CEF:0|Vaultwarden|Vaultwarden|"));
+        assert!(cef.contains("|user.login_failed|Failed login|7|"));
+        assert!(cef.ends_with(
+            "src=192.0.2.1 suid=6b8d2f4e outcome=failure cn1Label=deviceType cn1=9 suser=a\"b]@example.com"
+        ));
+    }
+
+    #[test]
+    fn test_validate_config() {
+        assert!(validate_config("siem.example.com:514", "syslog").is_ok());
+        assert!(validate_config("[2001:db8::1]:514", "cef").is_ok());
+        assert!(validate_config("siem.example.com", "syslog").is_err());
+        assert!(validate_config("siem.example.com:514", "json").is_err());
+    }
+}
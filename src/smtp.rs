@@ -0,0 +1,334 @@
+//
+// SMTP relays, with a failover to the next one when a relay can't be reached or refuses the credentials
+//
+// The relay of `SMTP_HOST` is the first one, the ones of `SMTP_RELAYS` follow in their order. A relay which failed is
+// tried last during a cool-down, which doubles after each consecutive failure, and it's healthy again after a success.
+// A mail refused by a relay, like one to an unknown recipient, isn't sent to the next ones as they would refuse it too.
+//
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lettre::{
+    transport::smtp::authentication::{Credentials, Mechanism as SmtpAuthMechanism},
+    transport::smtp::client::{Tls, TlsParameters},
+    transport::smtp::extension::ClientId,
+    transport::smtp::PoolConfig,
+    AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+};
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+use crate::{
+    api::EmptyResult,
+    mail::{Mail, MailTransport},
+    CONFIG,
+};
+
+const COOL_DOWN_BASE: Duration = Duration::from_secs(30);
+const COOL_DOWN_MAX: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Deserialize)]
+struct Relay {
+    host: String,
+    port: Option<u16>,
+    // `starttls` when unset, like `SMTP_SECURITY`
+    security: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    auth_mechanism: Option<String>,
+}
+
+impl Relay {
+    fn security(&self) -> &str {
+        self.security.as_deref().unwrap_or("starttls")
+    }
+
+    fn port(&self) -> u16 {
+        self.port.unwrap_or(match self.security() {
+            "force_tls" => 465,
+            "starttls" => 587,
+            _ => 25,
+        })
+    }
+
+    fn name(&self) -> String {
+        format!("{}:{}", self.host, self.port())
+    }
+}
+
+#[derive(Default)]
+struct Health {
+    failures: u32,
+    cool_down_until: Option<Instant>,
+    last_error: Option<String>,
+}
+
+// The transports are kept to reuse their pooled connections, they are built again when the SMTP settings change
+#[allow(clippy::type_complexity)]
+static TRANSPORTS: Lazy<Mutex<Option<(String, Vec<(String, AsyncSmtpTransport<Tokio1Executor>)>)>>> =
+    Lazy::new(|| Mutex::new(None));
+// By the name of the relay, as `host:port`
+static HEALTH: Lazy<Mutex<HashMap<String, Health>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn parse_relays(config: &str) -> Result<Vec<Relay>, String> {
+    serde_json::from_str(config).map_err(|e| format!("`SMTP_RELAYS` is not a valid JSON array of relays: {e}"))
+}
+
+/// Checks the `SMTP_RELAYS` setting, a JSON array of `{"host": "...", "port": 587, "security": "starttls",
+/// "username": "...", "password": "..."}` objects.
+pub fn validate_config(config: &str) -> Result<(), String> {
+    for relay in parse_relays(config)? {
+        if relay.host.trim().is_empty() {
+            return Err("The SMTP relays need a host".to_string());
+        }
+        if !["starttls", "force_tls", "off"].contains(&relay.security()) {
+            return Err(format!(
+                "The security of the SMTP relay `{}` needs to be one of `starttls`, `force_tls` or `off`",
+                relay.host
+            ));
+        }
+        if relay.username.is_some() != relay.password.is_some() {
+            return Err(format!("The SMTP relay `{}` needs both a username and a password, or none", relay.host));
+        }
+    }
+    Ok(())
+}
+
+// The relay of `SMTP_HOST`, then the ones of `SMTP_RELAYS`
+fn relays() -> Vec<Relay> {
+    let Some(host) = CONFIG.smtp_host() else {
+        return Vec::new();
+    };
+    let mut relays = vec![Relay {
+        host,
+        port: Some(CONFIG.smtp_port()),
+        security: Some(CONFIG.smtp_security()),
+        username: CONFIG.smtp_username(),
+        password: CONFIG.smtp_password(),
+        auth_mechanism: CONFIG.smtp_auth_mechanism(),
+    }];
+    if let Some(config) = CONFIG.smtp_relays() {
+        match parse_relays(&config) {
+            Ok(fallbacks) => relays.extend(fallbacks),
+            Err(e) => error!("{e}"),
+        }
+    }
+    relays
+}
+
+fn transports() -> Vec<(String, AsyncSmtpTransport<Tokio1Executor>)> {
+    let relays = relays();
+    let settings = format!(
+        "{:?}",
+        (
+            &relays,
+            CONFIG.smtp_timeout(),
+            CONFIG.smtp_accept_invalid_hostnames(),
+            CONFIG.smtp_accept_invalid_certs(),
+            CONFIG.helo_name(),
+            CONFIG.smtp_pool_size(),
+        )
+    );
+
+    let mut cached = TRANSPORTS.lock().unwrap();
+    match &*cached {
+        Some((cached_settings, transports)) if *cached_settings == settings => transports.clone(),
+        _ => {
+            let transports: Vec<_> = relays.iter().map(|relay| (relay.name(), build_transport(relay))).collect();
+            *cached = Some((settings, transports.clone()));
+            transports
+        }
+    }
+}
+
+fn build_transport(relay: &Relay) -> AsyncSmtpTransport<Tokio1Executor> {
+    let host = relay.host.as_str();
+
+    let smtp_client = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+        .port(relay.port())
+        .timeout(Some(Duration::from_secs(CONFIG.smtp_timeout())))
+        .pool_config(PoolConfig::new().max_size(CONFIG.smtp_pool_size()));
+
+    // Determine security
+    let smtp_client = if relay.security() != "off" {
+        let mut tls_parameters = TlsParameters::builder(host.to_string());
+        if CONFIG.smtp_accept_invalid_hostnames() {
+            tls_parameters = tls_parameters.dangerous_accept_invalid_hostnames(true);
+        }
+        if CONFIG.smtp_accept_invalid_certs() {
+            tls_parameters = tls_parameters.dangerous_accept_invalid_certs(true);
+        }
+        let tls_parameters = tls_parameters.build().unwrap();
+
+        if relay.security() == "force_tls" {
+            smtp_client.tls(Tls::Wrapper(tls_parameters))
+        } else {
+            smtp_client.tls(Tls::Required(tls_parameters))
+        }
+    } else {
+        smtp_client
+    };
+
+    let smtp_client = match (&relay.username, &relay.password) {
+        (Some(user), Some(pass)) => smtp_client.credentials(Credentials::new(user.clone(), pass.clone())),
+        _ => smtp_client,
+    };
+
+    let smtp_client = match CONFIG.helo_name() {
+        Some(helo_name) => smtp_client.hello_name(ClientId::Domain(helo_name)),
+        None => smtp_client,
+    };
+
+    let smtp_client = match &relay.auth_mechanism {
+        Some(mechanism) => {
+            let allowed_mechanisms = [SmtpAuthMechanism::Plain, SmtpAuthMechanism::Login, SmtpAuthMechanism::Xoauth2];
+            let mut selected_mechanisms = vec![];
+            for wanted_mechanism in mechanism.split(',') {
+                for m in &allowed_mechanisms {
+                    if m.to_string().to_lowercase()
+                        == wanted_mechanism.trim_matches(|c| c == '"' || c == '\'' || c == ' ').to_lowercase()
+                    {
+                        selected_mechanisms.push(*m);
+                    }
+                }
+            }
+
+            if !selected_mechanisms.is_empty() {
+                smtp_client.authentication(selected_mechanisms)
+            } else {
+                // Only show a warning, and return without setting an actual authentication mechanism
+                warn!("No valid SMTP Auth mechanism found for '{}', using default values", mechanism);
+                smtp_client
+            }
+        }
+        _ => smtp_client,
+    };
+
+    smtp_client.build()
+}
+
+// Match some common errors and make them more user friendly
+fn describe_error(e: &lettre::transport::smtp::Error) -> String {
+    if e.is_client() {
+        debug!("SMTP client error: {:#?}", e);
+        format!("SMTP client error: {e}")
+    } else if e.is_transient() {
+        debug!("SMTP 4xx error: {:#?}", e);
+        format!("SMTP 4xx error: {e}")
+    } else if e.is_permanent() {
+        debug!("SMTP 5xx error: {:#?}", e);
+        let mut msg = e.to_string();
+        // Add a special check for 535 to add a more descriptive message
+        if msg.contains("(535)") {
+            msg = format!("{msg} - Authentication credentials invalid");
+        }
+        format!("SMTP 5xx error: {msg}")
+    } else if e.is_timeout() {
+        debug!("SMTP timeout error: {:#?}", e);
+        format!("SMTP timeout error: {e}")
+    } else if e.is_tls() {
+        debug!("SMTP encryption error: {:#?}", e);
+        format!("SMTP encryption error: {e}")
+    } else {
+        debug!("SMTP error: {:#?}", e);
+        format!("SMTP error: {e}")
+    }
+}
+
+// The errors of the relay itself: it can't be reached, it's busy or it refused the credentials
+fn is_relay_failure(e: &lettre::transport::smtp::Error) -> bool {
+    if e.is_permanent() {
+        let msg = e.to_string();
+        return ["(530)", "(534)", "(535)"].iter().any(|code| msg.contains(code));
+    }
+    true
+}
+
+fn in_cool_down(name: &str, now: Instant) -> bool {
+    let health = HEALTH.lock().unwrap();
+    health.get(name).and_then(|h| h.cool_down_until).is_some_and(|until| until > now)
+}
+
+fn record_success(name: &str) {
+    HEALTH.lock().unwrap().remove(name);
+}
+
+fn record_failure(name: &str, error: &str) {
+    let mut health = HEALTH.lock().unwrap();
+    let health = health.entry(name.to_string()).or_default();
+    health.failures += 1;
+    let cool_down = COOL_DOWN_BASE.saturating_mul(1 << (health.failures - 1).min(16)).min(COOL_DOWN_MAX);
+    health.cool_down_until = Some(Instant::now() + cool_down);
+    health.last_error = Some(error.to_string());
+}
+
+/// The health of the relays, for the admin diagnostics.
+pub fn relays_json() -> Value {
+    let now = Instant::now();
+    let health = HEALTH.lock().unwrap();
+    let relays: Vec<Value> = relays()
+        .iter()
+        .map(|relay| {
+            let name = relay.name();
+            let h = health.get(&name);
+            json!({
+                "name": name,
+                "failures": h.map_or(0, |h| h.failures),
+                "cool_down_seconds": h
+                    .and_then(|h| h.cool_down_until)
+                    .map_or(0, |until| until.saturating_duration_since(now).as_secs()),
+                "last_error": h.and_then(|h| h.last_error.clone()),
+            })
+        })
+        .collect();
+    Value::from(relays)
+}
+
+/// Connects to each relay without sending a mail, for `--check-config`.
+pub async fn test_relays() -> Vec<(String, Result<(), String>)> {
+    let mut results = Vec::new();
+    for relay in relays() {
+        let result = match build_transport(&relay).test_connection().await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(String::from("The relay closed the connection")),
+            Err(e) => Err(describe_error(&e)),
+        };
+        results.push((relay.name(), result));
+    }
+    results
+}
+
+pub struct Smtp;
+
+#[rocket::async_trait]
+impl MailTransport for Smtp {
+    async fn send(&self, mail: &Mail) -> EmptyResult {
+        let (envelope, message) = mail.to_signed_message()?;
+
+        // The relays in their cool-down are tried last, in case all of them failed
+        let now = Instant::now();
+        let (healthy, cooling): (Vec<_>, Vec<_>) =
+            transports().into_iter().partition(|(name, _)| !in_cool_down(name, now));
+
+        let mut last_error = None;
+        for (name, transport) in healthy.into_iter().chain(cooling) {
+            match transport.send_raw(&envelope, &message).await {
+                Ok(_) => {
+                    record_success(&name);
+                    return Ok(());
+                }
+                Err(e) if is_relay_failure(&e) => {
+                    let error = describe_error(&e);
+                    warn!("The SMTP relay {name} failed: {error}");
+                    record_failure(&name, &error);
+                    last_error = Some(error);
+                }
+                Err(e) => err!(describe_error(&e)),
+            }
+        }
+        err!(last_error.unwrap_or_else(|| "No SMTP relay is configured".to_string()))
+    }
+}
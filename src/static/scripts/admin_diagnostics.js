@@ -195,6 +195,39 @@ function checkDns(dns_resolved) {
     }
 }
 
+function retryQueuedMail(event) {
+    event.preventDefault();
+    event.stopPropagation();
+    const id = event.target.dataset.vwMailRetry;
+    _post(`${BASE_URL}/admin/mail_queue/${id}/retry`,
+        "Mail queued again",
+        "Error queuing the mail again"
+    );
+}
+
+function deleteQueuedMail(event) {
+    event.preventDefault();
+    event.stopPropagation();
+    const id = event.target.dataset.vwMailDelete;
+    if (confirm("Are you sure you want to delete this mail? It won't be sent.")) {
+        _post(`${BASE_URL}/admin/mail_queue/${id}/delete`,
+            "Mail deleted",
+            "Error deleting the mail"
+        );
+    }
+}
+
+function prewarmIcons(event) {
+    event.preventDefault();
+    event.stopPropagation();
+    const domains = document.getElementById("icon-prewarm-domains").value.split(/[\s,]+/).filter(d => d);
+    _post(`${BASE_URL}/admin/icons/prewarm`,
+        "Icons queued for the pre-warm job",
+        "Error queuing the icons",
+        JSON.stringify({ "domains": domains })
+    );
+}
+
 function init(dj) {
     // Time check
     document.getElementById("time-browser-string").innerText = browserUTC;
@@ -238,4 +271,14 @@ document.addEventListener("DOMContentLoaded", (event) => {
     if (btnCopySupport) {
         btnCopySupport.addEventListener("click", copyToClipboard);
     }
+    document.querySelectorAll("button[data-vw-mail-retry]").forEach(btn => {
+        btn.addEventListener("click", retryQueuedMail);
+    });
+    document.querySelectorAll("button[data-vw-mail-delete]").forEach(btn => {
+        btn.addEventListener("click", deleteQueuedMail);
+    });
+    const btnIconPrewarm = document.getElementById("icon-prewarm");
+    if (btnIconPrewarm) {
+        btnIconPrewarm.addEventListener("click", prewarmIcons);
+    }
 });
\ No newline at end of file
@@ -0,0 +1,53 @@
+"use strict";
+/* eslint-env es2017, browser */
+/* global _post:readable, BASE_URL:readable, reload:readable */
+
+function saveJob(event) {
+    event.preventDefault();
+    const form = event.target;
+    const job_name = form.dataset.vwJobName;
+    if (!job_name) {
+        alert("Required parameters not found!");
+        return false;
+    }
+    const data = {
+        "enabled": form.elements["enabled"].checked,
+        "schedule": form.elements["schedule"].value || null
+    };
+    _post(`${BASE_URL}/admin/jobs/${job_name}`,
+        "Job settings saved correctly",
+        "Error saving the job settings",
+        JSON.stringify(data)
+    );
+}
+
+function runJob(event) {
+    event.preventDefault();
+    event.stopPropagation();
+    const job_name = event.target.dataset.vwJobName;
+    if (!job_name) {
+        alert("Required parameters not found!");
+        return false;
+    }
+    if (confirm(`Are you sure you want to run the ${job_name} job now?`)) {
+        _post(`${BASE_URL}/admin/jobs/${job_name}/run`,
+            "Job started, reload the page to see its run",
+            "Error starting the job"
+        );
+    }
+}
+
+// onLoad events
+document.addEventListener("DOMContentLoaded", (/*event*/) => {
+    document.querySelectorAll("form.vw-job-form").forEach(form => {
+        form.addEventListener("submit", saveJob);
+    });
+    document.querySelectorAll("button[vw-run-job]").forEach(btn => {
+        btn.addEventListener("click", runJob);
+    });
+
+    const btnReload = document.getElementById("reload");
+    if (btnReload) {
+        btnReload.addEventListener("click", reload);
+    }
+});
@@ -0,0 +1,57 @@
+"use strict";
+/* eslint-env es2017, browser, jquery */
+/* global _post:readable, BASE_URL:readable, reload:readable */
+
+function resendMail(event) {
+    event.preventDefault();
+    event.stopPropagation();
+    const mail_uuid = event.target.dataset.vwMailUuid;
+    const recipient = event.target.dataset.vwMailRecipient;
+    if (!mail_uuid) {
+        alert("Required parameters not found!");
+        return false;
+    }
+
+    // The links of the mail may have expired since it was first sent
+    if (confirm(`Are you sure you want to send this mail to ${recipient} again? It has the same content, its links may have expired.`)) {
+        _post(`${BASE_URL}/admin/mail_log/${mail_uuid}/resend`,
+            "Mail sent again",
+            "Error sending the mail again"
+        );
+    }
+}
+
+function initActions() {
+    document.querySelectorAll("button[vw-resend-mail]").forEach(btn => {
+        btn.addEventListener("click", resendMail);
+    });
+}
+
+// onLoad events
+document.addEventListener("DOMContentLoaded", (/*event*/) => {
+    jQuery("#mail-log-table").DataTable({
+        "drawCallback": function() {
+            initActions();
+        },
+        "responsive": true,
+        "order": [],
+        "lengthMenu": [
+            [-1, 25, 50, 100],
+            ["All", 25, 50, 100]
+        ],
+        "pageLength": 50,
+        "columnDefs": [{
+            "targets": [4],
+            "searchable": false,
+            "orderable": false
+        }]
+    });
+
+    // Add click events for mail actions
+    initActions();
+
+    const btnReload = document.getElementById("reload");
+    if (btnReload) {
+        btnReload.addEventListener("click", reload);
+    }
+});
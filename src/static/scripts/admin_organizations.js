@@ -1,5 +1,5 @@
 "use strict";
-/* eslint-env es2017, browser, jquery */
+/* eslint-env es2017, browser */
 /* global _post:readable, BASE_URL:readable, reload:readable, jdenticon:readable */
 
 function deleteOrganization(event) {
@@ -42,24 +42,6 @@ function initActions() {
 
 // onLoad events
 document.addEventListener("DOMContentLoaded", (/*event*/) => {
-    jQuery("#orgs-table").DataTable({
-        "drawCallback": function() {
-            initActions();
-        },
-        "stateSave": true,
-        "responsive": true,
-        "lengthMenu": [
-            [-1, 5, 10, 25, 50],
-            ["All", 5, 10, 25, 50]
-        ],
-        "pageLength": -1, // Default show all
-        "columnDefs": [{
-            "targets": [4,5],
-            "searchable": false,
-            "orderable": false
-        }]
-    });
-
     // Add click events for organization actions
     initActions();
 
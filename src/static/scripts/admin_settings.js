@@ -79,10 +79,69 @@ function backupDatabase(event) {
     );
 }
 
+function exportDatabase(event) {
+    event.preventDefault();
+    event.stopPropagation();
+    _post(`${BASE_URL}/admin/config/export_db`,
+        "Archive exported successfully",
+        "Error exporting the archive", null, false
+    );
+}
+
+function dbMaintenance(event) {
+    event.preventDefault();
+    event.stopPropagation();
+    _post(`${BASE_URL}/admin/config/db_maintenance`,
+        "Database maintenance done",
+        "Error running the database maintenance", null, false
+    );
+}
+
+async function loadEmailTemplates() {
+    const select = document.getElementById("email-template-name");
+    if (!select || select.options.length > 0) {
+        return;
+    }
+    const resp = await fetch(`${BASE_URL}/admin/email_templates`, {
+        "headers": { "Accept": "application/json" }
+    });
+    if (!resp.ok) {
+        alert("Error loading the email templates: " + resp.statusText);
+        return;
+    }
+    for (const name of await resp.json()) {
+        select.add(new Option(name, name));
+    }
+}
+
+async function previewEmailTemplate(event) {
+    event.preventDefault();
+    event.stopPropagation();
+    const name = document.getElementById("email-template-name").value;
+    if (!name) {
+        return;
+    }
+    const resp = await fetch(`${BASE_URL}/admin/email_templates/${encodeURIComponent(name)}/preview`, {
+        "headers": { "Accept": "application/json" }
+    });
+    if (!resp.ok) {
+        let error = resp.statusText;
+        try {
+            error = (await resp.json()).ErrorModel.Message;
+        } catch (e) { /* Keep the status text */ }
+        alert(`Error rendering the email template\n${error}`);
+        return;
+    }
+    const preview = await resp.json();
+    document.getElementById("email-template-subject").innerText = preview.subject;
+    document.getElementById("email-template-html").srcdoc = preview.html;
+    document.getElementById("email-template-preview").classList.remove("d-none");
+}
+
 // Two functions to help check if there were changes to the form fields
 // Useful for example during the smtp test to prevent people from clicking save before testing there new settings
 function initChangeDetection(form) {
-    const ignore_fields = ["smtp-test-email"];
+    const ignore_fields = ["smtp-test-email", "email-template-name"];
     Array.from(form).forEach((el) => {
         if (! ignore_fields.includes(el.id)) {
             el.dataset.origValue = el.value;
@@ -217,10 +276,26 @@ document.addEventListener("DOMContentLoaded", (/*event*/) => {
     if (btnBackupDatabase) {
         btnBackupDatabase.addEventListener("click", backupDatabase);
     }
+    const btnExportDatabase = document.getElementById("exportDatabase");
+    if (btnExportDatabase) {
+        btnExportDatabase.addEventListener("click", exportDatabase);
+    }
+    const btnDbMaintenance = document.getElementById("dbMaintenance");
+    if (btnDbMaintenance) {
+        btnDbMaintenance.addEventListener("click", dbMaintenance);
+    }
     const btnDeleteConf = document.getElementById("deleteConf");
     if (btnDeleteConf) {
         btnDeleteConf.addEventListener("click", deleteConf);
     }
+    const btnEmailTemplates = document.getElementById("b_email_templates");
+    if (btnEmailTemplates) {
+        btnEmailTemplates.addEventListener("click", loadEmailTemplates);
+    }
+    const btnPreviewEmailTemplate = document.getElementById("previewEmailTemplate");
+    if (btnPreviewEmailTemplate) {
+        btnPreviewEmailTemplate.addEventListener("click", previewEmailTemplate);
+    }
     const btnSmtpTest = document.getElementById("smtpTest");
     if (btnSmtpTest) {
         btnSmtpTest.addEventListener("click", smtpTest);
@@ -0,0 +1,78 @@
+"use strict";
+/* eslint-env es2017, browser */
+/* global _post:readable, BASE_URL:readable */
+
+function formJson(form) {
+    const data = {};
+    new FormData(form).forEach((value, key) => {
+        data[key] = value === "" ? null : value;
+    });
+    return data;
+}
+
+function saveDomain(event) {
+    event.preventDefault();
+    const data = formJson(event.target);
+    _post(`${BASE_URL}/admin/setup/domain`,
+        "Domain saved correctly",
+        "Error saving the domain",
+        JSON.stringify(data)
+    );
+}
+
+function saveSmtp(event) {
+    event.preventDefault();
+    const data = formJson(event.target);
+    data.port = data.port ? +data.port : null;
+    _post(`${BASE_URL}/admin/setup/smtp`,
+        "Mail settings saved correctly",
+        "Error saving the mail settings",
+        JSON.stringify(data)
+    );
+}
+
+function smtpTest(event) {
+    event.preventDefault();
+    const test_email = document.getElementById("setup-smtp-test-email");
+    _post(`${BASE_URL}/admin/setup/test/smtp`,
+        "SMTP Test email sent correctly",
+        "Error sending SMTP test email",
+        JSON.stringify({ "email": test_email.value }), false
+    );
+}
+
+function saveAdminToken(event) {
+    event.preventDefault();
+    const data = formJson(event.target);
+    if (data.token !== data.token_confirm) {
+        alert("The admin tokens do not match");
+        return false;
+    }
+    // Reloading the page opens the admin panel once the setup is done
+    _post(`${BASE_URL}/admin/setup/admin_token`,
+        "The setup is done, the admin token is set",
+        "Error saving the admin token",
+        JSON.stringify(data)
+    );
+}
+
+// onLoad events
+document.addEventListener("DOMContentLoaded", (/*event*/) => {
+    const domain = document.getElementById("setup-domain");
+    if (!domain.value) {
+        domain.value = BASE_URL;
+    }
+
+    const security = document.getElementById("setup-smtp-security");
+    if (security.dataset.value) {
+        security.value = security.dataset.value;
+    }
+
+    document.getElementById("setup-domain-form").addEventListener("submit", saveDomain);
+    document.getElementById("setup-smtp-form").addEventListener("submit", saveSmtp);
+    document.getElementById("setup-admin-token-form").addEventListener("submit", saveAdminToken);
+    const smtpTestForm = document.getElementById("setup-smtp-test-form");
+    if (smtpTestForm) {
+        smtpTestForm.addEventListener("submit", smtpTest);
+    }
+});
@@ -1,5 +1,5 @@
 "use strict";
-/* eslint-env es2017, browser, jquery */
+/* eslint-env es2017, browser */
 /* global _post:readable, BASE_URL:readable, reload:readable, jdenticon:readable */
 
 function deleteUser(event) {
@@ -158,34 +158,6 @@ const ORG_TYPES = {
     },
 };
 
-// Special sort function to sort dates in ISO format
-jQuery.extend(jQuery.fn.dataTableExt.oSort, {
-    "date-iso-pre": function(a) {
-        let x;
-        const sortDate = a.replace(/(<([^>]+)>)/gi, "").trim();
-        if (sortDate !== "") {
-            const dtParts = sortDate.split(" ");
-            const timeParts = (undefined != dtParts[1]) ? dtParts[1].split(":") : ["00", "00", "00"];
-            const dateParts = dtParts[0].split("-");
-            x = (dateParts[0] + dateParts[1] + dateParts[2] + timeParts[0] + timeParts[1] + ((undefined != timeParts[2]) ? timeParts[2] : 0)) * 1;
-            if (isNaN(x)) {
-                x = 0;
-            }
-        } else {
-            x = Infinity;
-        }
-        return x;
-    },
-
-    "date-iso-asc": function(a, b) {
-        return a - b;
-    },
-
-    "date-iso-desc": function(a, b) {
-        return b - a;
-    }
-});
-
 const userOrgTypeDialog = document.getElementById("userOrgTypeDialog");
 // Fill the form and title
 userOrgTypeDialog.addEventListener("show.bs.modal", function(event) {
@@ -261,27 +233,6 @@ function initUserTable() {
 
 // onLoad events
 document.addEventListener("DOMContentLoaded", (/*event*/) => {
-    jQuery("#users-table").DataTable({
-        "drawCallback": function() {
-            initUserTable();
-        },
-        "stateSave": true,
-        "responsive": true,
-        "lengthMenu": [
-            [-1, 2, 5, 10, 25, 50],
-            ["All", 2, 5, 10, 25, 50]
-        ],
-        "pageLength": -1, // Default show all
-        "columnDefs": [{
-            "targets": [1, 2],
-            "type": "date-iso"
-        }, {
-            "targets": 6,
-            "searchable": false,
-            "orderable": false
-        }]
-    });
-
     // Add click events for user actions
     initUserTable();
 
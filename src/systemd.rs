@@ -0,0 +1,128 @@
+//
+// Integration with systemd, for the installs managed by a service with `Type=notify`
+//
+// The readiness, the status and the shutdown are reported to `NOTIFY_SOCKET` with the sd_notify protocol, and the
+// watchdog is pinged at half of `WatchdogSec=` while the async runtime is responsive, so a hung server gets restarted.
+// The sockets of the socket activation (`LISTEN_FDS`) can't be taken over: Rocket binds its own listener, and the file
+// descriptors can't be adopted without unsafe code. A warning is logged, and `ROCKET_ADDRESS` and `ROCKET_PORT` are
+// bound like without them.
+//
+use std::time::Duration;
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Orbit, Rocket,
+};
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Sends the state, like `READY=1`, to the service manager. Nothing when the server isn't run by systemd.
+pub fn notify(state: &str) {
+    let Some(socket_path) = env_var("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&socket_path, state) {
+        warn!("Unable to notify systemd of `{state}`: {e}");
+    }
+}
+
+#[cfg(unix)]
+fn send(socket_path: &str, state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    // The sockets starting with `@` are in the abstract namespace of Linux
+    if let Some(name) = socket_path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            let addr = SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+            return Ok(());
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = name;
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "abstract sockets need Linux"));
+        }
+    }
+    socket.send_to(state.as_bytes(), socket_path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_socket_path: &str, _state: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "sd_notify needs Unix sockets"))
+}
+
+// Pinged twice per watchdog period, when the watchdog is enabled for this process
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    let usec: u64 = usec?.parse().ok().filter(|usec| *usec > 0)?;
+    if pid.is_some_and(|pid| pid.parse::<u32>().ok() != Some(own_pid)) {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Warns when systemd passed sockets to the server, they aren't used.
+pub fn check_socket_activation() {
+    let for_this_process = env_var("LISTEN_PID").and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id());
+    if for_this_process && env_var("LISTEN_FDS").is_some() {
+        warn!(
+            "systemd passed listening sockets with the socket activation, they aren't supported and are ignored. \
+            Vaultwarden listens on ROCKET_ADDRESS and ROCKET_PORT instead, disable the .socket unit"
+        );
+    }
+}
+
+/// Reports the readiness to systemd once the server listens, and the shutdown.
+pub struct SystemdNotify;
+
+#[rocket::async_trait]
+impl Fairing for SystemdNotify {
+    fn info(&self) -> Info {
+        Info {
+            name: "systemd Notify",
+            kind: Kind::Liftoff | Kind::Shutdown,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let config = rocket.config();
+        notify(&format!("READY=1\nSTATUS=Listening on {}:{}", config.address, config.port));
+
+        let usec = env_var("WATCHDOG_USEC");
+        let pid = env_var("WATCHDOG_PID");
+        if let Some(interval) = watchdog_interval(usec.as_deref(), pid.as_deref(), std::process::id()) {
+            info!("Pinging the systemd watchdog every {} ms", interval.as_millis());
+            // A task of the runtime, a blocked runtime stops the pings and gets the server restarted
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(interval);
+                loop {
+                    interval.tick().await;
+                    notify("WATCHDOG=1");
+                }
+            });
+        }
+    }
+
+    async fn on_shutdown(&self, _rocket: &Rocket<Orbit>) {
+        notify("STOPPING=1\nSTATUS=Shutting down");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(watchdog_interval(Some("30000000"), None, 42), Some(Duration::from_secs(15)));
+        assert_eq!(watchdog_interval(Some("30000000"), Some("42"), 42), Some(Duration::from_secs(15)));
+        assert_eq!(watchdog_interval(Some("30000000"), Some("7"), 42), None);
+        assert_eq!(watchdog_interval(Some("0"), None, 42), None);
+        assert_eq!(watchdog_interval(None, None, 42), None);
+    }
+}
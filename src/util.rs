@@ -1,7 +1,14 @@
 //
 // Web Headers and caching
 //
-use std::{collections::HashMap, io::Cursor, ops::Deref, path::Path};
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    ops::Deref,
+    path::Path,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
 
 use num_traits::ToPrimitive;
 use once_cell::sync::Lazy;
@@ -14,11 +21,12 @@ use rocket::{
 };
 
 use tokio::{
+    io::{AsyncRead, AsyncSeek, ReadBuf},
     runtime::Handle,
     time::{sleep, Duration},
 };
 
-use crate::CONFIG;
+use crate::{log_context, CONFIG};
 
 pub struct AppHeaders();
 
@@ -221,6 +229,176 @@ impl<'r, R: 'r + Responder<'r, 'static> + Send> Responder<'r, 'static> for Cache
     }
 }
 
+/// A response cached by the client, which revalidates it with its ETag before each use. For the files which keep their
+/// name when their content changes, where the hash of the content is the ETag.
+pub struct Revalidated<R> {
+    response: R,
+    etag: String,
+}
+
+impl<R> Revalidated<R> {
+    pub fn new(response: R, content_hash: &str) -> Revalidated<R> {
+        Self {
+            response,
+            etag: format!("\"{content_hash}\""),
+        }
+    }
+}
+
+// Weak comparison, the compressed responses have a weak ETag
+fn etag_matches<'a>(mut if_none_match: impl Iterator<Item = &'a str>, etag: &str) -> bool {
+    if_none_match.any(|value| {
+        value.split(',').map(str::trim).any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+    })
+}
+
+impl<'r, R: 'r + Responder<'r, 'static> + Send> Responder<'r, 'static> for Revalidated<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        if etag_matches(request.headers().get("If-None-Match"), &self.etag) {
+            return Response::build()
+                .status(Status::NotModified)
+                .raw_header("Cache-Control", "no-cache")
+                .raw_header("ETag", self.etag)
+                .ok();
+        }
+
+        let mut res = self.response.respond_to(request)?;
+        res.set_raw_header("Cache-Control", "no-cache");
+        res.set_raw_header("ETag", self.etag);
+        Ok(res)
+    }
+}
+
+// A file which can be downloaded in parts with the range requests, so the large downloads can be resumed.
+// Only a single range is served, the other requests get the whole file like before.
+pub struct RangedFile {
+    file: tokio::fs::File,
+    len: u64,
+    content_type: Option<ContentType>,
+}
+
+impl RangedFile {
+    pub async fn open(path: &Path) -> std::io::Result<RangedFile> {
+        let file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        let content_type = path.extension().and_then(|ext| ext.to_str()).and_then(ContentType::from_extension);
+        Ok(Self {
+            file,
+            len,
+            content_type,
+        })
+    }
+}
+
+// The first and last bytes of the requested range, or `Err` when it starts after the end of the file.
+// `None` when the whole file is sent instead, for the multiple or malformed ranges.
+fn parse_range(range: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let (start, end) = range.trim().strip_prefix("bytes=")?.split_once('-')?;
+    if end.contains(',') {
+        return None;
+    }
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        // The last bytes of the file
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        (len.saturating_sub(suffix), len - 1)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = if end.is_empty() {
+            u64::MAX
+        } else {
+            end.parse().ok()?
+        };
+        if end < start {
+            return None;
+        }
+        if start >= len {
+            return Some(Err(()));
+        }
+        (start, end.min(len - 1))
+    };
+    Some(Ok(range))
+}
+
+impl<'r> Responder<'r, 'static> for RangedFile {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut res = Response::build();
+        res.raw_header("Accept-Ranges", "bytes");
+        if let Some(content_type) = self.content_type {
+            res.header(content_type);
+        }
+
+        // No validator is sent with the files, so a conditional range can't be checked
+        let range = match request.headers().get_one("Range") {
+            Some(range) if !request.headers().contains("If-Range") => parse_range(range, self.len),
+            _ => None,
+        };
+        match range {
+            None => res.sized_body(self.len.to_usize(), self.file).ok(),
+            Some(Err(())) => Response::build()
+                .status(Status::RangeNotSatisfiable)
+                .raw_header("Content-Range", format!("bytes */{}", self.len))
+                .ok(),
+            Some(Ok((start, end))) => {
+                let mut file = self.file.try_into_std().map_err(|_| Status::InternalServerError)?;
+                std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(start)).map_err(|e| {
+                    error!("Unable to seek in the requested file: {e}");
+                    Status::InternalServerError
+                })?;
+                let len = end - start + 1;
+                res.status(Status::PartialContent)
+                    .raw_header("Content-Range", format!("bytes {start}-{end}/{}", self.len))
+                    .sized_body(
+                        len.to_usize(),
+                        FileRange {
+                            file: tokio::fs::File::from_std(file),
+                            remaining: len,
+                        },
+                    )
+                    .ok()
+            }
+        }
+    }
+}
+
+// The part of a file after its current position, up to `remaining` bytes
+struct FileRange {
+    file: tokio::fs::File,
+    remaining: u64,
+}
+
+impl AsyncRead for FileRange {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let max = buf.remaining().min(this.remaining.to_usize().unwrap_or(usize::MAX));
+        if max == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        // Initialized first, so the bytes read in the limited buffer can be added to `buf` without unsafe code
+        buf.initialize_unfilled_to(max);
+        let mut limited = buf.take(max);
+        ready!(Pin::new(&mut this.file).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        buf.advance(read);
+        this.remaining -= read as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Needed by the sized bodies, which are never seeked when their size is given
+impl AsyncSeek for FileRange {
+    fn start_seek(self: Pin<&mut Self>, _position: std::io::SeekFrom) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Err(std::io::ErrorKind::Unsupported.into()))
+    }
+}
+
 pub struct SafeString(String);
 
 impl std::fmt::Display for SafeString {
@@ -306,10 +484,15 @@ impl Fairing for BetterLogging {
         let uri_path_str = uri_path.url_decode_lossy();
         let uri_subpath = uri_path_str.strip_prefix(&CONFIG.domain_path()).unwrap_or(&uri_path_str);
         if self.0 || LOGGED_ROUTES.iter().any(|r| uri_subpath.starts_with(r)) {
-            match uri.query() {
+            let context = log_context::request_context(request).await;
+            log_context::in_context(context, || match uri.query() {
+                // Don't log the beginning of the access tokens of the WebSocket connections
+                Some(q) if q.as_str().contains("access_token=") => {
+                    info!(target: "request", "{} {}?access_token=***", method, uri_path_str)
+                }
                 Some(q) => info!(target: "request", "{} {}?{}", method, uri_path_str, &q[..q.len().min(30)]),
                 None => info!(target: "request", "{} {}", method, uri_path_str),
-            };
+            });
         }
     }
 
@@ -322,11 +505,14 @@ impl Fairing for BetterLogging {
         let uri_subpath = uri_path_str.strip_prefix(&CONFIG.domain_path()).unwrap_or(&uri_path_str);
         if self.0 || LOGGED_ROUTES.iter().any(|r| uri_subpath.starts_with(r)) {
             let status = response.status();
-            if let Some(ref route) = request.route() {
-                info!(target: "response", "{} => {}", route, status)
-            } else {
-                info!(target: "response", "{}", status)
-            }
+            let context = log_context::request_context(request).await;
+            log_context::in_context(context, || {
+                if let Some(ref route) = request.route() {
+                    info!(target: "response", "{} => {}", route, status)
+                } else {
+                    info!(target: "response", "{}", status)
+                }
+            });
         }
     }
 }
@@ -357,6 +543,33 @@ pub fn get_uuid() -> String {
     uuid::Uuid::new_v4().to_string()
 }
 
+/// A UUIDv7, which starts with its creation time in milliseconds, followed by 74 random bits.
+/// The new ids sort after the older ones, so they are inserted at the end of the primary key index.
+pub struct UuidV7(uuid::Uuid);
+
+impl UuidV7 {
+    pub fn now() -> Self {
+        let millis = chrono::Utc::now().timestamp_millis() as u64;
+        Self(uuid::Builder::from_unix_timestamp_millis(millis, &crate::crypto::get_random_bytes::<10>()).into_uuid())
+    }
+}
+
+impl std::fmt::Display for UuidV7 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The id of a new row of the tables which grow the most, a UUIDv7 with `DATABASE_UUID_V7`, else a UUIDv4.
+/// Both can be mixed in the same table.
+pub fn get_row_uuid() -> String {
+    if CONFIG.database_uuid_v7() {
+        UuidV7::now().to_string()
+    } else {
+        get_uuid()
+    }
+}
+
 //
 // String util methods
 //
@@ -400,6 +613,11 @@ where
 use std::env;
 
 pub fn get_env_str_value(key: &str) -> Option<String> {
+    read_env_str_value(key).map(|value| crate::secrets::resolve(key, value))
+}
+
+/// Like `get_env_str_value`, without fetching the secrets the value references.
+pub fn read_env_str_value(key: &str) -> Option<String> {
     let key_file = format!("{key}_FILE");
     let value_from_env = env::var(key);
     let value_file = env::var(&key_file);
@@ -700,19 +918,6 @@ where
     }
 }
 
-use reqwest::{header, Client, ClientBuilder};
-
-pub fn get_reqwest_client() -> &'static Client {
-    static INSTANCE: Lazy<Client> = Lazy::new(|| get_reqwest_client_builder().build().expect("Failed to build client"));
-    &INSTANCE
-}
-
-pub fn get_reqwest_client_builder() -> ClientBuilder {
-    let mut headers = header::HeaderMap::new();
-    headers.insert(header::USER_AGENT, header::HeaderValue::from_static("Vaultwarden"));
-    Client::builder().default_headers(headers).timeout(Duration::from_secs(10))
-}
-
 pub fn convert_json_key_lcase_first(src_json: Value) -> Value {
     match src_json {
         Value::Array(elm) => {
@@ -764,6 +969,42 @@ pub fn parse_experimental_client_feature_flags(experimental_client_feature_flags
     feature_states
 }
 
+/// Returns the preferred locale of an `Accept-Language` header, normalized like `pt-BR`.
+pub fn parse_accept_language(accept_language: &str) -> Option<String> {
+    // The languages are sorted by the clients, the first one is the language of their interface
+    let tag = accept_language.split(',').next()?.split(';').next()?.trim().replace('_', "-");
+    if tag.is_empty() || tag == "*" || tag.len() > 35 || !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return None;
+    }
+
+    let mut parts = tag.split('-');
+    let mut locale = parts.next()?.to_lowercase();
+    for part in parts {
+        locale.push('-');
+        if part.len() == 2 {
+            locale.push_str(&part.to_uppercase());
+        } else {
+            locale.push_str(part);
+        }
+    }
+    Some(locale)
+}
+
+/// Parses a comma separated list of IP ranges, like `10.0.0.0/8,fd00::/8`. A single IP is a range too.
+pub fn parse_ip_ranges(ranges: &str) -> Result<Vec<ipnet::IpNet>, String> {
+    ranges
+        .split(',')
+        .map(str::trim)
+        .filter(|range| !range.is_empty())
+        .map(|range| {
+            range
+                .parse::<ipnet::IpNet>()
+                .or_else(|_| range.parse::<std::net::IpAddr>().map(ipnet::IpNet::from))
+                .map_err(|_| format!("`{range}` is not an IP range"))
+        })
+        .collect()
+}
+
 mod dns_resolver {
     use std::{
         fmt,
@@ -774,8 +1015,12 @@ mod dns_resolver {
     use hickory_resolver::{system_conf::read_system_conf, TokioAsyncResolver};
     use once_cell::sync::Lazy;
     use reqwest::dns::{Name, Resolve, Resolving};
+    use url::{Host, Url};
 
-    use crate::{util::is_global, CONFIG};
+    use crate::{
+        util::{is_global, parse_ip_ranges},
+        CONFIG,
+    };
 
     #[derive(Debug, Clone)]
     pub enum CustomResolverError {
@@ -786,6 +1031,10 @@ mod dns_resolver {
             domain: String,
             ip: IpAddr,
         },
+        DeniedIp {
+            domain: String,
+            ip: IpAddr,
+        },
     }
 
     impl CustomResolverError {
@@ -812,6 +1061,10 @@ mod dns_resolver {
                     domain,
                     ip,
                 } => write!(f, "IP {ip} for domain '{domain}' is not a global IP!"),
+                Self::DeniedIp {
+                    domain,
+                    ip,
+                } => write!(f, "IP {ip} for domain '{domain}' matched ICON_DENIED_IP_RANGES"),
             }
         }
     }
@@ -844,20 +1097,45 @@ mod dns_resolver {
             }
         }
 
-        // Note that we get an iterator of addresses, but we only grab the first one for convenience
-        async fn resolve_domain(&self, name: &str) -> Result<Option<SocketAddr>, BoxError> {
+        // Only the allowed addresses are returned, so the connection is made to an address which was checked,
+        // and the domain isn't resolved again to another one. It's refused when none of them is allowed.
+        async fn resolve_domain(&self, name: &str) -> Result<Vec<SocketAddr>, BoxError> {
             pre_resolve(name)?;
 
-            let result = match self {
-                Self::Default() => tokio::net::lookup_host(name).await?.next(),
-                Self::Hickory(r) => r.lookup_ip(name).await?.iter().next().map(|a| SocketAddr::new(a, 0)),
+            let addrs: Vec<SocketAddr> = match self {
+                Self::Default() => tokio::net::lookup_host((name, 0)).await?.collect(),
+                Self::Hickory(r) => r.lookup_ip(name).await?.iter().map(|a| SocketAddr::new(a, 0)).collect(),
             };
 
-            if let Some(addr) = &result {
-                post_resolve(name, addr.ip())?;
+            let mut allowed = Vec::with_capacity(addrs.len());
+            let mut denied = None;
+            for addr in addrs {
+                match post_resolve(name, addr.ip()) {
+                    Ok(()) => allowed.push(addr),
+                    Err(e) => denied = denied.or(Some(e)),
+                }
             }
+            match denied {
+                Some(e) if allowed.is_empty() => Err(e.into()),
+                _ => Ok(allowed),
+            }
+        }
+    }
 
-            Ok(result)
+    /// Checks the host of a URL before it's requested, or followed after a redirect.
+    /// The IPs in the URLs aren't resolved, so they are only checked here.
+    pub fn check_icon_url(url: &Url) -> Result<(), CustomResolverError> {
+        match url.host() {
+            Some(Host::Domain(domain)) => pre_resolve(domain),
+            Some(Host::Ipv4(ip)) => {
+                pre_resolve(&ip.to_string())?;
+                post_resolve(&ip.to_string(), IpAddr::V4(ip))
+            }
+            Some(Host::Ipv6(ip)) => {
+                pre_resolve(&ip.to_string())?;
+                post_resolve(&ip.to_string(), IpAddr::V6(ip))
+            }
+            None => Ok(()),
         }
     }
 
@@ -871,8 +1149,18 @@ mod dns_resolver {
         Ok(())
     }
 
+    // The denied ranges win over the allowed ones, which win over `ICON_BLACKLIST_NON_GLOBAL_IPS`
     fn post_resolve(name: &str, ip: IpAddr) -> Result<(), CustomResolverError> {
-        if CONFIG.icon_blacklist_non_global_ips() && !is_global(ip) {
+        // An IPv4-mapped IPv6 address is checked as the IPv4 address it maps to
+        let ip = ip.to_canonical();
+        if in_ranges(CONFIG.icon_denied_ip_ranges(), ip) {
+            Err(CustomResolverError::DeniedIp {
+                domain: name.to_string(),
+                ip,
+            })
+        } else if in_ranges(CONFIG.icon_allowed_ip_ranges(), ip) {
+            Ok(())
+        } else if CONFIG.icon_blacklist_non_global_ips() && !is_global(ip) {
             Err(CustomResolverError::NonGlobalIp {
                 domain: name.to_string(),
                 ip,
@@ -882,19 +1170,25 @@ mod dns_resolver {
         }
     }
 
+    // The ranges were validated with the config
+    fn in_ranges(ranges: Option<String>, ip: IpAddr) -> bool {
+        ranges
+            .is_some_and(|ranges| parse_ip_ranges(&ranges).unwrap_or_default().iter().any(|range| range.contains(&ip)))
+    }
+
     impl Resolve for CustomDnsResolver {
         fn resolve(&self, name: Name) -> Resolving {
             let this = self.clone();
             Box::pin(async move {
                 let name = name.as_str();
-                let result = this.resolve_domain(name).await?;
-                Ok::<reqwest::dns::Addrs, _>(Box::new(result.into_iter()))
+                let addrs = this.resolve_domain(name).await?;
+                Ok::<reqwest::dns::Addrs, _>(Box::new(addrs.into_iter()))
             })
         }
     }
 }
 
-pub use dns_resolver::{CustomDnsResolver, CustomResolverError};
+pub use dns_resolver::{check_icon_url, CustomDnsResolver, CustomResolverError};
 
 /// TODO: This is extracted from IpAddr::is_global, which is unstable:
 /// https://doc.rust-lang.org/nightly/std/net/enum.IpAddr.html#method.is_global
@@ -968,6 +1262,20 @@ mod tests {
     use super::*;
     use std::net::IpAddr;
 
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 99))));
+        assert_eq!(parse_range("bytes=500-", 1000), Some(Ok((500, 999))));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse_range("bytes=-2000", 1000), Some(Ok((0, 999))));
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-99,200-299", 1000), None);
+        assert_eq!(parse_range("bytes=99-0", 1000), None);
+        assert_eq!(parse_range("items=0-99", 1000), None);
+    }
+
     #[test]
     #[ignore]
     fn test_ipv4_global() {
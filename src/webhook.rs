@@ -0,0 +1,240 @@
+//
+// Outbound webhooks, POSTing the server events as signed JSON to the configured URLs
+//
+// Each request has these headers:
+// - `X-Vaultwarden-Event`: the name of the event, like `user.registered`
+// - `X-Vaultwarden-Delivery`: an unique id, the same for the retries of a delivery
+// - `X-Vaultwarden-Timestamp`: the unix time when the request was signed
+// - `X-Vaultwarden-Signature`: `sha256=` followed by the hex HMAC-SHA256 of `<timestamp>.<body>`, keyed by the secret
+//
+use std::{net::IpAddr, time::Duration};
+
+use chrono::Utc;
+use serde_json::Value;
+use url::Url;
+
+use crate::{
+    crypto,
+    db::{models::EventType, DbConn},
+    http_client::{get_reqwest_client, TracedSend},
+    log_context,
+    util::format_date,
+    CONFIG,
+};
+
+const MAX_ATTEMPTS: u32 = 3;
+
+#[derive(Deserialize)]
+struct Webhook {
+    url: String,
+    secret: String,
+    // All the events when empty
+    #[serde(default)]
+    events: Vec<String>,
+}
+
+impl Webhook {
+    fn wants(&self, event: WebhookEvent) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == "*" || e == event.name())
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum WebhookEvent {
+    UserRegistered,
+    UserFailedLogin,
+    OrganizationMemberInvited,
+    OrganizationMemberConfirmed,
+    OrganizationMemberUpdated,
+    OrganizationMemberRemoved,
+    OrganizationMemberRevoked,
+    OrganizationMemberRestored,
+    CipherDeleted,
+}
+
+const ALL_EVENTS: [WebhookEvent; 9] = [
+    WebhookEvent::UserRegistered,
+    WebhookEvent::UserFailedLogin,
+    WebhookEvent::OrganizationMemberInvited,
+    WebhookEvent::OrganizationMemberConfirmed,
+    WebhookEvent::OrganizationMemberUpdated,
+    WebhookEvent::OrganizationMemberRemoved,
+    WebhookEvent::OrganizationMemberRevoked,
+    WebhookEvent::OrganizationMemberRestored,
+    WebhookEvent::CipherDeleted,
+];
+
+impl WebhookEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::UserRegistered => "user.registered",
+            Self::UserFailedLogin => "user.login_failed",
+            Self::OrganizationMemberInvited => "organization.member_invited",
+            Self::OrganizationMemberConfirmed => "organization.member_confirmed",
+            Self::OrganizationMemberUpdated => "organization.member_updated",
+            Self::OrganizationMemberRemoved => "organization.member_removed",
+            Self::OrganizationMemberRevoked => "organization.member_revoked",
+            Self::OrganizationMemberRestored => "organization.member_restored",
+            Self::CipherDeleted => "cipher.deleted",
+        }
+    }
+
+    // The events which are also logged as organization or user events
+    fn from_event_type(event_type: i32) -> Option<Self> {
+        match event_type {
+            t if t == EventType::UserFailedLogIn as i32 || t == EventType::UserFailedLogIn2fa as i32 => {
+                Some(Self::UserFailedLogin)
+            }
+            t if t == EventType::OrganizationUserInvited as i32 => Some(Self::OrganizationMemberInvited),
+            t if t == EventType::OrganizationUserConfirmed as i32 => Some(Self::OrganizationMemberConfirmed),
+            t if t == EventType::OrganizationUserUpdated as i32 => Some(Self::OrganizationMemberUpdated),
+            t if t == EventType::OrganizationUserRemoved as i32 => Some(Self::OrganizationMemberRemoved),
+            t if t == EventType::OrganizationUserRevoked as i32 => Some(Self::OrganizationMemberRevoked),
+            t if t == EventType::OrganizationUserRestored as i32 => Some(Self::OrganizationMemberRestored),
+            _ => None,
+        }
+    }
+}
+
+fn parse_webhooks(config: &str) -> Result<Vec<Webhook>, String> {
+    serde_json::from_str(config).map_err(|e| format!("`WEBHOOKS` is not a valid JSON array of webhooks: {e}"))
+}
+
+/// Checks the `WEBHOOKS` setting, a JSON array of `{"url": "...", "secret": "...", "events": ["..."]}` objects.
+pub fn validate_config(config: &str) -> Result<(), String> {
+    for webhook in parse_webhooks(config)? {
+        match Url::parse(&webhook.url) {
+            Ok(url) if url.scheme() == "https" || url.scheme() == "http" => {}
+            _ => return Err(format!("The webhook URL `{}` is not a valid http(s) URL", webhook.url)),
+        }
+        if webhook.secret.len() < 16 {
+            return Err(format!("The secret of the webhook `{}` needs to be at least 16 characters long", webhook.url));
+        }
+        if let Some(event) =
+            webhook.events.iter().find(|e| *e != "*" && !ALL_EVENTS.iter().any(|known| known.name() == e.as_str()))
+        {
+            return Err(format!("The webhook `{}` has an unknown event `{event}`", webhook.url));
+        }
+    }
+    Ok(())
+}
+
+fn webhooks_for(event: WebhookEvent) -> Vec<Webhook> {
+    let Some(config) = CONFIG.webhooks() else {
+        return Vec::new();
+    };
+    match parse_webhooks(&config) {
+        Ok(webhooks) => webhooks.into_iter().filter(|w| w.wants(event)).collect(),
+        Err(e) => {
+            error!("{e}");
+            Vec::new()
+        }
+    }
+}
+
+/// Sends an event to the webhooks subscribed to it, in the background.
+pub fn send(event: WebhookEvent, data: Value) {
+    let webhooks = webhooks_for(event);
+    if webhooks.is_empty() {
+        return;
+    }
+
+    let delivery_uuid = crate::util::get_uuid();
+    let body = json!({
+        "id": delivery_uuid,
+        "event": event.name(),
+        "date": format_date(&Utc::now().naive_utc()),
+        "data": data,
+    })
+    .to_string();
+
+    for webhook in webhooks {
+        let body = body.clone();
+        let delivery_uuid = delivery_uuid.clone();
+        log_context::spawn(async move {
+            deliver(&webhook, event, &delivery_uuid, &body).await;
+        });
+    }
+}
+
+async fn deliver(webhook: &Webhook, event: WebhookEvent, delivery_uuid: &str, body: &str) {
+    for attempt in 1..=MAX_ATTEMPTS {
+        let timestamp = Utc::now().timestamp().to_string();
+        let signature = crypto::hmac_sha256_sign(&webhook.secret, &format!("{timestamp}.{body}"));
+
+        let result = get_reqwest_client()
+            .post(&webhook.url)
+            .header("Content-Type", "application/json")
+            .header("X-Vaultwarden-Event", event.name())
+            .header("X-Vaultwarden-Delivery", delivery_uuid)
+            .header("X-Vaultwarden-Timestamp", &timestamp)
+            .header("X-Vaultwarden-Signature", format!("sha256={signature}"))
+            .body(body.to_string())
+            .send_traced()
+            .await
+            .and_then(|res| res.error_for_status());
+
+        match result {
+            Ok(_) => return,
+            // The client errors won't be fixed by trying again, except the rate limiting
+            Err(e) if e.status().is_some_and(|s| s.is_client_error() && s.as_u16() != 429) => {
+                error!("Webhook `{}` refused the {} event: {e}", webhook.url, event.name());
+                return;
+            }
+            Err(e) if attempt == MAX_ATTEMPTS => {
+                error!("Webhook `{}` failed for the {} event after {attempt} attempts: {e}", webhook.url, event.name());
+            }
+            Err(e) => {
+                warn!("Webhook `{}` failed for the {} event, trying again: {e}", webhook.url, event.name());
+                tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+            }
+        }
+    }
+}
+
+/// Sends the user events which have a webhook event, see `log_user_event`.
+pub async fn send_user_event(event_type: i32, user_uuid: &str, device_type: i32, ip: &IpAddr, conn: &mut DbConn) {
+    let Some(event) = WebhookEvent::from_event_type(event_type) else {
+        return;
+    };
+    if CONFIG.webhooks().is_none() {
+        return;
+    }
+    let email = crate::db::models::User::find_by_uuid(user_uuid, conn).await.map(|u| u.email);
+
+    send(
+        event,
+        json!({
+            "userId": user_uuid,
+            "email": email,
+            "twoFactor": event_type == EventType::UserFailedLogIn2fa as i32,
+            "deviceType": device_type,
+            "ipAddress": ip.to_string(),
+        }),
+    );
+}
+
+/// Sends the organization events which have a webhook event, see `log_event`.
+pub fn send_organization_event(
+    event_type: i32,
+    source_uuid: &str,
+    org_uuid: &str,
+    act_user_uuid: &str,
+    device_type: i32,
+    ip: &IpAddr,
+) {
+    let Some(event) = WebhookEvent::from_event_type(event_type) else {
+        return;
+    };
+
+    send(
+        event,
+        json!({
+            "organizationId": org_uuid,
+            "memberId": source_uuid,
+            "actingUserId": act_user_uuid,
+            "deviceType": device_type,
+            "ipAddress": ip.to_string(),
+        }),
+    );
+}